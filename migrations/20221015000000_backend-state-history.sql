@@ -0,0 +1,20 @@
+-- Every state a backend has entered, kept after the backend terminates.
+create table "backend_state_history" (
+    "id" integer primary key,
+
+    -- Backend whose state changed.
+    "backend" text not null,
+
+    -- The state the backend entered, as a string.
+    "state" text not null,
+
+    -- When the backend entered the state, in milliseconds since the Unix epoch.
+    "time" integer not null,
+
+    -- How the backend terminated, as JSON, if the state is terminal.
+    "termination" text,
+
+    foreign key("backend") references backend("name")
+);
+
+create index "backend_state_history_backend" on "backend_state_history" ("backend");
@@ -0,0 +1,7 @@
+-- When the backend of the route began to stop, after which the proxy no longer
+-- sends it new requests. Null while the route is live.
+alter table "route" add column "draining_since" integer;
+
+-- The number of requests and upgraded connections the proxy has open to the
+-- route's backend, updated by the proxy periodically.
+alter table "route" add column "connections" integer not null default 0;
@@ -0,0 +1,3 @@
+-- Token which requests must carry for the proxy to forward them to the route's
+-- backend. Null if the backend is public.
+alter table "route" add column "bearer_token" text;
@@ -0,0 +1,5 @@
+-- Port on the drone which the proxy passes raw TCP connections through to the
+-- route's address. Null for routes which are served over HTTP by subdomain.
+alter table "route" add column "tcp_port" integer;
+
+create unique index "route_tcp_port" on "route" ("tcp_port");
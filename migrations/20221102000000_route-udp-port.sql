@@ -0,0 +1,5 @@
+-- Port on the drone which the proxy relays UDP datagrams from to the route's
+-- address. Null for routes which are not relayed over UDP.
+alter table "route" add column "udp_port" integer;
+
+create unique index "route_udp_port" on "route" ("udp_port");
@@ -0,0 +1,3 @@
+-- Maximum number of long-lived connections (e.g. WebSockets or raw TCP connections) the
+-- proxy holds open to the route's backend at once. Null if unlimited.
+alter table "route" add column "max_connections" integer;
@@ -0,0 +1,3 @@
+-- Timeouts of the proxy's requests to the route's backend, as the JSON of its spawn
+-- request's `timeouts`. Null if the proxy's own apply.
+alter table "route" add column "timeouts" text;
@@ -0,0 +1,6 @@
+use anyhow::Result;
+use dis_spawner::scheduler::cli::run;
+
+fn main() -> Result<()> {
+    run()
+}
@@ -8,10 +8,13 @@
 //! based on type information stored in `sqlx-data.json`. If
 //! you change a query in this file, you will likely need to
 //! run `generate-sqlx-data.mjs` to get Rust to accept it.
-use std::str::FromStr;
+use std::{collections::HashSet, ops::RangeInclusive, str::FromStr};
 
 use crate::{
-    messages::agent::{BackendState, SpawnRequest},
+    messages::{
+        agent::{BackendState, BackendTermination, BackendTimeouts, SpawnRequest},
+        proxy::RouteInfo,
+    },
     types::BackendId,
 };
 use chrono::{DateTime, TimeZone, Utc};
@@ -29,6 +32,53 @@ pub struct Backend {
     pub spec: SpawnRequest,
 }
 
+/// A state that a backend entered.
+#[derive(Debug)]
+pub struct BackendStateRecord {
+    pub backend_id: BackendId,
+    pub state: BackendState,
+    pub time: DateTime<Utc>,
+    pub termination: Option<BackendTermination>,
+}
+
+/// Where the proxy sends requests for a subdomain.
+#[derive(Debug, Clone)]
+pub struct ProxyRoute {
+    pub backend_id: Option<BackendId>,
+
+    /// IP and port of the backend on the host.
+    pub address: String,
+
+    /// Token which requests must carry to be forwarded, if any.
+    pub bearer_token: Option<String>,
+
+    /// Long-lived connections the proxy holds open to the backend at once, if limited.
+    pub max_connections: Option<u32>,
+
+    /// Timeouts of requests to the backend, in place of the proxy's own.
+    pub timeouts: Option<BackendTimeouts>,
+
+    /// Addresses of further replicas of the backend. Only routes received over NATS have
+    /// replicas, since the drone runs each backend as a single container.
+    pub replicas: Vec<String>,
+
+    /// The cluster the backend belongs to: the cluster its routes were received from, or
+    /// the cluster its spawn request named. None for a backend of the drone's own
+    /// cluster whose spawn request did not name one.
+    pub cluster: Option<String>,
+}
+
+/// Parse the JSON of a route's timeouts, falling back to the proxy's own if it is invalid.
+fn parse_timeouts(timeouts: Option<String>) -> Option<BackendTimeouts> {
+    match serde_json::from_str(&timeouts?) {
+        Ok(timeouts) => Some(timeouts),
+        Err(error) => {
+            tracing::warn!(?error, "Ignoring invalid route timeouts.");
+            None
+        }
+    }
+}
+
 #[allow(unused)]
 impl DroneDatabase {
     pub fn new(pool: SqlitePool) -> DroneDatabase {
@@ -39,7 +89,9 @@ impl DroneDatabase {
         let backend_id = spec.backend_id.id().to_string();
         let spec =
             serde_json::to_string(&spec).expect("SpawnRequest serialization should never fail.");
+        let time = Utc::now().timestamp_millis();
 
+        let mut transaction = self.pool.begin().await?;
         sqlx::query!(
             r"
             insert into backend
@@ -50,8 +102,21 @@ impl DroneDatabase {
             backend_id,
             spec,
         )
-        .execute(&self.pool)
+        .execute(&mut transaction)
+        .await?;
+        sqlx::query!(
+            r"
+            insert into backend_state_history
+            (backend, state, time)
+            values
+            (?, 'Loading', ?)
+            ",
+            backend_id,
+            time,
+        )
+        .execute(&mut transaction)
         .await?;
+        transaction.commit().await?;
 
         Ok(())
     }
@@ -76,19 +141,128 @@ impl DroneDatabase {
         .collect()
     }
 
+    /// The current state of the backend, if the drone has a record of it.
+    pub async fn get_backend_state(
+        &self,
+        backend: &BackendId,
+    ) -> anyhow::Result<Option<BackendState>> {
+        let backend_id = backend.id();
+        let state = sqlx::query!(
+            r"
+            select state
+            from backend
+            where name = ?
+            ",
+            backend_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(match state {
+            Some(d) => Some(BackendState::from_str(&d.state)?),
+            None => None,
+        })
+    }
+
+    /// Move the backend to a new state, and record the transition in its history.
     pub async fn update_backend_state(
         &self,
         backend: &BackendId,
         state: BackendState,
+        termination: Option<&BackendTermination>,
     ) -> Result<()> {
+        let backend_id = backend.id().to_string();
         let state = state.to_string();
+        let time = Utc::now().timestamp_millis();
+        let exit_code = termination.and_then(|termination| termination.exit_code);
+        let termination = termination.map(|termination| {
+            serde_json::to_string(termination)
+                .expect("BackendTermination serialization should never fail.")
+        });
 
+        let mut transaction = self.pool.begin().await?;
         sqlx::query!(
             r"
             update backend
-            set state = ?
+            set state = ?, exit_code = coalesce(?, exit_code)
+            where name = ?
+            ",
+            state,
+            exit_code,
+            backend_id,
+        )
+        .execute(&mut transaction)
+        .await?;
+        sqlx::query!(
+            r"
+            insert into backend_state_history
+            (backend, state, time, termination)
+            values
+            (?, ?, ?, ?)
             ",
-            state
+            backend_id,
+            state,
+            time,
+            termination,
+        )
+        .execute(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    /// Every state entered by the given backend, or by all backends, oldest first.
+    pub async fn get_backend_history(
+        &self,
+        backend: Option<&BackendId>,
+    ) -> anyhow::Result<Vec<BackendStateRecord>> {
+        let backend_id = backend.map(|backend| backend.id().to_string());
+
+        sqlx::query!(
+            r"
+            select backend, state, time, termination
+            from backend_state_history
+            where ?1 is null or backend = ?1
+            order by id
+            ",
+            backend_id,
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(|d| {
+            Ok(BackendStateRecord {
+                backend_id: BackendId::new(d.backend.clone()),
+                state: BackendState::from_str(&d.state)?,
+                time: Utc.timestamp_millis(d.time),
+                termination: d
+                    .termination
+                    .as_deref()
+                    .map(serde_json::from_str)
+                    .transpose()?,
+            })
+        })
+        .collect()
+    }
+
+    /// Record a new expiry time in the backend's spec, so that it survives agent restarts.
+    pub async fn update_backend_expiry(
+        &self,
+        backend: &BackendId,
+        expires_at: DateTime<Utc>,
+    ) -> Result<()> {
+        let backend_id = backend.id().to_string();
+        let expires_at = expires_at.to_rfc3339();
+
+        sqlx::query!(
+            r"
+            update backend
+            set spec = json_set(spec, '$.expires_at', ?)
+            where name = ?
+            ",
+            expires_at,
+            backend_id,
         )
         .execute(&self.pool)
         .await?;
@@ -97,37 +271,322 @@ impl DroneDatabase {
     }
 
     /// Get the downstream source to direct a request on an incoming subdomain to.
-    pub async fn get_proxy_route(&self, subdomain: &str) -> Result<Option<String>> {
+    pub async fn get_proxy_route(&self, subdomain: &str) -> Result<Option<ProxyRoute>> {
         Ok(sqlx::query!(
-            r"
-            select address
-            from route
-            where subdomain = ?
-            ",
+            r#"
+            select route.backend, route.address, route.bearer_token, route.max_connections,
+                route.timeouts, json_extract(backend.spec, '$.cluster') as "cluster?: String"
+            from route left join backend on backend.name = route.backend
+            where route.subdomain = ? and route.draining_since is null
+                and route.tcp_port is null and route.udp_port is null
+            "#,
             subdomain
         )
         .fetch_optional(&self.pool)
         .await?
-        .map(|d| d.address))
+        .map(|d| ProxyRoute {
+            backend_id: d.backend.map(BackendId::new),
+            address: d.address,
+            bearer_token: d.bearer_token,
+            max_connections: d.max_connections.map(|max| max as u32),
+            timeouts: parse_timeouts(d.timeouts),
+            replicas: Vec::new(),
+            cluster: d.cluster,
+        }))
+    }
+
+    /// The routes which the proxy sends new requests through, i.e. those with a backend
+    /// which are not draining.
+    pub async fn get_live_proxy_routes(&self) -> Result<Vec<RouteInfo>> {
+        Ok(sqlx::query!(
+            r#"
+            select subdomain, backend as "backend!", address, bearer_token, max_connections,
+                timeouts
+            from route
+            where backend is not null and draining_since is null
+                and tcp_port is null and udp_port is null
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|d| RouteInfo {
+            subdomain: d.subdomain,
+            backend_id: BackendId::new(d.backend),
+            address: d.address,
+            bearer_token: d.bearer_token,
+            max_connections: d.max_connections.map(|max| max as u32),
+            timeouts: parse_timeouts(d.timeouts),
+            replicas: Vec::new(),
+        })
+        .collect())
     }
 
+    /// Route the given subdomain to the given address, replacing the address of an
+    /// existing route for the subdomain (e.g. when a restarted container is published
+    /// on a new port). An existing route keeps its bearer token if none is given, since
+    /// the token is not recovered when the drone adopts a running container.
     pub async fn insert_proxy_route(
         &self,
         backend: &BackendId,
         subdomain: &str,
         address: &str,
+        bearer_token: Option<&str>,
+        max_connections: Option<u32>,
+        timeouts: Option<&BackendTimeouts>,
     ) -> Result<()> {
         let backend_id = backend.id().to_string();
+        let timeouts = timeouts.map(|timeouts| {
+            serde_json::to_string(timeouts).expect("Timeouts serialization should never fail.")
+        });
         sqlx::query!(
             r"
             insert into route
-            (backend, subdomain, address, last_active)
+            (backend, subdomain, address, last_active, bearer_token, max_connections, timeouts)
             values
-            (?, ?, ?, unixepoch())
+            (?, ?, ?, unixepoch(), ?, ?, ?)
+            on conflict (subdomain) do update
+            set
+                address = excluded.address,
+                draining_since = null,
+                bearer_token = coalesce(excluded.bearer_token, route.bearer_token),
+                max_connections = excluded.max_connections,
+                timeouts = excluded.timeouts
             ",
             backend_id,
             subdomain,
-            address
+            address,
+            bearer_token,
+            max_connections,
+            timeouts
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Route raw TCP connections to a port on the drone to the given address. The route
+    /// keeps the port of an existing route with the same name (e.g. when a restarted
+    /// container is published on a new port), and otherwise takes the lowest free port
+    /// in the range. Returns the port, or None if every port in the range is taken.
+    pub async fn insert_tcp_route(
+        &self,
+        backend: &BackendId,
+        name: &str,
+        address: &str,
+        mut ports: RangeInclusive<u16>,
+        max_connections: Option<u32>,
+        timeouts: Option<&BackendTimeouts>,
+    ) -> Result<Option<u16>> {
+        let backend_id = backend.id().to_string();
+        let timeouts = timeouts.map(|timeouts| {
+            serde_json::to_string(timeouts).expect("Timeouts serialization should never fail.")
+        });
+        let mut transaction = self.pool.begin().await?;
+
+        let existing = sqlx::query!(
+            r#"
+            select tcp_port as "tcp_port!: i64"
+            from route
+            where subdomain = ? and tcp_port is not null
+            "#,
+            name
+        )
+        .fetch_optional(&mut transaction)
+        .await?;
+        let port = match existing {
+            Some(route) => route.tcp_port,
+            None => {
+                let taken: HashSet<i64> = sqlx::query!(
+                    r#"
+                    select tcp_port as "tcp_port!: i64"
+                    from route
+                    where tcp_port is not null
+                    "#
+                )
+                .fetch_all(&mut transaction)
+                .await?
+                .into_iter()
+                .map(|route| route.tcp_port)
+                .collect();
+
+                match ports.find(|port| !taken.contains(&(*port as i64))) {
+                    Some(port) => port as i64,
+                    None => return Ok(None),
+                }
+            }
+        };
+
+        sqlx::query!(
+            r"
+            insert into route
+            (backend, subdomain, address, last_active, tcp_port, max_connections, timeouts)
+            values
+            (?, ?, ?, unixepoch(), ?, ?, ?)
+            on conflict (subdomain) do update
+            set
+                address = excluded.address,
+                draining_since = null,
+                tcp_port = excluded.tcp_port,
+                max_connections = excluded.max_connections,
+                timeouts = excluded.timeouts
+            ",
+            backend_id,
+            name,
+            address,
+            port,
+            max_connections,
+            timeouts
+        )
+        .execute(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+
+        Ok(Some(port as u16))
+    }
+
+    /// Get the name and route of the TCP route on the given port, if it is not draining.
+    pub async fn get_tcp_route(&self, port: u16) -> Result<Option<(String, ProxyRoute)>> {
+        Ok(sqlx::query!(
+            r#"
+            select route.subdomain, route.backend, route.address, route.bearer_token,
+                route.max_connections, route.timeouts,
+                json_extract(backend.spec, '$.cluster') as "cluster?: String"
+            from route left join backend on backend.name = route.backend
+            where route.tcp_port = ? and route.draining_since is null
+            "#,
+            port
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|d| {
+            (
+                d.subdomain,
+                ProxyRoute {
+                    backend_id: d.backend.map(BackendId::new),
+                    address: d.address,
+                    bearer_token: d.bearer_token,
+                    max_connections: d.max_connections.map(|max| max as u32),
+                    timeouts: parse_timeouts(d.timeouts),
+                    replicas: Vec::new(),
+                    cluster: d.cluster,
+                },
+            )
+        }))
+    }
+
+    /// Relay UDP datagrams to a port on the drone to the given address, keeping the port
+    /// of an existing route with the same name, and otherwise taking the lowest free port
+    /// in the range. Returns the port, or None if every port in the range is taken.
+    pub async fn insert_udp_route(
+        &self,
+        backend: &BackendId,
+        name: &str,
+        address: &str,
+        mut ports: RangeInclusive<u16>,
+    ) -> Result<Option<u16>> {
+        let backend_id = backend.id().to_string();
+        let mut transaction = self.pool.begin().await?;
+
+        let existing = sqlx::query!(
+            r#"
+            select udp_port as "udp_port!: i64"
+            from route
+            where subdomain = ? and udp_port is not null
+            "#,
+            name
+        )
+        .fetch_optional(&mut transaction)
+        .await?;
+        let port = match existing {
+            Some(route) => route.udp_port,
+            None => {
+                let taken: HashSet<i64> = sqlx::query!(
+                    r#"
+                    select udp_port as "udp_port!: i64"
+                    from route
+                    where udp_port is not null
+                    "#
+                )
+                .fetch_all(&mut transaction)
+                .await?
+                .into_iter()
+                .map(|route| route.udp_port)
+                .collect();
+
+                match ports.find(|port| !taken.contains(&(*port as i64))) {
+                    Some(port) => port as i64,
+                    None => return Ok(None),
+                }
+            }
+        };
+
+        sqlx::query!(
+            r"
+            insert into route
+            (backend, subdomain, address, last_active, udp_port)
+            values
+            (?, ?, ?, unixepoch(), ?)
+            on conflict (subdomain) do update
+            set
+                address = excluded.address,
+                draining_since = null,
+                udp_port = excluded.udp_port
+            ",
+            backend_id,
+            name,
+            address,
+            port
+        )
+        .execute(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+
+        Ok(Some(port as u16))
+    }
+
+    /// Get the name and address of the UDP route on the given port, if it is not draining.
+    pub async fn get_udp_route(&self, port: u16) -> Result<Option<(String, String)>> {
+        Ok(sqlx::query!(
+            r"
+            select subdomain, address
+            from route
+            where udp_port = ? and draining_since is null
+            ",
+            port
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|d| (d.subdomain, d.address)))
+    }
+
+    /// The backend which the route with the given subdomain or name belongs to.
+    pub async fn get_route_backend(&self, subdomain: &str) -> Result<Option<BackendId>> {
+        Ok(sqlx::query!(
+            r"
+            select backend
+            from route
+            where subdomain = ?
+            ",
+            subdomain
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .and_then(|d| d.backend)
+        .map(BackendId::new))
+    }
+
+    /// Free the TCP and UDP ports of a backend which has stopped, so that other backends
+    /// can be assigned them.
+    pub async fn release_port_routes(&self, backend: &BackendId) -> Result<()> {
+        let backend_id = backend.id();
+        sqlx::query!(
+            r"
+            delete from route
+            where backend = ? and (tcp_port is not null or udp_port is not null)
+            ",
+            backend_id
         )
         .execute(&self.pool)
         .await?;
@@ -152,12 +611,81 @@ impl DroneDatabase {
         Ok(())
     }
 
+    /// Record the number of connections the proxy has open through each route. Routes
+    /// which are not listed have none.
+    pub async fn set_open_connections(&self, connections: &[(String, u32)]) -> Result<()> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query!(
+            r"
+            update route
+            set connections = 0
+            where connections != 0
+            "
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        for (subdomain, count) in connections {
+            sqlx::query!(
+                r"
+                update route
+                set connections = ?
+                where subdomain = ?
+                ",
+                count,
+                subdomain
+            )
+            .execute(&mut transaction)
+            .await?;
+        }
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    /// Stop the proxy from sending new requests to the backend, leaving open connections
+    /// to finish.
+    pub async fn drain_proxy_routes(&self, backend: &BackendId) -> Result<()> {
+        let backend_id = backend.id();
+        sqlx::query!(
+            r"
+            update route
+            set draining_since = unixepoch()
+            where backend = ? and draining_since is null
+            ",
+            backend_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// The number of connections the proxy has open to the backend, across its routes.
+    pub async fn get_backend_connections(&self, backend: &BackendId) -> Result<i64> {
+        let backend_id = backend.id();
+        let connections = sqlx::query!(
+            r#"
+            select coalesce(sum(connections), 0) as "connections!: i64"
+            from route
+            where backend = ?
+            "#,
+            backend_id
+        )
+        .fetch_one(&self.pool)
+        .await?
+        .connections;
+
+        Ok(connections)
+    }
+
+    /// Get the most recent time any of the backend's routes was active.
     pub async fn get_backend_last_active(&self, backend: &BackendId) -> Result<DateTime<Utc>> {
         let backend_id = backend.id();
 
         let time = sqlx::query!(
             r#"
-            select last_active
+            select max(last_active) as "last_active!: i64"
             from route
             where backend = ?
             "#,
@@ -0,0 +1,209 @@
+//! Reading and adjusting the cgroup v2 controls of backend containers directly, for
+//! settings which container engines do not expose.
+//!
+//! Containers are located through `/proc/<pid>/cgroup`, so the agent must share the
+//! host's PID namespace and have its cgroup filesystem mounted at `/sys/fs/cgroup`.
+
+use super::engine::ContainerStats;
+use crate::messages::agent::EffectiveResourceLimits;
+use anyhow::{anyhow, Context, Result};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::fs;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Whether the host uses the unified (v2) cgroup hierarchy.
+pub fn is_cgroup_v2() -> bool {
+    Path::new(CGROUP_ROOT).join("cgroup.controllers").exists()
+}
+
+/// The path of the unified hierarchy's cgroup within the contents of `/proc/<pid>/cgroup`.
+fn parse_unified_cgroup(proc_cgroup: &str) -> Option<&str> {
+    proc_cgroup
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+}
+
+/// Parse `cpu.max`, which holds a quota (or `max`) and a period in microseconds.
+fn parse_cpu_max(cpu_max: &str) -> Option<(Option<Duration>, Duration)> {
+    let (quota, period) = cpu_max.trim().split_once(' ')?;
+    let quota = match quota {
+        "max" => None,
+        quota => Some(Duration::from_micros(quota.parse().ok()?)),
+    };
+
+    Some((quota, Duration::from_micros(period.parse().ok()?)))
+}
+
+/// Parse a control file holding a number, or `max` for no limit.
+fn parse_limit(value: &str) -> Option<i64> {
+    value.trim().parse().ok()
+}
+
+/// The value of a key in a flat-keyed control file such as `cpu.stat`.
+fn parse_stat_field(stat: &str, key: &str) -> Option<u64> {
+    stat.lines().find_map(|line| {
+        let (name, value) = line.split_once(' ')?;
+        if name == key {
+            value.trim().parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// Total bytes received and sent on all interfaces but loopback, from the contents of
+/// `/proc/<pid>/net/dev`.
+fn parse_net_dev(net_dev: &str) -> (u64, u64) {
+    net_dev
+        .lines()
+        .skip(2)
+        .filter_map(|line| {
+            let (interface, counters) = line.split_once(':')?;
+            if interface.trim() == "lo" {
+                return None;
+            }
+
+            // Eight receive counters precede the transmit counters.
+            let counters: Vec<u64> = counters
+                .split_whitespace()
+                .filter_map(|counter| counter.parse().ok())
+                .collect();
+            Some((*counters.first()?, *counters.get(8)?))
+        })
+        .fold((0, 0), |(rx, tx), (interface_rx, interface_tx)| {
+            (rx + interface_rx, tx + interface_tx)
+        })
+}
+
+/// The directory of the cgroup that the process belongs to.
+async fn cgroup_directory(pid: i64) -> Result<PathBuf> {
+    let proc_cgroup = fs::read_to_string(format!("/proc/{}/cgroup", pid))
+        .await
+        .with_context(|| format!("Error reading cgroup of process {}.", pid))?;
+    let path = parse_unified_cgroup(&proc_cgroup)
+        .ok_or_else(|| anyhow!("Process {} is not in a cgroup v2 hierarchy.", pid))?;
+
+    Ok(Path::new(CGROUP_ROOT).join(path.trim_start_matches('/')))
+}
+
+/// Allow the cgroup of the process to accumulate unused CPU quota up to `burst`.
+pub async fn set_cpu_burst(pid: i64, burst: Duration) -> Result<()> {
+    if !is_cgroup_v2() {
+        return Err(anyhow!("CPU burst requires a host using cgroup v2."));
+    }
+
+    let path = cgroup_directory(pid).await?.join("cpu.max.burst");
+    fs::write(&path, burst.as_micros().to_string())
+        .await
+        .with_context(|| format!("Error writing {:?}.", path))
+}
+
+/// Sample the resource usage of the cgroup of the process, and the network traffic of
+/// its network namespace.
+pub async fn usage(pid: i64) -> Result<ContainerStats> {
+    if !is_cgroup_v2() {
+        return Err(anyhow!("Resource usage requires a host using cgroup v2."));
+    }
+
+    let directory = cgroup_directory(pid).await?;
+    let cpu_stat = fs::read_to_string(directory.join("cpu.stat")).await?;
+    let memory_current = fs::read_to_string(directory.join("memory.current")).await?;
+    let memory_stat = fs::read_to_string(directory.join("memory.stat")).await?;
+    let net_dev = fs::read_to_string(format!("/proc/{}/net/dev", pid)).await?;
+
+    // As in the Docker CLI, page cache which can be reclaimed is not counted as usage.
+    let memory_bytes = memory_current
+        .trim()
+        .parse::<u64>()?
+        .saturating_sub(parse_stat_field(&memory_stat, "inactive_file").unwrap_or_default());
+    let (network_rx_bytes, network_tx_bytes) = parse_net_dev(&net_dev);
+
+    Ok(ContainerStats {
+        cpu_time: Duration::from_micros(
+            parse_stat_field(&cpu_stat, "usage_usec").unwrap_or_default(),
+        ),
+        memory_bytes,
+        network_rx_bytes,
+        network_tx_bytes,
+    })
+}
+
+/// Read the CPU and memory limits of the cgroup of the process. Returns None if the
+/// host does not use cgroup v2.
+pub async fn effective_limits(pid: i64) -> Result<Option<EffectiveResourceLimits>> {
+    if !is_cgroup_v2() {
+        return Ok(None);
+    }
+
+    let directory = cgroup_directory(pid).await?;
+    let read = |name: &str| {
+        let path = directory.join(name);
+        async move { fs::read_to_string(&path).await.ok() }
+    };
+
+    let (cpu_quota, cpu_period) = read("cpu.max")
+        .await
+        .as_deref()
+        .and_then(parse_cpu_max)
+        .unwrap_or((None, Duration::from_millis(100)));
+
+    Ok(Some(EffectiveResourceLimits {
+        cpu_weight: read("cpu.weight")
+            .await
+            .and_then(|weight| weight.trim().parse().ok()),
+        cpu_quota,
+        cpu_period,
+        cpu_burst: read("cpu.max.burst")
+            .await
+            .and_then(|burst| burst.trim().parse().ok())
+            .filter(|burst| *burst > 0)
+            .map(Duration::from_micros),
+        memory_limit_bytes: read("memory.max").await.as_deref().and_then(parse_limit),
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_unified_cgroup() {
+        assert_eq!(
+            Some("/system.slice/docker-abc.scope"),
+            parse_unified_cgroup("0::/system.slice/docker-abc.scope\n")
+        );
+        assert_eq!(None, parse_unified_cgroup("12:cpu,cpuacct:/docker/abc\n"));
+    }
+
+    #[test]
+    fn test_parse_cpu_max() {
+        assert_eq!(
+            Some((None, Duration::from_millis(100))),
+            parse_cpu_max("max 100000\n")
+        );
+        assert_eq!(
+            Some((Some(Duration::from_millis(50)), Duration::from_millis(100))),
+            parse_cpu_max("50000 100000\n")
+        );
+        assert_eq!(None, parse_limit("max\n"));
+        assert_eq!(Some(1073741824), parse_limit("1073741824\n"));
+    }
+
+    #[test]
+    fn test_parse_usage() {
+        let cpu_stat = "usage_usec 3502\nuser_usec 2000\nsystem_usec 1502\n";
+        assert_eq!(Some(3502), parse_stat_field(cpu_stat, "usage_usec"));
+        assert_eq!(None, parse_stat_field(cpu_stat, "nr_periods"));
+
+        let net_dev = "Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo:     100       1    0    0    0     0          0         0      100       1    0    0    0     0       0          0
+  eth0:    5000      40    0    0    0     0          0         0     3000      30    0    0    0     0       0          0
+";
+        assert_eq!((5000, 3000), parse_net_dev(net_dev));
+    }
+}
@@ -0,0 +1,870 @@
+use super::{
+    cgroup::{effective_limits, set_cpu_burst, usage},
+    docker::{
+        check_bind_mounts, check_shm_size, container_labels, cpu_shares, duration_as_micros,
+        image_registry, is_root_user, merge_security_options, pids_limit, tcp_port, udp_port,
+        MANAGED_LABEL,
+    },
+    engine::{
+        ContainerEvent, ContainerEventType, ContainerHealth, ContainerStats, Engine,
+        ManagedContainer,
+    },
+    DockerApiTransport, DockerOptions,
+};
+use crate::messages::agent::{
+    BackendNetwork, EffectiveResourceLimits, PullPolicy, RestartPolicy, SecurityOptions,
+    SpawnRequest, Ulimits,
+};
+use anyhow::{anyhow, Context, Result};
+use async_stream::stream;
+use async_trait::async_trait;
+use bollard::{
+    auth::DockerCredentials,
+    container::LogOutput,
+    models::{ContainerInspectResponse, ImageInspect},
+};
+use futures::stream::BoxStream;
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{collections::HashMap, path::PathBuf, process::Stdio, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::Command,
+    sync::mpsc::Sender,
+};
+use tokio_stream::{wrappers::LinesStream, StreamExt};
+
+/// The containerd CLI used to manage containers. Unlike `ctr`, it sets up CNI
+/// networking and port publishing, and its `inspect` output matches Docker's.
+const NERDCTL: &str = "nerdctl";
+
+/// containerd namespace that backend containers are created in, which keeps them
+/// apart from containers managed by other clients (e.g. Kubernetes).
+const CONTAINERD_NAMESPACE: &str = "spawner";
+
+/// containerd does not report events by container name, so container states are
+/// polled at this interval and changes are reported as events.
+const EVENT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A row of `nerdctl ps` output.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerListEntry {
+    names: String,
+    status: String,
+}
+
+/// Runs backends with containerd, so that drones do not need Docker Engine.
+///
+/// containerd is driven through `nerdctl`, which must be on the agent's `PATH`.
+#[derive(Clone)]
+pub struct ContainerdInterface {
+    /// Path of containerd's API socket.
+    address: String,
+    runtime: Option<String>,
+    bind_mount_sources: Vec<PathBuf>,
+    default_container_port: u16,
+    default_network: BackendNetwork,
+
+    /// Credentials for pulling images, keyed by registry host. Used when the
+    /// spawn request does not carry its own credentials.
+    registry_credentials: Arc<HashMap<String, DockerCredentials>>,
+
+    /// Security options applied to every container, in addition to those in
+    /// the spawn request.
+    security_options: SecurityOptions,
+
+    /// Whether spawn requests may replace the profiles in `security_options`.
+    allow_profile_overrides: bool,
+
+    /// Whether to refuse to run containers whose processes would run as root.
+    forbid_root: bool,
+    ulimits: Ulimits,
+    max_shm_size_bytes: Option<i64>,
+    init: bool,
+    pids_limit: Option<i64>,
+    max_pids_limit: Option<i64>,
+}
+
+/// Returns true if a failed `nerdctl` command failed because the object it
+/// refers to does not exist.
+fn is_not_found(error: &anyhow::Error) -> bool {
+    let message = error.to_string();
+    message.contains("no such") || message.contains("not found")
+}
+
+fn restart_flag(restart_policy: RestartPolicy) -> String {
+    match restart_policy {
+        RestartPolicy::Never => "no".to_string(),
+        RestartPolicy::OnFailure {
+            max_retries: Some(max_retries),
+        } => format!("on-failure:{}", max_retries),
+        RestartPolicy::OnFailure { max_retries: None } => "on-failure".to_string(),
+        RestartPolicy::Always => "unless-stopped".to_string(),
+    }
+}
+
+impl ContainerdInterface {
+    pub async fn try_new(config: &DockerOptions) -> Result<Self> {
+        let address = match &config.transport {
+            DockerApiTransport::Socket(socket) => socket.clone(),
+            DockerApiTransport::Http(_) => {
+                return Err(anyhow!("containerd can only be reached through a socket."))
+            }
+        };
+
+        if config.gpu_count > 0 {
+            return Err(anyhow!(
+                "GPUs are not supported when running backends with containerd."
+            ));
+        }
+
+        let interface = ContainerdInterface {
+            address,
+            runtime: config.runtime.clone(),
+            bind_mount_sources: config.bind_mount_sources.clone(),
+            default_container_port: config.default_container_port,
+            default_network: config.default_network.clone(),
+            registry_credentials: Arc::new(config.load_registry_credentials()?),
+            security_options: config.load_security_options()?,
+            allow_profile_overrides: config.allow_profile_overrides,
+            forbid_root: config.forbid_root,
+            ulimits: config.ulimits,
+            max_shm_size_bytes: config.max_shm_size_bytes,
+            init: config.init,
+            pids_limit: config.pids_limit,
+            max_pids_limit: config.max_pids_limit,
+        };
+
+        // Fail early if nerdctl is missing or containerd is unreachable.
+        interface.nerdctl(&["version"]).await?;
+
+        Ok(interface)
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(NERDCTL);
+        command
+            .arg("--address")
+            .arg(&self.address)
+            .arg("--namespace")
+            .arg(CONTAINERD_NAMESPACE)
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+        command
+    }
+
+    /// Run `nerdctl` with the given arguments and return its standard output.
+    async fn nerdctl<S: AsRef<str>>(&self, args: &[S]) -> Result<Vec<u8>> {
+        let output = self
+            .command()
+            .args(args.iter().map(AsRef::as_ref))
+            .output()
+            .await
+            .context("Error running nerdctl.")?;
+
+        if !output.status.success() {
+            return Err(anyhow!(
+                "nerdctl {} failed: {}",
+                args.first().map(AsRef::as_ref).unwrap_or_default(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        Ok(output.stdout)
+    }
+
+    /// Run an `inspect` subcommand on a single object and parse its Docker-compatible output.
+    async fn inspect<T: DeserializeOwned>(&self, subcommand: &str, name: &str) -> Result<T> {
+        let output = self.nerdctl(&[subcommand, "inspect", name]).await?;
+        let mut results: Vec<T> = serde_json::from_slice(&output)?;
+
+        results
+            .pop()
+            .ok_or_else(|| anyhow!("No {} found with name {}.", subcommand, name))
+    }
+
+    async fn inspect_container(&self, container_name: &str) -> Result<ContainerInspectResponse> {
+        self.inspect("container", container_name).await
+    }
+
+    /// The host PID of the container's main process.
+    async fn container_pid(&self, container_name: &str) -> Result<i64> {
+        self.inspect_container(container_name)
+            .await?
+            .state
+            .and_then(|state| state.pid)
+            .filter(|pid| *pid > 0)
+            .ok_or_else(|| anyhow!("Container {} is not running.", container_name))
+    }
+
+    async fn list_containers(&self) -> Result<Vec<ContainerListEntry>> {
+        let output = self
+            .nerdctl(&["ps", "--all", "--format", "{{json .}}"])
+            .await?;
+
+        output
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| Ok(serde_json::from_slice(line)?))
+            .collect()
+    }
+
+    pub async fn image_exists(&self, image: &str) -> Result<bool> {
+        match self.inspect::<ImageInspect>("image", image).await {
+            Ok(_) => Ok(true),
+            Err(error) if is_not_found(&error) => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    async fn login(&self, image: &str, credentials: &DockerCredentials) -> Result<()> {
+        let registry = image_registry(image);
+        let username = credentials
+            .username
+            .as_deref()
+            .ok_or_else(|| anyhow!("Registry credentials for {} have no username.", registry))?;
+        let password = credentials
+            .password
+            .as_deref()
+            .ok_or_else(|| anyhow!("Registry credentials for {} have no password.", registry))?;
+
+        let mut child = self
+            .command()
+            .args([
+                "login",
+                "--username",
+                username,
+                "--password-stdin",
+                registry,
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Error running nerdctl.")?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(password.as_bytes()).await?;
+        }
+
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Logging in to {} failed: {}",
+                registry,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn create_isolated_network(&self, name: &str) -> Result<()> {
+        let label = format!("{}=true", MANAGED_LABEL);
+        match self
+            .nerdctl(&["network", "create", "--label", &label, name])
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(error) if error.to_string().contains("already exists") => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    async fn get_published_port(&self, container_name: &str, container_port: &str) -> Option<u16> {
+        let inspect = self.inspect_container(container_name).await.ok()?;
+
+        let port = inspect
+            .network_settings
+            .as_ref()?
+            .ports
+            .as_ref()?
+            .get(container_port)?
+            .as_ref()?
+            .first()?
+            .host_port
+            .as_ref()?;
+
+        port.parse().ok()
+    }
+
+    /// The arguments to `nerdctl run` for the given spawn request, excluding the image.
+    ///
+    /// Unlike the Docker API, nerdctl reads seccomp profiles from files, so the
+    /// profile in `security_options` is given separately as a path (or `unconfined`).
+    fn run_args(
+        &self,
+        name: &str,
+        spawn_request: &SpawnRequest,
+        security_options: &SecurityOptions,
+        seccomp_profile_path: Option<String>,
+        pids_limit: Option<i64>,
+    ) -> Vec<String> {
+        let mut args: Vec<String> = vec![
+            "run".to_string(),
+            "--detach".to_string(),
+            "--name".to_string(),
+            name.to_string(),
+            "--restart".to_string(),
+            restart_flag(spawn_request.restart_policy),
+        ];
+
+        for (key, value) in container_labels(name, spawn_request) {
+            args.push("--label".to_string());
+            args.push(format!("{}={}", key, value));
+        }
+
+        for (key, value) in &spawn_request.env {
+            args.push("--env".to_string());
+            args.push(format!("{}={}", key, value));
+        }
+
+        // Publishing a container port alone assigns it a random host port.
+        let container_ports = std::iter::once(tcp_port(self.container_port(spawn_request)))
+            .chain(
+                spawn_request
+                    .additional_ports
+                    .values()
+                    .chain(spawn_request.tcp_ports.values())
+                    .copied()
+                    .map(tcp_port),
+            )
+            .chain(spawn_request.udp_ports.values().copied().map(udp_port));
+        for port in container_ports {
+            args.push("--publish".to_string());
+            args.push(port);
+        }
+
+        match self.network(spawn_request) {
+            BackendNetwork::Default => (),
+            BackendNetwork::Named(network_name) => {
+                args.push("--network".to_string());
+                args.push(network_name);
+            }
+            BackendNetwork::Isolated => {
+                // The network shares the container's name.
+                args.push("--network".to_string());
+                args.push(name.to_string());
+            }
+        }
+
+        if let Some(runtime) = &self.runtime {
+            args.push("--runtime".to_string());
+            args.push(runtime.clone());
+        }
+
+        let resource_limits = &spawn_request.resource_limits;
+        let limits = [
+            (
+                "--cpu-period",
+                resource_limits.cpu_period.map(duration_as_micros),
+            ),
+            (
+                "--cpu-quota",
+                resource_limits.cpu_quota.map(duration_as_micros),
+            ),
+            ("--cpu-shares", cpu_shares(resource_limits)),
+            ("--memory", resource_limits.memory_limit_bytes),
+            ("--memory-swap", resource_limits.memory_swap_limit_bytes),
+        ];
+        for (flag, limit) in limits {
+            if let Some(limit) = limit {
+                args.push(flag.to_string());
+                args.push(limit.to_string());
+            }
+        }
+
+        if spawn_request.init.unwrap_or(self.init) {
+            args.push("--init".to_string());
+        }
+
+        if let Some(pids_limit) = pids_limit {
+            args.push("--pids-limit".to_string());
+            args.push(pids_limit.to_string());
+        }
+
+        if let Some(stop_signal) = &spawn_request.stop_signal {
+            args.push("--stop-signal".to_string());
+            args.push(stop_signal.clone());
+        }
+
+        if let Some(shm_size) = spawn_request.shm_size_bytes {
+            args.push("--shm-size".to_string());
+            args.push(shm_size.to_string());
+        }
+
+        for (name, ulimit) in self.ulimits.overridden_by(&spawn_request.ulimits).named() {
+            args.push("--ulimit".to_string());
+            args.push(format!("{}={}:{}", name, ulimit.soft, ulimit.hard));
+        }
+
+        for volume_mount in &spawn_request.volume_mounts {
+            let mut volume = format!("{}:{}", volume_mount.source, volume_mount.target);
+            if volume_mount.read_only {
+                volume.push_str(":ro");
+            }
+            args.push("--volume".to_string());
+            args.push(volume);
+        }
+
+        for tmpfs_mount in &spawn_request.tmpfs_mounts {
+            let mut options = Vec::new();
+            if let Some(size_bytes) = tmpfs_mount.size_bytes {
+                options.push(format!("size={}", size_bytes));
+            }
+            if let Some(mode) = tmpfs_mount.mode {
+                options.push(format!("mode={:o}", mode));
+            }
+
+            args.push("--tmpfs".to_string());
+            if options.is_empty() {
+                args.push(tmpfs_mount.target.clone());
+            } else {
+                args.push(format!("{}:{}", tmpfs_mount.target, options.join(",")));
+            }
+        }
+
+        if spawn_request.read_only_root_filesystem {
+            args.push("--read-only".to_string());
+        }
+
+        if let Some(profile) = seccomp_profile_path {
+            args.push("--security-opt".to_string());
+            args.push(format!("seccomp={}", profile));
+        }
+        if let Some(profile) = &security_options.apparmor_profile {
+            args.push("--security-opt".to_string());
+            args.push(format!("apparmor={}", profile));
+        }
+        if security_options.no_new_privileges {
+            args.push("--security-opt".to_string());
+            args.push("no-new-privileges".to_string());
+        }
+        for capability in &security_options.cap_drop {
+            args.push("--cap-drop".to_string());
+            args.push(capability.clone());
+        }
+
+        if let Some(user) = &spawn_request.user {
+            args.push("--user".to_string());
+            args.push(user.clone());
+        }
+
+        args
+    }
+}
+
+#[async_trait]
+impl Engine for ContainerdInterface {
+    fn container_events(&self) -> BoxStream<'_, ContainerEvent> {
+        Box::pin(stream!({
+            let mut interval = tokio::time::interval(EVENT_POLL_INTERVAL);
+            let mut was_running: HashMap<String, bool> = HashMap::new();
+
+            loop {
+                interval.tick().await;
+
+                let containers = match self.list_containers().await {
+                    Ok(containers) => containers,
+                    Err(error) => {
+                        tracing::error!(?error, "Error tracking container terminations.");
+                        continue;
+                    }
+                };
+
+                let mut is_running = HashMap::new();
+                for container in containers {
+                    let running = container.status.starts_with("Up");
+                    let event = match (was_running.get(&container.names), running) {
+                        (Some(true), false) => Some(ContainerEventType::Die),
+                        (Some(false) | None, true) => Some(ContainerEventType::Start),
+                        _ => None,
+                    };
+
+                    if let Some(event) = event {
+                        yield ContainerEvent {
+                            event,
+                            name: container.names.clone(),
+                        };
+                    }
+
+                    is_running.insert(container.names, running);
+                }
+
+                // Containers which were removed while running have died.
+                for (name, running) in &was_running {
+                    if *running && !is_running.contains_key(name) {
+                        yield ContainerEvent {
+                            event: ContainerEventType::Die,
+                            name: name.clone(),
+                        };
+                    }
+                }
+
+                was_running = is_running;
+            }
+        }))
+    }
+
+    fn get_logs(&self, container_name: &str, since: i64) -> BoxStream<'_, Result<LogOutput>> {
+        let mut command = self.command();
+        command.args(["logs", "--follow", "--timestamps"]);
+        if since != 0 {
+            command.arg("--since").arg(since.to_string());
+        }
+        command
+            .arg(container_name)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        Box::pin(stream!({
+            let mut child = match command.spawn() {
+                Ok(child) => child,
+                Err(error) => {
+                    yield Err(anyhow::Error::from(error).context("Error running nerdctl."));
+                    return;
+                }
+            };
+
+            let (stdout, stderr) = match (child.stdout.take(), child.stderr.take()) {
+                (Some(stdout), Some(stderr)) => (stdout, stderr),
+                _ => {
+                    yield Err(anyhow!("Log output of nerdctl was not captured."));
+                    return;
+                }
+            };
+
+            let stdout = LinesStream::new(BufReader::new(stdout).lines()).map(|line| {
+                line.map(|line| LogOutput::StdOut {
+                    message: format!("{}\n", line).into(),
+                })
+            });
+            let stderr = LinesStream::new(BufReader::new(stderr).lines()).map(|line| {
+                line.map(|line| LogOutput::StdErr {
+                    message: format!("{}\n", line).into(),
+                })
+            });
+
+            let mut lines = stdout.merge(stderr);
+            while let Some(line) = lines.next().await {
+                yield line.map_err(anyhow::Error::from);
+            }
+
+            // Keep the child process alive until its output has been consumed.
+            drop(child);
+        }))
+    }
+
+    async fn pull_image(
+        &self,
+        image: &str,
+        credentials: &Option<DockerCredentials>,
+        pull_policy: PullPolicy,
+    ) -> Result<()> {
+        match pull_policy {
+            PullPolicy::Always => (),
+            PullPolicy::IfNotPresent => {
+                if self.image_exists(image).await? {
+                    tracing::info!(%image, "Image already present, skipping pull.");
+                    return Ok(());
+                }
+            }
+            PullPolicy::Never => {
+                if self.image_exists(image).await? {
+                    return Ok(());
+                }
+
+                return Err(anyhow!(
+                    "Image {} is not present and pull policy is Never.",
+                    image
+                ));
+            }
+        }
+
+        let credentials = credentials.clone().or_else(|| {
+            self.registry_credentials
+                .get(image_registry(image))
+                .cloned()
+        });
+        if let Some(credentials) = credentials {
+            self.login(image, &credentials).await?;
+        }
+
+        self.nerdctl(&["pull", "--quiet", image]).await?;
+
+        Ok(())
+    }
+
+    async fn run_container(&self, name: &str, spawn_request: &SpawnRequest) -> Result<()> {
+        if spawn_request.gpus > 0 {
+            return Err(anyhow!(
+                "GPUs are not supported when running backends with containerd."
+            ));
+        }
+        check_shm_size(spawn_request.shm_size_bytes, self.max_shm_size_bytes)?;
+        check_bind_mounts(&spawn_request.volume_mounts, &self.bind_mount_sources)?;
+        let pids_limit = pids_limit(
+            spawn_request.pids_limit,
+            self.pids_limit,
+            self.max_pids_limit,
+        )?;
+
+        if self.forbid_root {
+            let user = match &spawn_request.user {
+                Some(user) => user.clone(),
+                None => self
+                    .inspect::<ImageInspect>("image", &spawn_request.image)
+                    .await?
+                    .config
+                    .and_then(|config| config.user)
+                    .unwrap_or_default(),
+            };
+
+            if is_root_user(&user) {
+                return Err(anyhow!(
+                    "Refusing to run image {} as root. Specify a non-root user in the spawn request.",
+                    spawn_request.image
+                ));
+            }
+        }
+
+        if self.network(spawn_request) == BackendNetwork::Isolated {
+            self.create_isolated_network(name).await?;
+        }
+
+        let security_options = merge_security_options(
+            &self.security_options,
+            &spawn_request.security_options,
+            self.allow_profile_overrides,
+        )?;
+        let seccomp_profile_path = match security_options.seccomp_profile.as_deref() {
+            None => None,
+            Some("unconfined") => Some("unconfined".to_string()),
+            Some(profile) => {
+                let path = std::env::temp_dir().join(format!("{}-seccomp.json", name));
+                tokio::fs::write(&path, profile)
+                    .await
+                    .context("Error writing seccomp profile.")?;
+                Some(path.to_string_lossy().to_string())
+            }
+        };
+
+        let mut args = self.run_args(
+            name,
+            spawn_request,
+            &security_options,
+            seccomp_profile_path,
+            pids_limit,
+        );
+        args.push(spawn_request.image.clone());
+        self.nerdctl(&args).await?;
+
+        if let Some(burst) = spawn_request.resource_limits.cpu_burst {
+            set_cpu_burst(self.container_pid(name).await?, burst).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn stop_container(&self, name: &str, grace_period: Duration) -> Result<()> {
+        let time = grace_period.as_secs().to_string();
+        self.nerdctl(&["stop", "--time", &time, name]).await?;
+
+        Ok(())
+    }
+
+    async fn remove_container(&self, name: &str) -> Result<()> {
+        match self.nerdctl(&["rm", "--force", name]).await {
+            Ok(_) => Ok(()),
+            Err(error) if is_not_found(&error) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    async fn rename_container(&self, name: &str, new_name: &str) -> Result<()> {
+        self.nerdctl(&["rename", name, new_name]).await?;
+
+        Ok(())
+    }
+
+    async fn is_restarting(&self, container_name: &str) -> Result<bool> {
+        let container = match self.inspect_container(container_name).await {
+            Ok(container) => container,
+            Err(error) if is_not_found(&error) => return Ok(false),
+            Err(error) => return Err(error),
+        };
+
+        Ok(container
+            .state
+            .and_then(|state| state.restarting)
+            .unwrap_or(false))
+    }
+
+    async fn is_running(&self, container_name: &str) -> Result<(bool, Option<i64>)> {
+        let container = match self.inspect_container(container_name).await {
+            Ok(container) => container,
+            Err(error) if is_not_found(&error) => return Ok((false, None)),
+            Err(error) => return Err(error),
+        };
+        let state = container
+            .state
+            .ok_or_else(|| anyhow!("No state found for container."))?;
+
+        let running = state
+            .running
+            .ok_or_else(|| anyhow!("State found but no running field for container."))?;
+
+        let exit_code = if running { None } else { state.exit_code };
+
+        Ok((running, exit_code))
+    }
+
+    async fn is_oom_killed(&self, container_name: &str) -> Result<bool> {
+        let container = match self.inspect_container(container_name).await {
+            Ok(container) => container,
+            Err(error) if is_not_found(&error) => return Ok(false),
+            Err(error) => return Err(error),
+        };
+
+        Ok(container
+            .state
+            .and_then(|state| state.oom_killed)
+            .unwrap_or(false))
+    }
+
+    async fn exec(
+        &self,
+        container_name: &str,
+        command: &[String],
+        output: Sender<LogOutput>,
+    ) -> Result<Option<i64>> {
+        let mut child = self
+            .command()
+            .arg("exec")
+            .arg(container_name)
+            .args(command)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Error running nerdctl.")?;
+
+        let (stdout, stderr) = match (child.stdout.take(), child.stderr.take()) {
+            (Some(stdout), Some(stderr)) => (stdout, stderr),
+            _ => return Err(anyhow!("Output of nerdctl exec was not captured.")),
+        };
+        let stdout = LinesStream::new(BufReader::new(stdout).lines()).map(|line| {
+            line.map(|line| LogOutput::StdOut {
+                message: format!("{}\n", line).into(),
+            })
+        });
+        let stderr = LinesStream::new(BufReader::new(stderr).lines()).map(|line| {
+            line.map(|line| LogOutput::StdErr {
+                message: format!("{}\n", line).into(),
+            })
+        });
+
+        let mut lines = stdout.merge(stderr);
+        while let Some(line) = lines.next().await {
+            if output.send(line?).await.is_err() {
+                // Nobody is reading the output, so stop the command rather than letting
+                // it block on a full pipe.
+                child.start_kill()?;
+                break;
+            }
+        }
+
+        let status = child.wait().await?;
+        Ok(status.code().map(i64::from))
+    }
+
+    async fn effective_resource_limits(
+        &self,
+        container_name: &str,
+    ) -> Result<Option<EffectiveResourceLimits>> {
+        effective_limits(self.container_pid(container_name).await?).await
+    }
+
+    async fn get_stats(&self, container_name: &str) -> Result<ContainerStats> {
+        usage(self.container_pid(container_name).await?).await
+    }
+
+    /// containerd does not run image health checks, so this is always None.
+    async fn get_health(&self, _container_name: &str) -> Result<Option<ContainerHealth>> {
+        Ok(None)
+    }
+
+    async fn get_port(&self, container_name: &str, container_port: u16) -> Option<u16> {
+        self.get_published_port(container_name, &tcp_port(container_port))
+            .await
+    }
+
+    async fn get_udp_port(&self, container_name: &str, container_port: u16) -> Option<u16> {
+        self.get_published_port(container_name, &udp_port(container_port))
+            .await
+    }
+
+    fn container_port(&self, spawn_request: &SpawnRequest) -> u16 {
+        spawn_request.port.unwrap_or(self.default_container_port)
+    }
+
+    fn network(&self, spawn_request: &SpawnRequest) -> BackendNetwork {
+        spawn_request
+            .network
+            .clone()
+            .unwrap_or_else(|| self.default_network.clone())
+    }
+
+    async fn remove_isolated_network(&self, name: &str) -> Result<()> {
+        match self.nerdctl(&["network", "rm", name]).await {
+            Ok(_) => Ok(()),
+            Err(error) if is_not_found(&error) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    async fn list_managed_containers(&self) -> Result<Vec<ManagedContainer>> {
+        let mut containers = Vec::new();
+        for container in self.list_containers().await? {
+            // Labels are read from `inspect`, because `ps` joins them with commas,
+            // which may also appear in their values.
+            let labels = match self.inspect_container(&container.names).await {
+                Ok(inspect) => inspect
+                    .config
+                    .and_then(|config| config.labels)
+                    .unwrap_or_default(),
+                Err(error) if is_not_found(&error) => continue,
+                Err(error) => return Err(error),
+            };
+
+            if labels.get(MANAGED_LABEL).map(String::as_str) == Some("true") {
+                containers.push(ManagedContainer {
+                    name: container.names,
+                    labels,
+                });
+            }
+        }
+
+        Ok(containers)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_restart_flag() {
+        assert_eq!("no", restart_flag(RestartPolicy::Never));
+        assert_eq!(
+            "on-failure:3",
+            restart_flag(RestartPolicy::OnFailure {
+                max_retries: Some(3)
+            })
+        );
+        assert_eq!(
+            "on-failure",
+            restart_flag(RestartPolicy::OnFailure { max_retries: None })
+        );
+        assert_eq!("unless-stopped", restart_flag(RestartPolicy::Always));
+    }
+}
@@ -1,110 +1,394 @@
-use super::DockerOptions;
+use super::{
+    cgroup::{effective_limits, set_cpu_burst},
+    engine::{
+        ContainerEvent, ContainerHealth, ContainerStats, Engine, ManagedContainer,
+        SPAWN_REQUEST_LABEL, TOKEN_PROTECTED_LABEL,
+    },
+    stats::from_docker_stats,
+    DockerOptions,
+};
+use crate::messages::agent::{
+    BackendNetwork, EffectiveResourceLimits, PullPolicy, ResourceLimits, RestartPolicy,
+    SecurityOptions, SpawnRequest, TmpfsMount, Ulimits, VolumeMount, VolumeMountKind,
+};
 use anyhow::{anyhow, Result};
+use async_trait::async_trait;
 use bollard::{
     auth::DockerCredentials,
     container::{
-        Config, CreateContainerOptions, LogOutput, LogsOptions, StartContainerOptions,
+        Config, CreateContainerOptions, ListContainersOptions, LogOutput, LogsOptions,
+        RemoveContainerOptions, RenameContainerOptions, StartContainerOptions, StatsOptions,
         StopContainerOptions,
     },
+    exec::{CreateExecOptions, StartExecResults},
     image::CreateImageOptions,
-    models::{EventMessage, HostConfig, PortBinding},
+    models::{
+        DeviceRequest, EventMessage, HealthStatusEnum, HostConfig, Mount, MountTmpfsOptions,
+        MountTypeEnum, PortBinding, ResourcesUlimits, RestartPolicyNameEnum,
+    },
+    network::CreateNetworkOptions,
     system::EventsOptions,
     Docker, API_DEFAULT_VERSION,
 };
-use std::collections::HashMap;
+use futures::stream::BoxStream;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::{mpsc::Sender, Mutex};
 use tokio_stream::{Stream, StreamExt};
 
-/// The port in the container which is exposed.
-const CONTAINER_PORT: u16 = 8080;
 const DEFAULT_DOCKER_TIMEOUT_SECONDS: u64 = 30;
 
+/// Time allowed for a stop request beyond the container's grace period.
+const STOP_REQUEST_MARGIN: Duration = Duration::from_secs(10);
+pub(super) const MANAGED_LABEL: &str = "dev.spawner.managed";
+const GPU_DRIVER: &str = "nvidia";
+
+/// Labels of a backend's container: those of its spawn request, plus the labels by which
+/// the drone identifies its containers, which the spawn request cannot override.
+pub(super) fn container_labels(
+    name: &str,
+    spawn_request: &SpawnRequest,
+) -> HashMap<String, String> {
+    let mut labels = spawn_request.labels.clone();
+    labels.insert(MANAGED_LABEL.to_string(), "true".to_string());
+    labels.insert("dev.spawner.backend".to_string(), name.to_string());
+    if spawn_request.bearer_token.is_some() || spawn_request.lease_token.is_some() {
+        labels.insert(TOKEN_PROTECTED_LABEL.to_string(), "true".to_string());
+    } else {
+        labels.remove(TOKEN_PROTECTED_LABEL);
+    }
+
+    // The environment may hold secrets resolved by the drone, and is not needed once the
+    // container exists.
+    let spawn_request = SpawnRequest {
+        credentials: None,
+        lease_token: None,
+        bearer_token: None,
+        env: HashMap::new(),
+        ..spawn_request.clone()
+    };
+    labels.insert(
+        SPAWN_REQUEST_LABEL.to_string(),
+        serde_json::to_string(&spawn_request)
+            .expect("SpawnRequest serialization should never fail."),
+    );
+
+    labels
+}
+
+/// Refuse a spawn request's `/dev/shm` size if it exceeds the drone's maximum.
+pub(super) fn check_shm_size(
+    shm_size_bytes: Option<i64>,
+    max_shm_size_bytes: Option<i64>,
+) -> Result<()> {
+    match (shm_size_bytes, max_shm_size_bytes) {
+        (Some(size), Some(max)) if size > max => Err(anyhow!(
+            "Requested /dev/shm size of {} bytes exceeds the drone's maximum of {} bytes.",
+            size,
+            max
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// The PID limit to give a spawn request's container: the requested limit if it is
+/// within the drone's maximum, otherwise the drone's default (capped by its maximum).
+/// None means unlimited.
+pub(super) fn pids_limit(
+    requested: Option<i64>,
+    default: Option<i64>,
+    max: Option<i64>,
+) -> Result<Option<i64>> {
+    match (requested, max) {
+        (Some(limit), Some(max)) if limit <= 0 || limit > max => Err(anyhow!(
+            "Requested PID limit of {} exceeds the drone's maximum of {}.",
+            limit,
+            max
+        )),
+        (Some(limit), _) => Ok(Some(limit)),
+        (None, Some(max)) => Ok(Some(default.map_or(max, |default| default.min(max)))),
+        (None, None) => Ok(default),
+    }
+}
+
+/// Registry of images whose name does not begin with a registry host.
+const DEFAULT_REGISTRY: &str = "docker.io";
+
 #[derive(Clone)]
 pub struct DockerInterface {
     docker: Docker,
     runtime: Option<String>,
+    bind_mount_sources: Vec<PathBuf>,
+
+    /// The port in the container which is exposed, unless the spawn request specifies one.
+    default_container_port: u16,
+
+    /// The network containers are attached to, unless the spawn request specifies one.
+    default_network: BackendNetwork,
+
+    /// Number of GPUs on the host, addressed by device IDs `0..gpu_count`.
+    gpu_count: u32,
+
+    /// Held while choosing GPUs for a container and starting it, so that
+    /// concurrent spawns are not assigned the same device.
+    gpu_lock: Arc<Mutex<()>>,
+
+    /// Credentials for pulling images, keyed by registry host. Used when the
+    /// spawn request does not carry its own credentials.
+    registry_credentials: Arc<HashMap<String, DockerCredentials>>,
+
+    /// Security options applied to every container, in addition to those in
+    /// the spawn request.
+    security_options: SecurityOptions,
+
+    /// Whether spawn requests may replace the profiles in `security_options`.
+    allow_profile_overrides: bool,
+
+    /// Whether to refuse to run containers whose processes would run as root.
+    forbid_root: bool,
+    ulimits: Ulimits,
+    max_shm_size_bytes: Option<i64>,
+    init: bool,
+    pids_limit: Option<i64>,
+    max_pids_limit: Option<i64>,
 }
 
-/// The list of possible container events.
-/// Comes from [Docker documentation](https://docs.docker.com/engine/reference/commandline/events/).
-#[derive(Debug, PartialEq, Eq)]
-pub enum ContainerEventType {
-    Attach,
-    Commit,
-    Copy,
-    Create,
-    Destroy,
-    Detach,
-    Die,
-    ExecCreate,
-    ExecDetach,
-    ExecDie,
-    ExecStart,
-    Export,
-    HealthStatus,
-    Kill,
-    Oom,
-    Pause,
-    Rename,
-    Resize,
-    Restart,
-    Start,
-    Stop,
-    Top,
-    Unpause,
-    Update,
+/// Return the registry host that an image reference pulls from.
+///
+/// Follows Docker's convention that the first component of the image name is a registry
+/// host only if it contains a `.` or `:`, or is `localhost`.
+pub(super) fn image_registry(image: &str) -> &str {
+    match image.split_once('/') {
+        Some((host, _)) if host.contains('.') || host.contains(':') || host == "localhost" => host,
+        _ => DEFAULT_REGISTRY,
+    }
 }
 
-#[allow(unused)]
-#[derive(Debug)]
-pub struct ContainerEvent {
-    pub event: ContainerEventType,
-    pub name: String,
+pub(super) fn tcp_port(port: u16) -> String {
+    format!("{}/tcp", port)
 }
 
-impl ContainerEvent {
-    pub fn from_event_message(event: &EventMessage) -> Option<Self> {
-        let action = event.action.as_deref()?;
-        let actor = event.actor.as_ref()?;
-        let name: String = actor.attributes.as_ref()?.get("name")?.to_string();
-
-        let event = match action {
-            "attach" => ContainerEventType::Attach,
-            "commit" => ContainerEventType::Commit,
-            "copy" => ContainerEventType::Copy,
-            "create" => ContainerEventType::Create,
-            "destroy" => ContainerEventType::Destroy,
-            "detach" => ContainerEventType::Detach,
-            "die" => ContainerEventType::Die,
-            "exec_create" => ContainerEventType::ExecCreate,
-            "exec_detach" => ContainerEventType::ExecDetach,
-            "exec_die" => ContainerEventType::ExecDie,
-            "exec_start" => ContainerEventType::ExecStart,
-            "export" => ContainerEventType::Export,
-            "health_status" => ContainerEventType::HealthStatus,
-            "kill" => ContainerEventType::Kill,
-            "oom" => ContainerEventType::Oom,
-            "pause" => ContainerEventType::Pause,
-            "rename" => ContainerEventType::Rename,
-            "resize" => ContainerEventType::Resize,
-            "restart" => ContainerEventType::Restart,
-            "start" => ContainerEventType::Start,
-            "stop" => ContainerEventType::Stop,
-            "top" => ContainerEventType::Top,
-            "unpause" => ContainerEventType::Unpause,
-            "update" => ContainerEventType::Update,
-            _ => {
-                tracing::info!(?action, "Unhandled container action.");
-                return None;
-            }
-        };
+pub(super) fn udp_port(port: u16) -> String {
+    format!("{}/udp", port)
+}
+
+/// Ports are given in Docker's `<port>/<protocol>` form.
+fn make_exposed_ports(ports: &[String]) -> Option<HashMap<String, HashMap<(), ()>>> {
+    Some(
+        ports
+            .iter()
+            .map(|port| (port.clone(), HashMap::new()))
+            .collect(),
+    )
+}
+
+/// Ports are given in Docker's `<port>/<protocol>` form.
+fn make_port_bindings(ports: &[String]) -> Option<HashMap<String, Option<Vec<PortBinding>>>> {
+    Some(
+        ports
+            .iter()
+            .map(|port| {
+                (
+                    port.clone(),
+                    Some(vec![PortBinding {
+                        host_ip: None,
+                        host_port: Some("0".to_string()),
+                    }]),
+                )
+            })
+            .collect(),
+    )
+}
+
+fn make_restart_policy(restart_policy: RestartPolicy) -> bollard::models::RestartPolicy {
+    let (name, maximum_retry_count) = match restart_policy {
+        RestartPolicy::Never => (RestartPolicyNameEnum::NO, None),
+        RestartPolicy::OnFailure { max_retries } => (
+            RestartPolicyNameEnum::ON_FAILURE,
+            max_retries.map(i64::from),
+        ),
+        RestartPolicy::Always => (RestartPolicyNameEnum::UNLESS_STOPPED, None),
+    };
+
+    bollard::models::RestartPolicy {
+        name: Some(name),
+        maximum_retry_count,
+    }
+}
+
+pub(super) fn duration_as_micros(duration: Duration) -> i64 {
+    duration.as_micros().try_into().unwrap_or(i64::MAX)
+}
+
+/// The CPU shares for the resource limits. A cgroup v2 weight is converted to the shares
+/// which runc converts back into the same weight.
+pub(super) fn cpu_shares(resource_limits: &ResourceLimits) -> Option<i64> {
+    match resource_limits.cpu_weight {
+        Some(weight) => {
+            let weight = weight.clamp(1, 10000) as i64;
+            Some(2 + ((weight - 1) * 262142 + 9998) / 9999)
+        }
+        None => resource_limits.cpu_shares,
+    }
+}
+
+/// Translate resource limits into the subset of `HostConfig` that enforces them.
+fn resource_host_config(resource_limits: &ResourceLimits) -> HostConfig {
+    HostConfig {
+        cpu_period: resource_limits.cpu_period.map(duration_as_micros),
+        cpu_quota: resource_limits.cpu_quota.map(duration_as_micros),
+        cpu_shares: cpu_shares(resource_limits),
+        memory: resource_limits.memory_limit_bytes,
+        memory_swap: resource_limits.memory_swap_limit_bytes,
+        ..HostConfig::default()
+    }
+}
+
+fn make_mount(volume_mount: &VolumeMount) -> Mount {
+    let typ = match volume_mount.kind {
+        VolumeMountKind::Volume => MountTypeEnum::VOLUME,
+        VolumeMountKind::Bind => MountTypeEnum::BIND,
+    };
+
+    Mount {
+        typ: Some(typ),
+        source: Some(volume_mount.source.clone()),
+        target: Some(volume_mount.target.clone()),
+        read_only: Some(volume_mount.read_only),
+        ..Mount::default()
+    }
+}
+
+fn make_tmpfs_mount(tmpfs_mount: &TmpfsMount) -> Mount {
+    Mount {
+        typ: Some(MountTypeEnum::TMPFS),
+        target: Some(tmpfs_mount.target.clone()),
+        tmpfs_options: Some(MountTmpfsOptions {
+            size_bytes: tmpfs_mount.size_bytes,
+            mode: tmpfs_mount.mode.map(i64::from),
+        }),
+        ..Mount::default()
+    }
+}
+
+/// Refuse a spawn request's bind mounts unless each source is an absolute path within
+/// one of the drone's allowed directories.
+pub(super) fn check_bind_mounts(
+    volume_mounts: &[VolumeMount],
+    bind_mount_sources: &[PathBuf],
+) -> Result<()> {
+    for volume_mount in volume_mounts {
+        if volume_mount.kind != VolumeMountKind::Bind {
+            continue;
+        }
+
+        let source = Path::new(&volume_mount.source);
+        let allowed = source.is_absolute()
+            && !source
+                .components()
+                .any(|component| component == Component::ParentDir)
+            && bind_mount_sources
+                .iter()
+                .any(|allowed| source.starts_with(allowed));
+        if !allowed {
+            return Err(anyhow!(
+                "Bind mount of {} is not within a directory the drone allows bind mounts from.",
+                volume_mount.source
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Combine the drone's security options with a backend's. The drone's profiles are a
+/// floor: unless `allow_profile_overrides` is set, a backend may not ask for a different
+/// profile, including `unconfined`. Privilege and capability restrictions accumulate.
+pub(super) fn merge_security_options(
+    drone: &SecurityOptions,
+    backend: &SecurityOptions,
+    allow_profile_overrides: bool,
+) -> Result<SecurityOptions> {
+    let mut cap_drop = drone.cap_drop.clone();
+    for capability in &backend.cap_drop {
+        if !cap_drop.contains(capability) {
+            cap_drop.push(capability.clone());
+        }
+    }
+
+    Ok(SecurityOptions {
+        seccomp_profile: merge_profile(
+            "seccomp",
+            &drone.seccomp_profile,
+            &backend.seccomp_profile,
+            allow_profile_overrides,
+        )?,
+        apparmor_profile: merge_profile(
+            "AppArmor",
+            &drone.apparmor_profile,
+            &backend.apparmor_profile,
+            allow_profile_overrides,
+        )?,
+        no_new_privileges: drone.no_new_privileges || backend.no_new_privileges,
+        cap_drop,
+    })
+}
 
-        Some(ContainerEvent { event, name })
+fn merge_profile(
+    kind: &str,
+    drone: &Option<String>,
+    backend: &Option<String>,
+    allow_profile_overrides: bool,
+) -> Result<Option<String>> {
+    match backend {
+        Some(profile) if Some(profile) != drone.as_ref() && !allow_profile_overrides => {
+            Err(anyhow!(
+                "The drone does not allow spawn requests to change its {} profile.",
+                kind
+            ))
+        }
+        Some(profile) => Ok(Some(profile.clone())),
+        None => Ok(drone.clone()),
     }
 }
 
-fn make_exposed_ports(port: u16) -> Option<HashMap<String, HashMap<(), ()>>> {
-    let dummy: HashMap<(), ()> = vec![].into_iter().collect();
-    Some(vec![(format!("{}/tcp", port), dummy)].into_iter().collect())
+/// Whether a container `user` setting (`user`, `user:group`, or empty for the
+/// image's default) refers to the root user.
+pub(super) fn is_root_user(user: &str) -> bool {
+    let user = user.split(':').next().unwrap_or_default();
+    user.is_empty() || user == "root" || user.parse::<u32>() == Ok(0)
+}
+
+fn make_ulimits(ulimits: &Ulimits) -> Vec<ResourcesUlimits> {
+    ulimits
+        .named()
+        .into_iter()
+        .map(|(name, ulimit)| ResourcesUlimits {
+            name: Some(name.to_string()),
+            soft: Some(ulimit.soft),
+            hard: Some(ulimit.hard),
+        })
+        .collect()
+}
+
+/// Docker's `security_opt` entries for the given security options.
+fn make_security_opt(security_options: &SecurityOptions) -> Vec<String> {
+    let mut security_opt = Vec::new();
+    if let Some(profile) = &security_options.seccomp_profile {
+        security_opt.push(format!("seccomp={}", profile));
+    }
+    if let Some(profile) = &security_options.apparmor_profile {
+        security_opt.push(format!("apparmor={}", profile));
+    }
+    if security_options.no_new_privileges {
+        security_opt.push("no-new-privileges:true".to_string());
+    }
+
+    security_opt
 }
 
 impl DockerInterface {
@@ -124,11 +408,26 @@ impl DockerInterface {
 
         Ok(DockerInterface {
             docker,
+            registry_credentials: Arc::new(config.load_registry_credentials()?),
+            security_options: config.load_security_options()?,
+            allow_profile_overrides: config.allow_profile_overrides,
+            forbid_root: config.forbid_root,
+            ulimits: config.ulimits,
+            max_shm_size_bytes: config.max_shm_size_bytes,
+            init: config.init,
+            pids_limit: config.pids_limit,
+            max_pids_limit: config.max_pids_limit,
             runtime: config.runtime.clone(),
+            bind_mount_sources: config.bind_mount_sources.clone(),
+            default_container_port: config.default_container_port,
+            default_network: config.default_network.clone(),
+            gpu_count: config.gpu_count,
+            gpu_lock: Arc::default(),
         })
     }
 
-    pub async fn container_events(&self) -> impl Stream<Item = ContainerEvent> {
+    /// Stream events of all containers on the host, as reported by the Docker API.
+    pub fn event_messages(&self) -> impl Stream<Item = EventMessage> + Send + '_ {
         let options: EventsOptions<&str> = EventsOptions {
             since: None,
             until: None,
@@ -137,7 +436,7 @@ impl DockerInterface {
         self.docker
             .events(Some(options))
             .filter_map(|event| match event {
-                Ok(event) => ContainerEvent::from_event_message(&event),
+                Ok(event) => Some(event),
                 Err(error) => {
                     tracing::error!(?error, "Error tracking container terminations.");
                     None
@@ -145,73 +444,62 @@ impl DockerInterface {
             })
     }
 
-    pub fn get_logs(
-        &self,
-        container_name: &str,
-    ) -> impl Stream<Item = Result<LogOutput, bollard::errors::Error>> {
-        self.docker.logs(
-            container_name,
-            Some(LogsOptions {
-                follow: true,
-                stdout: true,
-                stderr: true,
-                since: 0,
-                until: 0,
-                timestamps: true,
-                tail: "all",
-            }),
-        )
-    }
-
-    #[allow(unused)]
-    pub async fn pull_image(
-        &self,
-        image: &str,
-        credentials: &Option<DockerCredentials>,
-    ) -> Result<()> {
-        let options = Some(CreateImageOptions {
-            from_image: image,
-            ..Default::default()
-        });
-
-        let mut result = self.docker.create_image(options, None, credentials.clone());
-        while let Some(next) = result.next().await {
-            next?;
+    /// Returns true if the image is present on the host.
+    pub async fn image_exists(&self, image: &str) -> Result<bool> {
+        match self.docker.inspect_image(image).await {
+            Ok(_) => Ok(true),
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => Ok(false),
+            Err(err) => Err(err.into()),
         }
-
-        Ok(())
     }
 
-    pub async fn stop_container(&self, name: &str) -> Result<()> {
-        let options = StopContainerOptions { t: 10 };
-
-        self.docker.stop_container(name, Some(options)).await?;
-
-        Ok(())
+    /// The user the image's processes run as by default, or an empty string for root.
+    async fn image_user(&self, image: &str) -> Result<String> {
+        Ok(self
+            .docker
+            .inspect_image(image)
+            .await?
+            .config
+            .and_then(|config| config.user)
+            .unwrap_or_default())
     }
 
-    pub async fn is_running(&self, container_name: &str) -> Result<(bool, Option<i64>)> {
-        let container = match self.docker.inspect_container(container_name, None).await {
-            Ok(container) => container,
-            Err(bollard::errors::Error::DockerResponseServerError {
-                status_code: 404, ..
-            }) => return Ok((false, None)),
-            Err(err) => return Err(err.into()),
-        };
-        let state = container
+    /// The host PID of the container's main process.
+    async fn container_pid(&self, container_name: &str) -> Result<i64> {
+        self.docker
+            .inspect_container(container_name, None)
+            .await?
             .state
-            .ok_or_else(|| anyhow!("No state found for container."))?;
-
-        let running = state
-            .running
-            .ok_or_else(|| anyhow!("State found but no running field for container."))?;
+            .and_then(|state| state.pid)
+            .filter(|pid| *pid > 0)
+            .ok_or_else(|| anyhow!("Container {} is not running.", container_name))
+    }
 
-        let exit_code = if running { None } else { state.exit_code };
+    /// Create a bridge network for the exclusive use of one container.
+    ///
+    /// Succeeds if the network already exists, so that a backend can be re-run after
+    /// an interrupted start.
+    async fn create_isolated_network(&self, name: &str) -> Result<()> {
+        let options = CreateNetworkOptions {
+            name,
+            check_duplicate: true,
+            driver: "bridge",
+            labels: vec![(MANAGED_LABEL, "true")].into_iter().collect(),
+            ..CreateNetworkOptions::default()
+        };
 
-        Ok((running, exit_code))
+        match self.docker.create_network(options).await {
+            Ok(_)
+            | Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 409, ..
+            }) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
     }
 
-    pub async fn get_port(&self, container_name: &str) -> Option<u16> {
+    async fn get_published_port(&self, container_name: &str, container_port: &str) -> Option<u16> {
         let inspect = self
             .docker
             .inspect_container(container_name, None)
@@ -223,7 +511,7 @@ impl DockerInterface {
             .as_ref()?
             .ports
             .as_ref()?
-            .get(&format!("{}/tcp", CONTAINER_PORT))?
+            .get(container_port)?
             .as_ref()?
             .first()?
             .host_port
@@ -232,14 +520,214 @@ impl DockerInterface {
         port.parse().ok()
     }
 
-    /// Run the specified image and return the name of the created container.
-    pub async fn run_container(
+    /// Return the device IDs of GPUs assigned to running containers managed by spawner.
+    ///
+    /// This is derived from Docker rather than tracked in memory so that assignments
+    /// survive restarts of the agent.
+    async fn gpus_in_use(&self) -> Result<HashSet<String>> {
+        let label_filter = format!("{}=true", MANAGED_LABEL);
+        let options = ListContainersOptions {
+            filters: vec![("label", vec![label_filter.as_str()])]
+                .into_iter()
+                .collect(),
+            ..ListContainersOptions::default()
+        };
+
+        let mut in_use = HashSet::new();
+        for container in self.docker.list_containers(Some(options)).await? {
+            let container_id = if let Some(container_id) = container.id {
+                container_id
+            } else {
+                continue;
+            };
+
+            let device_requests = self
+                .docker
+                .inspect_container(&container_id, None)
+                .await?
+                .host_config
+                .and_then(|host_config| host_config.device_requests)
+                .unwrap_or_default();
+
+            for device_request in device_requests {
+                in_use.extend(device_request.device_ids.unwrap_or_default());
+            }
+        }
+
+        Ok(in_use)
+    }
+
+    /// Choose `count` GPUs which are not assigned to any running container.
+    async fn allocate_gpus(&self, count: u32) -> Result<Vec<String>> {
+        let in_use = self.gpus_in_use().await?;
+        let device_ids: Vec<String> = (0..self.gpu_count)
+            .map(|device_id| device_id.to_string())
+            .filter(|device_id| !in_use.contains(device_id))
+            .take(count as usize)
+            .collect();
+
+        if device_ids.len() < count as usize {
+            return Err(anyhow!(
+                "Requested {} GPUs, but only {} of {} are unassigned.",
+                count,
+                device_ids.len(),
+                self.gpu_count
+            ));
+        }
+
+        Ok(device_ids)
+    }
+}
+
+#[async_trait]
+impl Engine for DockerInterface {
+    fn container_events(&self) -> BoxStream<'_, ContainerEvent> {
+        Box::pin(
+            self.event_messages()
+                .filter_map(|event| ContainerEvent::from_event_message(&event)),
+        )
+    }
+
+    fn get_logs(&self, container_name: &str, since: i64) -> BoxStream<'_, Result<LogOutput>> {
+        Box::pin(
+            self.docker
+                .logs(
+                    container_name,
+                    Some(LogsOptions {
+                        follow: true,
+                        stdout: true,
+                        stderr: true,
+                        since,
+                        until: 0,
+                        timestamps: true,
+                        tail: "all",
+                    }),
+                )
+                .map(|log| log.map_err(anyhow::Error::from)),
+        )
+    }
+
+    /// Pull the image from its registry, subject to the given pull policy.
+    ///
+    /// If no credentials are given, credentials configured on the drone for the
+    /// image's registry are used, if any.
+    async fn pull_image(
         &self,
-        name: &str,
         image: &str,
-        env: &HashMap<String, String>,
+        credentials: &Option<DockerCredentials>,
+        pull_policy: PullPolicy,
     ) -> Result<()> {
-        let env: Vec<String> = env.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        match pull_policy {
+            PullPolicy::Always => (),
+            PullPolicy::IfNotPresent => {
+                if self.image_exists(image).await? {
+                    tracing::info!(%image, "Image already present, skipping pull.");
+                    return Ok(());
+                }
+            }
+            PullPolicy::Never => {
+                if self.image_exists(image).await? {
+                    return Ok(());
+                }
+
+                return Err(anyhow!(
+                    "Image {} is not present and pull policy is Never.",
+                    image
+                ));
+            }
+        }
+
+        let credentials = credentials.clone().or_else(|| {
+            self.registry_credentials
+                .get(image_registry(image))
+                .cloned()
+        });
+
+        let options = Some(CreateImageOptions {
+            from_image: image,
+            ..Default::default()
+        });
+
+        let mut result = self.docker.create_image(options, None, credentials);
+        while let Some(next) = result.next().await {
+            next?;
+        }
+
+        Ok(())
+    }
+
+    async fn run_container(&self, name: &str, spawn_request: &SpawnRequest) -> Result<()> {
+        check_bind_mounts(&spawn_request.volume_mounts, &self.bind_mount_sources)?;
+
+        if self.forbid_root {
+            let user = match &spawn_request.user {
+                Some(user) => user.clone(),
+                None => self.image_user(&spawn_request.image).await?,
+            };
+
+            if is_root_user(&user) {
+                return Err(anyhow!(
+                    "Refusing to run image {} as root. Specify a non-root user in the spawn request.",
+                    spawn_request.image
+                ));
+            }
+        }
+
+        check_shm_size(spawn_request.shm_size_bytes, self.max_shm_size_bytes)?;
+        let pids_limit = pids_limit(
+            spawn_request.pids_limit,
+            self.pids_limit,
+            self.max_pids_limit,
+        )?;
+
+        let env: Vec<String> = spawn_request
+            .env
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+        let mut container_ports = vec![tcp_port(self.container_port(spawn_request))];
+        container_ports.extend(
+            spawn_request
+                .additional_ports
+                .values()
+                .chain(spawn_request.tcp_ports.values())
+                .copied()
+                .map(tcp_port),
+        );
+        container_ports.extend(spawn_request.udp_ports.values().copied().map(udp_port));
+
+        let _gpu_guard = if spawn_request.gpus > 0 {
+            Some(self.gpu_lock.lock().await)
+        } else {
+            None
+        };
+
+        let device_requests = if spawn_request.gpus > 0 {
+            Some(vec![DeviceRequest {
+                driver: Some(GPU_DRIVER.to_string()),
+                device_ids: Some(self.allocate_gpus(spawn_request.gpus).await?),
+                capabilities: Some(vec![vec!["gpu".to_string()]]),
+                ..DeviceRequest::default()
+            }])
+        } else {
+            None
+        };
+
+        let network_mode = match self.network(spawn_request) {
+            BackendNetwork::Default => None,
+            BackendNetwork::Named(network_name) => Some(network_name),
+            BackendNetwork::Isolated => {
+                // The network shares the container's name.
+                self.create_isolated_network(name).await?;
+                Some(name.to_string())
+            }
+        };
+
+        let security_options = merge_security_options(
+            &self.security_options,
+            &spawn_request.security_options,
+            self.allow_profile_overrides,
+        )?;
 
         // Build the container.
         let container_id = {
@@ -248,31 +736,36 @@ impl DockerInterface {
             });
 
             let config: Config<String> = Config {
-                image: Some(image.to_string()),
+                image: Some(spawn_request.image.clone()),
                 env: Some(env),
-                exposed_ports: make_exposed_ports(CONTAINER_PORT),
-                labels: Some(
-                    vec![
-                        ("dev.spawner.managed".to_string(), "true".to_string()),
-                        ("dev.spawner.backend".to_string(), name.to_string()),
-                    ]
-                    .into_iter()
-                    .collect(),
-                ),
+                exposed_ports: make_exposed_ports(&container_ports),
+                user: spawn_request.user.clone(),
+                labels: Some(container_labels(name, spawn_request)),
+                stop_signal: spawn_request.stop_signal.clone(),
                 host_config: Some(HostConfig {
-                    port_bindings: Some(
-                        vec![(
-                            format!("{}/tcp", CONTAINER_PORT),
-                            Some(vec![PortBinding {
-                                host_ip: None,
-                                host_port: Some("0".to_string()),
-                            }]),
-                        )]
-                        .into_iter()
-                        .collect(),
-                    ),
+                    port_bindings: make_port_bindings(&container_ports),
                     runtime: self.runtime.clone(),
-                    ..HostConfig::default()
+                    device_requests,
+                    network_mode,
+                    restart_policy: Some(make_restart_policy(spawn_request.restart_policy)),
+                    mounts: Some(
+                        spawn_request
+                            .volume_mounts
+                            .iter()
+                            .map(make_mount)
+                            .chain(spawn_request.tmpfs_mounts.iter().map(make_tmpfs_mount))
+                            .collect(),
+                    ),
+                    readonly_rootfs: Some(spawn_request.read_only_root_filesystem),
+                    shm_size: spawn_request.shm_size_bytes,
+                    init: Some(spawn_request.init.unwrap_or(self.init)),
+                    pids_limit,
+                    security_opt: Some(make_security_opt(&security_options)),
+                    cap_drop: Some(security_options.cap_drop),
+                    ulimits: Some(make_ulimits(
+                        &self.ulimits.overridden_by(&spawn_request.ulimits),
+                    )),
+                    ..resource_host_config(&spawn_request.resource_limits)
                 }),
                 ..Config::default()
             };
@@ -288,6 +781,459 @@ impl DockerInterface {
             self.docker.start_container(&container_id, options).await?;
         };
 
+        if let Some(burst) = spawn_request.resource_limits.cpu_burst {
+            set_cpu_burst(self.container_pid(name).await?, burst).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn stop_container(&self, name: &str, grace_period: Duration) -> Result<()> {
+        let options = StopContainerOptions {
+            t: grace_period.as_secs() as i64,
+        };
+
+        // The request only completes once the container has stopped, so it must be
+        // allowed to take longer than the grace period.
+        let timeout = self
+            .docker
+            .timeout()
+            .max(grace_period + STOP_REQUEST_MARGIN);
+        self.docker
+            .clone()
+            .with_timeout(timeout)
+            .stop_container(name, Some(options))
+            .await?;
+
         Ok(())
     }
+
+    async fn remove_container(&self, name: &str) -> Result<()> {
+        let options = RemoveContainerOptions {
+            force: true,
+            ..RemoveContainerOptions::default()
+        };
+
+        match self.docker.remove_container(name, Some(options)).await {
+            Ok(()) => Ok(()),
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn rename_container(&self, name: &str, new_name: &str) -> Result<()> {
+        let options = RenameContainerOptions { name: new_name };
+
+        self.docker.rename_container(name, options).await?;
+
+        Ok(())
+    }
+
+    /// Returns true if the container has exited and Docker is waiting to restart it
+    /// according to its restart policy. Docker reports such containers as running.
+    async fn is_restarting(&self, container_name: &str) -> Result<bool> {
+        let container = match self.docker.inspect_container(container_name, None).await {
+            Ok(container) => container,
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(container
+            .state
+            .and_then(|state| state.restarting)
+            .unwrap_or(false))
+    }
+
+    async fn is_running(&self, container_name: &str) -> Result<(bool, Option<i64>)> {
+        let container = match self.docker.inspect_container(container_name, None).await {
+            Ok(container) => container,
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => return Ok((false, None)),
+            Err(err) => return Err(err.into()),
+        };
+        let state = container
+            .state
+            .ok_or_else(|| anyhow!("No state found for container."))?;
+
+        let running = state
+            .running
+            .ok_or_else(|| anyhow!("State found but no running field for container."))?;
+
+        let exit_code = if running { None } else { state.exit_code };
+
+        Ok((running, exit_code))
+    }
+
+    async fn is_oom_killed(&self, container_name: &str) -> Result<bool> {
+        let container = match self.docker.inspect_container(container_name, None).await {
+            Ok(container) => container,
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(container
+            .state
+            .and_then(|state| state.oom_killed)
+            .unwrap_or(false))
+    }
+
+    async fn exec(
+        &self,
+        container_name: &str,
+        command: &[String],
+        output: Sender<LogOutput>,
+    ) -> Result<Option<i64>> {
+        let options = CreateExecOptions {
+            cmd: Some(command.to_vec()),
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            ..CreateExecOptions::default()
+        };
+        let exec_id = self.docker.create_exec(container_name, options).await?.id;
+
+        if let StartExecResults::Attached {
+            output: mut stream, ..
+        } = self.docker.start_exec(&exec_id, None).await?
+        {
+            while let Some(message) = stream.next().await {
+                if output.send(message?).await.is_err() {
+                    // The receiver is gone, so there is nobody to send the rest of the output to.
+                    break;
+                }
+            }
+        }
+
+        Ok(self.docker.inspect_exec(&exec_id).await?.exit_code)
+    }
+
+    async fn effective_resource_limits(
+        &self,
+        container_name: &str,
+    ) -> Result<Option<EffectiveResourceLimits>> {
+        effective_limits(self.container_pid(container_name).await?).await
+    }
+
+    async fn get_stats(&self, container_name: &str) -> Result<ContainerStats> {
+        let options = StatsOptions {
+            stream: false,
+            one_shot: true,
+        };
+        let stats = self
+            .docker
+            .stats(container_name, Some(options))
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("No stats returned for container {}.", container_name))??;
+
+        Ok(from_docker_stats(&stats))
+    }
+
+    async fn get_health(&self, container_name: &str) -> Result<Option<ContainerHealth>> {
+        let status = self
+            .docker
+            .inspect_container(container_name, None)
+            .await?
+            .state
+            .and_then(|state| state.health)
+            .and_then(|health| health.status);
+
+        Ok(match status {
+            Some(HealthStatusEnum::STARTING) => Some(ContainerHealth::Starting),
+            Some(HealthStatusEnum::HEALTHY) => Some(ContainerHealth::Healthy),
+            Some(HealthStatusEnum::UNHEALTHY) => Some(ContainerHealth::Unhealthy),
+            Some(HealthStatusEnum::NONE) | Some(HealthStatusEnum::EMPTY) | None => None,
+        })
+    }
+
+    async fn get_port(&self, container_name: &str, container_port: u16) -> Option<u16> {
+        self.get_published_port(container_name, &tcp_port(container_port))
+            .await
+    }
+
+    async fn get_udp_port(&self, container_name: &str, container_port: u16) -> Option<u16> {
+        self.get_published_port(container_name, &udp_port(container_port))
+            .await
+    }
+
+    fn container_port(&self, spawn_request: &SpawnRequest) -> u16 {
+        spawn_request.port.unwrap_or(self.default_container_port)
+    }
+
+    fn network(&self, spawn_request: &SpawnRequest) -> BackendNetwork {
+        spawn_request
+            .network
+            .clone()
+            .unwrap_or_else(|| self.default_network.clone())
+    }
+
+    async fn remove_isolated_network(&self, name: &str) -> Result<()> {
+        match self.docker.remove_network(name).await {
+            Ok(_)
+            | Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn list_managed_containers(&self) -> Result<Vec<ManagedContainer>> {
+        let label_filter = format!("{}=true", MANAGED_LABEL);
+        let options = ListContainersOptions {
+            all: true,
+            filters: vec![("label", vec![label_filter.as_str()])]
+                .into_iter()
+                .collect(),
+            ..ListContainersOptions::default()
+        };
+
+        Ok(self
+            .docker
+            .list_containers(Some(options))
+            .await?
+            .into_iter()
+            .filter_map(|container| {
+                // Docker prefixes container names with a slash.
+                let name = container
+                    .names?
+                    .first()?
+                    .trim_start_matches('/')
+                    .to_string();
+                Some(ManagedContainer {
+                    name,
+                    labels: container.labels.unwrap_or_default(),
+                })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_bind_mounts() {
+        let mount = |kind, source: &str| VolumeMount {
+            kind,
+            source: source.to_string(),
+            target: "/data".to_string(),
+            read_only: false,
+        };
+        let allowed = [PathBuf::from("/srv/backends")];
+
+        assert!(
+            check_bind_mounts(&[mount(VolumeMountKind::Bind, "/srv/backends")], &allowed).is_ok()
+        );
+        assert!(check_bind_mounts(
+            &[mount(VolumeMountKind::Bind, "/srv/backends/a/b")],
+            &allowed
+        )
+        .is_ok());
+        // Named volumes are not host paths.
+        assert!(check_bind_mounts(&[mount(VolumeMountKind::Volume, "/etc")], &[]).is_ok());
+
+        assert!(check_bind_mounts(&[mount(VolumeMountKind::Bind, "/srv/backends")], &[]).is_err());
+        assert!(check_bind_mounts(&[mount(VolumeMountKind::Bind, "/etc")], &allowed).is_err());
+        assert!(check_bind_mounts(
+            &[mount(VolumeMountKind::Bind, "/srv/backends-other")],
+            &allowed
+        )
+        .is_err());
+        assert!(check_bind_mounts(
+            &[mount(VolumeMountKind::Bind, "/srv/backends/../../etc")],
+            &allowed
+        )
+        .is_err());
+        assert!(
+            check_bind_mounts(&[mount(VolumeMountKind::Bind, "srv/backends")], &allowed).is_err()
+        );
+    }
+
+    #[test]
+    fn test_image_registry() {
+        assert_eq!("docker.io", image_registry("ubuntu"));
+        assert_eq!("docker.io", image_registry("library/ubuntu:22.04"));
+        assert_eq!(
+            "ghcr.io",
+            image_registry("ghcr.io/drifting-in-space/test-image")
+        );
+        assert_eq!("localhost", image_registry("localhost/myimage"));
+        assert_eq!(
+            "registry.internal:5000",
+            image_registry("registry.internal:5000/team/image@sha256:abcd")
+        );
+    }
+
+    #[test]
+    fn test_cpu_shares() {
+        // runc's conversion from shares to a cgroup v2 weight.
+        let weight = |shares: i64| 1 + ((shares - 2) * 9999) / 262142;
+
+        for cpu_weight in [1, 2, 50, 100, 5000, 9999, 10000] {
+            let shares = cpu_shares(&ResourceLimits {
+                cpu_weight: Some(cpu_weight),
+                cpu_shares: Some(512),
+                ..ResourceLimits::default()
+            })
+            .unwrap();
+            assert_eq!(cpu_weight as i64, weight(shares));
+        }
+
+        assert_eq!(
+            Some(512),
+            cpu_shares(&ResourceLimits {
+                cpu_shares: Some(512),
+                ..ResourceLimits::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_check_shm_size() {
+        assert!(check_shm_size(None, Some(1 << 30)).is_ok());
+        assert!(check_shm_size(Some(1 << 30), None).is_ok());
+        assert!(check_shm_size(Some(1 << 30), Some(1 << 30)).is_ok());
+        assert!(check_shm_size(Some(2 << 30), Some(1 << 30)).is_err());
+    }
+
+    #[test]
+    fn test_pids_limit() {
+        assert_eq!(None, pids_limit(None, None, None).unwrap());
+        assert_eq!(Some(4096), pids_limit(None, Some(4096), None).unwrap());
+        assert_eq!(Some(100), pids_limit(Some(100), Some(4096), None).unwrap());
+        assert_eq!(
+            Some(8192),
+            pids_limit(Some(8192), Some(4096), Some(8192)).unwrap()
+        );
+        assert_eq!(
+            Some(1024),
+            pids_limit(None, Some(4096), Some(1024)).unwrap()
+        );
+        assert_eq!(Some(1024), pids_limit(None, None, Some(1024)).unwrap());
+        assert!(pids_limit(Some(2048), Some(512), Some(1024)).is_err());
+        assert!(pids_limit(Some(-1), Some(512), Some(1024)).is_err());
+    }
+
+    #[test]
+    fn test_is_root_user() {
+        assert!(is_root_user(""));
+        assert!(is_root_user("root"));
+        assert!(is_root_user("0"));
+        assert!(is_root_user("0:1000"));
+        assert!(is_root_user("root:nogroup"));
+        assert!(is_root_user("00"));
+        assert!(is_root_user("000:0"));
+        assert!(!is_root_user("1000"));
+        assert!(!is_root_user("1000:0"));
+        assert!(!is_root_user("nobody"));
+    }
+
+    #[test]
+    fn test_container_labels_token_protected() {
+        let mut spawn_request: SpawnRequest = serde_json::from_value(serde_json::json!({
+            "image": "image",
+            "backend_id": "backend-1",
+            "max_idle_secs": 60,
+            "env": {"SECRET": "value"},
+            "metadata": {},
+        }))
+        .unwrap();
+        spawn_request
+            .labels
+            .insert(TOKEN_PROTECTED_LABEL.to_string(), "true".to_string());
+        let container = ManagedContainer {
+            name: "spawner-backend-1".to_string(),
+            labels: container_labels("spawner-backend-1", &spawn_request),
+        };
+        assert!(!container.token_protected());
+
+        spawn_request.bearer_token = Some("token".to_string());
+        let container = ManagedContainer {
+            name: "spawner-backend-1".to_string(),
+            labels: container_labels("spawner-backend-1", &spawn_request),
+        };
+        assert!(container.token_protected());
+        let labelled = container.spawn_request(None).unwrap();
+        assert_eq!(None, labelled.bearer_token);
+        assert!(labelled.env.is_empty());
+    }
+
+    #[test]
+    fn test_merge_security_options() {
+        let drone = SecurityOptions {
+            seccomp_profile: None,
+            apparmor_profile: Some("docker-default".to_string()),
+            no_new_privileges: true,
+            cap_drop: vec!["NET_RAW".to_string()],
+        };
+        let backend = SecurityOptions {
+            seccomp_profile: Some("unconfined".to_string()),
+            apparmor_profile: Some("backend-profile".to_string()),
+            no_new_privileges: false,
+            cap_drop: vec!["NET_RAW".to_string(), "MKNOD".to_string()],
+        };
+
+        let merged = merge_security_options(&drone, &backend, true).unwrap();
+        assert_eq!(
+            SecurityOptions {
+                seccomp_profile: Some("unconfined".to_string()),
+                apparmor_profile: Some("backend-profile".to_string()),
+                no_new_privileges: true,
+                cap_drop: vec!["NET_RAW".to_string(), "MKNOD".to_string()],
+            },
+            merged
+        );
+        assert_eq!(
+            vec![
+                "seccomp=unconfined".to_string(),
+                "apparmor=backend-profile".to_string(),
+                "no-new-privileges:true".to_string(),
+            ],
+            make_security_opt(&merged)
+        );
+    }
+
+    #[test]
+    fn test_merge_security_options_floor() {
+        let drone = SecurityOptions {
+            seccomp_profile: None,
+            apparmor_profile: Some("docker-default".to_string()),
+            no_new_privileges: false,
+            cap_drop: vec![],
+        };
+        let unconfined = SecurityOptions {
+            seccomp_profile: Some("unconfined".to_string()),
+            ..SecurityOptions::default()
+        };
+        assert!(merge_security_options(&drone, &unconfined, false).is_err());
+        let unconfined = SecurityOptions {
+            apparmor_profile: Some("unconfined".to_string()),
+            ..SecurityOptions::default()
+        };
+        assert!(merge_security_options(&drone, &unconfined, false).is_err());
+
+        // Restrictions may be added, and the drone's own profile asked for.
+        let backend = SecurityOptions {
+            apparmor_profile: Some("docker-default".to_string()),
+            no_new_privileges: true,
+            cap_drop: vec!["NET_RAW".to_string()],
+            ..SecurityOptions::default()
+        };
+        assert_eq!(
+            SecurityOptions {
+                seccomp_profile: None,
+                apparmor_profile: Some("docker-default".to_string()),
+                no_new_privileges: true,
+                cap_drop: vec!["NET_RAW".to_string()],
+            },
+            merge_security_options(&drone, &backend, false).unwrap()
+        );
+    }
 }
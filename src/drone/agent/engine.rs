@@ -0,0 +1,246 @@
+//! The interface between the executor and the software that runs backends' containers.
+
+use crate::{
+    messages::agent::{BackendNetwork, EffectiveResourceLimits, PullPolicy, SpawnRequest},
+    types::BackendId,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use bollard::{auth::DockerCredentials, container::LogOutput, models::EventMessage};
+use futures::stream::BoxStream;
+use std::{collections::HashMap, time::Duration};
+use tokio::sync::mpsc::Sender;
+
+/// Runs backend containers on the drone's host. Each supported runtime (e.g. Docker or
+/// Podman) implements this trait, so that the executor's lifecycle handling is shared
+/// between them.
+///
+/// Containers are identified by name; the executor names each container after the
+/// backend's resource name.
+#[async_trait]
+pub trait Engine: Send + Sync + 'static {
+    /// Stream lifecycle events of containers on the host.
+    fn container_events(&self) -> BoxStream<'_, ContainerEvent>;
+
+    /// Stream the container's logs, starting at the given UNIX timestamp (or from
+    /// the beginning if it is zero).
+    fn get_logs(&self, container_name: &str, since: i64) -> BoxStream<'_, Result<LogOutput>>;
+
+    /// Pull the image from its registry, subject to the given pull policy.
+    async fn pull_image(
+        &self,
+        image: &str,
+        credentials: &Option<DockerCredentials>,
+        pull_policy: PullPolicy,
+    ) -> Result<()>;
+
+    /// Run the image described by the spawn request in a container with the given name.
+    async fn run_container(&self, name: &str, spawn_request: &SpawnRequest) -> Result<()>;
+
+    /// Send the container its stop signal, and kill it if it has not exited after
+    /// `grace_period`.
+    async fn stop_container(&self, name: &str, grace_period: Duration) -> Result<()>;
+
+    /// Remove the container, stopping it first if it is running. Succeeds if the container
+    /// does not exist.
+    async fn remove_container(&self, name: &str) -> Result<()>;
+
+    /// Give the container a new name, e.g. when a warm container is claimed by a backend.
+    async fn rename_container(&self, name: &str, new_name: &str) -> Result<()>;
+
+    /// Returns true if the container has exited and is waiting to be restarted
+    /// according to its restart policy.
+    async fn is_restarting(&self, container_name: &str) -> Result<bool>;
+
+    /// Returns whether the container is running and, if it has exited, its exit code.
+    async fn is_running(&self, container_name: &str) -> Result<(bool, Option<i64>)>;
+
+    /// Returns true if the container's main process was last killed for exceeding the
+    /// container's memory limit.
+    async fn is_oom_killed(&self, container_name: &str) -> Result<bool>;
+
+    /// Run a command in the running container, sending its output as it is produced.
+    /// Returns the command's exit code once it finishes.
+    async fn exec(
+        &self,
+        container_name: &str,
+        command: &[String],
+        output: Sender<LogOutput>,
+    ) -> Result<Option<i64>>;
+
+    /// Read the CPU and memory limits in effect for the running container, or None if
+    /// the engine cannot determine them.
+    async fn effective_resource_limits(
+        &self,
+        container_name: &str,
+    ) -> Result<Option<EffectiveResourceLimits>>;
+
+    /// Sample the container's cumulative resource usage.
+    async fn get_stats(&self, container_name: &str) -> Result<ContainerStats>;
+
+    /// Get the state of the container's health check, or None if the container does
+    /// not have one.
+    async fn get_health(&self, container_name: &str) -> Result<Option<ContainerHealth>>;
+
+    /// Get the host port that the given TCP container port is published on.
+    async fn get_port(&self, container_name: &str, container_port: u16) -> Option<u16>;
+
+    /// Get the host port that the given UDP container port is published on.
+    async fn get_udp_port(&self, container_name: &str, container_port: u16) -> Option<u16>;
+
+    /// The port within the container that the backend described by the spawn request listens on.
+    fn container_port(&self, spawn_request: &SpawnRequest) -> u16;
+
+    /// The network that the container for the given spawn request is attached to.
+    fn network(&self, spawn_request: &SpawnRequest) -> BackendNetwork;
+
+    /// Remove the network created for a container attached to `BackendNetwork::Isolated`,
+    /// if it exists.
+    async fn remove_isolated_network(&self, name: &str) -> Result<()>;
+
+    /// List the containers on the host which spawner created, whether or not they are
+    /// running.
+    async fn list_managed_containers(&self) -> Result<Vec<ManagedContainer>>;
+}
+
+/// Label holding the spawn request (without credentials) that a container was created
+/// for, so that the agent can resume managing the container after losing its database.
+pub const SPAWN_REQUEST_LABEL: &str = "dev.spawner.spawn-request";
+
+/// Label set on a container whose spawn request had a bearer or lease token. Tokens are
+/// not kept in [`SPAWN_REQUEST_LABEL`], so such a container cannot be re-adopted without
+/// exposing its backend.
+pub const TOKEN_PROTECTED_LABEL: &str = "dev.spawner.token-protected";
+
+/// A container on the host which spawner created.
+#[derive(Debug, Clone)]
+pub struct ManagedContainer {
+    pub name: String,
+    pub labels: HashMap<String, String>,
+}
+
+impl ManagedContainer {
+    /// The spawn request that the container was created for, if it is the container of
+    /// a backend in the given namespace. Claimed warm containers carry their pool's
+    /// spawn request, so they are not matched.
+    pub fn spawn_request(&self, namespace: Option<&str>) -> Option<SpawnRequest> {
+        let spawn_request: SpawnRequest =
+            serde_json::from_str(self.labels.get(SPAWN_REQUEST_LABEL)?).ok()?;
+
+        (BackendId::from_namespaced_resource_name(&self.name, namespace).as_ref()
+            == Some(&spawn_request.backend_id))
+        .then_some(spawn_request)
+    }
+
+    /// Whether the container's spawn request had a token which its labels do not hold.
+    pub fn token_protected(&self) -> bool {
+        self.labels.contains_key(TOKEN_PROTECTED_LABEL)
+    }
+}
+
+/// The list of possible container events, as named by the Docker API.
+/// Comes from [Docker documentation](https://docs.docker.com/engine/reference/commandline/events/).
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ContainerEventType {
+    Attach,
+    Commit,
+    Copy,
+    Create,
+    Destroy,
+    Detach,
+    Die,
+    ExecCreate,
+    ExecDetach,
+    ExecDie,
+    ExecStart,
+    Export,
+    HealthStatus,
+    Kill,
+    Oom,
+    Pause,
+    Rename,
+    Resize,
+    Restart,
+    Start,
+    Stop,
+    Top,
+    Unpause,
+    Update,
+}
+
+/// Cumulative resource usage of a container, sampled at a point in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ContainerStats {
+    /// CPU time used by the container's processes since it started.
+    pub cpu_time: Duration,
+
+    /// Memory in use, excluding reclaimable page cache.
+    pub memory_bytes: u64,
+
+    /// Bytes received over the network since the container started.
+    pub network_rx_bytes: u64,
+
+    /// Bytes sent over the network since the container started.
+    pub network_tx_bytes: u64,
+}
+
+/// The state of a container's health check.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ContainerHealth {
+    /// The health check has not yet passed.
+    Starting,
+    Healthy,
+    Unhealthy,
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub struct ContainerEvent {
+    pub event: ContainerEventType,
+    pub name: String,
+}
+
+impl ContainerEvent {
+    /// Parse an event reported by the Docker API.
+    pub fn from_event_message(event: &EventMessage) -> Option<Self> {
+        let action = event.action.as_deref()?;
+        let actor = event.actor.as_ref()?;
+        let name: String = actor.attributes.as_ref()?.get("name")?.to_string();
+
+        // Some actions carry details after a colon, e.g. "health_status: healthy".
+        let action = action.split(':').next()?.trim();
+
+        let event = match action {
+            "attach" => ContainerEventType::Attach,
+            "commit" => ContainerEventType::Commit,
+            "copy" => ContainerEventType::Copy,
+            "create" => ContainerEventType::Create,
+            "destroy" => ContainerEventType::Destroy,
+            "detach" => ContainerEventType::Detach,
+            "die" => ContainerEventType::Die,
+            "exec_create" => ContainerEventType::ExecCreate,
+            "exec_detach" => ContainerEventType::ExecDetach,
+            "exec_die" => ContainerEventType::ExecDie,
+            "exec_start" => ContainerEventType::ExecStart,
+            "export" => ContainerEventType::Export,
+            "health_status" => ContainerEventType::HealthStatus,
+            "kill" => ContainerEventType::Kill,
+            "oom" => ContainerEventType::Oom,
+            "pause" => ContainerEventType::Pause,
+            "rename" => ContainerEventType::Rename,
+            "resize" => ContainerEventType::Resize,
+            "restart" => ContainerEventType::Restart,
+            "start" => ContainerEventType::Start,
+            "stop" => ContainerEventType::Stop,
+            "top" => ContainerEventType::Top,
+            "unpause" => ContainerEventType::Unpause,
+            "update" => ContainerEventType::Update,
+            _ => {
+                tracing::info!(?action, "Unhandled container action.");
+                return None;
+            }
+        };
+
+        Some(ContainerEvent { event, name })
+    }
+}
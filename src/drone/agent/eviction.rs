@@ -0,0 +1,209 @@
+//! Eviction of backends when the drone's host runs low on memory or disk, so that the
+//! drone chooses which backends stop rather than leaving it to the kernel's OOM killer.
+//!
+//! While the host is under pressure, one backend is evicted at a time, and the next is
+//! only chosen once the previous one has stopped and freed its resources.
+
+use super::{executor::Executor, host};
+use crate::types::BackendId;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use std::{path::PathBuf, str::FromStr, sync::Arc, time::Duration};
+
+/// How often the host's available memory and disk are checked.
+const EVICTION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Which backend is evicted first when the host is under pressure.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum EvictionPolicy {
+    /// The backend which has gone longest without proxy activity.
+    #[default]
+    OldestIdle,
+
+    /// The backend with the lowest `priority` in its spawn request, and of those, the
+    /// one which has gone longest without proxy activity.
+    LowestPriority,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "oldest-idle" => Ok(EvictionPolicy::OldestIdle),
+            "lowest-priority" => Ok(EvictionPolicy::LowestPriority),
+            _ => Err(anyhow!(
+                "Expected eviction policy to be oldest-idle or lowest-priority, got {:?}.",
+                s
+            )),
+        }
+    }
+}
+
+/// When and how backends are evicted, configured at the drone level.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct EvictionOptions {
+    pub policy: EvictionPolicy,
+
+    /// Evict backends while the host's available memory is below this many bytes.
+    pub min_available_memory_bytes: Option<u64>,
+
+    /// Evict backends while the space available on the filesystem containing
+    /// `disk_path` is below this many bytes.
+    pub min_available_disk_bytes: Option<u64>,
+
+    /// A path on the filesystem that backends write to, e.g. the container engine's
+    /// data directory.
+    pub disk_path: PathBuf,
+}
+
+/// A running backend which may be evicted.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct EvictionCandidate {
+    pub backend_id: BackendId,
+    pub priority: i32,
+
+    /// When the proxy last saw activity for the backend, or None if it is not yet ready.
+    pub last_active: Option<DateTime<Utc>>,
+}
+
+/// The backend to evict first under the policy. Backends which are not yet ready are
+/// treated as the most recently active, since they have not had a chance to be used.
+fn choose_candidate(
+    candidates: &[EvictionCandidate],
+    policy: EvictionPolicy,
+) -> Option<&EvictionCandidate> {
+    let idle_since =
+        |candidate: &EvictionCandidate| (candidate.last_active.is_none(), candidate.last_active);
+
+    match policy {
+        EvictionPolicy::OldestIdle => candidates.iter().min_by_key(|c| idle_since(c)),
+        EvictionPolicy::LowestPriority => candidates
+            .iter()
+            .min_by_key(|c| (c.priority, idle_since(c))),
+    }
+}
+
+impl EvictionOptions {
+    /// A description of the pressure the host is under, or None if it has enough
+    /// memory and disk.
+    async fn pressure(&self) -> Result<Option<String>> {
+        if let Some(min_bytes) = self.min_available_memory_bytes {
+            let available_bytes = host::memory_info().await?.available_bytes;
+            if available_bytes < min_bytes {
+                return Ok(Some(format!(
+                    "Available memory ({} bytes) fell below {} bytes.",
+                    available_bytes, min_bytes
+                )));
+            }
+        }
+
+        if let Some(min_bytes) = self.min_available_disk_bytes {
+            let available_bytes = host::disk_available_bytes(&self.disk_path).await?;
+            if available_bytes < min_bytes {
+                return Ok(Some(format!(
+                    "Available disk space on {:?} ({} bytes) fell below {} bytes.",
+                    self.disk_path, available_bytes, min_bytes
+                )));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Repeatedly check the host for memory and disk pressure, and evict a backend chosen by
+/// the policy whenever it is under pressure.
+pub async fn eviction_loop(executor: Arc<Executor>, options: EvictionOptions) {
+    let mut interval = tokio::time::interval(EVICTION_CHECK_INTERVAL);
+    let mut evicting: Option<BackendId> = None;
+
+    loop {
+        interval.tick().await;
+
+        // Wait for the last evicted backend to stop before judging whether it was enough.
+        if let Some(backend_id) = &evicting {
+            if executor.is_running_backend(backend_id) {
+                continue;
+            }
+            evicting = None;
+        }
+
+        let pressure = match options.pressure().await {
+            Ok(Some(pressure)) => pressure,
+            Ok(None) => continue,
+            Err(error) => {
+                tracing::warn!(?error, "Error checking host for memory or disk pressure.");
+                continue;
+            }
+        };
+
+        let candidates = match executor.eviction_candidates().await {
+            Ok(candidates) => candidates,
+            Err(error) => {
+                tracing::warn!(?error, "Error listing backends to evict.");
+                continue;
+            }
+        };
+        let candidate = match choose_candidate(&candidates, options.policy) {
+            Some(candidate) => candidate,
+            None => {
+                tracing::warn!(%pressure, "Host is under pressure, but no backend can be evicted.");
+                continue;
+            }
+        };
+
+        tracing::warn!(
+            backend_id = %candidate.backend_id,
+            policy = ?options.policy,
+            %pressure,
+            "Evicting backend."
+        );
+        if executor.evict(&candidate.backend_id, pressure) {
+            evicting = Some(candidate.backend_id.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn chosen(candidates: &[EvictionCandidate], policy: EvictionPolicy) -> Option<&str> {
+        choose_candidate(candidates, policy).map(|candidate| candidate.backend_id.id())
+    }
+
+    #[test]
+    fn test_choose_candidate() {
+        let candidate = |id: &str, priority, last_active: Option<i64>| EvictionCandidate {
+            backend_id: BackendId::new(id.to_string()),
+            priority,
+            last_active: last_active.map(|secs| Utc.timestamp(secs, 0)),
+        };
+        let candidates = vec![
+            candidate("starting", -1, None),
+            candidate("busy", 0, Some(2000)),
+            candidate("idle", 1, Some(1000)),
+            candidate("idle-low-priority", 0, Some(1500)),
+        ];
+
+        assert_eq!(
+            Some("idle"),
+            chosen(&candidates, EvictionPolicy::OldestIdle)
+        );
+        assert_eq!(
+            Some("starting"),
+            chosen(&candidates, EvictionPolicy::LowestPriority)
+        );
+        assert_eq!(
+            Some("idle-low-priority"),
+            chosen(&candidates[1..], EvictionPolicy::LowestPriority)
+        );
+        assert_eq!(
+            Some("starting"),
+            chosen(&candidates[..1], EvictionPolicy::OldestIdle)
+        );
+        assert_eq!(None, chosen(&[], EvictionPolicy::OldestIdle));
+    }
+}
@@ -1,22 +1,71 @@
-use super::docker::{ContainerEventType, DockerInterface};
+use super::{
+    engine::{ContainerEventType, ContainerHealth, Engine},
+    eviction::EvictionCandidate,
+    image_policy::ImagePolicy,
+    logs::LogSink,
+    readiness::wait_probe_ready,
+    routes::RoutePublisher,
+    secrets::SecretSources,
+    stats::stats_loop,
+    status::BackendStatus,
+    warm_pool::WarmPool,
+    webhooks::Webhooks,
+};
 use crate::{
     database::{Backend, DroneDatabase},
-    drone::agent::wait_port_ready,
-    messages::agent::{BackendState, BackendStateMessage, DroneLogMessage, SpawnRequest},
+    drone::{
+        agent::wait_port_ready, backend_tls::BackendCa, cert::custom_domain::CnameVerifier,
+        proxy::tcp::PortRange,
+    },
+    messages::{
+        agent::{
+            BackendInfoMessage, BackendNetwork, BackendState, BackendStateMessage,
+            BackendStatsMessage, BackendTermination, DroneLogMessage, ReadinessCheck,
+            ReadinessProbe, RenewLeaseRequest, RenewLeaseResponse, SpawnRequest, TerminationReason,
+        },
+        cert::CustomDomainCertRequest,
+        proxy::{BackendActivityMessage, RouteInfo},
+    },
     nats::TypedNats,
+    retry::{do_with_backoff, RetryPolicy},
     types::BackendId,
 };
 use anyhow::{anyhow, Result};
-use chrono::Utc;
-use dashmap::DashMap;
+use chrono::{DateTime, Utc};
+use dashmap::{DashMap, DashSet};
 use serde_json::json;
-use std::{fmt::Debug, net::IpAddr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    future::pending,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tokio::{
-    sync::mpsc::{channel, Sender},
+    sync::{
+        mpsc::{channel, Sender},
+        watch,
+    },
     task::JoinHandle,
+    time::Instant,
 };
 use tokio_stream::StreamExt;
 
+/// How often to check the health of a starting container which defines a health check.
+const HEALTH_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a container has to exit after being sent its stop signal, unless its spawn
+/// request sets `stop_grace_period_secs`.
+const DEFAULT_STOP_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// How often to check whether a restarting container has been restarted.
+const RESTART_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often to check whether a draining backend's connections have closed. The proxy
+/// records open connections every second, so this is also the first wait.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 trait LogError {
     fn log_error(&self) -> &Self;
 }
@@ -32,55 +81,319 @@ impl<T, E: Debug> LogError for Result<T, E> {
     }
 }
 
+/// Whether a spawn request may start a backend on the drone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Admission {
+    Admitted,
+
+    /// The drone is already running `max_backends` backends.
+    AtCapacity,
+
+    /// The drone is already running this backend, either for the spawn request's key or
+    /// because the spawn request was received before.
+    Existing(BackendId),
+}
+
+/// The backends which have been admitted to the drone and have not yet terminated.
+#[derive(Default)]
+struct Admissions {
+    backends: Mutex<HashSet<BackendId>>,
+
+    /// The backend of each key among `backends`. Always locked after `backends`.
+    backend_keys: Mutex<HashMap<String, BackendId>>,
+}
+
+impl Admissions {
+    fn admit(&self, spawn_request: &SpawnRequest, max_backends: Option<u32>) -> Admission {
+        let backend_id = &spawn_request.backend_id;
+        let mut backends = self.backends.lock().expect("Backends lock was poisoned.");
+        let mut backend_keys = self
+            .backend_keys
+            .lock()
+            .expect("Backend keys lock was poisoned.");
+
+        // A repeated spawn request must not start the backend a second time.
+        if backends.contains(backend_id) {
+            return Admission::Existing(backend_id.clone());
+        }
+        if let Some(existing) = spawn_request
+            .key
+            .as_ref()
+            .and_then(|key| backend_keys.get(key))
+        {
+            if existing != backend_id {
+                return Admission::Existing(existing.clone());
+            }
+        }
+        if let Some(max_backends) = max_backends {
+            if backends.len() >= max_backends as usize {
+                return Admission::AtCapacity;
+            }
+        }
+
+        Self::insert(&mut backends, &mut backend_keys, spawn_request);
+        Admission::Admitted
+    }
+
+    /// Count a backend which the drone was already running before the agent restarted,
+    /// regardless of capacity.
+    fn readmit(&self, spawn_request: &SpawnRequest) {
+        let mut backends = self.backends.lock().expect("Backends lock was poisoned.");
+        let mut backend_keys = self
+            .backend_keys
+            .lock()
+            .expect("Backend keys lock was poisoned.");
+        Self::insert(&mut backends, &mut backend_keys, spawn_request);
+    }
+
+    fn insert(
+        backends: &mut HashSet<BackendId>,
+        backend_keys: &mut HashMap<String, BackendId>,
+        spawn_request: &SpawnRequest,
+    ) {
+        backends.insert(spawn_request.backend_id.clone());
+        if let Some(key) = &spawn_request.key {
+            backend_keys.insert(key.clone(), spawn_request.backend_id.clone());
+        }
+    }
+
+    /// Stop counting the backend, and free its key for another backend.
+    fn release(&self, spawn_request: &SpawnRequest) {
+        let mut backends = self.backends.lock().expect("Backends lock was poisoned.");
+        backends.remove(&spawn_request.backend_id);
+        if let Some(key) = &spawn_request.key {
+            let mut backend_keys = self
+                .backend_keys
+                .lock()
+                .expect("Backend keys lock was poisoned.");
+            if backend_keys.get(key) == Some(&spawn_request.backend_id) {
+                backend_keys.remove(key);
+            }
+        }
+    }
+
+    fn contains(&self, backend_id: &BackendId) -> bool {
+        self.backends
+            .lock()
+            .expect("Backends lock was poisoned.")
+            .contains(backend_id)
+    }
+
+    fn len(&self) -> usize {
+        self.backends
+            .lock()
+            .expect("Backends lock was poisoned.")
+            .len()
+    }
+}
+
 pub struct Executor {
     host_ip: IpAddr,
-    docker: DockerInterface,
+    engine: Arc<dyn Engine>,
     database: DroneDatabase,
     nc: TypedNats,
+    log_sinks: Arc<Vec<LogSink>>,
+    warm_pool: Arc<WarmPool>,
+
+    /// How often to publish the resource usage of running backends, if at all.
+    stats_interval: Option<Duration>,
+    image_policy: ImagePolicy,
+
+    /// How long backends may go without proxy activity, unless their spawn request sets
+    /// `max_idle_secs`.
+    idle_timeout: Duration,
+
+    /// How pulling images and creating containers are retried.
+    retry_policy: RetryPolicy,
+
+    /// How long open connections to a stopping backend have to close before it is stopped.
+    drain_timeout: Duration,
+    webhooks: Webhooks,
+    secrets: SecretSources,
+
+    /// Prefix of the names of the agent's containers, if it shares the container engine
+    /// with agents of other clusters.
+    namespace: Option<String>,
+    routes: RoutePublisher,
+
+    /// Ports which backends' TCP ports are assigned from, if the proxy passes raw TCP
+    /// connections through.
+    tcp_port_range: Option<PortRange>,
+
+    /// Ports which backends' UDP ports are assigned from, if the proxy relays UDP.
+    udp_port_range: Option<PortRange>,
+
+    /// CA which backends are issued certificates from, if the proxy connects to them over
+    /// mutual TLS.
+    backend_ca: Option<Arc<BackendCa>>,
+
+    /// Checks spawn requests' custom domains before they are routed, if they are.
+    custom_domains: Option<CnameVerifier>,
     _container_events_handle: JoinHandle<()>,
     backend_to_listener: Arc<DashMap<BackendId, Sender<()>>>,
+
+    /// Backends which have been accepted and have not yet terminated.
+    admissions: Arc<Admissions>,
+
+    /// Backends with a process which has been killed for exceeding the container's memory
+    /// limit since the container last started.
+    oom_killed: Arc<DashSet<BackendId>>,
     backend_to_log_loop:
         Arc<DashMap<BackendId, tokio::task::JoinHandle<Result<(), anyhow::Error>>>>,
+    backend_to_stats_loop: Arc<DashMap<BackendId, JoinHandle<()>>>,
+
+    /// The latest resource usage of each running backend, if usage is published.
+    backend_stats: Arc<DashMap<BackendId, BackendStatsMessage>>,
+
+    /// The info published for each backend when it became ready.
+    backend_info: Arc<DashMap<BackendId, BackendInfoMessage>>,
+
+    /// The most recent activity of each running backend reported by proxies over NATS.
+    reported_activity: Arc<DashMap<BackendId, DateTime<Utc>>>,
+
+    /// Leases of running backends which were spawned with an expiry time.
+    leases: Arc<DashMap<BackendId, Lease>>,
+
+    /// Channels through which running backends are told to stop, with the pressure on the
+    /// host that they are evicted to relieve.
+    backend_to_eviction: Arc<DashMap<BackendId, Sender<String>>>,
+}
+
+/// The expiry time of a backend, and the token needed to push it back.
+struct Lease {
+    token: Option<String>,
+    expires_at: watch::Sender<DateTime<Utc>>,
+}
+
+/// Resolve at the deadline, or never if there is none.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => pending().await,
+    }
+}
+
+/// Resolve once the backend's expiry time has passed, following any renewals of its
+/// lease. Never resolves if the backend has no expiry time.
+async fn wait_for_expiry(expiry: &mut Option<watch::Receiver<DateTime<Utc>>>) {
+    let expiry = match expiry {
+        Some(expiry) => expiry,
+        None => return pending().await,
+    };
+
+    loop {
+        let remaining = expiry
+            .borrow()
+            .signed_duration_since(Utc::now())
+            .to_std()
+            .unwrap_or_default();
+        tokio::select! {
+            _ = tokio::time::sleep(remaining) => return,
+            Ok(()) = expiry.changed() => continue,
+        }
+    }
 }
 
 impl Executor {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        docker: DockerInterface,
+        engine: Arc<dyn Engine>,
         database: DroneDatabase,
         nc: TypedNats,
         host_ip: IpAddr,
+        log_sinks: Vec<LogSink>,
+        warm_pool: Arc<WarmPool>,
+        stats_interval: Option<Duration>,
+        image_policy: ImagePolicy,
+        idle_timeout: Duration,
+        retry_policy: RetryPolicy,
+        drain_timeout: Duration,
+        webhooks: Webhooks,
+        secrets: SecretSources,
+        namespace: Option<String>,
+        routes: RoutePublisher,
+        tcp_port_range: Option<PortRange>,
+        udp_port_range: Option<PortRange>,
+        backend_ca: Option<Arc<BackendCa>>,
+        custom_domains: Option<CnameVerifier>,
     ) -> Self {
         let backend_to_listener: Arc<DashMap<BackendId, Sender<()>>> = Arc::default();
+        let oom_killed: Arc<DashSet<BackendId>> = Arc::default();
         let container_events_handle = tokio::spawn(Self::listen_for_container_events(
-            docker.clone(),
+            engine.clone(),
+            namespace.clone(),
             backend_to_listener.clone(),
+            oom_killed.clone(),
         ));
 
         Executor {
             host_ip,
-            docker,
+            engine,
             database,
             nc,
+            log_sinks: Arc::new(log_sinks),
+            warm_pool,
+            stats_interval,
+            image_policy,
+            idle_timeout,
+            retry_policy,
+            drain_timeout,
+            webhooks,
+            secrets,
+            namespace,
+            routes,
+            tcp_port_range,
+            udp_port_range,
+            backend_ca,
+            custom_domains,
             _container_events_handle: container_events_handle,
             backend_to_listener,
+            admissions: Arc::default(),
+            oom_killed,
             backend_to_log_loop: Arc::default(),
+            backend_to_stats_loop: Arc::default(),
+            backend_stats: Arc::default(),
+            backend_info: Arc::default(),
+            reported_activity: Arc::default(),
+            leases: Arc::default(),
+            backend_to_eviction: Arc::default(),
         }
     }
 
     async fn listen_for_container_events(
-        docker: DockerInterface,
+        engine: Arc<dyn Engine>,
+        namespace: Option<String>,
         backend_to_listener: Arc<DashMap<BackendId, Sender<()>>>,
+        oom_killed: Arc<DashSet<BackendId>>,
     ) {
-        let mut event_stream = docker.container_events().await;
+        let mut event_stream = engine.container_events();
         while let Some(event) = event_stream.next().await {
-            if event.event == ContainerEventType::Die {
-                let backend_id =
-                    if let Some(backend_id) = BackendId::from_resource_name(&event.name) {
-                        backend_id
-                    } else {
-                        continue;
-                    };
+            // Containers of agents in other namespaces are ignored.
+            let backend_id = if let Some(backend_id) =
+                BackendId::from_namespaced_resource_name(&event.name, namespace.as_deref())
+            {
+                backend_id
+            } else {
+                continue;
+            };
+
+            match event.event {
+                ContainerEventType::Oom => {
+                    tracing::warn!(%backend_id, "Process in container was killed for exceeding its memory limit.");
+                    oom_killed.insert(backend_id.clone());
+                }
+                ContainerEventType::Start => {
+                    oom_killed.remove(&backend_id);
+                }
+                _ => (),
+            }
 
+            if matches!(
+                event.event,
+                ContainerEventType::Die
+                    | ContainerEventType::HealthStatus
+                    | ContainerEventType::Start
+            ) {
                 if let Some(v) = backend_to_listener.get(&backend_id) {
                     v.try_send(()).log_error();
                 }
@@ -88,6 +401,61 @@ impl Executor {
         }
     }
 
+    /// Describe how the backend terminated upon entering the given terminal state.
+    async fn termination(
+        &self,
+        spawn_request: &SpawnRequest,
+        state: BackendState,
+    ) -> Result<BackendTermination> {
+        if state == BackendState::ErrorLoading {
+            // No container was started.
+            return Ok(BackendTermination {
+                reason: TerminationReason::StartFailed,
+                exit_code: None,
+                oom_killed: false,
+                error: None,
+            });
+        }
+
+        let container_name = self.container_name(&spawn_request.backend_id);
+        let (_, exit_code) = match self.engine.is_running(&container_name).await {
+            Ok(running) => running,
+            // A backend may expire while loading, before it has a container.
+            Err(_) if state == BackendState::Expired => {
+                return Ok(BackendTermination {
+                    reason: TerminationReason::Expired,
+                    exit_code: None,
+                    oom_killed: false,
+                    error: None,
+                })
+            }
+            Err(error) => return Err(error),
+        };
+        let main_process_oom_killed = self.engine.is_oom_killed(&container_name).await?;
+        let oom_killed =
+            self.oom_killed.remove(&spawn_request.backend_id).is_some() || main_process_oom_killed;
+
+        let reason = match state {
+            BackendState::Exited => TerminationReason::Exited,
+            BackendState::Failed if main_process_oom_killed => TerminationReason::OutOfMemory,
+            BackendState::Swept => TerminationReason::Idle,
+            BackendState::Unhealthy => TerminationReason::Unhealthy,
+            BackendState::Expired => TerminationReason::Expired,
+            BackendState::Evicted => TerminationReason::Evicted,
+            BackendState::ErrorLoading
+            | BackendState::ErrorStarting
+            | BackendState::TimedOutBeforeReady => TerminationReason::StartFailed,
+            _ => TerminationReason::Crashed,
+        };
+
+        Ok(BackendTermination {
+            reason,
+            exit_code,
+            oom_killed,
+            error: None,
+        })
+    }
+
     pub async fn start_backend(&self, spawn_request: &SpawnRequest) {
         self.database
             .insert_backend(spawn_request)
@@ -97,7 +465,10 @@ impl Executor {
         self.nc
             .publish(
                 &BackendStateMessage::subject(&spawn_request.backend_id),
-                &BackendStateMessage::new(BackendState::Loading),
+                &BackendStateMessage {
+                    labels: spawn_request.labels.clone(),
+                    ..BackendStateMessage::new(BackendState::Loading)
+                },
             )
             .await
             .log_error();
@@ -105,20 +476,89 @@ impl Executor {
         self.run_backend(spawn_request, BackendState::Loading).await
     }
 
+    /// Resume managing the backends recorded in the database, and re-adopt containers
+    /// which the database has no record of (e.g. because it was lost) from the spawn
+    /// requests in their labels. Containers which can't be re-adopted safely, i.e. claimed
+    /// warm containers and those of token-protected backends, are removed instead.
     pub async fn resume_backends(self: &Arc<Self>) -> Result<()> {
-        let backends = self.database.get_backends().await?;
+        let mut backends = self.database.get_backends().await?;
+        let containers = self.engine.list_managed_containers().await?;
+
+        for container in &containers {
+            // Unclaimed warm containers are not named as backends, and are left to the warm
+            // pool to replace.
+            let backend_id = match BackendId::from_namespaced_resource_name(
+                &container.name,
+                self.namespace.as_deref(),
+            ) {
+                Some(backend_id) => backend_id,
+                None => continue,
+            };
+            if backends
+                .iter()
+                .any(|backend| backend.backend_id == backend_id)
+            {
+                continue;
+            }
+
+            // Claimed warm containers keep their pool's labels, since labels can't be
+            // changed once a container exists, so their spawn requests are unknown. Tokens
+            // are not kept in labels, so routing to a token-protected backend would make
+            // it public.
+            let spawn_request = match container.spawn_request(self.namespace.as_deref()) {
+                Some(spawn_request) if !container.token_protected() => spawn_request,
+                spawn_request => {
+                    let reason = if spawn_request.is_some() {
+                        "token-protected backend"
+                    } else {
+                        "backend without its spawn request"
+                    };
+                    tracing::warn!(
+                        %backend_id,
+                        reason,
+                        "Removing container instead of re-adopting it."
+                    );
+                    if let Err(error) = self.engine.remove_container(&container.name).await {
+                        tracing::error!(?error, %backend_id, "Error removing container.");
+                    }
+                    continue;
+                }
+            };
+            tracing::info!(%backend_id, "Re-adopting container.");
+            self.database.insert_backend(&spawn_request).await?;
+            self.database
+                .update_backend_state(&backend_id, BackendState::Starting, None)
+                .await?;
+            backends.push(Backend {
+                backend_id,
+                state: BackendState::Starting,
+                spec: spawn_request,
+            });
+        }
 
         for backend in backends {
             let executor = self.clone();
             let Backend {
                 backend_id,
-                state,
+                mut state,
                 spec,
             } = backend;
+
+            // The agent may have stopped after creating the container but before
+            // recording that it was starting.
+            if state == BackendState::Loading
+                && containers
+                    .iter()
+                    .any(|container| container.name == self.container_name(&backend_id))
+            {
+                state = BackendState::Starting;
+            }
             tracing::info!(%backend_id, ?state, "Resuming backend");
+            self.admissions.readmit(&spec);
 
             if state.running() {
                 self.start_log_loop(&backend_id);
+                self.start_stats_loop(&backend_id);
             }
             tokio::spawn(async move { executor.run_backend(&spec, state).await });
         }
@@ -126,43 +566,126 @@ impl Executor {
         Ok(())
     }
 
+    /// Start forwarding the backend's logs, unless they are already being forwarded.
+    ///
+    /// The log stream ends when the container exits, so if the container has since been
+    /// restarted, a new loop is started from the current time to avoid repeating logs.
     fn start_log_loop(&self, backend_id: &BackendId) {
-        let docker = self.docker.clone();
+        let since = match self.backend_to_log_loop.get(backend_id) {
+            Some(handle) if !handle.is_finished() => return,
+            Some(_) => Utc::now().timestamp(),
+            None => 0,
+        };
+
+        let engine = self.engine.clone();
         let nc = self.nc.clone();
-        let backend_id = backend_id.clone();
-        self.backend_to_log_loop
-            .entry(backend_id.clone())
-            .or_insert_with(move || {
-                tokio::spawn(async move {
-                    let container_name = backend_id.to_resource_name();
-                    tracing::info!(%backend_id, "Log recording loop started.");
-                    let mut stream = docker.get_logs(&container_name);
-
-                    while let Some(v) = stream.next().await {
-                        match v {
-                            Ok(v) => {
-                                if let Some(message) = DroneLogMessage::from_log_message(&v) {
-                                    nc.publish(&DroneLogMessage::subject(&backend_id), &message)
-                                        .await?;
+        let log_sinks = self.log_sinks.clone();
+        let container_name = self.container_name(backend_id);
+        let handle = {
+            let backend_id = backend_id.clone();
+            tokio::spawn(async move {
+                tracing::info!(%backend_id, "Log recording loop started.");
+
+                let mut writers = Vec::with_capacity(log_sinks.len());
+                for sink in log_sinks.iter() {
+                    match sink.open(&backend_id, &nc).await {
+                        Ok(writer) => writers.push(writer),
+                        Err(error) => {
+                            tracing::warn!(?error, ?sink, %backend_id, "Error opening log sink.")
+                        }
+                    }
+                }
+
+                let mut stream = engine.get_logs(&container_name, since);
+
+                while let Some(v) = stream.next().await {
+                    match v {
+                        Ok(v) => {
+                            if let Some(message) = DroneLogMessage::from_log_message(&v) {
+                                for writer in &mut writers {
+                                    writer.write(&backend_id, &message).await.log_error();
                                 }
                             }
-                            Err(error) => {
-                                tracing::warn!(?error, "Error encountered forwarding log.");
-                            }
+                        }
+                        Err(error) => {
+                            tracing::warn!(?error, "Error encountered forwarding log.");
                         }
                     }
+                }
 
-                    tracing::info!(%backend_id, "Log loop terminated.");
+                tracing::info!(%backend_id, "Log loop terminated.");
 
-                    Ok::<(), anyhow::Error>(())
-                })
-            });
+                Ok::<(), anyhow::Error>(())
+            })
+        };
+
+        self.backend_to_log_loop.insert(backend_id.clone(), handle);
+    }
+
+    /// Start publishing the backend's resource usage, unless it is already being published
+    /// or publishing is disabled.
+    fn start_stats_loop(&self, backend_id: &BackendId) {
+        let interval = match self.stats_interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        if let Some(handle) = self.backend_to_stats_loop.get(backend_id) {
+            if !handle.is_finished() {
+                return;
+            }
+        }
+
+        let engine = self.engine.clone();
+        let nc = self.nc.clone();
+        let latest = self.backend_stats.clone();
+        let container_name = self.container_name(backend_id);
+        let handle = {
+            let backend_id = backend_id.clone();
+            tokio::spawn(async move {
+                if let Err(error) = stats_loop(
+                    engine,
+                    nc,
+                    backend_id.clone(),
+                    container_name,
+                    interval,
+                    latest,
+                )
+                .await
+                {
+                    tracing::warn!(?error, %backend_id, "Error publishing resource usage.");
+                }
+            })
+        };
+
+        self.backend_to_stats_loop
+            .insert(backend_id.clone(), handle);
     }
 
     async fn run_backend(&self, spawn_request: &SpawnRequest, mut state: BackendState) {
         let (send, mut recv) = channel(1);
         self.backend_to_listener
             .insert(spawn_request.backend_id.clone(), send);
+        let (evict_send, mut evict_recv) = channel(1);
+        self.backend_to_eviction
+            .insert(spawn_request.backend_id.clone(), evict_send);
+        let mut last_error = None;
+
+        // Backends resumed after the agent restarts are given a new startup deadline.
+        let startup_deadline = spawn_request
+            .startup_timeout_secs
+            .map(|timeout| Instant::now() + timeout);
+        let mut became_ready = state == BackendState::Ready;
+        let mut expiry = spawn_request.expires_at.map(|expires_at| {
+            let (send, recv) = watch::channel(expires_at);
+            self.leases.insert(
+                spawn_request.backend_id.clone(),
+                Lease {
+                    token: spawn_request.lease_token.clone(),
+                    expires_at: send,
+                },
+            );
+            recv
+        });
 
         loop {
             tracing::info!(
@@ -172,56 +695,466 @@ impl Executor {
                 "Executing state."
             );
 
-            let next_state = loop {
-                if state == BackendState::Swept {
-                    // When sweeping, we ignore external state changes to avoid an infinite loop.
-                    break self.step(spawn_request, state).await;
-                } else {
-                    // Otherwise, we allow the step to be interrupted if the state changes (i.e.
-                    // if the container dies).
-                    tokio::select! {
-                        next_state = self.step(spawn_request, state) => break next_state,
-                        _ = recv.recv() => {
-                            tracing::info!("State may have updated externally.");
-                            continue;
-                        },
+            let step = async {
+                loop {
+                    if state == BackendState::Swept {
+                        // When sweeping, we ignore external state changes to avoid an infinite loop.
+                        break self.step(spawn_request, state).await;
+                    } else {
+                        // Otherwise, we allow the step to be interrupted if the state changes (i.e.
+                        // if the container dies).
+                        tokio::select! {
+                            next_state = self.step(spawn_request, state) => break next_state,
+                            _ = recv.recv() => {
+                                tracing::info!("State may have updated externally.");
+                                continue;
+                            },
+                        }
+                    };
+                }
+            };
+            let next_state = if state.terminal() {
+                step.await
+            } else {
+                let startup_deadline = startup_deadline.filter(|_| !became_ready);
+                let evictable = state.can_transition_to(BackendState::Evicted);
+                tokio::select! {
+                    next_state = step => next_state,
+                    _ = sleep_until(startup_deadline) => {
+                        tracing::warn!(?state, "Backend was not ready before its startup deadline.");
+                        last_error = Some(format!(
+                            "Backend was not ready within {:?}.",
+                            spawn_request.startup_timeout_secs.unwrap_or_default()
+                        ));
+                        Ok(Some(BackendState::TimedOutBeforeReady))
+                    }
+                    _ = wait_for_expiry(&mut expiry) => {
+                        tracing::info!(?state, "Backend expired.");
+                        Ok(Some(BackendState::Expired))
+                    }
+                    Some(pressure) = evict_recv.recv(), if evictable => {
+                        tracing::warn!(?state, %pressure, "Backend evicted.");
+                        last_error = Some(pressure);
+                        Ok(Some(BackendState::Evicted))
+                    }
+                }
+            };
+
+            let next_state = match next_state {
+                Ok(next_state) => next_state,
+                Err(error) => match state.on_error() {
+                    Some(error_state) => {
+                        tracing::error!(?error, ?state, ?error_state, "Encountered error.");
+                        last_error = Some(format!("{:#}", error));
+                        Some(error_state)
                     }
-                };
+                    None => {
+                        tracing::error!(?error, ?state, "Encountered error.");
+                        break;
+                    }
+                },
             };
 
             match next_state {
-                Ok(Some(new_state)) => {
+                Some(new_state) => {
+                    if !state.can_transition_to(new_state) {
+                        tracing::error!(?state, ?new_state, "Invalid state transition.");
+                        break;
+                    }
+                    let previous_state = state;
                     state = new_state;
+                    became_ready |= state == BackendState::Ready;
 
                     if state.running() {
                         self.start_log_loop(&spawn_request.backend_id);
+                        self.start_stats_loop(&spawn_request.backend_id);
                     }
 
+                    let termination = if state.terminal() {
+                        match self.termination(spawn_request, state).await {
+                            Ok(termination) => {
+                                let termination = BackendTermination {
+                                    error: last_error.take(),
+                                    ..termination
+                                };
+                                tracing::info!(?termination, "Backend terminated.");
+                                Some(termination)
+                            }
+                            Err(error) => {
+                                tracing::warn!(
+                                    ?error,
+                                    "Couldn't determine how backend terminated."
+                                );
+                                None
+                            }
+                        }
+                    } else {
+                        None
+                    };
                     self.database
-                        .update_backend_state(&spawn_request.backend_id, state)
+                        .update_backend_state(
+                            &spawn_request.backend_id,
+                            state,
+                            termination.as_ref(),
+                        )
                         .await
                         .log_error();
+                    let message = BackendStateMessage {
+                        previous_state: Some(previous_state),
+                        termination,
+                        labels: spawn_request.labels.clone(),
+                        ..BackendStateMessage::new(state)
+                    };
                     self.nc
                         .publish(
                             &BackendStateMessage::subject(&spawn_request.backend_id),
-                            &BackendStateMessage::new(state),
+                            &message,
                         )
                         .await
                         .log_error();
+                    self.webhooks
+                        .send(&spawn_request.backend_id, &message)
+                        .log_error();
                 }
-                Ok(None) => {
+                None => {
                     // Successful termination.
                     tracing::info!("Terminated successfully.");
                     break;
                 }
+            }
+        }
+
+        self.backend_to_listener.remove(&spawn_request.backend_id);
+        self.admissions.release(spawn_request);
+        self.backend_to_eviction.remove(&spawn_request.backend_id);
+        self.leases.remove(&spawn_request.backend_id);
+        self.backend_stats.remove(&spawn_request.backend_id);
+        self.backend_info.remove(&spawn_request.backend_id);
+        self.reported_activity.remove(&spawn_request.backend_id);
+    }
+
+    /// The status of each backend which has not terminated.
+    pub async fn backend_statuses(&self) -> Result<Vec<BackendStatus>> {
+        let mut statuses = Vec::new();
+        for backend in self.database.get_backends().await? {
+            if backend.state.terminal() {
+                continue;
+            }
+
+            let address = self
+                .database
+                .get_proxy_route(backend.backend_id.id())
+                .await?
+                .map(|route| route.address);
+            let info = self.backend_info.get(&backend.backend_id);
+            statuses.push(BackendStatus {
+                address,
+                udp_ports: info
+                    .as_ref()
+                    .map(|info| info.udp_ports.clone())
+                    .unwrap_or_default(),
+                tcp_ports: info
+                    .as_ref()
+                    .map(|info| info.tcp_ports.clone())
+                    .unwrap_or_default(),
+                resource_limits: info.and_then(|info| info.resource_limits.clone()),
+                usage: self
+                    .backend_stats
+                    .get(&backend.backend_id)
+                    .map(|stats| stats.clone()),
+                image: backend.spec.image,
+                labels: backend.spec.labels,
+                state: backend.state,
+                backend_id: backend.backend_id,
+            });
+        }
+
+        Ok(statuses)
+    }
+
+    /// Push back the expiry time of a backend's lease. Returns None if the backend is not
+    /// running on this drone, in which case the request should be left to another drone.
+    pub async fn renew_lease(&self, request: &RenewLeaseRequest) -> Option<RenewLeaseResponse> {
+        let expires_at = {
+            let lease = match self.leases.get(&request.backend_id) {
+                Some(lease) => lease,
+                None if self.is_running_backend(&request.backend_id) => {
+                    return Some(RenewLeaseResponse::NoLease)
+                }
+                None => return None,
+            };
+            let token = match &lease.token {
+                Some(token) => token,
+                None => return Some(RenewLeaseResponse::NoLease),
+            };
+            if token.len() != request.token.len()
+                || !openssl::memcmp::eq(token.as_bytes(), request.token.as_bytes())
+            {
+                return Some(RenewLeaseResponse::InvalidToken);
+            }
+
+            let renewed = chrono::Duration::from_std(request.lease_secs)
+                .ok()
+                .and_then(|lease_secs| Utc::now().checked_add_signed(lease_secs))?;
+            let expires_at = renewed.max(*lease.expires_at.borrow());
+            lease.expires_at.send_replace(expires_at);
+            expires_at
+        };
+
+        tracing::info!(backend_id = %request.backend_id, %expires_at, "Renewed lease.");
+        self.database
+            .update_backend_expiry(&request.backend_id, expires_at)
+            .await
+            .log_error();
+
+        Some(RenewLeaseResponse::Renewed { expires_at })
+    }
+
+    /// Running backends which may be evicted to relieve pressure on the host.
+    pub async fn eviction_candidates(&self) -> Result<Vec<EvictionCandidate>> {
+        let mut candidates = Vec::new();
+        for backend in self.database.get_backends().await? {
+            if !backend.state.can_transition_to(BackendState::Evicted)
+                || !self.backend_to_eviction.contains_key(&backend.backend_id)
+            {
+                continue;
+            }
+
+            let last_active = if backend.state == BackendState::Ready {
+                Some(self.last_active(&backend.backend_id).await?)
+            } else {
+                None
+            };
+            candidates.push(EvictionCandidate {
+                backend_id: backend.backend_id,
+                priority: backend.spec.priority,
+                last_active,
+            });
+        }
+
+        Ok(candidates)
+    }
+
+    /// Tell a running backend to stop because of the given pressure on the host. Returns
+    /// false if the backend is not running or is already being evicted.
+    pub fn evict(&self, backend_id: &BackendId, pressure: String) -> bool {
+        match self.backend_to_eviction.get(backend_id) {
+            Some(evict) => evict.try_send(pressure).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Route the subdomain to the backend's port on the host, and publish the route.
+    async fn add_route(
+        &self,
+        spawn_request: &SpawnRequest,
+        subdomain: String,
+        port: u16,
+    ) -> Result<()> {
+        let route = RouteInfo {
+            subdomain,
+            backend_id: spawn_request.backend_id.clone(),
+            address: format!("{}:{}", self.host_ip, port),
+            bearer_token: spawn_request.bearer_token.clone(),
+            max_connections: spawn_request.max_connections,
+            timeouts: spawn_request.timeouts,
+            replicas: Vec::new(),
+        };
+        self.database
+            .insert_proxy_route(
+                &route.backend_id,
+                &route.subdomain,
+                &route.address,
+                route.bearer_token.as_deref(),
+                route.max_connections,
+                route.timeouts.as_ref(),
+            )
+            .await?;
+        self.routes.add(route).await;
+
+        Ok(())
+    }
+
+    /// Route the backend's custom domain to the port once it is checked to be a CNAME of
+    /// the backend's subdomain, and ask the cluster's certificate refresher for its
+    /// certificate. Failures are logged rather than failing the backend, which is still
+    /// reachable by its subdomain.
+    async fn add_custom_domain(
+        &self,
+        spawn_request: &SpawnRequest,
+        custom_domain: &str,
+        port: u16,
+    ) {
+        let verifier = match &self.custom_domains {
+            Some(verifier) => verifier,
+            None => {
+                tracing::warn!(%custom_domain, "Ignoring custom domain; custom domains are not enabled.");
+                return;
+            }
+        };
+        let custom_domain = custom_domain.to_ascii_lowercase();
+
+        let result = async {
+            verifier
+                .verify(&custom_domain, &spawn_request.backend_id)
+                .await?;
+            self.add_route(spawn_request, custom_domain.clone(), port)
+                .await?;
+            self.nc
+                .publish(
+                    &CustomDomainCertRequest::subject(verifier.cluster_domain()),
+                    &CustomDomainCertRequest {
+                        hostname: custom_domain.clone(),
+                        backend_id: spawn_request.backend_id.clone(),
+                    },
+                )
+                .await
+        };
+        match result.await {
+            Ok(()) => tracing::info!(%custom_domain, "Routed custom domain."),
+            Err(error) => tracing::warn!(?error, %custom_domain, "Error adding custom domain."),
+        }
+    }
+
+    /// Assign the backend's port on the host a port in the drone's TCP port range, through
+    /// which the proxy passes raw TCP connections to it. Returns the assigned port.
+    async fn add_tcp_route(
+        &self,
+        spawn_request: &SpawnRequest,
+        port_name: &str,
+        port: u16,
+    ) -> Result<u16> {
+        let range = self.tcp_port_range.ok_or_else(|| {
+            anyhow!("Spawn request has TCP ports, but the drone has no TCP port range.")
+        })?;
+        self.database
+            .insert_tcp_route(
+                &spawn_request.backend_id,
+                &spawn_request.tcp_route_name(port_name),
+                &format!("{}:{}", self.host_ip, port),
+                range.ports(),
+                spawn_request.max_connections,
+                spawn_request.timeouts.as_ref(),
+            )
+            .await?
+            .ok_or_else(|| anyhow!("Every port in the drone's TCP port range is taken."))
+    }
+
+    /// If the drone has a UDP port range, assign the backend's UDP port on the host a port
+    /// in it, which the proxy relays datagrams through. Returns the port clients should
+    /// send to: the assigned one, or otherwise the port on the host.
+    async fn add_udp_route(
+        &self,
+        spawn_request: &SpawnRequest,
+        port_name: &str,
+        port: u16,
+    ) -> Result<u16> {
+        let range = match self.udp_port_range {
+            Some(range) => range,
+            None => return Ok(port),
+        };
+        self.database
+            .insert_udp_route(
+                &spawn_request.backend_id,
+                &spawn_request.udp_route_name(port_name),
+                &format!("{}:{}", self.host_ip, port),
+                range.ports(),
+            )
+            .await?
+            .ok_or_else(|| anyhow!("Every port in the drone's UDP port range is taken."))
+    }
+
+    /// Record the activity of the executor's backends reported by a proxy.
+    pub fn record_activity(&self, message: &BackendActivityMessage) {
+        for (backend_id, last_active) in &message.last_active {
+            if !self.is_running_backend(backend_id) {
+                continue;
+            }
+            self.reported_activity
+                .entry(backend_id.clone())
+                .and_modify(|reported| *reported = (*reported).max(*last_active))
+                .or_insert(*last_active);
+        }
+    }
+
+    /// The most recent time the backend was active, at the proxy sharing the drone's
+    /// database or at any proxy reporting activity over NATS.
+    async fn last_active(&self, backend_id: &BackendId) -> Result<DateTime<Utc>> {
+        let recorded = self.database.get_backend_last_active(backend_id).await?;
+        Ok(match self.reported_activity.get(backend_id) {
+            Some(reported) => recorded.max(*reported),
+            None => recorded,
+        })
+    }
+
+    /// The routes to backends which the executor is running and not stopping.
+    pub async fn live_routes(&self) -> Result<Vec<RouteInfo>> {
+        let mut routes = self.database.get_live_proxy_routes().await?;
+        routes.retain(|route| self.is_running_backend(&route.backend_id));
+        Ok(routes)
+    }
+
+    /// Whether the backend has been accepted and has not yet terminated.
+    pub fn is_running_backend(&self, backend_id: &BackendId) -> bool {
+        self.admissions.contains(backend_id)
+    }
+
+    /// Stop the proxy from sending new requests to the backend, and wait up to the drain
+    /// timeout for the connections it has open to close. Errors are logged rather than
+    /// returned, so that they never prevent the backend from stopping.
+    async fn drain(&self, backend_id: &BackendId) {
+        if self.drain_timeout.is_zero() {
+            return;
+        }
+        if let Err(error) = self.database.drain_proxy_routes(backend_id).await {
+            tracing::warn!(?error, %backend_id, "Error draining backend's routes.");
+            return;
+        }
+
+        let deadline = Instant::now() + self.drain_timeout;
+        loop {
+            tokio::time::sleep(DRAIN_POLL_INTERVAL.min(self.drain_timeout)).await;
+            match self.database.get_backend_connections(backend_id).await {
+                Ok(0) => return,
+                Ok(connections) if Instant::now() >= deadline => {
+                    tracing::info!(%backend_id, connections, "Closing connections which outlasted the drain timeout.");
+                    return;
+                }
+                Ok(_) => (),
                 Err(error) => {
-                    tracing::error!(?error, ?state, "Encountered error.");
-                    break;
+                    tracing::warn!(?error, %backend_id, "Error reading backend's connections.");
+                    return;
                 }
             }
         }
     }
 
+    /// The name of the backend's container.
+    pub fn container_name(&self, backend_id: &BackendId) -> String {
+        backend_id.to_namespaced_resource_name(self.namespace.as_deref())
+    }
+
+    /// How long the backend may go without proxy activity before it is swept.
+    fn idle_timeout(&self, spawn_request: &SpawnRequest) -> Duration {
+        if spawn_request.max_idle_secs.is_zero() {
+            self.idle_timeout
+        } else {
+            spawn_request.max_idle_secs
+        }
+    }
+
+    /// Count the backend towards the drone's backends until it terminates, unless that
+    /// would exceed `max_backends` or another backend is running for the spawn request's
+    /// key. Admission is atomic, so racing requests with the same key admit one backend.
+    pub fn admit(&self, spawn_request: &SpawnRequest, max_backends: Option<u32>) -> Admission {
+        self.admissions.admit(spawn_request, max_backends)
+    }
+
+    /// The number of backends which have not yet terminated.
+    pub fn backend_count(&self) -> usize {
+        self.admissions.len()
+    }
+
     pub async fn step(
         &self,
         spawn_request: &SpawnRequest,
@@ -229,22 +1162,59 @@ impl Executor {
     ) -> Result<Option<BackendState>> {
         match state {
             BackendState::Loading => {
-                self.docker
-                    .pull_image(&spawn_request.image, &spawn_request.credentials)
-                    .await?;
+                let backend_id = self.container_name(&spawn_request.backend_id);
+                self.image_policy.check(&spawn_request.image).await?;
+                // Only the container sees resolved secrets and its certificate; the stored
+                // spawn request keeps the references.
+                let mut spawn_request = self.secrets.resolve(spawn_request).await?;
+                if let Some(backend_ca) = &self.backend_ca {
+                    spawn_request
+                        .env
+                        .extend(backend_ca.backend_env(&spawn_request.backend_id)?);
+                }
+                let spawn_request = &spawn_request;
 
-                let backend_id = spawn_request.backend_id.to_resource_name();
-                self.docker
-                    .run_container(&backend_id, &spawn_request.image, &spawn_request.env)
-                    .await?;
+                if self.warm_pool.claim(&backend_id, spawn_request).await {
+                    return Ok(Some(BackendState::Starting));
+                }
+
+                do_with_backoff(
+                    || {
+                        self.engine.pull_image(
+                            &spawn_request.image,
+                            &spawn_request.credentials,
+                            spawn_request.pull_policy,
+                        )
+                    },
+                    &self.retry_policy,
+                )
+                .await?;
+
+                do_with_backoff(
+                    || async {
+                        // A failed attempt may have left a container behind.
+                        self.engine.remove_container(&backend_id).await?;
+                        self.engine.run_container(&backend_id, spawn_request).await
+                    },
+                    &self.retry_policy,
+                )
+                .await?;
                 tracing::info!(%backend_id, "Container is running.");
 
                 Ok(Some(BackendState::Starting))
             }
             BackendState::Starting => {
+                if self
+                    .engine
+                    .is_restarting(&self.container_name(&spawn_request.backend_id))
+                    .await?
+                {
+                    return Ok(Some(BackendState::Restarting));
+                }
+
                 if !self
-                    .docker
-                    .is_running(&spawn_request.backend_id.to_resource_name())
+                    .engine
+                    .is_running(&self.container_name(&spawn_request.backend_id))
                     .await?
                     .0
                 {
@@ -252,33 +1222,170 @@ impl Executor {
                 }
 
                 let port = self
-                    .docker
-                    .get_port(&spawn_request.backend_id.to_resource_name())
+                    .engine
+                    .get_port(
+                        &self.container_name(&spawn_request.backend_id),
+                        self.engine.container_port(spawn_request),
+                    )
                     .await
                     .ok_or_else(|| {
                         anyhow!(
                             "Couldn't get port of container {}",
-                            spawn_request.backend_id.to_resource_name()
+                            self.container_name(&spawn_request.backend_id)
                         )
                     })?;
 
                 tracing::info!(%port, "Got port from container.");
-                wait_port_ready(port, self.host_ip).await?;
+                match &spawn_request.readiness_probe {
+                    Some(probe) => {
+                        wait_probe_ready(SocketAddr::new(self.host_ip, port), probe).await?
+                    }
+                    // Backends serving TLS can't answer the plaintext request of the
+                    // default check, so they only need to accept connections.
+                    None if self.backend_ca.is_some() => {
+                        wait_probe_ready(
+                            SocketAddr::new(self.host_ip, port),
+                            &ReadinessProbe {
+                                check: ReadinessCheck::Tcp,
+                                timeout: None,
+                                interval: None,
+                                max_attempts: None,
+                            },
+                        )
+                        .await?
+                    }
+                    None => wait_port_ready(port, self.host_ip).await?,
+                }
+
+                // If the image defines a health check, it must pass before the backend is ready.
+                loop {
+                    match self
+                        .engine
+                        .get_health(&self.container_name(&spawn_request.backend_id))
+                        .await?
+                    {
+                        None | Some(ContainerHealth::Healthy) => break,
+                        Some(ContainerHealth::Unhealthy) => {
+                            tracing::warn!("Container reported unhealthy while starting.");
+                            return Ok(Some(BackendState::ErrorStarting));
+                        }
+                        Some(ContainerHealth::Starting) => {
+                            tokio::time::sleep(HEALTH_POLL_INTERVAL).await
+                        }
+                    }
+                }
+
+                self.add_route(
+                    spawn_request,
+                    spawn_request.backend_id.id().to_string(),
+                    port,
+                )
+                .await?;
+                if let Some(custom_domain) = &spawn_request.custom_domain {
+                    self.add_custom_domain(spawn_request, custom_domain, port)
+                        .await;
+                }
+
+                for (port_name, container_port) in &spawn_request.additional_ports {
+                    let port = self
+                        .engine
+                        .get_port(
+                            &self.container_name(&spawn_request.backend_id),
+                            *container_port,
+                        )
+                        .await
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "Couldn't get port {} of container {}",
+                                port_name,
+                                self.container_name(&spawn_request.backend_id)
+                            )
+                        })?;
 
-                self.database
-                    .insert_proxy_route(
-                        &spawn_request.backend_id,
-                        spawn_request.backend_id.id(),
-                        &format!("{}:{}", self.host_ip, port),
+                    self.add_route(
+                        spawn_request,
+                        spawn_request.additional_port_subdomain(port_name),
+                        port,
                     )
                     .await?;
+                }
+
+                let mut backend_info = BackendInfoMessage::default();
+                for (port_name, container_port) in &spawn_request.tcp_ports {
+                    let port = self
+                        .engine
+                        .get_port(
+                            &self.container_name(&spawn_request.backend_id),
+                            *container_port,
+                        )
+                        .await
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "Couldn't get TCP port {} of container {}",
+                                port_name,
+                                self.container_name(&spawn_request.backend_id)
+                            )
+                        })?;
+
+                    let tcp_port = self.add_tcp_route(spawn_request, port_name, port).await?;
+                    backend_info.tcp_ports.insert(port_name.clone(), tcp_port);
+                }
+
+                for (port_name, container_port) in &spawn_request.udp_ports {
+                    let port = self
+                        .engine
+                        .get_udp_port(
+                            &self.container_name(&spawn_request.backend_id),
+                            *container_port,
+                        )
+                        .await
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "Couldn't get UDP port {} of container {}",
+                                port_name,
+                                self.container_name(&spawn_request.backend_id)
+                            )
+                        })?;
+
+                    let udp_port = self.add_udp_route(spawn_request, port_name, port).await?;
+                    backend_info.udp_ports.insert(port_name.clone(), udp_port);
+                }
+
+                backend_info.resource_limits = match self
+                    .engine
+                    .effective_resource_limits(&self.container_name(&spawn_request.backend_id))
+                    .await
+                {
+                    Ok(resource_limits) => resource_limits,
+                    Err(error) => {
+                        tracing::warn!(?error, "Couldn't read resource limits of container.");
+                        None
+                    }
+                };
+
+                self.nc
+                    .publish(
+                        &BackendInfoMessage::subject(&spawn_request.backend_id),
+                        &backend_info,
+                    )
+                    .await?;
+                self.backend_info
+                    .insert(spawn_request.backend_id.clone(), backend_info);
 
                 Ok(Some(BackendState::Ready))
             }
             BackendState::Ready => {
+                if self
+                    .engine
+                    .is_restarting(&self.container_name(&spawn_request.backend_id))
+                    .await?
+                {
+                    return Ok(Some(BackendState::Restarting));
+                }
+
                 if let (false, exit_code) = self
-                    .docker
-                    .is_running(&spawn_request.backend_id.to_resource_name())
+                    .engine
+                    .is_running(&self.container_name(&spawn_request.backend_id))
                     .await?
                 {
                     if exit_code == Some(0) {
@@ -288,15 +1395,21 @@ impl Executor {
                     }
                 }
 
+                if self
+                    .engine
+                    .get_health(&self.container_name(&spawn_request.backend_id))
+                    .await?
+                    == Some(ContainerHealth::Unhealthy)
+                {
+                    return Ok(Some(BackendState::Unhealthy));
+                }
+
                 // wait for idle
                 loop {
-                    let last_active = self
-                        .database
-                        .get_backend_last_active(&spawn_request.backend_id)
-                        .await?;
+                    let last_active = self.last_active(&spawn_request.backend_id).await?;
                     let next_check = last_active
                         .checked_add_signed(chrono::Duration::from_std(
-                            spawn_request.max_idle_secs,
+                            self.idle_timeout(spawn_request),
                         )?)
                         .ok_or_else(|| anyhow!("Checked add error."))?;
 
@@ -310,22 +1423,159 @@ impl Executor {
 
                 Ok(Some(BackendState::Swept))
             }
+            BackendState::Restarting => {
+                let container_name = self.container_name(&spawn_request.backend_id);
+                while self.engine.is_restarting(&container_name).await? {
+                    tokio::time::sleep(RESTART_POLL_INTERVAL).await;
+                }
+
+                match self.engine.is_running(&container_name).await? {
+                    (true, _) => Ok(Some(BackendState::Starting)),
+                    (false, Some(0)) => Ok(Some(BackendState::Exited)),
+                    (false, _) => Ok(Some(BackendState::Failed)),
+                }
+            }
             BackendState::ErrorLoading
             | BackendState::ErrorStarting
             | BackendState::TimedOutBeforeReady
             | BackendState::Failed
             | BackendState::Exited
-            | BackendState::Swept => {
-                let container_name = spawn_request.backend_id.to_resource_name();
-                if self.docker.is_running(&container_name).await?.0 {
-                    self.docker
-                        .stop_container(&container_name)
+            | BackendState::Swept
+            | BackendState::Unhealthy
+            | BackendState::Expired
+            | BackendState::Evicted
+            | BackendState::Lost => {
+                self.routes.remove_backend(&spawn_request.backend_id).await;
+                let container_name = self.container_name(&spawn_request.backend_id);
+                if self.engine.is_running(&container_name).await?.0 {
+                    self.drain(&spawn_request.backend_id).await;
+                    self.engine
+                        .stop_container(
+                            &container_name,
+                            spawn_request
+                                .stop_grace_period_secs
+                                .unwrap_or(DEFAULT_STOP_GRACE_PERIOD),
+                        )
                         .await
                         .map_err(|e| anyhow!("Error stopping container: {:?}", e))?;
                 }
 
+                if !spawn_request.tcp_ports.is_empty() || !spawn_request.udp_ports.is_empty() {
+                    self.database
+                        .release_port_routes(&spawn_request.backend_id)
+                        .await?;
+                }
+
+                if self.engine.network(spawn_request) == BackendNetwork::Isolated {
+                    self.engine
+                        .remove_isolated_network(&container_name)
+                        .await
+                        .map_err(|e| anyhow!("Error removing network: {:?}", e))?;
+                }
+
                 Ok(None)
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn spawn_request(backend_id: &str, key: Option<&str>) -> SpawnRequest {
+        let mut spawn_request: SpawnRequest = serde_json::from_value(serde_json::json!({
+            "image": "image",
+            "backend_id": backend_id,
+            "max_idle_secs": 60,
+            "env": {},
+            "metadata": {},
+        }))
+        .unwrap();
+        spawn_request.key = key.map(str::to_string);
+        spawn_request
+    }
+
+    #[test]
+    fn test_admit_at_capacity() {
+        let admissions = Admissions::default();
+        assert_eq!(
+            Admission::Admitted,
+            admissions.admit(&spawn_request("backend-1", None), Some(2))
+        );
+        assert_eq!(
+            Admission::Admitted,
+            admissions.admit(&spawn_request("backend-2", None), Some(2))
+        );
+        assert_eq!(
+            Admission::AtCapacity,
+            admissions.admit(&spawn_request("backend-3", None), Some(2))
+        );
+        // Without a limit, there is always room.
+        assert_eq!(
+            Admission::Admitted,
+            admissions.admit(&spawn_request("backend-3", None), None)
+        );
+        assert_eq!(3, admissions.len());
+
+        admissions.release(&spawn_request("backend-1", None));
+        admissions.release(&spawn_request("backend-3", None));
+        assert_eq!(
+            Admission::Admitted,
+            admissions.admit(&spawn_request("backend-4", None), Some(2))
+        );
+    }
+
+    #[test]
+    fn test_admit_same_backend() {
+        let admissions = Admissions::default();
+        let request = spawn_request("backend-1", Some("key"));
+        assert_eq!(Admission::Admitted, admissions.admit(&request, Some(1)));
+        // A duplicate spawn request finds the backend already running, rather than
+        // starting it again or finding the drone over capacity.
+        assert_eq!(
+            Admission::Existing(BackendId::new("backend-1".to_string())),
+            admissions.admit(&request, Some(1))
+        );
+        assert_eq!(
+            Admission::Existing(BackendId::new("backend-1".to_string())),
+            admissions.admit(&spawn_request("backend-1", None), None)
+        );
+        assert_eq!(1, admissions.len());
+
+        // Resuming a backend counts it once, even beyond capacity.
+        admissions.readmit(&request);
+        admissions.readmit(&spawn_request("backend-2", None));
+        assert_eq!(2, admissions.len());
+        assert!(admissions.contains(&BackendId::new("backend-1".to_string())));
+    }
+
+    #[test]
+    fn test_admit_same_key() {
+        let admissions = Admissions::default();
+        assert_eq!(
+            Admission::Admitted,
+            admissions.admit(&spawn_request("backend-1", Some("key")), None)
+        );
+        assert_eq!(
+            Admission::Existing(BackendId::new("backend-1".to_string())),
+            admissions.admit(&spawn_request("backend-2", Some("key")), None)
+        );
+        assert_eq!(
+            Admission::Admitted,
+            admissions.admit(&spawn_request("backend-2", Some("other-key")), None)
+        );
+
+        // Releasing a backend frees its key, but not a key held by another backend.
+        admissions.release(&spawn_request("backend-2", Some("key")));
+        assert_eq!(
+            Admission::Existing(BackendId::new("backend-1".to_string())),
+            admissions.admit(&spawn_request("backend-3", Some("key")), None)
+        );
+        admissions.release(&spawn_request("backend-1", Some("key")));
+        assert_eq!(
+            Admission::Admitted,
+            admissions.admit(&spawn_request("backend-3", Some("key")), None)
+        );
+    }
+}
@@ -0,0 +1,1042 @@
+use super::{
+    docker::image_registry,
+    engine::{
+        ContainerEvent, ContainerEventType, ContainerHealth, ContainerStats, Engine,
+        ManagedContainer,
+    },
+    DockerOptions,
+};
+use crate::messages::agent::{
+    BackendNetwork, EffectiveResourceLimits, PullPolicy, ResourceLimits, RestartPolicy,
+    SpawnRequest, Ulimits,
+};
+use anyhow::{anyhow, Context, Result};
+use async_stream::stream;
+use async_trait::async_trait;
+use bollard::{auth::DockerCredentials, container::LogOutput};
+use chrono::{SecondsFormat, Utc};
+use dashmap::DashMap;
+use futures::stream::BoxStream;
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    net::Ipv4Addr,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncBufReadExt, BufReader},
+    net::{TcpListener, TcpStream},
+    process::Command,
+    sync::{broadcast, mpsc::Sender, watch, Notify},
+    task::JoinHandle,
+};
+
+/// Memory given to a microVM whose spawn request does not limit it.
+const DEFAULT_MEMORY_MIB: i64 = 512;
+
+/// Smallest amount of memory a microVM is given, regardless of its spawn request.
+const MIN_MEMORY_MIB: i64 = 128;
+
+/// Most vCPUs a microVM is given, regardless of its spawn request.
+const MAX_VCPU_COUNT: i64 = 32;
+
+/// Each microVM is given a /30 subnet from 172.30.0.0/16, in which the host end of its
+/// TAP device and the guest take the two usable addresses.
+const SUBNET_BASE: Ipv4Addr = Ipv4Addr::new(172, 30, 0, 0);
+const SUBNET_COUNT: u32 = 1 << 14;
+
+/// Path within the root filesystem of the script which starts the image's process.
+const INIT_PATH: &str = "/spawner-init";
+
+/// Printed to the console by the init script when the image's process exits.
+const EXIT_CODE_MARKER: &str = "spawner-exit-code: ";
+
+/// Exit code reported for microVMs stopped by the drone, matching Docker's for
+/// killed containers.
+const KILLED_EXIT_CODE: i64 = 137;
+
+/// How often to check the console log of a running microVM for new output.
+const LOG_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Capacity of the channel that microVM events are broadcast on.
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+/// The part of the OCI runtime spec (written by `umoci unpack`) that describes the
+/// image's process.
+#[derive(Deserialize)]
+struct RuntimeSpec {
+    process: RuntimeProcess,
+}
+
+#[derive(Deserialize)]
+struct RuntimeProcess {
+    args: Vec<String>,
+
+    #[serde(default)]
+    env: Vec<String>,
+
+    #[serde(default)]
+    cwd: String,
+}
+
+/// A running (or exited) microVM.
+struct MicroVm {
+    /// Host ports that the guest's TCP ports are forwarded from, keyed by guest port.
+    ports: HashMap<u16, u16>,
+
+    /// Set to the exit code of the image's process when the microVM exits.
+    exit_code: watch::Receiver<Option<i64>>,
+
+    /// Notified to shut the microVM down.
+    stop: Arc<Notify>,
+
+    /// File that the microVM's serial console is written to.
+    console_path: PathBuf,
+}
+
+/// Runs each backend in its own Firecracker microVM, for stronger isolation
+/// than containers provide.
+///
+/// Images are converted into ext4 root filesystems when they are pulled. The
+/// kernel must be built with `CONFIG_IP_PNP` (the guest's network is configured
+/// through the kernel command line) and `CONFIG_DEVTMPFS_MOUNT`, and images must
+/// contain `/bin/sh`, which runs the image's process.
+///
+/// Guests are reached through TCP ports forwarded from the host by the agent.
+/// Outbound traffic from guests is routed through their TAP devices, so hosts
+/// which should give guests internet access need to masquerade 172.30.0.0/16.
+pub struct FirecrackerInterface {
+    firecracker_path: PathBuf,
+    kernel_image_path: PathBuf,
+    work_directory: PathBuf,
+    default_container_port: u16,
+    default_network: BackendNetwork,
+
+    /// Credentials for pulling images, keyed by registry host. Used when the
+    /// spawn request does not carry its own credentials.
+    registry_credentials: HashMap<String, DockerCredentials>,
+
+    /// MicroVMs started by this agent, keyed by name.
+    vms: Arc<DashMap<String, MicroVm>>,
+
+    /// Indices of the subnets assigned to running microVMs.
+    subnets: Arc<Mutex<HashSet<u32>>>,
+
+    events: broadcast::Sender<ContainerEvent>,
+
+    /// Held while converting an image, so that concurrent pulls of the same image
+    /// do not write to the same files.
+    pull_lock: tokio::sync::Mutex<()>,
+}
+
+/// Run a program to completion, failing with its standard error if it is unsuccessful.
+async fn run_command<S: AsRef<OsStr>>(program: &str, args: &[S]) -> Result<()> {
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .with_context(|| format!("Error running {}.", program))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Remove a directory and its contents, succeeding if it does not exist.
+async fn remove_directory(path: &Path) -> Result<()> {
+    match fs::remove_dir_all(path).await {
+        Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error.into()),
+        _ => Ok(()),
+    }
+}
+
+/// The total size of the regular files within a directory.
+fn directory_size(path: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry_path = entry?.path();
+        let metadata = entry_path.symlink_metadata()?;
+        if metadata.is_dir() {
+            size += directory_size(&entry_path)?;
+        } else if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+
+    Ok(size)
+}
+
+/// Quote a string for use as a single word in a shell script.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r#"'\''"#))
+}
+
+/// The addresses of the host and guest ends of the subnet with the given index.
+fn subnet_addresses(index: u32) -> (Ipv4Addr, Ipv4Addr) {
+    let base = u32::from(SUBNET_BASE) + index * 4;
+    (Ipv4Addr::from(base + 1), Ipv4Addr::from(base + 2))
+}
+
+/// A locally-administered MAC address derived from the guest's address.
+fn guest_mac(guest_address: Ipv4Addr) -> String {
+    let [a, b, c, d] = guest_address.octets();
+    format!("06:00:{:02x}:{:02x}:{:02x}:{:02x}", a, b, c, d)
+}
+
+fn vcpu_count(resource_limits: &ResourceLimits) -> i64 {
+    match (resource_limits.cpu_quota, resource_limits.cpu_period) {
+        (Some(quota), period) => {
+            let period = period
+                .unwrap_or(Duration::from_millis(100))
+                .as_micros()
+                .max(1);
+            let vcpus = quota.as_micros().div_ceil(period);
+            (vcpus as i64).clamp(1, MAX_VCPU_COUNT)
+        }
+        (None, _) => 1,
+    }
+}
+
+fn memory_mib(resource_limits: &ResourceLimits) -> i64 {
+    resource_limits
+        .memory_limit_bytes
+        .map(|bytes| bytes / (1024 * 1024))
+        .unwrap_or(DEFAULT_MEMORY_MIB)
+        .max(MIN_MEMORY_MIB)
+}
+
+/// The name of the first requested feature that microVMs do not support, if any.
+fn unsupported_feature(spawn_request: &SpawnRequest) -> Option<&'static str> {
+    if spawn_request.gpus > 0 {
+        Some("GPUs")
+    } else if !spawn_request.volume_mounts.is_empty() {
+        Some("volume mounts")
+    } else if !spawn_request.tmpfs_mounts.is_empty() {
+        Some("tmpfs mounts")
+    } else if !spawn_request.udp_ports.is_empty() {
+        Some("UDP ports")
+    } else if spawn_request.user.is_some() {
+        Some("user overrides")
+    } else if spawn_request.resource_limits.cpu_weight.is_some()
+        || spawn_request.resource_limits.cpu_burst.is_some()
+    {
+        Some("CPU weights or bursts")
+    } else if spawn_request.init == Some(true) {
+        Some("init processes")
+    } else if spawn_request.shm_size_bytes.is_some() {
+        Some("/dev/shm sizes")
+    } else if spawn_request.pids_limit.is_some() {
+        Some("PID limits")
+    } else if spawn_request.ulimits != Ulimits::default() {
+        Some("ulimits")
+    } else if spawn_request.restart_policy != RestartPolicy::Never {
+        Some("restart policies")
+    } else if spawn_request.stop_signal.is_some() || spawn_request.stop_grace_period_secs.is_some()
+    {
+        Some("stop signals or grace periods")
+    } else if matches!(spawn_request.network, Some(BackendNetwork::Named(_))) {
+        Some("named networks")
+    } else {
+        None
+    }
+}
+
+/// The script which runs as the guest's init process. It starts the image's process
+/// with the environment from the image and the spawn request, and reports its exit
+/// code on the console. When it exits, the guest kernel panics and the microVM stops.
+fn init_script(process: &RuntimeProcess) -> String {
+    let mut script = String::from("#!/bin/sh\n# Generated by spawner.\n");
+    script.push_str("if command -v mount >/dev/null; then\n");
+    script.push_str("  mount -t proc proc /proc 2>/dev/null\n");
+    script.push_str("  mount -t sysfs sysfs /sys 2>/dev/null\n");
+    script.push_str("fi\n");
+
+    for variable in &process.env {
+        if let Some((key, value)) = variable.split_once('=') {
+            script.push_str(&format!("export {}={}\n", key, shell_quote(value)));
+        }
+    }
+
+    // The spawn request's environment is provided on a second drive.
+    script.push_str(". /dev/vdb\n");
+    if !process.cwd.is_empty() {
+        script.push_str(&format!("cd {} || exit 1\n", shell_quote(&process.cwd)));
+    }
+
+    let args: Vec<String> = process.args.iter().map(|arg| shell_quote(arg)).collect();
+    script.push_str(&args.join(" "));
+    script.push_str("\nstatus=$?\n");
+    script.push_str(&format!("echo \"{}$status\"\n", EXIT_CODE_MARKER));
+    script.push_str("exit $status\n");
+
+    script
+}
+
+/// The contents of the drive which provides the spawn request's environment to the
+/// init script. The drive is sourced as a shell script, and padded with newlines to
+/// a whole number of sectors.
+fn config_drive(env: &HashMap<String, String>) -> Vec<u8> {
+    let mut contents = String::new();
+    for (key, value) in env {
+        contents.push_str(&format!("export {}={}\n", key, shell_quote(value)));
+    }
+
+    let mut contents = contents.into_bytes();
+    let padded_len = (contents.len() / 512 + 1) * 512;
+    contents.resize(padded_len, b'\n');
+    contents
+}
+
+/// The exit code reported by the init script in the console output, if any.
+fn parse_exit_code(console: &str) -> Option<i64> {
+    console
+        .lines()
+        .rev()
+        .find_map(|line| line.trim().strip_prefix(EXIT_CODE_MARKER))
+        .and_then(|code| code.trim().parse().ok())
+}
+
+/// Forward connections on the listener to the given address.
+fn forward_port(listener: TcpListener, target: (Ipv4Addr, u16)) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let mut inbound = match listener.accept().await {
+                Ok((inbound, _)) => inbound,
+                Err(error) => {
+                    tracing::warn!(?error, "Error accepting forwarded connection.");
+                    continue;
+                }
+            };
+
+            tokio::spawn(async move {
+                match TcpStream::connect(target).await {
+                    Ok(mut outbound) => {
+                        let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                    }
+                    Err(error) => {
+                        tracing::warn!(?error, ?target, "Error connecting to microVM.");
+                    }
+                }
+            });
+        }
+    })
+}
+
+impl FirecrackerInterface {
+    pub async fn try_new(config: &DockerOptions) -> Result<Self> {
+        let options = config
+            .firecracker_options
+            .as_ref()
+            .ok_or_else(|| anyhow!("Expected Firecracker options for the Firecracker engine."))?;
+
+        fs::create_dir_all(options.work_directory.join("images")).await?;
+        fs::create_dir_all(options.work_directory.join("vms")).await?;
+
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+
+        Ok(FirecrackerInterface {
+            firecracker_path: options.firecracker_path.clone(),
+            kernel_image_path: options.kernel_image_path.clone(),
+            work_directory: options.work_directory.clone(),
+            default_container_port: config.default_container_port,
+            default_network: config.default_network.clone(),
+            registry_credentials: config.load_registry_credentials()?,
+            vms: Arc::default(),
+            subnets: Arc::default(),
+            events,
+            pull_lock: tokio::sync::Mutex::default(),
+        })
+    }
+
+    fn image_directory(&self, image: &str) -> PathBuf {
+        let name: String = image
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        self.work_directory.join("images").join(name)
+    }
+
+    fn image_rootfs_path(&self, image: &str) -> PathBuf {
+        self.image_directory(image).join("rootfs.ext4")
+    }
+
+    /// Write a registry auth file for skopeo, so that credentials are not passed
+    /// on its command line.
+    async fn write_auth_file(
+        &self,
+        path: &Path,
+        image: &str,
+        credentials: &DockerCredentials,
+    ) -> Result<()> {
+        let auth = format!(
+            "{}:{}",
+            credentials.username.as_deref().unwrap_or_default(),
+            credentials.password.as_deref().unwrap_or_default()
+        );
+        let contents = json!({
+            "auths": {
+                image_registry(image): {
+                    "auth": openssl::base64::encode_block(auth.as_bytes()),
+                },
+            },
+        });
+
+        fs::write(path, serde_json::to_vec(&contents)?).await?;
+        fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+
+        Ok(())
+    }
+
+    /// Convert an image into an ext4 root filesystem containing the init script.
+    async fn convert_image(
+        &self,
+        image: &str,
+        credentials: &Option<DockerCredentials>,
+    ) -> Result<()> {
+        let _guard = self.pull_lock.lock().await;
+
+        let image_directory = self.image_directory(image);
+        let layout = image_directory.join("oci");
+        let bundle = image_directory.join("bundle");
+        let auth_file = image_directory.join("auth.json");
+        fs::create_dir_all(&image_directory).await?;
+
+        // Clear out the remains of an interrupted conversion.
+        remove_directory(&layout).await?;
+        remove_directory(&bundle).await?;
+
+        let mut copy_args = vec!["copy".to_string()];
+        if let Some(credentials) = credentials {
+            self.write_auth_file(&auth_file, image, credentials).await?;
+            copy_args.push("--src-authfile".to_string());
+            copy_args.push(auth_file.to_string_lossy().to_string());
+        }
+        copy_args.push(format!("docker://{}", image));
+        copy_args.push(format!("oci:{}:latest", layout.to_string_lossy()));
+
+        let copy_result = run_command("skopeo", &copy_args).await;
+        if credentials.is_some() {
+            fs::remove_file(&auth_file).await?;
+        }
+        copy_result?;
+
+        run_command(
+            "umoci",
+            &[
+                "unpack".to_string(),
+                "--image".to_string(),
+                format!("{}:latest", layout.to_string_lossy()),
+                bundle.to_string_lossy().to_string(),
+            ],
+        )
+        .await?;
+
+        let spec: RuntimeSpec =
+            serde_json::from_slice(&fs::read(bundle.join("config.json")).await?)
+                .context("Error parsing the runtime spec of the unpacked image.")?;
+        let rootfs = bundle.join("rootfs");
+        let init_path = rootfs.join(INIT_PATH.trim_start_matches('/'));
+        fs::write(&init_path, init_script(&spec.process)).await?;
+        fs::set_permissions(&init_path, std::fs::Permissions::from_mode(0o755)).await?;
+
+        // Leave room for the filesystem's own structures and for the backend's writes.
+        let size_mib = {
+            let rootfs = rootfs.clone();
+            tokio::task::spawn_blocking(move || directory_size(&rootfs)).await?? / (1024 * 1024)
+        };
+        let size_mib = size_mib + size_mib / 4 + 64;
+
+        let staged_path = image_directory.join("rootfs.ext4.tmp");
+        run_command(
+            "mkfs.ext4",
+            &[
+                "-F".to_string(),
+                "-q".to_string(),
+                "-L".to_string(),
+                "rootfs".to_string(),
+                "-d".to_string(),
+                rootfs.to_string_lossy().to_string(),
+                staged_path.to_string_lossy().to_string(),
+                format!("{}M", size_mib),
+            ],
+        )
+        .await?;
+        fs::rename(&staged_path, self.image_rootfs_path(image)).await?;
+
+        remove_directory(&layout).await?;
+        remove_directory(&bundle).await?;
+
+        Ok(())
+    }
+
+    fn allocate_subnet(&self) -> Result<u32> {
+        let mut subnets = self
+            .subnets
+            .lock()
+            .map_err(|_| anyhow!("Subnet allocation lock poisoned."))?;
+        let index = (0..SUBNET_COUNT)
+            .find(|index| !subnets.contains(index))
+            .ok_or_else(|| anyhow!("No subnets are left to assign to microVMs."))?;
+        subnets.insert(index);
+
+        Ok(index)
+    }
+
+    async fn create_tap_device(tap_name: &str, host_address: Ipv4Addr) -> Result<()> {
+        // Remove a device left behind by a previous run of the agent.
+        let _ = run_command("ip", &["link", "del", tap_name]).await;
+
+        run_command("ip", &["tuntap", "add", "dev", tap_name, "mode", "tap"]).await?;
+        run_command(
+            "ip",
+            &[
+                "addr",
+                "add",
+                &format!("{}/30", host_address),
+                "dev",
+                tap_name,
+            ],
+        )
+        .await?;
+        run_command("ip", &["link", "set", tap_name, "up"]).await?;
+
+        Ok(())
+    }
+
+    /// Start the microVM, forward its ports, and return once it is running.
+    async fn start_vm(
+        &self,
+        name: &str,
+        spawn_request: &SpawnRequest,
+        subnet: u32,
+        tap_name: &str,
+    ) -> Result<()> {
+        let (host_address, guest_address) = subnet_addresses(subnet);
+        let vm_directory = self.work_directory.join("vms").join(name);
+        remove_directory(&vm_directory).await?;
+        fs::create_dir_all(&vm_directory).await?;
+
+        let rootfs_path = vm_directory.join("rootfs.ext4");
+        let config_drive_path = vm_directory.join("config.drive");
+        let config_path = vm_directory.join("config.json");
+        let console_path = vm_directory.join("console.log");
+        let firecracker_log_path = vm_directory.join("firecracker.log");
+
+        let image_rootfs_path = self.image_rootfs_path(&spawn_request.image);
+        if !image_rootfs_path.exists() {
+            return Err(anyhow!(
+                "Image {} has not been converted for Firecracker.",
+                spawn_request.image
+            ));
+        }
+        run_command(
+            "cp",
+            &[
+                OsStr::new("--reflink=auto"),
+                OsStr::new("--sparse=always"),
+                image_rootfs_path.as_os_str(),
+                rootfs_path.as_os_str(),
+            ],
+        )
+        .await?;
+        fs::write(&config_drive_path, config_drive(&spawn_request.env)).await?;
+
+        Self::create_tap_device(tap_name, host_address).await?;
+
+        let read_only = spawn_request.read_only_root_filesystem;
+        let config = json!({
+            "boot-source": {
+                "kernel_image_path": self.kernel_image_path,
+                "boot_args": format!(
+                    "console=ttyS0 reboot=k panic=1 pci=off quiet {} ip={}::{}:255.255.255.252::eth0:off init={}",
+                    if read_only { "ro" } else { "rw" },
+                    guest_address,
+                    host_address,
+                    INIT_PATH,
+                ),
+            },
+            "drives": [
+                {
+                    "drive_id": "rootfs",
+                    "path_on_host": rootfs_path,
+                    "is_root_device": true,
+                    "is_read_only": read_only,
+                },
+                {
+                    "drive_id": "config",
+                    "path_on_host": config_drive_path,
+                    "is_root_device": false,
+                    "is_read_only": true,
+                },
+            ],
+            "machine-config": {
+                "vcpu_count": vcpu_count(&spawn_request.resource_limits),
+                "mem_size_mib": memory_mib(&spawn_request.resource_limits),
+            },
+            "network-interfaces": [
+                {
+                    "iface_id": "eth0",
+                    "guest_mac": guest_mac(guest_address),
+                    "host_dev_name": tap_name,
+                },
+            ],
+        });
+        fs::write(&config_path, serde_json::to_vec_pretty(&config)?).await?;
+        File::create(&firecracker_log_path).await?;
+
+        let console = File::create(&console_path).await?.into_std().await;
+        let child = Command::new(&self.firecracker_path)
+            .arg("--no-api")
+            .arg("--config-file")
+            .arg(&config_path)
+            .arg("--log-path")
+            .arg(&firecracker_log_path)
+            .stdin(Stdio::null())
+            .stdout(console)
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .context("Error running firecracker.")?;
+
+        let mut ports = HashMap::new();
+        let mut forwarders = Vec::new();
+        let guest_ports = std::iter::once(self.container_port(spawn_request))
+            .chain(spawn_request.additional_ports.values().copied())
+            .chain(spawn_request.tcp_ports.values().copied());
+        for guest_port in guest_ports {
+            let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+            ports.insert(guest_port, listener.local_addr()?.port());
+            forwarders.push(forward_port(listener, (guest_address, guest_port)));
+        }
+
+        let (exit_code_sender, exit_code) = watch::channel(None);
+        let stop = Arc::new(Notify::new());
+        self.vms.insert(
+            name.to_string(),
+            MicroVm {
+                ports,
+                exit_code,
+                stop: stop.clone(),
+                console_path: console_path.clone(),
+            },
+        );
+
+        tokio::spawn(supervise(Supervision {
+            name: name.to_string(),
+            child,
+            stop,
+            exit_code: exit_code_sender,
+            forwarders,
+            console_path,
+            rootfs_path,
+            tap_name: tap_name.to_string(),
+            subnet,
+            subnets: self.subnets.clone(),
+            events: self.events.clone(),
+        }));
+
+        self.events
+            .send(ContainerEvent {
+                event: ContainerEventType::Start,
+                name: name.to_string(),
+            })
+            .ok();
+
+        Ok(())
+    }
+}
+
+/// Everything needed to wait for a microVM to exit and clean up after it.
+struct Supervision {
+    name: String,
+    child: tokio::process::Child,
+    stop: Arc<Notify>,
+    exit_code: watch::Sender<Option<i64>>,
+    forwarders: Vec<JoinHandle<()>>,
+    console_path: PathBuf,
+    rootfs_path: PathBuf,
+    tap_name: String,
+    subnet: u32,
+    subnets: Arc<Mutex<HashSet<u32>>>,
+    events: broadcast::Sender<ContainerEvent>,
+}
+
+async fn supervise(mut supervision: Supervision) {
+    let stopped = tokio::select! {
+        result = supervision.child.wait() => {
+            if let Err(error) = result {
+                tracing::error!(?error, name = %supervision.name, "Error waiting for microVM.");
+            }
+            false
+        }
+        _ = supervision.stop.notified() => {
+            if let Err(error) = supervision.child.kill().await {
+                tracing::error!(?error, name = %supervision.name, "Error stopping microVM.");
+            }
+            true
+        }
+    };
+
+    for forwarder in &supervision.forwarders {
+        forwarder.abort();
+    }
+
+    let console = fs::read_to_string(&supervision.console_path)
+        .await
+        .unwrap_or_default();
+    let exit_code = match parse_exit_code(&console) {
+        Some(exit_code) => exit_code,
+        None if stopped => KILLED_EXIT_CODE,
+        None => 1,
+    };
+    tracing::info!(name = %supervision.name, exit_code, "MicroVM exited.");
+
+    if let Err(error) = run_command("ip", &["link", "del", &supervision.tap_name]).await {
+        tracing::warn!(?error, tap = %supervision.tap_name, "Error removing TAP device.");
+    }
+    if let Err(error) = fs::remove_file(&supervision.rootfs_path).await {
+        tracing::warn!(?error, "Error removing microVM root filesystem.");
+    }
+    if let Ok(mut subnets) = supervision.subnets.lock() {
+        subnets.remove(&supervision.subnet);
+    }
+
+    supervision.exit_code.send(Some(exit_code)).ok();
+    supervision
+        .events
+        .send(ContainerEvent {
+            event: ContainerEventType::Die,
+            name: supervision.name,
+        })
+        .ok();
+}
+
+#[async_trait]
+impl Engine for FirecrackerInterface {
+    fn container_events(&self) -> BoxStream<'_, ContainerEvent> {
+        let mut receiver = self.events.subscribe();
+
+        Box::pin(stream!({
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => yield event,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!(skipped, "MicroVM events were dropped.");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        }))
+    }
+
+    /// Stream the microVM's console output. Lines are timestamped as they are read,
+    /// so `since` is not supported.
+    fn get_logs(&self, container_name: &str, _since: i64) -> BoxStream<'_, Result<LogOutput>> {
+        let vm = self
+            .vms
+            .get(container_name)
+            .map(|vm| (vm.console_path.clone(), vm.exit_code.clone()));
+
+        Box::pin(stream!({
+            let (console_path, exit_code) = match vm {
+                Some(vm) => vm,
+                None => {
+                    yield Err(anyhow!("No microVM found."));
+                    return;
+                }
+            };
+
+            let file = match File::open(&console_path).await {
+                Ok(file) => file,
+                Err(error) => {
+                    yield Err(error.into());
+                    return;
+                }
+            };
+
+            let mut reader = BufReader::new(file);
+            let mut line = String::new();
+            loop {
+                match reader.read_line(&mut line).await {
+                    Ok(0) => {
+                        if exit_code.borrow().is_some() {
+                            return;
+                        }
+                        tokio::time::sleep(LOG_POLL_INTERVAL).await;
+                    }
+                    Ok(_) if line.ends_with('\n') => {
+                        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true);
+                        yield Ok(LogOutput::StdOut {
+                            message: format!("{} {}\n", timestamp, line.trim_end()).into(),
+                        });
+                        line.clear();
+                    }
+                    // Wait for the rest of a partially-written line.
+                    Ok(_) => (),
+                    Err(error) => {
+                        yield Err(error.into());
+                        return;
+                    }
+                }
+            }
+        }))
+    }
+
+    async fn pull_image(
+        &self,
+        image: &str,
+        credentials: &Option<DockerCredentials>,
+        pull_policy: PullPolicy,
+    ) -> Result<()> {
+        let exists = self.image_rootfs_path(image).exists();
+        match pull_policy {
+            PullPolicy::Always => (),
+            PullPolicy::IfNotPresent if exists => {
+                tracing::info!(%image, "Image already converted, skipping pull.");
+                return Ok(());
+            }
+            PullPolicy::IfNotPresent => (),
+            PullPolicy::Never if exists => return Ok(()),
+            PullPolicy::Never => {
+                return Err(anyhow!(
+                    "Image {} is not present and pull policy is Never.",
+                    image
+                ));
+            }
+        }
+
+        let credentials = credentials.clone().or_else(|| {
+            self.registry_credentials
+                .get(image_registry(image))
+                .cloned()
+        });
+
+        self.convert_image(image, &credentials).await
+    }
+
+    async fn run_container(&self, name: &str, spawn_request: &SpawnRequest) -> Result<()> {
+        if let Some(feature) = unsupported_feature(spawn_request) {
+            return Err(anyhow!(
+                "Backends run with Firecracker do not support {}.",
+                feature
+            ));
+        }
+
+        if let Some(vm) = self.vms.get(name) {
+            if vm.exit_code.borrow().is_none() {
+                return Err(anyhow!("MicroVM {} is already running.", name));
+            }
+        }
+
+        let subnet = self.allocate_subnet()?;
+        let tap_name = format!("fc-tap-{}", subnet);
+        let result = self.start_vm(name, spawn_request, subnet, &tap_name).await;
+
+        if result.is_err() {
+            let _ = run_command("ip", &["link", "del", &tap_name]).await;
+            if let Ok(mut subnets) = self.subnets.lock() {
+                subnets.remove(&subnet);
+            }
+        }
+
+        result
+    }
+
+    /// MicroVMs are killed immediately, since the guest cannot be sent a signal.
+    async fn stop_container(&self, name: &str, _grace_period: Duration) -> Result<()> {
+        let (stop, mut exit_code) = match self.vms.get(name) {
+            Some(vm) => (vm.stop.clone(), vm.exit_code.clone()),
+            None => return Err(anyhow!("No microVM found with name {}.", name)),
+        };
+
+        stop.notify_one();
+        while exit_code.borrow().is_none() {
+            exit_code.changed().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn remove_container(&self, name: &str) -> Result<()> {
+        let running = match self.vms.get(name) {
+            Some(vm) => vm.exit_code.borrow().is_none(),
+            None => return Ok(()),
+        };
+        if running {
+            self.stop_container(name, Duration::ZERO).await?;
+        }
+        self.vms.remove(name);
+
+        Ok(())
+    }
+
+    /// The supervisor of a microVM reports its events under the name it was started
+    /// with, so microVMs cannot be renamed.
+    async fn rename_container(&self, name: &str, _new_name: &str) -> Result<()> {
+        Err(anyhow!("MicroVM {} cannot be renamed.", name))
+    }
+
+    async fn is_restarting(&self, _container_name: &str) -> Result<bool> {
+        Ok(false)
+    }
+
+    async fn is_running(&self, container_name: &str) -> Result<(bool, Option<i64>)> {
+        Ok(match self.vms.get(container_name) {
+            Some(vm) => match *vm.exit_code.borrow() {
+                Some(exit_code) => (false, Some(exit_code)),
+                None => (true, None),
+            },
+            None => (false, None),
+        })
+    }
+
+    /// A process killed by the guest's OOM killer is indistinguishable from one killed by
+    /// a signal, so this is always false.
+    async fn is_oom_killed(&self, _container_name: &str) -> Result<bool> {
+        Ok(false)
+    }
+
+    async fn exec(
+        &self,
+        container_name: &str,
+        _command: &[String],
+        _output: Sender<LogOutput>,
+    ) -> Result<Option<i64>> {
+        Err(anyhow!(
+            "Running commands in microVM {} is not supported.",
+            container_name
+        ))
+    }
+
+    async fn effective_resource_limits(
+        &self,
+        _container_name: &str,
+    ) -> Result<Option<EffectiveResourceLimits>> {
+        Ok(None)
+    }
+
+    async fn get_stats(&self, container_name: &str) -> Result<ContainerStats> {
+        Err(anyhow!(
+            "Resource usage of microVM {} is not available.",
+            container_name
+        ))
+    }
+
+    /// Image health checks are not run inside microVMs, so this is always None.
+    async fn get_health(&self, _container_name: &str) -> Result<Option<ContainerHealth>> {
+        Ok(None)
+    }
+
+    async fn get_port(&self, container_name: &str, container_port: u16) -> Option<u16> {
+        self.vms
+            .get(container_name)?
+            .ports
+            .get(&container_port)
+            .copied()
+    }
+
+    async fn get_udp_port(&self, _container_name: &str, _container_port: u16) -> Option<u16> {
+        None
+    }
+
+    fn container_port(&self, spawn_request: &SpawnRequest) -> u16 {
+        spawn_request.port.unwrap_or(self.default_container_port)
+    }
+
+    fn network(&self, spawn_request: &SpawnRequest) -> BackendNetwork {
+        spawn_request
+            .network
+            .clone()
+            .unwrap_or_else(|| self.default_network.clone())
+    }
+
+    /// Every microVM has its own network device, which is removed when it exits.
+    async fn remove_isolated_network(&self, _name: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// MicroVMs are killed when the agent exits, so only those started by this agent
+    /// exist.
+    async fn list_managed_containers(&self) -> Result<Vec<ManagedContainer>> {
+        Ok(self
+            .vms
+            .iter()
+            .map(|vm| ManagedContainer {
+                name: vm.key().clone(),
+                labels: HashMap::new(),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_subnet_addresses() {
+        assert_eq!(
+            (Ipv4Addr::new(172, 30, 0, 1), Ipv4Addr::new(172, 30, 0, 2)),
+            subnet_addresses(0)
+        );
+        assert_eq!(
+            (Ipv4Addr::new(172, 30, 1, 1), Ipv4Addr::new(172, 30, 1, 2)),
+            subnet_addresses(64)
+        );
+        assert_eq!(
+            (
+                Ipv4Addr::new(172, 30, 255, 253),
+                Ipv4Addr::new(172, 30, 255, 254)
+            ),
+            subnet_addresses(SUBNET_COUNT - 1)
+        );
+    }
+
+    #[test]
+    fn test_init_script() {
+        let process = RuntimeProcess {
+            args: vec!["/bin/server".to_string(), "it's".to_string()],
+            env: vec!["PATH=/usr/bin:/bin".to_string()],
+            cwd: "/app".to_string(),
+        };
+
+        assert_eq!(
+            "#!/bin/sh\n# Generated by spawner.\n\
+            if command -v mount >/dev/null; then\n\
+            \x20 mount -t proc proc /proc 2>/dev/null\n\
+            \x20 mount -t sysfs sysfs /sys 2>/dev/null\n\
+            fi\n\
+            export PATH='/usr/bin:/bin'\n\
+            . /dev/vdb\n\
+            cd '/app' || exit 1\n\
+            '/bin/server' 'it'\\''s'\n\
+            status=$?\n\
+            echo \"spawner-exit-code: $status\"\n\
+            exit $status\n",
+            init_script(&process)
+        );
+    }
+
+    #[test]
+    fn test_parse_exit_code() {
+        assert_eq!(
+            Some(3),
+            parse_exit_code("booting\r\nspawner-exit-code: 3\r\nKernel panic\r\n")
+        );
+        assert_eq!(None, parse_exit_code("booting\n"));
+    }
+}
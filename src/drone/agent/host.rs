@@ -0,0 +1,164 @@
+//! Load of the drone's host, read from `/proc`, for reporting headroom in status messages
+//! and detecting memory or disk pressure.
+
+use anyhow::{anyhow, Context, Result};
+use std::{path::Path, process::Stdio};
+use tokio::{fs, process::Command};
+
+/// Cumulative CPU time of all of the host's CPUs, in clock ticks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuTimes {
+    /// Time spent idle or waiting for I/O.
+    pub idle: u64,
+    pub total: u64,
+}
+
+/// Memory of the host, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryInfo {
+    pub total_bytes: u64,
+
+    /// Memory which could be given to new processes without swapping.
+    pub available_bytes: u64,
+}
+
+/// Parse the aggregate `cpu` line of `/proc/stat`.
+fn parse_cpu_times(proc_stat: &str) -> Option<CpuTimes> {
+    let line = proc_stat.lines().find(|line| line.starts_with("cpu "))?;
+    let fields: Vec<u64> = line
+        .split_whitespace()
+        .skip(1)
+        .filter_map(|field| field.parse().ok())
+        .collect();
+
+    // Fields are user, nice, system, idle, iowait, irq, softirq, steal, and then guest
+    // time, which is already counted in user time.
+    Some(CpuTimes {
+        idle: fields.get(3)? + fields.get(4).unwrap_or(&0),
+        total: fields.iter().take(8).sum(),
+    })
+}
+
+/// The value of a field of `/proc/meminfo`, converted from kilobytes to bytes.
+fn parse_meminfo_field(meminfo: &str, key: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name != key {
+            return None;
+        }
+
+        let kilobytes: u64 = value.trim().trim_end_matches("kB").trim().parse().ok()?;
+        Some(kilobytes * 1024)
+    })
+}
+
+/// The available space of the single filesystem listed in POSIX `df -P -k` output,
+/// converted from kilobytes to bytes.
+fn parse_df_available(df: &str) -> Option<u64> {
+    // Filesystem, 1024-blocks, Used, Available, Capacity, Mounted on. The filesystem name
+    // may contain spaces, so fields are counted from the end.
+    let line = df.lines().nth(1)?;
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let kilobytes: u64 = fields.get(fields.len().checked_sub(3)?)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+pub async fn cpu_times() -> Result<CpuTimes> {
+    parse_cpu_times(&fs::read_to_string("/proc/stat").await?)
+        .ok_or_else(|| anyhow!("Couldn't parse /proc/stat."))
+}
+
+pub async fn memory_info() -> Result<MemoryInfo> {
+    let meminfo = fs::read_to_string("/proc/meminfo").await?;
+
+    Ok(MemoryInfo {
+        total_bytes: parse_meminfo_field(&meminfo, "MemTotal")
+            .ok_or_else(|| anyhow!("Couldn't read MemTotal from /proc/meminfo."))?,
+        available_bytes: parse_meminfo_field(&meminfo, "MemAvailable")
+            .ok_or_else(|| anyhow!("Couldn't read MemAvailable from /proc/meminfo."))?,
+    })
+}
+
+/// Space available to unprivileged users on the filesystem containing the path, in bytes.
+pub async fn disk_available_bytes(path: &Path) -> Result<u64> {
+    let output = Command::new("df")
+        .arg("-P")
+        .arg("-k")
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .context("Error running df.")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "df failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    parse_df_available(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| anyhow!("Couldn't parse output of df for {:?}.", path))
+}
+
+/// The percentage of CPU time that was idle between two samples, or None if no time
+/// passed between them.
+pub fn idle_percent(previous: &CpuTimes, current: &CpuTimes) -> Option<f64> {
+    let total = current.total.checked_sub(previous.total)?;
+    let idle = current.idle.checked_sub(previous.idle)?;
+    if total == 0 {
+        return None;
+    }
+
+    Some(100. * idle as f64 / total as f64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_host_load() {
+        let proc_stat = "cpu  100 10 50 800 40 0 0 0 0 0
+cpu0 50 5 25 400 20 0 0 0 0 0
+intr 12345
+";
+        let previous = parse_cpu_times(proc_stat).unwrap();
+        assert_eq!(
+            CpuTimes {
+                idle: 840,
+                total: 1000
+            },
+            previous
+        );
+
+        let current = CpuTimes {
+            idle: 1140,
+            total: 1400,
+        };
+        assert_eq!(Some(75.), idle_percent(&previous, &current));
+        assert_eq!(None, idle_percent(&previous, &previous));
+
+        let meminfo = "MemTotal:        8048152 kB
+MemFree:          512000 kB
+MemAvailable:    4024076 kB
+";
+        assert_eq!(
+            Some(8048152 * 1024),
+            parse_meminfo_field(meminfo, "MemTotal")
+        );
+        assert_eq!(
+            Some(4024076 * 1024),
+            parse_meminfo_field(meminfo, "MemAvailable")
+        );
+        assert_eq!(None, parse_meminfo_field(meminfo, "SwapTotal"));
+
+        let df = "Filesystem     1024-blocks     Used Available Capacity Mounted on
+/dev/sda1         41152736 30505872  8533712      79% /
+";
+        assert_eq!(Some(8533712 * 1024), parse_df_available(df));
+        assert_eq!(
+            None,
+            parse_df_available("Filesystem 1024-blocks Used Available\n")
+        );
+    }
+}
@@ -0,0 +1,104 @@
+//! Checks that images meet the drone's policy before they are pulled.
+//!
+//! Signatures are verified with `cosign verify`, which fetches them from the image's
+//! registry. Images must be referenced by digest when signatures are verified, so that
+//! the image which is pulled is the one whose signature was checked.
+
+use anyhow::{anyhow, Context, Result};
+use std::path::PathBuf;
+use tokio::process::Command;
+
+/// Requirements that a spawn request's image must meet, configured at the drone level.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct ImagePolicy {
+    /// Refuse images which are not referenced by digest.
+    pub require_digest: bool,
+
+    /// Public key that images must be signed with, passed to `cosign verify --key`. May
+    /// be a path or any key reference that cosign accepts.
+    pub cosign_key: Option<String>,
+
+    /// Path of the `cosign` binary. If not provided, `cosign` is looked up on the `PATH`.
+    pub cosign_path: Option<PathBuf>,
+}
+
+/// The digest of an image reference of the form `<name>@<algorithm>:<hex>`, if it
+/// has one.
+pub fn image_digest(image: &str) -> Option<&str> {
+    let (_, digest) = image.rsplit_once('@')?;
+    let (algorithm, hex) = digest.split_once(':')?;
+    let valid = !algorithm.is_empty()
+        && algorithm
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "+._-".contains(c))
+        && hex.len() >= 32
+        && hex.chars().all(|c| c.is_ascii_hexdigit());
+
+    valid.then_some(digest)
+}
+
+impl ImagePolicy {
+    /// Return an error if the image does not meet the policy.
+    pub async fn check(&self, image: &str) -> Result<()> {
+        if (self.require_digest || self.cosign_key.is_some()) && image_digest(image).is_none() {
+            return Err(anyhow!(
+                "Image {} must be referenced by digest (<name>@sha256:<digest>).",
+                image
+            ));
+        }
+
+        if let Some(key) = &self.cosign_key {
+            self.verify_signature(image, key).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn verify_signature(&self, image: &str, key: &str) -> Result<()> {
+        let cosign = self
+            .cosign_path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("cosign"));
+        let output = Command::new(&cosign)
+            // `--` keeps an image name beginning with `-` from being read as a flag.
+            .args(["verify", "--key", key, "--", image])
+            .output()
+            .await
+            .with_context(|| format!("Error running {:?}.", cosign))?;
+
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Signature verification of image {} failed: {}",
+                image,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        tracing::info!(%image, "Verified image signature.");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_image_digest() {
+        let digest = "sha256:b5b2b2c507a0944348e0303114d8d93aaaa081732b86451d9bce1f432a537bc7";
+        assert_eq!(
+            Some(digest),
+            image_digest(&format!("ghcr.io/drifting-in-space/test-image@{}", digest))
+        );
+        assert_eq!(
+            Some(digest),
+            image_digest(&format!("localhost:5000/test-image:latest@{}", digest))
+        );
+        assert_eq!(
+            None,
+            image_digest("ghcr.io/drifting-in-space/test-image:latest")
+        );
+        assert_eq!(None, image_digest("localhost:5000/test-image"));
+        assert_eq!(None, image_digest("test-image@sha256:latest"));
+    }
+}
@@ -0,0 +1,116 @@
+//! Destinations that the agent forwards backend container logs to.
+
+use crate::{messages::agent::DroneLogMessage, nats::TypedNats, types::BackendId};
+use anyhow::{anyhow, Result};
+use reqwest::{Client, Url};
+use serde_json::json;
+use std::{path::PathBuf, str::FromStr};
+use tokio::{
+    fs::{create_dir_all, File, OpenOptions},
+    io::AsyncWriteExt,
+};
+
+/// A destination for backend logs, configured at the drone level.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum LogSink {
+    /// Publish each line to the backend's log subject on NATS.
+    Nats,
+
+    /// Print each line to the agent's stdout, prefixed by the backend ID.
+    Stdout,
+
+    /// Append lines to a file named `<backend_id>.log` in the given directory.
+    File(PathBuf),
+
+    /// POST each line as a JSON object to the given URL, e.g. a Vector or
+    /// Fluent Bit HTTP source.
+    Http(Url),
+}
+
+impl FromStr for LogSink {
+    type Err = anyhow::Error;
+
+    /// Parses `nats`, `stdout`, `file:<directory>`, or an `http://` or `https://` URL.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "nats" {
+            Ok(LogSink::Nats)
+        } else if s == "stdout" {
+            Ok(LogSink::Stdout)
+        } else if let Some(directory) = s.strip_prefix("file:") {
+            Ok(LogSink::File(PathBuf::from(directory)))
+        } else if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(LogSink::Http(Url::parse(s)?))
+        } else {
+            Err(anyhow!(
+                "Expected log sink to be nats, stdout, file:<directory>, or an HTTP(S) URL, got {:?}.",
+                s
+            ))
+        }
+    }
+}
+
+impl LogSink {
+    /// Open a writer which sends one backend's logs to this sink.
+    pub async fn open(&self, backend_id: &BackendId, nc: &TypedNats) -> Result<LogWriter> {
+        Ok(match self {
+            LogSink::Nats => LogWriter::Nats(nc.clone()),
+            LogSink::Stdout => LogWriter::Stdout,
+            LogSink::File(directory) => {
+                create_dir_all(directory).await?;
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(directory.join(format!("{}.log", backend_id)))
+                    .await?;
+
+                LogWriter::File(file)
+            }
+            LogSink::Http(url) => LogWriter::Http(Client::new(), url.clone()),
+        })
+    }
+}
+
+/// Sends the logs of a single backend to a `LogSink`.
+pub enum LogWriter {
+    Nats(TypedNats),
+    Stdout,
+    File(File),
+    Http(Client, Url),
+}
+
+impl LogWriter {
+    pub async fn write(&mut self, backend_id: &BackendId, message: &DroneLogMessage) -> Result<()> {
+        match self {
+            LogWriter::Nats(nc) => {
+                nc.publish(&DroneLogMessage::subject(backend_id), message)
+                    .await
+            }
+            LogWriter::Stdout => {
+                println!("[{}] {}", backend_id, message.text.trim_end());
+                Ok(())
+            }
+            LogWriter::File(file) => {
+                file.write_all(message.text.as_bytes()).await?;
+                if !message.text.ends_with('\n') {
+                    file.write_all(b"\n").await?;
+                }
+
+                Ok(())
+            }
+            LogWriter::Http(client, url) => {
+                client
+                    .post(url.clone())
+                    .json(&json!({
+                        "backend_id": backend_id,
+                        "kind": message.kind,
+                        "text": message.text,
+                    }))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+
+                Ok(())
+            }
+        }
+    }
+}
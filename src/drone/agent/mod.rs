@@ -1,25 +1,82 @@
-use self::{docker::DockerInterface, executor::Executor};
+use self::{
+    containerd::ContainerdInterface,
+    docker::DockerInterface,
+    engine::Engine,
+    eviction::EvictionOptions,
+    executor::{Admission, Executor},
+    firecracker::FirecrackerInterface,
+    image_policy::ImagePolicy,
+    logs::LogSink,
+    podman::PodmanInterface,
+    routes::{route_sync_loop, RoutePublisher},
+    secrets::SecretSources,
+    warm_pool::{WarmPool, WarmPoolConfig},
+    webhooks::Webhooks,
+};
 use crate::{
-    database::DroneDatabase,
     database_connection::DatabaseConnection,
-    drone::cli::IpProvider,
+    drone::{
+        backend_tls::BackendCa, cert::custom_domain::CnameVerifier, cli::IpProvider,
+        proxy::tcp::PortRange,
+    },
+    keys::KeyCertPathPair,
     logging::LogError,
-    messages::agent::{
-        BackendStateMessage, DroneConnectRequest, DroneConnectResponse, DroneStatusMessage,
-        SpawnRequest,
+    messages::{
+        agent::{
+            BackendInfoMessage, BackendNetwork, BackendStateMessage, DrainRequest,
+            DroneConnectRequest, DroneConnectResponse, DroneLogMessage, DroneState,
+            DroneStatusMessage, ExecOutputMessage, ExecRequest, ExecResponse, PullPolicy,
+            RenewLeaseRequest, SecurityOptions, SpawnRequest, SpawnResponse, Ulimits,
+        },
+        proxy::BackendActivityMessage,
     },
     nats::TypedNats,
     nats_connection::NatsConnection,
-    retry::do_with_retry,
+    retry::{do_with_retry, RetryPolicy},
     types::DroneId,
 };
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use bollard::auth::DockerCredentials;
 use http::Uri;
 use hyper::Client;
-use std::{net::IpAddr, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    fs::File,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::signal::unix::{signal, SignalKind};
 
+mod cgroup;
+mod containerd;
 mod docker;
+mod engine;
+pub mod eviction;
 mod executor;
+mod firecracker;
+mod host;
+pub mod image_policy;
+pub mod logs;
+mod podman;
+mod readiness;
+mod routes;
+pub mod secrets;
+mod stats;
+mod status;
+pub mod warm_pool;
+pub mod webhooks;
+
+/// How often the agent publishes a status message.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(4);
+
+/// How often images in the pre-pull list are pulled again to pick up new versions.
+const PREPULL_INTERVAL: Duration = Duration::from_secs(15 * 60);
 
 #[derive(PartialEq, Eq, Debug)]
 pub enum DockerApiTransport {
@@ -33,10 +90,137 @@ impl Default for DockerApiTransport {
     }
 }
 
+/// The container engine that backends are run with.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum EngineKind {
+    #[default]
+    Docker,
+
+    /// Podman, through its Docker-compatible API.
+    Podman,
+
+    /// containerd, without Docker Engine. Requires `nerdctl`.
+    Containerd,
+
+    /// A Firecracker microVM for each backend. Requires `skopeo`, `umoci`, and
+    /// `mkfs.ext4` to convert images, and `ip` to create network devices.
+    Firecracker,
+}
+
+impl EngineKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EngineKind::Docker => "docker",
+            EngineKind::Podman => "podman",
+            EngineKind::Containerd => "containerd",
+            EngineKind::Firecracker => "firecracker",
+        }
+    }
+}
+
+impl FromStr for EngineKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "docker" => Ok(EngineKind::Docker),
+            "podman" => Ok(EngineKind::Podman),
+            "containerd" => Ok(EngineKind::Containerd),
+            "firecracker" => Ok(EngineKind::Firecracker),
+            _ => Err(anyhow!(
+                "Expected engine to be docker, podman, containerd, or firecracker, got {:?}.",
+                s
+            )),
+        }
+    }
+}
+
+impl EngineKind {
+    /// The API socket of the engine when running as root. Firecracker is run directly
+    /// rather than through an API, so its transport is unused.
+    pub fn default_transport(&self) -> DockerApiTransport {
+        match self {
+            EngineKind::Docker | EngineKind::Firecracker => DockerApiTransport::default(),
+            EngineKind::Podman => DockerApiTransport::Socket("/run/podman/podman.sock".to_string()),
+            EngineKind::Containerd => {
+                DockerApiTransport::Socket("/run/containerd/containerd.sock".to_string())
+            }
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Debug)]
 pub struct DockerOptions {
+    pub engine: EngineKind,
     pub transport: DockerApiTransport,
     pub runtime: Option<String>,
+
+    /// Host directories under which spawn requests may bind-mount paths. Bind mounts are
+    /// refused if there are none.
+    pub bind_mount_sources: Vec<PathBuf>,
+
+    /// Port within backend containers to proxy to, for spawn requests which do not specify one.
+    pub default_container_port: u16,
+
+    /// Network to attach backend containers to, for spawn requests which do not specify one.
+    pub default_network: BackendNetwork,
+
+    /// Number of GPUs on the host which may be assigned to backends.
+    pub gpu_count: u32,
+
+    /// JSON file mapping registry hosts to the credentials used to pull images from them.
+    pub registry_credentials_path: Option<PathBuf>,
+
+    /// Seccomp profile (JSON) applied to backend containers.
+    pub seccomp_profile_path: Option<PathBuf>,
+
+    /// AppArmor profile applied to backend containers.
+    pub apparmor_profile: Option<String>,
+
+    /// Let spawn requests replace the seccomp and AppArmor profiles above, including
+    /// with `unconfined`.
+    pub allow_profile_overrides: bool,
+
+    /// Prevent processes in backend containers from gaining privileges.
+    pub no_new_privileges: bool,
+
+    /// Linux capabilities dropped from every backend container.
+    pub cap_drop: Vec<String>,
+
+    /// Refuse to run backend containers whose processes would run as root.
+    pub forbid_root: bool,
+
+    /// `ulimit`s of backend containers, unless their spawn request overrides them.
+    pub ulimits: Ulimits,
+
+    /// Largest `/dev/shm` that a spawn request may ask for, in bytes.
+    pub max_shm_size_bytes: Option<i64>,
+
+    /// Run an init process as PID 1 of backend containers, unless their spawn request
+    /// says otherwise.
+    pub init: bool,
+
+    /// Maximum number of processes in backend containers, unless their spawn request
+    /// overrides it. None means unlimited.
+    pub pids_limit: Option<i64>,
+
+    /// Largest PID limit that a spawn request may ask for.
+    pub max_pids_limit: Option<i64>,
+
+    /// Required when `engine` is `EngineKind::Firecracker`.
+    pub firecracker_options: Option<FirecrackerOptions>,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub struct FirecrackerOptions {
+    /// Path of the `firecracker` binary.
+    pub firecracker_path: PathBuf,
+
+    /// Uncompressed Linux kernel that microVMs boot.
+    pub kernel_image_path: PathBuf,
+
+    /// Directory in which converted images and the files of each microVM are stored.
+    pub work_directory: PathBuf,
 }
 
 #[derive(PartialEq, Debug)]
@@ -52,6 +236,109 @@ pub struct AgentOptions {
     pub host_ip: IpAddr,
 
     pub docker_options: DockerOptions,
+
+    /// Images to pull when the agent starts and periodically afterwards, so that
+    /// backends using them do not wait on a pull.
+    pub prepull_images: Vec<String>,
+
+    /// Destinations to forward backend logs to.
+    pub log_sinks: Vec<LogSink>,
+
+    /// Images to keep warm containers of, which spawn requests can claim.
+    pub warm_pools: Vec<WarmPoolConfig>,
+
+    /// Whether to run commands in backend containers in response to `ExecRequest`s.
+    pub allow_exec: bool,
+
+    /// How often to publish the resource usage of each running backend, if at all.
+    pub stats_interval: Option<Duration>,
+
+    /// Requirements that images must meet before backends are started from them.
+    pub image_policy: ImagePolicy,
+
+    /// The most backends to run at once. Spawn requests beyond it are refused.
+    pub max_backends: Option<u32>,
+
+    /// How pulling a backend's image and creating its container are retried.
+    pub spawn_retry_policy: RetryPolicy,
+
+    /// How long a stopping backend's open proxy connections have to finish before its
+    /// container is stopped. The proxy sends it no new requests in the meantime.
+    pub drain_timeout: Duration,
+
+    /// How long backends may go without proxy activity before they are terminated,
+    /// unless their spawn request sets `max_idle_secs`.
+    pub idle_timeout: Duration,
+
+    /// URLs to call when backends become ready, terminate, or fail.
+    pub webhooks: Webhooks,
+
+    /// Address to serve the agent's status on over HTTP, if any.
+    pub status_address: Option<SocketAddr>,
+
+    /// Where to read the secrets referenced by spawn requests' environment variables.
+    pub secrets: SecretSources,
+
+    /// Prefix for the names of the agent's containers, so that agents of several
+    /// clusters can share a container engine without managing each other's containers.
+    pub namespace: Option<String>,
+
+    /// When to evict backends because the host is low on memory or disk, if at all.
+    pub eviction: Option<EvictionOptions>,
+
+    /// Ports on the drone which backends' TCP ports are assigned from. The proxy passes
+    /// raw TCP connections to them through to the backends.
+    pub tcp_port_range: Option<PortRange>,
+
+    /// Ports on the drone which backends' UDP ports are assigned from, if the proxy relays
+    /// UDP datagrams to them rather than clients sending to their published ports.
+    pub udp_port_range: Option<PortRange>,
+
+    /// CA to issue backends certificates from, if the proxy connects to them over mutual
+    /// TLS.
+    pub backend_ca: Option<KeyCertPathPair>,
+
+    /// DNS over HTTPS resolver which custom domains' CNAMEs are checked with, if spawn
+    /// requests' custom domains are routed.
+    pub custom_domain_resolver: Option<String>,
+
+    /// Labels the drone registers and heartbeats with, which the scheduler matches
+    /// spawn requests' drone selectors against.
+    pub labels: HashMap<String, String>,
+}
+
+impl DockerOptions {
+    /// Read the registry credentials file, if one was given.
+    pub fn load_registry_credentials(&self) -> Result<HashMap<String, DockerCredentials>> {
+        if let Some(path) = &self.registry_credentials_path {
+            let file = File::open(path)
+                .with_context(|| format!("Error opening registry credentials {:?}.", path))?;
+            serde_json::from_reader(file)
+                .with_context(|| format!("Error parsing registry credentials {:?}.", path))
+        } else {
+            Ok(HashMap::new())
+        }
+    }
+
+    /// The security options applied to every backend container, with the seccomp
+    /// profile read from its file.
+    pub fn load_security_options(&self) -> Result<SecurityOptions> {
+        let seccomp_profile = if let Some(path) = &self.seccomp_profile_path {
+            Some(
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("Error reading seccomp profile {:?}.", path))?,
+            )
+        } else {
+            None
+        };
+
+        Ok(SecurityOptions {
+            seccomp_profile,
+            apparmor_profile: self.apparmor_profile.clone(),
+            no_new_privileges: self.no_new_privileges,
+            cap_drop: self.cap_drop.clone(),
+        })
+    }
 }
 
 pub async fn wait_port_ready(port: u16, host_ip: IpAddr) -> Result<()> {
@@ -65,25 +352,46 @@ pub async fn wait_port_ready(port: u16, host_ip: IpAddr) -> Result<()> {
     Ok(())
 }
 
+/// Start a backend for each spawn request, unless the drone is draining, already
+/// running `max_backends` backends, or already running a backend with the request's key.
 pub async fn listen_for_spawn_requests(
     drone_id: DroneId,
-    docker: DockerInterface,
     nats: TypedNats,
-    host_ip: IpAddr,
-    db: DroneDatabase,
+    executor: Arc<Executor>,
+    draining: Arc<AtomicBool>,
+    max_backends: Option<u32>,
 ) -> Result<()> {
     let mut sub = nats.subscribe(&SpawnRequest::subject(drone_id)).await?;
-    let executor = Arc::new(Executor::new(docker, db, nats, host_ip));
-    executor.resume_backends().await?;
 
     loop {
         let req = sub.next().await;
 
         match req {
             Ok(Some(req)) => {
+                if draining.load(Ordering::SeqCst) {
+                    tracing::info!(backend_id = %req.value.backend_id, "Refused spawn request while draining.");
+                    req.respond(&SpawnResponse::Draining).await?;
+                    continue;
+                }
+                match executor.admit(&req.value, max_backends) {
+                    Admission::Admitted => (),
+                    Admission::AtCapacity => {
+                        let max_backends = max_backends.unwrap_or_default();
+                        tracing::warn!(backend_id = %req.value.backend_id, max_backends, "Refused spawn request at capacity.");
+                        req.respond(&SpawnResponse::AtCapacity { max_backends })
+                            .await?;
+                        continue;
+                    }
+                    Admission::Existing(backend_id) => {
+                        tracing::info!(requested_backend_id = %req.value.backend_id, %backend_id, "Found existing backend.");
+                        req.respond(&SpawnResponse::Existing { backend_id }).await?;
+                        continue;
+                    }
+                }
+
                 let executor = executor.clone();
 
-                req.respond(&true).await?;
+                req.respond(&SpawnResponse::Accepted).await?;
                 tokio::spawn(async move {
                     executor.start_backend(&req.value).await;
                 });
@@ -96,17 +404,234 @@ pub async fn listen_for_spawn_requests(
     }
 }
 
-/// Repeatedly publish a status message advertising this drone as available.
-async fn ready_loop(nc: TypedNats, drone_id: DroneId, cluster: String) {
-    let mut interval = tokio::time::interval(Duration::from_secs(4));
+/// Start or stop draining the drone in response to `DrainRequest`s.
+async fn listen_for_drain_requests(
+    drone_id: DroneId,
+    nats: TypedNats,
+    draining: Arc<AtomicBool>,
+) -> Result<()> {
+    let mut sub = nats.subscribe(&DrainRequest::subject(drone_id)).await?;
+
+    loop {
+        match sub.next().await {
+            Ok(Some(req)) => {
+                tracing::info!(drain = req.value.drain, "Received drain request.");
+                draining.store(req.value.drain, Ordering::SeqCst);
+                req.respond(&true).await?;
+            }
+            Ok(None) => return Err(anyhow!("Drain request subscription closed.")),
+            Err(error) => {
+                tracing::error!(?error, "Non-fatal error when listening for drain requests.")
+            }
+        }
+    }
+}
+
+async fn listen_for_lease_renewals(nats: TypedNats, executor: Arc<Executor>) -> Result<()> {
+    let mut sub = nats
+        .subscribe(RenewLeaseRequest::subscribe_subject())
+        .await?;
+
+    loop {
+        match sub.next().await {
+            Ok(Some(req)) => {
+                // Backends on other drones are left for those drones to reply to.
+                if let Some(response) = executor.renew_lease(&req.value).await {
+                    req.respond(&response).await?;
+                }
+            }
+            Ok(None) => return Err(anyhow!("Lease renewal subscription closed.")),
+            Err(error) => {
+                tracing::error!(?error, "Non-fatal error when listening for lease renewals.")
+            }
+        }
+    }
+}
+
+/// Keep track of the activity of the drone's backends reported by proxies, which may not
+/// share the drone's database.
+async fn listen_for_activity(
+    nats: TypedNats,
+    cluster: String,
+    executor: Arc<Executor>,
+) -> Result<()> {
+    let mut sub = nats
+        .subscribe(&BackendActivityMessage::subject(&cluster))
+        .await?;
+
+    loop {
+        match sub.next().await {
+            Ok(Some(message)) => executor.record_activity(&message.value),
+            Ok(None) => return Err(anyhow!("Backend activity subscription closed.")),
+            Err(error) => {
+                tracing::error!(
+                    ?error,
+                    "Non-fatal error when listening for backend activity."
+                )
+            }
+        }
+    }
+}
+
+/// Start draining the drone when the agent receives `SIGUSR1`.
+async fn drain_on_signal(draining: Arc<AtomicBool>) -> Result<()> {
+    let mut signals = signal(SignalKind::user_defined1())?;
+
+    while signals.recv().await.is_some() {
+        tracing::info!("Received SIGUSR1, draining.");
+        draining.store(true, Ordering::SeqCst);
+    }
+
+    Ok(())
+}
+
+/// Respond to requests to run commands in backend containers. Requests are refused
+/// unless `allow_exec` is set.
+async fn listen_for_exec_requests(
+    drone_id: DroneId,
+    executor: Arc<Executor>,
+    engine: Arc<dyn Engine>,
+    nats: TypedNats,
+    allow_exec: bool,
+) -> Result<()> {
+    let mut sub = nats.subscribe(&ExecRequest::subject(drone_id)).await?;
 
     loop {
+        let req = match sub.next().await {
+            Ok(Some(req)) => req,
+            Ok(None) => return Err(anyhow!("Exec request subscription closed.")),
+            Err(error) => {
+                tracing::error!(?error, "Non-fatal error when listening for exec requests.");
+                continue;
+            }
+        };
+        let request = req.value.clone();
+        let container_name = executor.container_name(&request.backend_id);
+
+        if !allow_exec {
+            tracing::warn!(backend_id = %request.backend_id, "Refused exec request.");
+            req.respond(&ExecResponse::Disabled).await?;
+            continue;
+        }
+        if !request.has_valid_exec_id() {
+            tracing::warn!(backend_id = %request.backend_id, exec_id = %request.exec_id, "Refused exec request with invalid exec ID.");
+            req.respond(&ExecResponse::InvalidExecId).await?;
+            continue;
+        }
+        if !matches!(engine.is_running(&container_name).await, Ok((true, _))) {
+            req.respond(&ExecResponse::NotRunning).await?;
+            continue;
+        }
+
+        tracing::info!(
+            backend_id = %request.backend_id,
+            command = ?request.command,
+            "Running command in backend."
+        );
+        req.respond(&ExecResponse::Started).await?;
+
+        let engine = engine.clone();
+        let nats = nats.clone();
+        tokio::spawn(async move {
+            let subject = ExecOutputMessage::subject(&request.backend_id, &request.exec_id);
+            let (send, mut recv) = tokio::sync::mpsc::channel(64);
+
+            let forward = async {
+                while let Some(output) = recv.recv().await {
+                    if let Some(message) = DroneLogMessage::from_log_message(&output) {
+                        nats.publish(&subject, &ExecOutputMessage::Output(message))
+                            .await
+                            .log_error("Error publishing exec output.");
+                    }
+                }
+            };
+            let (exit_code, ()) = tokio::join!(
+                engine.exec(&container_name, &request.command, send),
+                forward
+            );
+
+            let exit_code = match exit_code {
+                Ok(exit_code) => exit_code,
+                Err(error) => {
+                    tracing::warn!(?error, "Error running command in backend.");
+                    None
+                }
+            };
+            nats.publish(&subject, &ExecOutputMessage::Exited { exit_code })
+                .await
+                .log_error("Error publishing exec exit code.");
+        });
+    }
+}
+
+/// Whether the drone accepts spawn requests and, if not, whether backends remain.
+fn drone_state(draining: &AtomicBool, running_backends: u32) -> DroneState {
+    if !draining.load(Ordering::SeqCst) {
+        DroneState::Ready
+    } else if running_backends > 0 {
+        DroneState::Draining
+    } else {
+        DroneState::Drained
+    }
+}
+
+/// Repeatedly publish a status message advertising whether this drone is available,
+/// and how loaded it is.
+async fn ready_loop(
+    nc: TypedNats,
+    drone_id: DroneId,
+    cluster: String,
+    executor: Arc<Executor>,
+    draining: Arc<AtomicBool>,
+    max_backends: Option<u32>,
+    labels: HashMap<String, String>,
+) {
+    let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let mut last_state = DroneState::Ready;
+    let mut last_cpu_times = None;
+
+    loop {
+        let running_backends = executor.backend_count() as u32;
+        let state = drone_state(&draining, running_backends);
+        if state != last_state {
+            tracing::info!(?state, "Drone state changed.");
+            last_state = state;
+        }
+
+        let cpu_times = host::cpu_times()
+            .await
+            .map_err(|error| tracing::warn!(?error, "Error reading host CPU time."))
+            .ok();
+        let cpu_idle_percent = match (&last_cpu_times, &cpu_times) {
+            (Some(previous), Some(current)) => host::idle_percent(previous, current),
+            _ => None,
+        };
+        last_cpu_times = cpu_times;
+        let memory = host::memory_info()
+            .await
+            .map_err(|error| tracing::warn!(?error, "Error reading host memory."))
+            .ok();
+
         nc.publish(
             &DroneStatusMessage::subject(&drone_id),
             &DroneStatusMessage {
                 drone_id,
-                capacity: 100,
+                capacity: match (state, max_backends) {
+                    (DroneState::Ready, Some(max_backends)) => {
+                        max_backends.saturating_sub(running_backends)
+                    }
+                    (DroneState::Ready, None) => 100,
+                    _ => 0,
+                },
                 cluster: cluster.to_string(),
+                state,
+                running_backends,
+                max_backends,
+                cpu_idle_percent,
+                memory_available_bytes: memory.map(|memory| memory.available_bytes),
+                memory_total_bytes: memory.map(|memory| memory.total_bytes),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                labels: labels.clone(),
             },
         )
         .await
@@ -116,15 +641,73 @@ async fn ready_loop(nc: TypedNats, drone_id: DroneId, cluster: String) {
     }
 }
 
+/// Repeatedly pull each of the given images, so that they are present and up-to-date
+/// when a backend needs them.
+async fn prepull_loop(engine: Arc<dyn Engine>, images: Vec<String>) {
+    let mut interval = tokio::time::interval(PREPULL_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        for image in &images {
+            tracing::info!(%image, "Pre-pulling image.");
+            engine
+                .pull_image(image, &None, PullPolicy::Always)
+                .await
+                .log_error("Error pre-pulling image.");
+        }
+    }
+}
+
 pub async fn run_agent(agent_opts: AgentOptions) -> Result<()> {
     let nats = agent_opts.nats.connection().await?;
 
     // Ensure that status stream exists.
     nats.add_jetstream_stream("backend_status", BackendStateMessage::subscribe_subject())
         .await?;
+    nats.add_jetstream_stream("backend_info", BackendInfoMessage::subscribe_subject())
+        .await?;
+
+    tracing::info!(engine = ?agent_opts.docker_options.engine, "Connecting to container engine.");
+    let engine: Arc<dyn Engine> = match agent_opts.docker_options.engine {
+        EngineKind::Docker => Arc::new(DockerInterface::try_new(&agent_opts.docker_options).await?),
+        EngineKind::Podman => Arc::new(PodmanInterface::try_new(&agent_opts.docker_options).await?),
+        EngineKind::Containerd => {
+            Arc::new(ContainerdInterface::try_new(&agent_opts.docker_options).await?)
+        }
+        EngineKind::Firecracker => {
+            Arc::new(FirecrackerInterface::try_new(&agent_opts.docker_options).await?)
+        }
+    };
+    if agent_opts.docker_options.engine == EngineKind::Firecracker
+        && !agent_opts.warm_pools.is_empty()
+    {
+        return Err(anyhow!(
+            "Warm pools are not supported by the Firecracker engine."
+        ));
+    }
+    if !agent_opts.prepull_images.is_empty() {
+        tokio::spawn(prepull_loop(
+            engine.clone(),
+            agent_opts.prepull_images.clone(),
+        ));
+    }
+
+    let warm_pool = Arc::new(WarmPool::new(
+        engine.clone(),
+        agent_opts.host_ip,
+        agent_opts.warm_pools.clone(),
+        agent_opts.namespace.clone(),
+    ));
+    if !agent_opts.warm_pools.is_empty() {
+        tokio::spawn(warm_pool.clone().fill_loop());
+    }
+
+    let backend_ca = match &agent_opts.backend_ca {
+        Some(paths) => Some(Arc::new(BackendCa::load(paths)?)),
+        None => None,
+    };
 
-    tracing::info!("Connecting to Docker.");
-    let docker = DockerInterface::try_new(&agent_opts.docker_options).await?;
     tracing::info!("Connecting to sqlite.");
     let db = agent_opts.db.connection().await?;
     let cluster = agent_opts.cluster_domain.to_string();
@@ -136,6 +719,9 @@ pub async fn run_agent(agent_opts: AgentOptions) -> Result<()> {
         let request = DroneConnectRequest {
             cluster: cluster.clone(),
             ip,
+            labels: agent_opts.labels.clone(),
+            architecture: std::env::consts::ARCH.to_string(),
+            engine: agent_opts.docker_options.engine.as_str().to_string(),
         };
         do_with_retry(
             || nats.request(&subject, &request),
@@ -147,14 +733,119 @@ pub async fn run_agent(agent_opts: AgentOptions) -> Result<()> {
 
     match result {
         DroneConnectResponse::Success { drone_id } => {
+            let route_publisher = RoutePublisher::new(nats.clone(), drone_id, cluster.clone());
+            let executor = Arc::new(Executor::new(
+                engine.clone(),
+                db,
+                nats.clone(),
+                agent_opts.host_ip,
+                agent_opts.log_sinks,
+                warm_pool,
+                agent_opts.stats_interval,
+                agent_opts.image_policy.clone(),
+                agent_opts.idle_timeout,
+                agent_opts.spawn_retry_policy,
+                agent_opts.drain_timeout,
+                agent_opts.webhooks,
+                agent_opts.secrets,
+                agent_opts.namespace.clone(),
+                route_publisher.clone(),
+                agent_opts.tcp_port_range,
+                agent_opts.udp_port_range,
+                backend_ca,
+                agent_opts
+                    .custom_domain_resolver
+                    .as_deref()
+                    .map(|resolver_url| CnameVerifier::new(resolver_url, &cluster)),
+            ));
+            executor.resume_backends().await?;
+            {
+                let executor = executor.clone();
+                tokio::spawn(async move {
+                    route_sync_loop(route_publisher, executor)
+                        .await
+                        .log_error("Error syncing routes.");
+                });
+            }
+
+            let draining = Arc::new(AtomicBool::new(false));
             {
                 let nats = nats.clone();
                 let cluster = cluster.clone();
-                tokio::spawn(ready_loop(nats, drone_id, cluster));
+                tokio::spawn(ready_loop(
+                    nats,
+                    drone_id,
+                    cluster,
+                    executor.clone(),
+                    draining.clone(),
+                    agent_opts.max_backends,
+                    agent_opts.labels.clone(),
+                ));
+            }
+            {
+                let nats = nats.clone();
+                let draining = draining.clone();
+                tokio::spawn(async move {
+                    listen_for_drain_requests(drone_id, nats, draining)
+                        .await
+                        .log_error("Error listening for drain requests.");
+                });
+            }
+            {
+                let nats = nats.clone();
+                let executor = executor.clone();
+                tokio::spawn(async move {
+                    listen_for_lease_renewals(nats, executor)
+                        .await
+                        .log_error("Error listening for lease renewals.");
+                });
+            }
+            {
+                let nats = nats.clone();
+                let cluster = cluster.clone();
+                let executor = executor.clone();
+                tokio::spawn(async move {
+                    listen_for_activity(nats, cluster, executor)
+                        .await
+                        .log_error("Error listening for backend activity.");
+                });
+            }
+            {
+                let draining = draining.clone();
+                tokio::spawn(async move {
+                    drain_on_signal(draining)
+                        .await
+                        .log_error("Error listening for drain signal.");
+                });
+            }
+            if let Some(eviction) = agent_opts.eviction {
+                tokio::spawn(eviction::eviction_loop(executor.clone(), eviction));
+            }
+            if let Some(address) = agent_opts.status_address {
+                let executor = executor.clone();
+                let draining = draining.clone();
+                let max_backends = agent_opts.max_backends;
+                tokio::spawn(async move {
+                    status::serve_status(address, drone_id, executor, draining, max_backends)
+                        .await
+                        .log_error("Error serving agent status.");
+                });
+            }
+            {
+                let executor = executor.clone();
+                let engine = engine.clone();
+                let nats = nats.clone();
+                let allow_exec = agent_opts.allow_exec;
+                tokio::spawn(async move {
+                    listen_for_exec_requests(drone_id, executor, engine, nats, allow_exec)
+                        .await
+                        .log_error("Error listening for exec requests.");
+                });
             }
 
             tracing::info!("Listening for spawn requests.");
-            listen_for_spawn_requests(drone_id, docker, nats, agent_opts.host_ip, db).await
+            listen_for_spawn_requests(drone_id, nats, executor, draining, agent_opts.max_backends)
+                .await
         }
         DroneConnectResponse::NoSuchCluster => Err(anyhow!(
             "The platform server did not recognize the cluster {}",
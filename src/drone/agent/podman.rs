@@ -0,0 +1,211 @@
+use super::{
+    docker::DockerInterface,
+    engine::{ContainerEvent, ContainerHealth, ContainerStats, Engine, ManagedContainer},
+    DockerOptions,
+};
+use crate::messages::agent::{BackendNetwork, EffectiveResourceLimits, PullPolicy, SpawnRequest};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bollard::{auth::DockerCredentials, container::LogOutput, models::EventMessage};
+use futures::stream::BoxStream;
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+use tokio_stream::StreamExt;
+
+/// Event actions which Podman reports for containers but Docker does not. They carry
+/// nothing the executor acts on.
+const PODMAN_ONLY_ACTIONS: &[&str] = &[
+    "checkpoint",
+    "cleanup",
+    "exited",
+    "init",
+    "mount",
+    "prune",
+    "refresh",
+    "remove",
+    "restore",
+    "sync",
+    "unmount",
+];
+
+/// Runs backends with Podman, through its Docker-compatible API.
+///
+/// Most requests are handled as they are for Docker. The differences are in the
+/// events Podman reports, and in GPUs, which Podman exposes through CDI device names
+/// rather than Docker's device requests.
+#[derive(Clone)]
+pub struct PodmanInterface {
+    docker: DockerInterface,
+}
+
+/// Parse an event reported by Podman's Docker-compatible API.
+///
+/// Podman versions before 4.0 report container exits as `died` rather than `die`.
+fn container_event_from_podman(event: &EventMessage) -> Option<ContainerEvent> {
+    match event.action.as_deref() {
+        Some("died") => ContainerEvent::from_event_message(&EventMessage {
+            action: Some("die".to_string()),
+            ..event.clone()
+        }),
+        Some(action) if PODMAN_ONLY_ACTIONS.contains(&action) => None,
+        _ => ContainerEvent::from_event_message(event),
+    }
+}
+
+impl PodmanInterface {
+    pub async fn try_new(config: &DockerOptions) -> Result<Self> {
+        if config.gpu_count > 0 {
+            return Err(anyhow!(
+                "GPUs are not supported when running backends with Podman."
+            ));
+        }
+
+        Ok(PodmanInterface {
+            docker: DockerInterface::try_new(config).await?,
+        })
+    }
+}
+
+#[async_trait]
+impl Engine for PodmanInterface {
+    fn container_events(&self) -> BoxStream<'_, ContainerEvent> {
+        Box::pin(
+            self.docker
+                .event_messages()
+                .filter_map(|event| container_event_from_podman(&event)),
+        )
+    }
+
+    fn get_logs(&self, container_name: &str, since: i64) -> BoxStream<'_, Result<LogOutput>> {
+        self.docker.get_logs(container_name, since)
+    }
+
+    async fn pull_image(
+        &self,
+        image: &str,
+        credentials: &Option<DockerCredentials>,
+        pull_policy: PullPolicy,
+    ) -> Result<()> {
+        self.docker
+            .pull_image(image, credentials, pull_policy)
+            .await
+    }
+
+    async fn run_container(&self, name: &str, spawn_request: &SpawnRequest) -> Result<()> {
+        if spawn_request.gpus > 0 {
+            return Err(anyhow!(
+                "GPUs are not supported when running backends with Podman."
+            ));
+        }
+
+        self.docker.run_container(name, spawn_request).await
+    }
+
+    async fn stop_container(&self, name: &str, grace_period: Duration) -> Result<()> {
+        self.docker.stop_container(name, grace_period).await
+    }
+
+    async fn remove_container(&self, name: &str) -> Result<()> {
+        self.docker.remove_container(name).await
+    }
+
+    async fn rename_container(&self, name: &str, new_name: &str) -> Result<()> {
+        self.docker.rename_container(name, new_name).await
+    }
+
+    async fn is_restarting(&self, container_name: &str) -> Result<bool> {
+        self.docker.is_restarting(container_name).await
+    }
+
+    async fn is_running(&self, container_name: &str) -> Result<(bool, Option<i64>)> {
+        self.docker.is_running(container_name).await
+    }
+
+    async fn is_oom_killed(&self, container_name: &str) -> Result<bool> {
+        self.docker.is_oom_killed(container_name).await
+    }
+
+    async fn exec(
+        &self,
+        container_name: &str,
+        command: &[String],
+        output: Sender<LogOutput>,
+    ) -> Result<Option<i64>> {
+        self.docker.exec(container_name, command, output).await
+    }
+
+    async fn effective_resource_limits(
+        &self,
+        container_name: &str,
+    ) -> Result<Option<EffectiveResourceLimits>> {
+        self.docker.effective_resource_limits(container_name).await
+    }
+
+    async fn get_stats(&self, container_name: &str) -> Result<ContainerStats> {
+        self.docker.get_stats(container_name).await
+    }
+
+    async fn get_health(&self, container_name: &str) -> Result<Option<ContainerHealth>> {
+        self.docker.get_health(container_name).await
+    }
+
+    async fn get_port(&self, container_name: &str, container_port: u16) -> Option<u16> {
+        self.docker.get_port(container_name, container_port).await
+    }
+
+    async fn get_udp_port(&self, container_name: &str, container_port: u16) -> Option<u16> {
+        self.docker
+            .get_udp_port(container_name, container_port)
+            .await
+    }
+
+    fn container_port(&self, spawn_request: &SpawnRequest) -> u16 {
+        self.docker.container_port(spawn_request)
+    }
+
+    fn network(&self, spawn_request: &SpawnRequest) -> BackendNetwork {
+        self.docker.network(spawn_request)
+    }
+
+    async fn remove_isolated_network(&self, name: &str) -> Result<()> {
+        self.docker.remove_isolated_network(name).await
+    }
+
+    async fn list_managed_containers(&self) -> Result<Vec<ManagedContainer>> {
+        self.docker.list_managed_containers().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::drone::agent::engine::ContainerEventType;
+    use bollard::models::EventActor;
+    use std::collections::HashMap;
+
+    fn event_message(action: &str) -> EventMessage {
+        EventMessage {
+            action: Some(action.to_string()),
+            actor: Some(EventActor {
+                id: None,
+                attributes: Some(HashMap::from([(
+                    "name".to_string(),
+                    "spawner-mybackend".to_string(),
+                )])),
+            }),
+            ..EventMessage::default()
+        }
+    }
+
+    #[test]
+    fn test_container_event_from_podman() {
+        let event = container_event_from_podman(&event_message("died")).unwrap();
+        assert_eq!(ContainerEventType::Die, event.event);
+        assert_eq!("spawner-mybackend", event.name);
+
+        let event = container_event_from_podman(&event_message("health_status")).unwrap();
+        assert_eq!(ContainerEventType::HealthStatus, event.event);
+
+        assert!(container_event_from_podman(&event_message("cleanup")).is_none());
+    }
+}
@@ -0,0 +1,88 @@
+//! Readiness probes, which decide when a starting backend can be sent traffic.
+
+use crate::messages::agent::{ReadinessCheck, ReadinessProbe};
+use anyhow::{anyhow, Result};
+use http::Uri;
+use hyper::Client;
+use std::{net::SocketAddr, time::Duration};
+use tokio::net::TcpStream;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(100);
+const DEFAULT_MAX_ATTEMPTS: u32 = 300;
+
+/// The URI that an HTTP probe of `path` requests.
+fn probe_uri(address: SocketAddr, path: &str) -> Result<Uri> {
+    let separator = if path.starts_with('/') { "" } else { "/" };
+    Ok(Uri::from_maybe_shared(format!(
+        "http://{}{}{}",
+        address, separator, path
+    ))?)
+}
+
+async fn check_once(address: SocketAddr, check: &ReadinessCheck) -> Result<()> {
+    match check {
+        ReadinessCheck::Tcp => {
+            TcpStream::connect(address).await?;
+        }
+        ReadinessCheck::Http { path } => {
+            let status = Client::new().get(probe_uri(address, path)?).await?.status();
+            if !(status.is_success() || status.is_redirection()) {
+                return Err(anyhow!("Readiness probe returned status {}.", status));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Run the probe against the address until an attempt passes. Fails with the last
+/// attempt's error once the probe's attempts are exhausted.
+pub async fn wait_probe_ready(address: SocketAddr, probe: &ReadinessProbe) -> Result<()> {
+    let timeout = probe.timeout.unwrap_or(DEFAULT_TIMEOUT);
+    let interval = probe.interval.unwrap_or(DEFAULT_INTERVAL);
+    let max_attempts = probe.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1);
+    tracing::info!(%address, check = ?probe.check, "Running readiness probe.");
+
+    let mut last_error = None;
+    for attempt in 1..=max_attempts {
+        match tokio::time::timeout(timeout, check_once(address, &probe.check)).await {
+            Ok(Ok(())) => {
+                tracing::info!(%address, attempt, "Readiness probe passed.");
+                return Ok(());
+            }
+            Ok(Err(error)) => last_error = Some(error),
+            Err(_) => last_error = Some(anyhow!("Readiness probe timed out after {:?}.", timeout)),
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+
+    Err(anyhow!(
+        "Readiness probe failed {} times; last error: {:?}",
+        max_attempts,
+        last_error
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_probe_uri() {
+        let address: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        assert_eq!(
+            "http://127.0.0.1:8080/healthz",
+            probe_uri(address, "/healthz").unwrap().to_string()
+        );
+        assert_eq!(
+            "http://127.0.0.1:8080/ready?full=1",
+            probe_uri(address, "ready?full=1").unwrap().to_string()
+        );
+        assert_eq!(
+            "http://127.0.0.1:8080/",
+            probe_uri(address, "").unwrap().to_string()
+        );
+    }
+}
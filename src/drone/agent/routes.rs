@@ -0,0 +1,92 @@
+//! Publication of the drone's routes over NATS, so that proxies which do not share the
+//! drone's database can route to its backends.
+//!
+//! Routes are published as they are added and removed, and in full periodically and
+//! whenever a proxy asks, so that proxies recover from missed updates.
+
+use super::executor::Executor;
+use crate::{
+    logging::LogError,
+    messages::proxy::{RouteInfo, RouteSyncRequest, RouteUpdate, RouteUpdateMessage},
+    nats::TypedNats,
+    types::{BackendId, DroneId},
+};
+use anyhow::{anyhow, Result};
+use std::{sync::Arc, time::Duration};
+
+/// How often the drone publishes every one of its routes.
+const ROUTE_SYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Publishes changes to the drone's routes.
+#[derive(Clone)]
+pub struct RoutePublisher {
+    nats: TypedNats,
+    drone_id: DroneId,
+    cluster: String,
+}
+
+impl RoutePublisher {
+    pub fn new(nats: TypedNats, drone_id: DroneId, cluster: String) -> Self {
+        RoutePublisher {
+            nats,
+            drone_id,
+            cluster,
+        }
+    }
+
+    /// Publish an update. Errors are logged rather than returned, since the next sync
+    /// corrects whatever a lost update leaves out.
+    async fn publish(&self, update: RouteUpdate) {
+        self.nats
+            .publish(
+                &RouteUpdateMessage::subject(&self.cluster),
+                &RouteUpdateMessage {
+                    drone_id: self.drone_id,
+                    update,
+                },
+            )
+            .await
+            .log_error("Error publishing route update.");
+    }
+
+    pub async fn add(&self, route: RouteInfo) {
+        self.publish(RouteUpdate::Add(route)).await
+    }
+
+    pub async fn remove_backend(&self, backend_id: &BackendId) {
+        self.publish(RouteUpdate::RemoveBackend(backend_id.clone()))
+            .await
+    }
+
+    async fn sync(&self, executor: &Executor) {
+        match executor.live_routes().await {
+            Ok(routes) => self.publish(RouteUpdate::Sync(routes)).await,
+            Err(error) => tracing::warn!(?error, "Error reading routes to sync."),
+        }
+    }
+}
+
+/// Repeatedly publish every route of the drone, and do so immediately when a proxy asks.
+pub async fn route_sync_loop(publisher: RoutePublisher, executor: Arc<Executor>) -> Result<()> {
+    let mut sub = publisher
+        .nats
+        .subscribe(&RouteSyncRequest::subject(&publisher.cluster))
+        .await?;
+    let mut interval = tokio::time::interval(ROUTE_SYNC_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            request = sub.next() => match request {
+                Ok(Some(_)) => tracing::info!("Syncing routes at a proxy's request."),
+                Ok(None) => return Err(anyhow!("Route sync request subscription closed.")),
+                Err(error) => {
+                    tracing::warn!(?error, "Non-fatal error when listening for route sync requests.");
+                    continue;
+                }
+            },
+        }
+
+        publisher.sync(&executor).await;
+    }
+}
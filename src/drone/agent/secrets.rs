@@ -0,0 +1,190 @@
+//! Resolution of `secret://` references in spawn request environment variables, so that
+//! secrets are read by the drone when the container is created rather than sent over NATS.
+//!
+//! An environment variable whose value is `secret://<name>` is replaced with the value
+//! of `<name>` in the drone's secrets file, a JSON object of names to values. A value of
+//! `secret://vault/<mount>/<path>#<field>` is replaced with the field of the secret at
+//! `<path>` in the Vault KV version 2 engine mounted at `<mount>`.
+
+use crate::messages::agent::SpawnRequest;
+use anyhow::{anyhow, Context, Result};
+use reqwest::{Client, Url};
+use serde_json::Value;
+use std::{collections::HashMap, path::PathBuf};
+use tokio::fs;
+
+const SECRET_SCHEME: &str = "secret://";
+const VAULT_TOKEN_HEADER: &str = "X-Vault-Token";
+
+/// A Vault server to read secrets from.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct VaultOptions {
+    pub address: Url,
+
+    /// File containing the Vault token, e.g. the sink of a Vault agent. It is read
+    /// whenever secrets are resolved, so the token may be rotated.
+    pub token_path: PathBuf,
+}
+
+/// Where the drone reads secrets from, configured at the drone level.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct SecretSources {
+    /// JSON file mapping secret names to values.
+    pub file: Option<PathBuf>,
+    pub vault: Option<VaultOptions>,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+enum SecretReference<'a> {
+    File {
+        name: &'a str,
+    },
+    Vault {
+        mount: &'a str,
+        path: &'a str,
+        field: &'a str,
+    },
+}
+
+/// Parse an environment variable value as a secret reference, or return None if it is
+/// not one.
+fn parse_reference(value: &str) -> Result<Option<SecretReference<'_>>> {
+    let reference = match value.strip_prefix(SECRET_SCHEME) {
+        Some(reference) => reference,
+        None => return Ok(None),
+    };
+
+    if let Some(vault_reference) = reference.strip_prefix("vault/") {
+        let (location, field) = vault_reference
+            .split_once('#')
+            .ok_or_else(|| anyhow!("Expected {} to end with #<field>.", value))?;
+        let (mount, path) = location
+            .split_once('/')
+            .filter(|(mount, path)| !mount.is_empty() && !path.is_empty())
+            .ok_or_else(|| anyhow!("Expected {} to have a mount and a path.", value))?;
+        if field.is_empty() {
+            return Err(anyhow!("Expected {} to end with #<field>.", value));
+        }
+
+        Ok(Some(SecretReference::Vault { mount, path, field }))
+    } else if reference.is_empty() {
+        Err(anyhow!("Expected {} to name a secret.", value))
+    } else {
+        Ok(Some(SecretReference::File { name: reference }))
+    }
+}
+
+impl SecretSources {
+    /// Return a copy of the spawn request with secret references in its environment
+    /// replaced by their values. Errors never include secret values.
+    pub async fn resolve(&self, spawn_request: &SpawnRequest) -> Result<SpawnRequest> {
+        let mut file_secrets: Option<HashMap<String, String>> = None;
+        let mut vault_secrets: HashMap<String, Value> = HashMap::new();
+        let mut env = HashMap::with_capacity(spawn_request.env.len());
+
+        for (key, value) in &spawn_request.env {
+            let resolved = match parse_reference(value)? {
+                None => value.clone(),
+                Some(SecretReference::File { name }) => {
+                    if file_secrets.is_none() {
+                        file_secrets = Some(self.read_file().await?);
+                    }
+                    file_secrets
+                        .as_ref()
+                        .and_then(|secrets| secrets.get(name))
+                        .cloned()
+                        .ok_or_else(|| anyhow!("No secret named {} in the secrets file.", name))?
+                }
+                Some(SecretReference::Vault { mount, path, field }) => {
+                    let location = format!("{}/{}", mount, path);
+                    if !vault_secrets.contains_key(&location) {
+                        let secret = self.read_vault(mount, path).await?;
+                        vault_secrets.insert(location.clone(), secret);
+                    }
+                    vault_secrets[&location]
+                        .get(field)
+                        .and_then(Value::as_str)
+                        .map(str::to_string)
+                        .ok_or_else(|| {
+                            anyhow!("Vault secret {} has no string field {}.", location, field)
+                        })?
+                }
+            };
+            env.insert(key.clone(), resolved);
+        }
+
+        Ok(SpawnRequest {
+            env,
+            ..spawn_request.clone()
+        })
+    }
+
+    async fn read_file(&self) -> Result<HashMap<String, String>> {
+        let path = self
+            .file
+            .as_ref()
+            .ok_or_else(|| anyhow!("A secret was referenced, but no secrets file is set."))?;
+        let contents = fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Error reading secrets file {:?}.", path))?;
+
+        // Parse errors from serde_json include the offending input, so they are not passed on.
+        serde_json::from_str(&contents).map_err(|_| {
+            anyhow!(
+                "Expected secrets file {:?} to be a JSON object of strings.",
+                path
+            )
+        })
+    }
+
+    /// Read the data of a KV version 2 secret.
+    async fn read_vault(&self, mount: &str, path: &str) -> Result<Value> {
+        let vault = self
+            .vault
+            .as_ref()
+            .ok_or_else(|| anyhow!("A Vault secret was referenced, but no Vault is set."))?;
+        let token = fs::read_to_string(&vault.token_path)
+            .await
+            .with_context(|| format!("Error reading Vault token {:?}.", vault.token_path))?;
+        let url = vault.address.join(&format!("v1/{}/data/{}", mount, path))?;
+
+        let mut response: Value = Client::new()
+            .get(url)
+            .header(VAULT_TOKEN_HEADER, token.trim())
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("Error reading Vault secret {}/{}.", mount, path))?
+            .json()
+            .await?;
+
+        Ok(response["data"]["data"].take())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_reference() {
+        assert_eq!(None, parse_reference("8080").unwrap());
+        assert_eq!(
+            Some(SecretReference::File {
+                name: "db-password"
+            }),
+            parse_reference("secret://db-password").unwrap()
+        );
+        assert_eq!(
+            Some(SecretReference::Vault {
+                mount: "secret",
+                path: "myapp/db",
+                field: "password"
+            }),
+            parse_reference("secret://vault/secret/myapp/db#password").unwrap()
+        );
+        assert!(parse_reference("secret://").is_err());
+        assert!(parse_reference("secret://vault/secret/myapp/db").is_err());
+        assert!(parse_reference("secret://vault/secret#password").is_err());
+    }
+}
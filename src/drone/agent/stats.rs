@@ -0,0 +1,129 @@
+//! Periodic resource usage reports for running backends.
+
+use super::engine::{ContainerStats, Engine};
+use crate::{messages::agent::BackendStatsMessage, nats::TypedNats, types::BackendId};
+use anyhow::Result;
+use bollard::container::{MemoryStatsStats, Stats};
+use chrono::Utc;
+use dashmap::DashMap;
+use std::{sync::Arc, time::Duration};
+use tokio::time::Instant;
+
+/// Convert the stats reported by the Docker API into container stats.
+pub fn from_docker_stats(stats: &Stats) -> ContainerStats {
+    // As in the Docker CLI, page cache which can be reclaimed is not counted as usage.
+    let inactive_file = match stats.memory_stats.stats {
+        Some(MemoryStatsStats::V1(stats)) => stats.total_inactive_file,
+        Some(MemoryStatsStats::V2(stats)) => stats.inactive_file,
+        None => 0,
+    };
+    let memory_bytes = stats
+        .memory_stats
+        .usage
+        .unwrap_or_default()
+        .saturating_sub(inactive_file);
+
+    let (network_rx_bytes, network_tx_bytes) = stats
+        .networks
+        .iter()
+        .flat_map(|networks| networks.values())
+        .fold((0, 0), |(rx, tx), network| {
+            (rx + network.rx_bytes, tx + network.tx_bytes)
+        });
+
+    ContainerStats {
+        cpu_time: Duration::from_nanos(stats.cpu_stats.cpu_usage.total_usage),
+        memory_bytes,
+        network_rx_bytes,
+        network_tx_bytes,
+    }
+}
+
+/// Summarize the usage between two samples of a container's stats, taken `elapsed` apart.
+pub fn usage(
+    previous: &ContainerStats,
+    current: &ContainerStats,
+    elapsed: Duration,
+) -> BackendStatsMessage {
+    let cpu_time = current.cpu_time.saturating_sub(previous.cpu_time);
+    let cpu_percent = if elapsed.is_zero() {
+        0.
+    } else {
+        100. * cpu_time.as_secs_f64() / elapsed.as_secs_f64()
+    };
+
+    BackendStatsMessage {
+        cpu_percent,
+        memory_bytes: current.memory_bytes,
+        network_rx_bytes: current
+            .network_rx_bytes
+            .saturating_sub(previous.network_rx_bytes),
+        network_tx_bytes: current
+            .network_tx_bytes
+            .saturating_sub(previous.network_tx_bytes),
+        time: Utc::now(),
+    }
+}
+
+/// Publish the backend's resource usage at the given interval while its container runs,
+/// and keep the latest usage in `latest`.
+pub async fn stats_loop(
+    engine: Arc<dyn Engine>,
+    nc: TypedNats,
+    backend_id: BackendId,
+    container_name: String,
+    interval: Duration,
+    latest: Arc<DashMap<BackendId, BackendStatsMessage>>,
+) -> Result<()> {
+    let mut ticker = tokio::time::interval(interval);
+    let mut previous: Option<(Instant, ContainerStats)> = None;
+
+    loop {
+        ticker.tick().await;
+        if !engine.is_running(&container_name).await?.0 {
+            return Ok(());
+        }
+
+        let stats = engine.get_stats(&container_name).await?;
+        let now = Instant::now();
+        if let Some((last_time, last_stats)) = previous {
+            let message = usage(&last_stats, &stats, now - last_time);
+            nc.publish(&BackendStatsMessage::subject(&backend_id), &message)
+                .await?;
+            latest.insert(backend_id.clone(), message);
+        }
+        previous = Some((now, stats));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_usage() {
+        let previous = ContainerStats {
+            cpu_time: Duration::from_secs(10),
+            memory_bytes: 1000,
+            network_rx_bytes: 100,
+            network_tx_bytes: 200,
+        };
+        let current = ContainerStats {
+            cpu_time: Duration::from_secs(25),
+            memory_bytes: 2000,
+            network_rx_bytes: 150,
+            network_tx_bytes: 200,
+        };
+
+        let message = usage(&previous, &current, Duration::from_secs(10));
+        assert_eq!(150., message.cpu_percent);
+        assert_eq!(2000, message.memory_bytes);
+        assert_eq!(50, message.network_rx_bytes);
+        assert_eq!(0, message.network_tx_bytes);
+
+        // Counters reset when the container restarts.
+        let message = usage(&current, &previous, Duration::from_secs(10));
+        assert_eq!(0., message.cpu_percent);
+        assert_eq!(0, message.network_rx_bytes);
+    }
+}
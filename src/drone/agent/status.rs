@@ -0,0 +1,132 @@
+//! A local HTTP endpoint describing the agent's backends, for host-level tooling which
+//! does not have access to NATS.
+//!
+//! `GET /status` returns an `AgentStatus` as JSON.
+
+use super::{drone_state, executor::Executor};
+use crate::{
+    messages::agent::{BackendState, BackendStatsMessage, DroneState, EffectiveResourceLimits},
+    types::{BackendId, DroneId},
+};
+use anyhow::Result;
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+#[derive(Serialize, Debug, Clone)]
+pub struct BackendStatus {
+    pub backend_id: BackendId,
+    pub state: BackendState,
+    pub image: String,
+    pub labels: HashMap<String, String>,
+
+    /// Address on the host which the proxy routes the backend's traffic to, once it
+    /// has started.
+    pub address: Option<String>,
+
+    /// Host ports which the backend's UDP ports are published on.
+    pub udp_ports: HashMap<String, u16>,
+
+    /// Ports on the drone which the proxy passes the backend's TCP ports through on.
+    pub tcp_ports: HashMap<String, u16>,
+    pub resource_limits: Option<EffectiveResourceLimits>,
+
+    /// The most recently measured resource usage, if the drone publishes usage.
+    pub usage: Option<BackendStatsMessage>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct AgentStatus {
+    pub drone_id: DroneId,
+    pub state: DroneState,
+    pub max_backends: Option<u32>,
+    pub version: String,
+    pub backends: Vec<BackendStatus>,
+}
+
+struct StatusContext {
+    drone_id: DroneId,
+    executor: Arc<Executor>,
+    draining: Arc<AtomicBool>,
+    max_backends: Option<u32>,
+}
+
+impl StatusContext {
+    async fn status(&self) -> Result<AgentStatus> {
+        Ok(AgentStatus {
+            drone_id: self.drone_id,
+            state: drone_state(&self.draining, self.executor.backend_count() as u32),
+            max_backends: self.max_backends,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            backends: self.executor.backend_statuses().await?,
+        })
+    }
+
+    async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        let (status, body) = match (request.method(), request.uri().path()) {
+            (&Method::GET, "/status") => match self.status().await {
+                Ok(status) => match serde_json::to_string(&status) {
+                    Ok(body) => (StatusCode::OK, body),
+                    Err(error) => (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()),
+                },
+                Err(error) => {
+                    tracing::warn!(?error, "Error reading agent status.");
+                    (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", error))
+                }
+            },
+            (_, "/status") => (StatusCode::METHOD_NOT_ALLOWED, String::new()),
+            _ => (StatusCode::NOT_FOUND, String::new()),
+        };
+
+        let mut response = Response::new(Body::from(body));
+        *response.status_mut() = status;
+        if status == StatusCode::OK {
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                "application/json"
+                    .parse()
+                    .expect("Content type should always parse."),
+            );
+        }
+        response
+    }
+}
+
+/// Serve the agent's status on the given address until the server fails.
+pub async fn serve_status(
+    address: SocketAddr,
+    drone_id: DroneId,
+    executor: Arc<Executor>,
+    draining: Arc<AtomicBool>,
+    max_backends: Option<u32>,
+) -> Result<()> {
+    let context = Arc::new(StatusContext {
+        drone_id,
+        executor,
+        draining,
+        max_backends,
+    });
+    let make_service = make_service_fn(move |_| {
+        let context = context.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let context = context.clone();
+                async move { Ok::<_, Infallible>(context.handle(request).await) }
+            }))
+        }
+    });
+
+    tracing::info!(%address, "Serving agent status.");
+    Server::try_bind(&address)?.serve(make_service).await?;
+
+    Ok(())
+}
@@ -0,0 +1,413 @@
+//! Pools of pre-created containers which spawn requests can claim instead of waiting
+//! for a container to be created and its application to boot.
+//!
+//! Warm containers are started with `SPAWNER_WARM_POOL=1` in their environment and
+//! without any backend-specific configuration. When a spawn request claims one, the
+//! agent sends a POST request to `CLAIM_PATH` on the container's port with a JSON body
+//! of the form `{"backend_id": ..., "env": {...}}`, which the application must answer
+//! with a successful status once it has applied the environment. Only images whose
+//! application implements this handshake should be given a pool.
+//!
+//! Pools pull their images only if they are not present, so spawn requests with any
+//! other pull policy are given new containers. A claimed container keeps the labels of
+//! its pool, so if the drone's database is lost, the agent cannot re-adopt it and
+//! removes it instead.
+
+use super::{engine::Engine, wait_port_ready};
+use crate::{
+    logging::LogError,
+    messages::agent::{BackendNetwork, PullPolicy, SpawnRequest},
+    types::BackendId,
+};
+use anyhow::{anyhow, Result};
+use reqwest::Client;
+use serde_json::json;
+use std::{collections::HashMap, net::IpAddr, str::FromStr, sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+
+/// Path within warm containers that the claim handshake is sent to.
+pub const CLAIM_PATH: &str = "/.spawner/claim";
+
+/// Environment variable set in warm containers, so that their application knows to
+/// wait for the claim handshake.
+const WARM_POOL_ENV: &str = "SPAWNER_WARM_POOL";
+
+/// How often pools are checked for dead containers and topped up.
+const FILL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a warm container has to respond to the claim handshake.
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The number of warm containers to keep for an image, configured at the drone level.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct WarmPoolConfig {
+    pub image: String,
+    pub size: usize,
+}
+
+impl FromStr for WarmPoolConfig {
+    type Err = anyhow::Error;
+
+    /// Parses `<image>=<size>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (image, size) = s
+            .rsplit_once('=')
+            .ok_or_else(|| anyhow!("Expected warm pool to be <image>=<size>, got {:?}.", s))?;
+
+        Ok(WarmPoolConfig {
+            image: image.to_string(),
+            size: size.parse()?,
+        })
+    }
+}
+
+/// A warm container which is running and has not been claimed.
+struct WarmContainer {
+    name: String,
+    port: u16,
+}
+
+struct Pool {
+    index: usize,
+    config: WarmPoolConfig,
+
+    /// Namespace of the agent, which prefixes container names.
+    namespace: Option<String>,
+
+    /// The spawn request that the pool's containers are run with.
+    template: SpawnRequest,
+
+    /// Idle containers, in the order they were created.
+    idle: Mutex<Vec<WarmContainer>>,
+}
+
+impl Pool {
+    fn new(index: usize, config: WarmPoolConfig, namespace: Option<String>) -> Self {
+        let template = SpawnRequest {
+            image: config.image.clone(),
+            backend_id: BackendId::new(format!("warm-pool-{}", index)),
+            max_idle_secs: Duration::ZERO,
+            startup_timeout_secs: None,
+            expires_at: None,
+            lease_token: None,
+            priority: 0,
+            key: None,
+            bearer_token: None,
+            max_connections: None,
+            timeouts: None,
+            env: vec![(WARM_POOL_ENV.to_string(), "1".to_string())]
+                .into_iter()
+                .collect(),
+            metadata: HashMap::new(),
+            labels: HashMap::new(),
+            drone_selector: HashMap::new(),
+            cluster: None,
+            reschedulable: false,
+            credentials: None,
+            resource_limits: Default::default(),
+            ulimits: Default::default(),
+            gpus: 0,
+            volume_mounts: vec![],
+            read_only_root_filesystem: false,
+            init: None,
+            shm_size_bytes: None,
+            pids_limit: None,
+            tmpfs_mounts: vec![],
+            security_options: Default::default(),
+            user: None,
+            port: None,
+            additional_ports: HashMap::new(),
+            custom_domain: None,
+            udp_ports: HashMap::new(),
+            tcp_ports: HashMap::new(),
+            network: None,
+            pull_policy: PullPolicy::IfNotPresent,
+            restart_policy: Default::default(),
+            stop_signal: None,
+            stop_grace_period_secs: None,
+            readiness_probe: None,
+        };
+
+        Pool {
+            index,
+            config,
+            namespace,
+            template,
+            idle: Mutex::default(),
+        }
+    }
+
+    /// Container name for the given slot of the pool. Names are reused once a container
+    /// is claimed (and renamed), so that containers left over from a previous run of the
+    /// agent are replaced rather than leaked. They do not begin with the backend resource
+    /// prefix, so warm containers are never mistaken for backends.
+    fn container_name(&self, slot: usize) -> String {
+        let name = format!("spawner_warm_{}_{}", self.index, slot);
+        match &self.namespace {
+            Some(namespace) => format!("{}-{}", namespace, name),
+            None => name,
+        }
+    }
+}
+
+/// Whether a container created for `template` is configured the way `spawn_request`
+/// asks for, from an image pulled the way it asks for. The environment is excluded
+/// because it is provided by the handshake, and the port and network are excluded
+/// because they depend on the drone's defaults.
+fn same_container_config(template: &SpawnRequest, spawn_request: &SpawnRequest) -> bool {
+    template.image == spawn_request.image
+        && template.pull_policy == spawn_request.pull_policy
+        && template.labels == spawn_request.labels
+        && template.resource_limits == spawn_request.resource_limits
+        && template.ulimits == spawn_request.ulimits
+        && template.gpus == spawn_request.gpus
+        && template.volume_mounts == spawn_request.volume_mounts
+        && template.read_only_root_filesystem == spawn_request.read_only_root_filesystem
+        && template.init == spawn_request.init
+        && template.shm_size_bytes == spawn_request.shm_size_bytes
+        && template.pids_limit == spawn_request.pids_limit
+        && template.tmpfs_mounts == spawn_request.tmpfs_mounts
+        && template.security_options == spawn_request.security_options
+        && template.user == spawn_request.user
+        && template.additional_ports == spawn_request.additional_ports
+        && template.udp_ports == spawn_request.udp_ports
+        && template.tcp_ports == spawn_request.tcp_ports
+        && template.restart_policy == spawn_request.restart_policy
+        && template.stop_signal == spawn_request.stop_signal
+}
+
+/// The warm pools of a drone, one for each configured image.
+pub struct WarmPool {
+    engine: Arc<dyn Engine>,
+    host_ip: IpAddr,
+    client: Client,
+    pools: Vec<Pool>,
+}
+
+impl WarmPool {
+    pub fn new(
+        engine: Arc<dyn Engine>,
+        host_ip: IpAddr,
+        configs: Vec<WarmPoolConfig>,
+        namespace: Option<String>,
+    ) -> Self {
+        WarmPool {
+            engine,
+            host_ip,
+            client: Client::new(),
+            pools: configs
+                .into_iter()
+                .enumerate()
+                .map(|(index, config)| Pool::new(index, config, namespace.clone()))
+                .collect(),
+        }
+    }
+
+    /// Keep each pool topped up with running containers.
+    pub async fn fill_loop(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(FILL_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            for pool in &self.pools {
+                self.fill(pool).await.log_error("Error filling warm pool.");
+            }
+        }
+    }
+
+    async fn fill(&self, pool: &Pool) -> Result<()> {
+        // Discard containers which have exited since they were created.
+        let names: Vec<String> = pool
+            .idle
+            .lock()
+            .await
+            .iter()
+            .map(|container| container.name.clone())
+            .collect();
+        for name in names {
+            if !self.engine.is_running(&name).await?.0 {
+                tracing::warn!(%name, "Warm container exited.");
+                pool.idle
+                    .lock()
+                    .await
+                    .retain(|container| container.name != name);
+                self.engine.remove_container(&name).await?;
+            }
+        }
+
+        loop {
+            let slot = {
+                let idle = pool.idle.lock().await;
+                if idle.len() >= pool.config.size {
+                    return Ok(());
+                }
+
+                (0..)
+                    .find(|slot| {
+                        let name = pool.container_name(*slot);
+                        !idle.iter().any(|container| container.name == name)
+                    })
+                    .expect("There is always a free slot.")
+            };
+
+            let name = pool.container_name(slot);
+            tracing::info!(%name, image = %pool.config.image, "Creating warm container.");
+
+            self.engine
+                .pull_image(&pool.config.image, &None, PullPolicy::IfNotPresent)
+                .await?;
+            self.engine.remove_container(&name).await?;
+            self.engine.run_container(&name, &pool.template).await?;
+
+            let port = self
+                .engine
+                .get_port(&name, self.engine.container_port(&pool.template))
+                .await
+                .ok_or_else(|| anyhow!("Couldn't get port of warm container {}", name))?;
+            wait_port_ready(port, self.host_ip).await?;
+
+            pool.idle.lock().await.push(WarmContainer { name, port });
+        }
+    }
+
+    /// Find the pool, if any, whose containers can serve the spawn request.
+    fn pool_for(&self, spawn_request: &SpawnRequest) -> Option<&Pool> {
+        let network = self.engine.network(spawn_request);
+        if network == BackendNetwork::Isolated {
+            // Isolated networks are named after the container, so they can't be created
+            // before the backend's name is known.
+            return None;
+        }
+
+        self.pools.iter().find(|pool| {
+            same_container_config(&pool.template, spawn_request)
+                && self.engine.container_port(&pool.template)
+                    == self.engine.container_port(spawn_request)
+                && self.engine.network(&pool.template) == network
+        })
+    }
+
+    /// Try to claim a warm container for the spawn request, renaming it to `name`.
+    /// Returns false if no suitable container is available or the claim fails, in
+    /// which case the backend should be given a new container.
+    pub async fn claim(&self, name: &str, spawn_request: &SpawnRequest) -> bool {
+        let pool = match self.pool_for(spawn_request) {
+            Some(pool) => pool,
+            None => return false,
+        };
+        let container = {
+            // The container is renamed while the pool is locked, so that its slot is not
+            // reused before it has been renamed.
+            let mut idle = pool.idle.lock().await;
+            let container = match idle.pop() {
+                Some(container) => container,
+                None => {
+                    tracing::info!(image = %pool.config.image, "Warm pool is empty.");
+                    return false;
+                }
+            };
+
+            if let Err(error) = self.engine.rename_container(&container.name, name).await {
+                tracing::warn!(?error, "Error renaming claimed warm container.");
+                self.engine
+                    .remove_container(&container.name)
+                    .await
+                    .log_error("Error removing warm container.");
+                return false;
+            }
+
+            container
+        };
+
+        if let Err(error) = self.handshake(&container, spawn_request).await {
+            tracing::warn!(?error, "Error in warm container handshake.");
+            self.engine
+                .remove_container(name)
+                .await
+                .log_error("Error removing warm container.");
+            return false;
+        }
+
+        tracing::info!(warm_name = %container.name, %name, "Claimed warm container.");
+        true
+    }
+
+    async fn handshake(
+        &self,
+        container: &WarmContainer,
+        spawn_request: &SpawnRequest,
+    ) -> Result<()> {
+        self.client
+            .post(format!(
+                "http://{}:{}{}",
+                self.host_ip, container.port, CLAIM_PATH
+            ))
+            .timeout(CLAIM_TIMEOUT)
+            .json(&json!({
+                "backend_id": spawn_request.backend_id,
+                "env": spawn_request.env,
+            }))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_warm_pool_config() {
+        assert_eq!(
+            WarmPoolConfig {
+                image: "ghcr.io/drifting-in-space/test-image:latest".to_string(),
+                size: 3,
+            },
+            "ghcr.io/drifting-in-space/test-image:latest=3"
+                .parse()
+                .unwrap()
+        );
+        assert!("ghcr.io/drifting-in-space/test-image"
+            .parse::<WarmPoolConfig>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_same_container_config() {
+        let pool = Pool::new(
+            0,
+            WarmPoolConfig {
+                image: "test-image".to_string(),
+                size: 1,
+            },
+            None,
+        );
+        assert_eq!("spawner_warm_0_2", pool.container_name(2));
+        let namespaced_pool = Pool::new(1, pool.config.clone(), Some("staging".to_string()));
+        assert_eq!(
+            "staging-spawner_warm_1_0",
+            namespaced_pool.container_name(0)
+        );
+
+        let mut spawn_request = pool.template.clone();
+        spawn_request.backend_id = BackendId::new("backend".to_string());
+        spawn_request.env = vec![("KEY".to_string(), "value".to_string())]
+            .into_iter()
+            .collect();
+        assert!(same_container_config(&pool.template, &spawn_request));
+
+        // The pool's image may be stale, or may not be allowed to be pulled.
+        spawn_request.pull_policy = PullPolicy::Always;
+        assert!(!same_container_config(&pool.template, &spawn_request));
+        spawn_request.pull_policy = PullPolicy::Never;
+        assert!(!same_container_config(&pool.template, &spawn_request));
+        spawn_request.pull_policy = PullPolicy::IfNotPresent;
+
+        spawn_request.gpus = 1;
+        assert!(!same_container_config(&pool.template, &spawn_request));
+    }
+}
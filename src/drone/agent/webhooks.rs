@@ -0,0 +1,178 @@
+//! HTTP webhooks which the agent calls when backends become ready, terminate, or fail.
+//!
+//! Each webhook is a JSON POST of the backend's state message, with the event in the
+//! `X-Spawner-Event` header. When a secret is configured, the body is signed with
+//! HMAC-SHA256 and the signature sent as `X-Spawner-Signature: sha256=<hex>`, so that
+//! receivers can check that the call came from a drone.
+
+use crate::{
+    messages::agent::{BackendState, BackendStateMessage},
+    retry::{do_with_backoff, RetryPolicy},
+    types::BackendId,
+};
+use anyhow::Result;
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use reqwest::{Client, Url};
+use serde::Serialize;
+use std::{fmt::Write, time::Duration};
+
+const EVENT_HEADER: &str = "X-Spawner-Event";
+const SIGNATURE_HEADER: &str = "X-Spawner-Signature";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const DELIVERY_POLICY: RetryPolicy = RetryPolicy {
+    max_attempts: 3,
+    initial_delay: Duration::from_secs(1),
+    max_delay: Duration::from_secs(10),
+};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    Ready,
+
+    /// The backend stopped after exiting by itself, being swept, expiring, or being
+    /// evicted.
+    Terminated,
+
+    /// The backend stopped because of an error or failed health check, or never became
+    /// ready.
+    Failed,
+}
+
+impl WebhookEvent {
+    /// The event for a backend entering the given state, if there is one.
+    pub fn for_state(state: BackendState) -> Option<Self> {
+        match state {
+            BackendState::Ready => Some(WebhookEvent::Ready),
+            BackendState::Exited
+            | BackendState::Swept
+            | BackendState::Expired
+            | BackendState::Evicted => Some(WebhookEvent::Terminated),
+            state if state.terminal() => Some(WebhookEvent::Failed),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            WebhookEvent::Ready => "ready",
+            WebhookEvent::Terminated => "terminated",
+            WebhookEvent::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    event: WebhookEvent,
+    backend_id: &'a BackendId,
+
+    #[serde(flatten)]
+    message: &'a BackendStateMessage,
+}
+
+/// The hex-encoded HMAC-SHA256 of the body.
+fn sign(secret: &str, body: &[u8]) -> Result<String> {
+    let key = PKey::hmac(secret.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(body)?;
+
+    let mut signature = String::new();
+    for byte in signer.sign_to_vec()? {
+        write!(signature, "{:02x}", byte).expect("Writing to a String should never fail.");
+    }
+    Ok(signature)
+}
+
+/// Webhook URLs to call on backend lifecycle events, configured at the drone level.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Webhooks {
+    pub urls: Vec<Url>,
+
+    /// Key to sign payloads with. If not provided, payloads are not signed.
+    pub secret: Option<String>,
+}
+
+impl Webhooks {
+    /// Call every webhook for the backend's state change, if it is a lifecycle event.
+    /// Calls are made in the background and retried on failure, so they never hold up
+    /// the backend.
+    pub fn send(&self, backend_id: &BackendId, message: &BackendStateMessage) -> Result<()> {
+        let event = match WebhookEvent::for_state(message.state) {
+            Some(event) if !self.urls.is_empty() => event,
+            _ => return Ok(()),
+        };
+
+        let body = serde_json::to_vec(&WebhookPayload {
+            event,
+            backend_id,
+            message,
+        })?;
+        let signature = match &self.secret {
+            Some(secret) => Some(format!("sha256={}", sign(secret, &body)?)),
+            None => None,
+        };
+        let client = Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+
+        for url in &self.urls {
+            let (client, url, body, signature) =
+                (client.clone(), url.clone(), body.clone(), signature.clone());
+            let backend_id = backend_id.clone();
+            tokio::spawn(async move {
+                let result = do_with_backoff(
+                    || async {
+                        let mut request = client
+                            .post(url.clone())
+                            .header(http::header::CONTENT_TYPE, "application/json")
+                            .header(EVENT_HEADER, event.as_str())
+                            .body(body.clone());
+                        if let Some(signature) = &signature {
+                            request = request.header(SIGNATURE_HEADER, signature);
+                        }
+                        request.send().await?.error_for_status()?;
+                        Ok(())
+                    },
+                    &DELIVERY_POLICY,
+                )
+                .await;
+
+                if let Err(error) = result {
+                    tracing::warn!(?error, %backend_id, %url, ?event, "Couldn't deliver webhook.");
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_webhook_event() {
+        assert_eq!(
+            Some(WebhookEvent::Ready),
+            WebhookEvent::for_state(BackendState::Ready)
+        );
+        assert_eq!(
+            Some(WebhookEvent::Terminated),
+            WebhookEvent::for_state(BackendState::Swept)
+        );
+        assert_eq!(
+            Some(WebhookEvent::Failed),
+            WebhookEvent::for_state(BackendState::TimedOutBeforeReady)
+        );
+        assert_eq!(None, WebhookEvent::for_state(BackendState::Starting));
+    }
+
+    #[test]
+    fn test_sign() {
+        // Test case 2 of RFC 4231.
+        assert_eq!(
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            sign("Jefe", b"what do ya want for nothing?").unwrap()
+        );
+    }
+}
@@ -0,0 +1,227 @@
+//! Mutual TLS between the proxy and backends, so that on hosts shared with other
+//! workloads, neither side's traffic can be read or impersonated by another process.
+//!
+//! The operator provides a CA for backends. The agent issues each backend a server
+//! certificate signed by it, and passes the certificate, its key, and the CA to the
+//! container in its environment. The proxy issues itself a client certificate from the
+//! same CA, and only connects to backends which present a certificate signed by it.
+//! Backends should in turn only accept clients which present a certificate signed by the
+//! CA.
+
+use crate::{keys::KeyCertPathPair, types::BackendId};
+use anyhow::{anyhow, Context, Result};
+use openssl::{
+    asn1::Asn1Time,
+    bn::{BigNum, MsbOption},
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    x509::{
+        extension::{BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName},
+        X509NameBuilder, X509,
+    },
+};
+
+/// The name every backend's certificate is issued for, and which the proxy verifies,
+/// since backends are reached by IP address rather than by name.
+pub const BACKEND_SERVER_NAME: &str = "backend.spawner.internal";
+
+/// Environment variable holding the backend's certificate, in PEM format.
+pub const TLS_CERTIFICATE_ENV: &str = "SPAWNER_TLS_CERTIFICATE";
+
+/// Environment variable holding the private key of the backend's certificate, in PKCS #8
+/// PEM format.
+pub const TLS_PRIVATE_KEY_ENV: &str = "SPAWNER_TLS_PRIVATE_KEY";
+
+/// Environment variable holding the CA which the proxy's client certificate is signed
+/// by, in PEM format.
+pub const TLS_CLIENT_CA_ENV: &str = "SPAWNER_TLS_CLIENT_CA";
+
+/// Common name of the proxy's client certificate.
+const PROXY_COMMON_NAME: &str = "spawner-proxy";
+
+/// How long issued certificates are valid for. Backends are not expected to outlive them.
+const CERTIFICATE_DAYS: u32 = 365;
+
+/// A certificate issued by the backend CA, with its private key.
+pub struct IssuedCertificate {
+    pub certificate: X509,
+    pub private_key: PKey<Private>,
+}
+
+impl IssuedCertificate {
+    /// The private key in PKCS #8 DER format, as rustls expects it.
+    pub fn private_key_pkcs8_der(&self) -> Result<Vec<u8>> {
+        let pem = self.private_key.private_key_to_pem_pkcs8()?;
+        rustls_pemfile::pkcs8_private_keys(&mut pem.as_slice())?
+            .pop()
+            .ok_or_else(|| anyhow!("Expected a PKCS #8 private key."))
+    }
+}
+
+/// The CA which the certificates of backends and the proxy are issued by.
+pub struct BackendCa {
+    certificate: X509,
+    private_key: PKey<Private>,
+}
+
+impl BackendCa {
+    /// Read the CA's certificate and private key, in PEM format.
+    pub fn load(paths: &KeyCertPathPair) -> Result<Self> {
+        let certificate = X509::from_pem(&std::fs::read(&paths.certificate_path)?)
+            .with_context(|| format!("Error reading {:?}.", paths.certificate_path))?;
+        let private_key = PKey::private_key_from_pem(&std::fs::read(&paths.private_key_path)?)
+            .with_context(|| format!("Error reading {:?}.", paths.private_key_path))?;
+
+        Ok(BackendCa {
+            certificate,
+            private_key,
+        })
+    }
+
+    pub fn certificate(&self) -> &X509 {
+        &self.certificate
+    }
+
+    fn issue(
+        &self,
+        common_name: &str,
+        extended_key_usage: &ExtendedKeyUsage,
+    ) -> Result<IssuedCertificate> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let private_key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, common_name)?;
+        let name = name.build();
+
+        let mut serial = BigNum::new()?;
+        serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+
+        let mut builder = X509::builder()?;
+        builder.set_version(2)?;
+        builder.set_serial_number(&*serial.to_asn1_integer()?)?;
+        builder.set_subject_name(&name)?;
+        builder.set_issuer_name(self.certificate.subject_name())?;
+        builder.set_pubkey(&private_key)?;
+        builder.set_not_before(&*Asn1Time::days_from_now(0)?)?;
+        builder.set_not_after(&*Asn1Time::days_from_now(CERTIFICATE_DAYS)?)?;
+        builder.append_extension(BasicConstraints::new().critical().build()?)?;
+        builder.append_extension(
+            KeyUsage::new()
+                .critical()
+                .digital_signature()
+                .key_encipherment()
+                .build()?,
+        )?;
+        builder.append_extension(extended_key_usage.build()?)?;
+        let subject_alternative_name = SubjectAlternativeName::new()
+            .dns(BACKEND_SERVER_NAME)
+            .build(&builder.x509v3_context(Some(&self.certificate), None))?;
+        builder.append_extension(subject_alternative_name)?;
+        builder.sign(&self.private_key, MessageDigest::sha256())?;
+
+        Ok(IssuedCertificate {
+            certificate: builder.build(),
+            private_key,
+        })
+    }
+
+    /// Issue the certificate the backend serves the proxy with.
+    pub fn issue_backend(&self, backend_id: &BackendId) -> Result<IssuedCertificate> {
+        self.issue(backend_id.id(), ExtendedKeyUsage::new().server_auth())
+    }
+
+    /// Issue the certificate the proxy presents to backends.
+    pub fn issue_proxy(&self) -> Result<IssuedCertificate> {
+        self.issue(PROXY_COMMON_NAME, ExtendedKeyUsage::new().client_auth())
+    }
+
+    /// The environment variables which give the backend its certificate and the CA to
+    /// verify the proxy with.
+    pub fn backend_env(&self, backend_id: &BackendId) -> Result<Vec<(String, String)>> {
+        let issued = self.issue_backend(backend_id)?;
+
+        Ok(vec![
+            (
+                TLS_CERTIFICATE_ENV.to_string(),
+                String::from_utf8(issued.certificate.to_pem()?)?,
+            ),
+            (
+                TLS_PRIVATE_KEY_ENV.to_string(),
+                String::from_utf8(issued.private_key.private_key_to_pem_pkcs8()?)?,
+            ),
+            (
+                TLS_CLIENT_CA_ENV.to_string(),
+                String::from_utf8(self.certificate.to_pem()?)?,
+            ),
+        ])
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    /// A self-signed CA, as an operator might create with `openssl req -x509`.
+    pub fn test_ca() -> BackendCa {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let private_key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "Test backend CA")
+            .unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&private_key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder
+            .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+            .unwrap();
+        builder
+            .append_extension(KeyUsage::new().critical().key_cert_sign().build().unwrap())
+            .unwrap();
+        builder.sign(&private_key, MessageDigest::sha256()).unwrap();
+
+        BackendCa {
+            certificate: builder.build(),
+            private_key,
+        }
+    }
+
+    #[test]
+    fn test_issue_backend_certificate() {
+        let ca = test_ca();
+        let backend_id = BackendId::new("mybackend".to_string());
+        let issued = ca.issue_backend(&backend_id).unwrap();
+
+        assert!(issued
+            .certificate
+            .verify(&ca.certificate.public_key().unwrap())
+            .unwrap());
+        let names: Vec<_> = issued
+            .certificate
+            .subject_alt_names()
+            .unwrap()
+            .iter()
+            .filter_map(|name| name.dnsname().map(str::to_string))
+            .collect();
+        assert_eq!(vec![BACKEND_SERVER_NAME.to_string()], names);
+
+        let env = ca.backend_env(&backend_id).unwrap();
+        let names: Vec<_> = env.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            vec![TLS_CERTIFICATE_ENV, TLS_PRIVATE_KEY_ENV, TLS_CLIENT_CA_ENV],
+            names
+        );
+    }
+}
@@ -0,0 +1,227 @@
+//! The key of the ACME account certificates are requested with.
+//!
+//! ACME servers identify accounts by their key, and limit how many can be created, so
+//! the key is generated once and reused: it is kept at the account key path, and in the
+//! certificate store if there is one, so that it survives the drone moving hosts.
+//!
+//! Some ACME servers, e.g. ZeroSSL's, only create accounts bound to an account the
+//! operator holds with the CA, by an External Account Binding (RFC 8555 section 7.3.4).
+//! acme2 can't send one, so such accounts are created here first; acme2 then finds the
+//! existing account by its key.
+
+use super::store::CertStore;
+use acme2::gen_rsa_private_key;
+use anyhow::{anyhow, Context, Result};
+use openssl::{
+    hash::MessageDigest,
+    pkey::{PKey, Private},
+    sign::Signer,
+};
+use reqwest::{header::CONTENT_TYPE, Client};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::path::Path;
+
+/// Credentials binding the ACME account to an account held with the CA.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct ExternalAccountBinding {
+    pub key_id: String,
+
+    /// The MAC key, base64url-encoded as CAs provide it.
+    pub hmac_key: String,
+}
+
+impl ExternalAccountBinding {
+    /// The `externalAccountBinding` of a newAccount request: the account's public key,
+    /// signed with the MAC key.
+    fn binding(&self, account_key: &PKey<Private>, new_account_url: &str) -> Result<Value> {
+        let hmac_key =
+            base64::decode_config(self.hmac_key.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+                .context("Expected the external account binding MAC key to be base64url.")?;
+        let protected = b64(json!({
+            "alg": "HS256",
+            "kid": self.key_id,
+            "url": new_account_url,
+        })
+        .to_string());
+        let payload = b64(jwk(account_key)?.to_string());
+
+        let hmac_key = PKey::hmac(&hmac_key)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &hmac_key)?;
+        signer.update(format!("{}.{}", protected, payload).as_bytes())?;
+
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": b64(signer.sign_to_vec()?),
+        }))
+    }
+}
+
+fn b64(data: impl AsRef<[u8]>) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+/// The public key of the account, as a JSON Web Key.
+fn jwk(account_key: &PKey<Private>) -> Result<Value> {
+    let rsa = account_key
+        .rsa()
+        .context("Expected the ACME account key to be RSA.")?;
+    Ok(json!({
+        "e": b64(rsa.e().to_vec()),
+        "kty": "RSA",
+        "n": b64(rsa.n().to_vec()),
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DirectoryUrls {
+    new_nonce: String,
+    new_account: String,
+}
+
+/// Create the account of the key with the external account binding, unless it exists.
+pub async fn register_account(
+    client: &Client,
+    directory_url: &str,
+    account_key: &PKey<Private>,
+    eab: &ExternalAccountBinding,
+    contact: &[&str],
+) -> Result<()> {
+    let directory: DirectoryUrls = client
+        .get(directory_url)
+        .send()
+        .await?
+        .error_for_status()
+        .context("Error fetching ACME directory.")?
+        .json()
+        .await?;
+    let nonce_response = client
+        .head(&directory.new_nonce)
+        .send()
+        .await?
+        .error_for_status()
+        .context("Error fetching ACME nonce.")?;
+    let nonce = nonce_response
+        .headers()
+        .get("Replay-Nonce")
+        .ok_or_else(|| anyhow!("Expected Replay-Nonce from ACME server."))?
+        .to_str()?;
+
+    let protected = b64(json!({
+        "alg": "RS256",
+        "jwk": jwk(account_key)?,
+        "nonce": nonce,
+        "url": directory.new_account,
+    })
+    .to_string());
+    let payload = b64(json!({
+        "contact": contact,
+        "termsOfServiceAgreed": true,
+        "externalAccountBinding": eab.binding(account_key, &directory.new_account)?,
+    })
+    .to_string());
+    let mut signer = Signer::new(MessageDigest::sha256(), account_key)?;
+    signer.update(format!("{}.{}", protected, payload).as_bytes())?;
+    let body = json!({
+        "protected": protected,
+        "payload": payload,
+        "signature": b64(signer.sign_to_vec()?),
+    });
+
+    let response = client
+        .post(&directory.new_account)
+        .header(CONTENT_TYPE, "application/jose+json")
+        .body(body.to_string())
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(anyhow!(
+            "Error registering ACME account with external account binding: {}",
+            response.text().await.unwrap_or_default()
+        ));
+    }
+
+    Ok(())
+}
+
+fn parse(private_key_pem: &str) -> Result<PKey<Private>> {
+    PKey::private_key_from_pem(private_key_pem.as_bytes())
+        .context("Error reading ACME account key.")
+}
+
+/// The account key at the path, or else the one in the store, or else a new one, saved to
+/// both.
+pub async fn account_key(path: &Path, store: Option<&dyn CertStore>) -> Result<PKey<Private>> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(private_key_pem) => return parse(&private_key_pem),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => (),
+        Err(error) => return Err(error).context("Error reading ACME account key."),
+    }
+
+    if let Some(store) = store {
+        if let Some(private_key_pem) = store.load_account_key().await? {
+            tracing::info!("Restoring ACME account key from store.");
+            let private_key = parse(&private_key_pem)?;
+            tokio::fs::write(path, &private_key_pem).await?;
+            return Ok(private_key);
+        }
+    }
+
+    tracing::info!(?path, "Generating ACME account key.");
+    let private_key = gen_rsa_private_key(4096)?;
+    let private_key_pem = String::from_utf8(private_key.private_key_to_pem_pkcs8()?)?;
+    tokio::fs::write(path, &private_key_pem).await?;
+    if let Some(store) = store {
+        store.save_account_key(&private_key_pem).await?;
+    }
+
+    Ok(private_key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_account_key_reused() {
+        let path = std::env::temp_dir().join(format!("acme-account-{}.key", std::process::id()));
+
+        let first = account_key(&path, None).await.unwrap();
+        let second = account_key(&path, None).await.unwrap();
+        assert!(first.public_eq(&second));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_external_account_binding() {
+        let account_key = gen_rsa_private_key(2048).unwrap();
+        let eab = ExternalAccountBinding {
+            key_id: "kid-1".to_string(),
+            hmac_key: b64(b"secret"),
+        };
+        let binding = eab
+            .binding(&account_key, "https://acme.server/new-account")
+            .unwrap();
+
+        let field = |name: &str| binding[name].as_str().unwrap().to_string();
+        let decode = |value: String| {
+            let json = base64::decode_config(value, base64::URL_SAFE_NO_PAD).unwrap();
+            serde_json::from_slice::<Value>(&json).unwrap()
+        };
+        assert_eq!(
+            json!({"alg": "HS256", "kid": "kid-1", "url": "https://acme.server/new-account"}),
+            decode(field("protected"))
+        );
+        assert_eq!(jwk(&account_key).unwrap(), decode(field("payload")));
+
+        let hmac_key = PKey::hmac(b"secret").unwrap();
+        let mut signer = Signer::new(MessageDigest::sha256(), &hmac_key).unwrap();
+        signer
+            .update(format!("{}.{}", field("protected"), field("payload")).as_bytes())
+            .unwrap();
+        assert_eq!(b64(signer.sign_to_vec().unwrap()), field("signature"));
+    }
+}
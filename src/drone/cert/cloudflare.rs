@@ -0,0 +1,116 @@
+//! Challenge records set through the Cloudflare API.
+
+use super::dns::{challenge_record_name, DnsProvider, CHALLENGE_TTL};
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use reqwest::{Client, RequestBuilder};
+use serde::Deserialize;
+use serde_json::json;
+use std::env::var;
+
+const API_URL: &str = "https://api.cloudflare.com/client/v4";
+
+/// The envelope of every Cloudflare API response.
+#[derive(Deserialize)]
+struct ApiResponse<T> {
+    success: bool,
+    #[serde(default)]
+    errors: Vec<ApiError>,
+    result: Option<T>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiError {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct DnsRecord {
+    id: String,
+}
+
+pub struct Cloudflare {
+    zone_id: String,
+    api_token: String,
+    client: Client,
+}
+
+impl Cloudflare {
+    pub fn from_env(zone_id: &str, client: &Client) -> Result<Self> {
+        Ok(Cloudflare {
+            zone_id: zone_id.to_string(),
+            api_token: var("CLOUDFLARE_API_TOKEN")
+                .context("Expected CLOUDFLARE_API_TOKEN for Cloudflare.")?,
+            client: client.clone(),
+        })
+    }
+
+    fn records_url(&self) -> String {
+        format!("{}/zones/{}/dns_records", API_URL, self.zone_id)
+    }
+
+    /// Send a request to the Cloudflare API, returning the result of its response.
+    async fn send<T: for<'de> Deserialize<'de>>(&self, request: RequestBuilder) -> Result<T> {
+        let response: ApiResponse<T> = request
+            .bearer_auth(&self.api_token)
+            .send()
+            .await?
+            .json()
+            .await?;
+        if !response.success {
+            let messages: Vec<String> = response
+                .errors
+                .into_iter()
+                .map(|error| error.message)
+                .collect();
+            return Err(anyhow!(
+                "Cloudflare request failed: {}",
+                messages.join("; ")
+            ));
+        }
+
+        response
+            .result
+            .ok_or_else(|| anyhow!("Cloudflare response had no result."))
+    }
+}
+
+#[async_trait]
+impl DnsProvider for Cloudflare {
+    /// Cloudflare applies changes to its name servers within seconds, so the record is
+    /// not waited for.
+    async fn set_challenge_record(&self, cluster_domain: &str, value: &str) -> Result<()> {
+        let _: DnsRecord = self
+            .send(self.client.post(self.records_url()).json(&json!({
+                "type": "TXT",
+                "name": challenge_record_name(cluster_domain),
+                "content": value,
+                "ttl": CHALLENGE_TTL,
+            })))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn remove_challenge_record(&self, cluster_domain: &str, value: &str) -> Result<()> {
+        let name = challenge_record_name(cluster_domain);
+        let records: Vec<DnsRecord> = self
+            .send(self.client.get(self.records_url()).query(&[
+                ("type", "TXT"),
+                ("name", name.as_str()),
+                ("content", value),
+            ]))
+            .await?;
+
+        for record in records {
+            let _: serde_json::Value = self
+                .send(
+                    self.client
+                        .delete(format!("{}/{}", self.records_url(), record.id)),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,412 @@
+//! Certificates for backends' custom domains: customer-owned hostnames, e.g.
+//! `app.customer.com`, which are CNAMEs of a backend's subdomain.
+//!
+//! Once a backend with a custom domain is ready, its agent checks the CNAME, routes the
+//! hostname to the backend, and asks the cluster's certificate refresher for a
+//! certificate. The refresher checks the CNAME again, since anything on NATS could ask,
+//! and orders a certificate for exactly the hostname. Wildcards don't cover it and its
+//! DNS isn't ours to set, so it is validated with an HTTP-01 challenge: the ACME server
+//! fetches the token from the hostname, i.e. from one of the cluster's proxies, which
+//! asks for the key authorization on the hostname's own challenge subject.
+//!
+//! Certificates are kept beside the cluster's, in `custom-domains/<hostname>/`, published
+//! to the cluster's proxies, and renewed like the cluster's for as long as their domain
+//! has been requested within `RETENTION`.
+
+use super::{
+    account::account_key, get_certificate, http_challenge::HttpChallengeResponder,
+    https_client::get_https_client, order_state::OrderState, renewal_time, write_certificate,
+    ChallengeSolver,
+};
+use crate::{
+    drone::cli::CertOptions,
+    keys::KeyCertPathPair,
+    messages::cert::{CustomDomainCertRequest, CustomDomainCertSync, CustomDomainCertificate},
+    nats::TypedNats,
+    types::BackendId,
+};
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use openssl::x509::X509;
+use reqwest::{header::ACCEPT, Client};
+use serde::Deserialize;
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+const CUSTOM_DOMAINS_DIR: &str = "custom-domains";
+
+/// File in a custom domain's directory whose modification time is when the domain was
+/// last requested.
+const REQUESTED_FILE: &str = "requested";
+
+/// How long after its domain was last requested a certificate stops being renewed.
+/// Agents request certificates as their backends become ready, so domains still in use
+/// are requested far more often than this.
+const RETENTION: Duration = Duration::from_secs(30 * 24 * 3600);
+
+const RENEWAL_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// DNS record type of CNAME records.
+const CNAME_TYPE: u16 = 5;
+
+/// Whether the host could be a backend's custom domain: a lowercase DNS name outside the
+/// cluster domains.
+pub fn is_custom_domain(host: &str, cluster_domains: &[String]) -> bool {
+    let is_dns_name = host.len() <= 253
+        && host.contains('.')
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        });
+
+    is_dns_name
+        && cluster_domains.iter().all(|cluster_domain| {
+            host != cluster_domain && !host.ends_with(&format!(".{}", cluster_domain))
+        })
+}
+
+/// A response of a DNS over HTTPS resolver's JSON API.
+#[derive(Deserialize)]
+struct DohResponse {
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DohAnswer>,
+}
+
+#[derive(Deserialize)]
+struct DohAnswer {
+    #[serde(rename = "type")]
+    record_type: u16,
+    data: String,
+}
+
+impl DohResponse {
+    /// Whether the answer includes a CNAME record pointing to the target.
+    fn has_cname(&self, target: &str) -> bool {
+        self.answer.iter().any(|answer| {
+            answer.record_type == CNAME_TYPE
+                && answer
+                    .data
+                    .trim_end_matches('.')
+                    .eq_ignore_ascii_case(target)
+        })
+    }
+}
+
+/// Checks that custom domains are CNAMEs of their backends' subdomains, through a DNS over
+/// HTTPS resolver, so that the drone's view isn't skewed by its own resolver's caching or
+/// split-horizon records.
+pub struct CnameVerifier {
+    client: Client,
+    resolver_url: String,
+    cluster_domain: String,
+}
+
+impl CnameVerifier {
+    pub fn new(resolver_url: &str, cluster_domain: &str) -> Self {
+        CnameVerifier {
+            client: Client::new(),
+            resolver_url: resolver_url.to_string(),
+            cluster_domain: cluster_domain.to_string(),
+        }
+    }
+
+    pub fn cluster_domain(&self) -> &str {
+        &self.cluster_domain
+    }
+
+    pub async fn verify(&self, hostname: &str, backend_id: &BackendId) -> Result<()> {
+        if !is_custom_domain(hostname, std::slice::from_ref(&self.cluster_domain)) {
+            return Err(anyhow!("{:?} can't be a custom domain.", hostname));
+        }
+
+        let response: DohResponse = self
+            .client
+            .get(&self.resolver_url)
+            .query(&[("name", hostname), ("type", "CNAME")])
+            .header(ACCEPT, "application/dns-json")
+            .send()
+            .await?
+            .error_for_status()
+            .context("Error looking up custom domain.")?
+            .json()
+            .await?;
+
+        let target = format!("{}.{}", backend_id.id(), self.cluster_domain);
+        if !response.has_cname(&target) {
+            return Err(anyhow!(
+                "Expected {} to be a CNAME of {}.",
+                hostname,
+                target
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// The certificates of custom domains held by the refresher, in their directories.
+struct CustomDomainDirectory {
+    path: PathBuf,
+}
+
+impl CustomDomainDirectory {
+    fn new(cluster_certificate_path: &Path) -> Self {
+        CustomDomainDirectory {
+            path: cluster_certificate_path.with_file_name(CUSTOM_DOMAINS_DIR),
+        }
+    }
+
+    fn key_paths(&self, hostname: &str) -> KeyCertPathPair {
+        KeyCertPathPair {
+            private_key_path: self.path.join(hostname).join("key.pem"),
+            certificate_path: self.path.join(hostname).join("cert.pem"),
+        }
+    }
+
+    fn hostnames(&self) -> Vec<String> {
+        let entries = match std::fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        entries
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .collect()
+    }
+
+    fn mark_requested(&self, hostname: &str) -> Result<()> {
+        let path = self.path.join(hostname);
+        std::fs::create_dir_all(&path)?;
+        std::fs::write(path.join(REQUESTED_FILE), Utc::now().to_rfc3339())?;
+
+        Ok(())
+    }
+
+    fn requested_at(&self, hostname: &str) -> Option<SystemTime> {
+        let path = self.path.join(hostname).join(REQUESTED_FILE);
+        std::fs::metadata(path).ok()?.modified().ok()
+    }
+
+    fn load(&self, hostname: &str) -> Option<CustomDomainCertificate> {
+        let key_paths = self.key_paths(hostname);
+        Some(CustomDomainCertificate {
+            hostname: hostname.to_string(),
+            certificate_pem: std::fs::read_to_string(key_paths.certificate_path).ok()?,
+            private_key_pem: std::fs::read_to_string(key_paths.private_key_path).ok()?,
+        })
+    }
+
+    fn remove(&self, hostname: &str) -> Result<()> {
+        std::fs::remove_dir_all(self.path.join(hostname))?;
+        Ok(())
+    }
+}
+
+fn needs_renewal(certificate: &CustomDomainCertificate) -> bool {
+    X509::from_pem(certificate.certificate_pem.as_bytes())
+        .ok()
+        .and_then(|cert| renewal_time(&cert))
+        .is_none_or(|renewal_time| renewal_time <= Utc::now())
+}
+
+/// Issues, renews, and publishes the certificates of the cluster's custom domains.
+struct CustomDomainIssuer<'a> {
+    cert_options: &'a CertOptions,
+    nats: TypedNats,
+    client: Client,
+    directory: CustomDomainDirectory,
+}
+
+impl<'a> CustomDomainIssuer<'a> {
+    async fn issue(&self, hostname: &str) -> Result<CustomDomainCertificate> {
+        tracing::info!(%hostname, "Issuing certificate for custom domain.");
+        let store = match &self.cert_options.store {
+            Some(store) => Some(store.store(Some(&self.nats)).await?),
+            None => None,
+        };
+        let account_key =
+            account_key(&self.cert_options.account_key_path, store.as_deref()).await?;
+        let key_paths = self.directory.key_paths(hostname);
+        let mut state = OrderState::resume(
+            OrderState::path(&key_paths.certificate_path),
+            hostname,
+            self.cert_options.key_type,
+        )
+        .await?;
+
+        let responder = HttpChallengeResponder::start(&self.nats, hostname).await?;
+        let (pkey, chain) = get_certificate(
+            hostname,
+            ChallengeSolver::Http(&responder),
+            account_key,
+            &self.cert_options.acme_server_url,
+            self.cert_options.acme_eab.as_ref(),
+            &self.client,
+            &mut state,
+        )
+        .await?;
+
+        let (certificate_pem, private_key_pem) = write_certificate(&key_paths, &pkey, &chain)?;
+        state.finish().await?;
+
+        Ok(CustomDomainCertificate {
+            hostname: hostname.to_string(),
+            certificate_pem,
+            private_key_pem,
+        })
+    }
+
+    async fn publish(&self, certificate: &CustomDomainCertificate) -> Result<()> {
+        self.nats
+            .publish(
+                &CustomDomainCertificate::subject(&self.cert_options.cluster_domain),
+                certificate,
+            )
+            .await
+    }
+
+    /// Publish the certificate of the requested domain, issuing it first unless there is
+    /// one which isn't due for renewal.
+    async fn handle_request(
+        &self,
+        verifier: &CnameVerifier,
+        request: &CustomDomainCertRequest,
+    ) -> Result<()> {
+        verifier
+            .verify(&request.hostname, &request.backend_id)
+            .await?;
+        self.directory.mark_requested(&request.hostname)?;
+
+        let certificate = match self.directory.load(&request.hostname) {
+            Some(certificate) if !needs_renewal(&certificate) => certificate,
+            _ => self.issue(&request.hostname).await?,
+        };
+        self.publish(&certificate).await
+    }
+
+    /// Renew the certificates which are due, and forget domains no longer requested.
+    async fn renew(&self) {
+        for hostname in self.directory.hostnames() {
+            let expired = match self.directory.requested_at(&hostname) {
+                Some(requested_at) => requested_at
+                    .elapsed()
+                    .is_ok_and(|elapsed| elapsed > RETENTION),
+                None => true,
+            };
+            if expired {
+                tracing::info!(%hostname, "Custom domain is no longer requested; removing its certificate.");
+                if let Err(error) = self.directory.remove(&hostname) {
+                    tracing::warn!(?error, %hostname, "Error removing custom domain certificate.");
+                }
+                continue;
+            }
+
+            if self
+                .directory
+                .load(&hostname)
+                .is_none_or(|certificate| needs_renewal(&certificate))
+            {
+                let result = match self.issue(&hostname).await {
+                    Ok(certificate) => self.publish(&certificate).await,
+                    Err(error) => Err(error),
+                };
+                if let Err(error) = result {
+                    tracing::warn!(?error, %hostname, "Error renewing custom domain certificate, will try again.");
+                }
+            }
+        }
+    }
+
+    async fn publish_all(&self) {
+        for hostname in self.directory.hostnames() {
+            if let Some(certificate) = self.directory.load(&hostname) {
+                if let Err(error) = self.publish(&certificate).await {
+                    tracing::warn!(?error, %hostname, "Error publishing custom domain certificate.");
+                }
+            }
+        }
+    }
+}
+
+/// Answer requests for certificates of the cluster's custom domains, checking their
+/// CNAMEs with the DNS over HTTPS resolver, and keep the certificates renewed.
+pub async fn custom_domain_loop(cert_options: &CertOptions, resolver_url: &str) -> Result<()> {
+    let cluster_domain = &cert_options.cluster_domain;
+    let issuer = CustomDomainIssuer {
+        cert_options,
+        nats: cert_options.nats.connection().await?,
+        client: get_https_client()?,
+        directory: CustomDomainDirectory::new(&cert_options.key_paths.certificate_path),
+    };
+    let verifier = CnameVerifier::new(resolver_url, cluster_domain);
+
+    let mut requests = issuer
+        .nats
+        .subscribe(&CustomDomainCertRequest::subject(cluster_domain))
+        .await?;
+    let mut syncs = issuer
+        .nats
+        .subscribe(&CustomDomainCertSync::subject(cluster_domain))
+        .await?;
+    let mut renewal_check = tokio::time::interval(RENEWAL_CHECK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            request = requests.next() => match request {
+                Ok(Some(request)) => {
+                    if let Err(error) = issuer.handle_request(&verifier, &request.value).await {
+                        tracing::warn!(?error, hostname = %request.value.hostname, "Error providing certificate for custom domain.");
+                    }
+                }
+                Ok(None) => return Err(anyhow!("Custom domain request subscription closed.")),
+                Err(error) => tracing::error!(?error, "Non-fatal error when listening for custom domain requests."),
+            },
+            sync = syncs.next() => match sync {
+                Ok(Some(_)) => issuer.publish_all().await,
+                Ok(None) => return Err(anyhow!("Custom domain sync subscription closed.")),
+                Err(error) => tracing::error!(?error, "Non-fatal error when listening for custom domain syncs."),
+            },
+            _ = renewal_check.tick() => issuer.renew().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_custom_domain() {
+        let cluster_domains = ["spawner.test".to_string()];
+        assert!(is_custom_domain("app.customer.com", &cluster_domains));
+        assert!(!is_custom_domain("spawner.test", &cluster_domains));
+        assert!(!is_custom_domain("backend.spawner.test", &cluster_domains));
+        assert!(!is_custom_domain("localhost", &cluster_domains));
+        assert!(!is_custom_domain("App.customer.com", &cluster_domains));
+        assert!(!is_custom_domain("app.customer.com:443", &cluster_domains));
+        assert!(!is_custom_domain("app..customer.com", &cluster_domains));
+    }
+
+    #[test]
+    fn test_has_cname() {
+        let response: DohResponse = serde_json::from_str(
+            r#"{
+                "Status": 0,
+                "Answer": [
+                    {"name": "app.customer.com.", "type": 5, "TTL": 300, "data": "Backend.spawner.test."},
+                    {"name": "backend.spawner.test.", "type": 1, "TTL": 300, "data": "10.0.0.1"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert!(response.has_cname("backend.spawner.test"));
+        assert!(!response.has_cname("other.spawner.test"));
+
+        let empty: DohResponse = serde_json::from_str(r#"{"Status": 3}"#).unwrap();
+        assert!(!empty.has_cname("backend.spawner.test"));
+    }
+}
@@ -0,0 +1,185 @@
+//! The DNS providers through which the certificate refresher answers DNS-01 challenges,
+//! by setting a TXT record at `_acme-challenge.<cluster domain>`.
+//!
+//! By default the platform sets the record, at the refresher's request over NATS. A
+//! cluster whose domain is hosted on Route53, Cloudflare, or Google Cloud DNS can instead
+//! have the refresher set it with the provider's API directly, authenticating as each
+//! provider's own tools do.
+
+use super::{cloudflare::Cloudflare, google_dns::GoogleCloudDns, route53::Route53};
+use crate::{messages::cert::SetAcmeDnsRecord, nats::TypedNats};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use reqwest::Client;
+use std::str::FromStr;
+
+/// TTL of challenge records, short so that a retried challenge isn't answered with a
+/// stale value.
+pub const CHALLENGE_TTL: u32 = 60;
+
+/// The name of the TXT record answering a cluster domain's DNS-01 challenges. Challenges
+/// for the wildcard `*.<cluster domain>` are answered at the same name.
+pub fn challenge_record_name(cluster_domain: &str) -> String {
+    format!("_acme-challenge.{}", cluster_domain)
+}
+
+/// Sets and removes the TXT records which answer DNS-01 challenges.
+#[async_trait]
+pub trait DnsProvider: Send + Sync {
+    /// Set the cluster domain's challenge record to the value, returning once the
+    /// provider has applied the change.
+    async fn set_challenge_record(&self, cluster_domain: &str, value: &str) -> Result<()>;
+
+    /// Remove the challenge record, once the challenge has been validated.
+    async fn remove_challenge_record(&self, cluster_domain: &str, value: &str) -> Result<()>;
+}
+
+/// Which DNS provider the certificate refresher uses, configured at the drone level.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub enum DnsProviderKind {
+    /// Ask the platform to set the record over NATS.
+    #[default]
+    Nats,
+
+    /// Route53, with credentials from the `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`,
+    /// and (optionally) `AWS_SESSION_TOKEN` environment variables.
+    Route53 { hosted_zone_id: String },
+
+    /// Cloudflare, with an API token from the `CLOUDFLARE_API_TOKEN` environment variable.
+    Cloudflare { zone_id: String },
+
+    /// Google Cloud DNS, as the service account whose key file is named by the
+    /// `GOOGLE_APPLICATION_CREDENTIALS` environment variable, or else as the instance's
+    /// service account.
+    GoogleCloudDns {
+        project: String,
+        managed_zone: String,
+    },
+}
+
+impl FromStr for DnsProviderKind {
+    type Err = anyhow::Error;
+
+    /// Parses `nats`, `route53:<hosted zone ID>`, `cloudflare:<zone ID>`, or
+    /// `google:<project>/<managed zone>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            anyhow!(
+                "Expected DNS provider to be nats, route53:<hosted zone ID>, cloudflare:<zone ID>, or google:<project>/<managed zone>, got {:?}.",
+                s
+            )
+        };
+
+        let (kind, zone) = match s.split_once(':') {
+            Some((kind, zone)) if !zone.is_empty() => (kind, zone),
+            _ if s == "nats" => return Ok(DnsProviderKind::Nats),
+            _ => return Err(invalid()),
+        };
+        match kind {
+            "route53" => Ok(DnsProviderKind::Route53 {
+                hosted_zone_id: zone.to_string(),
+            }),
+            "cloudflare" => Ok(DnsProviderKind::Cloudflare {
+                zone_id: zone.to_string(),
+            }),
+            "google" => {
+                let (project, managed_zone) = zone
+                    .split_once('/')
+                    .filter(|(project, managed_zone)| {
+                        !project.is_empty() && !managed_zone.is_empty()
+                    })
+                    .ok_or_else(invalid)?;
+                Ok(DnsProviderKind::GoogleCloudDns {
+                    project: project.to_string(),
+                    managed_zone: managed_zone.to_string(),
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl DnsProviderKind {
+    /// The provider, with its credentials read from the environment.
+    pub fn provider(&self, nats: &TypedNats, client: &Client) -> Result<Box<dyn DnsProvider>> {
+        Ok(match self {
+            DnsProviderKind::Nats => Box::new(NatsDnsProvider { nats: nats.clone() }),
+            DnsProviderKind::Route53 { hosted_zone_id } => {
+                Box::new(Route53::from_env(hosted_zone_id, client)?)
+            }
+            DnsProviderKind::Cloudflare { zone_id } => {
+                Box::new(Cloudflare::from_env(zone_id, client)?)
+            }
+            DnsProviderKind::GoogleCloudDns {
+                project,
+                managed_zone,
+            } => Box::new(GoogleCloudDns::from_env(project, managed_zone, client)?),
+        })
+    }
+}
+
+/// Asks the platform to set challenge records over NATS.
+struct NatsDnsProvider {
+    nats: TypedNats,
+}
+
+#[async_trait]
+impl DnsProvider for NatsDnsProvider {
+    async fn set_challenge_record(&self, cluster_domain: &str, value: &str) -> Result<()> {
+        let result = self
+            .nats
+            .request(
+                &SetAcmeDnsRecord::subject(),
+                &SetAcmeDnsRecord {
+                    cluster: cluster_domain.to_string(),
+                    value: value.to_string(),
+                },
+            )
+            .await?;
+
+        if !result {
+            return Err(anyhow!("Platform rejected TXT record."));
+        }
+
+        Ok(())
+    }
+
+    /// The platform replaces the record at the next challenge, so it is left in place.
+    async fn remove_challenge_record(&self, _cluster_domain: &str, _value: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_dns_provider() {
+        assert_eq!(DnsProviderKind::Nats, "nats".parse().unwrap());
+        assert_eq!(
+            DnsProviderKind::Route53 {
+                hosted_zone_id: "Z0123456789ABC".to_string()
+            },
+            "route53:Z0123456789ABC".parse().unwrap()
+        );
+        assert_eq!(
+            DnsProviderKind::Cloudflare {
+                zone_id: "023e105f4ecef8ad9ca31a8372d0c353".to_string()
+            },
+            "cloudflare:023e105f4ecef8ad9ca31a8372d0c353"
+                .parse()
+                .unwrap()
+        );
+        assert_eq!(
+            DnsProviderKind::GoogleCloudDns {
+                project: "my-project".to_string(),
+                managed_zone: "spawner-zone".to_string()
+            },
+            "google:my-project/spawner-zone".parse().unwrap()
+        );
+        assert!("route53".parse::<DnsProviderKind>().is_err());
+        assert!("google:my-project".parse::<DnsProviderKind>().is_err());
+        assert!("powerdns:zone".parse::<DnsProviderKind>().is_err());
+    }
+}
@@ -0,0 +1,266 @@
+//! Challenge records set through the Google Cloud DNS API.
+//!
+//! Access tokens are obtained as the service account whose key file is named by
+//! `GOOGLE_APPLICATION_CREDENTIALS`, by signing a JWT with its key, or else from the
+//! metadata server of the instance the drone runs on.
+
+use super::dns::{challenge_record_name, DnsProvider, CHALLENGE_TTL};
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{env::var, path::PathBuf, time::Duration};
+
+const API_URL: &str = "https://dns.googleapis.com/dns/v1";
+const SCOPE: &str = "https://www.googleapis.com/auth/ndev.clouddns.readwrite";
+const METADATA_TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+const JWT_BEARER_GRANT: &str = "urn:ietf:params:oauth:grant-type:jwt-bearer";
+
+/// How often, and how many times, a change is checked for having been applied.
+const CHANGE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const CHANGE_POLL_ATTEMPTS: u32 = 36;
+
+/// The fields of a service account key file which are needed to obtain tokens.
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct Change {
+    id: String,
+    status: String,
+}
+
+fn encode_segment(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// A JWT asserting the service account's identity, signed with its key, to exchange for
+/// an access token.
+fn service_account_assertion(key: &ServiceAccountKey, now: i64) -> Result<String> {
+    let header = json!({"alg": "RS256", "typ": "JWT"});
+    let claims = json!({
+        "iss": key.client_email,
+        "scope": SCOPE,
+        "aud": key.token_uri,
+        "iat": now,
+        "exp": now + 3600,
+    });
+    let signing_input = format!(
+        "{}.{}",
+        encode_segment(header.to_string().as_bytes()),
+        encode_segment(claims.to_string().as_bytes())
+    );
+
+    let private_key = PKey::private_key_from_pem(key.private_key.as_bytes())
+        .context("Error reading service account private key.")?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &private_key)?;
+    signer.update(signing_input.as_bytes())?;
+    let signature = signer.sign_to_vec()?;
+
+    Ok(format!("{}.{}", signing_input, encode_segment(&signature)))
+}
+
+pub struct GoogleCloudDns {
+    project: String,
+    managed_zone: String,
+
+    /// Key file of the service account to act as, or None to act as the instance's.
+    credentials_path: Option<PathBuf>,
+    client: Client,
+}
+
+impl GoogleCloudDns {
+    pub fn from_env(project: &str, managed_zone: &str, client: &Client) -> Result<Self> {
+        Ok(GoogleCloudDns {
+            project: project.to_string(),
+            managed_zone: managed_zone.to_string(),
+            credentials_path: var("GOOGLE_APPLICATION_CREDENTIALS")
+                .ok()
+                .map(PathBuf::from),
+            client: client.clone(),
+        })
+    }
+
+    async fn access_token(&self) -> Result<String> {
+        let request = match &self.credentials_path {
+            Some(path) => {
+                let key: ServiceAccountKey = serde_json::from_slice(
+                    &tokio::fs::read(path)
+                        .await
+                        .with_context(|| format!("Error reading credentials {:?}.", path))?,
+                )
+                .with_context(|| format!("Expected {:?} to be a service account key.", path))?;
+                let assertion = service_account_assertion(&key, Utc::now().timestamp())?;
+                self.client.post(&key.token_uri).form(&[
+                    ("grant_type", JWT_BEARER_GRANT),
+                    ("assertion", assertion.as_str()),
+                ])
+            }
+            None => self
+                .client
+                .get(METADATA_TOKEN_URL)
+                .header("Metadata-Flavor", "Google"),
+        };
+
+        let response: TokenResponse = request
+            .send()
+            .await?
+            .error_for_status()
+            .context("Error obtaining Google Cloud access token.")?
+            .json()
+            .await?;
+
+        Ok(response.access_token)
+    }
+
+    fn zone_url(&self) -> String {
+        format!(
+            "{}/projects/{}/managedZones/{}",
+            API_URL, self.project, self.managed_zone
+        )
+    }
+
+    /// The TXT record set at the name, if there is one.
+    async fn record_set(&self, token: &str, name: &str) -> Result<Option<Value>> {
+        let mut response: Value = self
+            .client
+            .get(format!("{}/rrsets", self.zone_url()))
+            .query(&[("name", name), ("type", "TXT")])
+            .bearer_auth(token)
+            .send()
+            .await?
+            .error_for_status()
+            .context("Error listing Google Cloud DNS records.")?
+            .json()
+            .await?;
+
+        Ok(response["rrsets"]
+            .as_array_mut()
+            .and_then(|record_sets| record_sets.pop()))
+    }
+
+    /// Replace the TXT record set at the challenge record's name with the given one, and
+    /// wait for the change to be applied.
+    async fn replace_record_set(&self, cluster_domain: &str, additions: Vec<Value>) -> Result<()> {
+        let token = self.access_token().await?;
+        // Cloud DNS names are fully qualified.
+        let name = format!("{}.", challenge_record_name(cluster_domain));
+        let deletions: Vec<Value> = self.record_set(&token, &name).await?.into_iter().collect();
+        if deletions.is_empty() && additions.is_empty() {
+            return Ok(());
+        }
+
+        let change: Change = self
+            .client
+            .post(format!("{}/changes", self.zone_url()))
+            .bearer_auth(&token)
+            .json(&json!({
+                "additions": additions
+                    .into_iter()
+                    .map(|mut addition| {
+                        addition["name"] = json!(name);
+                        addition
+                    })
+                    .collect::<Vec<_>>(),
+                "deletions": deletions,
+            }))
+            .send()
+            .await?
+            .error_for_status()
+            .context("Error changing Google Cloud DNS records.")?
+            .json()
+            .await?;
+
+        let mut status = change.status;
+        for _ in 0..CHANGE_POLL_ATTEMPTS {
+            if status == "done" {
+                return Ok(());
+            }
+            tokio::time::sleep(CHANGE_POLL_INTERVAL).await;
+            let polled: Change = self
+                .client
+                .get(format!("{}/changes/{}", self.zone_url(), change.id))
+                .bearer_auth(&token)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            status = polled.status;
+        }
+
+        Err(anyhow!(
+            "Timed out waiting for Google Cloud DNS change {}.",
+            change.id
+        ))
+    }
+}
+
+#[async_trait]
+impl DnsProvider for GoogleCloudDns {
+    async fn set_challenge_record(&self, cluster_domain: &str, value: &str) -> Result<()> {
+        self.replace_record_set(
+            cluster_domain,
+            vec![json!({
+                "type": "TXT",
+                "ttl": CHALLENGE_TTL,
+                "rrdatas": [format!("\"{}\"", value)],
+            })],
+        )
+        .await
+    }
+
+    async fn remove_challenge_record(&self, cluster_domain: &str, _value: &str) -> Result<()> {
+        self.replace_record_set(cluster_domain, Vec::new()).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use openssl::{rsa::Rsa, sign::Verifier};
+
+    #[test]
+    fn test_service_account_assertion() {
+        let private_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let key = ServiceAccountKey {
+            client_email: "cert-refresh@my-project.iam.gserviceaccount.com".to_string(),
+            private_key: String::from_utf8(private_key.private_key_to_pem_pkcs8().unwrap())
+                .unwrap(),
+            token_uri: "https://oauth2.googleapis.com/token".to_string(),
+        };
+
+        let assertion = service_account_assertion(&key, 1_600_000_000).unwrap();
+        let (signing_input, signature) = assertion.rsplit_once('.').unwrap();
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &private_key).unwrap();
+        verifier.update(signing_input.as_bytes()).unwrap();
+        assert!(verifier
+            .verify(&base64::decode_config(signature, base64::URL_SAFE_NO_PAD).unwrap())
+            .unwrap());
+
+        let claims: Value = serde_json::from_slice(
+            &base64::decode_config(
+                signing_input.split_once('.').unwrap().1,
+                base64::URL_SAFE_NO_PAD,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(key.client_email, claims["iss"]);
+        assert_eq!(SCOPE, claims["scope"]);
+        assert_eq!(1_600_003_600, claims["exp"]);
+    }
+}
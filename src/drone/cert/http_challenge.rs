@@ -0,0 +1,90 @@
+//! Answers to HTTP-01 challenges, for clusters whose DNS can't be delegated to spawner.
+//!
+//! The ACME server fetches `http://<cluster domain>/.well-known/acme-challenge/<token>`,
+//! which reaches one of the cluster's proxies rather than the drone refreshing the
+//! certificate. The proxy asks for the key authorization of the token over NATS, and the
+//! refresher responds while the challenge is pending.
+
+use crate::{messages::cert::AcmeHttpChallenge, nats::TypedNats};
+use anyhow::Result;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::task::JoinHandle;
+
+/// The key authorizations of pending challenges, by token.
+type Pending = Arc<Mutex<HashMap<String, String>>>;
+
+/// Responds to proxies' requests for the key authorizations of a cluster's pending
+/// HTTP-01 challenges, until dropped.
+pub struct HttpChallengeResponder {
+    pending: Pending,
+    task: JoinHandle<()>,
+}
+
+impl HttpChallengeResponder {
+    pub async fn start(nats: &TypedNats, cluster_domain: &str) -> Result<Self> {
+        let mut sub = nats
+            .subscribe(&AcmeHttpChallenge::subject(cluster_domain))
+            .await?;
+        let pending = Pending::default();
+
+        let task = tokio::spawn({
+            let pending = pending.clone();
+            async move {
+                loop {
+                    match sub.next().await {
+                        Ok(Some(req)) => {
+                            let key_authorization = pending
+                                .lock()
+                                .expect("Challenge lock was poisoned.")
+                                .get(&req.value.token)
+                                .cloned();
+                            // Other refreshers of the cluster may have the challenge
+                            // pending, so unknown tokens are left for them to answer.
+                            if let Some(key_authorization) = key_authorization {
+                                if let Err(error) = req.respond(&key_authorization).await {
+                                    tracing::warn!(?error, "Error answering HTTP-01 challenge.");
+                                }
+                            }
+                        }
+                        Ok(None) => {
+                            tracing::warn!("HTTP-01 challenge subscription closed.");
+                            return;
+                        }
+                        Err(error) => {
+                            tracing::error!(
+                                ?error,
+                                "Non-fatal error when listening for HTTP-01 challenges."
+                            )
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(HttpChallengeResponder { pending, task })
+    }
+
+    /// Answer the challenge with the token, until it is removed.
+    pub fn add(&self, token: &str, key_authorization: String) {
+        self.pending
+            .lock()
+            .expect("Challenge lock was poisoned.")
+            .insert(token.to_string(), key_authorization);
+    }
+
+    pub fn remove(&self, token: &str) {
+        self.pending
+            .lock()
+            .expect("Challenge lock was poisoned.")
+            .remove(token);
+    }
+}
+
+impl Drop for HttpChallengeResponder {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
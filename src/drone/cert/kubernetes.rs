@@ -0,0 +1,243 @@
+//! Certificates stored as Kubernetes TLS Secrets, through the API server of the cluster
+//! the drone runs in, as its service account.
+
+use super::store::CertStore;
+use crate::messages::cert::{AlternateCertificate, ClusterCertificate, KeyType};
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, env::var, path::Path};
+
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+const TLS_SECRET_TYPE: &str = "kubernetes.io/tls";
+const OPAQUE_SECRET_TYPE: &str = "Opaque";
+const CERTIFICATE_KEY: &str = "tls.crt";
+const PRIVATE_KEY_KEY: &str = "tls.key";
+
+/// Name of the Secret holding the ACME account key, under `PRIVATE_KEY_KEY`.
+const ACCOUNT_KEY_SECRET: &str = "spawner-acme-account";
+
+fn secret_name(cluster_domain: &str) -> String {
+    format!("spawner-cert-{}", cluster_domain)
+}
+
+/// Keys of the certificate and private key with an alternate key type, stored in the
+/// primary certificate's Secret.
+fn alternate_keys(key_type: KeyType) -> (String, String) {
+    (
+        format!("tls-{}.crt", key_type),
+        format!("tls-{}.key", key_type),
+    )
+}
+
+#[derive(Serialize, Deserialize)]
+struct ObjectMeta {
+    name: String,
+    #[serde(rename = "resourceVersion", skip_serializing_if = "Option::is_none")]
+    resource_version: Option<String>,
+}
+
+/// The fields of a Secret which are read and written. Values of `data` are base64.
+#[derive(Serialize, Deserialize)]
+struct Secret {
+    #[serde(rename = "apiVersion")]
+    api_version: String,
+    kind: String,
+    metadata: ObjectMeta,
+    #[serde(rename = "type")]
+    secret_type: String,
+    #[serde(default)]
+    data: BTreeMap<String, String>,
+}
+
+impl Secret {
+    fn new(name: &str, secret_type: &str, data: &[(&str, &str)]) -> Self {
+        Secret {
+            api_version: "v1".to_string(),
+            kind: "Secret".to_string(),
+            metadata: ObjectMeta {
+                name: name.to_string(),
+                resource_version: None,
+            },
+            secret_type: secret_type.to_string(),
+            data: data
+                .iter()
+                .map(|(key, value)| (key.to_string(), base64::encode(value)))
+                .collect(),
+        }
+    }
+
+    fn value(&self, key: &str) -> Result<String> {
+        let value = self
+            .data
+            .get(key)
+            .ok_or_else(|| anyhow!("Secret {} has no {}.", self.metadata.name, key))?;
+        Ok(String::from_utf8(base64::decode(value)?)?)
+    }
+}
+
+pub struct KubernetesSecrets {
+    /// URL of the namespace's Secrets.
+    secrets_url: String,
+    client: Client,
+}
+
+impl KubernetesSecrets {
+    /// Connect to the API server as the pod's service account, with the namespace the pod
+    /// runs in unless another is given.
+    pub fn in_cluster(namespace: Option<&str>) -> Result<Self> {
+        let host = var("KUBERNETES_SERVICE_HOST")
+            .context("Expected KUBERNETES_SERVICE_HOST; is the drone running in Kubernetes?")?;
+        let port = var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+        let service_account = Path::new(SERVICE_ACCOUNT_DIR);
+        let namespace = match namespace {
+            Some(namespace) => namespace.to_string(),
+            None => std::fs::read_to_string(service_account.join("namespace"))
+                .context("Error reading service account namespace.")?
+                .trim()
+                .to_string(),
+        };
+        let ca = reqwest::Certificate::from_pem(
+            &std::fs::read(service_account.join("ca.crt"))
+                .context("Error reading service account CA.")?,
+        )?;
+
+        Ok(KubernetesSecrets {
+            secrets_url: format!(
+                "https://{}:{}/api/v1/namespaces/{}/secrets",
+                host, port, namespace
+            ),
+            client: Client::builder()
+                .add_root_certificate(ca)
+                .build()
+                .context("Error building Kubernetes client.")?,
+        })
+    }
+
+    /// The service account's token, read for each request because Kubernetes rotates it.
+    async fn token(&self) -> Result<String> {
+        let token = tokio::fs::read_to_string(Path::new(SERVICE_ACCOUNT_DIR).join("token"))
+            .await
+            .context("Error reading service account token.")?;
+        Ok(token.trim().to_string())
+    }
+
+    async fn get(&self, name: &str) -> Result<Option<Secret>> {
+        let response = self
+            .client
+            .get(format!("{}/{}", self.secrets_url, name))
+            .bearer_auth(self.token().await?)
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        Ok(Some(
+            response
+                .error_for_status()
+                .context("Error getting Kubernetes Secret.")?
+                .json()
+                .await?,
+        ))
+    }
+
+    /// Create the Secret, or replace it if it exists.
+    async fn put(&self, mut secret: Secret) -> Result<()> {
+        let name = secret.metadata.name.clone();
+        let request = match self.get(&name).await? {
+            // Replacing with the version read fails if the Secret has changed since.
+            Some(existing) => {
+                secret.metadata.resource_version = existing.metadata.resource_version;
+                self.client
+                    .put(format!("{}/{}", self.secrets_url, name))
+                    .json(&secret)
+            }
+            None => self.client.post(&self.secrets_url).json(&secret),
+        };
+
+        request
+            .bearer_auth(self.token().await?)
+            .send()
+            .await?
+            .error_for_status()
+            .context("Error saving Kubernetes Secret.")?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CertStore for KubernetesSecrets {
+    async fn load(&self, cluster_domain: &str) -> Result<Option<ClusterCertificate>> {
+        let secret = match self.get(&secret_name(cluster_domain)).await? {
+            Some(secret) => secret,
+            None => return Ok(None),
+        };
+
+        let mut alternates = Vec::new();
+        for key_type in KeyType::ALL {
+            let (certificate_key, private_key_key) = alternate_keys(key_type);
+            if secret.data.contains_key(&certificate_key) {
+                alternates.push(AlternateCertificate {
+                    key_type,
+                    certificate_pem: secret.value(&certificate_key)?,
+                    private_key_pem: secret.value(&private_key_key)?,
+                });
+            }
+        }
+
+        Ok(Some(ClusterCertificate {
+            cluster: cluster_domain.to_string(),
+            certificate_pem: secret.value(CERTIFICATE_KEY)?,
+            private_key_pem: secret.value(PRIVATE_KEY_KEY)?,
+            alternates,
+        }))
+    }
+
+    async fn save(&self, certificate: &ClusterCertificate) -> Result<()> {
+        let mut data = vec![
+            (
+                CERTIFICATE_KEY.to_string(),
+                certificate.certificate_pem.as_str(),
+            ),
+            (
+                PRIVATE_KEY_KEY.to_string(),
+                certificate.private_key_pem.as_str(),
+            ),
+        ];
+        for alternate in &certificate.alternates {
+            let (certificate_key, private_key_key) = alternate_keys(alternate.key_type);
+            data.push((certificate_key, alternate.certificate_pem.as_str()));
+            data.push((private_key_key, alternate.private_key_pem.as_str()));
+        }
+        let data: Vec<(&str, &str)> = data
+            .iter()
+            .map(|(key, value)| (key.as_str(), *value))
+            .collect();
+
+        self.put(Secret::new(
+            &secret_name(&certificate.cluster),
+            TLS_SECRET_TYPE,
+            &data,
+        ))
+        .await
+    }
+
+    async fn load_account_key(&self) -> Result<Option<String>> {
+        match self.get(ACCOUNT_KEY_SECRET).await? {
+            Some(secret) => Ok(Some(secret.value(PRIVATE_KEY_KEY)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn save_account_key(&self, private_key_pem: &str) -> Result<()> {
+        self.put(Secret::new(
+            ACCOUNT_KEY_SECRET,
+            OPAQUE_SECRET_TYPE,
+            &[(PRIVATE_KEY_KEY, private_key_pem)],
+        ))
+        .await
+    }
+}
@@ -1,78 +1,234 @@
-use self::https_client::get_https_client;
+use self::{
+    account::{account_key, register_account, ExternalAccountBinding},
+    custom_domain::custom_domain_loop,
+    dns::DnsProvider,
+    http_challenge::HttpChallengeResponder,
+    https_client::get_https_client,
+    order_state::OrderState,
+    store::restore_cert,
+};
 use super::cli::CertOptions;
-use crate::{messages::cert::SetAcmeDnsRecord, nats::TypedNats};
+use crate::keys::KeyCertPathPair;
+use crate::messages::cert::{
+    AlternateCertificate, CertificateExpiryAlert, CertificateRenewal, ClusterCertificate, KeyType,
+};
+use crate::retry::RetryPolicy;
 use acme2::{
-    gen_rsa_private_key, AccountBuilder, AuthorizationStatus, ChallengeStatus, Csr,
-    DirectoryBuilder, OrderBuilder, OrderStatus,
+    AccountBuilder, AuthorizationStatus, ChallengeStatus, Csr, DirectoryBuilder, OrderBuilder,
+    OrderStatus,
 };
 use anyhow::{anyhow, Context, Result};
 use chrono::{DateTime, NaiveDateTime, Utc};
 use openssl::{
-    asn1::Asn1Time,
+    asn1::{Asn1Time, Asn1TimeRef},
     pkey::{PKey, Private},
+    rand::rand_bytes,
+    sha::Sha256,
     x509::X509,
 };
 use reqwest::Client;
-use std::{path::Path, time::Duration};
+use std::{path::Path, str::FromStr, sync::OnceLock, time::Duration};
 
+pub mod account;
+mod cloudflare;
+pub mod custom_domain;
+pub mod dns;
+mod google_dns;
+mod http_challenge;
 mod https_client;
+mod kubernetes;
+mod order_state;
+mod route53;
+pub mod store;
 
 const DNS_01: &str = "dns-01";
-const REFRESH_MARGIN: Duration = Duration::from_secs(3600 * 24 * 15);
+const HTTP_01: &str = "http-01";
 const MAX_SLEEP: Duration = Duration::from_secs(3600);
 
+/// Certificates are renewed at a random point between these fractions of their lifetime.
+const RENEW_AFTER_MIN: f64 = 0.6;
+const RENEW_AFTER_MAX: f64 = 0.75;
+
+/// How long to wait before retrying after consecutive failures to renew.
+const RENEWAL_RETRY: RetryPolicy = RetryPolicy {
+    max_attempts: u32::MAX,
+    initial_delay: Duration::from_secs(60),
+    max_delay: Duration::from_secs(3600),
+};
+const ACME_CONTACT: &str = "mailto:paul@driftingin.space";
+
+/// Directory URLs of well-known ACME servers, by the names `--acme-server` accepts.
+const KNOWN_ACME_SERVERS: &[(&str, &str)] = &[
+    (
+        "letsencrypt",
+        "https://acme-v02.api.letsencrypt.org/directory",
+    ),
+    (
+        "letsencrypt-staging",
+        "https://acme-staging-v02.api.letsencrypt.org/directory",
+    ),
+    ("zerossl", "https://acme.zerossl.com/v2/DV90"),
+];
+
+/// The directory URL of the ACME server, given its URL or the name of a well-known one.
+pub fn acme_directory_url(server: &str) -> String {
+    KNOWN_ACME_SERVERS
+        .iter()
+        .find(|(name, _)| *name == server)
+        .map_or(server, |(_, url)| url)
+        .to_string()
+}
+
+/// JetStream stream keeping the latest certificate of each cluster, for proxies which
+/// start after it was issued.
+pub const CERTIFICATE_STREAM: &str = "cluster_certificates";
+
+/// The type of ACME challenge the certificate refresher answers.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum ChallengeType {
+    /// Set a TXT record through the DNS provider, for a wildcard certificate covering every
+    /// backend's subdomain.
+    #[default]
+    Dns01,
+
+    /// Have the cluster's proxies serve the key authorization over HTTP. Wildcards can't
+    /// be validated this way, so the certificate only covers the cluster domain itself,
+    /// for clusters which route requests by path.
+    Http01,
+}
+
+impl FromStr for ChallengeType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            DNS_01 => Ok(ChallengeType::Dns01),
+            HTTP_01 => Ok(ChallengeType::Http01),
+            _ => Err(anyhow!(
+                "Expected ACME challenge to be dns-01 or http-01, got {:?}.",
+                s
+            )),
+        }
+    }
+}
+
+/// How the challenges of an order are answered.
+#[derive(Clone, Copy)]
+pub enum ChallengeSolver<'a> {
+    Dns(&'a dyn DnsProvider),
+    Http(&'a HttpChallengeResponder),
+}
+
 pub async fn get_certificate(
     cluster_domain: &str,
-    nats: &TypedNats,
+    solver: ChallengeSolver<'_>,
+    account_key: PKey<Private>,
     acme_server_url: &str,
+    eab: Option<&ExternalAccountBinding>,
     client: &Client,
-) -> Result<(PKey<Private>, X509)> {
+    state: &mut OrderState,
+) -> Result<(PKey<Private>, Vec<X509>)> {
     let _span = tracing::info_span!("Getting certificate", %cluster_domain);
     let _span_guard = _span.enter();
 
+    if let ChallengeSolver::Dns(dns) = solver {
+        for value in state.dns_records().to_vec() {
+            tracing::info!("Removing TXT record left by interrupted order.");
+            if let Err(error) = dns.remove_challenge_record(cluster_domain, &value).await {
+                tracing::warn!(?error, "Error removing TXT record.");
+            }
+            state.remove_dns_record(&value).await?;
+        }
+    }
+
     let dir = DirectoryBuilder::new(acme_server_url.to_string())
         .http_client(client.clone())
         .build()
         .await?;
 
+    if let Some(eab) = eab {
+        register_account(client, acme_server_url, &account_key, eab, &[ACME_CONTACT]).await?;
+    } else if dir
+        .meta
+        .as_ref()
+        .and_then(|meta| meta.external_account_required)
+        == Some(true)
+    {
+        return Err(anyhow!(
+            "ACME server requires an external account binding; pass --acme-eab-key-id and --acme-eab-hmac-key."
+        ));
+    }
+
     let mut builder = AccountBuilder::new(dir);
-    builder.contact(vec!["mailto:paul@driftingin.space".to_string()]);
+    builder.private_key(account_key);
+    builder.contact(vec![ACME_CONTACT.to_string()]);
     builder.terms_of_service_agreed(true);
     let account = builder.build().await?;
 
     let mut builder = OrderBuilder::new(account);
-    builder.add_dns_identifier(format!("*.{}", cluster_domain));
+    // The wildcard covers backends' subdomains, but not the cluster domain itself.
+    builder.add_dns_identifier(cluster_domain.to_string());
+    if let ChallengeSolver::Dns(_) = solver {
+        builder.add_dns_identifier(format!("*.{}", cluster_domain));
+    }
     let order = builder.build().await?;
 
     let authorizations = order.authorizations().await?;
     for auth in authorizations {
+        if auth.status == AuthorizationStatus::Valid {
+            tracing::info!(identifier = ?auth.identifier, "Authorization is already valid.");
+            continue;
+        }
+
         tracing::info!("Requesting challenge.");
-        let challenge = auth
-            .get_challenge(DNS_01)
-            .ok_or_else(|| anyhow!("Couldn't obtain dns-01 challenge."))?;
-
-        let value = challenge
-            .key_authorization_encoded()?
-            .ok_or_else(|| anyhow!("No authorization value."))?;
-
-        tracing::info!("Requesting TXT record from platform.");
-        let result = nats
-            .request(
-                &SetAcmeDnsRecord::subject(),
-                &SetAcmeDnsRecord {
-                    cluster: cluster_domain.to_string(),
-                    value,
-                },
-            )
-            .await?;
+        let challenge = match solver {
+            ChallengeSolver::Dns(dns) => {
+                let challenge = auth
+                    .get_challenge(DNS_01)
+                    .ok_or_else(|| anyhow!("Couldn't obtain dns-01 challenge."))?;
 
-        if !result {
-            return Err(anyhow!("Platform rejected TXT record."));
-        }
+                let value = challenge
+                    .key_authorization_encoded()?
+                    .ok_or_else(|| anyhow!("No authorization value."))?;
+
+                tracing::info!("Setting TXT record.");
+                state.add_dns_record(&value).await?;
+                dns.set_challenge_record(cluster_domain, &value).await?;
 
-        tracing::info!("Validating challenge.");
-        let challenge = challenge.validate().await?;
-        let challenge = challenge.wait_done(Duration::from_secs(5), 3).await?;
+                tracing::info!("Validating challenge.");
+                let challenge = challenge.validate().await?;
+                let challenge = challenge.wait_done(Duration::from_secs(5), 3).await;
+                match dns.remove_challenge_record(cluster_domain, &value).await {
+                    Ok(()) => state.remove_dns_record(&value).await?,
+                    Err(error) => tracing::warn!(?error, "Error removing TXT record."),
+                }
+                challenge?
+            }
+            ChallengeSolver::Http(responder) => {
+                let challenge = auth
+                    .get_challenge(HTTP_01)
+                    .ok_or_else(|| anyhow!("Couldn't obtain http-01 challenge."))?;
+
+                let token = challenge
+                    .token
+                    .clone()
+                    .ok_or_else(|| anyhow!("Challenge has no token."))?;
+                let key_authorization = challenge
+                    .key_authorization()?
+                    .ok_or_else(|| anyhow!("No authorization value."))?;
+
+                tracing::info!("Serving key authorization.");
+                responder.add(&token, key_authorization);
+
+                tracing::info!("Validating challenge.");
+                let challenge = match challenge.validate().await {
+                    Ok(challenge) => challenge.wait_done(Duration::from_secs(5), 3).await,
+                    Err(error) => Err(error),
+                };
+                responder.remove(&token);
+                challenge?
+            }
+        };
         if challenge.status != ChallengeStatus::Valid {
             return Err(anyhow!("ACME challenge failed."));
         }
@@ -91,7 +247,7 @@ pub async fn get_certificate(
     }
 
     tracing::info!("Waiting for order to become done.");
-    let pkey = gen_rsa_private_key(4096)?;
+    let pkey = state.private_key()?;
     let order = order.finalize(Csr::Automatic(pkey.clone())).await?;
     let order = order.wait_done(Duration::from_secs(5), 3).await?;
 
@@ -100,93 +256,349 @@ pub async fn get_certificate(
     }
 
     tracing::info!("Waiting for certificate.");
-    let cert = order
+    let chain = order
         .certificate()
         .await?
         .ok_or_else(|| anyhow!("ACME order response didn't include certificate."))?;
 
-    let cert = cert
-        .into_iter()
-        .next()
-        .ok_or_else(|| anyhow!("Certificate list is empty."))?;
+    if chain.is_empty() {
+        return Err(anyhow!("Certificate list is empty."));
+    }
 
     tracing::info!("Got certificate from ACME.");
 
-    Ok((pkey, cert))
+    Ok((pkey, chain))
+}
+
+/// The cluster's certificates, by key type: the primary at the certificate paths, and any
+/// alternates beside it.
+fn certificate_paths(cert_options: &CertOptions) -> Vec<(KeyType, KeyCertPathPair)> {
+    std::iter::once((cert_options.key_type, cert_options.key_paths.clone()))
+        .chain(cert_options.alternate_key_types.iter().map(|key_type| {
+            (
+                *key_type,
+                cert_options.key_paths.alternate(key_type.as_str()),
+            )
+        }))
+        .collect()
 }
 
 pub async fn refresh_certificate(cert_options: &CertOptions) -> Result<()> {
     let client = get_https_client()?;
     let nats = cert_options.nats.connection().await?;
+    let cluster_domain = &cert_options.cluster_domain;
+    let store = match &cert_options.store {
+        Some(store) => Some(store.store(Some(&nats)).await?),
+        None => None,
+    };
+    let account_key = account_key(&cert_options.account_key_path, store.as_deref()).await?;
+
+    let dns;
+    let responder;
+    let solver = match cert_options.challenge {
+        ChallengeType::Dns01 => {
+            dns = cert_options.dns_provider.provider(&nats, &client)?;
+            ChallengeSolver::Dns(dns.as_ref())
+        }
+        ChallengeType::Http01 => {
+            responder = HttpChallengeResponder::start(&nats, cluster_domain).await?;
+            ChallengeSolver::Http(&responder)
+        }
+    };
+
+    // Each key type is a separate order, but the ACME server reuses the authorizations
+    // validated for the first, so challenges are only answered once.
+    let mut certificates = Vec::new();
+    for (key_type, key_paths) in certificate_paths(cert_options) {
+        let mut state = OrderState::resume(
+            OrderState::path(&key_paths.certificate_path),
+            cluster_domain,
+            key_type,
+        )
+        .await?;
+        let (pkey, chain) = get_certificate(
+            cluster_domain,
+            solver,
+            account_key.clone(),
+            &cert_options.acme_server_url,
+            cert_options.acme_eab.as_ref(),
+            &client,
+            &mut state,
+        )
+        .await?;
+        let (certificate_pem, private_key_pem) = write_certificate(&key_paths, &pkey, &chain)?;
+        state.finish().await?;
+        certificates.push(AlternateCertificate {
+            key_type,
+            certificate_pem,
+            private_key_pem,
+        });
+    }
 
-    let (pkey, cert) = get_certificate(
-        &cert_options.cluster_domain,
-        &nats,
-        &cert_options.acme_server_url,
-        &client,
-    )
-    .await?;
+    let primary = certificates.remove(0);
+    let certificate = ClusterCertificate {
+        cluster: cluster_domain.to_string(),
+        certificate_pem: primary.certificate_pem,
+        private_key_pem: primary.private_key_pem,
+        alternates: certificates,
+    };
 
-    std::fs::write(&cert_options.key_paths.certificate_path, cert.to_pem()?)?;
-    std::fs::write(
-        &cert_options.key_paths.private_key_path,
-        pkey.private_key_to_pem_pkcs8()?,
-    )?;
+    if let Some(store) = &store {
+        store.save(&certificate).await?;
+        tracing::info!("Saved certificate to store.");
+    }
+
+    if cert_options.distribute {
+        nats.add_latest_jetstream_stream(
+            CERTIFICATE_STREAM,
+            ClusterCertificate::subscribe_subject(),
+        )
+        .await?;
+        nats.publish(&ClusterCertificate::subject(cluster_domain), &certificate)
+            .await?;
+        tracing::info!("Published certificate to the cluster's proxies.");
+    }
 
     Ok(())
 }
 
+/// Write the certificate chain and its key to the paths, returning them as PEM.
+fn write_certificate(
+    key_paths: &KeyCertPathPair,
+    pkey: &PKey<Private>,
+    chain: &[X509],
+) -> Result<(String, String)> {
+    // Clients need the intermediate certificates too, to verify the leaf.
+    let mut certificate_pem = Vec::new();
+    for cert in chain {
+        certificate_pem.extend(cert.to_pem()?);
+    }
+    let private_key_pem = pkey.private_key_to_pem_pkcs8()?;
+
+    std::fs::write(&key_paths.certificate_path, &certificate_pem)?;
+    std::fs::write(&key_paths.private_key_path, &private_key_pem)?;
+
+    Ok((
+        String::from_utf8(certificate_pem)?,
+        String::from_utf8(private_key_pem)?,
+    ))
+}
+
 pub fn cert_validity(certificate_path: &Path) -> Option<DateTime<Utc>> {
-    let cert_pem = std::fs::read(certificate_path).ok()?;
-    let cert = X509::from_pem(&cert_pem).ok()?;
-    let not_after_asn1 = cert.not_after();
-    let not_after_unix = Asn1Time::from_unix(0).ok()?.diff(not_after_asn1).ok()?;
-    let not_after_naive = NaiveDateTime::from_timestamp(
-        i64::from(not_after_unix.days) * 86400 + i64::from(not_after_unix.secs),
-        0,
-    );
-    Some(DateTime::from_utc(not_after_naive, Utc))
+    pem_validity(&std::fs::read(certificate_path).ok()?)
+}
+
+/// When the first certificate in the PEM expires.
+fn pem_validity(cert_pem: &[u8]) -> Option<DateTime<Utc>> {
+    x509_validity(&X509::from_pem(cert_pem).ok()?)
+}
+
+/// When the certificate expires.
+pub fn x509_validity(cert: &X509) -> Option<DateTime<Utc>> {
+    asn1_time(cert.not_after())
+}
+
+fn asn1_time(time: &Asn1TimeRef) -> Option<DateTime<Utc>> {
+    let unix = Asn1Time::from_unix(0).ok()?.diff(time).ok()?;
+    let naive =
+        NaiveDateTime::from_timestamp(i64::from(unix.days) * 86400 + i64::from(unix.secs), 0);
+    Some(DateTime::from_utc(naive, Utc))
+}
+
+/// The fraction of the certificate's lifetime after which it is renewed. It is random,
+/// so that the drones of a cluster don't all renew at once, but fixed for a certificate
+/// within a process, so that each check agrees.
+fn renewal_fraction(cert: &X509) -> f64 {
+    static SALT: OnceLock<[u8; 16]> = OnceLock::new();
+    let salt = SALT.get_or_init(|| {
+        let mut salt = [0; 16];
+        rand_bytes(&mut salt).expect("Random number generation should never fail.");
+        salt
+    });
+
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(cert.signature().as_slice());
+    let hash = hasher.finish();
+    let sample = u64::from_be_bytes(hash[..8].try_into().expect("Hash is at least 8 bytes."));
+
+    RENEW_AFTER_MIN + (RENEW_AFTER_MAX - RENEW_AFTER_MIN) * (sample as f64 / u64::MAX as f64)
+}
+
+/// When to renew the certificate.
+fn renewal_time(cert: &X509) -> Option<DateTime<Utc>> {
+    let not_before = asn1_time(cert.not_before())?;
+    let not_after = asn1_time(cert.not_after())?;
+    let lifetime = (not_after - not_before).num_seconds() as f64;
+    Some(not_before + chrono::Duration::seconds((lifetime * renewal_fraction(cert)) as i64))
+}
+
+/// Restore the stored certificate, if it is valid for longer than the one at the
+/// certificate paths, e.g. because the host is new or another drone refreshed it.
+async fn restore_from_store(cert_options: &CertOptions) -> Result<()> {
+    if let Some(store) = &cert_options.store {
+        let nats = cert_options.nats.connection().await?;
+        let store = store.store(Some(&nats)).await?;
+        restore_cert(
+            store.as_ref(),
+            &cert_options.cluster_domain,
+            &cert_options.key_paths,
+        )
+        .await?;
+    }
+
+    Ok(())
 }
 
 pub async fn refresh_if_not_valid(cert_options: &CertOptions) -> Result<Option<Duration>> {
-    if let Some(valid_until) = cert_validity(&cert_options.key_paths.certificate_path) {
-        let refresh_at = valid_until
-            .checked_sub_signed(chrono::Duration::from_std(REFRESH_MARGIN)?)
-            .ok_or_else(|| {
-                anyhow!(
-                    "Date subtraction would result in over/underflow, this should never happen."
-                )
-            })?;
+    if let Err(error) = restore_from_store(cert_options).await {
+        tracing::warn!(?error, "Error restoring certificate from store.");
+    }
+
+    // The certificates are renewed together, as soon as any is due or missing.
+    let renewal_times: Option<Vec<_>> = certificate_paths(cert_options)
+        .iter()
+        .map(|(_, key_paths)| {
+            std::fs::read(&key_paths.certificate_path)
+                .ok()
+                .and_then(|pem| X509::from_pem(&pem).ok())
+                .as_ref()
+                .and_then(renewal_time)
+        })
+        .collect();
+    if let Some(refresh_at) = renewal_times.and_then(|times| times.into_iter().min()) {
         let time_until_refresh = refresh_at.signed_duration_since(Utc::now());
 
         if time_until_refresh > chrono::Duration::zero() {
             return Ok(Some(time_until_refresh.to_std()?));
         }
 
-        tracing::info!(
-            ?valid_until,
-            "Certificate exists, but is ready for refresh."
-        );
+        tracing::info!(%refresh_at, "Certificates exist, but are ready for refresh.");
     }
 
     tracing::info!("Refreshing certificate.");
-    refresh_certificate(cert_options)
+    let result = refresh_certificate(cert_options)
         .await
-        .context("Error refreshing certificate.")?;
+        .context("Error refreshing certificate.");
+    publish_renewal(cert_options, &result).await;
+    result?;
     tracing::info!("Done refreshing certificate.");
 
     Ok(None)
 }
 
-pub async fn refresh_loop(cert_options: CertOptions) -> Result<()> {
+/// Tell the cluster's proxies how an attempt to renew its certificate went.
+async fn publish_renewal(cert_options: &CertOptions, result: &Result<()>) {
+    let cluster_domain = &cert_options.cluster_domain;
+    let renewal = CertificateRenewal {
+        cluster: cluster_domain.clone(),
+        time: Utc::now(),
+        success: result.is_ok(),
+        error: result.as_ref().err().map(|error| format!("{:#}", error)),
+        valid_until: cert_validity(&cert_options.key_paths.certificate_path),
+    };
+
+    let published = async {
+        let nats = cert_options.nats.connection().await?;
+        nats.publish(&CertificateRenewal::subject(cluster_domain), &renewal)
+            .await
+    };
+    if let Err(error) = published.await {
+        tracing::warn!(?error, "Error publishing certificate renewal.");
+    }
+}
+
+/// Raise an alert if the certificate is missing or expires within the danger window.
+/// Renewal begins well before then, so this only happens if it has been failing.
+async fn check_expiry(cert_options: &CertOptions, last_error: Option<&str>) {
+    let valid_until = cert_validity(&cert_options.key_paths.certificate_path);
+    let in_danger = match valid_until {
+        Some(valid_until) => chrono::Duration::from_std(cert_options.danger_window)
+            .map_or(true, |window| valid_until - Utc::now() < window),
+        None => true,
+    };
+    if !in_danger {
+        return;
+    }
+
+    tracing::error!(?valid_until, ?last_error, "Certificate is close to expiry.");
+    let cluster_domain = &cert_options.cluster_domain;
+    let published = async {
+        let nats = cert_options.nats.connection().await?;
+        nats.publish(
+            &CertificateExpiryAlert::subject(cluster_domain),
+            &CertificateExpiryAlert {
+                cluster: cluster_domain.clone(),
+                valid_until,
+                last_error: last_error.map(str::to_string),
+            },
+        )
+        .await
+    };
+    if let Err(error) = published.await {
+        tracing::warn!(?error, "Error publishing certificate expiry alert.");
+    }
+}
+
+async fn renewal_loop(cert_options: &CertOptions) -> Result<()> {
+    let mut failures = 0;
     loop {
-        match refresh_if_not_valid(&cert_options).await {
-            Ok(Some(valid_until)) => tokio::time::sleep(valid_until.min(MAX_SLEEP)).await,
-            Ok(None) => tokio::time::sleep(MAX_SLEEP).await,
+        let (sleep, last_error) = match refresh_if_not_valid(cert_options).await {
+            Ok(Some(valid_until)) => {
+                failures = 0;
+                (valid_until.min(MAX_SLEEP), None)
+            }
+            Ok(None) => {
+                failures = 0;
+                (MAX_SLEEP, None)
+            }
             Err(error) => {
-                tracing::warn!(?error, "Error issuing certificate, will try again.");
-                tokio::time::sleep(Duration::from_secs(3600)).await;
+                failures += 1;
+                let retry_in = RENEWAL_RETRY.delay(failures);
+                tracing::warn!(
+                    ?error,
+                    ?retry_in,
+                    "Error issuing certificate, will try again."
+                );
+                (retry_in, Some(format!("{:#}", error)))
+            }
+        };
+        check_expiry(cert_options, last_error.as_deref()).await;
+        tokio::time::sleep(sleep).await;
+    }
+}
+
+/// Keep the cluster's certificate renewed, along with those of its backends' custom
+/// domains if they are enabled.
+pub async fn refresh_loop(cert_options: CertOptions) -> Result<()> {
+    match &cert_options.custom_domain_resolver {
+        Some(resolver_url) => {
+            tokio::select! {
+                result = renewal_loop(&cert_options) => result,
+                result = custom_domain_loop(&cert_options, resolver_url) => result,
             }
         }
+        None => renewal_loop(&cert_options).await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{drone::backend_tls::test::test_ca, types::BackendId};
+
+    #[test]
+    fn test_renewal_time() {
+        let cert = test_ca()
+            .issue_backend(&BackendId::new("backend".to_string()))
+            .unwrap()
+            .certificate;
+        let not_before = asn1_time(cert.not_before()).unwrap();
+        let lifetime = x509_validity(&cert).unwrap() - not_before;
+
+        let renew_at = renewal_time(&cert).unwrap();
+        assert!(renew_at >= not_before + lifetime * 6 / 10);
+        assert!(renew_at <= not_before + lifetime * 3 / 4);
+        assert_eq!(Some(renew_at), renewal_time(&cert));
     }
 }
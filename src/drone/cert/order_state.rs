@@ -0,0 +1,161 @@
+//! The state of an in-progress certificate order, saved beside the certificate so that a
+//! refresher which crashes mid-order resumes cleanly: it removes the TXT records it left
+//! behind, and orders with the same certificate key. ACME servers reuse authorizations
+//! an account has already validated, so challenges it passed are not repeated.
+
+use crate::messages::cert::KeyType;
+use anyhow::{Context, Result};
+use openssl::{
+    ec::{EcGroup, EcKey},
+    nid::Nid,
+    pkey::{PKey, Private},
+};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+fn generate_key(key_type: KeyType) -> Result<PKey<Private>> {
+    Ok(match key_type {
+        KeyType::Rsa => acme2::gen_rsa_private_key(4096)?,
+        KeyType::Ecdsa => {
+            let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+            PKey::from_ec_key(EcKey::generate(&group)?)?
+        }
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OrderState {
+    cluster_domain: String,
+
+    #[serde(default)]
+    key_type: KeyType,
+
+    /// Private key of the certificate being ordered, in PKCS #8 PEM format.
+    private_key_pem: String,
+
+    /// Values of the DNS-01 TXT records set and not yet removed.
+    #[serde(default)]
+    dns_records: Vec<String>,
+
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl OrderState {
+    /// Where the state of orders for the certificate at the path is saved.
+    pub fn path(certificate_path: &Path) -> PathBuf {
+        let mut file_name = certificate_path
+            .file_name()
+            .unwrap_or_default()
+            .to_os_string();
+        file_name.push(".order.json");
+        certificate_path.with_file_name(file_name)
+    }
+
+    /// The state of the interrupted order of the cluster's certificate with a key of the
+    /// type, if there is one, or else of a new order.
+    pub async fn resume(path: PathBuf, cluster_domain: &str, key_type: KeyType) -> Result<Self> {
+        match tokio::fs::read(&path).await {
+            Ok(state) => match serde_json::from_slice::<OrderState>(&state) {
+                Ok(state)
+                    if state.cluster_domain == cluster_domain && state.key_type == key_type =>
+                {
+                    tracing::info!(?path, "Resuming interrupted certificate order.");
+                    return Ok(OrderState { path, ..state });
+                }
+                Ok(_) => tracing::warn!(
+                    ?path,
+                    "Ignoring order state of another cluster or key type."
+                ),
+                Err(error) => tracing::warn!(?error, ?path, "Ignoring unreadable order state."),
+            },
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => (),
+            Err(error) => return Err(error).context("Error reading order state."),
+        }
+
+        let private_key = generate_key(key_type)?;
+        let state = OrderState {
+            cluster_domain: cluster_domain.to_string(),
+            key_type,
+            private_key_pem: String::from_utf8(private_key.private_key_to_pem_pkcs8()?)?,
+            dns_records: Vec::new(),
+            path,
+        };
+        state.save().await?;
+
+        Ok(state)
+    }
+
+    async fn save(&self) -> Result<()> {
+        tokio::fs::write(&self.path, serde_json::to_vec(self)?)
+            .await
+            .context("Error saving order state.")
+    }
+
+    pub fn private_key(&self) -> Result<PKey<Private>> {
+        PKey::private_key_from_pem(self.private_key_pem.as_bytes())
+            .context("Error reading order's certificate key.")
+    }
+
+    pub fn dns_records(&self) -> &[String] {
+        &self.dns_records
+    }
+
+    /// Record that the TXT record is about to be set.
+    pub async fn add_dns_record(&mut self, value: &str) -> Result<()> {
+        self.dns_records.push(value.to_string());
+        self.save().await
+    }
+
+    pub async fn remove_dns_record(&mut self, value: &str) -> Result<()> {
+        self.dns_records.retain(|record| record != value);
+        self.save().await
+    }
+
+    /// Forget the order, once its certificate has been written.
+    pub async fn finish(self) -> Result<()> {
+        tokio::fs::remove_file(&self.path)
+            .await
+            .context("Error removing order state.")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resume_order() {
+        let path = std::env::temp_dir().join(format!("cert-{}.order.json", std::process::id()));
+
+        let mut state = OrderState::resume(path.clone(), "spawner.test", KeyType::Rsa)
+            .await
+            .unwrap();
+        state.add_dns_record("first").await.unwrap();
+        state.add_dns_record("second").await.unwrap();
+        state.remove_dns_record("first").await.unwrap();
+
+        // A refresher which crashed picks up where it left off.
+        let resumed = OrderState::resume(path.clone(), "spawner.test", KeyType::Rsa)
+            .await
+            .unwrap();
+        assert_eq!(["second".to_string()], resumed.dns_records());
+        assert!(resumed
+            .private_key()
+            .unwrap()
+            .public_eq(&state.private_key().unwrap()));
+
+        // Orders of another cluster or key type start afresh.
+        let other = OrderState::resume(path.clone(), "other.test", KeyType::Rsa)
+            .await
+            .unwrap();
+        assert!(other.dns_records().is_empty());
+        let ecdsa = OrderState::resume(path.clone(), "other.test", KeyType::Ecdsa)
+            .await
+            .unwrap();
+        assert!(ecdsa.private_key().unwrap().ec_key().is_ok());
+
+        ecdsa.finish().await.unwrap();
+        assert!(!path.exists());
+    }
+}
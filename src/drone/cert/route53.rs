@@ -0,0 +1,234 @@
+//! Challenge records set through the Route53 API, signed with AWS Signature Version 4.
+
+use super::dns::{challenge_record_name, DnsProvider, CHALLENGE_TTL};
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use openssl::{hash::MessageDigest, pkey::PKey, sha::sha256, sign::Signer};
+use reqwest::{Client, Method};
+use std::{env::var, time::Duration};
+
+const HOST: &str = "route53.amazonaws.com";
+
+/// Route53 is a global service, signed for this region.
+const REGION: &str = "us-east-1";
+const SERVICE: &str = "route53";
+
+/// How often, and how many times, a change is checked for having been applied.
+const CHANGE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const CHANGE_POLL_ATTEMPTS: u32 = 36;
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The key which requests to the service on the given date (`YYYYMMDD`) are signed with.
+fn signing_key(secret_access_key: &str, date: &str, service: &str) -> Result<Vec<u8>> {
+    let key = hmac_sha256(
+        format!("AWS4{}", secret_access_key).as_bytes(),
+        date.as_bytes(),
+    )?;
+    let key = hmac_sha256(&key, REGION.as_bytes())?;
+    let key = hmac_sha256(&key, service.as_bytes())?;
+    hmac_sha256(&key, b"aws4_request")
+}
+
+/// The text of the first element with the given name in an XML document.
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(&xml[start..end])
+}
+
+fn change_batch(action: &str, name: &str, value: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<ChangeResourceRecordSetsRequest xmlns="https://{host}/doc/2013-04-01/">
+<ChangeBatch><Changes><Change>
+<Action>{action}</Action>
+<ResourceRecordSet><Name>{name}</Name><Type>TXT</Type><TTL>{ttl}</TTL>
+<ResourceRecords><ResourceRecord><Value>"{value}"</Value></ResourceRecord></ResourceRecords>
+</ResourceRecordSet>
+</Change></Changes></ChangeBatch>
+</ChangeResourceRecordSetsRequest>"#,
+        host = HOST,
+        action = action,
+        name = name,
+        ttl = CHALLENGE_TTL,
+        value = value
+    )
+}
+
+pub struct Route53 {
+    hosted_zone_id: String,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+    client: Client,
+}
+
+impl Route53 {
+    pub fn from_env(hosted_zone_id: &str, client: &Client) -> Result<Self> {
+        Ok(Route53 {
+            hosted_zone_id: hosted_zone_id.to_string(),
+            access_key_id: var("AWS_ACCESS_KEY_ID")
+                .context("Expected AWS_ACCESS_KEY_ID for Route53.")?,
+            secret_access_key: var("AWS_SECRET_ACCESS_KEY")
+                .context("Expected AWS_SECRET_ACCESS_KEY for Route53.")?,
+            session_token: var("AWS_SESSION_TOKEN").ok(),
+            client: client.clone(),
+        })
+    }
+
+    /// Send a signed request to the Route53 API, returning the body of its response.
+    async fn request(&self, method: Method, path: &str, body: String) -> Result<String> {
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+
+        let mut headers = vec![("host", HOST.to_string()), ("x-amz-date", amz_date.clone())];
+        if let Some(session_token) = &self.session_token {
+            headers.push(("x-amz-security-token", session_token.clone()));
+        }
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect();
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+
+        let canonical_request = format!(
+            "{}\n{}\n\n{}\n{}\n{}",
+            method,
+            path,
+            canonical_headers,
+            signed_headers,
+            hex(&sha256(body.as_bytes()))
+        );
+        let scope = format!("{}/{}/{}/aws4_request", date, REGION, SERVICE);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&sha256(canonical_request.as_bytes()))
+        );
+        let signature = hex(&hmac_sha256(
+            &signing_key(&self.secret_access_key, &date, SERVICE)?,
+            string_to_sign.as_bytes(),
+        )?);
+
+        let mut request = self
+            .client
+            .request(method, format!("https://{}{}", HOST, path))
+            .header(
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    self.access_key_id, scope, signed_headers, signature
+                ),
+            )
+            .header("x-amz-date", amz_date)
+            .body(body);
+        if let Some(session_token) = &self.session_token {
+            request = request.header("x-amz-security-token", session_token);
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        let text = response.text().await?;
+        if !status.is_success() {
+            return Err(anyhow!(
+                "Route53 request failed with {}: {}",
+                status,
+                xml_element(&text, "Message").unwrap_or(&text)
+            ));
+        }
+
+        Ok(text)
+    }
+
+    /// Apply a change to the challenge record, and wait for it to reach every Route53
+    /// name server.
+    async fn change(&self, action: &str, cluster_domain: &str, value: &str) -> Result<()> {
+        let name = challenge_record_name(cluster_domain);
+        let response = self
+            .request(
+                Method::POST,
+                &format!("/2013-04-01/hostedzone/{}/rrset", self.hosted_zone_id),
+                change_batch(action, &name, value),
+            )
+            .await?;
+        let change_id = xml_element(&response, "Id")
+            .ok_or_else(|| anyhow!("Route53 response had no change ID."))?
+            .trim_start_matches("/change/")
+            .to_string();
+
+        for _ in 0..CHANGE_POLL_ATTEMPTS {
+            let response = self
+                .request(
+                    Method::GET,
+                    &format!("/2013-04-01/change/{}", change_id),
+                    String::new(),
+                )
+                .await?;
+            if xml_element(&response, "Status") == Some("INSYNC") {
+                return Ok(());
+            }
+            tokio::time::sleep(CHANGE_POLL_INTERVAL).await;
+        }
+
+        Err(anyhow!(
+            "Timed out waiting for Route53 change {}.",
+            change_id
+        ))
+    }
+}
+
+#[async_trait]
+impl DnsProvider for Route53 {
+    async fn set_challenge_record(&self, cluster_domain: &str, value: &str) -> Result<()> {
+        self.change("UPSERT", cluster_domain, value).await
+    }
+
+    async fn remove_challenge_record(&self, cluster_domain: &str, value: &str) -> Result<()> {
+        self.change("DELETE", cluster_domain, value).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_signing_key() {
+        // The example from AWS's documentation, which is for IAM rather than Route53.
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "iam",
+        )
+        .unwrap();
+        assert_eq!(
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d",
+            hex(&key)
+        );
+    }
+
+    #[test]
+    fn test_xml_element() {
+        let response = "<ChangeResourceRecordSetsResponse><ChangeInfo><Id>/change/C2682N5HXP0BZ4</Id><Status>PENDING</Status></ChangeInfo></ChangeResourceRecordSetsResponse>";
+        assert_eq!(Some("/change/C2682N5HXP0BZ4"), xml_element(response, "Id"));
+        assert_eq!(Some("PENDING"), xml_element(response, "Status"));
+        assert_eq!(None, xml_element(response, "Message"));
+    }
+}
@@ -0,0 +1,330 @@
+//! Durable storage of certificates, from which a drone on a new or ephemeral host
+//! recovers its cluster's certificate rather than having to wait for a new one.
+//!
+//! The certificate refresher saves each certificate it obtains to the store, as well as
+//! writing it to the certificate paths. At startup, the refresher and the proxy restore
+//! the stored certificate to their certificate paths, if it is valid for longer than the
+//! one there.
+//!
+//! Certificates with alternate key types are stored along with the cluster's primary one.
+
+use super::{kubernetes::KubernetesSecrets, pem_validity};
+use crate::{
+    keys::KeyCertPathPair,
+    messages::cert::{AcmeAccountKey, AlternateCertificate, ClusterCertificate, KeyType},
+    nats::TypedNats,
+};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::{path::PathBuf, str::FromStr};
+
+/// Name of the NATS key-value bucket certificates are stored in.
+const NATS_BUCKET: &str = "spawner_certs";
+
+/// Name of the file the ACME account key is stored in by a file store.
+const ACCOUNT_KEY_FILE: &str = "acme-account.key";
+
+/// Stores each cluster's latest certificate, and the ACME account key.
+#[async_trait]
+pub trait CertStore: Send + Sync {
+    async fn load(&self, cluster_domain: &str) -> Result<Option<ClusterCertificate>>;
+
+    async fn save(&self, certificate: &ClusterCertificate) -> Result<()>;
+
+    /// The private key of the ACME account, in PKCS #8 PEM format.
+    async fn load_account_key(&self) -> Result<Option<String>>;
+
+    async fn save_account_key(&self, private_key_pem: &str) -> Result<()>;
+}
+
+/// Where certificates are stored, beyond the certificate paths.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum CertStoreKind {
+    /// Files under `<directory>/<cluster domain>/`, e.g. on a persistent volume.
+    File { directory: PathBuf },
+
+    /// A NATS key-value bucket, keyed by cluster domain.
+    Nats,
+
+    /// Kubernetes TLS Secrets named `spawner-cert-<cluster domain>`, in the namespace, or
+    /// else the drone's own. The drone must run in the cluster, as a service account
+    /// which can get, create, and update Secrets.
+    Kubernetes { namespace: Option<String> },
+}
+
+impl FromStr for CertStoreKind {
+    type Err = anyhow::Error;
+
+    /// Parses `file:<directory>`, `nats`, `kubernetes`, or `kubernetes:<namespace>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("file", directory)) if !directory.is_empty() => Ok(CertStoreKind::File {
+                directory: PathBuf::from(directory),
+            }),
+            Some(("kubernetes", namespace)) if !namespace.is_empty() => {
+                Ok(CertStoreKind::Kubernetes {
+                    namespace: Some(namespace.to_string()),
+                })
+            }
+            None if s == "nats" => Ok(CertStoreKind::Nats),
+            None if s == "kubernetes" => Ok(CertStoreKind::Kubernetes { namespace: None }),
+            _ => Err(anyhow!(
+                "Expected certificate store to be file:<directory>, nats, kubernetes, or kubernetes:<namespace>, got {:?}.",
+                s
+            )),
+        }
+    }
+}
+
+impl CertStoreKind {
+    /// The store. NATS is required for a NATS store.
+    pub async fn store(&self, nats: Option<&TypedNats>) -> Result<Box<dyn CertStore>> {
+        Ok(match self {
+            CertStoreKind::File { directory } => Box::new(FileCertStore {
+                directory: directory.clone(),
+            }),
+            CertStoreKind::Nats => {
+                let nats = nats
+                    .ok_or_else(|| anyhow!("Expected NATS for storing certificates."))?
+                    .clone();
+                nats.add_latest_jetstream_stream(
+                    &format!("KV_{}", NATS_BUCKET),
+                    ClusterCertificate::store_subscribe_subject(NATS_BUCKET),
+                )
+                .await?;
+                Box::new(NatsCertStore { nats })
+            }
+            CertStoreKind::Kubernetes { namespace } => {
+                Box::new(KubernetesSecrets::in_cluster(namespace.as_deref())?)
+            }
+        })
+    }
+}
+
+/// Write the cluster's stored certificate to its paths if it is valid for longer than the
+/// certificate there, if any. Returns whether it was.
+pub async fn restore_cert(
+    store: &dyn CertStore,
+    cluster_domain: &str,
+    key_paths: &KeyCertPathPair,
+) -> Result<bool> {
+    let stored = match store.load(cluster_domain).await? {
+        Some(stored) => stored,
+        None => return Ok(false),
+    };
+    let stored_validity = pem_validity(stored.certificate_pem.as_bytes());
+    let current_validity = tokio::fs::read(&key_paths.certificate_path)
+        .await
+        .ok()
+        .and_then(|pem| pem_validity(&pem));
+    if stored_validity <= current_validity {
+        return Ok(false);
+    }
+
+    tracing::info!(%cluster_domain, valid_until = ?stored_validity, "Restoring stored certificate.");
+    write_cert_files(&stored, key_paths).await?;
+
+    Ok(true)
+}
+
+/// Write the certificate to the paths, and its alternates beside them.
+pub async fn write_cert_files(
+    certificate: &ClusterCertificate,
+    key_paths: &KeyCertPathPair,
+) -> Result<()> {
+    for alternate in &certificate.alternates {
+        let key_paths = key_paths.alternate(alternate.key_type.as_str());
+        tokio::fs::write(&key_paths.certificate_path, &alternate.certificate_pem).await?;
+        tokio::fs::write(&key_paths.private_key_path, &alternate.private_key_pem).await?;
+    }
+    tokio::fs::write(&key_paths.certificate_path, &certificate.certificate_pem).await?;
+    tokio::fs::write(&key_paths.private_key_path, &certificate.private_key_pem).await?;
+
+    Ok(())
+}
+
+struct FileCertStore {
+    directory: PathBuf,
+}
+
+impl FileCertStore {
+    fn key_paths(&self, cluster_domain: &str) -> KeyCertPathPair {
+        let directory = self.directory.join(cluster_domain);
+        KeyCertPathPair {
+            private_key_path: directory.join("key.pem"),
+            certificate_path: directory.join("cert.pem"),
+        }
+    }
+}
+
+#[async_trait]
+impl CertStore for FileCertStore {
+    async fn load(&self, cluster_domain: &str) -> Result<Option<ClusterCertificate>> {
+        let key_paths = self.key_paths(cluster_domain);
+        let certificate_pem = match tokio::fs::read_to_string(&key_paths.certificate_path).await {
+            Ok(certificate_pem) => certificate_pem,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+
+        let mut alternates = Vec::new();
+        for key_type in KeyType::ALL {
+            let key_paths = key_paths.alternate(key_type.as_str());
+            if let Ok(certificate_pem) =
+                tokio::fs::read_to_string(&key_paths.certificate_path).await
+            {
+                alternates.push(AlternateCertificate {
+                    key_type,
+                    certificate_pem,
+                    private_key_pem: tokio::fs::read_to_string(&key_paths.private_key_path).await?,
+                });
+            }
+        }
+
+        Ok(Some(ClusterCertificate {
+            cluster: cluster_domain.to_string(),
+            certificate_pem,
+            private_key_pem: tokio::fs::read_to_string(&key_paths.private_key_path).await?,
+            alternates,
+        }))
+    }
+
+    async fn save(&self, certificate: &ClusterCertificate) -> Result<()> {
+        let key_paths = self.key_paths(&certificate.cluster);
+        tokio::fs::create_dir_all(self.directory.join(&certificate.cluster)).await?;
+        // The certificate is written last, so that one is never loaded without its key.
+        for alternate in &certificate.alternates {
+            let key_paths = key_paths.alternate(alternate.key_type.as_str());
+            tokio::fs::write(&key_paths.private_key_path, &alternate.private_key_pem).await?;
+            tokio::fs::write(&key_paths.certificate_path, &alternate.certificate_pem).await?;
+        }
+        tokio::fs::write(&key_paths.private_key_path, &certificate.private_key_pem).await?;
+        tokio::fs::write(&key_paths.certificate_path, &certificate.certificate_pem).await?;
+
+        Ok(())
+    }
+
+    async fn load_account_key(&self) -> Result<Option<String>> {
+        match tokio::fs::read_to_string(self.directory.join(ACCOUNT_KEY_FILE)).await {
+            Ok(private_key_pem) => Ok(Some(private_key_pem)),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    async fn save_account_key(&self, private_key_pem: &str) -> Result<()> {
+        tokio::fs::create_dir_all(&self.directory).await?;
+        tokio::fs::write(self.directory.join(ACCOUNT_KEY_FILE), private_key_pem).await?;
+
+        Ok(())
+    }
+}
+
+/// Stores certificates in a JetStream stream laid out as a NATS key-value bucket, so that
+/// they can be inspected with the NATS tools' `kv` commands.
+struct NatsCertStore {
+    nats: TypedNats,
+}
+
+#[async_trait]
+impl CertStore for NatsCertStore {
+    async fn load(&self, cluster_domain: &str) -> Result<Option<ClusterCertificate>> {
+        self.nats
+            .get_latest(
+                &ClusterCertificate::store_subject(NATS_BUCKET, cluster_domain),
+                &format!("KV_{}", NATS_BUCKET),
+            )
+            .await
+    }
+
+    async fn save(&self, certificate: &ClusterCertificate) -> Result<()> {
+        self.nats
+            .publish_jetstream(
+                &ClusterCertificate::store_subject(NATS_BUCKET, &certificate.cluster),
+                certificate,
+            )
+            .await
+    }
+
+    async fn load_account_key(&self) -> Result<Option<String>> {
+        let account_key = self
+            .nats
+            .get_latest(
+                &AcmeAccountKey::store_subject(NATS_BUCKET),
+                &format!("KV_{}", NATS_BUCKET),
+            )
+            .await?;
+        Ok(account_key.map(|account_key| account_key.private_key_pem))
+    }
+
+    async fn save_account_key(&self, private_key_pem: &str) -> Result<()> {
+        self.nats
+            .publish_jetstream(
+                &AcmeAccountKey::store_subject(NATS_BUCKET),
+                &AcmeAccountKey {
+                    private_key_pem: private_key_pem.to_string(),
+                },
+            )
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_cert_store() {
+        assert_eq!(
+            CertStoreKind::File {
+                directory: PathBuf::from("/var/lib/spawner/certs")
+            },
+            "file:/var/lib/spawner/certs".parse().unwrap()
+        );
+        assert_eq!(CertStoreKind::Nats, "nats".parse().unwrap());
+        assert_eq!(
+            CertStoreKind::Kubernetes { namespace: None },
+            "kubernetes".parse().unwrap()
+        );
+        assert_eq!(
+            CertStoreKind::Kubernetes {
+                namespace: Some("spawner".to_string())
+            },
+            "kubernetes:spawner".parse().unwrap()
+        );
+        assert!("file".parse::<CertStoreKind>().is_err());
+        assert!("nats:bucket".parse::<CertStoreKind>().is_err());
+        assert!("vault".parse::<CertStoreKind>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_file_store() {
+        let directory = std::env::temp_dir().join(format!("cert-store-{}", std::process::id()));
+        let store = FileCertStore {
+            directory: directory.clone(),
+        };
+        assert!(store.load("spawner.test").await.unwrap().is_none());
+
+        store
+            .save(&ClusterCertificate {
+                cluster: "spawner.test".to_string(),
+                certificate_pem: "certificate".to_string(),
+                private_key_pem: "key".to_string(),
+                alternates: vec![AlternateCertificate {
+                    key_type: KeyType::Ecdsa,
+                    certificate_pem: "ecdsa certificate".to_string(),
+                    private_key_pem: "ecdsa key".to_string(),
+                }],
+            })
+            .await
+            .unwrap();
+        let loaded = store.load("spawner.test").await.unwrap().unwrap();
+        assert_eq!("certificate", loaded.certificate_pem);
+        assert_eq!("key", loaded.private_key_pem);
+        assert_eq!(1, loaded.alternates.len());
+        assert_eq!(KeyType::Ecdsa, loaded.alternates[0].key_type);
+        assert_eq!("ecdsa key", loaded.alternates[0].private_key_pem);
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
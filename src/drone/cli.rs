@@ -1,14 +1,52 @@
 use super::{
-    agent::{AgentOptions, DockerApiTransport, DockerOptions},
-    proxy::{ProxyHttpsOptions, ProxyOptions},
+    agent::{
+        eviction::{EvictionOptions, EvictionPolicy},
+        image_policy::ImagePolicy,
+        logs::LogSink,
+        secrets::{SecretSources, VaultOptions},
+        warm_pool::WarmPoolConfig,
+        webhooks::Webhooks,
+        AgentOptions, DockerApiTransport, DockerOptions, EngineKind, FirecrackerOptions,
+    },
+    cert::{
+        account::ExternalAccountBinding, acme_directory_url, dns::DnsProviderKind,
+        store::CertStoreKind, ChallengeType,
+    },
+    proxy::{
+        access_log::AccessLogSink, compression::CompressionRule, error_pages::ErrorPageRule,
+        forwarded::ForwardedHeadersRule, jwt::JwtOptions, proxy_protocol::ProxyProtocol,
+        rate_limit::RateLimit, request_limits::RequestLimits, static_site::StaticSiteRule,
+        tcp::PortRange, timeouts::UpstreamTimeouts, AdditionalCluster, HostCert, ProxyHttpsOptions,
+        ProxyOptions,
+    },
 };
 use crate::{
-    database_connection::DatabaseConnection, keys::KeyCertPathPair, nats_connection::NatsConnection,
+    database_connection::DatabaseConnection,
+    keys::KeyCertPathPair,
+    messages::{
+        agent::{BackendNetwork, Ulimit, Ulimits},
+        cert::KeyType,
+    },
+    nats_connection::NatsConnection,
+    retry::RetryPolicy,
+    types::BackendId,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use clap::{Parser, Subcommand};
+use http::StatusCode;
 use reqwest::Url;
-use std::{fmt::Debug, net::IpAddr, path::PathBuf};
+use std::{
+    fmt::Debug,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+/// Longest wait between attempts at pulling an image or creating a container.
+const MAX_SPAWN_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Name of the ACME account key, beside the certificate's private key unless given.
+const DEFAULT_ACME_ACCOUNT_KEY: &str = "acme-account.key";
 
 #[derive(Parser)]
 pub struct Opts {
@@ -22,6 +60,248 @@ pub struct Opts {
     #[clap(long, action)]
     pub cluster_domain: Option<String>,
 
+    /// Another cluster domain for the proxy to serve, as `<domain>` or
+    /// `<domain>=<private key path>,<certificate path>`. Over HTTPS, the certificate is
+    /// served to clients which ask for the domain by SNI, and reloaded when its files
+    /// change. Backends are only routed under the domain of their own cluster. May be
+    /// repeated.
+    #[clap(long, action)]
+    pub additional_cluster: Vec<AdditionalCluster>,
+
+    /// A certificate for the proxy to serve over HTTPS to clients which ask for the
+    /// hostname by SNI, as `<hostname>=<private key path>,<certificate path>`, where the
+    /// hostname may be a wildcard `*.<domain>`. Certificates are reloaded when their files
+    /// change, so operator-provided certificates can be used without --cert-refresh.
+    /// May be repeated.
+    #[clap(long, action)]
+    pub host_cert: Vec<HostCert>,
+
+    /// Where the proxy writes a JSON access log entry for each request: `stdout`,
+    /// `file:<path>`, or `nats:<subject>`.
+    #[clap(long, action)]
+    pub access_log: Option<AccessLogSink>,
+
+    /// URL of a JSON Web Key Set. If provided, the proxy only forwards requests which carry
+    /// a JWT signed by one of its keys, in an `Authorization: Bearer` header or a
+    /// `spawner_token` cookie.
+    #[clap(long, action)]
+    pub jwks_url: Option<Url>,
+
+    /// Issuer that JWTs must name in their `iss` claim.
+    #[clap(long, action)]
+    pub jwt_issuer: Option<String>,
+
+    /// Audience that JWTs must name in their `aud` claim.
+    #[clap(long, action)]
+    pub jwt_audience: Option<String>,
+
+    /// Claim of JWTs which must equal the ID of the backend the request is for.
+    #[clap(long, default_value = "backend_id", action)]
+    pub jwt_backend_claim: String,
+
+    /// Apply routes published by agents over NATS, so that the proxy can route to backends
+    /// on drones whose database it does not share. Requires --nats-url.
+    #[clap(long, action)]
+    pub route_updates: bool,
+
+    /// Publish the time each backend was last active to `cluster.<domain>.activity` over
+    /// NATS every few seconds, for agents on other drones and dashboards. Requires
+    /// --nats-url.
+    #[clap(long, action)]
+    pub publish_activity: bool,
+
+    /// Page the proxy serves for a class of error (`not-found`, `unavailable`, `timed-out`,
+    /// `unauthorized`, `rate-limited`, `starting`, `connection-limit`, or `too-large`), as
+    /// `<class>=<template path>` or `<class>=redirect:<url>`. In templates, `{{host}}` is
+    /// replaced with the requested host. May be repeated.
+    #[clap(long, action)]
+    pub error_page: Vec<ErrorPageRule>,
+
+    /// How long the proxy holds requests for a backend which is still loading or starting,
+    /// waiting for it to become ready. Zero fails them immediately.
+    #[clap(long, default_value = "0", action)]
+    pub hold_starting_secs: u64,
+
+    /// Serve a page which reloads until the backend is ready to requests for a starting
+    /// backend which are not held long enough, rather than 404 Not Found. The page can be
+    /// replaced with --error-page starting=<template path>.
+    #[clap(long, action)]
+    pub starting_page: bool,
+
+    /// Also route requests to the cluster domain itself by path, at
+    /// `<cluster>/<backend id>/...`, for deployments without wildcard subdomains. The
+    /// prefix is stripped before forwarding, and passed in `X-Forwarded-Prefix`.
+    #[clap(long, action)]
+    pub path_routing: bool,
+
+    /// Status the proxy responds with to a WebSocket or other upgrade request for a backend
+    /// which already has as many long-lived connections open as its spawn request's
+    /// `max_connections`. Excess TCP connections are closed.
+    #[clap(long, default_value = "503", action)]
+    pub connection_limit_status: StatusCode,
+
+    /// How long the proxy caches routes it looks up in the database. Zero disables the
+    /// cache. With --nats-url, the routes to a backend are dropped from the cache as soon
+    /// as its state changes.
+    #[clap(long, default_value = "0", action)]
+    pub route_cache_ttl_secs: u64,
+
+    /// Largest request body, in bytes, the proxy forwards to a backend. Larger requests
+    /// are answered with 413 Payload Too Large, or cut off if their length isn't declared
+    /// up front. Applies to streaming (e.g. gRPC) request bodies as a whole.
+    #[clap(long, action)]
+    pub max_request_body_bytes: Option<u64>,
+
+    /// Largest total size, in bytes, of the headers of a request the proxy forwards.
+    /// Larger requests are answered with 431 Request Header Fields Too Large.
+    #[clap(long, action)]
+    pub max_request_header_bytes: Option<usize>,
+
+    /// Longest path and query of a request the proxy forwards. Longer requests are
+    /// answered with 414 URI Too Long.
+    #[clap(long, action)]
+    pub max_uri_length: Option<usize>,
+
+    /// How long the proxy waits to connect to a backend before answering 502 Bad Gateway.
+    #[clap(long, default_value = "10", action)]
+    pub upstream_connect_timeout_secs: u64,
+
+    /// How long the proxy waits for a backend's response headers before answering
+    /// 504 Gateway Timeout. A backend's spawn request can override this.
+    #[clap(long, default_value = "60", action)]
+    pub upstream_read_timeout_secs: u64,
+
+    /// How long a response body, or an idle pooled connection to a backend, may go
+    /// without data before it is closed. A backend's spawn request can override this.
+    #[clap(long, default_value = "60", action)]
+    pub upstream_idle_timeout_secs: u64,
+
+    /// How long streaming traffic (WebSockets and other upgraded connections, gRPC calls,
+    /// server-sent events, and TCP passthrough) may go without data before it is closed.
+    /// A backend's spawn request can override this.
+    #[clap(long, default_value = "3600", action)]
+    pub streaming_idle_timeout_secs: u64,
+
+    /// Pin each client to one replica of backends which are a pool of containers, by a
+    /// cookie with this name, so that a browser keeps hitting the replica holding its
+    /// session. Without it, requests are spread across replicas in turn.
+    #[clap(long, action)]
+    pub affinity_cookie: Option<String>,
+
+    /// Handle each request in a tracing span carrying its W3C trace and span IDs, so that
+    /// the proxy's logs of a request join its distributed trace. Requests are forwarded
+    /// to backends with a `traceparent` header whether or not this is set.
+    #[clap(long, action)]
+    pub trace_spans: bool,
+
+    /// Staple OCSP responses to the certificates the proxy serves over HTTPS, fetched
+    /// from their issuers' responders and refreshed in the background. Certificate files
+    /// must include the issuer's certificate after the leaf.
+    #[clap(long, action)]
+    pub ocsp_stapling: bool,
+
+    /// Answer HTTP-01 challenges at `/.well-known/acme-challenge/` on cluster domains with
+    /// key authorizations from the cluster's certificate refresher, over NATS. When the
+    /// proxy serves HTTPS, challenges are answered on the HTTP port. Requires --nats-url.
+    #[clap(long, action)]
+    pub acme_http_challenges: bool,
+
+    /// Route backends' custom domains, given in spawn requests. Agents check that each is
+    /// a CNAME of its backend's subdomain before routing it, certificate refreshers issue
+    /// certificates for them over HTTP-01 (so the cluster's proxies need
+    /// --acme-http-challenges), and proxies serve them. Requires --nats-url.
+    #[clap(long, action)]
+    pub custom_domains: bool,
+
+    /// DNS over HTTPS resolver, with a JSON API, which custom domains' CNAMEs are checked
+    /// with.
+    #[clap(long, default_value = "https://cloudflare-dns.com/dns-query", action)]
+    pub dns_over_https_url: String,
+
+    /// Expect every connection to the proxy, on its HTTP, HTTPS, and TCP ports, to begin
+    /// with a PROXY protocol (v1 or v2) header from a load balancer in front of it, and
+    /// take clients' addresses from it. Connections without one are closed.
+    #[clap(long, action)]
+    pub accept_proxy_protocol: bool,
+
+    /// Begin TCP passthrough connections to backends with a PROXY protocol v2 header
+    /// carrying the client's address. HTTP requests carry it in their forwarded headers.
+    #[clap(long, action)]
+    pub send_proxy_protocol: bool,
+
+    /// Address to serve the proxy's Prometheus metrics on, at `/metrics`.
+    #[clap(long, action)]
+    pub proxy_metrics_address: Option<SocketAddr>,
+
+    /// Address to serve the proxy's health at `/healthz`, and its readiness at `/readyz`.
+    /// The proxy is ready once its route table has synced (with --route-updates), NATS is
+    /// connected, and its certificates have loaded.
+    #[clap(long, action)]
+    pub proxy_health_address: Option<SocketAddr>,
+
+    /// Limit on the rate of requests the proxy forwards to each backend, as
+    /// `<requests per second>` or `<requests per second>,<burst>`. Requests beyond it are
+    /// answered with 429 Too Many Requests.
+    #[clap(long, action)]
+    pub backend_rate_limit: Option<RateLimit>,
+
+    /// Limit on the rate of requests the proxy forwards from each client IP to each
+    /// backend, in the same form as --backend-rate-limit.
+    #[clap(long, action)]
+    pub client_rate_limit: Option<RateLimit>,
+
+    /// Range of ports, as `<start>-<end>`, which backends' TCP ports are assigned from.
+    /// The proxy listens on each of them and passes raw TCP connections through to the
+    /// backend assigned the port.
+    #[clap(long, action)]
+    pub tcp_port_range: Option<PortRange>,
+
+    /// Range of ports, as `<start>-<end>`, which backends' UDP ports are assigned from.
+    /// The proxy listens on each of them and relays datagrams to and from the backend
+    /// assigned the port. Without it, clients send to the ports backends are published on.
+    #[clap(long, action)]
+    pub udp_port_range: Option<PortRange>,
+
+    /// Path to read the certificate of a CA for backends from. The agent issues each
+    /// backend a certificate from it, passed to the container in `SPAWNER_TLS_CERTIFICATE`
+    /// and `SPAWNER_TLS_PRIVATE_KEY`, and the proxy only connects to backends over TLS,
+    /// presenting a client certificate from it. Backends should only accept clients with a
+    /// certificate from the CA in `SPAWNER_TLS_CLIENT_CA`. Backends without a readiness
+    /// probe are considered ready once their port accepts connections.
+    #[clap(long, action)]
+    pub backend_ca_certificate: Option<PathBuf>,
+
+    /// Path to read the private key of the CA for backends from.
+    #[clap(long, action)]
+    pub backend_ca_private_key: Option<PathBuf>,
+
+    /// Whether the proxy appends to (`append`, the default), replaces (`replace`), or
+    /// leaves alone (`off`) the `Forwarded` and `X-Forwarded-*` headers of requests, as
+    /// `<mode>` for every cluster domain or `<domain>=<mode>` for one. Replacing them
+    /// discards values set by clients. May be repeated.
+    #[clap(long, action)]
+    pub forwarded_headers: Vec<ForwardedHeadersRule>,
+
+    /// Directory of a static site the proxy serves at the cluster domain itself, as
+    /// `<directory>` for every cluster domain or `<domain>=<directory>` for one. Its
+    /// `ended.html`, if any, is served for the hosts of terminated backends, with
+    /// `{{host}}`, `{{backend}}`, and `{{cluster}}` replaced. May be repeated.
+    #[clap(long, action)]
+    pub static_site: Vec<StaticSiteRule>,
+
+    /// Content types of backends' responses which the proxy compresses with brotli or
+    /// gzip, for clients which accept either, as comma-separated media types (`<type>/*`
+    /// matches any subtype) for every cluster domain, or `<domain>=<media types>` for one.
+    /// `off` compresses nothing. May be repeated.
+    #[clap(long, action)]
+    pub compress: Vec<CompressionRule>,
+
+    /// JSON file mapping cluster domains to the CORS configuration the proxy applies to
+    /// their backends: `allowed_origins`, `allowed_methods`, `allowed_headers`,
+    /// `exposed_headers`, `allow_credentials`, and `max_age_secs`.
+    #[clap(long, action)]
+    pub cors_config: Option<PathBuf>,
+
     /// Port to listen for HTTP requests on.
     #[clap(long, default_value = "80", action)]
     pub http_port: u16,
@@ -30,6 +310,13 @@ pub struct Opts {
     #[clap(long, default_value = "443", action)]
     pub https_port: u16,
 
+    /// Also serve HTTP/3 over QUIC on the UDP port of the same number as the HTTPS port,
+    /// and advertise it in responses' `Alt-Svc` header. QUIC connections carry no PROXY
+    /// protocol header, so a load balancer in front must pass UDP through with clients'
+    /// addresses intact.
+    #[clap(long, action)]
+    pub http3: bool,
+
     /// Path to read private key from.
     #[clap(long, action)]
     pub https_private_key: Option<PathBuf>,
@@ -42,10 +329,76 @@ pub struct Opts {
     #[clap(long, action)]
     pub nats_url: Option<String>,
 
-    /// Server to use for certificate signing.
+    /// Directory URL of the ACME server to request certificates from, or one of
+    /// `letsencrypt`, `letsencrypt-staging`, or `zerossl`.
     #[clap(long, action)]
     pub acme_server: Option<String>,
 
+    /// Key ID of the External Account Binding with the ACME server, for servers which
+    /// require one, e.g. ZeroSSL. Requires --acme-eab-hmac-key.
+    #[clap(long, action)]
+    pub acme_eab_key_id: Option<String>,
+
+    /// Base64url MAC key of the External Account Binding with the ACME server.
+    #[clap(long, action)]
+    pub acme_eab_hmac_key: Option<String>,
+
+    /// How the certificate refresher sets the TXT records answering DNS-01 challenges:
+    /// `nats` to ask the platform, or `route53:<hosted zone ID>`, `cloudflare:<zone ID>`,
+    /// or `google:<project>/<managed zone>` to set them with the provider's API. Route53
+    /// credentials are read from `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`, the
+    /// Cloudflare API token from `CLOUDFLARE_API_TOKEN`, and Google Cloud credentials from
+    /// `GOOGLE_APPLICATION_CREDENTIALS` or the instance's metadata server.
+    #[clap(long, default_value = "nats", action)]
+    pub dns_provider: DnsProviderKind,
+
+    /// The type of ACME challenge the certificate refresher answers: `dns-01` for a
+    /// wildcard certificate, or `http-01` for a certificate for the cluster domain itself,
+    /// answered by the cluster's proxies, for clusters which can't delegate DNS and so
+    /// route requests by path.
+    #[clap(long, default_value = "dns-01", action)]
+    pub acme_challenge: ChallengeType,
+
+    /// Distribute certificates over NATS: the certificate refresher publishes each
+    /// certificate it obtains, and proxies serve those published for their clusters,
+    /// writing them to their certificate paths. Proxies switch to a new certificate once
+    /// both it and its key have been received, without dropping open connections.
+    #[clap(long, action)]
+    pub distribute_certs: bool,
+
+    /// Where certificates are stored, so that a drone on a new host recovers its
+    /// cluster's: `file:<directory>`, `nats` for a NATS key-value bucket, or `kubernetes`
+    /// or `kubernetes:<namespace>` for Kubernetes TLS Secrets. The certificate refresher
+    /// saves each certificate it obtains, and it and the proxy restore the stored
+    /// certificate to the certificate paths at startup if it is newer.
+    #[clap(long, action)]
+    pub cert_store: Option<CertStoreKind>,
+
+    /// Path of the key of the ACME account the certificate refresher requests
+    /// certificates with, generated if it doesn't exist (or restored from the certificate
+    /// store, if there is one). Defaults to `acme-account.key` beside --https-private-key.
+    #[clap(long, action)]
+    pub acme_account_key: Option<PathBuf>,
+
+    /// How many days before its certificate expires the certificate refresher begins
+    /// publishing alerts over NATS (on `cluster.<domain>.certificate.alert`). Renewal
+    /// begins 60 to 75% of the way through a certificate's lifetime, so this is only
+    /// reached if renewals are failing.
+    #[clap(long, default_value = "7", action)]
+    pub cert_danger_window_days: u64,
+
+    /// Type of key of the certificates the certificate refresher requests: `rsa` or
+    /// `ecdsa` (P-256).
+    #[clap(long, default_value = "rsa", action)]
+    pub cert_key_type: KeyType,
+
+    /// Also keep a certificate with the other type of key, beside each cluster's at its
+    /// certificate paths with the key type appended (e.g. `cert-rsa.pem`). The
+    /// certificate refresher requests both, and proxies serve whichever the client
+    /// supports, preferring the cluster's own.
+    #[clap(long, action)]
+    pub dual_certs: bool,
+
     /// Public IP of this drone, used for directing traffic outside the host.
     #[clap(long, action)]
     pub ip: Option<IpAddr>,
@@ -64,7 +417,235 @@ pub struct Opts {
     #[clap(long, action)]
     pub docker_runtime: Option<String>,
 
-    /// Unix socket through which to send Docker commands.
+    /// Host directory under which spawn requests may bind-mount paths into backend
+    /// containers. May be repeated. Bind mounts are refused if not provided.
+    #[clap(long, action)]
+    pub bind_mount_source: Vec<PathBuf>,
+
+    /// Port that backend containers listen on, unless their spawn request specifies otherwise.
+    #[clap(long, default_value = "8080", action)]
+    pub container_port: u16,
+
+    /// Name of an existing Docker network to attach backend containers to, unless
+    /// their spawn request specifies otherwise. Mutually exclusive with --isolate-backend-networks.
+    #[clap(long, action)]
+    pub docker_network: Option<String>,
+
+    /// Create a separate network for each backend, unless its spawn request specifies otherwise.
+    #[clap(long, action)]
+    pub isolate_backend_networks: bool,
+
+    /// Path to a JSON file mapping registry hostnames (e.g. "ghcr.io" or "docker.io") to
+    /// the credentials used to pull images from them, in the form
+    /// `{"ghcr.io": {"username": "...", "password": "..."}}`.
+    #[clap(long, action)]
+    pub registry_credentials: Option<PathBuf>,
+
+    /// Path to a seccomp profile (JSON) to apply to backend containers.
+    #[clap(long, action)]
+    pub seccomp_profile: Option<PathBuf>,
+
+    /// Name of an AppArmor profile to apply to backend containers.
+    #[clap(long, action)]
+    pub apparmor_profile: Option<String>,
+
+    /// Let spawn requests replace the seccomp and AppArmor profiles of their containers,
+    /// including with `unconfined`. Otherwise, spawn requests which ask for a different
+    /// profile from the drone's are refused.
+    #[clap(long, action)]
+    pub allow_profile_overrides: bool,
+
+    /// Prevent processes in backend containers from gaining privileges, e.g. through
+    /// setuid binaries.
+    #[clap(long, action)]
+    pub no_new_privileges: bool,
+
+    /// Linux capability to drop from every backend container, e.g. NET_RAW. May be repeated.
+    #[clap(long, action)]
+    pub cap_drop: Vec<String>,
+
+    /// Refuse to run backend containers as root, either because the spawn request asks for
+    /// it or because it is the image's default user.
+    #[clap(long, action)]
+    pub forbid_root: bool,
+
+    /// Default `ulimit` for backend containers, as `<name>=<soft>[:<hard>]` where the name is
+    /// `nofile`, `nproc`, or `core`. Spawn requests may override it. May be repeated.
+    #[clap(long, value_parser = parse_ulimit)]
+    pub ulimit: Vec<(String, Ulimit)>,
+
+    /// Largest /dev/shm size, in bytes, that spawn requests may ask for. Unlimited if not
+    /// provided.
+    #[clap(long, action)]
+    pub max_shm_size: Option<i64>,
+
+    /// Run an init process (e.g. tini) as PID 1 of backend containers to reap zombie
+    /// processes, unless their spawn request says otherwise.
+    #[clap(long, action)]
+    pub init: bool,
+
+    /// Maximum number of processes and threads in each backend container, unless its
+    /// spawn request overrides it. Zero means unlimited.
+    #[clap(long, default_value = "4096", action)]
+    pub pids_limit: i64,
+
+    /// Largest PID limit that spawn requests may ask for. Unlimited if not provided.
+    #[clap(long, action)]
+    pub max_pids_limit: Option<i64>,
+
+    /// Image to pull when the agent starts and keep up-to-date, so that backends using it
+    /// start without waiting on a pull. May be repeated.
+    #[clap(long, action)]
+    pub prepull_image: Vec<String>,
+
+    /// Number of NVIDIA GPUs on this host which may be dedicated to backends.
+    #[clap(long, default_value = "0", action)]
+    pub gpu_count: u32,
+
+    /// Destination to forward backend logs to: `nats`, `stdout`, `file:<directory>`, or an
+    /// HTTP(S) URL to POST each line to as JSON. May be repeated. Defaults to `nats`.
+    #[clap(long, action)]
+    pub log_sink: Vec<LogSink>,
+
+    /// Keep warm containers of an image, as `<image>=<count>`, which spawn requests for the
+    /// image can claim instead of creating a container. The image must implement the claim
+    /// handshake. May be repeated.
+    #[clap(long, action)]
+    pub warm_pool: Vec<WarmPoolConfig>,
+
+    /// Allow commands to be run inside backend containers through exec requests, for
+    /// debugging. Anyone who can publish to the drone's NATS subjects can then run
+    /// commands in its backends.
+    #[clap(long, action)]
+    pub allow_exec: bool,
+
+    /// How often to publish the resource usage of each running backend, in seconds. Zero
+    /// disables resource usage reports.
+    #[clap(long, default_value = "10", action)]
+    pub stats_interval_secs: u64,
+
+    /// How long a backend may go without requests or open connections through the proxy
+    /// before it is terminated, in seconds, unless its spawn request sets `max_idle_secs`.
+    #[clap(long, default_value = "300", action)]
+    pub idle_timeout_secs: u64,
+
+    /// The most backends to run at once. Spawn requests beyond it are refused, so that the
+    /// controller can place the backend on another drone. Unlimited if not provided.
+    #[clap(long, action)]
+    pub max_backends: Option<u32>,
+
+    /// Label of this drone, as `<key>=<value>` (e.g. `region=eu` or `gpu=true`), which the
+    /// scheduler matches spawn requests' drone selectors against. May be repeated.
+    #[clap(long, value_parser = parse_label)]
+    pub drone_label: Vec<(String, String)>,
+
+    /// Attempts to make at pulling a backend's image, and at creating its container,
+    /// before the backend fails to load.
+    #[clap(long, default_value = "3", action)]
+    pub spawn_attempts: u32,
+
+    /// Wait after the first failed attempt at pulling an image or creating a container,
+    /// in milliseconds. The wait doubles after each further failure, up to 30 seconds.
+    #[clap(long, default_value = "1000", action)]
+    pub spawn_retry_delay_ms: u64,
+
+    /// How long requests and WebSocket connections to a stopping backend have to finish
+    /// before its container is stopped, in seconds. The proxy sends the backend no new
+    /// requests in the meantime. Zero stops backends immediately.
+    #[clap(long, default_value = "10", action)]
+    pub drain_timeout_secs: u64,
+
+    /// Refuse to start backends from images which are not referenced by digest.
+    #[clap(long, action)]
+    pub require_image_digest: bool,
+
+    /// Refuse to start backends from images which are not signed with this key, as
+    /// verified by `cosign verify --key`. Implies --require-image-digest.
+    #[clap(long, action)]
+    pub cosign_key: Option<String>,
+
+    /// Path of the `cosign` binary used to verify image signatures. Defaults to `cosign`
+    /// on the PATH.
+    #[clap(long, action)]
+    pub cosign_path: Option<PathBuf>,
+
+    /// URL to POST to when a backend becomes ready, terminates, or fails. May be repeated.
+    #[clap(long, action)]
+    pub webhook_url: Vec<Url>,
+
+    /// Secret to sign webhook payloads with, using HMAC-SHA256. The signature is sent in
+    /// the `X-Spawner-Signature` header.
+    #[clap(long, action)]
+    pub webhook_secret: Option<String>,
+
+    /// Address to serve the agent's status on, as JSON at `/status`, e.g. `127.0.0.1:9090`.
+    /// The endpoint is unauthenticated, so it should only be bound to a private address.
+    #[clap(long, action)]
+    pub status_address: Option<SocketAddr>,
+
+    /// JSON file mapping secret names to values. Spawn requests can set an environment
+    /// variable to `secret://<name>` to have it replaced with the secret's value.
+    #[clap(long, action)]
+    pub secrets_file: Option<PathBuf>,
+
+    /// Address of a Vault server. Spawn requests can set an environment variable to
+    /// `secret://vault/<mount>/<path>#<field>` to have it replaced with a field of a KV
+    /// version 2 secret.
+    #[clap(long, action)]
+    pub vault_address: Option<Url>,
+
+    /// File containing the token used to read secrets from Vault.
+    #[clap(long, action)]
+    pub vault_token_file: Option<PathBuf>,
+
+    /// Prefix for the names of the agent's containers, so that agents of several clusters
+    /// can share a container engine. Agents only manage containers in their own namespace.
+    /// May contain letters, digits, and underscores.
+    #[clap(long, value_parser = parse_namespace)]
+    pub namespace: Option<String>,
+
+    /// Evict backends while the host has less than this much memory available, in
+    /// megabytes, rather than leaving the kernel to kill processes when it runs out.
+    #[clap(long, action)]
+    pub eviction_min_memory_mb: Option<u64>,
+
+    /// Evict backends while the filesystem containing --eviction-disk-path has less than
+    /// this much space available, in megabytes.
+    #[clap(long, action)]
+    pub eviction_min_disk_mb: Option<u64>,
+
+    /// Path on the filesystem whose available space is checked for eviction, typically
+    /// the container engine's data directory.
+    #[clap(long, default_value = "/var/lib/docker", action)]
+    pub eviction_disk_path: PathBuf,
+
+    /// Which backend to evict first: `oldest-idle`, the backend with the longest time
+    /// since proxy activity, or `lowest-priority`, the backend with the lowest `priority`
+    /// in its spawn request.
+    #[clap(long, default_value = "oldest-idle", action)]
+    pub eviction_policy: EvictionPolicy,
+
+    /// Container engine to run backends with: `docker`, `podman`, `containerd`, or
+    /// `firecracker`. Podman is driven through its Docker-compatible API, and containerd
+    /// through `nerdctl`. Firecracker runs each backend in its own microVM.
+    #[clap(long, default_value = "docker", action)]
+    pub engine: EngineKind,
+
+    /// Uncompressed Linux kernel for Firecracker microVMs. Required with --engine firecracker.
+    #[clap(long, action)]
+    pub firecracker_kernel: Option<PathBuf>,
+
+    /// Path of the firecracker binary.
+    #[clap(long, default_value = "firecracker", action)]
+    pub firecracker_binary: PathBuf,
+
+    /// Directory in which to store converted images and the files of each microVM.
+    #[clap(long, default_value = "/var/lib/spawner/firecracker", action)]
+    pub firecracker_dir: PathBuf,
+
+    /// Unix socket through which to send Docker (or containerd) commands. Defaults to the engine's
+    /// system-wide socket; rootless Podman's socket is usually
+    /// $XDG_RUNTIME_DIR/podman/podman.sock.
     #[clap(long, action)]
     pub docker_socket: Option<String>,
 
@@ -84,6 +665,13 @@ enum Command {
     /// Refresh the certificate, and the exit.
     Cert,
 
+    /// Print every state entered by the backends the agent has managed, and then exit.
+    History {
+        /// Only print the history of this backend.
+        #[clap(long, action)]
+        backend: Option<String>,
+    },
+
     /// Run one or more components as a service, indefinitely. Components are selected with --proxy, --agent, and --refresh.
     Serve {
         /// Run the proxy server.
@@ -116,6 +704,36 @@ pub struct CertOptions {
     pub nats: NatsConnection,
     pub key_paths: KeyCertPathPair,
     pub acme_server_url: String,
+
+    /// Binds the ACME account to an account held with the CA, if the server requires it.
+    pub acme_eab: Option<ExternalAccountBinding>,
+
+    /// Sets the TXT records answering DNS-01 challenges.
+    pub dns_provider: DnsProviderKind,
+    pub challenge: ChallengeType,
+
+    /// Publish each certificate obtained to the cluster's proxies over NATS.
+    pub distribute: bool,
+
+    /// Where each certificate obtained is saved, and restored from.
+    pub store: Option<CertStoreKind>,
+
+    /// Where the ACME account key is kept, so that the same account is reused.
+    pub account_key_path: PathBuf,
+
+    /// How long before the certificate expires alerts are published.
+    pub danger_window: Duration,
+
+    /// DNS over HTTPS resolver which custom domains' CNAMEs are checked with, if
+    /// certificates are issued for backends' custom domains.
+    pub custom_domain_resolver: Option<String>,
+
+    /// Type of key of the certificates requested.
+    pub key_type: KeyType,
+
+    /// Types of key of the certificates requested beside each of `key_type`, kept at the
+    /// certificate paths with the key type appended.
+    pub alternate_key_types: Vec<KeyType>,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -131,6 +749,10 @@ pub enum DronePlan {
         db: DatabaseConnection,
     },
     DoCertificateRefresh(CertOptions),
+    ShowHistory {
+        db: DatabaseConnection,
+        backend: Option<BackendId>,
+    },
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -152,6 +774,49 @@ impl IpProvider {
     }
 }
 
+/// Parse a `--namespace` flag. Hyphens are excluded so that a namespace can't be mistaken
+/// for the start of a container name in another namespace.
+fn parse_namespace(s: &str) -> Result<String> {
+    if !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Ok(s.to_string())
+    } else {
+        Err(anyhow!(
+            "Expected namespace to contain only letters, digits, and underscores, got {:?}.",
+            s
+        ))
+    }
+}
+
+fn parse_label(s: &str) -> Result<(String, String)> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(anyhow!("Expected label to be <key>=<value>, got {:?}.", s)),
+    }
+}
+
+/// Parse a `--ulimit` flag. As with `docker run`, the hard limit defaults to the soft limit.
+fn parse_ulimit(s: &str) -> Result<(String, Ulimit)> {
+    let (name, limits) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected ulimit to be <name>=<soft>[:<hard>], got {:?}.", s))?;
+    if !["nofile", "nproc", "core"].contains(&name) {
+        return Err(anyhow!(
+            "Expected ulimit name to be nofile, nproc, or core, got {:?}.",
+            name
+        ));
+    }
+
+    let (soft, hard) = match limits.split_once(':') {
+        Some((soft, hard)) => (soft.parse()?, hard.parse()?),
+        None => {
+            let limit = limits.parse()?;
+            (limit, limit)
+        }
+    };
+
+    Ok((name.to_string(), Ulimit { soft, hard }))
+}
+
 impl From<Opts> for DronePlan {
     fn from(opts: Opts) -> Self {
         let key_cert_pair = if let (Some(private_key_path), Some(certificate_path)) =
@@ -173,6 +838,46 @@ impl From<Opts> for DronePlan {
 
             None
         };
+        let acme_account_key = opts.acme_account_key.clone().or_else(|| {
+            key_cert_pair.as_ref().map(|key_cert_pair| {
+                key_cert_pair
+                    .private_key_path
+                    .with_file_name(DEFAULT_ACME_ACCOUNT_KEY)
+            })
+        });
+        let alternate_key_types: Vec<KeyType> = if opts.dual_certs {
+            KeyType::ALL
+                .into_iter()
+                .filter(|key_type| *key_type != opts.cert_key_type)
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let acme_eab = match (opts.acme_eab_key_id.clone(), opts.acme_eab_hmac_key.clone()) {
+            (Some(key_id), Some(hmac_key)) => Some(ExternalAccountBinding { key_id, hmac_key }),
+            (None, None) => None,
+            _ => panic!("Expected both --acme-eab-key-id and --acme-eab-hmac-key, or neither."),
+        };
+
+        let backend_ca = if let (Some(private_key_path), Some(certificate_path)) =
+            (&opts.backend_ca_private_key, &opts.backend_ca_certificate)
+        {
+            Some(KeyCertPathPair {
+                certificate_path: certificate_path.clone(),
+                private_key_path: private_key_path.clone(),
+            })
+        } else {
+            assert!(
+                opts.backend_ca_private_key.is_none(),
+                "Expected --backend-ca-certificate if --backend-ca-private-key is provided."
+            );
+            assert!(
+                opts.backend_ca_certificate.is_none(),
+                "Expected --backend-ca-private-key if --backend-ca-certificate is provided."
+            );
+
+            None
+        };
 
         let nats = opts
             .nats_url
@@ -186,21 +891,45 @@ impl From<Opts> for DronePlan {
             Command::Migrate => DronePlan::DoMigration {
                 db: db.expect("Expected --db-path when using migrate."),
             },
+            Command::History { backend } => DronePlan::ShowHistory {
+                db: db.expect("Expected --db-path when using history."),
+                backend: backend.map(BackendId::new),
+            },
             Command::Cert => {
                 DronePlan::DoCertificateRefresh(CertOptions {
                     cluster_domain: opts.cluster_domain.expect("Expected --cluster-domain when using cert command."),
                     nats: nats.expect("Expected --nats-host when using cert command."),
                     key_paths: key_cert_pair.expect("Expected --https-certificate and --https-private-key to point to location to write cert and key."),
-                    acme_server_url: opts.acme_server.expect("Expected --acme-server when using cert command."),
+                    acme_server_url: acme_directory_url(&opts.acme_server.expect("Expected --acme-server when using cert command.")),
+                    acme_eab,
+                    dns_provider: opts.dns_provider,
+                    challenge: opts.acme_challenge,
+                    distribute: opts.distribute_certs,
+                    store: opts.cert_store,
+                    account_key_path: acme_account_key.expect("Expected --https-private-key or --acme-account-key when using cert command."),
+                    danger_window: Duration::from_secs(opts.cert_danger_window_days * 24 * 3600),
+                    custom_domain_resolver: None,
+                    key_type: opts.cert_key_type,
+                    alternate_key_types,
                 })
             },
             Command::Serve { proxy, agent, cert_refresh } => {
                 let cert_options = if cert_refresh {
                     Some(CertOptions {
-                        acme_server_url: opts.acme_server.clone().expect("Expected --acme-server for certificate refreshing."),
+                        acme_server_url: acme_directory_url(&opts.acme_server.clone().expect("Expected --acme-server for certificate refreshing.")),
+                        acme_eab,
                         cluster_domain: opts.cluster_domain.clone().expect("Expected --cluster-domain for certificate refreshing."),
                         key_paths: key_cert_pair.clone().expect("Expected --https-certificate and --https-private-key for certificate refresh."),
                         nats: nats.clone().expect("Expected --nats-url."),
+                        dns_provider: opts.dns_provider.clone(),
+                        challenge: opts.acme_challenge,
+                        distribute: opts.distribute_certs,
+                        store: opts.cert_store.clone(),
+                        account_key_path: acme_account_key.expect("Expected --https-private-key or --acme-account-key for certificate refresh."),
+                        danger_window: Duration::from_secs(opts.cert_danger_window_days * 24 * 3600),
+                        custom_domain_resolver: opts.custom_domains.then(|| opts.dns_over_https_url.clone()),
+                        key_type: opts.cert_key_type,
+                        alternate_key_types: alternate_key_types.clone(),
                     })
                 } else {
                     None
@@ -210,6 +939,7 @@ impl From<Opts> for DronePlan {
                     let https_options = key_cert_pair.map(|key_cert_pair| ProxyHttpsOptions {
                         key_paths: key_cert_pair,
                         port: opts.https_port,
+                        http3: opts.http3,
                     });
 
                     Some(ProxyOptions {
@@ -221,6 +951,65 @@ impl From<Opts> for DronePlan {
                             .expect("Expected --db-path for serving proxy."),
                         http_port: opts.http_port,
                         https_options,
+                        additional_clusters: opts.additional_cluster,
+                        host_certs: opts.host_cert,
+                        nats: if opts.route_updates || opts.publish_activity || matches!(opts.access_log, Some(AccessLogSink::Nats(_))) {
+                            Some(nats.clone().expect("Expected --nats-url for route updates, activity, or access logs published to NATS."))
+                        } else if opts.route_cache_ttl_secs > 0 {
+                            // Optional: without NATS, cached routes only expire.
+                            nats.clone()
+                        } else {
+                            None
+                        },
+                        route_updates: opts.route_updates,
+                        publish_activity: opts.publish_activity,
+                        metrics_address: opts.proxy_metrics_address,
+                        health_address: opts.proxy_health_address,
+                        error_pages: opts.error_page,
+                        hold_starting: Duration::from_secs(opts.hold_starting_secs),
+                        starting_page: opts.starting_page,
+                        path_routing: opts.path_routing,
+                        connection_limit_status: opts.connection_limit_status,
+                        route_cache_ttl: Duration::from_secs(opts.route_cache_ttl_secs),
+                        request_limits: RequestLimits {
+                            max_body_bytes: opts.max_request_body_bytes,
+                            max_header_bytes: opts.max_request_header_bytes,
+                            max_uri_length: opts.max_uri_length,
+                        },
+                        upstream_timeouts: UpstreamTimeouts {
+                            connect: Duration::from_secs(opts.upstream_connect_timeout_secs),
+                            read: Duration::from_secs(opts.upstream_read_timeout_secs),
+                            idle: Duration::from_secs(opts.upstream_idle_timeout_secs),
+                            streaming_idle: Duration::from_secs(opts.streaming_idle_timeout_secs),
+                        },
+                        affinity_cookie: opts.affinity_cookie,
+                        trace_spans: opts.trace_spans,
+                        ocsp_stapling: opts.ocsp_stapling,
+                        alternate_key_types,
+                        acme_http_challenges: opts.acme_http_challenges,
+                        custom_domains: opts.custom_domains,
+                        receive_certs: opts.distribute_certs,
+                        cert_store: opts.cert_store,
+                        proxy_protocol: ProxyProtocol {
+                            accept: opts.accept_proxy_protocol,
+                            send: opts.send_proxy_protocol,
+                        },
+                        backend_rate_limit: opts.backend_rate_limit,
+                        client_rate_limit: opts.client_rate_limit,
+                        tcp_port_range: opts.tcp_port_range,
+                        udp_port_range: opts.udp_port_range,
+                        backend_ca: backend_ca.clone(),
+                        access_log: opts.access_log,
+                        cors_config: opts.cors_config,
+                        forwarded_headers: opts.forwarded_headers,
+                        compression: opts.compress,
+                        static_sites: opts.static_site,
+                        jwt: opts.jwks_url.map(|jwks_url| JwtOptions {
+                            jwks_url,
+                            issuer: opts.jwt_issuer,
+                            audience: opts.jwt_audience,
+                            backend_claim: opts.jwt_backend_claim,
+                        }),
                     })
                 } else {
                     None
@@ -232,9 +1021,35 @@ impl From<Opts> for DronePlan {
                     } else if let Some(docker_http) = opts.docker_http {
                         DockerApiTransport::Http(docker_http)
                     } else {
-                        DockerApiTransport::default()
+                        opts.engine.default_transport()
+                    };
+
+                    let firecracker_options = if opts.engine == EngineKind::Firecracker {
+                        Some(FirecrackerOptions {
+                            firecracker_path: opts.firecracker_binary,
+                            kernel_image_path: opts.firecracker_kernel.expect("Expected --firecracker-kernel for running backends with Firecracker."),
+                            work_directory: opts.firecracker_dir,
+                        })
+                    } else {
+                        None
+                    };
+
+                    let default_network = match (opts.docker_network, opts.isolate_backend_networks) {
+                        (Some(_), true) => panic!("Expected at most one of --docker-network and --isolate-backend-networks."),
+                        (Some(network_name), false) => BackendNetwork::Named(network_name),
+                        (None, true) => BackendNetwork::Isolated,
+                        (None, false) => BackendNetwork::Default,
                     };
 
+                    let mut ulimits = Ulimits::default();
+                    for (name, ulimit) in opts.ulimit {
+                        match name.as_str() {
+                            "nofile" => ulimits.nofile = Some(ulimit),
+                            "nproc" => ulimits.nproc = Some(ulimit),
+                            _ => ulimits.core = Some(ulimit),
+                        }
+                    }
+
                     let ip = if let Some(ip) = opts.ip {
                         IpProvider::Literal(ip)
                     } else if let Some(ip_api) = opts.ip_api {
@@ -247,11 +1062,85 @@ impl From<Opts> for DronePlan {
                         cluster_domain: opts.cluster_domain.clone().expect("Expected --cluster-domain for running agent."),
                         db: db.expect("Expected --db-path for running agent."),
                         docker_options: DockerOptions {
+                            engine: opts.engine,
                             runtime: opts.docker_runtime.clone(),
+                            bind_mount_sources: opts.bind_mount_source.clone(),
                             transport: docker_transport,
+                            default_container_port: opts.container_port,
+                            default_network,
+                            gpu_count: opts.gpu_count,
+                            registry_credentials_path: opts.registry_credentials,
+                            seccomp_profile_path: opts.seccomp_profile,
+                            apparmor_profile: opts.apparmor_profile,
+                            allow_profile_overrides: opts.allow_profile_overrides,
+                            no_new_privileges: opts.no_new_privileges,
+                            cap_drop: opts.cap_drop,
+                            forbid_root: opts.forbid_root,
+                            ulimits,
+                            max_shm_size_bytes: opts.max_shm_size,
+                            init: opts.init,
+                            pids_limit: (opts.pids_limit > 0).then_some(opts.pids_limit),
+                            max_pids_limit: opts.max_pids_limit,
+                            firecracker_options,
                         },
                         nats: nats.clone().expect("Expected --nats-url for running agent."),
                         ip,
+                        prepull_images: opts.prepull_image,
+                        log_sinks: if opts.log_sink.is_empty() {
+                            vec![LogSink::Nats]
+                        } else {
+                            opts.log_sink
+                        },
+                        warm_pools: opts.warm_pool,
+                        allow_exec: opts.allow_exec,
+                        stats_interval: (opts.stats_interval_secs > 0)
+                            .then(|| Duration::from_secs(opts.stats_interval_secs)),
+                        idle_timeout: Duration::from_secs(opts.idle_timeout_secs),
+                        max_backends: opts.max_backends,
+                        spawn_retry_policy: RetryPolicy {
+                            max_attempts: opts.spawn_attempts.max(1),
+                            initial_delay: Duration::from_millis(opts.spawn_retry_delay_ms),
+                            max_delay: MAX_SPAWN_RETRY_DELAY,
+                        },
+                        drain_timeout: Duration::from_secs(opts.drain_timeout_secs),
+                        image_policy: ImagePolicy {
+                            require_digest: opts.require_image_digest,
+                            cosign_key: opts.cosign_key,
+                            cosign_path: opts.cosign_path,
+                        },
+                        webhooks: Webhooks {
+                            urls: opts.webhook_url,
+                            secret: opts.webhook_secret,
+                        },
+                        status_address: opts.status_address,
+                        namespace: opts.namespace,
+                        tcp_port_range: opts.tcp_port_range,
+                        udp_port_range: opts.udp_port_range,
+                        backend_ca,
+                        eviction: (opts.eviction_min_memory_mb.is_some()
+                            || opts.eviction_min_disk_mb.is_some())
+                        .then(|| EvictionOptions {
+                            policy: opts.eviction_policy,
+                            min_available_memory_bytes: opts
+                                .eviction_min_memory_mb
+                                .map(|mb| mb * 1024 * 1024),
+                            min_available_disk_bytes: opts
+                                .eviction_min_disk_mb
+                                .map(|mb| mb * 1024 * 1024),
+                            disk_path: opts.eviction_disk_path,
+                        }),
+                        secrets: SecretSources {
+                            file: opts.secrets_file,
+                            vault: match (opts.vault_address, opts.vault_token_file) {
+                                (Some(address), Some(token_path)) => {
+                                    Some(VaultOptions { address, token_path })
+                                }
+                                (None, None) => None,
+                                _ => panic!("Expected --vault-address and --vault-token-file to be provided together."),
+                            },
+                        },
+                        custom_domain_resolver: opts.custom_domains.then(|| opts.dns_over_https_url.clone()),
+                        labels: opts.drone_label.into_iter().collect(),
 
                         host_ip: opts.host_ip.expect("Expected --host-ip for running agent.")
                     })
@@ -273,12 +1162,17 @@ impl From<Opts> for DronePlan {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::drone::proxy::{
+        error_pages::{ErrorClass, ErrorPage},
+        forwarded::ForwardedHeadersMode,
+    };
     use anyhow::Result;
+    use std::collections::HashMap;
 
     fn parse_args(args: &[&str]) -> Result<DronePlan> {
         let mut full_args = vec!["drone"];
         full_args.extend(args.iter());
-        Ok(Opts::try_parse_from(full_args)?.try_into()?)
+        Ok(Opts::try_parse_from(full_args)?.into())
     }
 
     #[test]
@@ -292,6 +1186,25 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_history() {
+        let opts = parse_args(&[
+            "--db-path",
+            "mydatabase",
+            "history",
+            "--backend",
+            "mybackend",
+        ])
+        .unwrap();
+        assert_eq!(
+            DronePlan::ShowHistory {
+                db: DatabaseConnection::new("mydatabase".to_string()),
+                backend: Some(BackendId::new("mybackend".to_string())),
+            },
+            opts
+        );
+    }
+
     #[test]
     fn test_cert() {
         let opts = parse_args(&[
@@ -319,6 +1232,16 @@ mod test {
                     certificate_path: PathBuf::from("mycert.cert"),
                 },
                 acme_server_url: "https://acme.server/dir".to_string(),
+                acme_eab: None,
+                dns_provider: DnsProviderKind::Nats,
+                challenge: ChallengeType::Dns01,
+                distribute: false,
+                store: None,
+                account_key_path: PathBuf::from("acme-account.key"),
+                danger_window: Duration::from_secs(7 * 24 * 3600),
+                custom_domain_resolver: None,
+                key_type: KeyType::Rsa,
+                alternate_key_types: Vec::new(),
             }),
             opts
         );
@@ -342,6 +1265,41 @@ mod test {
                     cluster_domain: "mycluster.test".to_string(),
                     http_port: 80,
                     https_options: None,
+                    additional_clusters: vec![],
+                    host_certs: vec![],
+                    access_log: None,
+                    cors_config: None,
+                    forwarded_headers: vec![],
+                    compression: vec![],
+                    static_sites: vec![],
+                    route_updates: false,
+                    publish_activity: false,
+                    metrics_address: None,
+                    health_address: None,
+                    error_pages: Vec::new(),
+                    hold_starting: Duration::ZERO,
+                    starting_page: false,
+                    path_routing: false,
+                    connection_limit_status: StatusCode::SERVICE_UNAVAILABLE,
+                    route_cache_ttl: Duration::ZERO,
+                    request_limits: RequestLimits::default(),
+                    upstream_timeouts: UpstreamTimeouts::default(),
+                    affinity_cookie: None,
+                    trace_spans: false,
+                    ocsp_stapling: false,
+                    alternate_key_types: Vec::new(),
+                    acme_http_challenges: false,
+                    custom_domains: false,
+                    receive_certs: false,
+                    cert_store: None,
+                    proxy_protocol: ProxyProtocol::default(),
+                    backend_rate_limit: None,
+                    client_rate_limit: None,
+                    tcp_port_range: None,
+                    udp_port_range: None,
+                    backend_ca: None,
+                    jwt: None,
+                    nats: None,
                 }),
                 agent_options: None,
                 cert_options: None,
@@ -397,6 +1355,158 @@ mod test {
         .unwrap();
     }
 
+    #[test]
+    fn test_gpu_count() {
+        let opts = parse_args(&[
+            "--db-path",
+            "mydatabase",
+            "--cluster-domain",
+            "mycluster.test",
+            "--ip",
+            "123.123.123.123",
+            "--host-ip",
+            "56.56.56.56",
+            "--nats-url",
+            "nats://foo@bar",
+            "--gpu-count",
+            "2",
+            "serve",
+            "--agent",
+        ])
+        .unwrap();
+
+        if let DronePlan::RunService {
+            agent_options: Some(agent_options),
+            ..
+        } = opts
+        {
+            assert_eq!(2, agent_options.docker_options.gpu_count);
+        } else {
+            panic!("Expected agent options.");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected at most one of --docker-network")]
+    fn test_network_and_isolated_networks() {
+        parse_args(&[
+            "--db-path",
+            "mydatabase",
+            "--cluster-domain",
+            "mycluster.test",
+            "--ip",
+            "123.123.123.123",
+            "--host-ip",
+            "56.56.56.56",
+            "--nats-url",
+            "nats://foo@bar",
+            "--docker-network",
+            "backends",
+            "--isolate-backend-networks",
+            "serve",
+            "--agent",
+        ])
+        .unwrap();
+    }
+
+    #[test]
+    fn test_podman_engine() {
+        let opts = parse_args(&[
+            "--db-path",
+            "mydatabase",
+            "--cluster-domain",
+            "mycluster.test",
+            "--ip",
+            "123.123.123.123",
+            "--host-ip",
+            "56.56.56.56",
+            "--nats-url",
+            "nats://foo@bar",
+            "--engine",
+            "podman",
+            "serve",
+            "--agent",
+        ])
+        .unwrap();
+
+        if let DronePlan::RunService {
+            agent_options: Some(agent_options),
+            ..
+        } = opts
+        {
+            assert_eq!(EngineKind::Podman, agent_options.docker_options.engine);
+            assert_eq!(
+                DockerApiTransport::Socket("/run/podman/podman.sock".to_string()),
+                agent_options.docker_options.transport
+            );
+        } else {
+            panic!("Expected agent options.");
+        }
+    }
+
+    #[test]
+    fn test_firecracker_engine() {
+        let opts = parse_args(&[
+            "--db-path",
+            "mydatabase",
+            "--cluster-domain",
+            "mycluster.test",
+            "--ip",
+            "123.123.123.123",
+            "--host-ip",
+            "56.56.56.56",
+            "--nats-url",
+            "nats://foo@bar",
+            "--engine",
+            "firecracker",
+            "--firecracker-kernel",
+            "/var/lib/spawner/vmlinux",
+            "serve",
+            "--agent",
+        ])
+        .unwrap();
+
+        if let DronePlan::RunService {
+            agent_options: Some(agent_options),
+            ..
+        } = opts
+        {
+            assert_eq!(EngineKind::Firecracker, agent_options.docker_options.engine);
+            assert_eq!(
+                Some(FirecrackerOptions {
+                    firecracker_path: PathBuf::from("firecracker"),
+                    kernel_image_path: PathBuf::from("/var/lib/spawner/vmlinux"),
+                    work_directory: PathBuf::from("/var/lib/spawner/firecracker"),
+                }),
+                agent_options.docker_options.firecracker_options
+            );
+        } else {
+            panic!("Expected agent options.");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected --firecracker-kernel")]
+    fn test_firecracker_engine_no_kernel() {
+        parse_args(&[
+            "--db-path",
+            "mydatabase",
+            "--cluster-domain",
+            "mycluster.test",
+            "--ip",
+            "123.123.123.123",
+            "--host-ip",
+            "56.56.56.56",
+            "--nats-url",
+            "nats://foo@bar",
+            "--engine",
+            "firecracker",
+            "serve",
+            "--agent",
+        ])
+        .unwrap();
+    }
+
     #[test]
     fn test_proxy_with_https() {
         let opts = parse_args(&[
@@ -430,17 +1540,95 @@ mod test {
                             private_key_path: PathBuf::from("mycert.key"),
                             certificate_path: PathBuf::from("mycert.cert"),
                         },
-                        port: 443
+                        port: 443,
+                        http3: false,
                     }),
+                    additional_clusters: vec![],
+                    host_certs: vec![],
+                    access_log: None,
+                    cors_config: None,
+                    forwarded_headers: vec![],
+                    compression: vec![],
+                    static_sites: vec![],
+                    route_updates: false,
+                    publish_activity: false,
+                    metrics_address: None,
+                    health_address: None,
+                    error_pages: Vec::new(),
+                    hold_starting: Duration::ZERO,
+                    starting_page: false,
+                    path_routing: false,
+                    connection_limit_status: StatusCode::SERVICE_UNAVAILABLE,
+                    route_cache_ttl: Duration::ZERO,
+                    request_limits: RequestLimits::default(),
+                    upstream_timeouts: UpstreamTimeouts::default(),
+                    affinity_cookie: None,
+                    trace_spans: false,
+                    ocsp_stapling: false,
+                    alternate_key_types: Vec::new(),
+                    acme_http_challenges: false,
+                    custom_domains: false,
+                    receive_certs: false,
+                    cert_store: None,
+                    proxy_protocol: ProxyProtocol::default(),
+                    backend_rate_limit: None,
+                    client_rate_limit: None,
+                    tcp_port_range: None,
+                    udp_port_range: None,
+                    backend_ca: None,
+                    jwt: None,
+                    nats: None,
                 }),
                 agent_options: Some(AgentOptions {
                     db: DatabaseConnection::new("mydatabase".to_string()),
                     cluster_domain: "mycluster.test".to_string(),
                     docker_options: DockerOptions {
+                        engine: EngineKind::Docker,
                         transport: DockerApiTransport::Socket("/var/run/docker.sock".to_string()),
                         runtime: None,
+                        bind_mount_sources: vec![],
+                        default_container_port: 8080,
+                        default_network: BackendNetwork::Default,
+                        gpu_count: 0,
+                        registry_credentials_path: None,
+                        seccomp_profile_path: None,
+                        apparmor_profile: None,
+                        allow_profile_overrides: false,
+                        no_new_privileges: false,
+                        cap_drop: vec![],
+                        forbid_root: false,
+                        ulimits: Ulimits::default(),
+                        max_shm_size_bytes: None,
+                        init: false,
+                        pids_limit: Some(4096),
+                        max_pids_limit: None,
+                        firecracker_options: None,
                     },
                     ip: IpProvider::Literal("123.123.123.123".parse().unwrap()),
+                    prepull_images: vec![],
+                    log_sinks: vec![LogSink::Nats],
+                    warm_pools: vec![],
+                    allow_exec: false,
+                    stats_interval: Some(Duration::from_secs(10)),
+                    image_policy: ImagePolicy::default(),
+                    idle_timeout: Duration::from_secs(300),
+                    max_backends: None,
+                    spawn_retry_policy: RetryPolicy {
+                        max_attempts: 3,
+                        initial_delay: Duration::from_secs(1),
+                        max_delay: Duration::from_secs(30),
+                    },
+                    drain_timeout: Duration::from_secs(10),
+                    webhooks: Webhooks::default(),
+                    status_address: None,
+                    namespace: None,
+                    tcp_port_range: None,
+                    udp_port_range: None,
+                    backend_ca: None,
+                    eviction: None,
+                    secrets: SecretSources::default(),
+                    custom_domain_resolver: None,
+                    labels: HashMap::new(),
                     host_ip: "56.56.56.56".parse().unwrap(),
                     nats: NatsConnection::new("nats://foo@bar".to_string()).unwrap(),
                 }),
@@ -462,18 +1650,187 @@ mod test {
             "mycert.cert",
             "--https-private-key",
             "mycert.key",
+            "--additional-cluster",
+            "othercluster.test=other.key,other.cert",
+            "--additional-cluster",
+            "sub.mycluster.test",
+            "--host-cert",
+            "*.corp.test=corp.key,corp.cert",
+            "--access-log",
+            "nats:access.drone",
+            "--jwks-url",
+            "https://auth.example.com/.well-known/jwks.json",
+            "--jwt-issuer",
+            "https://auth.example.com/",
+            "--jwt-backend-claim",
+            "sub",
+            "--cors-config",
+            "/etc/spawner/cors.json",
+            "--route-updates",
+            "--publish-activity",
+            "--proxy-metrics-address",
+            "127.0.0.1:9091",
+            "--proxy-health-address",
+            "127.0.0.1:9092",
+            "--error-page",
+            "not-found=/etc/spawner/404.html",
+            "--error-page",
+            "unavailable=redirect:https://status.example.com/",
+            "--hold-starting-secs",
+            "30",
+            "--starting-page",
+            "--path-routing",
+            "--connection-limit-status",
+            "409",
+            "--route-cache-ttl-secs",
+            "5",
+            "--max-request-body-bytes",
+            "10485760",
+            "--max-request-header-bytes",
+            "16384",
+            "--max-uri-length",
+            "4096",
+            "--upstream-connect-timeout-secs",
+            "5",
+            "--upstream-read-timeout-secs",
+            "30",
+            "--upstream-idle-timeout-secs",
+            "90",
+            "--streaming-idle-timeout-secs",
+            "600",
+            "--affinity-cookie",
+            "spawner_replica",
+            "--trace-spans",
+            "--ocsp-stapling",
+            "--acme-http-challenges",
+            "--custom-domains",
+            "--dns-over-https-url",
+            "https://dns.google/resolve",
+            "--cert-key-type",
+            "ecdsa",
+            "--dual-certs",
+            "--distribute-certs",
+            "--cert-store",
+            "kubernetes:spawner",
+            "--acme-account-key",
+            "/var/lib/spawner/acme-account.key",
+            "--cert-danger-window-days",
+            "3",
+            "--accept-proxy-protocol",
+            "--send-proxy-protocol",
+            "--backend-rate-limit",
+            "100,200",
+            "--client-rate-limit",
+            "10",
+            "--tcp-port-range",
+            "30000-30999",
+            "--udp-port-range",
+            "31000-31999",
+            "--backend-ca-certificate",
+            "/etc/spawner/backend-ca.cert",
+            "--backend-ca-private-key",
+            "/etc/spawner/backend-ca.key",
+            "--forwarded-headers",
+            "replace",
+            "--forwarded-headers",
+            "othercluster.test=off",
+            "--compress",
+            "text/html,application/javascript",
+            "--compress",
+            "othercluster.test=off",
+            "--static-site",
+            "othercluster.test=/var/www/othercluster",
             "--http-port",
             "12345",
             "--https-port",
             "12398",
+            "--http3",
             "--ip",
             "123.123.123.123",
             "--host-ip",
             "56.56.56.56",
+            "--bind-mount-source",
+            "/srv/backends",
+            "--bind-mount-source",
+            "/mnt/shared",
             "--nats-url",
             "nats://foo@bar",
+            "--prepull-image",
+            "ghcr.io/drifting-in-space/demo-image-drop-four",
+            "--prepull-image",
+            "ghcr.io/drifting-in-space/test-image",
+            "--log-sink",
+            "stdout",
+            "--log-sink",
+            "file:/var/log/spawner",
+            "--warm-pool",
+            "ghcr.io/drifting-in-space/test-image=2",
+            "--allow-exec",
+            "--stats-interval-secs",
+            "0",
+            "--apparmor-profile",
+            "docker-default",
+            "--allow-profile-overrides",
+            "--no-new-privileges",
+            "--cap-drop",
+            "NET_RAW",
+            "--cap-drop",
+            "MKNOD",
+            "--forbid-root",
+            "--ulimit",
+            "nofile=65536",
+            "--ulimit",
+            "core=0:-1",
+            "--max-shm-size",
+            "1073741824",
+            "--init",
+            "--require-image-digest",
+            "--idle-timeout-secs",
+            "60",
+            "--max-backends",
+            "50",
+            "--drone-label",
+            "region=eu",
+            "--drone-label",
+            "gpu=true",
+            "--spawn-attempts",
+            "5",
+            "--spawn-retry-delay-ms",
+            "250",
+            "--drain-timeout-secs",
+            "30",
+            "--cosign-key",
+            "/etc/spawner/cosign.pub",
+            "--webhook-url",
+            "https://billing.example.com/spawner",
+            "--webhook-secret",
+            "mysecret",
+            "--status-address",
+            "127.0.0.1:9090",
+            "--namespace",
+            "staging",
+            "--eviction-min-memory-mb",
+            "512",
+            "--eviction-policy",
+            "lowest-priority",
+            "--secrets-file",
+            "/etc/spawner/secrets.json",
+            "--vault-address",
+            "https://vault.example.com:8200",
+            "--vault-token-file",
+            "/run/vault/token",
+            "--pids-limit",
+            "0",
+            "--max-pids-limit",
+            "32768",
             "--acme-server",
-            "https://acme-server",
+            "zerossl",
+            "--acme-eab-key-id",
+            "kid-1",
+            "--acme-eab-hmac-key",
+            "c2VjcmV0",
+            "--dns-provider",
+            "route53:Z0123456789ABC",
         ])
         .unwrap();
         assert_eq!(
@@ -487,28 +1844,262 @@ mod test {
                             private_key_path: PathBuf::from("mycert.key"),
                             certificate_path: PathBuf::from("mycert.cert"),
                         },
-                        port: 12398
+                        port: 12398,
+                        http3: true,
+                    }),
+                    additional_clusters: vec![
+                        AdditionalCluster {
+                            cluster_domain: "othercluster.test".to_string(),
+                            key_paths: Some(KeyCertPathPair {
+                                private_key_path: PathBuf::from("other.key"),
+                                certificate_path: PathBuf::from("other.cert"),
+                            }),
+                        },
+                        AdditionalCluster {
+                            cluster_domain: "sub.mycluster.test".to_string(),
+                            key_paths: None,
+                        },
+                    ],
+                    host_certs: vec![HostCert {
+                        hostname: "*.corp.test".to_string(),
+                        key_paths: KeyCertPathPair {
+                            private_key_path: PathBuf::from("corp.key"),
+                            certificate_path: PathBuf::from("corp.cert"),
+                        },
+                    }],
+                    access_log: Some(AccessLogSink::Nats("access.drone".to_string())),
+                    cors_config: Some(PathBuf::from("/etc/spawner/cors.json")),
+                    route_updates: true,
+                    publish_activity: true,
+                    metrics_address: Some("127.0.0.1:9091".parse().unwrap()),
+                    health_address: Some("127.0.0.1:9092".parse().unwrap()),
+                    hold_starting: Duration::from_secs(30),
+                    starting_page: true,
+                    path_routing: true,
+                    connection_limit_status: StatusCode::CONFLICT,
+                    route_cache_ttl: Duration::from_secs(5),
+                    request_limits: RequestLimits {
+                        max_body_bytes: Some(10485760),
+                        max_header_bytes: Some(16384),
+                        max_uri_length: Some(4096),
+                    },
+                    upstream_timeouts: UpstreamTimeouts {
+                        connect: Duration::from_secs(5),
+                        read: Duration::from_secs(30),
+                        idle: Duration::from_secs(90),
+                        streaming_idle: Duration::from_secs(600),
+                    },
+                    affinity_cookie: Some("spawner_replica".to_string()),
+                    trace_spans: true,
+                    ocsp_stapling: true,
+                    alternate_key_types: vec![KeyType::Rsa],
+                    acme_http_challenges: true,
+                    custom_domains: true,
+                    receive_certs: true,
+                    cert_store: Some(CertStoreKind::Kubernetes {
+                        namespace: Some("spawner".to_string()),
+                    }),
+                    proxy_protocol: ProxyProtocol {
+                        accept: true,
+                        send: true,
+                    },
+                    error_pages: vec![
+                        ErrorPageRule {
+                            class: ErrorClass::NotFound,
+                            page: ErrorPage::Template(PathBuf::from("/etc/spawner/404.html")),
+                        },
+                        ErrorPageRule {
+                            class: ErrorClass::Unavailable,
+                            page: ErrorPage::Redirect(
+                                Url::parse("https://status.example.com/").unwrap(),
+                            ),
+                        },
+                    ],
+                    backend_rate_limit: Some(RateLimit {
+                        requests_per_second: 100.0,
+                        burst: 200,
+                    }),
+                    client_rate_limit: Some(RateLimit {
+                        requests_per_second: 10.0,
+                        burst: 10,
+                    }),
+                    tcp_port_range: Some(PortRange {
+                        start: 30000,
+                        end: 30999,
+                    }),
+                    udp_port_range: Some(PortRange {
+                        start: 31000,
+                        end: 31999,
+                    }),
+                    backend_ca: Some(KeyCertPathPair {
+                        private_key_path: PathBuf::from("/etc/spawner/backend-ca.key"),
+                        certificate_path: PathBuf::from("/etc/spawner/backend-ca.cert"),
+                    }),
+                    forwarded_headers: vec![
+                        ForwardedHeadersRule {
+                            cluster_domain: None,
+                            mode: ForwardedHeadersMode::Replace,
+                        },
+                        ForwardedHeadersRule {
+                            cluster_domain: Some("othercluster.test".to_string()),
+                            mode: ForwardedHeadersMode::Off,
+                        },
+                    ],
+                    compression: vec![
+                        CompressionRule {
+                            cluster_domain: None,
+                            content_types: vec![
+                                "text/html".to_string(),
+                                "application/javascript".to_string(),
+                            ],
+                        },
+                        CompressionRule {
+                            cluster_domain: Some("othercluster.test".to_string()),
+                            content_types: vec![],
+                        },
+                    ],
+                    static_sites: vec![StaticSiteRule {
+                        cluster_domain: Some("othercluster.test".to_string()),
+                        path: PathBuf::from("/var/www/othercluster"),
+                    }],
+                    jwt: Some(JwtOptions {
+                        jwks_url: Url::parse("https://auth.example.com/.well-known/jwks.json")
+                            .unwrap(),
+                        issuer: Some("https://auth.example.com/".to_string()),
+                        audience: None,
+                        backend_claim: "sub".to_string(),
                     }),
+                    nats: Some(NatsConnection::new("nats://foo@bar".to_string()).unwrap()),
                 }),
                 agent_options: Some(AgentOptions {
                     db: DatabaseConnection::new("mydatabase".to_string()),
                     cluster_domain: "mycluster.test".to_string(),
                     docker_options: DockerOptions {
+                        engine: EngineKind::Docker,
                         transport: DockerApiTransport::Socket("/var/run/docker.sock".to_string()),
                         runtime: None,
+                        bind_mount_sources: vec![
+                            PathBuf::from("/srv/backends"),
+                            PathBuf::from("/mnt/shared"),
+                        ],
+                        default_container_port: 8080,
+                        default_network: BackendNetwork::Default,
+                        gpu_count: 0,
+                        registry_credentials_path: None,
+                        seccomp_profile_path: None,
+                        apparmor_profile: Some("docker-default".to_string()),
+                        allow_profile_overrides: true,
+                        no_new_privileges: true,
+                        cap_drop: vec!["NET_RAW".to_string(), "MKNOD".to_string()],
+                        forbid_root: true,
+                        ulimits: Ulimits {
+                            nofile: Some(Ulimit {
+                                soft: 65536,
+                                hard: 65536,
+                            }),
+                            nproc: None,
+                            core: Some(Ulimit { soft: 0, hard: -1 }),
+                        },
+                        max_shm_size_bytes: Some(1073741824),
+                        init: true,
+                        pids_limit: None,
+                        max_pids_limit: Some(32768),
+                        firecracker_options: None,
                     },
                     ip: IpProvider::Literal("123.123.123.123".parse().unwrap()),
+                    prepull_images: vec![
+                        "ghcr.io/drifting-in-space/demo-image-drop-four".to_string(),
+                        "ghcr.io/drifting-in-space/test-image".to_string(),
+                    ],
+                    log_sinks: vec![
+                        LogSink::Stdout,
+                        LogSink::File(PathBuf::from("/var/log/spawner")),
+                    ],
+                    warm_pools: vec![WarmPoolConfig {
+                        image: "ghcr.io/drifting-in-space/test-image".to_string(),
+                        size: 2,
+                    }],
+                    allow_exec: true,
+                    stats_interval: None,
+                    image_policy: ImagePolicy {
+                        require_digest: true,
+                        cosign_key: Some("/etc/spawner/cosign.pub".to_string()),
+                        cosign_path: None,
+                    },
+                    idle_timeout: Duration::from_secs(60),
+                    max_backends: Some(50),
+                    spawn_retry_policy: RetryPolicy {
+                        max_attempts: 5,
+                        initial_delay: Duration::from_millis(250),
+                        max_delay: Duration::from_secs(30),
+                    },
+                    drain_timeout: Duration::from_secs(30),
+                    webhooks: Webhooks {
+                        urls: vec![Url::parse("https://billing.example.com/spawner").unwrap()],
+                        secret: Some("mysecret".to_string()),
+                    },
+                    status_address: Some("127.0.0.1:9090".parse().unwrap()),
+                    namespace: Some("staging".to_string()),
+                    tcp_port_range: Some(PortRange {
+                        start: 30000,
+                        end: 30999,
+                    }),
+                    udp_port_range: Some(PortRange {
+                        start: 31000,
+                        end: 31999,
+                    }),
+                    backend_ca: Some(KeyCertPathPair {
+                        private_key_path: PathBuf::from("/etc/spawner/backend-ca.key"),
+                        certificate_path: PathBuf::from("/etc/spawner/backend-ca.cert"),
+                    }),
+                    eviction: Some(EvictionOptions {
+                        policy: EvictionPolicy::LowestPriority,
+                        min_available_memory_bytes: Some(512 * 1024 * 1024),
+                        min_available_disk_bytes: None,
+                        disk_path: PathBuf::from("/var/lib/docker"),
+                    }),
+                    secrets: SecretSources {
+                        file: Some(PathBuf::from("/etc/spawner/secrets.json")),
+                        vault: Some(VaultOptions {
+                            address: Url::parse("https://vault.example.com:8200").unwrap(),
+                            token_path: PathBuf::from("/run/vault/token"),
+                        }),
+                    },
+                    custom_domain_resolver: Some("https://dns.google/resolve".to_string()),
+                    labels: vec![
+                        ("region".to_string(), "eu".to_string()),
+                        ("gpu".to_string(), "true".to_string()),
+                    ]
+                    .into_iter()
+                    .collect(),
                     host_ip: "56.56.56.56".parse().unwrap(),
                     nats: NatsConnection::new("nats://foo@bar".to_string()).unwrap(),
                 }),
                 cert_options: Some(CertOptions {
-                    acme_server_url: "https://acme-server".to_string(),
+                    acme_server_url: "https://acme.zerossl.com/v2/DV90".to_string(),
+                    acme_eab: Some(ExternalAccountBinding {
+                        key_id: "kid-1".to_string(),
+                        hmac_key: "c2VjcmV0".to_string(),
+                    }),
                     cluster_domain: "mycluster.test".to_string(),
                     key_paths: KeyCertPathPair {
                         private_key_path: PathBuf::from("mycert.key"),
                         certificate_path: PathBuf::from("mycert.cert"),
                     },
                     nats: NatsConnection::new("nats://foo@bar".to_string()).unwrap(),
+                    dns_provider: DnsProviderKind::Route53 {
+                        hosted_zone_id: "Z0123456789ABC".to_string(),
+                    },
+                    challenge: ChallengeType::Dns01,
+                    distribute: true,
+                    store: Some(CertStoreKind::Kubernetes {
+                        namespace: Some("spawner".to_string()),
+                    }),
+                    account_key_path: PathBuf::from("/var/lib/spawner/acme-account.key"),
+                    danger_window: Duration::from_secs(3 * 24 * 3600),
+                    custom_domain_resolver: Some("https://dns.google/resolve".to_string()),
+                    key_type: KeyType::Ecdsa,
+                    alternate_key_types: vec![KeyType::Rsa],
                 }),
                 nats: Some(NatsConnection::new("nats://foo@bar".to_string()).unwrap()),
             },
@@ -7,12 +7,14 @@ use self::{
 use crate::logging::TracingHandle;
 use crate::retry::do_with_retry;
 use anyhow::Result;
+use chrono::SecondsFormat;
 use clap::Parser;
 use futures::{future::select_all, Future};
 use signal_hook::{consts::SIGINT, iterator::Signals};
 use std::{pin::Pin, thread};
 
 mod agent;
+mod backend_tls;
 mod cert;
 pub mod cli;
 mod proxy;
@@ -21,7 +23,7 @@ async fn main() -> Result<()> {
     let mut tracing_handle = TracingHandle::init()?;
 
     let opts = Opts::parse();
-    let plan = DronePlan::try_from(opts)?;
+    let plan = DronePlan::from(opts);
 
     match plan {
         DronePlan::RunService {
@@ -56,7 +58,7 @@ async fn main() -> Result<()> {
                 futs.push(Box::pin(run_agent(agent_options)))
             }
 
-            let (result, _, _) = select_all(futs.into_iter()).await;
+            let (result, _, _) = select_all(futs).await;
             result?;
         }
         DronePlan::DoMigration { db } => {
@@ -65,15 +67,36 @@ async fn main() -> Result<()> {
         DronePlan::DoCertificateRefresh(cert_options) => {
             refresh_certificate(&cert_options).await?;
         }
+        DronePlan::ShowHistory { db, backend } => {
+            let db = db.connection().await?;
+            for record in db.get_backend_history(backend.as_ref()).await? {
+                let mut line = format!(
+                    "{}\t{}\t{}",
+                    record.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+                    record.backend_id,
+                    record.state
+                );
+                if let Some(termination) = record.termination {
+                    line.push_str(&format!("\t{:?}", termination.reason));
+                    if let Some(exit_code) = termination.exit_code {
+                        line.push_str(&format!(" (exit code {})", exit_code));
+                    }
+                    if let Some(error) = termination.error {
+                        line.push_str(&format!(": {}", error));
+                    }
+                }
+                println!("{}", line);
+            }
+        }
     }
     Ok(())
 }
 
 pub fn run() -> Result<()> {
-    let mut signals = Signals::new(&[SIGINT])?;
+    let mut signals = Signals::new([SIGINT])?;
 
     thread::spawn(move || {
-        for _ in signals.forever() {
+        if signals.forever().next().is_some() {
             // TODO: we could shut down containers here.
             std::process::exit(0)
         }
@@ -0,0 +1,164 @@
+//! Structured access logs of the requests handled by the proxy, one JSON object per
+//! request, for per-tenant usage accounting.
+
+use crate::{messages::proxy::AccessLogMessage, nats::TypedNats};
+use anyhow::{anyhow, Result};
+use hyper::{body::HttpBody, Body};
+use std::{path::PathBuf, str::FromStr};
+use tokio::{
+    fs::OpenOptions,
+    io::AsyncWriteExt,
+    sync::mpsc::{channel, Sender},
+    time::Instant,
+};
+
+/// Entries waiting to be written beyond this are dropped, so that a slow destination
+/// never holds up requests.
+const ACCESS_LOG_BUFFER: usize = 1024;
+
+/// A destination for access logs, configured at the drone level.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum AccessLogSink {
+    /// Print each entry to the proxy's stdout.
+    Stdout,
+
+    /// Append entries to the given file.
+    File(PathBuf),
+
+    /// Publish each entry to the given NATS subject.
+    Nats(String),
+}
+
+impl FromStr for AccessLogSink {
+    type Err = anyhow::Error;
+
+    /// Parses `stdout`, `file:<path>`, or `nats:<subject>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "stdout" {
+            Ok(AccessLogSink::Stdout)
+        } else if let Some(path) = s.strip_prefix("file:") {
+            Ok(AccessLogSink::File(PathBuf::from(path)))
+        } else if let Some(subject) = s.strip_prefix("nats:") {
+            Ok(AccessLogSink::Nats(subject.to_string()))
+        } else {
+            Err(anyhow!(
+                "Expected access log to be stdout, file:<path>, or nats:<subject>, got {:?}.",
+                s
+            ))
+        }
+    }
+}
+
+/// Queues access log entries to be written to a sink in the background.
+#[derive(Clone)]
+pub struct AccessLogger {
+    sender: Sender<AccessLogMessage>,
+}
+
+impl AccessLogger {
+    /// Start writing entries to the sink. `nats` is required for `AccessLogSink::Nats`.
+    pub async fn start(sink: AccessLogSink, nats: Option<TypedNats>) -> Result<Self> {
+        let (sender, mut receiver) = channel::<AccessLogMessage>(ACCESS_LOG_BUFFER);
+
+        match sink {
+            AccessLogSink::Stdout => {
+                tokio::spawn(async move {
+                    while let Some(entry) = receiver.recv().await {
+                        match serde_json::to_string(&entry) {
+                            Ok(line) => println!("{}", line),
+                            Err(error) => tracing::warn!(?error, "Error serializing access log."),
+                        }
+                    }
+                });
+            }
+            AccessLogSink::File(path) => {
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .await?;
+                tokio::spawn(async move {
+                    while let Some(entry) = receiver.recv().await {
+                        let result = async {
+                            let mut line = serde_json::to_vec(&entry)?;
+                            line.push(b'\n');
+                            file.write_all(&line).await?;
+                            Ok::<(), anyhow::Error>(())
+                        }
+                        .await;
+                        if let Err(error) = result {
+                            tracing::warn!(?error, ?path, "Error writing access log.");
+                        }
+                    }
+                });
+            }
+            AccessLogSink::Nats(subject) => {
+                let nats = nats.ok_or_else(|| anyhow!("Expected NATS for access logs."))?;
+                let subject = AccessLogMessage::subject(&subject);
+                tokio::spawn(async move {
+                    while let Some(entry) = receiver.recv().await {
+                        if let Err(error) = nats.publish(&subject, &entry).await {
+                            tracing::warn!(?error, "Error publishing access log.");
+                        }
+                    }
+                });
+            }
+        }
+
+        Ok(AccessLogger { sender })
+    }
+
+    pub fn log(&self, entry: AccessLogMessage) {
+        if self.sender.try_send(entry).is_err() {
+            tracing::warn!("Dropped access log entry.");
+        }
+    }
+
+    /// Pass the response body through, and log the entry with the number of bytes sent
+    /// once the body ends or the client goes away. Trailers are passed through too, so
+    /// gRPC responses are unaffected.
+    pub fn log_after_body(
+        &self,
+        mut body: Body,
+        entry: AccessLogMessage,
+        started: Instant,
+    ) -> Body {
+        let (mut sender, counted_body) = Body::channel();
+        let logger = self.clone();
+
+        tokio::spawn(async move {
+            let mut bytes = 0;
+            loop {
+                match body.data().await {
+                    Some(Ok(chunk)) => {
+                        bytes += chunk.len() as u64;
+                        if sender.send_data(chunk).await.is_err() {
+                            // The client has gone away.
+                            break;
+                        }
+                    }
+                    Some(Err(error)) => {
+                        tracing::warn!(?error, "Error reading response body.");
+                        sender.abort();
+                        break;
+                    }
+                    None => {
+                        if let Ok(Some(trailers)) = body.trailers().await {
+                            // An error means the client has gone away, leaving nothing to do.
+                            let _ = sender.send_trailers(trailers).await;
+                        }
+                        break;
+                    }
+                }
+            }
+
+            logger.log(AccessLogMessage {
+                bytes,
+                duration_ms: started.elapsed(),
+                ..entry
+            });
+        });
+
+        counted_body
+    }
+}
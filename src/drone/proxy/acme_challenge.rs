@@ -0,0 +1,190 @@
+//! Answers to HTTP-01 challenges at `/.well-known/acme-challenge/<token>` on cluster
+//! domains, for clusters whose certificates can't be issued with DNS-01 challenges.
+//!
+//! The proxy doesn't know which challenges are pending, so it asks the cluster's
+//! certificate refresher for the key authorization of each token requested, over NATS.
+//! Tokens no refresher has pending are not found.
+//!
+//! Challenges of backends' custom domains are for the custom domain itself, and are asked
+//! for on the custom domain's subject, which the refresher ordering its certificate
+//! answers on.
+
+use crate::{
+    drone::cert::custom_domain::is_custom_domain, messages::cert::AcmeHttpChallenge,
+    nats::TypedNats,
+};
+use anyhow::Result;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc, time::Duration};
+
+const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// How long the certificate refresher has to answer before the token is not found.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The token of a request for an HTTP-01 challenge. Tokens are base64url, so anything
+/// else is not a challenge.
+fn challenge_token(req: &Request<Body>) -> Option<&str> {
+    if req.method() != Method::GET {
+        return None;
+    }
+    let token = req.uri().path().strip_prefix(ACME_CHALLENGE_PREFIX)?;
+    if token.is_empty()
+        || !token
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    {
+        return None;
+    }
+
+    Some(token)
+}
+
+#[derive(Clone)]
+pub struct AcmeChallenges {
+    nats: TypedNats,
+    cluster_domains: Arc<Vec<String>>,
+
+    /// Whether challenges of backends' custom domains are answered too.
+    custom_domains: bool,
+}
+
+impl AcmeChallenges {
+    pub fn new(nats: TypedNats, cluster_domains: Vec<String>, custom_domains: bool) -> Self {
+        AcmeChallenges {
+            nats,
+            cluster_domains: Arc::new(cluster_domains),
+            custom_domains,
+        }
+    }
+
+    /// The domain the host's challenges are for, if they are answered.
+    fn challenge_domain<'a>(&'a self, host: &'a str) -> Option<&'a str> {
+        if let Some(cluster_domain) = self.cluster_domains.iter().find(|domain| *domain == host) {
+            return Some(cluster_domain);
+        }
+
+        (self.custom_domains && is_custom_domain(host, &self.cluster_domains)).then_some(host)
+    }
+
+    /// The response to the request, if it is for a challenge of one of the cluster
+    /// domains or a custom domain.
+    pub async fn response(
+        &self,
+        req: &Request<Body>,
+        host: &str,
+    ) -> Result<Option<Response<Body>>> {
+        let token = match challenge_token(req) {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        let domain = match self.challenge_domain(host) {
+            Some(domain) => domain,
+            None => return Ok(None),
+        };
+
+        let key_authorization = tokio::time::timeout(
+            LOOKUP_TIMEOUT,
+            self.nats.request(
+                &AcmeHttpChallenge::subject(domain),
+                &AcmeHttpChallenge {
+                    token: token.to_string(),
+                },
+            ),
+        )
+        .await;
+
+        let response = match key_authorization {
+            Ok(Ok(key_authorization)) => {
+                tracing::info!(%domain, %token, "Answered HTTP-01 challenge.");
+                Response::builder()
+                    .header(http::header::CONTENT_TYPE, "text/plain")
+                    .body(Body::from(key_authorization))?
+            }
+            result => {
+                tracing::info!(%domain, %token, ?result, "No pending HTTP-01 challenge for token.");
+                Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty())?
+            }
+        };
+
+        Ok(Some(response))
+    }
+
+    /// The response to a request on the HTTP port of a proxy serving HTTPS, where only
+    /// challenges are answered.
+    async fn challenge_only_response(&self, req: Request<Body>) -> Result<Response<Body>> {
+        let host = match req.headers().get(http::header::HOST) {
+            Some(host) => Some(std::str::from_utf8(host.as_bytes())?),
+            None => req.uri().host(),
+        };
+        if let Some(host) = host {
+            if let Some(response) = self.response(&req, host).await? {
+                return Ok(response);
+            }
+        }
+
+        Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())?)
+    }
+}
+
+/// Answer challenges, and nothing else, on the given address until the server fails.
+pub async fn serve_acme_challenges(address: SocketAddr, challenges: AcmeChallenges) -> Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let challenges = challenges.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let challenges = challenges.clone();
+                async move { challenges.challenge_only_response(req).await }
+            }))
+        }
+    });
+
+    tracing::info!(%address, "Serving HTTP-01 challenges.");
+    Server::try_bind(&address)?.serve(make_service).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(method: Method, path: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_challenge_token() {
+        assert_eq!(
+            Some("LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0"),
+            challenge_token(&request(
+                Method::GET,
+                "/.well-known/acme-challenge/LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0"
+            ))
+        );
+        assert_eq!(
+            None,
+            challenge_token(&request(Method::POST, "/.well-known/acme-challenge/abc"))
+        );
+        assert_eq!(
+            None,
+            challenge_token(&request(Method::GET, "/.well-known/acme-challenge/"))
+        );
+        assert_eq!(
+            None,
+            challenge_token(&request(Method::GET, "/.well-known/acme-challenge/a/../b"))
+        );
+        assert_eq!(None, challenge_token(&request(Method::GET, "/backend/")));
+    }
+}
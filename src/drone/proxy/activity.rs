@@ -0,0 +1,92 @@
+//! Publication of the time each backend was last active at the proxy, so that agents on
+//! drones whose database the proxy does not share, and external dashboards, have an
+//! authoritative signal of activity.
+
+use super::route_table::RouteTable;
+use crate::{
+    database::DroneDatabase, logging::LogError, messages::proxy::BackendActivityMessage,
+    nats::TypedNats, types::BackendId,
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::{collections::HashMap, time::Duration};
+use tokio::time::Instant;
+
+/// How often the proxy publishes the backends which have been active.
+const ACTIVITY_PUBLISH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Collects the backends which have been active and periodically publishes them.
+pub struct ActivityPublisher {
+    nats: TypedNats,
+    cluster_domains: Vec<String>,
+    route_table: Option<RouteTable>,
+    last_active: HashMap<BackendId, DateTime<Utc>>,
+    last_publish: Instant,
+}
+
+impl ActivityPublisher {
+    pub fn new(
+        nats: TypedNats,
+        cluster_domains: Vec<String>,
+        route_table: Option<RouteTable>,
+    ) -> Self {
+        ActivityPublisher {
+            nats,
+            cluster_domains,
+            route_table,
+            last_active: HashMap::new(),
+            last_publish: Instant::now(),
+        }
+    }
+
+    /// The backend a route belongs to, from the routes published by agents if the proxy
+    /// applies them, or otherwise from the database.
+    async fn route_backend(&self, db: &DroneDatabase, route: &str) -> Result<Option<BackendId>> {
+        if let Some(backend_id) = self
+            .route_table
+            .as_ref()
+            .and_then(|route_table| route_table.get(None, route))
+            .and_then(|route| route.backend_id)
+        {
+            return Ok(Some(backend_id));
+        }
+
+        Ok(db.get_route_backend(route).await?)
+    }
+
+    /// Record the routes which were active at the given time, and publish if it is time to.
+    pub async fn record(&mut self, db: &DroneDatabase, routes: &[String], now: DateTime<Utc>) {
+        for route in routes {
+            match self.route_backend(db, route).await {
+                Ok(Some(backend_id)) => {
+                    self.last_active.insert(backend_id, now);
+                }
+                Ok(None) => (),
+                Err(error) => tracing::warn!(?error, %route, "Error reading backend of route."),
+            }
+        }
+
+        if self.last_publish.elapsed() >= ACTIVITY_PUBLISH_INTERVAL {
+            self.publish().await;
+        }
+    }
+
+    /// Publish the backends active since the last publication to each of the proxy's
+    /// clusters, since routes do not record which cluster they belong to.
+    async fn publish(&mut self) {
+        self.last_publish = Instant::now();
+        if self.last_active.is_empty() {
+            return;
+        }
+
+        let message = BackendActivityMessage {
+            last_active: std::mem::take(&mut self.last_active),
+        };
+        for cluster_domain in &self.cluster_domains {
+            self.nats
+                .publish(&BackendActivityMessage::subject(cluster_domain), &message)
+                .await
+                .log_error("Error publishing backend activity.");
+        }
+    }
+}
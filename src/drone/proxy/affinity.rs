@@ -0,0 +1,197 @@
+//! Balancing requests across the replicas of backends which are a small pool of
+//! containers, rather than one.
+//!
+//! Without affinity, requests are spread across replicas in turn. In affinity mode, the
+//! proxy pins each client to a replica with a cookie naming it, so that a browser keeps
+//! hitting the replica which holds its session. Replicas are named by a hash of their
+//! address rather than their position in the route, so that pins survive the route being
+//! refreshed or reordered; a client pinned to a replica which has gone away is pinned to
+//! another.
+
+use crate::database::ProxyRoute;
+use http::HeaderMap;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// FNV-1a, which is stable across builds and processes, unlike the standard library's
+/// hasher, so that every proxy names a replica the same way.
+fn replica_name(address: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in address.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// The value of the named cookie, if the request carries it.
+fn cookie_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value.trim())
+}
+
+/// The replica a request is sent to.
+#[derive(PartialEq, Eq, Debug)]
+pub struct ChosenReplica {
+    pub address: String,
+
+    /// The `Set-Cookie` value pinning the client to the replica, if it isn't already.
+    pub set_cookie: Option<String>,
+}
+
+#[derive(Clone, Default)]
+pub struct ReplicaBalancer {
+    /// Name of the cookie which pins clients to replicas, in affinity mode.
+    affinity_cookie: Option<String>,
+
+    /// Whether the proxy serves HTTPS, so that its cookies can be marked secure.
+    https: bool,
+    next: Arc<AtomicUsize>,
+}
+
+impl ReplicaBalancer {
+    pub fn new(affinity_cookie: Option<String>, https: bool) -> Self {
+        ReplicaBalancer {
+            affinity_cookie,
+            https,
+            next: Arc::default(),
+        }
+    }
+
+    /// Choose the replica of the route to send a request with the given headers to.
+    /// `cookie_path` is the path the backend is served under, which the cookie is
+    /// scoped to.
+    pub fn choose(
+        &self,
+        route: &ProxyRoute,
+        headers: &HeaderMap,
+        cookie_path: &str,
+    ) -> ChosenReplica {
+        if route.replicas.is_empty() {
+            return ChosenReplica {
+                address: route.address.clone(),
+                set_cookie: None,
+            };
+        }
+        let replicas: Vec<&String> = std::iter::once(&route.address)
+            .chain(&route.replicas)
+            .collect();
+
+        let cookie_name = match &self.affinity_cookie {
+            Some(cookie_name) => cookie_name,
+            None => {
+                let index = self.next.fetch_add(1, Ordering::Relaxed) % replicas.len();
+                return ChosenReplica {
+                    address: replicas[index].clone(),
+                    set_cookie: None,
+                };
+            }
+        };
+
+        if let Some(pinned) = cookie_value(headers, cookie_name) {
+            if let Some(address) = replicas
+                .iter()
+                .find(|address| replica_name(address) == pinned)
+            {
+                return ChosenReplica {
+                    address: address.to_string(),
+                    set_cookie: None,
+                };
+            }
+        }
+
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % replicas.len();
+        let address = replicas[index].clone();
+        let mut set_cookie = format!(
+            "{}={}; Path={}; HttpOnly; SameSite=Lax",
+            cookie_name,
+            replica_name(&address),
+            cookie_path
+        );
+        if self.https {
+            set_cookie.push_str("; Secure");
+        }
+        ChosenReplica {
+            address,
+            set_cookie: Some(set_cookie),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn route(address: &str, replicas: &[&str]) -> ProxyRoute {
+        ProxyRoute {
+            backend_id: None,
+            address: address.to_string(),
+            bearer_token: None,
+            max_connections: None,
+            timeouts: None,
+            replicas: replicas.iter().map(|replica| replica.to_string()).collect(),
+            cluster: None,
+        }
+    }
+
+    fn with_cookie(cookie: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(http::header::COOKIE, cookie.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let balancer = ReplicaBalancer::default();
+        let route = route("10.0.0.1:1000", &["10.0.0.2:1000"]);
+        let addresses: Vec<String> = (0..3)
+            .map(|_| balancer.choose(&route, &HeaderMap::new(), "/").address)
+            .collect();
+        assert_eq!(
+            vec!["10.0.0.1:1000", "10.0.0.2:1000", "10.0.0.1:1000"],
+            addresses
+        );
+    }
+
+    #[test]
+    fn test_affinity() {
+        let balancer = ReplicaBalancer::new(Some("spawner_replica".to_string()), true);
+        let route = route("10.0.0.1:1000", &["10.0.0.2:1000", "10.0.0.3:1000"]);
+
+        let chosen = balancer.choose(&route, &HeaderMap::new(), "/");
+        let set_cookie = chosen.set_cookie.unwrap();
+        assert!(set_cookie.ends_with("; Path=/; HttpOnly; SameSite=Lax; Secure"));
+        let cookie = set_cookie.split(';').next().unwrap();
+
+        // The pin holds across requests, and after the route is refreshed in another
+        // order.
+        let headers = with_cookie(&format!("other=1; {}", cookie));
+        for _ in 0..3 {
+            assert_eq!(
+                ChosenReplica {
+                    address: chosen.address.clone(),
+                    set_cookie: None,
+                },
+                balancer.choose(&route, &headers, "/")
+            );
+        }
+        let mut refreshed = route.clone();
+        refreshed.replicas.reverse();
+        assert_eq!(
+            chosen.address,
+            balancer.choose(&refreshed, &headers, "/").address
+        );
+
+        // A client pinned to a replica which has gone away is pinned to another.
+        let headers = with_cookie("spawner_replica=0000000000000000");
+        assert!(balancer.choose(&route, &headers, "/").set_cookie.is_some());
+    }
+}
@@ -1,32 +1,88 @@
-use crate::keys::{load_certs, load_private_key, KeyCertPathPair};
-use anyhow::Result;
+use super::{ocsp::staple_ocsp, strip_cluster_domain};
+use crate::{
+    drone::cert::{store::write_cert_files, x509_validity, CERTIFICATE_STREAM},
+    keys::{load_certs, load_private_key, parse_certs, parse_private_key, KeyCertPathPair},
+    messages::cert::{ClusterCertificate, CustomDomainCertSync, CustomDomainCertificate},
+    nats::TypedNats,
+};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
 use notify::{
     event::{AccessKind, AccessMode},
     recommended_watcher, Event, EventKind, INotifyWatcher, RecursiveMode, Watcher,
 };
+use openssl::{pkey::PKey, x509::X509};
 use rustls::{
     server::ResolvesServerCert,
     sign::{any_supported_type, CertifiedKey},
-    Certificate, PrivateKey,
+    Certificate, PrivateKey, SignatureScheme,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
 };
-use std::sync::Arc;
-use tokio::sync::watch::{channel, Receiver, Sender};
+use tokio::{
+    sync::watch::{channel, Receiver, Sender},
+    task::JoinHandle,
+};
+
+/// How often the certificate files are checked for changes which the watcher misses,
+/// e.g. replacement by renaming, as when Kubernetes updates a mounted Secret.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// When the private key and certificate files were last modified, if both exist.
+fn modified(key_cert_path_pair: &KeyCertPathPair) -> Option<(SystemTime, SystemTime)> {
+    let private_key = std::fs::metadata(&key_cert_path_pair.private_key_path).ok()?;
+    let certificate = std::fs::metadata(&key_cert_path_pair.certificate_path).ok()?;
+    Some((private_key.modified().ok()?, certificate.modified().ok()?))
+}
 
 pub struct CertRefresher {
     receiver: Receiver<Option<Arc<CertifiedKey>>>,
+    sender: Arc<Sender<Option<Arc<CertifiedKey>>>>,
     _watcher: INotifyWatcher,
+    poll_task: JoinHandle<()>,
+    ocsp_task: Option<JoinHandle<()>>,
 }
 
 impl CertRefresher {
     pub fn new(key_cert_path_pair: KeyCertPathPair) -> Result<Self> {
         let (sender, receiver) = channel(None);
 
-        if let Err(error) = Self::try_to_update_certs(&key_cert_path_pair, &sender) {
-            tracing::warn!(?error, "Certificates aren't ready yet. This is expected if the certificate fetcher has just started.")
+        let mut last_modified = None;
+        match Self::try_to_update_certs(&key_cert_path_pair, &sender) {
+            Ok(()) => last_modified = modified(&key_cert_path_pair),
+            Err(error) => tracing::warn!(?error, "Certificates aren't ready yet. This is expected if the certificate fetcher has just started."),
         }
+        let sender = Arc::new(sender);
+
+        let poll_task = tokio::spawn({
+            let key_cert_path_pair = key_cert_path_pair.clone();
+            let sender = sender.clone();
+            async move {
+                let mut interval = tokio::time::interval(POLL_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    let current = modified(&key_cert_path_pair);
+                    if current.is_none() || current == last_modified {
+                        continue;
+                    }
+                    match Self::try_to_update_certs(&key_cert_path_pair, &sender) {
+                        Ok(()) => {
+                            tracing::info!(?key_cert_path_pair, "Reloaded changed key/cert pair.");
+                            last_modified = current;
+                        }
+                        // Retried at the next interval, e.g. once both files are in place.
+                        Err(error) => tracing::warn!(?error, "Error reloading key/cert pair."),
+                    }
+                }
+            }
+        });
 
         let mut watcher = {
             let key_cert_path_pair = key_cert_path_pair.clone();
+            let sender = sender.clone();
             let mut private_key: Option<PrivateKey> = None;
             let mut certificate: Option<Vec<Certificate>> = None;
 
@@ -58,6 +114,10 @@ impl CertRefresher {
                     }
 
                     if let (Some(key), Some(cert)) = (&private_key, &certificate) {
+                        if !key_matches_certificate(key, cert) {
+                            tracing::info!("Waiting for the key of the new certificate.");
+                            return;
+                        }
                         tracing::info!("Updating key/cert pair.");
 
                         let private_key = match any_supported_type(key) {
@@ -80,7 +140,10 @@ impl CertRefresher {
 
         let resolver = CertRefresher {
             receiver,
+            sender,
             _watcher: watcher,
+            poll_task,
+            ocsp_task: None,
         };
 
         Ok(resolver)
@@ -90,8 +153,13 @@ impl CertRefresher {
         key_cert_path_pair: &KeyCertPathPair,
         sender: &Sender<Option<Arc<CertifiedKey>>>,
     ) -> Result<()> {
-        let cert_key_pair = Some(Arc::new(key_cert_path_pair.load_certified_key()?));
-        let _ = sender.send(cert_key_pair);
+        let private_key = load_private_key(&key_cert_path_pair.private_key_path)?;
+        let certificate = load_certs(&key_cert_path_pair.certificate_path)?;
+        if !key_matches_certificate(&private_key, &certificate) {
+            return Err(anyhow!("Private key does not match certificate."));
+        }
+        let certified_key = CertifiedKey::new(certificate, any_supported_type(&private_key)?);
+        let _ = sender.send(Some(Arc::new(certified_key)));
 
         Ok(())
     }
@@ -99,19 +167,388 @@ impl CertRefresher {
     pub fn resolver(&self) -> CertResolver {
         CertResolver {
             receiver: self.receiver.clone(),
+            alternates: Vec::new(),
+        }
+    }
+
+    /// Staple OCSP responses to the certificate, refreshed in the background.
+    pub fn staple_ocsp(&mut self) {
+        self.ocsp_task = Some(tokio::spawn(staple_ocsp(self.sender.clone())));
+    }
+}
+
+impl Drop for CertRefresher {
+    fn drop(&mut self) {
+        self.poll_task.abort();
+        if let Some(ocsp_task) = &self.ocsp_task {
+            ocsp_task.abort();
+        }
+    }
+}
+
+/// Whether the certificate is for the key. While a certificate is being replaced, one of
+/// the pair is written before the other, and the new certificate is only served once both
+/// are in place; until then, the old one is. Either way, open connections are unaffected.
+fn key_matches_certificate(key: &PrivateKey, certificate: &[Certificate]) -> bool {
+    let key = match PKey::private_key_from_pkcs8(&key.0) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    let public_key = match certificate
+        .first()
+        .and_then(|cert| X509::from_der(&cert.0).ok())
+        .and_then(|cert| cert.public_key().ok())
+    {
+        Some(public_key) => public_key,
+        None => return false,
+    };
+
+    key.public_eq(&public_key)
+}
+
+/// Write the certificates published over NATS for the cluster to its paths, for its
+/// `CertRefresher` to load, beginning with the latest published before the proxy started.
+pub async fn receive_certs(
+    nats: TypedNats,
+    cluster_domain: String,
+    key_paths: KeyCertPathPair,
+) -> Result<()> {
+    let subject = ClusterCertificate::subject(&cluster_domain);
+
+    // Subscribe before fetching the latest, so that none are missed in between.
+    let mut sub = nats.subscribe(&subject).await?;
+    match nats.get_latest(&subject, CERTIFICATE_STREAM).await {
+        Ok(Some(certificate)) => write_cert(&certificate, &key_paths).await?,
+        Ok(None) => (),
+        Err(error) => {
+            tracing::warn!(?error, %cluster_domain, "No certificate has been published yet.")
+        }
+    }
+
+    loop {
+        match sub.next().await {
+            Ok(Some(message)) => {
+                if let Err(error) = write_cert(&message.value, &key_paths).await {
+                    tracing::error!(?error, "Error writing received certificate.");
+                }
+            }
+            Ok(None) => return Err(anyhow!("Certificate subscription closed.")),
+            Err(error) => {
+                tracing::error!(?error, "Non-fatal error when receiving certificates.")
+            }
+        }
+    }
+}
+
+async fn write_cert(certificate: &ClusterCertificate, key_paths: &KeyCertPathPair) -> Result<()> {
+    tracing::info!(cluster = %certificate.cluster, "Received certificate.");
+    write_cert_files(certificate, key_paths).await
+}
+
+/// The certificates of backends' custom domains, by hostname, received over NATS from
+/// the clusters' certificate refreshers.
+#[derive(Clone, Default)]
+pub struct CustomDomainCerts {
+    certs: Arc<RwLock<HashMap<String, Arc<CertifiedKey>>>>,
+}
+
+impl CustomDomainCerts {
+    fn get(&self, server_name: &str) -> Option<Arc<CertifiedKey>> {
+        self.certs
+            .read()
+            .expect("Custom domain certificate lock was poisoned.")
+            .get(&server_name.to_ascii_lowercase())
+            .cloned()
+    }
+
+    fn insert(&self, certificate: &CustomDomainCertificate) -> Result<()> {
+        let private_key = parse_private_key(certificate.private_key_pem.as_bytes())?;
+        let chain = parse_certs(certificate.certificate_pem.as_bytes())?;
+        if !key_matches_certificate(&private_key, &chain) {
+            return Err(anyhow!("Private key does not match certificate."));
+        }
+        let certified_key = CertifiedKey::new(chain, any_supported_type(&private_key)?);
+        self.certs
+            .write()
+            .expect("Custom domain certificate lock was poisoned.")
+            .insert(certificate.hostname.clone(), Arc::new(certified_key));
+
+        Ok(())
+    }
+
+    /// Serve the certificates published for the cluster's custom domains, beginning by
+    /// asking for those issued before the proxy started.
+    pub async fn receive(self, nats: TypedNats, cluster_domain: String) -> Result<()> {
+        let mut sub = nats
+            .subscribe(&CustomDomainCertificate::subject(&cluster_domain))
+            .await?;
+        nats.publish(
+            &CustomDomainCertSync::subject(&cluster_domain),
+            &CustomDomainCertSync,
+        )
+        .await?;
+
+        loop {
+            match sub.next().await {
+                Ok(Some(message)) => match self.insert(&message.value) {
+                    Ok(()) => {
+                        tracing::info!(hostname = %message.value.hostname, "Received custom domain certificate.")
+                    }
+                    Err(error) => {
+                        tracing::error!(?error, "Error loading received custom domain certificate.")
+                    }
+                },
+                Ok(None) => return Err(anyhow!("Custom domain certificate subscription closed.")),
+                Err(error) => {
+                    tracing::error!(
+                        ?error,
+                        "Non-fatal error when receiving custom domain certificates."
+                    )
+                }
+            }
         }
     }
 }
 
 pub struct CertResolver {
     receiver: Receiver<Option<Arc<CertifiedKey>>>,
+
+    /// Certificates for the same names with other types of key, for clients which can't
+    /// use this one's.
+    alternates: Vec<CertResolver>,
+}
+
+impl CertResolver {
+    pub fn with_alternates(self, alternates: Vec<CertResolver>) -> Self {
+        CertResolver { alternates, ..self }
+    }
+
+    /// Whether a certificate has been loaded, i.e. whether TLS handshakes can succeed.
+    pub fn is_loaded(&self) -> bool {
+        self.receiver.borrow().is_some()
+    }
+
+    /// When the loaded certificate expires.
+    pub fn valid_until(&self) -> Option<DateTime<Utc>> {
+        let certified_key = self.receiver.borrow().clone()?;
+        x509_validity(&X509::from_der(&certified_key.cert.first()?.0).ok()?)
+    }
+}
+
+/// The first of the certificates whose key can sign with one of the client's signature
+/// schemes, e.g. an RSA certificate for an old client which doesn't support ECDSA, or
+/// else the first, for the client to reject.
+fn select_key(
+    candidates: &[Arc<CertifiedKey>],
+    signature_schemes: &[SignatureScheme],
+) -> Option<Arc<CertifiedKey>> {
+    candidates
+        .iter()
+        .find(|candidate| candidate.key.choose_scheme(signature_schemes).is_some())
+        .or_else(|| candidates.first())
+        .cloned()
 }
 
 impl ResolvesServerCert for CertResolver {
     fn resolve(
         &self,
-        _client_hello: rustls::server::ClientHello,
+        client_hello: rustls::server::ClientHello,
     ) -> Option<Arc<rustls::sign::CertifiedKey>> {
-        self.receiver.borrow().clone()
+        if self.alternates.is_empty() {
+            return self.receiver.borrow().clone();
+        }
+
+        let candidates: Vec<_> = std::iter::once(self)
+            .chain(&self.alternates)
+            .filter_map(|resolver| resolver.receiver.borrow().clone())
+            .collect();
+        select_key(&candidates, client_hello.signature_schemes())
+    }
+}
+
+/// Whether the hostname of an operator-provided certificate, which may be a wildcard
+/// `*.<domain>`, covers the server name.
+fn host_matches(hostname: &str, server_name: &str) -> bool {
+    match hostname.strip_prefix("*.") {
+        Some(domain) => server_name
+            .split_once('.')
+            .is_some_and(|(_, parent)| parent.eq_ignore_ascii_case(domain)),
+        None => hostname.eq_ignore_ascii_case(server_name),
+    }
+}
+
+/// Selects the certificate which the client names by SNI: an operator-provided
+/// certificate for the hostname, else that of the cluster domain, falling back to the
+/// drone's own certificate. Each certificate is refreshed independently.
+pub struct SniCertResolver {
+    default: CertResolver,
+
+    /// Resolvers for additional cluster domains, by domain.
+    clusters: Vec<(String, CertResolver)>,
+
+    /// Resolvers for operator-provided certificates, by hostname.
+    hosts: Vec<(String, CertResolver)>,
+
+    /// Certificates of backends' custom domains, if they are served.
+    custom_domains: Option<CustomDomainCerts>,
+}
+
+impl SniCertResolver {
+    pub fn new(
+        default: CertResolver,
+        clusters: Vec<(String, CertResolver)>,
+        hosts: Vec<(String, CertResolver)>,
+        custom_domains: Option<CustomDomainCerts>,
+    ) -> Self {
+        SniCertResolver {
+            default,
+            clusters,
+            hosts,
+            custom_domains,
+        }
+    }
+
+    /// The hostname or cluster domain whose certificate is served for the server name,
+    /// if not the drone's own.
+    fn select(&self, server_name: &str) -> Option<&(String, CertResolver)> {
+        let matching_hosts = || {
+            self.hosts
+                .iter()
+                .filter(|(hostname, _)| host_matches(hostname, server_name))
+        };
+        // An exact hostname is more specific than a wildcard.
+        matching_hosts()
+            .find(|(hostname, _)| !hostname.starts_with("*."))
+            .or_else(|| matching_hosts().next())
+            .or_else(|| {
+                self.clusters
+                    .iter()
+                    .filter(|(cluster_domain, _)| {
+                        strip_cluster_domain(server_name, cluster_domain).is_some()
+                    })
+                    .max_by_key(|(cluster_domain, _)| cluster_domain.len())
+            })
+    }
+}
+
+impl ResolvesServerCert for SniCertResolver {
+    fn resolve(
+        &self,
+        client_hello: rustls::server::ClientHello,
+    ) -> Option<Arc<rustls::sign::CertifiedKey>> {
+        let server_name = client_hello.server_name();
+        let selected = server_name.and_then(|server_name| self.select(server_name));
+        if selected.is_none() {
+            let custom_domain = server_name
+                .zip(self.custom_domains.as_ref())
+                .and_then(|(server_name, custom_domains)| custom_domains.get(server_name));
+            if custom_domain.is_some() {
+                return custom_domain;
+            }
+        }
+
+        let resolver = selected
+            .map(|(_, resolver)| resolver)
+            .unwrap_or(&self.default);
+
+        resolver.resolve(client_hello)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{drone::backend_tls::test::test_ca, types::BackendId};
+
+    #[test]
+    fn test_key_matches_certificate() {
+        let ca = test_ca();
+        let issue = |backend: &str| {
+            let issued = ca
+                .issue_backend(&BackendId::new(backend.to_string()))
+                .unwrap();
+            (
+                PrivateKey(issued.private_key_pkcs8_der().unwrap()),
+                vec![Certificate(issued.certificate.to_der().unwrap())],
+            )
+        };
+        let (old_key, old_cert) = issue("old");
+        let (new_key, new_cert) = issue("new");
+
+        assert!(key_matches_certificate(&old_key, &old_cert));
+        assert!(key_matches_certificate(&new_key, &new_cert));
+        assert!(!key_matches_certificate(&old_key, &new_cert));
+        assert!(!key_matches_certificate(&new_key, &[]));
+    }
+
+    #[test]
+    fn test_host_matches() {
+        assert!(host_matches("app.corp.test", "app.corp.test"));
+        assert!(host_matches("app.corp.test", "APP.corp.test"));
+        assert!(!host_matches("app.corp.test", "other.corp.test"));
+        assert!(host_matches("*.corp.test", "app.corp.test"));
+        assert!(!host_matches("*.corp.test", "corp.test"));
+        assert!(!host_matches("*.corp.test", "a.app.corp.test"));
+    }
+
+    #[test]
+    fn test_select_key() {
+        let issued = test_ca()
+            .issue_backend(&BackendId::new("backend".to_string()))
+            .unwrap();
+        let ecdsa = Arc::new(CertifiedKey::new(
+            vec![Certificate(issued.certificate.to_der().unwrap())],
+            any_supported_type(&PrivateKey(issued.private_key_pkcs8_der().unwrap())).unwrap(),
+        ));
+        let rsa_key = PKey::from_rsa(openssl::rsa::Rsa::generate(2048).unwrap()).unwrap();
+        let rsa = Arc::new(CertifiedKey::new(
+            vec![Certificate(issued.certificate.to_der().unwrap())],
+            any_supported_type(&PrivateKey(rsa_key.private_key_to_der().unwrap())).unwrap(),
+        ));
+        let candidates = [ecdsa.clone(), rsa.clone()];
+
+        let modern = [
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::RSA_PSS_SHA256,
+        ];
+        assert!(Arc::ptr_eq(
+            &ecdsa,
+            &select_key(&candidates, &modern).unwrap()
+        ));
+        let rsa_only = [SignatureScheme::RSA_PKCS1_SHA256];
+        assert!(Arc::ptr_eq(
+            &rsa,
+            &select_key(&candidates, &rsa_only).unwrap()
+        ));
+        let unsupported = [SignatureScheme::ED25519];
+        assert!(Arc::ptr_eq(
+            &ecdsa,
+            &select_key(&candidates, &unsupported).unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_custom_domain_certs() {
+        let ca = test_ca();
+        let issued = ca
+            .issue_backend(&BackendId::new("backend".to_string()))
+            .unwrap();
+        let other = ca
+            .issue_backend(&BackendId::new("other".to_string()))
+            .unwrap();
+        let certificate = |private_key: &PKey<openssl::pkey::Private>| CustomDomainCertificate {
+            hostname: "app.customer.com".to_string(),
+            certificate_pem: String::from_utf8(issued.certificate.to_pem().unwrap()).unwrap(),
+            private_key_pem: String::from_utf8(private_key.private_key_to_pem_pkcs8().unwrap())
+                .unwrap(),
+        };
+
+        let certs = CustomDomainCerts::default();
+        assert!(certs.insert(&certificate(&other.private_key)).is_err());
+        assert!(certs.get("app.customer.com").is_none());
+
+        certs.insert(&certificate(&issued.private_key)).unwrap();
+        assert!(certs.get("App.Customer.com").is_some());
+        assert!(certs.get("other.customer.com").is_none());
     }
 }
@@ -0,0 +1,500 @@
+//! Compression of backends' responses, for clusters whose backends serve HTML and
+//! JavaScript uncompressed. A response is compressed with brotli or gzip, whichever the
+//! client's `Accept-Encoding` prefers, if its content type is one of those compressed for
+//! its cluster domain.
+
+use anyhow::{anyhow, Result};
+use async_compression::{
+    tokio::write::{BrotliEncoder, GzipEncoder},
+    Level,
+};
+use bytes::Bytes;
+use http::{header, HeaderMap, HeaderValue, Response, StatusCode};
+use hyper::{
+    body::{HttpBody, Sender},
+    Body,
+};
+use std::str::FromStr;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Responses whose `Content-Length` is below this are sent uncompressed, since
+/// compressing them saves little.
+const MIN_COMPRESSED_LENGTH: u64 = 1024;
+
+/// Brotli quality, from 0 to 11. Higher qualities are too slow to compress with as
+/// responses are sent.
+const BROTLI_QUALITY: u32 = 4;
+
+/// The content types compressed for one cluster domain, or for every cluster domain
+/// without a rule of its own.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct CompressionRule {
+    pub cluster_domain: Option<String>,
+
+    /// Lowercase media types, e.g. `text/html`, or `<type>/*` for every subtype of a type.
+    /// Empty if responses are not compressed.
+    pub content_types: Vec<String>,
+}
+
+/// Parses `off`, or comma-separated media types.
+fn parse_content_types(s: &str) -> Result<Vec<String>> {
+    if s == "off" {
+        return Ok(Vec::new());
+    }
+
+    s.split(',')
+        .map(|content_type| {
+            let content_type = content_type.trim().to_ascii_lowercase();
+            match content_type.split_once('/') {
+                Some((type_, subtype)) if !type_.is_empty() && !subtype.is_empty() => {
+                    Ok(content_type)
+                }
+                _ => Err(anyhow!(
+                    "Expected compressed content types to be off or comma-separated media types, got {:?}.",
+                    s
+                )),
+            }
+        })
+        .collect()
+}
+
+impl FromStr for CompressionRule {
+    type Err = anyhow::Error;
+
+    /// Parses `<content types>` or `<domain>=<content types>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((cluster_domain, content_types)) => Ok(CompressionRule {
+                cluster_domain: Some(cluster_domain.to_string()),
+                content_types: parse_content_types(content_types)?,
+            }),
+            None => Ok(CompressionRule {
+                cluster_domain: None,
+                content_types: parse_content_types(s)?,
+            }),
+        }
+    }
+}
+
+/// The content types compressed for the cluster domain under the rules.
+pub fn content_types_for<'a>(rules: &'a [CompressionRule], cluster_domain: &str) -> &'a [String] {
+    rules
+        .iter()
+        .find(|rule| rule.cluster_domain.as_deref() == Some(cluster_domain))
+        .or_else(|| rules.iter().find(|rule| rule.cluster_domain.is_none()))
+        .map(|rule| rule.content_types.as_slice())
+        .unwrap_or_default()
+}
+
+/// Whether the media type of a `Content-Type` header is one of the content types.
+fn is_compressed_type(content_type: &str, content_types: &[String]) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    content_types
+        .iter()
+        .any(|compressed| match compressed.strip_suffix("/*") {
+            Some(type_) => media_type
+                .split_once('/')
+                .is_some_and(|(media_type, _)| media_type == type_),
+            None => *compressed == media_type,
+        })
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// The encoding's name in `Accept-Encoding` and `Content-Encoding` headers.
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// The encoding the client prefers of those responses are compressed with, going by the
+/// quality values in its `Accept-Encoding` headers. Brotli wins ties.
+pub fn negotiate_encoding(headers: &HeaderMap) -> Option<Encoding> {
+    let mut brotli = None;
+    let mut gzip = None;
+    let mut any = None;
+
+    let codings = headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+    for coding in codings {
+        let mut params = coding.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let quality = params
+            .find_map(|param| {
+                let (key, value) = param.split_once('=')?;
+                if key.trim().eq_ignore_ascii_case("q") {
+                    value.trim().parse::<f32>().ok()
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(1.0);
+
+        if name.eq_ignore_ascii_case(Encoding::Brotli.name()) {
+            brotli = Some(quality);
+        } else if name.eq_ignore_ascii_case(Encoding::Gzip.name()) {
+            gzip = Some(quality);
+        } else if name == "*" {
+            any = Some(quality);
+        }
+    }
+
+    let brotli = brotli.or(any).unwrap_or_default();
+    let gzip = gzip.or(any).unwrap_or_default();
+    if brotli > 0.0 && brotli >= gzip {
+        Some(Encoding::Brotli)
+    } else if gzip > 0.0 {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+/// How the response to a request is compressed, decided from the request before it is
+/// handled.
+pub struct ResponseCompression {
+    /// The encoding the client prefers, if it accepts one.
+    pub encoding: Option<Encoding>,
+
+    /// The content types compressed for the request's cluster domain.
+    pub content_types: Vec<String>,
+
+    /// Whether the request is a `HEAD` request, whose response has no body.
+    pub head: bool,
+}
+
+impl ResponseCompression {
+    /// Whether the response is in one of the content types and has a body which could be
+    /// compressed: one which is not already encoded, not a range, not known to be small,
+    /// and which intermediaries may transform.
+    fn is_compressible(&self, response: &Response<Body>) -> bool {
+        let status = response.status();
+        let headers = response.headers();
+        if self.head
+            || status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::PARTIAL_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+            || headers.contains_key(header::CONTENT_ENCODING)
+            || headers.contains_key(header::CONTENT_RANGE)
+        {
+            return false;
+        }
+
+        let no_transform = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+        let small = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .is_some_and(|length| length < MIN_COMPRESSED_LENGTH);
+        if no_transform || small {
+            return false;
+        }
+
+        headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|content_type| is_compressed_type(content_type, &self.content_types))
+    }
+
+    /// Compress the response's body, if it is compressible and the client accepts an
+    /// encoding. Compressible responses are marked as varying by `Accept-Encoding` either
+    /// way, so that caches keep the compressed and uncompressed ones apart.
+    pub fn apply(&self, response: Response<Body>) -> Response<Body> {
+        if !self.is_compressible(&response) {
+            return response;
+        }
+
+        let (mut parts, body) = response.into_parts();
+        parts.headers.append(
+            header::VARY,
+            HeaderValue::from_static(header::ACCEPT_ENCODING.as_str()),
+        );
+        let encoding = match self.encoding {
+            Some(encoding) => encoding,
+            None => return Response::from_parts(parts, body),
+        };
+
+        parts.headers.remove(header::CONTENT_LENGTH);
+        parts.headers.insert(
+            header::CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.name()),
+        );
+        // The compressed body is a different representation, which a strong validator of
+        // the uncompressed one must not match.
+        if let Some(etag) = parts.headers.get(header::ETAG) {
+            if etag.as_bytes().starts_with(b"\"") {
+                let mut weak = b"W/".to_vec();
+                weak.extend_from_slice(etag.as_bytes());
+                if let Ok(weak) = HeaderValue::from_bytes(&weak) {
+                    parts.headers.insert(header::ETAG, weak);
+                }
+            }
+        }
+
+        let body = match encoding {
+            Encoding::Brotli => compress_body(
+                body,
+                BrotliEncoder::with_quality(Vec::new(), Level::Precise(BROTLI_QUALITY)),
+                BrotliEncoder::get_mut,
+            ),
+            Encoding::Gzip => {
+                compress_body(body, GzipEncoder::new(Vec::new()), GzipEncoder::get_mut)
+            }
+        };
+        Response::from_parts(parts, body)
+    }
+}
+
+/// Send what the encoder has output so far, returning false if the client has gone away.
+async fn send_output(sender: &mut Sender, output: &mut Vec<u8>) -> bool {
+    if output.is_empty() {
+        return true;
+    }
+    sender
+        .send_data(Bytes::from(std::mem::take(output)))
+        .await
+        .is_ok()
+}
+
+/// The body, compressed by the encoder as it arrives. The encoder is flushed after each
+/// chunk, so that responses which are streamed reach the client as they are sent.
+fn compress_body<E>(mut body: Body, mut encoder: E, output: fn(&mut E) -> &mut Vec<u8>) -> Body
+where
+    E: AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, compressed_body) = Body::channel();
+
+    tokio::spawn(async move {
+        loop {
+            match body.data().await {
+                Some(Ok(chunk)) => {
+                    let written = match encoder.write_all(&chunk).await {
+                        Ok(()) => encoder.flush().await,
+                        Err(error) => Err(error),
+                    };
+                    if let Err(error) = written {
+                        tracing::warn!(?error, "Error compressing response body.");
+                        sender.abort();
+                        break;
+                    }
+                    if !send_output(&mut sender, output(&mut encoder)).await {
+                        // The client has gone away.
+                        break;
+                    }
+                }
+                Some(Err(error)) => {
+                    tracing::warn!(?error, "Error reading response body.");
+                    sender.abort();
+                    break;
+                }
+                None => {
+                    if let Err(error) = encoder.shutdown().await {
+                        tracing::warn!(?error, "Error compressing response body.");
+                        sender.abort();
+                        break;
+                    }
+                    if !send_output(&mut sender, output(&mut encoder)).await {
+                        break;
+                    }
+                    if let Ok(Some(trailers)) = body.trailers().await {
+                        // An error means the client has gone away, leaving nothing to do.
+                        let _ = sender.send_trailers(trailers).await;
+                    }
+                    break;
+                }
+            }
+        }
+    });
+
+    compressed_body
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder};
+    use tokio::io::AsyncReadExt;
+
+    fn headers(accept_encoding: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_str(accept_encoding).unwrap(),
+        );
+        headers
+    }
+
+    fn typed_response(content_type: &str, body: &str) -> Response<Body> {
+        Response::builder()
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_LENGTH, body.len())
+            .header(header::ETAG, "\"v1\"")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    fn compression(encoding: Option<Encoding>) -> ResponseCompression {
+        ResponseCompression {
+            encoding,
+            content_types: vec!["text/html".to_string(), "application/*".to_string()],
+            head: false,
+        }
+    }
+
+    #[test]
+    fn test_parse_compression_rule() {
+        assert_eq!(
+            CompressionRule {
+                cluster_domain: None,
+                content_types: vec!["text/html".to_string(), "application/*".to_string()],
+            },
+            "text/html, Application/*".parse().unwrap()
+        );
+        assert_eq!(
+            CompressionRule {
+                cluster_domain: Some("internal.test".to_string()),
+                content_types: vec![],
+            },
+            "internal.test=off".parse().unwrap()
+        );
+        assert!("text".parse::<CompressionRule>().is_err());
+        assert!("internal.test=text/html,"
+            .parse::<CompressionRule>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_content_types_for() {
+        let rules: Vec<CompressionRule> = vec![
+            "text/html".parse().unwrap(),
+            "internal.test=off".parse().unwrap(),
+        ];
+        assert_eq!(["text/html"], content_types_for(&rules, "spawner.test"));
+        assert!(content_types_for(&rules, "internal.test").is_empty());
+        assert!(content_types_for(&[], "spawner.test").is_empty());
+    }
+
+    #[test]
+    fn test_negotiate_encoding() {
+        assert_eq!(None, negotiate_encoding(&HeaderMap::new()));
+        assert_eq!(None, negotiate_encoding(&headers("identity, deflate")));
+        assert_eq!(
+            Some(Encoding::Brotli),
+            negotiate_encoding(&headers("gzip, br"))
+        );
+        assert_eq!(Some(Encoding::Gzip), negotiate_encoding(&headers("GZIP")));
+        assert_eq!(
+            Some(Encoding::Gzip),
+            negotiate_encoding(&headers("br;q=0.5, gzip;q=0.8"))
+        );
+        assert_eq!(None, negotiate_encoding(&headers("br;q=0, gzip; q=0")));
+        assert_eq!(Some(Encoding::Brotli), negotiate_encoding(&headers("*")));
+        assert_eq!(
+            Some(Encoding::Gzip),
+            negotiate_encoding(&headers("br;q=0, *;q=0.1"))
+        );
+    }
+
+    #[test]
+    fn test_is_compressed_type() {
+        let content_types = ["text/html".to_string(), "application/*".to_string()];
+        assert!(is_compressed_type(
+            "text/html; charset=utf-8",
+            &content_types
+        ));
+        assert!(is_compressed_type("Application/JavaScript", &content_types));
+        assert!(!is_compressed_type("text/css", &content_types));
+        assert!(!is_compressed_type("image/png", &content_types));
+    }
+
+    #[tokio::test]
+    async fn test_apply() {
+        let page = "<p>Hello, world!</p>".repeat(100);
+
+        let response = compression(Some(Encoding::Gzip)).apply(typed_response("text/html", &page));
+        assert_eq!("gzip", response.headers()[header::CONTENT_ENCODING]);
+        assert_eq!("accept-encoding", response.headers()[header::VARY]);
+        assert_eq!("W/\"v1\"", response.headers()[header::ETAG]);
+        assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.len() < page.len());
+        let mut decompressed = String::new();
+        GzipDecoder::new(&body[..])
+            .read_to_string(&mut decompressed)
+            .await
+            .unwrap();
+        assert_eq!(page, decompressed);
+
+        let response =
+            compression(Some(Encoding::Brotli)).apply(typed_response("application/json", &page));
+        assert_eq!("br", response.headers()[header::CONTENT_ENCODING]);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let mut decompressed = String::new();
+        BrotliDecoder::new(&body[..])
+            .read_to_string(&mut decompressed)
+            .await
+            .unwrap();
+        assert_eq!(page, decompressed);
+
+        // The client accepts no encoding the proxy compresses with.
+        let response = compression(None).apply(typed_response("text/html", &page));
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!("accept-encoding", response.headers()[header::VARY]);
+        assert_eq!("\"v1\"", response.headers()[header::ETAG]);
+
+        let uncompressed = [
+            typed_response("image/png", &page),
+            typed_response("text/html", "<p>Hello, world!</p>"),
+            Response::builder()
+                .header(header::CONTENT_TYPE, "text/html")
+                .header(header::CONTENT_ENCODING, "gzip")
+                .body(Body::from(page.clone()))
+                .unwrap(),
+            Response::builder()
+                .header(header::CONTENT_TYPE, "text/html")
+                .header(header::CACHE_CONTROL, "public, no-transform")
+                .body(Body::from(page.clone()))
+                .unwrap(),
+            Response::builder()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_TYPE, "text/html")
+                .body(Body::from(page.clone()))
+                .unwrap(),
+        ];
+        for response in uncompressed {
+            let headers = response.headers().clone();
+            let response = compression(Some(Encoding::Gzip)).apply(response);
+            assert_eq!(&headers, response.headers());
+        }
+
+        let head = ResponseCompression {
+            head: true,
+            ..compression(Some(Encoding::Gzip))
+        };
+        let response = head.apply(typed_response("text/html", &page));
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+    }
+}
@@ -14,6 +14,18 @@ impl DashMultiset {
             .or_insert_with(|| 1);
     }
 
+    /// Add the backend unless it is already present `max` times. Returns whether it was
+    /// added.
+    pub fn try_add(&self, backend: &str, max: u32) -> bool {
+        let mut count = self.map.entry(backend.to_string()).or_insert(0);
+        if *count < max {
+            *count += 1;
+            true
+        } else {
+            false
+        }
+    }
+
     pub fn remove(&self, backend: &str) {
         self.map.remove_if_mut(backend, |_, d| {
             *d -= 1;
@@ -26,6 +38,9 @@ impl DashMultiset {
 pub struct ConnectionTracker {
     request_events: Arc<DashSet<String>>,
     long_lived_connections: Arc<DashMultiset>,
+
+    /// Connections counted against backends' connection limits, by backend ID.
+    limited_connections: Arc<DashMultiset>,
 }
 
 impl ConnectionTracker {
@@ -41,6 +56,48 @@ impl ConnectionTracker {
         self.long_lived_connections.remove(backend);
     }
 
+    /// Count a request to the backend as an open connection until the guard is dropped.
+    pub fn open_connection(&self, backend: &str) -> ConnectionGuard {
+        self.increment_connections(backend);
+        ConnectionGuard {
+            tracker: self.clone(),
+            backend: backend.to_string(),
+        }
+    }
+
+    /// Count a long-lived connection against the backend's connection limit until the
+    /// guard is dropped. Returns None if the backend already has `max_connections` open.
+    pub fn try_open_limited(
+        &self,
+        backend_id: &str,
+        max_connections: u32,
+    ) -> Option<LimitedConnectionGuard> {
+        if !self
+            .limited_connections
+            .try_add(backend_id, max_connections)
+        {
+            // Don't leave an empty entry behind for a backend with a limit of zero.
+            self.limited_connections
+                .map
+                .remove_if(backend_id, |_, d| *d == 0);
+            return None;
+        }
+
+        Some(LimitedConnectionGuard {
+            tracker: self.clone(),
+            backend_id: backend_id.to_string(),
+        })
+    }
+
+    /// The number of open connections to each backend which has any.
+    pub fn open_connections(&self) -> Vec<(String, u32)> {
+        self.long_lived_connections
+            .map
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect()
+    }
+
     pub fn get_and_clear_active_backends(&self) -> Vec<String> {
         let request_events = self.request_events.clone();
 
@@ -54,3 +111,53 @@ impl ConnectionTracker {
         result
     }
 }
+
+/// An open connection to a backend, counted until it is dropped.
+pub struct ConnectionGuard {
+    tracker: ConnectionTracker,
+    backend: String,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.tracker.decrement_connections(&self.backend);
+    }
+}
+
+/// A connection counted against a backend's connection limit until it is dropped.
+pub struct LimitedConnectionGuard {
+    tracker: ConnectionTracker,
+    backend_id: String,
+}
+
+impl Drop for LimitedConnectionGuard {
+    fn drop(&mut self) {
+        self.tracker.limited_connections.remove(&self.backend_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_limited_connections() {
+        let tracker = ConnectionTracker::default();
+
+        let first = tracker.try_open_limited("backend", 2);
+        let second = tracker.try_open_limited("backend", 2);
+        assert!(first.is_some() && second.is_some());
+        assert!(tracker.try_open_limited("backend", 2).is_none());
+        assert!(tracker.try_open_limited("other-backend", 2).is_some());
+
+        // Closing a connection makes room for another.
+        drop(first);
+        assert!(tracker.try_open_limited("backend", 2).is_some());
+
+        assert!(tracker.try_open_limited("closed-backend", 0).is_none());
+        assert!(!tracker
+            .limited_connections
+            .map
+            .contains_key("closed-backend"));
+    }
+}
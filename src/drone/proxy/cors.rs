@@ -0,0 +1,252 @@
+//! Cross-origin resource sharing, applied by the proxy on behalf of backends so that
+//! frontends on other origins can call them without each backend image implementing it.
+//!
+//! CORS is configured per cluster domain in a JSON file mapping each domain to its
+//! `CorsOptions`. Preflight requests to a configured cluster are answered by the proxy
+//! and never reach the backend.
+
+use anyhow::{Context, Result};
+use http::{header, HeaderMap, HeaderValue, Method, Request};
+use hyper::Body;
+use serde::Deserialize;
+use std::{collections::HashMap, path::Path};
+
+const ANY_ORIGIN: &str = "*";
+
+fn default_allowed_methods() -> Vec<String> {
+    ["GET", "HEAD", "POST"]
+        .iter()
+        .map(|method| method.to_string())
+        .collect()
+}
+
+/// Which cross-origin requests the proxy allows to a cluster's backends.
+#[derive(Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct CorsOptions {
+    /// Origins allowed to make requests, e.g. `https://app.example.com`. An origin of
+    /// `*` allows any origin, and `https://*.example.com` allows any subdomain.
+    pub allowed_origins: Vec<String>,
+
+    /// Methods allowed in cross-origin requests. Defaults to GET, HEAD and POST.
+    #[serde(default = "default_allowed_methods")]
+    pub allowed_methods: Vec<String>,
+
+    /// Request headers allowed in cross-origin requests. If empty, whichever headers a
+    /// preflight request asks for are allowed.
+    #[serde(default)]
+    pub allowed_headers: Vec<String>,
+
+    /// Response headers which the frontend may read, beyond the CORS-safelisted ones.
+    #[serde(default)]
+    pub exposed_headers: Vec<String>,
+
+    /// Whether requests may carry cookies and `Authorization` headers.
+    #[serde(default)]
+    pub allow_credentials: bool,
+
+    /// How long browsers may cache the result of a preflight request.
+    #[serde(default)]
+    pub max_age_secs: Option<u64>,
+}
+
+/// Read the CORS configuration of each cluster domain from a JSON file.
+pub fn read_cors_config(path: &Path) -> Result<HashMap<String, CorsOptions>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Error reading CORS configuration {:?}.", path))?;
+    serde_json::from_str(&contents)
+        .with_context(|| format!("Error parsing CORS configuration {:?}.", path))
+}
+
+/// The origin of a cross-origin request, if the request has one.
+pub fn request_origin(req: &Request<Body>) -> Option<&str> {
+    req.headers()
+        .get(header::ORIGIN)
+        .and_then(|origin| origin.to_str().ok())
+}
+
+/// Whether the request is a CORS preflight request, rather than a request to the backend.
+pub fn is_preflight(req: &Request<Body>) -> bool {
+    req.method() == Method::OPTIONS
+        && req.headers().contains_key(header::ORIGIN)
+        && req
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+fn header_value(values: &[String]) -> Option<HeaderValue> {
+    HeaderValue::from_str(&values.join(", ")).ok()
+}
+
+impl CorsOptions {
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins.iter().any(|allowed| {
+            if allowed == ANY_ORIGIN || allowed == origin {
+                return true;
+            }
+
+            // e.g. `https://*.example.com` allows `https://app.example.com`.
+            let (scheme, domain) = match allowed.split_once("://*.") {
+                Some(pattern) => pattern,
+                None => return false,
+            };
+            let subdomain = origin
+                .strip_prefix(scheme)
+                .and_then(|rest| rest.strip_prefix("://"))
+                .and_then(|host| host.strip_suffix(domain));
+            matches!(subdomain, Some(subdomain) if subdomain.len() > 1 && subdomain.ends_with('.'))
+        })
+    }
+
+    fn allows_method(&self, method: &str) -> bool {
+        self.allowed_methods
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(method))
+    }
+
+    /// Headers which every response to an allowed origin carries.
+    fn origin_headers(&self, origin: &str, headers: &mut HeaderMap) -> Option<()> {
+        let allow_origin = if self.allow_credentials
+            || !self
+                .allowed_origins
+                .iter()
+                .any(|allowed| allowed == ANY_ORIGIN)
+        {
+            HeaderValue::from_str(origin).ok()?
+        } else {
+            HeaderValue::from_static(ANY_ORIGIN)
+        };
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        if self.allow_credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+
+        Some(())
+    }
+
+    /// The headers of the response to a preflight request, or None if the origin or
+    /// requested method is not allowed.
+    pub fn preflight_headers(
+        &self,
+        origin: &str,
+        request_headers: &HeaderMap,
+    ) -> Option<HeaderMap> {
+        let method = request_headers
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)?
+            .to_str()
+            .ok()?;
+        if !self.allows_origin(origin) || !self.allows_method(method) {
+            return None;
+        }
+
+        let mut headers = HeaderMap::new();
+        self.origin_headers(origin, &mut headers)?;
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            header_value(&self.allowed_methods)?,
+        );
+        if self.allowed_headers.is_empty() {
+            if let Some(requested) = request_headers.get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+                headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, requested.clone());
+            }
+        } else {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_HEADERS,
+                header_value(&self.allowed_headers)?,
+            );
+        }
+        if let Some(max_age_secs) = self.max_age_secs {
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, max_age_secs.into());
+        }
+
+        Some(headers)
+    }
+
+    /// Add CORS headers to the response to a request from the origin, replacing any set
+    /// by the backend. Responses to origins which are not allowed are left as they are.
+    pub fn apply(&self, origin: &str, response_headers: &mut HeaderMap) {
+        if !self.allows_origin(origin) {
+            return;
+        }
+
+        for name in [
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        ] {
+            response_headers.remove(name);
+        }
+        if self.origin_headers(origin, response_headers).is_none() {
+            return;
+        }
+        if !self.exposed_headers.is_empty() {
+            if let Some(exposed) = header_value(&self.exposed_headers) {
+                response_headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, exposed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_preflight_headers() {
+        let options: CorsOptions = serde_json::from_str(
+            r#"{
+                "allowed_origins": ["https://app.example.com", "https://*.preview.example.com"],
+                "allowed_methods": ["GET", "PUT"],
+                "allow_credentials": true,
+                "max_age_secs": 600
+            }"#,
+        )
+        .unwrap();
+
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert(
+            header::ACCESS_CONTROL_REQUEST_METHOD,
+            HeaderValue::from_static("PUT"),
+        );
+        request_headers.insert(
+            header::ACCESS_CONTROL_REQUEST_HEADERS,
+            HeaderValue::from_static("content-type"),
+        );
+
+        let headers = options
+            .preflight_headers("https://app.example.com", &request_headers)
+            .unwrap();
+        assert_eq!(
+            "https://app.example.com",
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN]
+        );
+        assert_eq!("true", headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS]);
+        assert_eq!("GET, PUT", headers[header::ACCESS_CONTROL_ALLOW_METHODS]);
+        assert_eq!(
+            "content-type",
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS]
+        );
+        assert_eq!("600", headers[header::ACCESS_CONTROL_MAX_AGE]);
+
+        assert!(options
+            .preflight_headers("https://pr-12.preview.example.com", &request_headers)
+            .is_some());
+        assert!(options
+            .preflight_headers("https://preview.example.com", &request_headers)
+            .is_none());
+        assert!(options
+            .preflight_headers("https://evil.example.com", &request_headers)
+            .is_none());
+
+        request_headers.insert(
+            header::ACCESS_CONTROL_REQUEST_METHOD,
+            HeaderValue::from_static("DELETE"),
+        );
+        assert!(options
+            .preflight_headers("https://app.example.com", &request_headers)
+            .is_none());
+    }
+}
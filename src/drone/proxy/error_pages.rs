@@ -0,0 +1,250 @@
+//! Pages served by the proxy in place of a bare status when it can't forward a request,
+//! so that operators can show branded pages or send clients elsewhere.
+//!
+//! Templates are HTML files read when the proxy starts, in which `{{host}}` is replaced
+//! with the (escaped) host the client asked for.
+
+use anyhow::{anyhow, Context, Result};
+use http::{header, Response, StatusCode};
+use hyper::Body;
+use std::{collections::HashMap, path::PathBuf, str::FromStr};
+use url::Url;
+
+const HOST_PLACEHOLDER: &str = "{{host}}";
+
+/// Served for a starting backend without a page configured. Browsers reload it until the
+/// backend is ready, following the `Refresh` header the proxy sends with it.
+const DEFAULT_STARTING_PAGE: &str = "<!DOCTYPE html>
+<html>
+<head><title>Starting</title></head>
+<body><p>{{host}} is starting. This page will reload when it is ready.</p></body>
+</html>
+";
+
+/// Why the proxy could not forward a request.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum ErrorClass {
+    /// No backend is routed from the host.
+    NotFound,
+
+    /// The backend could not be reached, e.g. because it refused the connection.
+    Unavailable,
+
+    /// The backend did not respond in time.
+    TimedOut,
+
+    /// The request lacks the backend's bearer token or a valid JWT.
+    Unauthorized,
+
+    /// The request exceeds a rate limit.
+    RateLimited,
+
+    /// The backend is still starting.
+    Starting,
+
+    /// The backend already has as many long-lived connections open as it allows. Served
+    /// with the proxy's configured connection limit status, rather than this class's.
+    ConnectionLimit,
+
+    /// The request exceeds a size limit. Served with 431 or 414 if its headers or URI are
+    /// too large, rather than this class's 413.
+    TooLarge,
+}
+
+impl ErrorClass {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorClass::NotFound => StatusCode::NOT_FOUND,
+            ErrorClass::Unavailable => StatusCode::BAD_GATEWAY,
+            ErrorClass::TimedOut => StatusCode::GATEWAY_TIMEOUT,
+            ErrorClass::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorClass::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorClass::Starting => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorClass::ConnectionLimit => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorClass::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        }
+    }
+}
+
+impl FromStr for ErrorClass {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "not-found" => Ok(ErrorClass::NotFound),
+            "unavailable" => Ok(ErrorClass::Unavailable),
+            "timed-out" => Ok(ErrorClass::TimedOut),
+            "unauthorized" => Ok(ErrorClass::Unauthorized),
+            "rate-limited" => Ok(ErrorClass::RateLimited),
+            "starting" => Ok(ErrorClass::Starting),
+            "connection-limit" => Ok(ErrorClass::ConnectionLimit),
+            "too-large" => Ok(ErrorClass::TooLarge),
+            _ => Err(anyhow!(
+                "Expected error class to be not-found, unavailable, timed-out, unauthorized, rate-limited, starting, connection-limit, or too-large, got {:?}.",
+                s
+            )),
+        }
+    }
+}
+
+/// What the proxy serves for a class of error.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum ErrorPage {
+    /// An HTML template, served with the error's status.
+    Template(PathBuf),
+
+    /// A URL to redirect the client to with 302 Found.
+    Redirect(Url),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct ErrorPageRule {
+    pub class: ErrorClass,
+    pub page: ErrorPage,
+}
+
+impl FromStr for ErrorPageRule {
+    type Err = anyhow::Error;
+
+    /// Parses `<class>=<template path>` or `<class>=redirect:<url>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (class, page) = s.split_once('=').ok_or_else(|| {
+            anyhow!(
+                "Expected error page to be <class>=<template path> or <class>=redirect:<url>, got {:?}.",
+                s
+            )
+        })?;
+        let page = match page.strip_prefix("redirect:") {
+            Some(url) => ErrorPage::Redirect(url.parse()?),
+            None => ErrorPage::Template(PathBuf::from(page)),
+        };
+
+        Ok(ErrorPageRule {
+            class: class.parse()?,
+            page,
+        })
+    }
+}
+
+enum LoadedPage {
+    Html(String),
+    Redirect(Url),
+}
+
+pub fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// The error pages of the proxy, with their templates read.
+#[derive(Default)]
+pub struct ErrorPages {
+    pages: HashMap<ErrorClass, LoadedPage>,
+}
+
+impl ErrorPages {
+    pub fn load(rules: &[ErrorPageRule]) -> Result<Self> {
+        let mut pages = HashMap::new();
+        pages.insert(
+            ErrorClass::Starting,
+            LoadedPage::Html(DEFAULT_STARTING_PAGE.to_string()),
+        );
+        for rule in rules {
+            let page = match &rule.page {
+                ErrorPage::Template(path) => LoadedPage::Html(
+                    std::fs::read_to_string(path)
+                        .with_context(|| format!("Error reading error page {:?}.", path))?,
+                ),
+                ErrorPage::Redirect(url) => LoadedPage::Redirect(url.clone()),
+            };
+            pages.insert(rule.class, page);
+        }
+
+        Ok(ErrorPages { pages })
+    }
+
+    /// The response to a request for the host which failed with the class of error. Errors
+    /// without a page configured, other than `Starting`, get an empty body.
+    pub fn response(&self, class: ErrorClass, host: Option<&str>) -> Result<Response<Body>> {
+        self.response_with_status(class, class.status(), host)
+    }
+
+    /// Like `response`, but with the given status in place of the class's own, unless the
+    /// class's page is a redirect.
+    pub fn response_with_status(
+        &self,
+        class: ErrorClass,
+        status: StatusCode,
+        host: Option<&str>,
+    ) -> Result<Response<Body>> {
+        let response = match self.pages.get(&class) {
+            Some(LoadedPage::Html(template)) => Response::builder()
+                .status(status)
+                .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+                .body(Body::from(template.replace(
+                    HOST_PLACEHOLDER,
+                    &escape_html(host.unwrap_or_default()),
+                )))?,
+            Some(LoadedPage::Redirect(url)) => Response::builder()
+                .status(StatusCode::FOUND)
+                .header(header::LOCATION, url.as_str())
+                .body(Body::empty())?,
+            None => Response::builder().status(status).body(Body::empty())?,
+        };
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_error_page_rule() {
+        assert_eq!(
+            ErrorPageRule {
+                class: ErrorClass::NotFound,
+                page: ErrorPage::Template(PathBuf::from("/etc/spawner/404.html")),
+            },
+            "not-found=/etc/spawner/404.html".parse().unwrap()
+        );
+        assert_eq!(
+            ErrorPageRule {
+                class: ErrorClass::Unavailable,
+                page: ErrorPage::Redirect("https://status.example.com/".parse().unwrap()),
+            },
+            "unavailable=redirect:https://status.example.com/"
+                .parse()
+                .unwrap()
+        );
+        assert!("teapot=/etc/spawner/418.html"
+            .parse::<ErrorPageRule>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_error_page_response() {
+        let mut pages = ErrorPages::default();
+        pages.pages.insert(
+            ErrorClass::NotFound,
+            LoadedPage::Html("<p>No backend at {{host}}.</p>".to_string()),
+        );
+
+        let response = pages
+            .response(ErrorClass::NotFound, Some("<script>.mycluster.test"))
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        let body = futures::executor::block_on(hyper::body::to_bytes(response.into_body()));
+        assert_eq!(
+            "<p>No backend at &lt;script&gt;.mycluster.test.</p>",
+            body.unwrap()
+        );
+
+        let response = pages.response(ErrorClass::Unavailable, None).unwrap();
+        assert_eq!(StatusCode::BAD_GATEWAY, response.status());
+    }
+}
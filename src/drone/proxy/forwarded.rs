@@ -0,0 +1,234 @@
+//! `Forwarded` and `X-Forwarded-*` headers, which tell backends the client's IP address
+//! and the protocol and host it connected to the proxy with.
+
+use anyhow::{anyhow, Result};
+use http::{header, HeaderMap, HeaderValue};
+use std::{net::IpAddr, str::FromStr};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+
+/// What the proxy does with forwarding headers on requests to a cluster's backends.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum ForwardedHeadersMode {
+    /// Add the client to the headers the request arrived with, e.g. when the proxy is
+    /// behind a trusted load balancer.
+    #[default]
+    Append,
+
+    /// Discard the headers the request arrived with, since a client could have set them
+    /// to anything, and set them afresh.
+    Replace,
+
+    /// Pass the request's headers through untouched.
+    Off,
+}
+
+impl FromStr for ForwardedHeadersMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "append" => Ok(ForwardedHeadersMode::Append),
+            "replace" => Ok(ForwardedHeadersMode::Replace),
+            "off" => Ok(ForwardedHeadersMode::Off),
+            _ => Err(anyhow!(
+                "Expected forwarded headers mode to be append, replace, or off, got {:?}.",
+                s
+            )),
+        }
+    }
+}
+
+/// The forwarded headers mode of one cluster domain, or of every cluster domain without
+/// a rule of its own.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct ForwardedHeadersRule {
+    pub cluster_domain: Option<String>,
+    pub mode: ForwardedHeadersMode,
+}
+
+impl FromStr for ForwardedHeadersRule {
+    type Err = anyhow::Error;
+
+    /// Parses `<mode>` or `<domain>=<mode>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((cluster_domain, mode)) => Ok(ForwardedHeadersRule {
+                cluster_domain: Some(cluster_domain.to_string()),
+                mode: mode.parse()?,
+            }),
+            None => Ok(ForwardedHeadersRule {
+                cluster_domain: None,
+                mode: s.parse()?,
+            }),
+        }
+    }
+}
+
+/// The mode that applies to the cluster domain under the rules.
+pub fn mode_for(rules: &[ForwardedHeadersRule], cluster_domain: &str) -> ForwardedHeadersMode {
+    rules
+        .iter()
+        .find(|rule| rule.cluster_domain.as_deref() == Some(cluster_domain))
+        .or_else(|| rules.iter().find(|rule| rule.cluster_domain.is_none()))
+        .map(|rule| rule.mode)
+        .unwrap_or_default()
+}
+
+/// A node of a `Forwarded` header. IPv6 addresses are bracketed and quoted (RFC 7239).
+fn forwarded_node(client_ip: IpAddr) -> String {
+    match client_ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    }
+}
+
+/// A `Forwarded` header value, quoting the host if it is not a valid token.
+fn forwarded_element(client_ip: IpAddr, proto: &str, host: Option<&str>) -> String {
+    let mut element = format!("for={};proto={}", forwarded_node(client_ip), proto);
+    if let Some(host) = host {
+        if host.contains(':') {
+            element.push_str(&format!(";host=\"{}\"", host));
+        } else {
+            element.push_str(&format!(";host={}", host));
+        }
+    }
+    element
+}
+
+fn append(headers: &mut HeaderMap, name: &'static str, value: &str) -> Result<()> {
+    let combined = match headers.get(name).map(HeaderValue::to_str) {
+        Some(Ok(existing)) => format!("{}, {}", existing, value),
+        _ => value.to_string(),
+    };
+    headers.insert(name, HeaderValue::from_str(&combined)?);
+    Ok(())
+}
+
+/// Add forwarding headers for a request from the client, which connected with the
+/// protocol `proto` (`http` or `https`) and asked for `host`.
+pub fn add_forwarded_headers(
+    headers: &mut HeaderMap,
+    mode: ForwardedHeadersMode,
+    client_ip: IpAddr,
+    proto: &str,
+    host: Option<&str>,
+) -> Result<()> {
+    match mode {
+        ForwardedHeadersMode::Off => return Ok(()),
+        ForwardedHeadersMode::Replace => {
+            for name in [X_FORWARDED_FOR, X_FORWARDED_PROTO, X_FORWARDED_HOST] {
+                headers.remove(name);
+            }
+            headers.remove(header::FORWARDED);
+        }
+        ForwardedHeadersMode::Append => {}
+    }
+
+    // Headers with a single value are kept as set by a trusted proxy in front of this one.
+    let forwarded = forwarded_element(client_ip, proto, host);
+    let existing_forwarded = headers
+        .get_all(header::FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let forwarded = if existing_forwarded.is_empty() {
+        forwarded
+    } else {
+        format!("{}, {}", existing_forwarded, forwarded)
+    };
+    headers.insert(header::FORWARDED, HeaderValue::from_str(&forwarded)?);
+
+    append(headers, X_FORWARDED_FOR, &client_ip.to_string())?;
+    if !headers.contains_key(X_FORWARDED_PROTO) {
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_str(proto)?);
+    }
+    if let Some(host) = host {
+        if !headers.contains_key(X_FORWARDED_HOST) {
+            headers.insert(X_FORWARDED_HOST, HeaderValue::from_str(host)?);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_add_forwarded_headers() {
+        let client_ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let mut incoming = HeaderMap::new();
+        incoming.insert(X_FORWARDED_FOR, HeaderValue::from_static("10.0.0.1"));
+        incoming.insert(X_FORWARDED_PROTO, HeaderValue::from_static("https"));
+        incoming.insert(header::FORWARDED, HeaderValue::from_static("for=10.0.0.1"));
+
+        let mut headers = incoming.clone();
+        add_forwarded_headers(
+            &mut headers,
+            ForwardedHeadersMode::Append,
+            client_ip,
+            "http",
+            Some("mybackend.mycluster.test"),
+        )
+        .unwrap();
+        assert_eq!("10.0.0.1, 203.0.113.7", headers[X_FORWARDED_FOR]);
+        assert_eq!("https", headers[X_FORWARDED_PROTO]);
+        assert_eq!("mybackend.mycluster.test", headers[X_FORWARDED_HOST]);
+        assert_eq!(
+            "for=10.0.0.1, for=203.0.113.7;proto=http;host=mybackend.mycluster.test",
+            headers[header::FORWARDED]
+        );
+
+        let mut headers = incoming.clone();
+        add_forwarded_headers(
+            &mut headers,
+            ForwardedHeadersMode::Replace,
+            "2001:db8::1".parse().unwrap(),
+            "https",
+            Some("mybackend.mycluster.test:8443"),
+        )
+        .unwrap();
+        assert_eq!("2001:db8::1", headers[X_FORWARDED_FOR]);
+        assert_eq!("https", headers[X_FORWARDED_PROTO]);
+        assert_eq!(
+            "for=\"[2001:db8::1]\";proto=https;host=\"mybackend.mycluster.test:8443\"",
+            headers[header::FORWARDED]
+        );
+
+        let mut headers = incoming.clone();
+        add_forwarded_headers(
+            &mut headers,
+            ForwardedHeadersMode::Off,
+            client_ip,
+            "http",
+            None,
+        )
+        .unwrap();
+        assert_eq!(incoming, headers);
+    }
+
+    #[test]
+    fn test_mode_for() {
+        let rules: Vec<ForwardedHeadersRule> = vec![
+            "replace".parse().unwrap(),
+            "internal.test=append".parse().unwrap(),
+        ];
+        assert_eq!(
+            ForwardedHeadersMode::Append,
+            mode_for(&rules, "internal.test")
+        );
+        assert_eq!(
+            ForwardedHeadersMode::Replace,
+            mode_for(&rules, "mycluster.test")
+        );
+        assert_eq!(
+            ForwardedHeadersMode::Append,
+            mode_for(&[], "mycluster.test")
+        );
+    }
+}
@@ -0,0 +1,176 @@
+//! Health and readiness endpoints of the proxy, served on `GET /healthz` and
+//! `GET /readyz` at a separate address from the proxy itself, so that orchestrators and
+//! load balancers can take an unhealthy proxy out of rotation.
+//!
+//! `/healthz` responds as long as the process is serving. `/readyz` responds 200 OK only
+//! once the proxy can route requests: its route table has synced with the drones of its
+//! clusters, its NATS connection is up, and its certificates have loaded, as applicable.
+//! Either way, the body lists the result of each check.
+
+use super::{certs::CertResolver, route_table::RouteTable};
+use crate::nats::TypedNats;
+use anyhow::Result;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use std::{
+    convert::Infallible,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// How long the NATS server has to acknowledge a flush for the connection to count as up.
+const NATS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What the proxy's readiness depends on.
+#[derive(Clone)]
+pub struct ProxyHealth {
+    /// The route table, if routes are received over NATS, and the clusters it serves.
+    route_table: Option<(RouteTable, Vec<String>)>,
+    nats: Option<TypedNats>,
+
+    /// Whether the proxy serves HTTPS, and so can't serve until its certificates load.
+    https: bool,
+    certs: Arc<Mutex<Vec<CertResolver>>>,
+}
+
+impl ProxyHealth {
+    pub fn new(
+        route_table: Option<(RouteTable, Vec<String>)>,
+        nats: Option<TypedNats>,
+        https: bool,
+    ) -> Self {
+        ProxyHealth {
+            route_table,
+            nats,
+            https,
+            certs: Arc::default(),
+        }
+    }
+
+    /// Wait for the certificate to load before reporting ready.
+    pub fn add_cert(&self, resolver: CertResolver) {
+        self.certs
+            .lock()
+            .expect("Health lock was poisoned.")
+            .push(resolver);
+    }
+
+    fn certs_loaded(&self) -> bool {
+        let certs = self.certs.lock().expect("Health lock was poisoned.");
+        !certs.is_empty() && certs.iter().all(CertResolver::is_loaded)
+    }
+
+    /// The name and result of each readiness check which applies to the proxy.
+    async fn checks(&self) -> Vec<(&'static str, bool)> {
+        let mut checks = Vec::new();
+        if let Some((route_table, clusters)) = &self.route_table {
+            checks.push(("route-table", route_table.is_synced(clusters)));
+        }
+        if let Some(nats) = &self.nats {
+            let connected = matches!(
+                tokio::time::timeout(NATS_TIMEOUT, nats.flush()).await,
+                Ok(Ok(()))
+            );
+            checks.push(("nats", connected));
+        }
+        if self.https {
+            checks.push(("certs", self.certs_loaded()));
+        }
+
+        checks
+    }
+
+    async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        let (status, body) = match (request.method(), request.uri().path()) {
+            (&Method::GET, "/healthz") => (StatusCode::OK, "ok\n".to_string()),
+            (&Method::GET, "/readyz") => {
+                let checks = self.checks().await;
+                let status = if checks.iter().all(|(_, ok)| *ok) {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                };
+                (status, render_checks(&checks))
+            }
+            (_, "/healthz" | "/readyz") => (StatusCode::METHOD_NOT_ALLOWED, String::new()),
+            _ => (StatusCode::NOT_FOUND, String::new()),
+        };
+
+        let mut response = Response::new(Body::from(body));
+        *response.status_mut() = status;
+        response
+    }
+}
+
+fn render_checks(checks: &[(&str, bool)]) -> String {
+    let mut out = String::new();
+    for (name, ok) in checks {
+        let _ = writeln!(out, "{}: {}", name, if *ok { "ok" } else { "failed" });
+    }
+    out
+}
+
+/// Serve the proxy's health and readiness on the given address until the server fails.
+pub async fn serve_health(address: SocketAddr, health: ProxyHealth) -> Result<()> {
+    let health = Arc::new(health);
+    let make_service = make_service_fn(move |_| {
+        let health = health.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let health = health.clone();
+                async move { Ok::<_, Infallible>(health.handle(request).await) }
+            }))
+        }
+    });
+
+    tracing::info!(%address, "Serving proxy health.");
+    Server::try_bind(&address)?.serve(make_service).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn get(health: &ProxyHealth, path: &str) -> (StatusCode, String) {
+        let request = Request::get(path).body(Body::empty()).unwrap();
+        let response = health.handle(request).await;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_readiness() {
+        let route_table = RouteTable::default();
+        let health = ProxyHealth::new(
+            Some((route_table, vec!["spawner.test".to_string()])),
+            None,
+            true,
+        );
+
+        assert_eq!(
+            (StatusCode::OK, "ok\n".to_string()),
+            get(&health, "/healthz").await
+        );
+        assert_eq!(
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "route-table: failed\ncerts: failed\n".to_string()
+            ),
+            get(&health, "/readyz").await
+        );
+
+        let health = ProxyHealth::new(None, None, false);
+        assert_eq!(
+            (StatusCode::OK, String::new()),
+            get(&health, "/readyz").await
+        );
+        assert_eq!(StatusCode::NOT_FOUND, get(&health, "/metrics").await.0);
+    }
+}
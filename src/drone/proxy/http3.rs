@@ -0,0 +1,414 @@
+//! HTTP/3 over QUIC, served on the HTTPS port's UDP counterpart with the same
+//! certificates and routing as HTTPS. Clients learn of it from the `Alt-Svc` header of
+//! responses, and fall back to HTTPS over TCP if UDP is blocked. Upgrades such as
+//! WebSockets are not carried over HTTP/3, so clients make them over TCP.
+
+use super::service::{MakeProxyService, RemoteAddr};
+use anyhow::Result;
+use bytes::{Buf, Bytes};
+use h3::{error::Code, server::RequestStream};
+use h3_quinn::{RecvStream, SendStream};
+use http::{HeaderMap, HeaderValue, Request, Response};
+use hyper::{body::HttpBody, service::Service, Body};
+use std::{net::SocketAddr, sync::Arc};
+
+const ALPN_H3: &[u8] = b"h3";
+
+/// How long clients may remember that HTTP/3 is available, in seconds.
+const ALT_SVC_MAX_AGE: u32 = 86400;
+
+/// Headers which are specific to an HTTP/1.1 connection, and may not be sent over
+/// HTTP/3 (RFC 9114, section 4.2).
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+impl RemoteAddr for quinn::Connection {
+    fn remote_addr(&self) -> SocketAddr {
+        self.remote_address()
+    }
+}
+
+/// The `Alt-Svc` header value advertising HTTP/3 on the port.
+pub fn alt_svc(port: u16) -> HeaderValue {
+    HeaderValue::from_str(&format!("h3=\":{}\"; ma={}", port, ALT_SVC_MAX_AGE))
+        .expect("Alt-Svc header value is always valid.")
+}
+
+/// Serve HTTP/3 requests on the UDP port, with the TLS configuration of the HTTPS port
+/// offering HTTP/3 rather than HTTP/2 and HTTP/1.1.
+pub fn serve_http3(
+    addr: SocketAddr,
+    tls_cfg: &rustls::ServerConfig,
+    make_proxy: MakeProxyService,
+) -> Result<impl std::future::Future<Output = ()>> {
+    let mut tls_cfg = tls_cfg.clone();
+    tls_cfg.alpn_protocols = vec![ALPN_H3.to_vec()];
+    let endpoint =
+        quinn::Endpoint::server(quinn::ServerConfig::with_crypto(Arc::new(tls_cfg)), addr)?;
+
+    Ok(async move {
+        while let Some(connecting) = endpoint.accept().await {
+            let make_proxy = make_proxy.clone();
+            tokio::spawn(async move {
+                let connection = match connecting.await {
+                    Ok(connection) => connection,
+                    Err(error) => {
+                        tracing::info!(?error, "Error accepting QUIC connection.");
+                        return;
+                    }
+                };
+                if let Err(error) = serve_connection(connection, make_proxy).await {
+                    tracing::info!(?error, "Error serving HTTP/3 connection.");
+                }
+            });
+        }
+    })
+}
+
+async fn serve_connection(
+    connection: quinn::Connection,
+    mut make_proxy: MakeProxyService,
+) -> Result<()> {
+    let proxy = make_proxy.call(&connection).await?;
+    let mut connection: h3::server::Connection<_, Bytes> =
+        h3::server::Connection::new(h3_quinn::Connection::new(connection)).await?;
+
+    while let Some((request, stream)) = connection.accept().await? {
+        let mut proxy = proxy.clone();
+        tokio::spawn(async move {
+            let (mut send, recv) = stream.split();
+            let (parts, ()) = request.into_parts();
+            let request = Request::from_parts(parts, request_body(recv));
+
+            match proxy.call(request).await {
+                Ok(response) => {
+                    if let Err(error) = send_response(&mut send, response).await {
+                        tracing::info!(?error, "Error sending HTTP/3 response.");
+                    }
+                }
+                // The error has been logged by the proxy.
+                Err(_) => send.stop_stream(Code::H3_INTERNAL_ERROR),
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// The body of a request, read from its stream as the proxy forwards it.
+fn request_body(mut recv: RequestStream<RecvStream, Bytes>) -> Body {
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        loop {
+            match recv.recv_data().await {
+                Ok(Some(mut data)) => {
+                    let chunk = data.copy_to_bytes(data.remaining());
+                    if sender.send_data(chunk).await.is_err() {
+                        // The proxy has stopped reading the body.
+                        recv.stop_sending(Code::H3_NO_ERROR);
+                        break;
+                    }
+                }
+                Ok(None) => {
+                    if let Ok(Some(trailers)) = recv.recv_trailers().await {
+                        let _ = sender.send_trailers(trailers).await;
+                    }
+                    break;
+                }
+                Err(error) => {
+                    tracing::info!(?error, "Error reading HTTP/3 request body.");
+                    sender.abort();
+                    break;
+                }
+            }
+        }
+    });
+
+    body
+}
+
+fn strip_connection_headers(headers: &mut HeaderMap) {
+    for name in CONNECTION_HEADERS {
+        headers.remove(name);
+    }
+}
+
+async fn send_response(
+    send: &mut RequestStream<SendStream<Bytes>, Bytes>,
+    response: Response<Body>,
+) -> Result<()> {
+    let (mut parts, mut body) = response.into_parts();
+    strip_connection_headers(&mut parts.headers);
+    send.send_response(Response::from_parts(parts, ())).await?;
+
+    while let Some(chunk) = body.data().await {
+        send.send_data(chunk?).await?;
+    }
+    match body.trailers().await? {
+        Some(trailers) => send.send_trailers(trailers).await?,
+        None => send.finish().await?,
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        database_connection::DatabaseConnection,
+        drone::proxy::{
+            connection_tracker::ConnectionTracker, proxy_protocol::ProxyProtocol,
+            request_limits::RequestLimits, static_site::StaticSiteRule, timeouts::UpstreamTimeouts,
+            ProxyHttpsOptions, ProxyOptions,
+        },
+        keys::KeyCertPathPair,
+        types::BackendId,
+    };
+    use futures::future::poll_fn;
+    use h3::client::SendRequest;
+    use http::StatusCode;
+    use hyper::service::{make_service_fn, service_fn};
+    use openssl::{
+        asn1::Asn1Time,
+        bn::BigNum,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::PKey,
+        x509::{
+            extension::{BasicConstraints, SubjectAlternativeName},
+            X509NameBuilder, X509,
+        },
+    };
+    use rustls::{Certificate, PrivateKey, RootCertStore};
+    use std::{convert::Infallible, time::Duration};
+
+    /// A self-signed certificate for `spawner.test` and its subdomains, and its key.
+    fn self_signed_certificate() -> (Certificate, PrivateKey) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let private_key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "spawner.test")
+            .unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&private_key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder
+            .append_extension(BasicConstraints::new().critical().build().unwrap())
+            .unwrap();
+        let subject_alternative_name = SubjectAlternativeName::new()
+            .dns("spawner.test")
+            .dns("*.spawner.test")
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(subject_alternative_name).unwrap();
+        builder.sign(&private_key, MessageDigest::sha256()).unwrap();
+
+        let pem = private_key.private_key_to_pem_pkcs8().unwrap();
+        let private_key = rustls_pemfile::pkcs8_private_keys(&mut pem.as_slice())
+            .unwrap()
+            .pop()
+            .unwrap();
+        (
+            Certificate(builder.build().to_der().unwrap()),
+            PrivateKey(private_key),
+        )
+    }
+
+    /// Make a GET request over HTTP/3, returning the response and its body.
+    async fn get(
+        send_request: &mut SendRequest<h3_quinn::OpenStreams, Bytes>,
+        uri: &str,
+    ) -> (Response<()>, Vec<u8>) {
+        let request = Request::get(uri).body(()).unwrap();
+        let mut stream = send_request.send_request(request).await.unwrap();
+        stream.finish().await.unwrap();
+
+        let response = stream.recv_response().await.unwrap();
+        let mut body = Vec::new();
+        while let Some(mut chunk) = stream.recv_data().await.unwrap() {
+            body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+        }
+        (response, body)
+    }
+
+    #[test]
+    fn test_alt_svc() {
+        assert_eq!("h3=\":443\"; ma=86400", alt_svc(443));
+    }
+
+    #[tokio::test]
+    async fn test_serve_http3() {
+        let dir = std::env::temp_dir().join(format!("spawner-http3-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("site")).unwrap();
+        std::fs::write(dir.join("site/index.html"), "<p>Hello over QUIC!</p>").unwrap();
+        let addr = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let db = DatabaseConnection::new(dir.join("drone.db").to_string_lossy().to_string());
+
+        // A backend, routed to through the drone's database.
+        let backend = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(
+            |_| async {
+                Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                    Ok::<_, Infallible>(Response::new(Body::from(format!(
+                        "Hello from the backend at {}!",
+                        req.uri().path()
+                    ))))
+                }))
+            },
+        ));
+        let backend_addr = backend.local_addr();
+        tokio::spawn(backend);
+        let spawn_request = serde_json::from_value(serde_json::json!({
+            "image": "image",
+            "backend_id": "backend",
+            "max_idle_secs": 60,
+            "env": {},
+            "metadata": {},
+        }))
+        .unwrap();
+        let drone_db = db.connection().await.unwrap();
+        drone_db.insert_backend(&spawn_request).await.unwrap();
+        drone_db
+            .insert_proxy_route(
+                &BackendId::new("backend".to_string()),
+                "backend",
+                &backend_addr.to_string(),
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        let options = ProxyOptions {
+            db: db.clone(),
+            cluster_domain: "spawner.test".to_string(),
+            http_port: 80,
+            https_options: Some(ProxyHttpsOptions {
+                port: addr.port(),
+                key_paths: KeyCertPathPair {
+                    private_key_path: dir.join("proxy.key"),
+                    certificate_path: dir.join("proxy.cert"),
+                },
+                http3: true,
+            }),
+            additional_clusters: vec![],
+            host_certs: vec![],
+            access_log: None,
+            cors_config: None,
+            forwarded_headers: vec![],
+            compression: vec![],
+            static_sites: vec![StaticSiteRule {
+                cluster_domain: None,
+                path: dir.join("site"),
+            }],
+            route_updates: false,
+            publish_activity: false,
+            metrics_address: None,
+            health_address: None,
+            error_pages: Vec::new(),
+            hold_starting: Duration::ZERO,
+            starting_page: false,
+            path_routing: false,
+            connection_limit_status: StatusCode::SERVICE_UNAVAILABLE,
+            route_cache_ttl: Duration::ZERO,
+            request_limits: RequestLimits::default(),
+            upstream_timeouts: UpstreamTimeouts::default(),
+            affinity_cookie: None,
+            trace_spans: false,
+            ocsp_stapling: false,
+            alternate_key_types: Vec::new(),
+            acme_http_challenges: false,
+            custom_domains: false,
+            receive_certs: false,
+            cert_store: None,
+            backend_ca: None,
+            proxy_protocol: ProxyProtocol::default(),
+            backend_rate_limit: None,
+            client_rate_limit: None,
+            tcp_port_range: None,
+            udp_port_range: None,
+            jwt: None,
+            nats: None,
+        };
+        let make_proxy = MakeProxyService::new(
+            drone_db,
+            &options,
+            ConnectionTracker::default(),
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let (certificate, private_key) = self_signed_certificate();
+        let server_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![certificate.clone()], private_key)
+            .unwrap();
+        tokio::spawn(serve_http3(addr, &server_config, make_proxy).unwrap());
+
+        let mut roots = RootCertStore::empty();
+        roots.add(&certificate).unwrap();
+        let mut client_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![ALPN_H3.to_vec()];
+        let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(client_config)));
+        let connection = endpoint
+            .connect(addr, "backend.spawner.test")
+            .unwrap()
+            .await
+            .unwrap();
+
+        let (mut driver, mut send_request) = h3::client::new(h3_quinn::Connection::new(connection))
+            .await
+            .unwrap();
+        tokio::spawn(async move { poll_fn(|cx| driver.poll_close(cx)).await });
+
+        let (response, body) = get(&mut send_request, "https://spawner.test/").await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            alt_svc(addr.port()),
+            response.headers()[http::header::ALT_SVC]
+        );
+        assert_eq!(b"<p>Hello over QUIC!</p>".as_slice(), body);
+
+        let (response, body) = get(&mut send_request, "https://backend.spawner.test/path").await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            alt_svc(addr.port()),
+            response.headers()[http::header::ALT_SVC]
+        );
+        assert_eq!(b"Hello from the backend at /path!".as_slice(), body);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,431 @@
+//! Validation of JWTs presented to the proxy against the keys published at a JWKS URL,
+//! so that backends can be fronted by an existing OIDC provider.
+//!
+//! A token is accepted if it is signed with RS256, RS384, RS512, ES256 or ES384 by one
+//! of the published keys, has not expired, and carries the configured issuer, audience,
+//! and a claim equal to the ID of the backend it is presented to.
+
+use anyhow::{anyhow, Context, Result};
+use openssl::{
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    ecdsa::EcdsaSig,
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{Id, PKey, Public},
+    rsa::Rsa,
+    sign::Verifier,
+};
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::RwLock, time::Instant};
+
+/// Keys are fetched again after this long, so that rotated keys are picked up.
+const KEYS_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// A token signed by an unknown key causes the keys to be fetched again, but no more
+/// often than this.
+const KEYS_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Tolerance for clock differences between the proxy and the token's issuer.
+const CLOCK_LEEWAY_SECS: i64 = 60;
+
+/// How JWTs are validated, configured at the drone level.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct JwtOptions {
+    /// URL of the JSON Web Key Set whose keys tokens must be signed with.
+    pub jwks_url: Url,
+
+    /// Required value of the `iss` claim.
+    pub issuer: Option<String>,
+
+    /// Required value (or one of the values) of the `aud` claim.
+    pub audience: Option<String>,
+
+    /// Claim which must equal the ID of the backend the request is for.
+    pub backend_claim: String,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+struct VerificationKey {
+    kid: Option<String>,
+    key: PKey<Public>,
+}
+
+fn decode_segment(segment: &str) -> Result<Vec<u8>> {
+    Ok(base64::decode_config(segment, base64::URL_SAFE_NO_PAD)?)
+}
+
+fn big_num(value: &Option<String>, name: &str) -> Result<BigNum> {
+    let value = value
+        .as_ref()
+        .ok_or_else(|| anyhow!("Expected key to have {}.", name))?;
+    Ok(BigNum::from_slice(&decode_segment(value)?)?)
+}
+
+impl Jwk {
+    fn public_key(&self) -> Result<PKey<Public>> {
+        match self.kty.as_str() {
+            "RSA" => {
+                let rsa =
+                    Rsa::from_public_components(big_num(&self.n, "n")?, big_num(&self.e, "e")?)?;
+                Ok(PKey::from_rsa(rsa)?)
+            }
+            "EC" => {
+                let nid = match self.crv.as_deref() {
+                    Some("P-256") => Nid::X9_62_PRIME256V1,
+                    Some("P-384") => Nid::SECP384R1,
+                    crv => return Err(anyhow!("Unsupported curve {:?}.", crv)),
+                };
+                let group = EcGroup::from_curve_name(nid)?;
+                let x = big_num(&self.x, "x")?;
+                let y = big_num(&self.y, "y")?;
+                let ec = EcKey::from_public_key_affine_coordinates(&group, &x, &y)?;
+                Ok(PKey::from_ec_key(ec)?)
+            }
+            kty => Err(anyhow!("Unsupported key type {}.", kty)),
+        }
+    }
+}
+
+/// Parse the keys of a JWKS document, skipping keys which cannot verify tokens.
+fn parse_jwks(jwks: &JwkSet) -> Vec<VerificationKey> {
+    jwks.keys
+        .iter()
+        .filter_map(|jwk| match jwk.public_key() {
+            Ok(key) => Some(VerificationKey {
+                kid: jwk.kid.clone(),
+                key,
+            }),
+            Err(error) => {
+                tracing::debug!(?error, kid = ?jwk.kid, "Skipping key in JWKS.");
+                None
+            }
+        })
+        .collect()
+}
+
+/// The message digest and key type of a signing algorithm. Symmetric algorithms and
+/// `none` are rejected, since the keys are public.
+fn algorithm(alg: &str) -> Result<(MessageDigest, Id)> {
+    match alg {
+        "RS256" => Ok((MessageDigest::sha256(), Id::RSA)),
+        "RS384" => Ok((MessageDigest::sha384(), Id::RSA)),
+        "RS512" => Ok((MessageDigest::sha512(), Id::RSA)),
+        "ES256" => Ok((MessageDigest::sha256(), Id::EC)),
+        "ES384" => Ok((MessageDigest::sha384(), Id::EC)),
+        _ => Err(anyhow!("Unsupported JWT algorithm {}.", alg)),
+    }
+}
+
+/// A parsed, but not yet verified, JWT.
+struct UnverifiedToken<'a> {
+    header: JwtHeader,
+    signing_input: &'a str,
+    signature: Vec<u8>,
+    claims: &'a str,
+}
+
+impl<'a> UnverifiedToken<'a> {
+    fn parse(token: &'a str) -> Result<Self> {
+        let (signing_input, signature) = token
+            .rsplit_once('.')
+            .ok_or_else(|| anyhow!("Expected JWT to have three segments."))?;
+        let (header, claims) = signing_input
+            .split_once('.')
+            .ok_or_else(|| anyhow!("Expected JWT to have three segments."))?;
+
+        Ok(UnverifiedToken {
+            header: serde_json::from_slice(&decode_segment(header)?)
+                .context("Expected JWT header to be JSON.")?,
+            signing_input,
+            signature: decode_segment(signature)?,
+            claims,
+        })
+    }
+
+    fn is_signed_by(&self, key: &PKey<Public>) -> Result<bool> {
+        let (digest, key_type) = algorithm(&self.header.alg)?;
+        if key.id() != key_type {
+            return Ok(false);
+        }
+
+        // JWS encodes ECDSA signatures as r and s concatenated, rather than as DER.
+        let signature = if key_type == Id::EC {
+            let (r, s) = self.signature.split_at(self.signature.len() / 2);
+            EcdsaSig::from_private_components(BigNum::from_slice(r)?, BigNum::from_slice(s)?)?
+                .to_der()?
+        } else {
+            self.signature.clone()
+        };
+
+        let mut verifier = Verifier::new(digest, key)?;
+        verifier.update(self.signing_input.as_bytes())?;
+        Ok(verifier.verify(&signature).unwrap_or(false))
+    }
+
+    /// The key which the token names, or every key if it does not name one.
+    fn candidate_keys<'k>(
+        &self,
+        keys: &'k [VerificationKey],
+    ) -> impl Iterator<Item = &'k VerificationKey> {
+        let kid = self.header.kid.clone();
+        keys.iter()
+            .filter(move |key| kid.is_none() || key.kid == kid)
+    }
+
+    /// The token's claims, if it is signed by one of the keys, or None if none of them
+    /// match.
+    fn verify(&self, keys: &[VerificationKey]) -> Result<Option<Value>> {
+        for key in self.candidate_keys(keys) {
+            if self.is_signed_by(&key.key)? {
+                let claims = serde_json::from_slice(&decode_segment(self.claims)?)
+                    .context("Expected JWT claims to be JSON.")?;
+                return Ok(Some(claims));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Check the claims of a verified token against the options, for a request to the
+/// given backend at the given time in seconds since the epoch.
+fn check_claims(claims: &Value, options: &JwtOptions, backend_id: &str, now: i64) -> Result<()> {
+    let expires_at = claims["exp"]
+        .as_i64()
+        .ok_or_else(|| anyhow!("Expected JWT to have an exp claim."))?;
+    if now > expires_at + CLOCK_LEEWAY_SECS {
+        return Err(anyhow!("JWT has expired."));
+    }
+    if let Some(not_before) = claims["nbf"].as_i64() {
+        if now + CLOCK_LEEWAY_SECS < not_before {
+            return Err(anyhow!("JWT is not yet valid."));
+        }
+    }
+
+    if let Some(issuer) = &options.issuer {
+        if claims["iss"].as_str() != Some(issuer) {
+            return Err(anyhow!("Expected JWT to be issued by {}.", issuer));
+        }
+    }
+
+    if let Some(audience) = &options.audience {
+        let has_audience = match &claims["aud"] {
+            Value::String(aud) => aud == audience,
+            Value::Array(auds) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+            _ => false,
+        };
+        if !has_audience {
+            return Err(anyhow!("Expected JWT to have audience {}.", audience));
+        }
+    }
+
+    if claims[&options.backend_claim].as_str() != Some(backend_id) {
+        return Err(anyhow!(
+            "Expected JWT claim {} to be {}.",
+            options.backend_claim,
+            backend_id
+        ));
+    }
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct KeyCache {
+    keys: Vec<VerificationKey>,
+    fetched_at: Option<Instant>,
+}
+
+/// Validates JWTs, fetching and caching the keys of the JWKS as they are needed.
+#[derive(Clone)]
+pub struct JwtValidator {
+    options: Arc<JwtOptions>,
+    client: Client,
+    cache: Arc<RwLock<KeyCache>>,
+}
+
+impl JwtValidator {
+    pub fn new(options: JwtOptions) -> Self {
+        JwtValidator {
+            options: Arc::new(options),
+            client: Client::new(),
+            cache: Arc::default(),
+        }
+    }
+
+    /// Fetch the keys again, unless another request did so since `fetched_at`.
+    async fn refresh(&self, fetched_at: Option<Instant>) -> Result<()> {
+        let mut cache = self.cache.write().await;
+        if cache.fetched_at != fetched_at {
+            return Ok(());
+        }
+
+        let jwks: JwkSet = self
+            .client
+            .get(self.options.jwks_url.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .with_context(|| format!("Error reading JWKS from {}.", self.options.jwks_url))?;
+
+        cache.keys = parse_jwks(&jwks);
+        cache.fetched_at = Some(Instant::now());
+        tracing::info!(keys = cache.keys.len(), "Fetched JWKS.");
+
+        Ok(())
+    }
+
+    /// Return an error unless the token is valid for a request to the backend.
+    pub async fn validate(&self, token: &str, backend_id: &str) -> Result<()> {
+        let token = UnverifiedToken::parse(token)?;
+
+        let mut refreshed = false;
+        let claims = loop {
+            let fetched_at = {
+                let cache = self.cache.read().await;
+                let stale = match cache.fetched_at {
+                    Some(fetched_at) => fetched_at.elapsed() > KEYS_MAX_AGE,
+                    None => true,
+                };
+                if !stale {
+                    if let Some(claims) = token.verify(&cache.keys)? {
+                        break claims;
+                    }
+                }
+
+                let can_refresh = stale
+                    || cache
+                        .fetched_at
+                        .map(|fetched_at| fetched_at.elapsed() > KEYS_MIN_REFRESH_INTERVAL)
+                        .unwrap_or(true);
+                if refreshed || !can_refresh {
+                    return Err(anyhow!("JWT is not signed by a key in the JWKS."));
+                }
+                cache.fetched_at
+            };
+
+            self.refresh(fetched_at).await?;
+            refreshed = true;
+        };
+
+        let now = chrono::Utc::now().timestamp();
+        check_claims(&claims, &self.options, backend_id, now)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use openssl::sign::Signer;
+    use serde_json::json;
+
+    fn options() -> JwtOptions {
+        JwtOptions {
+            jwks_url: Url::parse("https://auth.example.com/.well-known/jwks.json").unwrap(),
+            issuer: Some("https://auth.example.com/".to_string()),
+            audience: Some("spawner".to_string()),
+            backend_claim: "backend_id".to_string(),
+        }
+    }
+
+    fn encode_segment(bytes: &[u8]) -> String {
+        base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+    }
+
+    #[test]
+    fn test_verify_and_check_claims() {
+        let rsa = Rsa::generate(2048).unwrap();
+        let private_key = PKey::from_rsa(rsa.clone()).unwrap();
+        let jwks: JwkSet = serde_json::from_value(json!({
+            "keys": [
+                {"kty": "oct", "kid": "symmetric", "k": "c2VjcmV0"},
+                {
+                    "kty": "RSA",
+                    "kid": "key-1",
+                    "n": encode_segment(&rsa.n().to_vec()),
+                    "e": encode_segment(&rsa.e().to_vec()),
+                },
+            ]
+        }))
+        .unwrap();
+        let keys = parse_jwks(&jwks);
+        assert_eq!(1, keys.len());
+
+        let claims = json!({
+            "iss": "https://auth.example.com/",
+            "aud": ["spawner", "other"],
+            "backend_id": "mybackend",
+            "exp": 2000,
+        });
+        let signing_input = format!(
+            "{}.{}",
+            encode_segment(br#"{"alg":"RS256","kid":"key-1"}"#),
+            encode_segment(claims.to_string().as_bytes())
+        );
+        let mut signer = Signer::new(MessageDigest::sha256(), &private_key).unwrap();
+        signer.update(signing_input.as_bytes()).unwrap();
+        let signature = signer.sign_to_vec().unwrap();
+        let token = format!("{}.{}", signing_input, encode_segment(&signature));
+
+        let verified = UnverifiedToken::parse(&token)
+            .unwrap()
+            .verify(&keys)
+            .unwrap()
+            .unwrap();
+        assert_eq!(claims, verified);
+
+        let tampered = format!(
+            "{}.{}.{}",
+            encode_segment(br#"{"alg":"RS256","kid":"key-1"}"#),
+            encode_segment(
+                json!({"backend_id": "otherbackend", "exp": 2000})
+                    .to_string()
+                    .as_bytes()
+            ),
+            encode_segment(&signature)
+        );
+        assert_eq!(
+            None,
+            UnverifiedToken::parse(&tampered)
+                .unwrap()
+                .verify(&keys)
+                .unwrap()
+        );
+
+        assert!(check_claims(&verified, &options(), "mybackend", 1000).is_ok());
+        assert!(check_claims(&verified, &options(), "otherbackend", 1000).is_err());
+        assert!(check_claims(&verified, &options(), "mybackend", 3000).is_err());
+        let wrong_audience = JwtOptions {
+            audience: Some("billing".to_string()),
+            ..options()
+        };
+        assert!(check_claims(&verified, &wrong_audience, "mybackend", 1000).is_err());
+    }
+}
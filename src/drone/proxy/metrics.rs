@@ -0,0 +1,449 @@
+//! Prometheus metrics of the proxy, served in the text exposition format on
+//! `GET /metrics` at a separate address from the proxy itself.
+//!
+//! Per-backend metrics are forgotten an hour after the backend's last request, so that
+//! the number of series does not grow with every backend the proxy has ever served.
+//!
+//! Each certificate's expiry is read from the certificate the proxy has loaded, and the
+//! status of its last renewal from the renewals the certificate refresher publishes.
+
+use super::{certs::CertResolver, connection_tracker::ConnectionTracker, route_table::RouteTable};
+use crate::{
+    database::DroneDatabase, messages::cert::CertificateRenewal, nats::TypedNats, types::BackendId,
+};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// Upper bounds, in seconds, of the buckets of the request latency histogram.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// How long the metrics of a backend without requests are kept.
+const BACKEND_METRICS_EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+const TEXT_FORMAT: &str = "text/plain; version=0.0.4";
+
+#[derive(Default)]
+struct Histogram {
+    /// The number of observations in each bucket, not counting those in lower buckets.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+struct BackendMetrics {
+    /// Responses by status code.
+    responses: BTreeMap<u16, u64>,
+
+    /// Time until the backend's response headers arrived.
+    latency: Histogram,
+    upstream_errors: u64,
+    last_seen: Instant,
+}
+
+impl BackendMetrics {
+    fn new(now: Instant) -> Self {
+        BackendMetrics {
+            responses: BTreeMap::new(),
+            latency: Histogram::default(),
+            upstream_errors: 0,
+            last_seen: now,
+        }
+    }
+}
+
+/// Metrics of the requests the proxy has forwarded to each backend.
+#[derive(Clone, Default)]
+pub struct ProxyMetrics {
+    backends: Arc<Mutex<BTreeMap<String, BackendMetrics>>>,
+
+    /// The certificate of each cluster domain served over HTTPS.
+    certs: Arc<Mutex<Vec<(String, CertResolver)>>>,
+
+    /// The last renewal of each cluster's certificate since the proxy started.
+    renewals: Arc<Mutex<BTreeMap<String, CertificateRenewal>>>,
+}
+
+impl ProxyMetrics {
+    fn with_backend(&self, backend_id: &BackendId, f: impl FnOnce(&mut BackendMetrics)) {
+        let now = Instant::now();
+        let mut backends = self.backends.lock().expect("Metrics lock was poisoned.");
+        let metrics = backends
+            .entry(backend_id.id().to_string())
+            .or_insert_with(|| BackendMetrics::new(now));
+        metrics.last_seen = now;
+        f(metrics);
+    }
+
+    /// Record a response from the backend, or from the proxy on its behalf.
+    pub fn record_response(&self, backend_id: &BackendId, status: StatusCode, latency: Duration) {
+        self.with_backend(backend_id, |metrics| {
+            *metrics.responses.entry(status.as_u16()).or_default() += 1;
+            metrics.latency.observe(latency.as_secs_f64());
+        });
+    }
+
+    /// Record a request which could not be forwarded to the backend, e.g. because it
+    /// refused the connection.
+    pub fn record_upstream_error(&self, backend_id: &BackendId) {
+        self.with_backend(backend_id, |metrics| metrics.upstream_errors += 1);
+    }
+
+    pub fn add_cert(&self, cluster_domain: String, resolver: CertResolver) {
+        self.certs
+            .lock()
+            .expect("Metrics lock was poisoned.")
+            .push((cluster_domain, resolver));
+    }
+
+    pub fn record_renewal(&self, renewal: CertificateRenewal) {
+        self.renewals
+            .lock()
+            .expect("Metrics lock was poisoned.")
+            .insert(renewal.cluster.clone(), renewal);
+    }
+
+    /// Record the renewals of the cluster's certificate published over NATS.
+    pub async fn listen_renewals(self, nats: TypedNats, cluster_domain: String) -> Result<()> {
+        let mut sub = nats
+            .subscribe(&CertificateRenewal::subject(&cluster_domain))
+            .await?;
+        loop {
+            match sub.next().await {
+                Ok(Some(message)) => self.record_renewal(message.value),
+                Ok(None) => return Err(anyhow!("Certificate renewal subscription closed.")),
+                Err(error) => {
+                    tracing::warn!(
+                        ?error,
+                        "Non-fatal error when listening for certificate renewals."
+                    )
+                }
+            }
+        }
+    }
+
+    /// Render the per-certificate metrics.
+    fn render_certs(&self, out: &mut String, now: DateTime<Utc>) -> std::fmt::Result {
+        writeln!(out, "# HELP spawner_proxy_certificate_expiry_days Days until each cluster's loaded certificate expires.")?;
+        writeln!(out, "# TYPE spawner_proxy_certificate_expiry_days gauge")?;
+        for (cluster, resolver) in self
+            .certs
+            .lock()
+            .expect("Metrics lock was poisoned.")
+            .iter()
+        {
+            if let Some(valid_until) = resolver.valid_until() {
+                writeln!(
+                    out,
+                    "spawner_proxy_certificate_expiry_days{{cluster=\"{}\"}} {}",
+                    cluster,
+                    (valid_until - now).num_seconds() as f64 / 86400.0
+                )?;
+            }
+        }
+
+        let renewals = self.renewals.lock().expect("Metrics lock was poisoned.");
+        writeln!(out, "# HELP spawner_proxy_certificate_renewal_success Whether the last renewal of each cluster's certificate succeeded.")?;
+        writeln!(
+            out,
+            "# TYPE spawner_proxy_certificate_renewal_success gauge"
+        )?;
+        for (cluster, renewal) in renewals.iter() {
+            writeln!(
+                out,
+                "spawner_proxy_certificate_renewal_success{{cluster=\"{}\"}} {}",
+                cluster,
+                u8::from(renewal.success)
+            )?;
+        }
+
+        writeln!(out, "# HELP spawner_proxy_certificate_last_renewal_timestamp_seconds When each cluster's certificate was last renewed, or renewal last failed.")?;
+        writeln!(
+            out,
+            "# TYPE spawner_proxy_certificate_last_renewal_timestamp_seconds gauge"
+        )?;
+        for (cluster, renewal) in renewals.iter() {
+            writeln!(
+                out,
+                "spawner_proxy_certificate_last_renewal_timestamp_seconds{{cluster=\"{}\"}} {}",
+                cluster,
+                renewal.time.timestamp()
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Render the per-backend metrics, forgetting those of backends without recent requests.
+    fn render_backends(&self, out: &mut String, now: Instant) -> std::fmt::Result {
+        let mut backends = self.backends.lock().expect("Metrics lock was poisoned.");
+        backends
+            .retain(|_, metrics| now.duration_since(metrics.last_seen) < BACKEND_METRICS_EXPIRY);
+
+        writeln!(out, "# HELP spawner_proxy_requests_total Requests forwarded to each backend, by response status.")?;
+        writeln!(out, "# TYPE spawner_proxy_requests_total counter")?;
+        for (backend, metrics) in backends.iter() {
+            for (status, count) in &metrics.responses {
+                writeln!(
+                    out,
+                    "spawner_proxy_requests_total{{backend=\"{}\",status=\"{}\"}} {}",
+                    backend, status, count
+                )?;
+            }
+        }
+
+        writeln!(out, "# HELP spawner_proxy_request_duration_seconds Time until each backend's response headers arrived.")?;
+        writeln!(
+            out,
+            "# TYPE spawner_proxy_request_duration_seconds histogram"
+        )?;
+        for (backend, metrics) in backends.iter() {
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(metrics.latency.buckets) {
+                cumulative += count;
+                writeln!(
+                    out,
+                    "spawner_proxy_request_duration_seconds_bucket{{backend=\"{}\",le=\"{}\"}} {}",
+                    backend, bound, cumulative
+                )?;
+            }
+            writeln!(
+                out,
+                "spawner_proxy_request_duration_seconds_bucket{{backend=\"{}\",le=\"+Inf\"}} {}",
+                backend, metrics.latency.count
+            )?;
+            writeln!(
+                out,
+                "spawner_proxy_request_duration_seconds_sum{{backend=\"{}\"}} {}",
+                backend, metrics.latency.sum
+            )?;
+            writeln!(
+                out,
+                "spawner_proxy_request_duration_seconds_count{{backend=\"{}\"}} {}",
+                backend, metrics.latency.count
+            )?;
+        }
+
+        writeln!(out, "# HELP spawner_proxy_upstream_errors_total Requests which could not be forwarded to each backend.")?;
+        writeln!(out, "# TYPE spawner_proxy_upstream_errors_total counter")?;
+        for (backend, metrics) in backends.iter() {
+            writeln!(
+                out,
+                "spawner_proxy_upstream_errors_total{{backend=\"{}\"}} {}",
+                backend, metrics.upstream_errors
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+struct MetricsContext {
+    metrics: ProxyMetrics,
+    db: DroneDatabase,
+    connection_tracker: ConnectionTracker,
+    route_table: Option<RouteTable>,
+}
+
+impl MetricsContext {
+    async fn render(&self) -> Result<String> {
+        let mut out = String::new();
+        self.metrics.render_backends(&mut out, Instant::now())?;
+        self.metrics.render_certs(&mut out, Utc::now())?;
+
+        // Connections are tracked by route, i.e. by subdomain or TCP/UDP route name.
+        writeln!(
+            out,
+            "# HELP spawner_proxy_active_connections Connections open through each route."
+        )?;
+        writeln!(out, "# TYPE spawner_proxy_active_connections gauge")?;
+        for (route, connections) in self.connection_tracker.open_connections() {
+            writeln!(
+                out,
+                "spawner_proxy_active_connections{{route=\"{}\"}} {}",
+                route, connections
+            )?;
+        }
+
+        writeln!(
+            out,
+            "# HELP spawner_proxy_routes Live routes known to the proxy, by where it learned them."
+        )?;
+        writeln!(out, "# TYPE spawner_proxy_routes gauge")?;
+        let database_routes = self.db.get_live_proxy_routes().await?.len();
+        writeln!(
+            out,
+            "spawner_proxy_routes{{source=\"database\"}} {}",
+            database_routes
+        )?;
+        if let Some(route_table) = &self.route_table {
+            writeln!(
+                out,
+                "spawner_proxy_routes{{source=\"nats\"}} {}",
+                route_table.len()
+            )?;
+        }
+
+        Ok(out)
+    }
+
+    async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        let (status, body) = match (request.method(), request.uri().path()) {
+            (&Method::GET, "/metrics") => match self.render().await {
+                Ok(body) => (StatusCode::OK, body),
+                Err(error) => {
+                    tracing::warn!(?error, "Error rendering proxy metrics.");
+                    (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", error))
+                }
+            },
+            (_, "/metrics") => (StatusCode::METHOD_NOT_ALLOWED, String::new()),
+            _ => (StatusCode::NOT_FOUND, String::new()),
+        };
+
+        let mut response = Response::new(Body::from(body));
+        *response.status_mut() = status;
+        if status == StatusCode::OK {
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                TEXT_FORMAT
+                    .parse()
+                    .expect("Content type should always parse."),
+            );
+        }
+        response
+    }
+}
+
+/// Serve the proxy's metrics on the given address until the server fails.
+pub async fn serve_metrics(
+    address: SocketAddr,
+    metrics: ProxyMetrics,
+    db: DroneDatabase,
+    connection_tracker: ConnectionTracker,
+    route_table: Option<RouteTable>,
+) -> Result<()> {
+    let context = Arc::new(MetricsContext {
+        metrics,
+        db,
+        connection_tracker,
+        route_table,
+    });
+    let make_service = make_service_fn(move |_| {
+        let context = context.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let context = context.clone();
+                async move { Ok::<_, Infallible>(context.handle(request).await) }
+            }))
+        }
+    });
+
+    tracing::info!(%address, "Serving proxy metrics.");
+    Server::try_bind(&address)?.serve(make_service).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_backend_metrics() {
+        let metrics = ProxyMetrics::default();
+        let backend_id = BackendId::new("mybackend".to_string());
+        metrics.record_response(&backend_id, StatusCode::OK, Duration::from_millis(20));
+        metrics.record_response(&backend_id, StatusCode::OK, Duration::from_millis(200));
+        metrics.record_response(&backend_id, StatusCode::NOT_FOUND, Duration::from_secs(30));
+        metrics.record_upstream_error(&backend_id);
+
+        let mut out = String::new();
+        metrics.render_backends(&mut out, Instant::now()).unwrap();
+        for line in [
+            "spawner_proxy_requests_total{backend=\"mybackend\",status=\"200\"} 2",
+            "spawner_proxy_requests_total{backend=\"mybackend\",status=\"404\"} 1",
+            "spawner_proxy_request_duration_seconds_bucket{backend=\"mybackend\",le=\"0.01\"} 0",
+            "spawner_proxy_request_duration_seconds_bucket{backend=\"mybackend\",le=\"0.025\"} 1",
+            "spawner_proxy_request_duration_seconds_bucket{backend=\"mybackend\",le=\"10\"} 2",
+            "spawner_proxy_request_duration_seconds_bucket{backend=\"mybackend\",le=\"+Inf\"} 3",
+            "spawner_proxy_request_duration_seconds_count{backend=\"mybackend\"} 3",
+            "spawner_proxy_upstream_errors_total{backend=\"mybackend\"} 1",
+        ] {
+            assert!(
+                out.lines().any(|l| l == line),
+                "Missing {:?} in {}",
+                line,
+                out
+            );
+        }
+
+        // Backends without recent requests are forgotten.
+        let mut out = String::new();
+        metrics
+            .render_backends(&mut out, Instant::now() + BACKEND_METRICS_EXPIRY)
+            .unwrap();
+        assert!(!out.contains("mybackend"));
+    }
+
+    #[test]
+    fn test_render_renewal_metrics() {
+        let metrics = ProxyMetrics::default();
+        let time = Utc::now();
+        metrics.record_renewal(CertificateRenewal {
+            cluster: "spawner.test".to_string(),
+            time,
+            success: true,
+            error: None,
+            valid_until: None,
+        });
+        metrics.record_renewal(CertificateRenewal {
+            cluster: "spawner.test".to_string(),
+            time,
+            success: false,
+            error: Some("Error refreshing certificate.".to_string()),
+            valid_until: None,
+        });
+
+        let mut out = String::new();
+        metrics.render_certs(&mut out, time).unwrap();
+        for line in [
+            "spawner_proxy_certificate_renewal_success{cluster=\"spawner.test\"} 0".to_string(),
+            format!(
+                "spawner_proxy_certificate_last_renewal_timestamp_seconds{{cluster=\"spawner.test\"}} {}",
+                time.timestamp()
+            ),
+        ] {
+            assert!(
+                out.lines().any(|l| l == line),
+                "Missing {:?} in {}",
+                line,
+                out
+            );
+        }
+    }
+}
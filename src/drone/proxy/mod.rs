@@ -1,24 +1,166 @@
 use self::{
-    certs::CertRefresher, connection_tracker::ConnectionTracker, service::MakeProxyService,
+    access_log::{AccessLogSink, AccessLogger},
+    acme_challenge::{serve_acme_challenges, AcmeChallenges},
+    activity::ActivityPublisher,
+    certs::{receive_certs, CertRefresher, CustomDomainCerts, SniCertResolver},
+    compression::CompressionRule,
+    connection_tracker::ConnectionTracker,
+    error_pages::ErrorPageRule,
+    forwarded::ForwardedHeadersRule,
+    health::{serve_health, ProxyHealth},
+    http3::serve_http3,
+    jwt::JwtOptions,
+    metrics::{serve_metrics, ProxyMetrics},
+    proxy_protocol::{ProxyProtocol, ProxyProtocolIncoming},
+    rate_limit::RateLimit,
+    request_limits::RequestLimits,
+    route_cache::RouteCache,
+    route_table::RouteTable,
+    service::MakeProxyService,
+    static_site::StaticSiteRule,
+    tcp::{serve_tcp, PortRange},
+    timeouts::UpstreamTimeouts,
     tls::TlsAcceptor,
+    udp::serve_udp,
+    upstream::{UpstreamConnector, UpstreamTls},
 };
 use crate::{
-    database::DroneDatabase, database_connection::DatabaseConnection, keys::KeyCertPathPair,
+    database::DroneDatabase,
+    database_connection::DatabaseConnection,
+    drone::{
+        backend_tls::BackendCa,
+        cert::store::{restore_cert, CertStoreKind},
+    },
+    keys::KeyCertPathPair,
+    logging::LogError,
+    messages::cert::KeyType,
+    nats_connection::NatsConnection,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use http::StatusCode;
 use hyper::{server::conn::AddrIncoming, Server};
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc, time::Duration};
 use tokio::select;
 
+pub mod access_log;
+mod acme_challenge;
+mod activity;
+mod affinity;
 mod certs;
+pub mod compression;
 mod connection_tracker;
+mod cors;
+pub mod error_pages;
+pub mod forwarded;
+mod health;
+mod http3;
+pub mod jwt;
+mod metrics;
+mod ocsp;
+mod path_routing;
+pub mod proxy_protocol;
+pub mod rate_limit;
+pub mod request_limits;
+mod route_cache;
+mod route_table;
 mod service;
+pub mod static_site;
+pub mod tcp;
+pub mod timeouts;
 mod tls;
+mod trace_context;
+mod udp;
+mod upstream;
 
 #[derive(PartialEq, Eq, Debug)]
 pub struct ProxyHttpsOptions {
     pub port: u16,
     pub key_paths: KeyCertPathPair,
+
+    /// Also serve HTTP/3 on the UDP port of the same number, advertised to clients with
+    /// `Alt-Svc`.
+    pub http3: bool,
+}
+
+/// A cluster domain which the proxy serves in addition to the drone's own.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct AdditionalCluster {
+    pub cluster_domain: String,
+
+    /// Certificate served over HTTPS to clients which ask for the domain by SNI. If not
+    /// provided, the drone's own certificate is served, e.g. because it is a wildcard
+    /// which covers both domains.
+    pub key_paths: Option<KeyCertPathPair>,
+}
+
+impl FromStr for AdditionalCluster {
+    type Err = anyhow::Error;
+
+    /// Parses `<domain>` or `<domain>=<private key path>,<certificate path>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (cluster_domain, key_paths) = match s.split_once('=') {
+            Some((cluster_domain, paths)) => {
+                let key_paths = parse_key_paths(paths).ok_or_else(|| {
+                    anyhow!(
+                        "Expected cluster to be <domain>=<private key path>,<certificate path>, got {:?}.",
+                        s
+                    )
+                })?;
+                (cluster_domain, Some(key_paths))
+            }
+            None => (s, None),
+        };
+        if cluster_domain.is_empty() {
+            return Err(anyhow!("Expected cluster to have a domain, got {:?}.", s));
+        }
+
+        Ok(AdditionalCluster {
+            cluster_domain: cluster_domain.to_string(),
+            key_paths,
+        })
+    }
+}
+
+/// Parses `<private key path>,<certificate path>`.
+fn parse_key_paths(paths: &str) -> Option<KeyCertPathPair> {
+    let (private_key_path, certificate_path) = paths.split_once(',')?;
+    Some(KeyCertPathPair {
+        private_key_path: PathBuf::from(private_key_path),
+        certificate_path: PathBuf::from(certificate_path),
+    })
+}
+
+/// A certificate provided by the operator for a hostname, e.g. one issued by a corporate
+/// CA, served to clients which ask for the hostname by SNI in preference to the cluster's.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct HostCert {
+    /// The hostname, or `*.<domain>` for any hostname one label under the domain.
+    pub hostname: String,
+    pub key_paths: KeyCertPathPair,
+}
+
+impl FromStr for HostCert {
+    type Err = anyhow::Error;
+
+    /// Parses `<hostname>=<private key path>,<certificate path>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (hostname, key_paths) = s
+            .split_once('=')
+            .and_then(|(hostname, paths)| Some((hostname, parse_key_paths(paths)?)))
+            .filter(|(hostname, _)| !hostname.is_empty())
+            .ok_or_else(|| {
+                anyhow!(
+                    "Expected host certificate to be <hostname>=<private key path>,<certificate path>, got {:?}.",
+                    s
+                )
+            })?;
+
+        Ok(HostCert {
+            hostname: hostname.to_ascii_lowercase(),
+            key_paths,
+        })
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -27,46 +169,342 @@ pub struct ProxyOptions {
     pub http_port: u16,
     pub https_options: Option<ProxyHttpsOptions>,
     pub cluster_domain: String,
+
+    /// Other cluster domains whose backends are routed to by the same proxy. Each backend
+    /// is only routed under the domain of its own cluster: the cluster its spawn request
+    /// names, or else the drone's.
+    pub additional_clusters: Vec<AdditionalCluster>,
+
+    /// Operator-provided certificates for particular hostnames, reloaded when their files
+    /// change.
+    pub host_certs: Vec<HostCert>,
+
+    /// Where to write a structured log entry for each request handled.
+    pub access_log: Option<AccessLogSink>,
+
+    /// If provided, requests are only forwarded if they carry a valid JWT for the backend.
+    pub jwt: Option<JwtOptions>,
+
+    /// Whether the `Forwarded` and `X-Forwarded-*` headers of requests are appended to,
+    /// replaced, or left alone, for every cluster domain or for specific ones.
+    pub forwarded_headers: Vec<ForwardedHeadersRule>,
+
+    /// Content types of backends' responses which are compressed, for every cluster domain
+    /// or for specific ones.
+    pub compression: Vec<CompressionRule>,
+
+    /// Static sites served at cluster domains themselves, and for the hosts of terminated
+    /// backends, for every cluster domain or for specific ones.
+    pub static_sites: Vec<StaticSiteRule>,
+
+    /// JSON file mapping cluster domains to the CORS configuration of their backends.
+    pub cors_config: Option<PathBuf>,
+
+    /// Apply routes published by agents over NATS, in addition to those in the database.
+    pub route_updates: bool,
+
+    /// Ports on which raw TCP connections are passed through to backends.
+    pub tcp_port_range: Option<PortRange>,
+
+    /// Ports on which UDP datagrams are relayed to and from backends.
+    pub udp_port_range: Option<PortRange>,
+
+    /// Limit on the rate of requests to each backend, across clients.
+    pub backend_rate_limit: Option<RateLimit>,
+
+    /// Limit on the rate of requests from each client IP to each backend.
+    pub client_rate_limit: Option<RateLimit>,
+
+    /// Pages served in place of a bare status when a request can't be forwarded.
+    pub error_pages: Vec<ErrorPageRule>,
+
+    /// How long requests for a backend which is still loading or starting are held for
+    /// it to become ready.
+    pub hold_starting: Duration,
+
+    /// Serve a page which reloads until the backend is ready to requests for a starting
+    /// backend, rather than 404 Not Found.
+    pub starting_page: bool,
+
+    /// Route requests to a cluster domain itself by the first segment of their path, e.g.
+    /// `https://<cluster>/<backend>/...`, stripping it before forwarding.
+    pub path_routing: bool,
+
+    /// Status of the response to a request for a long-lived connection (e.g. a WebSocket)
+    /// to a backend which already has as many open as its spawn request allows.
+    pub connection_limit_status: StatusCode,
+
+    /// How long routes looked up in the database are cached for. Zero disables the cache.
+    pub route_cache_ttl: Duration,
+
+    /// Limits on the body, header, and URI size of requests forwarded to backends.
+    pub request_limits: RequestLimits,
+
+    /// Timeouts of connections and requests to backends, which backends' spawn requests
+    /// can override.
+    pub upstream_timeouts: UpstreamTimeouts,
+
+    /// Name of the cookie pinning each client to one replica of backends which have
+    /// several. Without one, requests are spread across replicas in turn.
+    pub affinity_cookie: Option<String>,
+
+    /// Handle each request in a tracing span carrying its W3C trace context. Requests are
+    /// forwarded with trace context regardless.
+    pub trace_spans: bool,
+
+    /// Staple OCSP responses, fetched from the issuers of the certificates served over
+    /// HTTPS, in TLS handshakes.
+    pub ocsp_stapling: bool,
+
+    /// Types of key of the certificates kept beside each cluster's, at its certificate
+    /// paths with the key type appended, which are served to clients which can't use the
+    /// cluster's own certificate.
+    pub alternate_key_types: Vec<KeyType>,
+
+    /// Answer the HTTP-01 challenges of the clusters' certificate refreshers, on the HTTP
+    /// port even when the proxy serves HTTPS.
+    pub acme_http_challenges: bool,
+
+    /// Route hosts outside the cluster domains as backends' custom domains, answer their
+    /// HTTP-01 challenges if challenges are answered, and serve their certificates,
+    /// published over NATS.
+    pub custom_domains: bool,
+
+    /// Serve the certificates published over NATS for the clusters, by writing them to
+    /// the certificate paths, whose certificates are reloaded as they change.
+    pub receive_certs: bool,
+
+    /// Where certificates are stored, to restore to the certificate paths at startup if
+    /// they are newer than the certificates there.
+    pub cert_store: Option<CertStoreKind>,
+
+    /// Whether inbound connections begin with PROXY headers from a load balancer, and
+    /// whether TCP passthrough connections to backends are begun with one.
+    pub proxy_protocol: ProxyProtocol,
+
+    /// CA which backends' certificates are issued by, if the proxy connects to backends
+    /// over mutual TLS, presenting a client certificate issued by it.
+    pub backend_ca: Option<KeyCertPathPair>,
+
+    /// Address to serve Prometheus metrics on, if any.
+    pub metrics_address: Option<SocketAddr>,
+
+    /// Address to serve health and readiness endpoints on, if any.
+    pub health_address: Option<SocketAddr>,
+
+    /// Publish the time each backend was last active over NATS.
+    pub publish_activity: bool,
+
+    /// Required when access logs or activity are published to NATS, route updates are
+    /// applied, HTTP-01 challenges are answered, or certificates are received. If given along with a route cache, cached routes are dropped as their
+    /// backends change state.
+    pub nats: Option<NatsConnection>,
 }
 
-async fn record_connections(db: DroneDatabase, connection_tracker: ConnectionTracker) {
+impl ProxyOptions {
+    /// The drone's cluster domain, followed by the additional ones.
+    fn cluster_domains(&self) -> Vec<String> {
+        std::iter::once(self.cluster_domain.clone())
+            .chain(
+                self.additional_clusters
+                    .iter()
+                    .map(|cluster| cluster.cluster_domain.clone()),
+            )
+            .collect()
+    }
+
+    /// The certificate paths of each cluster domain served over HTTPS with its own
+    /// certificate.
+    fn cluster_key_paths(&self) -> Vec<(String, KeyCertPathPair)> {
+        let https_options = match &self.https_options {
+            Some(https_options) => https_options,
+            None => return Vec::new(),
+        };
+        std::iter::once((self.cluster_domain.clone(), https_options.key_paths.clone()))
+            .chain(self.additional_clusters.iter().filter_map(|cluster| {
+                let key_paths = cluster.key_paths.clone()?;
+                Some((cluster.cluster_domain.clone(), key_paths))
+            }))
+            .collect()
+    }
+}
+
+/// The subdomain of the host within the cluster domain, if it is under it.
+fn strip_cluster_domain<'a>(host: &'a str, cluster_domain: &str) -> Option<&'a str> {
+    host.strip_suffix(cluster_domain)?.strip_suffix('.')
+}
+
+/// The longest of the cluster domains the host is under, and the host's subdomain
+/// within it.
+fn split_host<'a, 'b>(host: &'a str, cluster_domains: &'b [String]) -> Option<(&'b str, &'a str)> {
+    cluster_domains
+        .iter()
+        .filter_map(|cluster_domain| {
+            strip_cluster_domain(host, cluster_domain)
+                .map(|subdomain| (cluster_domain.as_str(), subdomain))
+        })
+        .max_by_key(|(cluster_domain, _)| cluster_domain.len())
+}
+
+async fn record_connections(
+    db: DroneDatabase,
+    connection_tracker: ConnectionTracker,
+    mut activity: Option<ActivityPublisher>,
+) {
     loop {
         let backends = connection_tracker.get_and_clear_active_backends();
         if let Err(error) = db.reset_last_active_times(&backends).await {
             tracing::error!(?error, "Encountered database error.");
         }
+        if let Some(activity) = &mut activity {
+            activity.record(&db, &backends, Utc::now()).await;
+        }
+
+        // The agent waits for these to close before stopping a backend.
+        let connections = connection_tracker.open_connections();
+        if let Err(error) = db.set_open_connections(&connections).await {
+            tracing::error!(?error, "Encountered database error.");
+        }
 
         tokio::time::sleep(Duration::from_secs(1)).await;
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn run_server(
     db: DroneDatabase,
     options: ProxyOptions,
     connection_tracker: ConnectionTracker,
+    access_logger: Option<AccessLogger>,
+    route_table: Option<RouteTable>,
+    route_cache: Option<RouteCache>,
+    metrics: Option<ProxyMetrics>,
+    health: Option<ProxyHealth>,
+    acme_challenges: Option<AcmeChallenges>,
+    custom_domain_certs: Option<CustomDomainCerts>,
 ) -> Result<()> {
-    let make_proxy = MakeProxyService::new(db, options.cluster_domain, connection_tracker.clone());
+    let make_proxy = MakeProxyService::new(
+        db,
+        &options,
+        connection_tracker.clone(),
+        access_logger,
+        route_table,
+        route_cache,
+        metrics.clone(),
+        acme_challenges,
+    )?;
+
+    if let Some(https_options) = &options.https_options {
+        let mut cert_refresher = CertRefresher::new(https_options.key_paths.clone())?;
+
+        // The refreshers watch their certificates for changes, so they must live as long
+        // as the server.
+        let mut cluster_cert_refreshers = Vec::new();
+        for cluster in &options.additional_clusters {
+            if let Some(key_paths) = &cluster.key_paths {
+                cluster_cert_refreshers.push((
+                    cluster.cluster_domain.clone(),
+                    CertRefresher::new(key_paths.clone())?,
+                ));
+            }
+        }
+        let mut alternate_cert_refreshers = Vec::new();
+        for key_type in &options.alternate_key_types {
+            for (cluster_domain, key_paths) in options.cluster_key_paths() {
+                alternate_cert_refreshers.push((
+                    cluster_domain,
+                    CertRefresher::new(key_paths.alternate(key_type.as_str()))?,
+                ));
+            }
+        }
+        let mut host_cert_refreshers = Vec::new();
+        for host_cert in &options.host_certs {
+            host_cert_refreshers.push((
+                host_cert.hostname.clone(),
+                CertRefresher::new(host_cert.key_paths.clone())?,
+            ));
+        }
+        if options.ocsp_stapling {
+            cert_refresher.staple_ocsp();
+            for (_, refresher) in cluster_cert_refreshers
+                .iter_mut()
+                .chain(&mut alternate_cert_refreshers)
+                .chain(&mut host_cert_refreshers)
+            {
+                refresher.staple_ocsp();
+            }
+        }
+        if let Some(health) = &health {
+            health.add_cert(cert_refresher.resolver());
+            for (_, refresher) in cluster_cert_refreshers.iter().chain(&host_cert_refreshers) {
+                health.add_cert(refresher.resolver());
+            }
+        }
+        if let Some(metrics) = &metrics {
+            metrics.add_cert(options.cluster_domain.clone(), cert_refresher.resolver());
+            for (domain, refresher) in cluster_cert_refreshers.iter().chain(&host_cert_refreshers) {
+                metrics.add_cert(domain.clone(), refresher.resolver());
+            }
+        }
 
-    if let Some(https_options) = options.https_options {
-        let cert_refresher = CertRefresher::new(https_options.key_paths.clone())?;
+        let with_alternates = |domain: &str, refresher: &CertRefresher| {
+            refresher.resolver().with_alternates(
+                alternate_cert_refreshers
+                    .iter()
+                    .filter(|(alternate_domain, _)| alternate_domain == domain)
+                    .map(|(_, alternate)| alternate.resolver())
+                    .collect(),
+            )
+        };
+        let resolver = SniCertResolver::new(
+            with_alternates(&options.cluster_domain, &cert_refresher),
+            cluster_cert_refreshers
+                .iter()
+                .map(|(domain, refresher)| (domain.clone(), with_alternates(domain, refresher)))
+                .collect(),
+            host_cert_refreshers
+                .iter()
+                .map(|(hostname, refresher)| (hostname.clone(), refresher.resolver()))
+                .collect(),
+            custom_domain_certs,
+        );
 
         let tls_cfg = {
-            let cfg = rustls::ServerConfig::builder()
+            let mut cfg = rustls::ServerConfig::builder()
                 .with_safe_defaults()
                 .with_no_client_auth()
-                .with_cert_resolver(Arc::new(cert_refresher.resolver()));
+                .with_cert_resolver(Arc::new(resolver));
+
+            // Offer HTTP/2 so that gRPC clients can connect over TLS. The server accepts
+            // either version on any connection, including cleartext HTTP/2.
+            cfg.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
 
             Arc::new(cfg)
         };
 
         let addr = SocketAddr::from(([0, 0, 0, 0], https_options.port));
-        let incoming = AddrIncoming::bind(&addr)?;
-        let server = Server::builder(TlsAcceptor::new(tls_cfg, incoming)).serve(make_proxy);
-        server.await?;
+        if https_options.http3 {
+            tokio::spawn(serve_http3(addr, &tls_cfg, make_proxy.clone())?);
+        }
+        if options.proxy_protocol.accept {
+            let incoming = ProxyProtocolIncoming::bind(addr).await?;
+            let server = Server::builder(TlsAcceptor::new(tls_cfg, incoming)).serve(make_proxy);
+            server.await?;
+        } else {
+            let incoming = AddrIncoming::bind(&addr)?;
+            let server = Server::builder(TlsAcceptor::new(tls_cfg, incoming)).serve(make_proxy);
+            server.await?;
+        }
     } else {
         let addr = SocketAddr::from(([0, 0, 0, 0], options.http_port));
-        let server = Server::bind(&addr).serve(make_proxy);
-        server.await?;
+        if options.proxy_protocol.accept {
+            let incoming = ProxyProtocolIncoming::bind(addr).await?;
+            let server = Server::builder(incoming).serve(make_proxy);
+            server.await?;
+        } else {
+            let server = Server::bind(&addr).serve(make_proxy);
+            server.await?;
+        }
     }
 
     Ok(())
@@ -75,12 +513,217 @@ async fn run_server(
 pub async fn serve(options: ProxyOptions) -> Result<()> {
     let connection_tracker = ConnectionTracker::default();
     let db = options.db.connection().await?;
+    let nats = match &options.nats {
+        Some(nats) => Some(nats.connection().await?),
+        None => None,
+    };
+    let access_logger = match options.access_log.clone() {
+        Some(sink) => Some(AccessLogger::start(sink, nats.clone()).await?),
+        None => None,
+    };
+    let route_table = if options.route_updates {
+        let nats = nats
+            .clone()
+            .ok_or_else(|| anyhow!("Expected NATS for route updates."))?;
+        let route_table = RouteTable::default();
+        for cluster_domain in options.cluster_domains() {
+            let route_table = route_table.clone();
+            let nats = nats.clone();
+            tokio::spawn(async move {
+                route_table
+                    .listen(nats, cluster_domain)
+                    .await
+                    .log_error("Error listening for route updates.");
+            });
+        }
+        Some(route_table)
+    } else {
+        None
+    };
+    let route_cache = if options.route_cache_ttl.is_zero() {
+        None
+    } else {
+        let route_cache = RouteCache::new(options.route_cache_ttl);
+        match nats.clone() {
+            Some(nats) => {
+                let route_cache = route_cache.clone();
+                tokio::spawn(async move {
+                    route_cache
+                        .listen(nats)
+                        .await
+                        .log_error("Error listening for backend states.");
+                });
+            }
+            None => {
+                tokio::spawn(route_cache.clone().prune_loop());
+            }
+        }
+        Some(route_cache)
+    };
+    let metrics = match options.metrics_address {
+        Some(address) => {
+            let metrics = ProxyMetrics::default();
+            let server = serve_metrics(
+                address,
+                metrics.clone(),
+                db.clone(),
+                connection_tracker.clone(),
+                route_table.clone(),
+            );
+            tokio::spawn(async move {
+                server.await.log_error("Error serving proxy metrics.");
+            });
+            if let Some(nats) = &nats {
+                for cluster_domain in options.cluster_domains() {
+                    let listener = metrics
+                        .clone()
+                        .listen_renewals(nats.clone(), cluster_domain);
+                    tokio::spawn(async move {
+                        listener
+                            .await
+                            .log_error("Error listening for certificate renewals.");
+                    });
+                }
+            }
+            Some(metrics)
+        }
+        None => None,
+    };
+    let health = match options.health_address {
+        Some(address) => {
+            let health = ProxyHealth::new(
+                route_table
+                    .clone()
+                    .map(|route_table| (route_table, options.cluster_domains())),
+                nats.clone(),
+                options.https_options.is_some(),
+            );
+            let server = serve_health(address, health.clone());
+            tokio::spawn(async move {
+                server.await.log_error("Error serving proxy health.");
+            });
+            Some(health)
+        }
+        None => None,
+    };
+    let acme_challenges = if options.acme_http_challenges {
+        let nats = nats
+            .clone()
+            .ok_or_else(|| anyhow!("Expected NATS for answering HTTP-01 challenges."))?;
+        let acme_challenges =
+            AcmeChallenges::new(nats, options.cluster_domains(), options.custom_domains);
+        // ACME servers only validate HTTP-01 challenges over HTTP, on port 80.
+        if options.https_options.is_some() {
+            let server = serve_acme_challenges(
+                SocketAddr::from(([0, 0, 0, 0], options.http_port)),
+                acme_challenges.clone(),
+            );
+            tokio::spawn(async move {
+                server.await.log_error("Error serving HTTP-01 challenges.");
+            });
+        }
+        Some(acme_challenges)
+    } else {
+        None
+    };
+    if let Some(cert_store) = &options.cert_store {
+        let store = cert_store.store(nats.as_ref()).await?;
+        for (cluster_domain, key_paths) in options.cluster_key_paths() {
+            if let Err(error) = restore_cert(store.as_ref(), &cluster_domain, &key_paths).await {
+                tracing::warn!(?error, %cluster_domain, "Error restoring certificate from store.");
+            }
+        }
+    }
+    if options.receive_certs {
+        let nats = nats
+            .clone()
+            .ok_or_else(|| anyhow!("Expected NATS for receiving certificates."))?;
+        if options.https_options.is_none() {
+            return Err(anyhow!("Expected HTTPS for receiving certificates."));
+        }
+        for (cluster_domain, key_paths) in options.cluster_key_paths() {
+            let nats = nats.clone();
+            tokio::spawn(async move {
+                receive_certs(nats, cluster_domain, key_paths)
+                    .await
+                    .log_error("Error receiving certificates.");
+            });
+        }
+    }
+    // Custom domains are routed over HTTP too, but only need certificates over HTTPS.
+    let custom_domain_certs = if options.custom_domains && options.https_options.is_some() {
+        let nats = nats
+            .clone()
+            .ok_or_else(|| anyhow!("Expected NATS for custom domains."))?;
+        let custom_domain_certs = CustomDomainCerts::default();
+        for cluster_domain in options.cluster_domains() {
+            let receiver = custom_domain_certs
+                .clone()
+                .receive(nats.clone(), cluster_domain);
+            tokio::spawn(async move {
+                receiver
+                    .await
+                    .log_error("Error receiving custom domain certificates.");
+            });
+        }
+        Some(custom_domain_certs)
+    } else {
+        None
+    };
+    let activity = if options.publish_activity {
+        let nats = nats.ok_or_else(|| anyhow!("Expected NATS for publishing activity."))?;
+        Some(ActivityPublisher::new(
+            nats,
+            options.cluster_domains(),
+            route_table.clone(),
+        ))
+    } else {
+        None
+    };
+
+    let tcp_port_range = options.tcp_port_range;
+    let proxy_protocol = options.proxy_protocol;
+    let upstream_timeouts = options.upstream_timeouts;
+    let tcp_upstream = match (&tcp_port_range, &options.backend_ca) {
+        (Some(_), Some(paths)) => UpstreamTls::new(&BackendCa::load(paths)?)?.connector(&[])?,
+        _ => UpstreamConnector::plain(),
+    }
+    .with_connect_timeout(upstream_timeouts.connect);
+    let tcp_server = async {
+        match tcp_port_range {
+            Some(ports) => {
+                serve_tcp(
+                    ports,
+                    db.clone(),
+                    connection_tracker.clone(),
+                    tcp_upstream,
+                    proxy_protocol,
+                    upstream_timeouts,
+                )
+                .await
+            }
+            None => std::future::pending().await,
+        }
+    };
+    let udp_port_range = options.udp_port_range;
+    let udp_server = async {
+        match udp_port_range {
+            Some(ports) => serve_udp(ports, db.clone(), connection_tracker.clone()).await,
+            None => std::future::pending().await,
+        }
+    };
 
     select! {
-        result = run_server(db.clone(), options, connection_tracker.clone()) => {
+        result = run_server(db.clone(), options, connection_tracker.clone(), access_logger, route_table, route_cache, metrics, health, acme_challenges, custom_domain_certs) => {
             tracing::info!(?result, "run_server returned early.")
         }
-        () = record_connections(db, connection_tracker) => {
+        result = tcp_server => {
+            tracing::info!(?result, "serve_tcp returned early.")
+        }
+        result = udp_server => {
+            tracing::info!(?result, "serve_udp returned early.")
+        }
+        () = record_connections(db.clone(), connection_tracker.clone(), activity) => {
             tracing::info!("record_connections returned early.")
         }
     };
@@ -0,0 +1,225 @@
+//! OCSP stapling: the proxy fetches the issuer's OCSP response for each certificate it
+//! serves and sends it in TLS handshakes, so that clients needn't ask the issuer whether
+//! the certificate has been revoked before trusting it.
+//!
+//! Like most servers, the proxy leaves verifying the responder's signature to clients.
+//! It only checks that a response covers the certificate, says it is good, and is
+//! current, so as never to staple one which would fail the handshake.
+
+use anyhow::{anyhow, Context, Result};
+use openssl::{
+    hash::MessageDigest,
+    ocsp::{OcspCertId, OcspCertStatus, OcspRequest, OcspResponse, OcspResponseStatus},
+    x509::X509,
+};
+use reqwest::{header::CONTENT_TYPE, Client};
+use rustls::{sign::CertifiedKey, Certificate};
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::watch::Sender, time::Instant};
+
+/// How often responses are refreshed. CAs issue responses valid for days, so this leaves
+/// plenty of time to retry.
+const OCSP_REFRESH: Duration = Duration::from_secs(6 * 3600);
+
+/// How long after failing to fetch a response to try again.
+const OCSP_RETRY: Duration = Duration::from_secs(5 * 60);
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Leeway for clock skew when checking that a response is current.
+const VALIDITY_LEEWAY_SECS: u32 = 300;
+
+/// The leaf certificate and its issuer, which together identify the leaf to the responder.
+fn leaf_and_issuer(chain: &[Certificate]) -> Result<(X509, X509)> {
+    match chain {
+        [leaf, issuer, ..] => Ok((X509::from_der(&leaf.0)?, X509::from_der(&issuer.0)?)),
+        _ => Err(anyhow!(
+            "Expected the certificate chain to include the issuer."
+        )),
+    }
+}
+
+/// Check that the response says the certificate is good, and is current.
+fn check_response(response: &[u8], leaf: &X509, issuer: &X509) -> Result<()> {
+    let response = OcspResponse::from_der(response)?;
+    if response.status() != OcspResponseStatus::SUCCESSFUL {
+        return Err(anyhow!(
+            "OCSP responder returned status {}.",
+            response.status().as_raw()
+        ));
+    }
+    let basic = response.basic()?;
+    let id = OcspCertId::from_cert(MessageDigest::sha1(), leaf, issuer)?;
+    let status = basic
+        .find_status(&id)
+        .ok_or_else(|| anyhow!("OCSP response does not cover the certificate."))?;
+    if status.status != OcspCertStatus::GOOD {
+        return Err(anyhow!(
+            "OCSP status of the certificate is {}.",
+            status.status.as_raw()
+        ));
+    }
+    status
+        .check_validity(VALIDITY_LEEWAY_SECS, None)
+        .context("OCSP response is not current.")?;
+
+    Ok(())
+}
+
+/// Fetch a current response for the certificate from its issuer's responder.
+async fn fetch_response(client: &Client, chain: &[Certificate]) -> Result<Vec<u8>> {
+    let (leaf, issuer) = leaf_and_issuer(chain)?;
+    let responder = leaf
+        .ocsp_responders()?
+        .iter()
+        .next()
+        .map(|url| url.to_string())
+        .ok_or_else(|| anyhow!("Certificate names no OCSP responder."))?;
+
+    let mut request = OcspRequest::new()?;
+    request.add_id(OcspCertId::from_cert(
+        MessageDigest::sha1(),
+        &leaf,
+        &issuer,
+    )?)?;
+    let response = client
+        .post(&responder)
+        .header(CONTENT_TYPE, "application/ocsp-request")
+        .body(request.to_der()?)
+        .timeout(REQUEST_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()
+        .context("Error fetching OCSP response.")?
+        .bytes()
+        .await?
+        .to_vec();
+    check_response(&response, &leaf, &issuer)?;
+
+    Ok(response)
+}
+
+/// Replace the certificate with a copy with the response stapled, unless it has since
+/// been replaced, e.g. by a renewed certificate. Returns the copy if it was sent.
+fn staple(
+    sender: &Sender<Option<Arc<CertifiedKey>>>,
+    certified_key: &Arc<CertifiedKey>,
+    ocsp: Option<Vec<u8>>,
+) -> Option<Arc<CertifiedKey>> {
+    let stapled = Arc::new(CertifiedKey {
+        ocsp,
+        ..CertifiedKey::clone(certified_key)
+    });
+    let sent = sender.send_if_modified(|current| match current {
+        Some(current) if Arc::ptr_eq(current, certified_key) => {
+            *current = stapled.clone();
+            true
+        }
+        _ => false,
+    });
+
+    sent.then_some(stapled)
+}
+
+/// Staple responses to the certificates sent on the channel, fetching a new one whenever
+/// the certificate changes and refreshing it in the background.
+pub async fn staple_ocsp(sender: Arc<Sender<Option<Arc<CertifiedKey>>>>) {
+    let client = Client::new();
+    let mut receiver = sender.subscribe();
+    // The certificate last sent here, whose own changes are not new certificates.
+    let mut stapled: Option<Arc<CertifiedKey>> = None;
+    let mut next_fetch = Instant::now();
+
+    loop {
+        tokio::select! {
+            result = receiver.changed() => {
+                if result.is_err() {
+                    return;
+                }
+                let is_own = match (&*receiver.borrow(), &stapled) {
+                    (Some(current), Some(stapled)) => Arc::ptr_eq(current, stapled),
+                    _ => false,
+                };
+                if is_own {
+                    continue;
+                }
+            }
+            _ = tokio::time::sleep_until(next_fetch) => (),
+        }
+
+        let certified_key = match receiver.borrow_and_update().clone() {
+            Some(certified_key) => certified_key,
+            None => {
+                next_fetch = Instant::now() + OCSP_REFRESH;
+                continue;
+            }
+        };
+        match fetch_response(&client, &certified_key.cert).await {
+            Ok(response) => {
+                tracing::info!("Stapled OCSP response.");
+                stapled = staple(&sender, &certified_key, Some(response));
+                next_fetch = Instant::now() + OCSP_REFRESH;
+            }
+            Err(error) => {
+                tracing::warn!(?error, "Error fetching OCSP response, will try again.");
+                // The previous response is kept while it is current.
+                let expired = certified_key.ocsp.as_ref().is_some_and(|response| {
+                    leaf_and_issuer(&certified_key.cert)
+                        .and_then(|(leaf, issuer)| check_response(response, &leaf, &issuer))
+                        .is_err()
+                });
+                if expired {
+                    stapled = staple(&sender, &certified_key, None);
+                }
+                next_fetch = Instant::now() + OCSP_RETRY;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{drone::backend_tls::test::test_ca, types::BackendId};
+    use rustls::{sign::any_supported_type, PrivateKey};
+    use tokio::sync::watch::channel;
+
+    fn certified_key(backend: &str) -> Arc<CertifiedKey> {
+        let issued = test_ca()
+            .issue_backend(&BackendId::new(backend.to_string()))
+            .unwrap();
+        let key = any_supported_type(&PrivateKey(issued.private_key_pkcs8_der().unwrap())).unwrap();
+        Arc::new(CertifiedKey::new(
+            vec![Certificate(issued.certificate.to_der().unwrap())],
+            key,
+        ))
+    }
+
+    #[test]
+    fn test_staple() {
+        let old = certified_key("old");
+        let (sender, receiver) = channel(Some(old.clone()));
+
+        let stapled = staple(&sender, &old, Some(b"response".to_vec())).unwrap();
+        assert_eq!(Some(b"response".to_vec()), stapled.ocsp);
+        assert!(Arc::ptr_eq(&stapled, receiver.borrow().as_ref().unwrap()));
+
+        // A certificate which has since been replaced is not stapled.
+        let new = certified_key("new");
+        sender.send_replace(Some(new.clone()));
+        assert!(staple(&sender, &stapled, Some(b"response".to_vec())).is_none());
+        assert!(Arc::ptr_eq(&new, receiver.borrow().as_ref().unwrap()));
+    }
+
+    #[test]
+    fn test_check_response_unsuccessful() {
+        let issued = test_ca()
+            .issue_backend(&BackendId::new("backend".to_string()))
+            .unwrap();
+        let response = OcspResponse::create(OcspResponseStatus::TRY_LATER, None)
+            .unwrap()
+            .to_der()
+            .unwrap();
+        assert!(check_response(&response, &issued.certificate, &issued.certificate).is_err());
+    }
+}
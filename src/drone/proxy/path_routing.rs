@@ -0,0 +1,90 @@
+//! Routing by path prefix, for deployments which can't allocate wildcard subdomains.
+//! Backends are reachable at `https://<cluster>/<subdomain>/...`, and the prefix is
+//! stripped before requests are forwarded, so that backends see the same paths as when
+//! routed by subdomain.
+
+use anyhow::Result;
+use http::{uri::PathAndQuery, Uri};
+
+/// Header telling backends the prefix they are served under, so that they can generate
+/// links which route back to them.
+pub const X_FORWARDED_PREFIX: &str = "x-forwarded-prefix";
+
+/// A request routed by the first segment of its path.
+#[derive(PartialEq, Eq, Debug)]
+pub struct PathRoute<'a> {
+    /// The first segment of the path, which is routed like a subdomain.
+    pub subdomain: &'a str,
+
+    /// The path after the first segment, e.g. `/` or `/api/items`, with the query.
+    pub rest: String,
+}
+
+/// Split the subdomain from the path of a request. Returns None if the path has no first
+/// segment.
+pub fn split_path_prefix(path_and_query: &str) -> Option<PathRoute<'_>> {
+    let path_and_query = path_and_query.strip_prefix('/')?;
+    let end = path_and_query
+        .find(['/', '?'])
+        .unwrap_or(path_and_query.len());
+    let (subdomain, rest) = path_and_query.split_at(end);
+    if subdomain.is_empty() {
+        return None;
+    }
+
+    let rest = if rest.starts_with('/') {
+        rest.to_string()
+    } else {
+        format!("/{}", rest)
+    };
+    Some(PathRoute { subdomain, rest })
+}
+
+/// Whether the path is exactly the prefix, without a trailing slash. Such requests are
+/// redirected to the prefix with a slash, so that relative links in the backend's pages
+/// resolve under the prefix.
+pub fn needs_trailing_slash(path: &str, subdomain: &str) -> bool {
+    path.strip_prefix('/') == Some(subdomain)
+}
+
+/// The URI with its path replaced by the path after the prefix.
+pub fn strip_path_prefix(uri: &Uri, rest: &str) -> Result<Uri> {
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(PathAndQuery::try_from(rest)?);
+    Ok(Uri::from_parts(parts)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_path_prefix() {
+        assert_eq!(
+            Some(PathRoute {
+                subdomain: "mybackend",
+                rest: "/api/items?page=2".to_string(),
+            }),
+            split_path_prefix("/mybackend/api/items?page=2")
+        );
+        assert_eq!(
+            Some(PathRoute {
+                subdomain: "mybackend",
+                rest: "/?page=2".to_string(),
+            }),
+            split_path_prefix("/mybackend?page=2")
+        );
+        assert_eq!(
+            Some(PathRoute {
+                subdomain: "mybackend",
+                rest: "/".to_string(),
+            }),
+            split_path_prefix("/mybackend/")
+        );
+        assert_eq!(None, split_path_prefix("/"));
+        assert_eq!(None, split_path_prefix("/?page=2"));
+
+        assert!(needs_trailing_slash("/mybackend", "mybackend"));
+        assert!(!needs_trailing_slash("/mybackend/", "mybackend"));
+    }
+}
@@ -0,0 +1,315 @@
+//! The PROXY protocol (versions 1 and 2), by which a load balancer in front of the proxy
+//! passes on the address of each connection's client, so that it is not lost at the hop.
+//!
+//! When enabled, every inbound connection must begin with a PROXY header, since a client
+//! connecting directly could otherwise claim any address. The proxy can also send a
+//! version 2 header to backends on TCP passthrough connections; HTTP requests carry the
+//! client's address in their `Forwarded` headers instead, since their connections to
+//! backends are shared between clients.
+
+use super::service::RemoteAddr;
+use anyhow::{anyhow, Result};
+use hyper::server::accept::Accept;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+/// Every version 2 header begins with this.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Every version 1 header begins with this.
+const V1_PREFIX: &[u8] = b"PROXY ";
+
+/// Longest a version 1 header may be, including its CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// How long a connection has to send its header before it is closed.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connections whose header has been read, waiting to be accepted by the server.
+const ACCEPT_BACKLOG: usize = 128;
+
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _destination, source_port, _destination_port] => {
+            Ok(Some(SocketAddr::new(source.parse()?, source_port.parse()?)))
+        }
+        _ => Err(anyhow!("Malformed PROXY protocol header {:?}.", line)),
+    }
+}
+
+fn parse_v2_addresses(family: u8, addresses: &[u8]) -> Result<Option<SocketAddr>> {
+    // The high nibble is the address family, the low nibble the transport protocol.
+    match family >> 4 {
+        0x1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        0x2 if addresses.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port,
+            )))
+        }
+        0x1 | 0x2 => Err(anyhow!(
+            "PROXY protocol header is too short for its addresses."
+        )),
+        // Unix sockets, or unspecified.
+        _ => Ok(None),
+    }
+}
+
+/// Read the PROXY header from the start of a connection, leaving the stream at the first
+/// byte after it. Returns the client's address, or None if the header doesn't carry one,
+/// e.g. for the load balancer's own health checks.
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<SocketAddr>> {
+    // Both versions' headers are at least this long, so it is never read past.
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        let mut header = [0; 4];
+        stream.read_exact(&mut header).await?;
+        let [version_command, family, length @ ..] = header;
+        if version_command >> 4 != 2 {
+            return Err(anyhow!(
+                "Unsupported PROXY protocol version {}.",
+                version_command >> 4
+            ));
+        }
+        let mut addresses = vec![0; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut addresses).await?;
+
+        return match version_command & 0xf {
+            // LOCAL: the connection was opened by the load balancer itself.
+            0x0 => Ok(None),
+            0x1 => parse_v2_addresses(family, &addresses),
+            command => Err(anyhow!("Unsupported PROXY protocol command {}.", command)),
+        };
+    }
+
+    if start.starts_with(V1_PREFIX) {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err(anyhow!("PROXY protocol header is too long."));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        line.truncate(line.len() - 2);
+        return parse_v1(std::str::from_utf8(&line)?);
+    }
+
+    Err(anyhow!(
+        "Connection did not begin with a PROXY protocol header."
+    ))
+}
+
+/// A version 2 header describing a TCP connection from `source` to `destination`.
+pub fn encode_v2(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    // Version 2, PROXY command.
+    header.push(0x21);
+
+    let (family, mut addresses) = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            (0x11, [source.octets(), destination.octets()].concat())
+        }
+        (source, destination) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            (
+                0x21,
+                [to_v6(source).octets(), to_v6(destination).octets()].concat(),
+            )
+        }
+    };
+    addresses.extend(source.port().to_be_bytes());
+    addresses.extend(destination.port().to_be_bytes());
+
+    header.push(family);
+    header.extend((addresses.len() as u16).to_be_bytes());
+    header.extend(addresses);
+    header
+}
+
+/// Where the proxy expects and sends PROXY headers.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct ProxyProtocol {
+    /// Every inbound connection begins with a PROXY header from a load balancer.
+    pub accept: bool,
+
+    /// Begin every TCP passthrough connection to a backend with a PROXY header.
+    pub send: bool,
+}
+
+/// A connection whose client address was read from its PROXY header.
+pub struct ProxiedStream {
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+}
+
+impl RemoteAddr for ProxiedStream {
+    fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+}
+
+impl AsyncRead for ProxiedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxiedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Read the connection's PROXY header for the client's address, falling back to the
+/// peer's address if the header doesn't carry one.
+pub async fn read_client_addr(stream: &mut TcpStream, peer: SocketAddr) -> Result<SocketAddr> {
+    let source = tokio::time::timeout(HEADER_TIMEOUT, read_header(stream))
+        .await
+        .map_err(|_| anyhow!("Timed out waiting for PROXY protocol header."))??;
+
+    Ok(source.unwrap_or(peer))
+}
+
+async fn accept_proxied(mut stream: TcpStream, peer: SocketAddr) -> Result<ProxiedStream> {
+    let remote_addr = read_client_addr(&mut stream, peer).await?;
+    Ok(ProxiedStream {
+        stream,
+        remote_addr,
+    })
+}
+
+/// Accepts connections which begin with a PROXY header. Headers are read concurrently,
+/// so that a slow connection doesn't hold up others.
+pub struct ProxyProtocolIncoming {
+    accepted: mpsc::Receiver<ProxiedStream>,
+}
+
+impl ProxyProtocolIncoming {
+    pub async fn bind(address: SocketAddr) -> Result<Self> {
+        let listener = TcpListener::bind(address).await?;
+        let (send, accepted) = mpsc::channel(ACCEPT_BACKLOG);
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(error) => {
+                        tracing::warn!(?error, "Error accepting connection.");
+                        continue;
+                    }
+                };
+
+                let send = send.clone();
+                tokio::spawn(async move {
+                    match accept_proxied(stream, peer).await {
+                        Ok(stream) => {
+                            let _ = send.send(stream).await;
+                        }
+                        Err(error) => {
+                            tracing::info!(?error, %peer, "Closing connection without a valid PROXY protocol header.")
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(ProxyProtocolIncoming { accepted })
+    }
+}
+
+impl Accept for ProxyProtocolIncoming {
+    type Conn = ProxiedStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.get_mut()
+            .accepted
+            .poll_recv(cx)
+            .map(|stream| stream.map(Ok))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_read_v1_header() {
+        let mut stream: &[u8] = b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(
+            Some("203.0.113.7:51234".parse().unwrap()),
+            block_on(read_header(&mut stream)).unwrap()
+        );
+        assert_eq!(b"GET / HTTP/1.1\r\n", stream);
+
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(None, block_on(read_header(&mut stream)).unwrap());
+
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\nHost: example.com\r\n";
+        assert!(block_on(read_header(&mut stream)).is_err());
+    }
+
+    #[test]
+    fn test_v2_header_round_trip() {
+        for (source, destination) in [
+            ("203.0.113.7:51234", "192.0.2.1:443"),
+            ("[2001:db8::7]:51234", "[2001:db8::1]:443"),
+        ] {
+            let source: SocketAddr = source.parse().unwrap();
+            let mut header = encode_v2(source, destination.parse().unwrap());
+            header.extend(b"payload");
+
+            let mut stream = header.as_slice();
+            assert_eq!(Some(source), block_on(read_header(&mut stream)).unwrap());
+            assert_eq!(b"payload", stream);
+        }
+
+        // A LOCAL header, as sent by load balancers' health checks.
+        let mut stream: &[u8] = b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00";
+        assert_eq!(None, block_on(read_header(&mut stream)).unwrap());
+    }
+}
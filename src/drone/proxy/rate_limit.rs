@@ -0,0 +1,215 @@
+//! Rate limits on requests to backends, so that one abusive client can't saturate a
+//! backend which serves a session to others.
+//!
+//! Each limit is a token bucket, which holds up to `burst` requests and refills at
+//! `requests_per_second`. Requests beyond the limit are answered with 429 Too Many
+//! Requests and never reach the backend.
+
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use std::{hash::Hash, net::IpAddr, str::FromStr, sync::Arc, time::Duration};
+use tokio::time::Instant;
+
+/// How often buckets which have refilled completely are forgotten.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub requests_per_second: f64,
+
+    /// Requests which may be made at once, after a period without requests.
+    pub burst: u32,
+}
+
+impl FromStr for RateLimit {
+    type Err = anyhow::Error;
+
+    /// Parses `<requests per second>` or `<requests per second>,<burst>`. The burst
+    /// defaults to one second of requests.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rate, burst) = match s.split_once(',') {
+            Some((rate, burst)) => (rate, Some(burst)),
+            None => (s, None),
+        };
+        let requests_per_second: f64 = rate.parse()?;
+        if !(requests_per_second > 0.0 && requests_per_second.is_finite()) {
+            return Err(anyhow!(
+                "Expected rate limit to allow a positive number of requests per second, got {:?}.",
+                s
+            ));
+        }
+        let burst = match burst {
+            Some(burst) => burst.parse()?,
+            None => requests_per_second.ceil() as u32,
+        };
+        if burst == 0 {
+            return Err(anyhow!(
+                "Expected rate limit to allow a burst of at least one request, got {:?}.",
+                s
+            ));
+        }
+
+        Ok(RateLimit {
+            requests_per_second,
+            burst,
+        })
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.requests_per_second).min(limit.burst as f64);
+        self.updated = now;
+    }
+}
+
+/// A token bucket for each key.
+struct RateLimiter<K: Hash + Eq> {
+    limit: RateLimit,
+    buckets: DashMap<K, Bucket>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            limit,
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Take a request from the key's bucket, if it has one left.
+    fn check(&self, key: K, now: Instant) -> bool {
+        let mut bucket = self.buckets.entry(key).or_insert_with(|| Bucket {
+            tokens: self.limit.burst as f64,
+            updated: now,
+        });
+        bucket.refill(&self.limit, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forget buckets which have refilled completely, since they are the same as new ones.
+    fn prune(&self, now: Instant) {
+        let limit = self.limit;
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(&limit, now);
+            bucket.tokens < limit.burst as f64
+        });
+    }
+}
+
+/// The proxy's rate limits on requests to each backend, and from each client to each
+/// backend.
+pub struct RateLimits {
+    per_backend: Option<RateLimiter<String>>,
+    per_client: Option<RateLimiter<(String, IpAddr)>>,
+}
+
+impl RateLimits {
+    pub fn new(per_backend: Option<RateLimit>, per_client: Option<RateLimit>) -> Self {
+        RateLimits {
+            per_backend: per_backend.map(RateLimiter::new),
+            per_client: per_client.map(RateLimiter::new),
+        }
+    }
+
+    fn check_at(&self, backend: &str, client_ip: IpAddr, now: Instant) -> bool {
+        // The client's limit is checked first, so that requests it rejects don't use up
+        // the backend's allowance for other clients.
+        if let Some(per_client) = &self.per_client {
+            if !per_client.check((backend.to_string(), client_ip), now) {
+                return false;
+            }
+        }
+        if let Some(per_backend) = &self.per_backend {
+            if !per_backend.check(backend.to_string(), now) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Whether a request from the client to the backend is within the limits.
+    pub fn check(&self, backend: &str, client_ip: IpAddr) -> bool {
+        self.check_at(backend, client_ip, Instant::now())
+    }
+
+    /// Periodically forget buckets which have refilled, so that every client ever seen
+    /// is not remembered.
+    pub async fn prune_loop(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let now = Instant::now();
+            if let Some(per_backend) = &self.per_backend {
+                per_backend.prune(now);
+            }
+            if let Some(per_client) = &self.per_client {
+                per_client.prune(now);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_limits() {
+        let limits = RateLimits::new(Some("10".parse().unwrap()), Some("1,2".parse().unwrap()));
+        let start = Instant::now();
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+        let other_client: IpAddr = "203.0.113.8".parse().unwrap();
+
+        // The client may make a burst of two requests, then one a second.
+        assert!(limits.check_at("backend", client, start));
+        assert!(limits.check_at("backend", client, start));
+        assert!(!limits.check_at("backend", client, start));
+        assert!(limits.check_at("other-backend", client, start));
+        assert!(limits.check_at("backend", client, start + Duration::from_secs(1)));
+        assert!(!limits.check_at("backend", client, start + Duration::from_secs(1)));
+
+        // The backend's bucket of ten has refilled, and the client has taken one of them.
+        let later = start + Duration::from_secs(1);
+        let allowed = (0..20)
+            .filter(|i| {
+                let ip = IpAddr::from([198, 51, 100, *i as u8]);
+                limits.check_at("backend", ip, later)
+            })
+            .count();
+        assert_eq!(9, allowed);
+        assert!(!limits.check_at("backend", other_client, later));
+    }
+
+    #[test]
+    fn test_parse_rate_limit() {
+        assert_eq!(
+            RateLimit {
+                requests_per_second: 0.5,
+                burst: 1
+            },
+            "0.5".parse().unwrap()
+        );
+        assert_eq!(
+            RateLimit {
+                requests_per_second: 100.0,
+                burst: 20
+            },
+            "100,20".parse().unwrap()
+        );
+        assert!("0".parse::<RateLimit>().is_err());
+        assert!("10,0".parse::<RateLimit>().is_err());
+    }
+}
@@ -0,0 +1,197 @@
+//! Limits on the size of requests the proxy forwards, so that backends are protected from
+//! oversized requests and the proxy's memory use stays bounded.
+//!
+//! The URI and headers are checked before a request is routed. A body with a
+//! `Content-Length` over the limit is refused up front; other bodies are counted as they
+//! are forwarded, and the request is aborted once they pass the limit.
+
+use hyper::{body::HttpBody, header, Body, Request, StatusCode};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Bytes each header counts for beyond its name and value, as in HTTP/1.1: `: ` and CRLF.
+const HEADER_OVERHEAD: usize = 4;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct RequestLimits {
+    /// Bytes of request body forwarded to a backend, exceeding which gets 413 Payload Too
+    /// Large.
+    pub max_body_bytes: Option<u64>,
+
+    /// Bytes of request headers, exceeding which gets 431 Request Header Fields Too Large.
+    pub max_header_bytes: Option<usize>,
+
+    /// Length of the request's path and query, exceeding which gets 414 URI Too Long.
+    pub max_uri_length: Option<usize>,
+}
+
+/// Which limit a request exceeded.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum LimitExceeded {
+    Body,
+    Headers,
+    Uri,
+}
+
+impl LimitExceeded {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            LimitExceeded::Body => StatusCode::PAYLOAD_TOO_LARGE,
+            LimitExceeded::Headers => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            LimitExceeded::Uri => StatusCode::URI_TOO_LONG,
+        }
+    }
+}
+
+/// Whether a request body being forwarded has passed the limit.
+#[derive(Clone, Default)]
+pub struct BodyLimit {
+    exceeded: Arc<AtomicBool>,
+}
+
+impl BodyLimit {
+    pub fn exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::Relaxed)
+    }
+}
+
+impl RequestLimits {
+    /// The limit the request's URI, headers, or declared body length exceed, if any.
+    pub fn check_head<B>(&self, req: &Request<B>) -> Option<LimitExceeded> {
+        if let Some(max_uri_length) = self.max_uri_length {
+            let uri_length = req
+                .uri()
+                .path_and_query()
+                .map_or(0, |path_and_query| path_and_query.as_str().len());
+            if uri_length > max_uri_length {
+                return Some(LimitExceeded::Uri);
+            }
+        }
+
+        if let Some(max_header_bytes) = self.max_header_bytes {
+            let header_bytes: usize = req
+                .headers()
+                .iter()
+                .map(|(name, value)| name.as_str().len() + value.len() + HEADER_OVERHEAD)
+                .sum();
+            if header_bytes > max_header_bytes {
+                return Some(LimitExceeded::Headers);
+            }
+        }
+
+        if let Some(max_body_bytes) = self.max_body_bytes {
+            let content_length = req
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|length| length.to_str().ok())
+                .and_then(|length| length.parse::<u64>().ok());
+            if matches!(content_length, Some(length) if length > max_body_bytes) {
+                return Some(LimitExceeded::Body);
+            }
+        }
+
+        None
+    }
+
+    /// The body, cut off with an error once it passes the body limit, if there is one.
+    pub fn limit_body(&self, mut body: Body) -> (Body, BodyLimit) {
+        let limit = BodyLimit::default();
+        let max_body_bytes = match self.max_body_bytes {
+            Some(max_body_bytes) => max_body_bytes,
+            None => return (body, limit),
+        };
+        let (mut sender, limited_body) = Body::channel();
+        let exceeded = limit.exceeded.clone();
+
+        tokio::spawn(async move {
+            let mut bytes = 0;
+            loop {
+                match body.data().await {
+                    Some(Ok(chunk)) => {
+                        bytes += chunk.len() as u64;
+                        if bytes > max_body_bytes {
+                            exceeded.store(true, Ordering::Relaxed);
+                            sender.abort();
+                            break;
+                        }
+                        if sender.send_data(chunk).await.is_err() {
+                            // The backend has stopped reading the body.
+                            break;
+                        }
+                    }
+                    Some(Err(error)) => {
+                        tracing::warn!(?error, "Error reading request body.");
+                        sender.abort();
+                        break;
+                    }
+                    None => {
+                        if let Ok(Some(trailers)) = body.trailers().await {
+                            let _ = sender.send_trailers(trailers).await;
+                        }
+                        break;
+                    }
+                }
+            }
+        });
+
+        (limited_body, limit)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limits() -> RequestLimits {
+        RequestLimits {
+            max_body_bytes: Some(10),
+            max_header_bytes: Some(64),
+            max_uri_length: Some(16),
+        }
+    }
+
+    #[test]
+    fn test_check_head() {
+        let request = Request::get("/short")
+            .header("host", "mybackend.spawner.test")
+            .header("content-length", "10")
+            .body(())
+            .unwrap();
+        assert_eq!(None, limits().check_head(&request));
+        assert_eq!(None, RequestLimits::default().check_head(&request));
+
+        let request = Request::get("/a/much/longer/path").body(()).unwrap();
+        assert_eq!(Some(LimitExceeded::Uri), limits().check_head(&request));
+
+        let request = Request::get("/")
+            .header("cookie", "x".repeat(64))
+            .body(())
+            .unwrap();
+        assert_eq!(Some(LimitExceeded::Headers), limits().check_head(&request));
+
+        let request = Request::post("/")
+            .header("content-length", "11")
+            .body(())
+            .unwrap();
+        assert_eq!(Some(LimitExceeded::Body), limits().check_head(&request));
+    }
+
+    #[tokio::test]
+    async fn test_limit_body() {
+        let (body, limit) = limits().limit_body(Body::from("0123456789"));
+        assert_eq!("0123456789", hyper::body::to_bytes(body).await.unwrap());
+        assert!(!limit.exceeded());
+
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            // The second chunk may be refused once the limit is passed.
+            sender.send_data("012345".into()).await.unwrap();
+            let _ = sender.send_data("6789ab".into()).await;
+        });
+        let (body, limit) = limits().limit_body(body);
+        assert!(hyper::body::to_bytes(body).await.is_err());
+        assert!(limit.exceeded());
+    }
+}
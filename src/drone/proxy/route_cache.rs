@@ -0,0 +1,150 @@
+//! In-process cache of the routes the proxy looks up in the database, so that busy
+//! backends don't cost a query per request.
+//!
+//! Routes are cached by subdomain, i.e. by host within its cluster, for a fixed time.
+//! When the proxy is connected to NATS, the routes to a backend are also dropped as soon
+//! as it changes state (e.g. when it starts draining or terminates), so that requests are
+//! not sent to a stopped backend for the rest of the time.
+
+use crate::{
+    database::ProxyRoute, messages::agent::BackendStateMessage, nats::TypedNats, types::BackendId,
+};
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use std::{sync::Arc, time::Duration};
+use tokio::time::Instant;
+
+struct CachedRoute {
+    route: ProxyRoute,
+    expires: Instant,
+}
+
+#[derive(Clone)]
+pub struct RouteCache {
+    ttl: Duration,
+    routes: Arc<DashMap<String, CachedRoute>>,
+}
+
+impl RouteCache {
+    pub fn new(ttl: Duration) -> Self {
+        RouteCache {
+            ttl,
+            routes: Arc::default(),
+        }
+    }
+
+    fn get_at(&self, subdomain: &str, now: Instant) -> Option<ProxyRoute> {
+        let cached = self.routes.get(subdomain)?;
+        if cached.expires > now {
+            return Some(cached.route.clone());
+        }
+        drop(cached);
+        self.routes
+            .remove_if(subdomain, |_, cached| cached.expires <= now);
+
+        None
+    }
+
+    /// The cached route of the subdomain, if it has not expired.
+    pub fn get(&self, subdomain: &str) -> Option<ProxyRoute> {
+        self.get_at(subdomain, Instant::now())
+    }
+
+    pub fn insert(&self, subdomain: &str, route: ProxyRoute) {
+        self.routes.insert(
+            subdomain.to_string(),
+            CachedRoute {
+                route,
+                expires: Instant::now() + self.ttl,
+            },
+        );
+    }
+
+    /// Drop the cached routes to the backend, including those of its additional ports.
+    pub fn invalidate_backend(&self, backend_id: &BackendId) {
+        self.routes
+            .retain(|_, cached| cached.route.backend_id.as_ref() != Some(backend_id));
+    }
+
+    /// Drop cached routes which have expired, so that hosts which are no longer
+    /// requested are not remembered.
+    fn prune(&self, now: Instant) {
+        self.routes.retain(|_, cached| cached.expires > now);
+    }
+
+    /// Drop the routes to backends as their state changes, and periodically drop expired
+    /// routes.
+    pub async fn listen(self, nats: TypedNats) -> Result<()> {
+        let mut sub = nats
+            .subscribe(BackendStateMessage::subscribe_subject())
+            .await?;
+        let mut interval = tokio::time::interval(self.ttl.max(Duration::from_secs(1)));
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => self.prune(Instant::now()),
+                message = sub.next() => match message {
+                    Ok(Some(message)) => {
+                        // Subjects are of the form backend.<backend id>.status.
+                        if let Some(backend_id) = message
+                            .subject()
+                            .strip_prefix("backend.")
+                            .and_then(|subject| subject.strip_suffix(".status"))
+                        {
+                            self.invalidate_backend(&BackendId::new(backend_id.to_string()));
+                        }
+                    }
+                    Ok(None) => return Err(anyhow!("Backend state subscription closed.")),
+                    Err(error) => {
+                        tracing::warn!(?error, "Non-fatal error when listening for backend states.")
+                    }
+                },
+            }
+        }
+    }
+
+    /// Periodically drop expired routes, for a cache without NATS to listen on.
+    pub async fn prune_loop(self) {
+        let mut interval = tokio::time::interval(self.ttl.max(Duration::from_secs(1)));
+        loop {
+            interval.tick().await;
+            self.prune(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn route(backend_id: &str) -> ProxyRoute {
+        ProxyRoute {
+            backend_id: Some(BackendId::new(backend_id.to_string())),
+            address: "127.0.0.1:8080".to_string(),
+            bearer_token: None,
+            max_connections: None,
+            timeouts: None,
+            replicas: Vec::new(),
+            cluster: None,
+        }
+    }
+
+    #[test]
+    fn test_route_cache() {
+        let cache = RouteCache::new(Duration::from_secs(5));
+        cache.insert("mybackend", route("mybackend"));
+        cache.insert("mybackend-api", route("mybackend"));
+        cache.insert("otherbackend", route("otherbackend"));
+        let now = Instant::now();
+
+        assert!(cache.get_at("mybackend", now).is_some());
+        assert!(cache
+            .get_at("mybackend", now + Duration::from_secs(5))
+            .is_none());
+        assert!(cache.get_at("mybackend", now).is_none());
+
+        cache.invalidate_backend(&BackendId::new("mybackend".to_string()));
+        assert!(cache.get_at("mybackend-api", now).is_none());
+        assert!(cache.get_at("otherbackend", now).is_some());
+    }
+}
@@ -0,0 +1,322 @@
+//! Routes received from agents over NATS, consulted before the database so that the
+//! proxy can route to backends on drones whose database it does not share. Routes are
+//! kept by the cluster they were published in, so that a backend is only reachable
+//! under its own cluster's domain.
+
+use crate::{
+    database::ProxyRoute,
+    messages::proxy::{RouteInfo, RouteSyncRequest, RouteUpdate, RouteUpdateMessage},
+    nats::TypedNats,
+    types::DroneId,
+};
+use anyhow::{anyhow, Result};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// Routes of a drone which has not synced for this long are dropped, since the drone
+/// has probably gone away. Drones sync every 30 seconds.
+const ROUTE_EXPIRY: Duration = Duration::from_secs(90);
+
+struct DroneRoutes {
+    /// Routes by subdomain.
+    routes: HashMap<String, RouteInfo>,
+    last_update: Instant,
+}
+
+impl DroneRoutes {
+    fn new(now: Instant) -> Self {
+        DroneRoutes {
+            routes: HashMap::new(),
+            last_update: now,
+        }
+    }
+}
+
+/// How long after asking drones for their routes the table is considered synced, if no
+/// drone has responded, e.g. because the cluster has none yet.
+const SYNC_GRACE: Duration = Duration::from_secs(5);
+
+/// The routes of every drone in the proxy's clusters.
+#[derive(Clone, Default)]
+pub struct RouteTable {
+    /// Routes by cluster and drone, since each cluster's drones are numbered separately.
+    drones: Arc<Mutex<HashMap<(String, DroneId), DroneRoutes>>>,
+
+    /// Clusters whose drones have been asked for their routes, and have had time to
+    /// respond.
+    synced_clusters: Arc<Mutex<HashSet<String>>>,
+}
+
+impl RouteTable {
+    /// The route of the subdomain in the cluster, or in any cluster if None, e.g. for a
+    /// custom domain.
+    pub fn get(&self, cluster: Option<&str>, subdomain: &str) -> Option<ProxyRoute> {
+        let drones = self.drones.lock().expect("Route table lock was poisoned.");
+        drones
+            .iter()
+            .filter(|((drone_cluster, _), _)| {
+                cluster.is_none_or(|cluster| drone_cluster == cluster)
+            })
+            .find_map(|((drone_cluster, _), drone)| {
+                Some((drone_cluster, drone.routes.get(subdomain)?))
+            })
+            .map(|(drone_cluster, route)| ProxyRoute {
+                backend_id: Some(route.backend_id.clone()),
+                address: route.address.clone(),
+                bearer_token: route.bearer_token.clone(),
+                max_connections: route.max_connections,
+                timeouts: route.timeouts,
+                replicas: route.replicas.clone(),
+                cluster: Some(drone_cluster.clone()),
+            })
+    }
+
+    /// The number of routes in the table.
+    pub fn len(&self) -> usize {
+        let drones = self.drones.lock().expect("Route table lock was poisoned.");
+        drones.values().map(|drone| drone.routes.len()).sum()
+    }
+
+    /// Whether the routes of every one of the clusters have been synced since the proxy
+    /// started listening for them.
+    pub fn is_synced(&self, clusters: &[String]) -> bool {
+        let synced_clusters = self
+            .synced_clusters
+            .lock()
+            .expect("Route table lock was poisoned.");
+        clusters
+            .iter()
+            .all(|cluster| synced_clusters.contains(cluster))
+    }
+
+    fn set_synced(&self, cluster: &str, synced: bool) {
+        let mut synced_clusters = self
+            .synced_clusters
+            .lock()
+            .expect("Route table lock was poisoned.");
+        if synced {
+            synced_clusters.insert(cluster.to_string());
+        } else {
+            synced_clusters.remove(cluster);
+        }
+    }
+
+    fn apply(&self, cluster: &str, message: RouteUpdateMessage, now: Instant) {
+        let mut drones = self.drones.lock().expect("Route table lock was poisoned.");
+        let key = (cluster.to_string(), message.drone_id);
+
+        match message.update {
+            RouteUpdate::Add(route) => {
+                // A subdomain belongs to one backend of the cluster, which may have moved
+                // drones.
+                for ((drone_cluster, _), drone) in drones.iter_mut() {
+                    if drone_cluster == cluster {
+                        drone.routes.remove(&route.subdomain);
+                    }
+                }
+                let drone = drones.entry(key).or_insert_with(|| DroneRoutes::new(now));
+                drone.routes.insert(route.subdomain.clone(), route);
+            }
+            RouteUpdate::RemoveBackend(backend_id) => {
+                if let Some(drone) = drones.get_mut(&key) {
+                    drone
+                        .routes
+                        .retain(|_, route| route.backend_id != backend_id);
+                }
+            }
+            RouteUpdate::Sync(routes) => {
+                let routes = routes
+                    .into_iter()
+                    .map(|route| (route.subdomain.clone(), route))
+                    .collect();
+                drones.insert(
+                    key,
+                    DroneRoutes {
+                        routes,
+                        last_update: now,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Drop the routes of drones which have not synced recently.
+    fn expire(&self, now: Instant) {
+        let mut drones = self.drones.lock().expect("Route table lock was poisoned.");
+        drones.retain(|(cluster, drone_id), drone| {
+            let expired = now.duration_since(drone.last_update) > ROUTE_EXPIRY;
+            if expired {
+                tracing::warn!(
+                    %cluster,
+                    ?drone_id,
+                    "Dropping routes of drone which stopped syncing."
+                );
+            }
+            !expired
+        });
+    }
+
+    /// Apply the route updates published in the cluster, after asking its drones to
+    /// publish their routes in full.
+    pub async fn listen(self, nats: TypedNats, cluster: String) -> Result<()> {
+        let mut sub = nats
+            .subscribe(&RouteUpdateMessage::subject(&cluster))
+            .await?;
+        nats.publish(&RouteSyncRequest::subject(&cluster), &RouteSyncRequest)
+            .await?;
+        let mut interval = tokio::time::interval(ROUTE_EXPIRY / 3);
+        let sync_grace = tokio::time::sleep(SYNC_GRACE);
+        tokio::pin!(sync_grace);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => self.expire(Instant::now()),
+                _ = &mut sync_grace, if !self.is_synced(std::slice::from_ref(&cluster)) => {
+                    self.set_synced(&cluster, true);
+                }
+                message = sub.next() => match message {
+                    Ok(Some(message)) => {
+                        if matches!(message.value.update, RouteUpdate::Sync(_)) {
+                            self.set_synced(&cluster, true);
+                        }
+                        self.apply(&cluster, message.value, Instant::now());
+                    }
+                    Ok(None) => {
+                        self.set_synced(&cluster, false);
+                        return Err(anyhow!("Route update subscription closed."));
+                    }
+                    Err(error) => {
+                        tracing::warn!(?error, "Non-fatal error when listening for route updates.")
+                    }
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::BackendId;
+
+    fn route(subdomain: &str, backend_id: &str, address: &str) -> RouteInfo {
+        RouteInfo {
+            subdomain: subdomain.to_string(),
+            backend_id: BackendId::new(backend_id.to_string()),
+            address: address.to_string(),
+            bearer_token: None,
+            max_connections: None,
+            timeouts: None,
+            replicas: Vec::new(),
+        }
+    }
+
+    fn message(drone_id: u32, update: RouteUpdate) -> RouteUpdateMessage {
+        RouteUpdateMessage {
+            drone_id: DroneId::new(drone_id),
+            update,
+        }
+    }
+
+    fn address(table: &RouteTable, subdomain: &str) -> Option<String> {
+        table
+            .get(Some("spawner.test"), subdomain)
+            .map(|route| route.address)
+    }
+
+    #[test]
+    fn test_apply_route_updates() {
+        let table = RouteTable::default();
+        let start = Instant::now();
+
+        table.apply(
+            "spawner.test",
+            message(1, RouteUpdate::Add(route("a", "a", "10.0.0.1:1000"))),
+            start,
+        );
+        table.apply(
+            "spawner.test",
+            message(
+                1,
+                RouteUpdate::Sync(vec![
+                    route("a", "a", "10.0.0.1:1000"),
+                    route("b", "b", "10.0.0.1:1001"),
+                ]),
+            ),
+            start,
+        );
+        table.apply(
+            "spawner.test",
+            message(2, RouteUpdate::Add(route("c", "c", "10.0.0.2:1000"))),
+            start,
+        );
+        assert_eq!(Some("10.0.0.1:1001".to_string()), address(&table, "b"));
+        assert_eq!(Some("10.0.0.2:1000".to_string()), address(&table, "c"));
+
+        // The backend of subdomain a was restarted on drone 2.
+        table.apply(
+            "spawner.test",
+            message(2, RouteUpdate::Add(route("a", "a", "10.0.0.2:1001"))),
+            start,
+        );
+        assert_eq!(Some("10.0.0.2:1001".to_string()), address(&table, "a"));
+
+        table.apply(
+            "spawner.test",
+            message(
+                1,
+                RouteUpdate::RemoveBackend(BackendId::new("b".to_string())),
+            ),
+            start,
+        );
+        assert_eq!(None, address(&table, "b"));
+
+        // Drone 1 keeps syncing, while drone 2 has gone away.
+        table.apply(
+            "spawner.test",
+            message(1, RouteUpdate::Sync(vec![route("d", "d", "10.0.0.1:1002")])),
+            start + Duration::from_secs(60),
+        );
+        table.expire(start + ROUTE_EXPIRY + Duration::from_secs(1));
+        assert_eq!(None, address(&table, "a"));
+        assert_eq!(None, address(&table, "c"));
+        assert_eq!(Some("10.0.0.1:1002".to_string()), address(&table, "d"));
+    }
+
+    #[test]
+    fn test_cluster_routes() {
+        let table = RouteTable::default();
+        let start = Instant::now();
+
+        // Each cluster numbers its own drones, and may reuse subdomains.
+        table.apply(
+            "eu.spawner.test",
+            message(1, RouteUpdate::Add(route("a", "a", "10.0.0.1:1000"))),
+            start,
+        );
+        table.apply(
+            "us.spawner.test",
+            message(1, RouteUpdate::Add(route("a", "a", "10.1.0.1:1000"))),
+            start,
+        );
+        table.apply(
+            "us.spawner.test",
+            message(1, RouteUpdate::Add(route("b", "b", "10.1.0.1:1001"))),
+            start,
+        );
+
+        let route = table.get(Some("eu.spawner.test"), "a").unwrap();
+        assert_eq!("10.0.0.1:1000", route.address);
+        assert_eq!(Some("eu.spawner.test".to_string()), route.cluster);
+        assert_eq!(
+            "10.1.0.1:1000",
+            table.get(Some("us.spawner.test"), "a").unwrap().address
+        );
+        assert!(table.get(Some("eu.spawner.test"), "b").is_none());
+        assert!(table.get(None, "b").is_some());
+    }
+}
@@ -1,22 +1,174 @@
-use super::connection_tracker::ConnectionTracker;
-use crate::database::DroneDatabase;
+use super::{
+    access_log::AccessLogger,
+    acme_challenge::AcmeChallenges,
+    affinity::ReplicaBalancer,
+    compression::{content_types_for, negotiate_encoding, CompressionRule, ResponseCompression},
+    connection_tracker::{ConnectionTracker, LimitedConnectionGuard},
+    cors::{is_preflight, read_cors_config, request_origin, CorsOptions},
+    error_pages::{ErrorClass, ErrorPages},
+    forwarded::{add_forwarded_headers, mode_for, ForwardedHeadersRule},
+    http3::alt_svc,
+    jwt::JwtValidator,
+    metrics::ProxyMetrics,
+    path_routing::{
+        needs_trailing_slash, split_path_prefix, strip_path_prefix, X_FORWARDED_PREFIX,
+    },
+    rate_limit::RateLimits,
+    request_limits::RequestLimits,
+    route_cache::RouteCache,
+    route_table::RouteTable,
+    split_host,
+    static_site::StaticSites,
+    timeouts::{copy_bidirectional_with_idle_timeout, idle_timeout_body, UpstreamTimeouts},
+    trace_context::TraceContext,
+    upstream::{UpstreamConnector, UpstreamTls},
+    ProxyOptions,
+};
+use crate::{
+    database::{DroneDatabase, ProxyRoute},
+    drone::{backend_tls::BackendCa, cert::custom_domain::is_custom_domain},
+    messages::{agent::BackendState, proxy::AccessLogMessage},
+    types::BackendId,
+};
 use anyhow::{anyhow, Result};
+use chrono::Utc;
 use http::uri::{Authority, Scheme};
-use http::Uri;
-use hyper::client::HttpConnector;
+use http::{HeaderValue, Method, Uri, Version};
+use hyper::server::conn::AddrStream;
 use hyper::Client;
-use hyper::{service::Service, Body, Request, Response, StatusCode};
+use hyper::{service::Service, Body, HeaderMap, Request, Response, StatusCode};
+use std::collections::HashMap;
 use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
-use std::time::SystemTime;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use std::{
     convert::Infallible,
     future::{ready, Future, Ready},
     pin::Pin,
     task::Poll,
 };
+use tokio::{sync::oneshot, time::Instant};
+use tracing::Instrument;
 
 const UPGRADE: &str = "upgrade";
+const GRPC_CONTENT_TYPE: &str = "application/grpc";
+const BEARER_PREFIX: &str = "Bearer ";
+const BEARER_TOKEN_COOKIE: &str = "spawner_token";
+
+/// How often a request held for a starting backend checks whether it has become ready.
+const STARTING_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Seconds after which browsers reload the page served for a starting backend.
+const STARTING_REFRESH_SECS: &str = "2";
+
+/// What a request is routed to.
+struct RequestTarget<'a> {
+    cluster_domain: &'a str,
+
+    /// The route's key: a subdomain, or a custom domain.
+    subdomain: String,
+
+    /// For requests routed by path, the path after the subdomain's segment.
+    path_rest: Option<String>,
+
+    /// Whether the route's key is a custom domain, which is routed whatever cluster its
+    /// backend belongs to.
+    custom_domain: bool,
+}
+
+/// The route of a subdomain, if it has one yet.
+enum RouteStatus {
+    Ready(ProxyRoute),
+
+    /// The subdomain's backend is loading or starting, and has no route yet.
+    Starting,
+    Missing,
+}
+
+/// The host a request is for. HTTP/2 requests carry it in the URI's authority rather
+/// than a Host header.
+fn request_host(req: &Request<Body>) -> Option<&str> {
+    match req.headers().get(http::header::HOST) {
+        Some(host) => host.to_str().ok(),
+        None => req.uri().host(),
+    }
+}
+
+/// The tokens a request carries in its `Authorization` header or `spawner_token` cookie.
+fn presented_tokens(headers: &HeaderMap) -> impl Iterator<Item = &str> {
+    let from_header = headers
+        .get_all(http::header::AUTHORIZATION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .filter_map(|value| value.strip_prefix(BEARER_PREFIX));
+    let from_cookie = headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .filter(|(name, _)| *name == BEARER_TOKEN_COOKIE)
+        .map(|(_, value)| value);
+
+    from_header.chain(from_cookie).map(str::trim)
+}
+
+/// Whether the request carries the bearer token. Tokens are compared in constant time.
+fn is_authorized(headers: &HeaderMap, bearer_token: &str) -> bool {
+    presented_tokens(headers).any(|token| {
+        token.len() == bearer_token.len()
+            && openssl::memcmp::eq(token.as_bytes(), bearer_token.as_bytes())
+    })
+}
+
+/// Whether the request is a gRPC call, which must be sent to the backend over HTTP/2.
+/// gRPC-Web is excluded, since it works over HTTP/1.1.
+fn is_grpc(headers: &HeaderMap) -> bool {
+    let content_type = match headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(content_type) => content_type,
+        None => return false,
+    };
+
+    match content_type.strip_prefix(GRPC_CONTENT_TYPE) {
+        Some(rest) => rest.is_empty() || rest.starts_with('+') || rest.starts_with(';'),
+        None => false,
+    }
+}
+
+/// Whether the response is a stream of server-sent events, which may go quiet for long
+/// periods.
+fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/event-stream"))
+}
+
+/// The protocol that a request asks to switch to, e.g. `websocket`, if its `Connection`
+/// header lists the `upgrade` option and its `Upgrade` header names a protocol.
+fn requested_upgrade(headers: &HeaderMap) -> Option<&str> {
+    let connection_upgrade = headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|option| option.trim().eq_ignore_ascii_case(UPGRADE));
+    if !connection_upgrade {
+        return None;
+    }
+
+    headers
+        .get(http::header::UPGRADE)?
+        .to_str()
+        .ok()
+        .map(str::trim)
+        .filter(|protocol| !protocol.is_empty())
+}
 
 /// Clone a request (method and headers, not body).
 fn clone_request(request: &Request<Body>) -> Result<Request<Body>, hyper::http::Error> {
@@ -39,25 +191,150 @@ fn clone_response(response: &Response<Body>) -> Result<Response<Body>, hyper::ht
     builder.body(Body::empty())
 }
 
+/// A connection to the proxy which knows the address of its client.
+pub trait RemoteAddr {
+    fn remote_addr(&self) -> SocketAddr;
+}
+
+impl RemoteAddr for AddrStream {
+    fn remote_addr(&self) -> SocketAddr {
+        AddrStream::remote_addr(self)
+    }
+}
+
+/// The backend a response came from, attached to the response so that it can be logged.
+struct RoutedBackend(BackendId);
+
+/// Resolves with the number of bytes sent to the client over an upgraded connection,
+/// once it closes.
+struct UpgradeClosed(oneshot::Receiver<u64>);
+
+#[derive(Clone)]
 pub struct MakeProxyService {
     db: DroneDatabase,
-    client: Client<HttpConnector, Body>,
-    cluster: String,
+    client: Client<UpstreamConnector, Body>,
+    h2c_client: Client<UpstreamConnector, Body>,
+    cluster_domains: Arc<Vec<String>>,
     connection_tracker: ConnectionTracker,
+    access_logger: Option<AccessLogger>,
+    jwt_validator: Option<JwtValidator>,
+    cors: Arc<HashMap<String, Arc<CorsOptions>>>,
+    forwarded_headers: Arc<Vec<ForwardedHeadersRule>>,
+    compression: Arc<Vec<CompressionRule>>,
+
+    /// Whether clients connect over HTTPS.
+    https: bool,
+    route_table: Option<RouteTable>,
+    route_cache: Option<RouteCache>,
+    rate_limits: Option<Arc<RateLimits>>,
+    metrics: Option<ProxyMetrics>,
+    error_pages: Arc<ErrorPages>,
+    hold_starting: Duration,
+    starting_page: bool,
+    path_routing: bool,
+    custom_domains: bool,
+    connection_limit_status: StatusCode,
+    alt_svc: Option<HeaderValue>,
+    static_sites: Arc<StaticSites>,
+    request_limits: RequestLimits,
+    upstream_timeouts: UpstreamTimeouts,
+    replica_balancer: ReplicaBalancer,
+    trace_spans: bool,
+    acme_challenges: Option<AcmeChallenges>,
 }
 
 impl MakeProxyService {
-    pub fn new(db: DroneDatabase, cluster: String, connection_tracker: ConnectionTracker) -> Self {
-        MakeProxyService {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        db: DroneDatabase,
+        options: &ProxyOptions,
+        connection_tracker: ConnectionTracker,
+        access_logger: Option<AccessLogger>,
+        route_table: Option<RouteTable>,
+        route_cache: Option<RouteCache>,
+        metrics: Option<ProxyMetrics>,
+        acme_challenges: Option<AcmeChallenges>,
+    ) -> Result<Self> {
+        let cluster_domains = options.cluster_domains();
+        let cors = match &options.cors_config {
+            Some(path) => read_cors_config(path)?
+                .into_iter()
+                .map(|(cluster_domain, cors)| (cluster_domain, Arc::new(cors)))
+                .collect(),
+            None => HashMap::new(),
+        };
+        let rate_limits =
+            if options.backend_rate_limit.is_some() || options.client_rate_limit.is_some() {
+                let rate_limits = Arc::new(RateLimits::new(
+                    options.backend_rate_limit,
+                    options.client_rate_limit,
+                ));
+                tokio::spawn(rate_limits.clone().prune_loop());
+                Some(rate_limits)
+            } else {
+                None
+            };
+
+        let (connector, h2_connector) = match &options.backend_ca {
+            Some(paths) => {
+                let upstream_tls = UpstreamTls::new(&BackendCa::load(paths)?)?;
+                (
+                    upstream_tls.connector(&[b"http/1.1"])?,
+                    upstream_tls.connector(&[b"h2"])?,
+                )
+            }
+            None => (UpstreamConnector::plain(), UpstreamConnector::plain()),
+        };
+        let timeouts = options.upstream_timeouts;
+        let connector = connector.with_connect_timeout(timeouts.connect);
+        let h2_connector = h2_connector.with_connect_timeout(timeouts.connect);
+
+        Ok(MakeProxyService {
             db,
-            client: Client::new(),
-            cluster,
+            client: Client::builder()
+                .pool_idle_timeout(timeouts.idle)
+                .build(connector),
+            h2c_client: Client::builder()
+                .pool_idle_timeout(timeouts.idle)
+                .http2_only(true)
+                .build(h2_connector),
+            cluster_domains: Arc::new(cluster_domains),
             connection_tracker,
-        }
+            access_logger,
+            jwt_validator: options.jwt.clone().map(JwtValidator::new),
+            cors: Arc::new(cors),
+            forwarded_headers: Arc::new(options.forwarded_headers.clone()),
+            compression: Arc::new(options.compression.clone()),
+            https: options.https_options.is_some(),
+            route_table,
+            route_cache,
+            rate_limits,
+            metrics,
+            error_pages: Arc::new(ErrorPages::load(&options.error_pages)?),
+            hold_starting: options.hold_starting,
+            starting_page: options.starting_page,
+            path_routing: options.path_routing,
+            custom_domains: options.custom_domains,
+            connection_limit_status: options.connection_limit_status,
+            alt_svc: options
+                .https_options
+                .as_ref()
+                .filter(|https_options| https_options.http3)
+                .map(|https_options| alt_svc(https_options.port)),
+            static_sites: Arc::new(StaticSites::load(&options.static_sites)?),
+            request_limits: options.request_limits,
+            upstream_timeouts: timeouts,
+            replica_balancer: ReplicaBalancer::new(
+                options.affinity_cookie.clone(),
+                options.https_options.is_some(),
+            ),
+            trace_spans: options.trace_spans,
+            acme_challenges,
+        })
     }
 }
 
-impl<T> Service<T> for MakeProxyService {
+impl<'a, T: RemoteAddr> Service<&'a T> for MakeProxyService {
     type Response = ProxyService;
     type Error = Infallible;
     type Future = Ready<Result<ProxyService, Infallible>>;
@@ -69,12 +346,37 @@ impl<T> Service<T> for MakeProxyService {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, _req: T) -> Self::Future {
+    fn call(&mut self, connection: &'a T) -> Self::Future {
         ready(Ok(ProxyService {
             db: self.db.clone(),
             client: self.client.clone(),
-            cluster: self.cluster.clone(),
+            h2c_client: self.h2c_client.clone(),
+            cluster_domains: self.cluster_domains.clone(),
             connection_tracker: self.connection_tracker.clone(),
+            access_logger: self.access_logger.clone(),
+            jwt_validator: self.jwt_validator.clone(),
+            cors: self.cors.clone(),
+            forwarded_headers: self.forwarded_headers.clone(),
+            compression: self.compression.clone(),
+            https: self.https,
+            route_table: self.route_table.clone(),
+            route_cache: self.route_cache.clone(),
+            rate_limits: self.rate_limits.clone(),
+            metrics: self.metrics.clone(),
+            error_pages: self.error_pages.clone(),
+            hold_starting: self.hold_starting,
+            starting_page: self.starting_page,
+            path_routing: self.path_routing,
+            custom_domains: self.custom_domains,
+            connection_limit_status: self.connection_limit_status,
+            alt_svc: self.alt_svc.clone(),
+            static_sites: self.static_sites.clone(),
+            request_limits: self.request_limits,
+            upstream_timeouts: self.upstream_timeouts,
+            replica_balancer: self.replica_balancer.clone(),
+            trace_spans: self.trace_spans,
+            acme_challenges: self.acme_challenges.clone(),
+            client_ip: connection.remote_addr().ip(),
         }))
     }
 }
@@ -82,9 +384,63 @@ impl<T> Service<T> for MakeProxyService {
 #[derive(Clone)]
 pub struct ProxyService {
     db: DroneDatabase,
-    client: Client<HttpConnector, Body>,
-    cluster: String,
+
+    /// Client for requests to backends over HTTP/1.1.
+    client: Client<UpstreamConnector, Body>,
+
+    /// Client for requests to backends over HTTP/2, used for gRPC. Cleartext unless
+    /// backends are reached over mutual TLS.
+    h2c_client: Client<UpstreamConnector, Body>,
+    cluster_domains: Arc<Vec<String>>,
     connection_tracker: ConnectionTracker,
+    access_logger: Option<AccessLogger>,
+    jwt_validator: Option<JwtValidator>,
+    cors: Arc<HashMap<String, Arc<CorsOptions>>>,
+    forwarded_headers: Arc<Vec<ForwardedHeadersRule>>,
+    compression: Arc<Vec<CompressionRule>>,
+    https: bool,
+    route_table: Option<RouteTable>,
+    route_cache: Option<RouteCache>,
+    rate_limits: Option<Arc<RateLimits>>,
+    metrics: Option<ProxyMetrics>,
+    error_pages: Arc<ErrorPages>,
+
+    /// How long requests for a starting backend are held for it to become ready.
+    hold_starting: Duration,
+
+    /// Whether a page which reloads until the backend is ready is served for requests to a
+    /// starting backend which are not held, or not held long enough.
+    starting_page: bool,
+
+    /// Whether requests to a cluster domain itself are routed by the first segment of
+    /// their path.
+    path_routing: bool,
+
+    /// Whether hosts outside the cluster domains are routed as backends' custom domains.
+    custom_domains: bool,
+
+    /// Status of the response to an upgrade request for a backend at its connection limit.
+    connection_limit_status: StatusCode,
+
+    /// Advertises HTTP/3 in responses, if it is served.
+    alt_svc: Option<HeaderValue>,
+
+    /// Served at cluster domains themselves, and for the hosts of terminated backends.
+    static_sites: Arc<StaticSites>,
+    request_limits: RequestLimits,
+
+    /// Timeouts of requests to backends, unless overridden by their spawn requests.
+    upstream_timeouts: UpstreamTimeouts,
+
+    /// Chooses the replica of backends which have several, shared between connections.
+    replica_balancer: ReplicaBalancer,
+
+    /// Whether each request is handled in a span carrying its trace context.
+    trace_spans: bool,
+
+    /// Answers HTTP-01 challenges on cluster domains, if enabled.
+    acme_challenges: Option<AcmeChallenges>,
+    client_ip: IpAddr,
 }
 
 #[allow(unused)]
@@ -98,15 +454,40 @@ impl ProxyService {
         Ok(uri)
     }
 
+    /// Upgrade the connection to the backend, e.g. to a WebSocket, and once the backend
+    /// agrees, upgrade the client's connection and copy data between the two until either
+    /// side closes. The connection counts as activity on the backend while it is open, and
+    /// against its connection limit, if it has one, and is closed once it has been idle
+    /// for the streaming idle timeout.
     async fn handle_upgrade(
         self,
         mut req: Request<Body>,
         backend: &str,
+        protocol: String,
+        limited_connection: Option<LimitedConnectionGuard>,
+        timeouts: UpstreamTimeouts,
     ) -> anyhow::Result<Response<Body>> {
-        let response = self.client.request(clone_request(&req)?).await?;
+        let response = match tokio::time::timeout(
+            timeouts.read,
+            self.client.request(clone_request(&req)?),
+        )
+        .await
+        {
+            Ok(response) => response?,
+            Err(_) => {
+                tracing::warn!(%backend, "Timed out waiting for backend to upgrade connection.");
+                return self
+                    .error_pages
+                    .response(ErrorClass::TimedOut, request_host(&req));
+            }
+        };
 
         if response.status() == StatusCode::SWITCHING_PROTOCOLS {
-            let response_clone = clone_response(&response)?;
+            let mut response_clone = clone_response(&response)?;
+            let (closed_send, closed_recv) = oneshot::channel();
+            response_clone
+                .extensions_mut()
+                .insert(UpgradeClosed(closed_recv));
 
             let mut upgraded_response = match hyper::upgrade::on(response).await {
                 Ok(upgraded) => upgraded,
@@ -121,12 +502,14 @@ impl ProxyService {
             tokio::task::spawn(async move {
                 match hyper::upgrade::on(&mut req).await {
                     Ok(mut upgraded_request) => {
+                        let _limited_connection = limited_connection;
                         let started = SystemTime::now();
 
                         connection_tracker.increment_connections(&backend);
-                        let result = tokio::io::copy_bidirectional(
+                        let result = copy_bidirectional_with_idle_timeout(
                             &mut upgraded_response,
                             &mut upgraded_request,
+                            timeouts.streaming_idle,
                         )
                         .await;
                         connection_tracker.decrement_connections(&backend);
@@ -136,8 +519,12 @@ impl ProxyService {
                             .as_secs();
 
                         match result {
-                            Ok((from_client, from_server)) => {
-                                tracing::info!(%from_client, %from_server, ?duration, "Upgraded connection closed.");
+                            Ok((from_server, from_client)) => {
+                                tracing::info!(%from_client, %from_server, ?duration, %protocol, "Upgraded connection closed.");
+                                let _ = closed_send.send(from_server);
+                            }
+                            Err(error) if error.kind() == ErrorKind::TimedOut => {
+                                tracing::info!(?duration, %protocol, "Closed idle upgraded connection.");
                             }
                             Err(error) if error.kind() == ErrorKind::UnexpectedEof => {
                                 tracing::info!(
@@ -164,30 +551,432 @@ impl ProxyService {
         }
     }
 
+    /// The route of the subdomain in the cluster (or in any cluster, if None), from the
+    /// routes published by agents if there is one, or otherwise from the route cache or
+    /// the database.
+    async fn get_route(
+        &self,
+        cluster: Option<&str>,
+        subdomain: &str,
+    ) -> Result<Option<ProxyRoute>> {
+        if let Some(route) = self
+            .route_table
+            .as_ref()
+            .and_then(|route_table| route_table.get(cluster, subdomain))
+        {
+            return Ok(Some(route));
+        }
+
+        if let Some(route_cache) = &self.route_cache {
+            if let Some(route) = route_cache.get(subdomain) {
+                return Ok(self.in_cluster(&route, cluster).then_some(route));
+            }
+        }
+
+        // Missing routes aren't cached, so that a backend is reachable as soon as it's ready.
+        let route = self.db.get_proxy_route(subdomain).await?;
+        if let (Some(route_cache), Some(route)) = (&self.route_cache, &route) {
+            route_cache.insert(subdomain, route.clone());
+        }
+
+        Ok(route.filter(|route| self.in_cluster(route, cluster)))
+    }
+
+    /// Whether the route's backend belongs to the cluster. Backends whose spawn requests
+    /// named no cluster belong to the drone's own.
+    fn in_cluster(&self, route: &ProxyRoute, cluster: Option<&str>) -> bool {
+        let cluster = match cluster {
+            Some(cluster) => cluster,
+            None => return true,
+        };
+        route
+            .cluster
+            .as_deref()
+            .or_else(|| self.cluster_domains.first().map(String::as_str))
+            == Some(cluster)
+    }
+
+    /// The cluster domain the host is for, whether by subdomain or, if requests are routed
+    /// by path, as the cluster domain itself.
+    fn cluster_domain_of(&self, host: &str) -> Option<&str> {
+        match split_host(host, &self.cluster_domains) {
+            Some((cluster_domain, _)) => Some(cluster_domain),
+            None if self.path_routing => self
+                .cluster_domains
+                .iter()
+                .find(|cluster_domain| *cluster_domain == host)
+                .map(String::as_str),
+            None => None,
+        }
+    }
+
+    /// What a request for the host and URI is routed to, by the subdomain of the host or,
+    /// if requests are routed by path, by the first segment of the path. Custom domains
+    /// are routed by the whole host.
+    fn request_target(&self, host: &str, uri: &Uri) -> Option<RequestTarget<'_>> {
+        if let Some((cluster_domain, subdomain)) = split_host(host, &self.cluster_domains) {
+            return Some(RequestTarget {
+                cluster_domain,
+                subdomain: subdomain.to_string(),
+                path_rest: None,
+                custom_domain: false,
+            });
+        }
+
+        if let Some(cluster_domain) = self.cluster_domain_of(host) {
+            let path_route = split_path_prefix(uri.path_and_query()?.as_str())?;
+            return Some(RequestTarget {
+                cluster_domain,
+                subdomain: path_route.subdomain.to_string(),
+                path_rest: Some(path_route.rest),
+                custom_domain: false,
+            });
+        }
+
+        if self.custom_domains && is_custom_domain(host, &self.cluster_domains) {
+            return Some(RequestTarget {
+                cluster_domain: self.cluster_domains.first()?,
+                subdomain: host.to_string(),
+                path_rest: None,
+                custom_domain: true,
+            });
+        }
+
+        None
+    }
+
+    /// Whether the backend of the subdomain is loading or starting. Only the backend's own
+    /// subdomain is recognized, not those of its additional ports.
+    async fn is_starting(&self, subdomain: &str) -> Result<bool> {
+        let state = self
+            .db
+            .get_backend_state(&BackendId::new(subdomain.to_string()))
+            .await?;
+        Ok(matches!(
+            state,
+            Some(BackendState::Loading | BackendState::Starting)
+        ))
+    }
+
+    /// Whether the backend of the subdomain has terminated. Like `is_starting`, only the
+    /// backend's own subdomain is recognized.
+    async fn is_terminated(&self, subdomain: &str) -> Result<bool> {
+        let state = self
+            .db
+            .get_backend_state(&BackendId::new(subdomain.to_string()))
+            .await?;
+        Ok(matches!(state, Some(state) if state.terminal()))
+    }
+
+    /// The route of the subdomain in the cluster. If the subdomain's backend is starting,
+    /// and requests are held for starting backends, waits for it to become ready.
+    async fn get_route_when_ready(
+        &self,
+        cluster: Option<&str>,
+        subdomain: &str,
+    ) -> Result<RouteStatus> {
+        if let Some(route) = self.get_route(cluster, subdomain).await? {
+            return Ok(RouteStatus::Ready(route));
+        }
+        if (self.hold_starting.is_zero() && !self.starting_page)
+            || !self.is_starting(subdomain).await?
+        {
+            return Ok(RouteStatus::Missing);
+        }
+
+        let deadline = Instant::now() + self.hold_starting;
+        while Instant::now() < deadline {
+            tokio::time::sleep(STARTING_POLL_INTERVAL).await;
+            if let Some(route) = self.get_route(cluster, subdomain).await? {
+                return Ok(RouteStatus::Ready(route));
+            }
+            if !self.is_starting(subdomain).await? {
+                return Ok(RouteStatus::Missing);
+            }
+        }
+
+        Ok(RouteStatus::Starting)
+    }
+
+    /// Whether the request carries the route's bearer token, if it has one, and a valid
+    /// JWT for its backend, if JWTs are required.
+    async fn is_permitted(&self, req: &Request<Body>, route: &ProxyRoute, subdomain: &str) -> bool {
+        if let Some(bearer_token) = &route.bearer_token {
+            if !is_authorized(req.headers(), bearer_token) {
+                return false;
+            }
+        }
+
+        if let Some(validator) = &self.jwt_validator {
+            let backend_id = route.backend_id.as_ref().map_or(subdomain, BackendId::id);
+            let mut errors = Vec::new();
+            for token in presented_tokens(req.headers()) {
+                match validator.validate(token, backend_id).await {
+                    Ok(()) => return true,
+                    Err(error) => errors.push(error),
+                }
+            }
+            tracing::info!(?errors, %backend_id, "Rejected request without a valid JWT.");
+            return false;
+        }
+
+        true
+    }
+
     async fn handle(self, mut req: Request<Body>) -> anyhow::Result<Response<Body>> {
-        if let Some(host) = req.headers().get(http::header::HOST) {
-            let host = std::str::from_utf8(host.as_bytes())?;
+        // HTTP/2 requests carry the host in the URI's authority rather than a Host header.
+        let host = match req.headers().get(http::header::HOST) {
+            Some(host) => Some(std::str::from_utf8(host.as_bytes())?),
+            None => req.uri().host(),
+        };
+
+        if let Some(exceeded) = self.request_limits.check_head(&req) {
+            tracing::info!(?exceeded, ?host, "Refused request exceeding size limit.");
+            return self.error_pages.response_with_status(
+                ErrorClass::TooLarge,
+                exceeded.status(),
+                host,
+            );
+        }
+
+        if let (Some(acme_challenges), Some(host)) = (&self.acme_challenges, host) {
+            if let Some(response) = acme_challenges.response(&req, host).await? {
+                return Ok(response);
+            }
+        }
+
+        if let Some(host) = host {
+            if let Some(RequestTarget {
+                cluster_domain,
+                subdomain,
+                path_rest,
+                custom_domain,
+            }) = self.request_target(host, req.uri())
+            {
+                let cluster = (!custom_domain).then_some(cluster_domain);
+                let route = match self.get_route_when_ready(cluster, &subdomain).await? {
+                    RouteStatus::Ready(route) => Some(route),
+                    RouteStatus::Starting if self.starting_page => {
+                        let mut response = self
+                            .error_pages
+                            .response(ErrorClass::Starting, Some(host))?;
+                        let headers = response.headers_mut();
+                        headers.insert(
+                            http::header::REFRESH,
+                            HeaderValue::from_static(STARTING_REFRESH_SECS),
+                        );
+                        headers.insert(
+                            http::header::RETRY_AFTER,
+                            HeaderValue::from_static(STARTING_REFRESH_SECS),
+                        );
+                        return Ok(response);
+                    }
+                    RouteStatus::Starting | RouteStatus::Missing => None,
+                };
+                if let Some(route) = route {
+                    if path_rest.is_some() && needs_trailing_slash(req.uri().path(), &subdomain) {
+                        let location = match req.uri().query() {
+                            Some(query) => format!("/{}/?{}", subdomain, query),
+                            None => format!("/{}/", subdomain),
+                        };
+                        return Ok(Response::builder()
+                            .status(StatusCode::PERMANENT_REDIRECT)
+                            .header(http::header::LOCATION, location)
+                            .body(Body::empty())?);
+                    }
+
+                    if !self.is_permitted(&req, &route, &subdomain).await {
+                        let mut response = self
+                            .error_pages
+                            .response(ErrorClass::Unauthorized, Some(host))?;
+                        response.headers_mut().insert(
+                            http::header::WWW_AUTHENTICATE,
+                            HeaderValue::from_static("Bearer"),
+                        );
+                        if let Some(backend_id) = route.backend_id {
+                            response.extensions_mut().insert(RoutedBackend(backend_id));
+                        }
+                        return Ok(response);
+                    }
+
+                    if let Some(rate_limits) = &self.rate_limits {
+                        let backend = route
+                            .backend_id
+                            .as_ref()
+                            .map_or(subdomain.as_str(), BackendId::id);
+                        if !rate_limits.check(backend, self.client_ip) {
+                            let mut response = self
+                                .error_pages
+                                .response(ErrorClass::RateLimited, Some(host))?;
+                            response
+                                .headers_mut()
+                                .insert(http::header::RETRY_AFTER, HeaderValue::from_static("1"));
+                            if let Some(backend_id) = route.backend_id {
+                                response.extensions_mut().insert(RoutedBackend(backend_id));
+                            }
+                            return Ok(response);
+                        }
+                    }
+
+                    let mode = mode_for(&self.forwarded_headers, cluster_domain);
+                    let proto = if self.https { "https" } else { "http" };
+                    let host = host.to_string();
+                    add_forwarded_headers(
+                        req.headers_mut(),
+                        mode,
+                        self.client_ip,
+                        proto,
+                        Some(&host),
+                    )?;
+
+                    if let Some(path_rest) = &path_rest {
+                        req.headers_mut().insert(
+                            X_FORWARDED_PREFIX,
+                            HeaderValue::from_str(&format!("/{}", subdomain))?,
+                        );
+                        *req.uri_mut() = strip_path_prefix(req.uri(), path_rest)?;
+                    }
 
-            // TODO: we shouldn't need to allocate a string just to strip a prefix.
-            if let Some(subdomain) = host.strip_suffix(&format!(".{}", self.cluster)) {
-                let subdomain = subdomain.to_string();
-                if let Some(addr) = self.db.get_proxy_route(&subdomain).await? {
                     self.connection_tracker.track_request(&subdomain);
-                    *req.uri_mut() = Self::rewrite_uri(&addr, req.uri())?;
+                    let cookie_path = match &path_rest {
+                        Some(_) => format!("/{}", subdomain),
+                        None => "/".to_string(),
+                    };
+                    let replica = self
+                        .replica_balancer
+                        .choose(&route, req.headers(), &cookie_path);
+                    *req.uri_mut() = Self::rewrite_uri(&replica.address, req.uri())?;
+                    let timeouts = self.upstream_timeouts.for_backend(route.timeouts.as_ref());
 
-                    if let Some(connection) = req.headers().get(hyper::http::header::CONNECTION) {
-                        if connection
-                            .to_str()
-                            .unwrap_or_default()
-                            .to_lowercase()
-                            .contains(UPGRADE)
-                        {
-                            return self.handle_upgrade(req, &subdomain).await;
+                    if let Some(protocol) = requested_upgrade(req.headers()) {
+                        let protocol = protocol.to_string();
+                        let limited_connection = match route.max_connections {
+                            Some(max_connections) => {
+                                let backend = route
+                                    .backend_id
+                                    .as_ref()
+                                    .map_or(subdomain.as_str(), BackendId::id);
+                                match self
+                                    .connection_tracker
+                                    .try_open_limited(backend, max_connections)
+                                {
+                                    Some(guard) => Some(guard),
+                                    None => {
+                                        tracing::info!(%backend, max_connections, "Refused connection to backend at its connection limit.");
+                                        let mut response = self.error_pages.response_with_status(
+                                            ErrorClass::ConnectionLimit,
+                                            self.connection_limit_status,
+                                            Some(&host),
+                                        )?;
+                                        if let Some(backend_id) = route.backend_id {
+                                            response
+                                                .extensions_mut()
+                                                .insert(RoutedBackend(backend_id));
+                                        }
+                                        return Ok(response);
+                                    }
+                                }
+                            }
+                            None => None,
+                        };
+                        let mut response = self
+                            .handle_upgrade(req, &subdomain, protocol, limited_connection, timeouts)
+                            .await?;
+                        if let Some(set_cookie) = &replica.set_cookie {
+                            response.headers_mut().append(
+                                http::header::SET_COOKIE,
+                                HeaderValue::from_str(set_cookie)?,
+                            );
                         }
+                        if let Some(backend_id) = route.backend_id {
+                            response.extensions_mut().insert(RoutedBackend(backend_id));
+                        }
+                        return Ok(response);
                     }
 
-                    let result = self.client.request(req).await;
-                    return Ok(result?);
+                    // Counted until the response headers arrive, so that a backend which is
+                    // stopping is given time to respond.
+                    let _connection = self.connection_tracker.open_connection(&subdomain);
+
+                    let (parts, body) = req.into_parts();
+                    let (body, body_limit) = self.request_limits.limit_body(body);
+                    let mut req = Request::from_parts(parts, body);
+
+                    // Backends are sent HTTP/1.1 regardless of the client's version, except
+                    // for gRPC, which requires HTTP/2 end-to-end. gRPC calls may stream, so
+                    // are only timed out once idle for the streaming idle timeout.
+                    let grpc = is_grpc(req.headers());
+                    let result = if grpc {
+                        *req.version_mut() = Version::HTTP_2;
+                        tokio::time::timeout(timeouts.streaming_idle, self.h2c_client.request(req))
+                            .await
+                    } else {
+                        *req.version_mut() = Version::HTTP_11;
+                        tokio::time::timeout(timeouts.read, self.client.request(req)).await
+                    };
+                    let mut response = match result {
+                        Ok(Ok(response)) => {
+                            let idle = if grpc || is_event_stream(response.headers()) {
+                                timeouts.streaming_idle
+                            } else {
+                                timeouts.idle
+                            };
+                            let (parts, body) = response.into_parts();
+                            Response::from_parts(parts, idle_timeout_body(body, idle))
+                        }
+                        Err(_) => {
+                            tracing::warn!(%subdomain, "Timed out waiting for backend to respond.");
+                            self.error_pages
+                                .response(ErrorClass::TimedOut, Some(&host))?
+                        }
+                        Ok(Err(_)) if body_limit.exceeded() => {
+                            tracing::info!(%subdomain, "Cut off request body exceeding size limit.");
+                            self.error_pages
+                                .response(ErrorClass::TooLarge, Some(&host))?
+                        }
+                        Ok(Err(error)) => {
+                            tracing::warn!(?error, %subdomain, "Error forwarding request to backend.");
+                            if let (Some(metrics), Some(backend_id)) =
+                                (&self.metrics, &route.backend_id)
+                            {
+                                metrics.record_upstream_error(backend_id);
+                            }
+                            self.error_pages
+                                .response(ErrorClass::Unavailable, Some(&host))?
+                        }
+                    };
+                    if let Some(set_cookie) = &replica.set_cookie {
+                        response
+                            .headers_mut()
+                            .append(http::header::SET_COOKIE, HeaderValue::from_str(set_cookie)?);
+                    }
+                    if let Some(backend_id) = route.backend_id {
+                        response.extensions_mut().insert(RoutedBackend(backend_id));
+                    }
+                    return Ok(response);
+                }
+
+                if let Some(site) = self.static_sites.for_cluster(cluster_domain) {
+                    if self.is_terminated(&subdomain).await? {
+                        return site.ended_response(host, &subdomain, cluster_domain);
+                    }
+                    // Paths at the cluster domain which aren't routed to a backend.
+                    if path_rest.is_some() {
+                        if let Some(response) =
+                            site.file_response(req.method(), req.uri().path())?
+                        {
+                            return Ok(response);
+                        }
+                    }
+                }
+            } else if let Some(site) = self
+                .cluster_domains
+                .iter()
+                .find(|cluster_domain| *cluster_domain == host)
+                .and_then(|cluster_domain| self.static_sites.for_cluster(cluster_domain))
+            {
+                if let Some(response) = site.file_response(req.method(), req.uri().path())? {
+                    return Ok(response);
                 }
             }
 
@@ -196,19 +985,158 @@ impl ProxyService {
 
         tracing::warn!("No host header present on request.");
 
-        Ok(Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(Body::empty())?)
+        self.error_pages.response(ErrorClass::NotFound, host)
+    }
+
+    /// Answer CORS preflight requests to clusters with CORS configured, and add CORS
+    /// headers to the responses to other cross-origin requests to them.
+    async fn cors_handle(self, req: Request<Body>) -> anyhow::Result<Response<Body>> {
+        let cors = request_host(&req)
+            .and_then(|host| self.cluster_domain_of(host))
+            .and_then(|cluster_domain| self.cors.get(cluster_domain))
+            .cloned();
+        let (cors, origin) = match (cors, request_origin(&req)) {
+            (Some(cors), Some(origin)) => (cors, origin.to_string()),
+            _ => return self.handle(req).await,
+        };
+
+        if is_preflight(&req) {
+            let response = match cors.preflight_headers(&origin, req.headers()) {
+                Some(headers) => {
+                    let mut response = Response::builder()
+                        .status(StatusCode::NO_CONTENT)
+                        .body(Body::empty())?;
+                    *response.headers_mut() = headers;
+                    response
+                }
+                None => {
+                    tracing::info!(%origin, "Rejected CORS preflight request.");
+                    Response::builder()
+                        .status(StatusCode::FORBIDDEN)
+                        .body(Body::empty())?
+                }
+            };
+            return Ok(response);
+        }
+
+        let mut response = self.handle(req).await?;
+        cors.apply(&origin, response.headers_mut());
+        Ok(response)
+    }
+
+    /// How the response to the request is compressed, if its cluster domain compresses
+    /// any content types. Upgrades and gRPC calls are left alone.
+    fn response_compression(&self, req: &Request<Body>) -> Option<ResponseCompression> {
+        if is_grpc(req.headers()) || requested_upgrade(req.headers()).is_some() {
+            return None;
+        }
+        let target = self.request_target(request_host(req)?, req.uri())?;
+        let content_types = content_types_for(&self.compression, target.cluster_domain);
+        if content_types.is_empty() {
+            return None;
+        }
+
+        Some(ResponseCompression {
+            encoding: negotiate_encoding(req.headers()),
+            content_types: content_types.to_vec(),
+            head: req.method() == Method::HEAD,
+        })
+    }
+
+    /// The access log entry of a request, before it is handled.
+    fn access_log_entry(&self, req: &Request<Body>, trace: &TraceContext) -> AccessLogMessage {
+        let host = request_host(req);
+
+        AccessLogMessage {
+            time: Utc::now(),
+            backend_id: None,
+            host: host.map(str::to_string),
+            method: req.method().to_string(),
+            // The query is left out, since it may contain credentials.
+            path: req.uri().path().to_string(),
+            status: 0,
+            bytes: 0,
+            duration_ms: Duration::ZERO,
+            client_ip: Some(self.client_ip),
+            trace_id: Some(trace.trace_id.clone()),
+        }
     }
 
     async fn warn_handle(self, mut req: Request<Body>) -> anyhow::Result<Response<Body>> {
-        let result = self.handle(req).await;
+        let trace = TraceContext::propagate(req.headers_mut());
+        let access_log = self
+            .access_logger
+            .clone()
+            .map(|logger| (logger, self.access_log_entry(&req, &trace), Instant::now()));
+        let metrics = self.metrics.clone();
+        let compression = self.response_compression(&req);
+        let alt_svc = self.alt_svc.clone();
+        let span = if self.trace_spans {
+            tracing::info_span!(
+                "proxy_request",
+                trace_id = %trace.trace_id,
+                span_id = %trace.span_id,
+                parent_span_id = ?trace.parent_span_id,
+                method = %req.method(),
+                path = %req.uri().path(),
+            )
+        } else {
+            tracing::Span::none()
+        };
+        let started = Instant::now();
+        let result = self.cors_handle(req).instrument(span).await;
+        let result = match compression {
+            Some(compression) => result.map(|response| compression.apply(response)),
+            None => result,
+        };
+        let result = match alt_svc {
+            Some(alt_svc) => result.map(|mut response| {
+                response
+                    .headers_mut()
+                    .insert(http::header::ALT_SVC, alt_svc);
+                response
+            }),
+            None => result,
+        };
 
         if let Err(error) = &result {
             tracing::warn!(?error, "Error handling request.")
         }
+        if let (Some(metrics), Ok(response)) = (metrics, &result) {
+            if let Some(RoutedBackend(backend_id)) = response.extensions().get() {
+                metrics.record_response(backend_id, response.status(), started.elapsed());
+            }
+        }
+
+        match (result, access_log) {
+            (Ok(mut response), Some((logger, entry, started))) => {
+                let entry = AccessLogMessage {
+                    backend_id: response
+                        .extensions_mut()
+                        .remove::<RoutedBackend>()
+                        .map(|backend| backend.0),
+                    status: response.status().as_u16(),
+                    ..entry
+                };
 
-        result
+                if let Some(UpgradeClosed(closed)) = response.extensions_mut().remove() {
+                    tokio::spawn(async move {
+                        let bytes = closed.await.unwrap_or_default();
+                        logger.log(AccessLogMessage {
+                            bytes,
+                            duration_ms: started.elapsed(),
+                            ..entry
+                        });
+                    });
+                    Ok(response)
+                } else {
+                    let (parts, body) = response.into_parts();
+                    let body = logger.log_after_body(body, entry, started);
+                    Ok(Response::from_parts(parts, body))
+                }
+            }
+            (result, _) => result,
+        }
     }
 }
 
@@ -231,3 +1159,63 @@ impl Service<Request<Body>> for ProxyService {
         Box::pin(self.clone().warn_handle(req))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers(name: http::header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, http::HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_is_authorized() {
+        let token = "s3cret-token";
+
+        assert!(is_authorized(
+            &headers(http::header::AUTHORIZATION, "Bearer s3cret-token"),
+            token
+        ));
+        assert!(is_authorized(
+            &headers(
+                http::header::COOKIE,
+                "theme=dark; spawner_token=s3cret-token"
+            ),
+            token
+        ));
+
+        assert!(!is_authorized(
+            &headers(http::header::AUTHORIZATION, "Bearer s3cret-tokex"),
+            token
+        ));
+        assert!(!is_authorized(
+            &headers(http::header::AUTHORIZATION, "Bearer s3cret-token-2"),
+            token
+        ));
+        assert!(!is_authorized(
+            &headers(http::header::AUTHORIZATION, "s3cret-token"),
+            token
+        ));
+        assert!(!is_authorized(
+            &headers(http::header::COOKIE, "other_token=s3cret-token"),
+            token
+        ));
+        assert!(!is_authorized(&HeaderMap::new(), token));
+    }
+
+    #[test]
+    fn test_presented_tokens() {
+        let mut headers = headers(http::header::AUTHORIZATION, "Bearer  from-header ");
+        headers.insert(
+            http::header::COOKIE,
+            http::HeaderValue::from_static("spawner_token=from-cookie; other=ignored"),
+        );
+
+        assert_eq!(
+            vec!["from-header", "from-cookie"],
+            presented_tokens(&headers).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,265 @@
+//! Small static sites served by the proxy itself: a landing or maintenance page at the
+//! cluster domain itself, and a page for backends which have terminated, e.g. telling
+//! the user their session ended and linking to where they can start a new one.
+//!
+//! A site is a directory, read into memory when the proxy starts. Its `index.html` and
+//! other files are served at the cluster domain (or, if requests are routed by path, at
+//! paths which are not routed to a backend). Its `ended.html` is served with 410 Gone for
+//! the hosts of terminated backends, with `{{host}}`, `{{backend}}`, and `{{cluster}}`
+//! replaced; without one, a default page linking to the cluster domain is served.
+
+use super::error_pages::escape_html;
+use anyhow::{anyhow, Context, Result};
+use http::{header, Method, Response, StatusCode};
+use hyper::{body::Bytes, Body};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Served for terminated backends by sites without an `ended.html`.
+const DEFAULT_ENDED_PAGE: &str = "<!DOCTYPE html>
+<html>
+<head><title>Session ended</title></head>
+<body><p>This session has ended. <a href=\"//{{cluster}}/\">Start a new one.</a></p></body>
+</html>
+";
+
+const ENDED_PAGE_PATH: &str = "/ended.html";
+
+/// Sites are held in memory, so are limited to this many bytes in total.
+const MAX_SITE_BYTES: u64 = 16 * 1024 * 1024;
+
+/// The static site of one cluster domain, or of every cluster domain without a rule of
+/// its own.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct StaticSiteRule {
+    pub cluster_domain: Option<String>,
+    pub path: PathBuf,
+}
+
+impl FromStr for StaticSiteRule {
+    type Err = anyhow::Error;
+
+    /// Parses `<directory>` or `<domain>=<directory>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (cluster_domain, path) = match s.split_once('=') {
+            Some((cluster_domain, path)) => (Some(cluster_domain.to_string()), path),
+            None => (None, s),
+        };
+        if path.is_empty() {
+            return Err(anyhow!(
+                "Expected static site to be <directory> or <domain>=<directory>, got {:?}.",
+                s
+            ));
+        }
+
+        Ok(StaticSiteRule {
+            cluster_domain,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+fn content_type(path: &str) -> &'static str {
+    match path.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff2") => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+/// A static site, with its files read.
+#[derive(Default)]
+pub struct StaticSite {
+    /// Contents by path within the site, e.g. `/index.html`.
+    files: HashMap<String, Bytes>,
+}
+
+impl StaticSite {
+    fn read_dir(&mut self, root: &Path, dir: &Path, total_bytes: &mut u64) -> Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                self.read_dir(root, &path, total_bytes)?;
+                continue;
+            }
+
+            let contents = std::fs::read(&path)?;
+            *total_bytes += contents.len() as u64;
+            if *total_bytes > MAX_SITE_BYTES {
+                return Err(anyhow!(
+                    "Expected static site {:?} to be at most {} bytes.",
+                    root,
+                    MAX_SITE_BYTES
+                ));
+            }
+            let relative = path
+                .strip_prefix(root)?
+                .to_string_lossy()
+                .replace('\\', "/");
+            self.files.insert(format!("/{}", relative), contents.into());
+        }
+
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut site = StaticSite::default();
+        site.read_dir(path, path, &mut 0)
+            .with_context(|| format!("Error reading static site {:?}.", path))?;
+        Ok(site)
+    }
+
+    /// The response to a request for the path within the site, if it has a file there.
+    /// Paths ending in `/` are served the `index.html` of the directory.
+    pub fn file_response(&self, method: &Method, path: &str) -> Result<Option<Response<Body>>> {
+        if method != Method::GET && method != Method::HEAD {
+            return Ok(None);
+        }
+        let path = if path.ends_with('/') {
+            format!("{}index.html", path)
+        } else {
+            path.to_string()
+        };
+        let contents = match self.files.get(&path) {
+            Some(contents) => contents.clone(),
+            None => return Ok(None),
+        };
+
+        let length = contents.len();
+        let body = if method == Method::HEAD {
+            Body::empty()
+        } else {
+            Body::from(contents)
+        };
+        Ok(Some(
+            Response::builder()
+                .header(header::CONTENT_TYPE, content_type(&path))
+                .header(header::CONTENT_LENGTH, length)
+                .body(body)?,
+        ))
+    }
+
+    /// The response to a request for the host of a backend which has terminated.
+    pub fn ended_response(
+        &self,
+        host: &str,
+        backend: &str,
+        cluster_domain: &str,
+    ) -> Result<Response<Body>> {
+        let template = match self.files.get(ENDED_PAGE_PATH) {
+            Some(contents) => String::from_utf8_lossy(contents),
+            None => DEFAULT_ENDED_PAGE.into(),
+        };
+        let page = template
+            .replace("{{host}}", &escape_html(host))
+            .replace("{{backend}}", &escape_html(backend))
+            .replace("{{cluster}}", &escape_html(cluster_domain));
+
+        Ok(Response::builder()
+            .status(StatusCode::GONE)
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(page))?)
+    }
+}
+
+/// The static sites of the proxy's cluster domains.
+#[derive(Default)]
+pub struct StaticSites {
+    default: Option<StaticSite>,
+    clusters: HashMap<String, StaticSite>,
+}
+
+impl StaticSites {
+    pub fn load(rules: &[StaticSiteRule]) -> Result<Self> {
+        let mut sites = StaticSites::default();
+        for rule in rules {
+            let site = StaticSite::load(&rule.path)?;
+            match &rule.cluster_domain {
+                Some(cluster_domain) => {
+                    sites.clusters.insert(cluster_domain.clone(), site);
+                }
+                None => sites.default = Some(site),
+            }
+        }
+
+        Ok(sites)
+    }
+
+    /// The site which applies to the cluster domain, if any.
+    pub fn for_cluster(&self, cluster_domain: &str) -> Option<&StaticSite> {
+        self.clusters.get(cluster_domain).or(self.default.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_static_site_rule() {
+        assert_eq!(
+            StaticSiteRule {
+                cluster_domain: None,
+                path: PathBuf::from("/var/www/landing"),
+            },
+            "/var/www/landing".parse().unwrap()
+        );
+        assert_eq!(
+            StaticSiteRule {
+                cluster_domain: Some("othercluster.test".to_string()),
+                path: PathBuf::from("/var/www/other"),
+            },
+            "othercluster.test=/var/www/other".parse().unwrap()
+        );
+        assert!("othercluster.test=".parse::<StaticSiteRule>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_static_site() {
+        let dir = std::env::temp_dir().join(format!("static-site-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("css")).unwrap();
+        std::fs::write(dir.join("index.html"), "<p>Welcome</p>").unwrap();
+        std::fs::write(dir.join("css/site.css"), "p {}").unwrap();
+        let site = StaticSite::load(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let response = site.file_response(&Method::GET, "/").unwrap().unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!("<p>Welcome</p>", body);
+        let response = site
+            .file_response(&Method::GET, "/css/site.css")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            "text/css; charset=utf-8",
+            response.headers()[header::CONTENT_TYPE]
+        );
+        assert!(site
+            .file_response(&Method::GET, "/missing.html")
+            .unwrap()
+            .is_none());
+        assert!(site.file_response(&Method::POST, "/").unwrap().is_none());
+
+        let response = site
+            .ended_response("mybackend.spawner.test", "mybackend", "spawner.test")
+            .unwrap();
+        assert_eq!(StatusCode::GONE, response.status());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("href=\"//spawner.test/\""));
+    }
+}
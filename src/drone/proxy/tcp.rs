@@ -0,0 +1,207 @@
+//! Raw TCP passthrough, for backends which do not speak HTTP (e.g. databases, game
+//! servers, or SSH).
+//!
+//! The proxy listens on every port in the drone's TCP port range. The agent assigns each
+//! of a backend's TCP ports one of them, and the proxy passes connections to that port
+//! through to the backend. Open connections count as activity on the backend, and are
+//! waited for when it drains. Connections beyond the backend's connection limit are
+//! closed as soon as they are accepted. If backends are reached over mutual TLS, the
+//! proxy connects to them over TLS, and passes the decrypted stream to the client.
+//! Behind a load balancer, the client's address can be read from a PROXY header, and
+//! passed on to the backend in another. Connections idle for the streaming idle timeout
+//! are closed.
+
+use super::{
+    connection_tracker::ConnectionTracker,
+    proxy_protocol::{encode_v2, read_client_addr, ProxyProtocol},
+    timeouts::{copy_bidirectional_with_idle_timeout, UpstreamTimeouts},
+    upstream::UpstreamConnector,
+};
+use crate::{database::DroneDatabase, types::BackendId};
+use anyhow::{anyhow, Result};
+use futures::future::try_join_all;
+use std::{io::ErrorKind, net::SocketAddr, ops::RangeInclusive, str::FromStr};
+use tokio::net::{TcpListener, TcpStream};
+
+/// An inclusive range of ports on the drone, e.g. `30000-30999`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn ports(&self) -> RangeInclusive<u16> {
+        self.start..=self.end
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow!("Expected port range to be <start>-<end>, got {:?}.", s))?;
+        let range = PortRange {
+            start: start.parse()?,
+            end: end.parse()?,
+        };
+        if range.start > range.end {
+            return Err(anyhow!(
+                "Expected port range to start before it ends, got {:?}.",
+                s
+            ));
+        }
+
+        Ok(range)
+    }
+}
+
+/// What the proxy needs to pass connections through to backends.
+#[derive(Clone)]
+struct Passthrough {
+    db: DroneDatabase,
+    connection_tracker: ConnectionTracker,
+    upstream: UpstreamConnector,
+    proxy_protocol: ProxyProtocol,
+    timeouts: UpstreamTimeouts,
+}
+
+impl Passthrough {
+    /// Pass a connection to a port in the range through to the backend routed from it,
+    /// until either side closes or it has been idle for the streaming idle timeout.
+    async fn handle_connection(
+        &self,
+        mut client: TcpStream,
+        peer: SocketAddr,
+        port: u16,
+    ) -> Result<()> {
+        let client_addr = if self.proxy_protocol.accept {
+            read_client_addr(&mut client, peer).await?
+        } else {
+            peer
+        };
+
+        let (subdomain, route) = match self.db.get_tcp_route(port).await? {
+            Some(route) => route,
+            None => {
+                tracing::info!(port, "Closing TCP connection to port with no route.");
+                return Ok(());
+            }
+        };
+
+        let _limited_connection = match route.max_connections {
+            Some(max_connections) => {
+                let backend = route
+                    .backend_id
+                    .as_ref()
+                    .map_or(subdomain.as_str(), BackendId::id);
+                match self
+                    .connection_tracker
+                    .try_open_limited(backend, max_connections)
+                {
+                    Some(guard) => Some(guard),
+                    None => {
+                        tracing::info!(port, %backend, max_connections, "Closing TCP connection to backend at its connection limit.");
+                        return Ok(());
+                    }
+                }
+            }
+            None => None,
+        };
+        let _connection = self.connection_tracker.open_connection(&subdomain);
+        let proxy_header = if self.proxy_protocol.send {
+            Some(encode_v2(client_addr, client.local_addr()?))
+        } else {
+            None
+        };
+        let mut backend = self
+            .upstream
+            .connect(&route.address, proxy_header.as_deref())
+            .await?;
+        let idle_timeout = self
+            .timeouts
+            .for_backend(route.timeouts.as_ref())
+            .streaming_idle;
+        match copy_bidirectional_with_idle_timeout(&mut client, &mut backend, idle_timeout).await {
+            Ok((from_client, from_backend)) => {
+                tracing::info!(port, %subdomain, %client_addr, from_client, from_backend, "TCP connection closed.");
+            }
+            Err(error) if error.kind() == ErrorKind::TimedOut => {
+                tracing::info!(port, %subdomain, %client_addr, ?idle_timeout, "Closed idle TCP connection.");
+            }
+            Err(error) => return Err(error.into()),
+        }
+
+        Ok(())
+    }
+
+    async fn listen(self, port: u16) -> Result<()> {
+        let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?;
+
+        loop {
+            let (client, peer) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(error) => {
+                    tracing::warn!(?error, port, "Error accepting TCP connection.");
+                    continue;
+                }
+            };
+
+            let passthrough = self.clone();
+            tokio::spawn(async move {
+                if let Err(error) = passthrough.handle_connection(client, peer, port).await {
+                    tracing::warn!(?error, port, "Error passing TCP connection through.");
+                }
+            });
+        }
+    }
+}
+
+/// Listen on every port in the range, and pass connections through to backends.
+pub async fn serve_tcp(
+    ports: PortRange,
+    db: DroneDatabase,
+    connection_tracker: ConnectionTracker,
+    upstream: UpstreamConnector,
+    proxy_protocol: ProxyProtocol,
+    timeouts: UpstreamTimeouts,
+) -> Result<()> {
+    let passthrough = Passthrough {
+        db,
+        connection_tracker,
+        upstream,
+        proxy_protocol,
+        timeouts,
+    };
+    try_join_all(ports.ports().map(|port| passthrough.clone().listen(port))).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_port_range() {
+        assert_eq!(
+            PortRange {
+                start: 30000,
+                end: 30999
+            },
+            "30000-30999".parse().unwrap()
+        );
+        assert_eq!(
+            vec![9000],
+            "9000-9000"
+                .parse::<PortRange>()
+                .unwrap()
+                .ports()
+                .collect::<Vec<_>>()
+        );
+        assert!("30999-30000".parse::<PortRange>().is_err());
+        assert!("30000".parse::<PortRange>().is_err());
+    }
+}
@@ -0,0 +1,236 @@
+//! Timeouts of the proxy's connections and requests to backends.
+//!
+//! Ordinary requests are given a limited time for the backend's response headers, and
+//! their response bodies are cut off if they stall. Streaming traffic (upgraded
+//! connections such as WebSockets, gRPC calls, server-sent event streams, and TCP
+//! passthrough) is expected to go quiet for long periods, and is only closed once it has
+//! been idle for the much longer streaming idle timeout. A backend's spawn request can
+//! override any of these but the connect timeout.
+
+use crate::messages::agent::BackendTimeouts;
+use hyper::{body::HttpBody, Body};
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Instant,
+};
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct UpstreamTimeouts {
+    /// How long establishing a connection to a backend may take, including the TLS
+    /// handshake if backends are reached over mutual TLS.
+    pub connect: Duration,
+
+    /// How long the proxy waits for a backend's response headers.
+    pub read: Duration,
+
+    /// How long a response body, or a pooled connection, may go without data.
+    pub idle: Duration,
+
+    /// How long streaming traffic may go without data.
+    pub streaming_idle: Duration,
+}
+
+impl Default for UpstreamTimeouts {
+    fn default() -> Self {
+        UpstreamTimeouts {
+            connect: Duration::from_secs(10),
+            read: Duration::from_secs(60),
+            idle: Duration::from_secs(60),
+            streaming_idle: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl UpstreamTimeouts {
+    /// The timeouts of requests to a backend, with its spawn request's overrides.
+    pub fn for_backend(&self, overrides: Option<&BackendTimeouts>) -> UpstreamTimeouts {
+        let overrides = match overrides {
+            Some(overrides) => overrides,
+            None => return *self,
+        };
+
+        UpstreamTimeouts {
+            connect: self.connect,
+            read: overrides.read_secs.map_or(self.read, Duration::from_secs),
+            idle: overrides.idle_secs.map_or(self.idle, Duration::from_secs),
+            streaming_idle: overrides
+                .streaming_idle_secs
+                .map_or(self.streaming_idle, Duration::from_secs),
+        }
+    }
+}
+
+/// The body, cut off with an error if it goes without data for the timeout.
+pub fn idle_timeout_body(mut body: Body, timeout: Duration) -> Body {
+    let (mut sender, timed_body) = Body::channel();
+
+    tokio::spawn(async move {
+        loop {
+            match tokio::time::timeout(timeout, body.data()).await {
+                Ok(Some(Ok(chunk))) => {
+                    if sender.send_data(chunk).await.is_err() {
+                        // The client has gone away.
+                        break;
+                    }
+                }
+                Ok(Some(Err(error))) => {
+                    tracing::warn!(?error, "Error reading response body.");
+                    sender.abort();
+                    break;
+                }
+                Ok(None) => {
+                    if let Ok(Some(trailers)) = body.trailers().await {
+                        let _ = sender.send_trailers(trailers).await;
+                    }
+                    break;
+                }
+                Err(_) => {
+                    tracing::info!(?timeout, "Cutting off idle response body.");
+                    sender.abort();
+                    break;
+                }
+            }
+        }
+    });
+
+    timed_body
+}
+
+/// A stream which records when data last passed through it, in either direction.
+struct ActivityStream<'a, S> {
+    inner: &'a mut S,
+    last_active: Arc<Mutex<Instant>>,
+}
+
+impl<'a, S> ActivityStream<'a, S> {
+    fn touch(&self) {
+        *self
+            .last_active
+            .lock()
+            .expect("Activity lock was poisoned.") = Instant::now();
+    }
+}
+
+impl<'a, S: AsyncRead + Unpin> AsyncRead for ActivityStream<'a, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut *this.inner).poll_read(cx, buf);
+        if result.is_ready() {
+            this.touch();
+        }
+        result
+    }
+}
+
+impl<'a, S: AsyncWrite + Unpin> AsyncWrite for ActivityStream<'a, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut *this.inner).poll_write(cx, buf);
+        if result.is_ready() {
+            this.touch();
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Like `tokio::io::copy_bidirectional`, but fails with `TimedOut` once no data has
+/// passed in either direction for the timeout.
+pub async fn copy_bidirectional_with_idle_timeout<A, B>(
+    a: &mut A,
+    b: &mut B,
+    timeout: Duration,
+) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let last_active = Arc::new(Mutex::new(Instant::now()));
+    // Everything passing between the two passes through `a`.
+    let mut a = ActivityStream {
+        inner: a,
+        last_active: last_active.clone(),
+    };
+
+    let idle = async {
+        loop {
+            let deadline = *last_active.lock().expect("Activity lock was poisoned.") + timeout;
+            if Instant::now() >= deadline {
+                return io::Error::new(io::ErrorKind::TimedOut, "Connection was idle.");
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+    };
+
+    tokio::select! {
+        result = tokio::io::copy_bidirectional(&mut a, b) => result,
+        error = idle => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn test_backend_timeouts() {
+        let timeouts = UpstreamTimeouts::default();
+        assert_eq!(timeouts, timeouts.for_backend(None));
+
+        let overridden = timeouts.for_backend(Some(&BackendTimeouts {
+            read_secs: Some(300),
+            idle_secs: None,
+            streaming_idle_secs: Some(30),
+        }));
+        assert_eq!(Duration::from_secs(300), overridden.read);
+        assert_eq!(timeouts.idle, overridden.idle);
+        assert_eq!(Duration::from_secs(30), overridden.streaming_idle);
+    }
+
+    #[tokio::test]
+    async fn test_idle_connection_closed() {
+        let (mut client, mut proxy_client) = tokio::io::duplex(64);
+        let (mut proxy_backend, _backend) = tokio::io::duplex(64);
+
+        let copy = tokio::spawn(async move {
+            copy_bidirectional_with_idle_timeout(
+                &mut proxy_client,
+                &mut proxy_backend,
+                Duration::from_millis(300),
+            )
+            .await
+        });
+
+        // Activity pushes the deadline back.
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        client.write_all(b"ping").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!copy.is_finished());
+
+        let error = copy.await.unwrap().unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, error.kind());
+    }
+}
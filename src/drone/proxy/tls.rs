@@ -1,4 +1,5 @@
 //use anyhow::Result;
+use super::service::RemoteAddr;
 use core::future::Future;
 use core::task::Context;
 use futures::ready;
@@ -6,25 +7,30 @@ use hyper::server::accept::Accept;
 use hyper::server::conn::{AddrIncoming, AddrStream};
 use rustls::ServerConfig;
 use std::io;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::Poll;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 // From: https://github.com/rustls/hyper-rustls/blob/main/examples/server.rs
-pub struct TlsAcceptor {
+pub struct TlsAcceptor<I = AddrIncoming> {
     config: Arc<ServerConfig>,
-    incoming: AddrIncoming,
+    incoming: I,
 }
 
-impl TlsAcceptor {
-    pub fn new(config: Arc<ServerConfig>, incoming: AddrIncoming) -> TlsAcceptor {
+impl<I> TlsAcceptor<I> {
+    pub fn new(config: Arc<ServerConfig>, incoming: I) -> TlsAcceptor<I> {
         TlsAcceptor { config, incoming }
     }
 }
 
-impl Accept for TlsAcceptor {
-    type Conn = TlsStream;
+impl<I, C> Accept for TlsAcceptor<I>
+where
+    I: Accept<Conn = C, Error = io::Error> + Unpin,
+    C: AsyncRead + AsyncWrite + RemoteAddr + Unpin,
+{
+    type Conn = TlsStream<C>;
     type Error = io::Error;
 
     fn poll_accept(
@@ -40,7 +46,7 @@ impl Accept for TlsAcceptor {
     }
 }
 
-impl AsyncRead for TlsStream {
+impl<C: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsStream<C> {
     fn poll_read(
         self: Pin<&mut Self>,
         cx: &mut Context,
@@ -61,7 +67,7 @@ impl AsyncRead for TlsStream {
     }
 }
 
-impl AsyncWrite for TlsStream {
+impl<C: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsStream<C> {
     fn poll_write(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -96,23 +102,32 @@ impl AsyncWrite for TlsStream {
     }
 }
 
-enum State {
-    Handshaking(tokio_rustls::Accept<AddrStream>),
-    Streaming(tokio_rustls::server::TlsStream<AddrStream>),
+enum State<C> {
+    Handshaking(tokio_rustls::Accept<C>),
+    Streaming(tokio_rustls::server::TlsStream<C>),
 }
 
 // tokio_rustls::server::TlsStream doesn't expose constructor methods,
 // so we have to TlsAcceptor::accept and handshake to have access to it
 // TlsStream implements AsyncRead/AsyncWrite handshaking tokio_rustls::Accept first
-pub struct TlsStream {
-    state: State,
+pub struct TlsStream<C = AddrStream> {
+    state: State<C>,
+    remote_addr: SocketAddr,
 }
 
-impl TlsStream {
-    fn new(stream: AddrStream, config: Arc<ServerConfig>) -> TlsStream {
+impl<C: AsyncRead + AsyncWrite + RemoteAddr + Unpin> TlsStream<C> {
+    fn new(stream: C, config: Arc<ServerConfig>) -> TlsStream<C> {
+        let remote_addr = stream.remote_addr();
         let accept = tokio_rustls::TlsAcceptor::from(config).accept(stream);
         TlsStream {
             state: State::Handshaking(accept),
+            remote_addr,
         }
     }
 }
+
+impl<C> RemoteAddr for TlsStream<C> {
+    fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+}
@@ -0,0 +1,183 @@
+//! Propagation of W3C trace context (https://www.w3.org/TR/trace-context/), so that
+//! distributed traces connect a user's request to the telemetry of the backend which
+//! handles it.
+//!
+//! The proxy acts as a span of the trace: a request with a valid `traceparent` header is
+//! forwarded with the proxy's span as the parent, and a request without one begins a new
+//! trace. `tracestate` is passed through as is, unless the `traceparent` it accompanied
+//! was invalid, in which case it is dropped, as the specification requires.
+
+use hyper::HeaderMap;
+use std::fmt::Write;
+
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+
+/// The only version the proxy sends. Later versions are parsed as far as version 00
+/// goes, as the specification requires.
+const VERSION: &str = "00";
+
+/// Set on traces the proxy begins, so that backends record them.
+const SAMPLED: u8 = 0x01;
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    openssl::rand::rand_bytes(&mut bytes).expect("Random number generation should never fail.");
+    bytes
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+fn is_lower_hex(s: &str, length: usize) -> bool {
+    s.len() == length && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// The proxy's span of the trace a request belongs to.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct TraceContext {
+    pub trace_id: String,
+
+    /// The proxy's own span, which is the parent of the backend's.
+    pub span_id: String,
+
+    /// The span of whoever sent the request to the proxy, if it carried trace context.
+    pub parent_span_id: Option<String>,
+    pub flags: u8,
+}
+
+impl TraceContext {
+    /// The trace context of a `traceparent` header, if it is valid.
+    fn parse(traceparent: &str) -> Option<TraceContext> {
+        let mut fields = traceparent.trim().split('-');
+        let version = fields.next()?;
+        let trace_id = fields.next()?;
+        let parent_id = fields.next()?;
+        let flags = fields.next()?;
+
+        if !is_lower_hex(version, 2) || version == "ff" {
+            return None;
+        }
+        // Version 00 has exactly four fields; later versions may add more.
+        if version == VERSION && fields.next().is_some() {
+            return None;
+        }
+        if !is_lower_hex(trace_id, 32) || trace_id.bytes().all(|b| b == b'0') {
+            return None;
+        }
+        if !is_lower_hex(parent_id, 16) || parent_id.bytes().all(|b| b == b'0') {
+            return None;
+        }
+        if !is_lower_hex(flags, 2) {
+            return None;
+        }
+
+        Some(TraceContext {
+            trace_id: trace_id.to_string(),
+            span_id: to_hex(&random_bytes::<8>()),
+            parent_span_id: Some(parent_id.to_string()),
+            flags: u8::from_str_radix(flags, 16).ok()?,
+        })
+    }
+
+    fn new_trace() -> TraceContext {
+        TraceContext {
+            trace_id: to_hex(&random_bytes::<16>()),
+            span_id: to_hex(&random_bytes::<8>()),
+            parent_span_id: None,
+            flags: SAMPLED,
+        }
+    }
+
+    /// The `traceparent` header sent to the backend.
+    pub fn traceparent(&self) -> String {
+        format!(
+            "{}-{}-{}-{:02x}",
+            VERSION, self.trace_id, self.span_id, self.flags
+        )
+    }
+
+    /// Continue the trace of a request, or begin a new one, replacing its `traceparent`
+    /// header with the proxy's span.
+    pub fn propagate(headers: &mut HeaderMap) -> TraceContext {
+        let received = headers
+            .get(TRACEPARENT)
+            .and_then(|traceparent| traceparent.to_str().ok())
+            .and_then(TraceContext::parse);
+        let context = match received {
+            Some(context) => context,
+            None => {
+                headers.remove(TRACESTATE);
+                TraceContext::new_trace()
+            }
+        };
+
+        headers.insert(
+            TRACEPARENT,
+            context
+                .traceparent()
+                .parse()
+                .expect("traceparent is always a valid header value."),
+        );
+        context
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_continue_trace() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            TRACEPARENT,
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+                .parse()
+                .unwrap(),
+        );
+        headers.insert(TRACESTATE, "vendor=value".parse().unwrap());
+
+        let context = TraceContext::propagate(&mut headers);
+        assert_eq!("0af7651916cd43dd8448eb211c80319c", context.trace_id);
+        assert_eq!(Some("b7ad6b7169203331"), context.parent_span_id.as_deref());
+        assert_ne!("b7ad6b7169203331", context.span_id);
+        assert_eq!(
+            format!("00-0af7651916cd43dd8448eb211c80319c-{}-01", context.span_id),
+            headers[TRACEPARENT]
+        );
+        assert_eq!("vendor=value", headers[TRACESTATE]);
+    }
+
+    #[test]
+    fn test_begin_trace() {
+        for invalid in [
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01",
+            "00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01",
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+            "garbage",
+        ] {
+            let mut headers = HeaderMap::new();
+            headers.insert(TRACEPARENT, invalid.parse().unwrap());
+            headers.insert(TRACESTATE, "vendor=value".parse().unwrap());
+
+            let context = TraceContext::propagate(&mut headers);
+            assert_eq!(None, context.parent_span_id, "{}", invalid);
+            assert_eq!(context.traceparent(), headers[TRACEPARENT]);
+            assert!(headers.get(TRACESTATE).is_none());
+        }
+
+        // Later versions are read as version 00.
+        assert!(TraceContext::parse(
+            "01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra"
+        )
+        .is_some());
+    }
+}
@@ -0,0 +1,293 @@
+//! UDP relay, for realtime backends (e.g. WebRTC or game servers) which clients reach
+//! through the proxy rather than on a port the backend's container is published on.
+//!
+//! The proxy listens on every port in the drone's UDP port range. Each client sending
+//! to a port gets a session with its own socket to the backend routed from the port,
+//! so that the backend's replies can be relayed back to the right client. Every datagram
+//! relayed counts as activity on the backend. Sessions close once the port no longer
+//! routes to their backend, and each port relays for a limited number of clients at once.
+
+use super::{connection_tracker::ConnectionTracker, tcp::PortRange};
+use crate::database::DroneDatabase;
+use anyhow::Result;
+use dashmap::DashMap;
+use futures::future::try_join_all;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{net::UdpSocket, time::Instant};
+
+/// How long a client's session lasts without a datagram in either direction.
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Most clients a port relays for at once. Datagrams from further clients are dropped
+/// until a session closes.
+const MAX_SESSIONS_PER_PORT: usize = 1024;
+
+/// How often a session checks that the port still routes to its backend.
+const ROUTE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Large enough for any UDP datagram.
+const MAX_DATAGRAM_SIZE: usize = 65_536;
+
+struct Session {
+    /// Socket connected to the backend, used only for this client.
+    socket: Arc<UdpSocket>,
+    route_name: String,
+    address: String,
+    last_active: Mutex<Instant>,
+}
+
+impl Session {
+    fn touch(&self) {
+        *self.last_active.lock().expect("Session lock was poisoned.") = Instant::now();
+    }
+
+    fn idle_until(&self) -> Instant {
+        *self.last_active.lock().expect("Session lock was poisoned.") + SESSION_IDLE_TIMEOUT
+    }
+}
+
+/// Relays datagrams between clients of one port and the backend routed from it.
+#[derive(Clone)]
+struct Relay {
+    port: u16,
+    listener: Arc<UdpSocket>,
+    db: DroneDatabase,
+    connection_tracker: ConnectionTracker,
+    sessions: Arc<DashMap<SocketAddr, Arc<Session>>>,
+    max_sessions: usize,
+    route_check_interval: Duration,
+}
+
+impl Relay {
+    /// The client's session, opening one to the backend routed from the port if the
+    /// client has none. Returns None if no backend is routed from the port, or if the
+    /// port already has as many sessions as it allows.
+    async fn session(&self, client: SocketAddr) -> Result<Option<Arc<Session>>> {
+        if let Some(session) = self.sessions.get(&client) {
+            return Ok(Some(session.clone()));
+        }
+        if self.sessions.len() >= self.max_sessions {
+            tracing::debug!(port = self.port, %client, "Too many UDP sessions, dropping datagram.");
+            return Ok(None);
+        }
+
+        let (route_name, address) = match self.db.get_udp_route(self.port).await? {
+            Some(route) => route,
+            None => return Ok(None),
+        };
+        let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).await?;
+        socket.connect(&address).await?;
+        let session = Arc::new(Session {
+            socket: Arc::new(socket),
+            route_name,
+            address,
+            last_active: Mutex::new(Instant::now()),
+        });
+        self.sessions.insert(client, session.clone());
+        tracing::info!(port = self.port, %client, address = %session.address, "Opened UDP session.");
+
+        tokio::spawn(self.clone().relay_replies(client, session.clone()));
+
+        Ok(Some(session))
+    }
+
+    /// Whether the port still routes to the session's backend. Errors count as the route
+    /// being unchanged, so that a database hiccup does not cut off clients.
+    async fn route_unchanged(&self, session: &Session) -> bool {
+        match self.db.get_udp_route(self.port).await {
+            Ok(route) => route.is_some_and(|(route_name, address)| {
+                route_name == session.route_name && address == session.address
+            }),
+            Err(error) => {
+                tracing::warn!(?error, port = self.port, "Error checking UDP route.");
+                true
+            }
+        }
+    }
+
+    /// Relay the backend's replies to the client until the session goes idle or the
+    /// port no longer routes to its backend.
+    async fn relay_replies(self, client: SocketAddr, session: Arc<Session>) {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let mut next_route_check = Instant::now() + self.route_check_interval;
+
+        loop {
+            let idle_until = session.idle_until();
+            if Instant::now() >= idle_until {
+                tracing::info!(%client, "Closed idle UDP session.");
+                break;
+            }
+            if Instant::now() >= next_route_check {
+                if !self.route_unchanged(&session).await {
+                    tracing::info!(%client, "Closed UDP session of released route.");
+                    break;
+                }
+                next_route_check = Instant::now() + self.route_check_interval;
+            }
+
+            let len = tokio::select! {
+                result = session.socket.recv(&mut buf) => match result {
+                    Ok(len) => len,
+                    Err(error) => {
+                        // e.g. the backend's port is closed; the client may try again later.
+                        tracing::info!(?error, %client, "Error receiving UDP datagram from backend.");
+                        continue;
+                    }
+                },
+                _ = tokio::time::sleep_until(idle_until.min(next_route_check)) => continue,
+            };
+
+            session.touch();
+            self.connection_tracker.track_request(&session.route_name);
+            if let Err(error) = self.listener.send_to(&buf[..len], client).await {
+                tracing::warn!(?error, %client, "Error relaying UDP datagram to client.");
+            }
+        }
+
+        self.sessions.remove(&client);
+    }
+
+    async fn run(self) -> Result<()> {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+
+        loop {
+            let (len, client) = match self.listener.recv_from(&mut buf).await {
+                Ok(datagram) => datagram,
+                Err(error) => {
+                    tracing::warn!(?error, port = self.port, "Error receiving UDP datagram.");
+                    continue;
+                }
+            };
+
+            let session = match self.session(client).await {
+                Ok(Some(session)) => session,
+                Ok(None) => continue,
+                Err(error) => {
+                    tracing::warn!(?error, port = self.port, "Error opening UDP session.");
+                    continue;
+                }
+            };
+            session.touch();
+            self.connection_tracker.track_request(&session.route_name);
+            if let Err(error) = session.socket.send(&buf[..len]).await {
+                tracing::warn!(?error, port = self.port, "Error relaying UDP datagram.");
+            }
+        }
+    }
+}
+
+/// Listen on every port in the range, and relay datagrams to backends.
+pub async fn serve_udp(
+    ports: PortRange,
+    db: DroneDatabase,
+    connection_tracker: ConnectionTracker,
+) -> Result<()> {
+    let mut relays = Vec::new();
+    for port in ports.ports() {
+        let listener = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?;
+        relays.push(
+            Relay {
+                port,
+                listener: Arc::new(listener),
+                db: db.clone(),
+                connection_tracker: connection_tracker.clone(),
+                sessions: Arc::default(),
+                max_sessions: MAX_SESSIONS_PER_PORT,
+                route_check_interval: ROUTE_CHECK_INTERVAL,
+            }
+            .run(),
+        );
+    }
+    try_join_all(relays).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{database_connection::DatabaseConnection, messages::agent::SpawnRequest};
+    use tokio::time::timeout;
+
+    const WAIT: Duration = Duration::from_millis(300);
+
+    #[tokio::test]
+    async fn test_relay() {
+        let db_dir = std::env::temp_dir().join(format!("spawner-udp-{}", std::process::id()));
+        std::fs::create_dir_all(&db_dir).unwrap();
+        let db = DatabaseConnection::new(db_dir.join("drone.db").to_string_lossy().to_string())
+            .connection()
+            .await
+            .unwrap();
+
+        let backend = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let spawn_request: SpawnRequest = serde_json::from_value(serde_json::json!({
+            "image": "image",
+            "backend_id": "backend",
+            "max_idle_secs": 60,
+            "env": {},
+            "metadata": {},
+        }))
+        .unwrap();
+        let backend_id = spawn_request.backend_id.clone();
+        db.insert_backend(&spawn_request).await.unwrap();
+        db.insert_udp_route(
+            &backend_id,
+            "backend-game",
+            &backend.local_addr().unwrap().to_string(),
+            port..=port,
+        )
+        .await
+        .unwrap();
+
+        let relay = Relay {
+            port,
+            listener: Arc::new(listener),
+            db: db.clone(),
+            connection_tracker: ConnectionTracker::default(),
+            sessions: Arc::default(),
+            max_sessions: 1,
+            route_check_interval: Duration::from_millis(50),
+        };
+        let sessions = relay.sessions.clone();
+        tokio::spawn(relay.run());
+
+        let mut buf = [0; 16];
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(("127.0.0.1", port)).await.unwrap();
+        client.send(b"ping").await.unwrap();
+        let (len, session_address) = timeout(WAIT, backend.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"ping", &buf[..len]);
+        backend.send_to(b"pong", session_address).await.unwrap();
+        let len = timeout(WAIT, client.recv(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(b"pong", &buf[..len]);
+
+        // The port already relays for as many clients as it allows.
+        let other_client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        other_client
+            .send_to(b"ping", ("127.0.0.1", port))
+            .await
+            .unwrap();
+        assert!(timeout(WAIT, backend.recv_from(&mut buf)).await.is_err());
+        assert_eq!(1, sessions.len());
+
+        // Once the route is released, the session closes rather than relaying to the
+        // address it was opened with.
+        db.release_port_routes(&backend_id).await.unwrap();
+        tokio::time::sleep(WAIT).await;
+        assert!(sessions.is_empty());
+        client.send(b"ping").await.unwrap();
+        assert!(timeout(WAIT, backend.recv_from(&mut buf)).await.is_err());
+
+        let _ = std::fs::remove_dir_all(db_dir);
+    }
+}
@@ -0,0 +1,268 @@
+//! Connections from the proxy to backends, in plaintext or, if the drone has a backend
+//! CA, over mutual TLS. With a backend CA, the proxy never falls back to plaintext: a
+//! backend which doesn't serve TLS with a certificate from the CA can't be reached.
+
+use crate::drone::backend_tls::{BackendCa, BACKEND_SERVER_NAME};
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use http::Uri;
+use hyper::{
+    client::connect::{Connected, Connection},
+    service::Service,
+};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName};
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+/// The CA the proxy trusts backends' certificates from, and the client certificate it
+/// presents to them.
+pub struct UpstreamTls {
+    ca_certificate: Certificate,
+    client_certificate: Certificate,
+    client_private_key: PrivateKey,
+}
+
+impl UpstreamTls {
+    /// Issue the proxy a client certificate from the backend CA.
+    pub fn new(ca: &BackendCa) -> Result<Self> {
+        let issued = ca.issue_proxy()?;
+
+        Ok(UpstreamTls {
+            ca_certificate: Certificate(ca.certificate().to_der()?),
+            client_certificate: Certificate(issued.certificate.to_der()?),
+            client_private_key: PrivateKey(issued.private_key_pkcs8_der()?),
+        })
+    }
+
+    /// A connector which offers the given ALPN protocols, e.g. `h2` for gRPC.
+    pub fn connector(&self, alpn_protocols: &[&[u8]]) -> Result<UpstreamConnector> {
+        let mut roots = RootCertStore::empty();
+        roots.add(&self.ca_certificate)?;
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_single_cert(
+                vec![self.client_certificate.clone()],
+                self.client_private_key.clone(),
+            )?;
+        config.alpn_protocols = alpn_protocols
+            .iter()
+            .map(|protocol| protocol.to_vec())
+            .collect();
+
+        Ok(UpstreamConnector {
+            tls: Some(TlsConnector::from(Arc::new(config))),
+            connect_timeout: None,
+        })
+    }
+}
+
+/// Connects the proxy to backends, for both the HTTP clients and TCP passthrough.
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    tls: Option<TlsConnector>,
+
+    /// How long establishing a connection may take, including the TLS handshake.
+    connect_timeout: Option<Duration>,
+}
+
+impl UpstreamConnector {
+    pub fn plain() -> Self {
+        UpstreamConnector {
+            tls: None,
+            connect_timeout: None,
+        }
+    }
+
+    pub fn with_connect_timeout(self, connect_timeout: Duration) -> Self {
+        UpstreamConnector {
+            connect_timeout: Some(connect_timeout),
+            ..self
+        }
+    }
+
+    async fn connect_without_timeout(
+        &self,
+        address: &str,
+        proxy_header: Option<&[u8]>,
+    ) -> Result<UpstreamStream> {
+        let mut stream = TcpStream::connect(address).await?;
+        if let Some(proxy_header) = proxy_header {
+            stream.write_all(proxy_header).await?;
+        }
+        match &self.tls {
+            None => Ok(UpstreamStream::Plain(stream)),
+            Some(connector) => {
+                let server_name = ServerName::try_from(BACKEND_SERVER_NAME)?;
+                let stream = connector.connect(server_name, stream).await?;
+                Ok(UpstreamStream::Tls(Box::new(stream)))
+            }
+        }
+    }
+
+    /// Open a connection to the backend at the address, first sending it the PROXY
+    /// header if one is given (outside of TLS, as backends expect).
+    pub async fn connect(
+        &self,
+        address: &str,
+        proxy_header: Option<&[u8]>,
+    ) -> Result<UpstreamStream> {
+        let connecting = self.connect_without_timeout(address, proxy_header);
+        match self.connect_timeout {
+            Some(connect_timeout) => tokio::time::timeout(connect_timeout, connecting)
+                .await
+                .map_err(|_| anyhow!("Timed out connecting to backend at {}.", address))?,
+            None => connecting.await,
+        }
+    }
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = UpstreamStream;
+    type Error = anyhow::Error;
+    type Future = BoxFuture<'static, Result<UpstreamStream>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connector = self.clone();
+        Box::pin(async move {
+            let authority = uri
+                .authority()
+                .ok_or_else(|| anyhow!("Expected upstream URI to have an authority."))?;
+            connector.connect(authority.as_str(), None).await
+        })
+    }
+}
+
+/// A connection to a backend.
+pub enum UpstreamStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        match self {
+            UpstreamStream::Plain(stream) => stream.connected(),
+            UpstreamStream::Tls(stream) => {
+                let connected = stream.get_ref().0.connected();
+                if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+                    connected.negotiated_h2()
+                } else {
+                    connected
+                }
+            }
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{drone::backend_tls::test::test_ca, types::BackendId};
+    use rustls::{server::AllowAnyAuthenticatedClient, ServerConfig};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tokio_rustls::TlsAcceptor;
+
+    #[tokio::test]
+    async fn test_mutual_tls_connection() {
+        let ca = test_ca();
+        let issued = ca
+            .issue_backend(&BackendId::new("mybackend".to_string()))
+            .unwrap();
+        let mut client_roots = RootCertStore::empty();
+        client_roots
+            .add(&Certificate(ca.certificate().to_der().unwrap()))
+            .unwrap();
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(client_roots))
+            .with_single_cert(
+                vec![Certificate(issued.certificate.to_der().unwrap())],
+                PrivateKey(issued.private_key_pkcs8_der().unwrap()),
+            )
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let connector = UpstreamTls::new(&ca).unwrap().connector(&[]).unwrap();
+        let mut stream = connector.connect(&address, None).await.unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).await.unwrap();
+        assert_eq!("hello", received);
+        server.await.unwrap();
+
+        // A backend serving plaintext is refused rather than spoken to in the clear.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await;
+        });
+        assert!(connector.connect(&address, None).await.is_err());
+    }
+}
@@ -51,6 +51,27 @@ impl KeyCertPathPair {
             ]
         }
     }
+
+    /// The paths of another certificate for the same names, e.g. one with another type of
+    /// key, distinguished by the name appended to each file's stem: `cert-rsa.pem` beside
+    /// `cert.pem`.
+    pub fn alternate(&self, name: &str) -> KeyCertPathPair {
+        let suffixed = |path: &Path| {
+            let mut file_name = path.file_stem().unwrap_or_default().to_os_string();
+            file_name.push("-");
+            file_name.push(name);
+            if let Some(extension) = path.extension() {
+                file_name.push(".");
+                file_name.push(extension);
+            }
+            path.with_file_name(file_name)
+        };
+
+        KeyCertPathPair {
+            private_key_path: suffixed(&self.private_key_path),
+            certificate_path: suffixed(&self.certificate_path),
+        }
+    }
 }
 
 // Load public certificate from file.
@@ -65,6 +86,12 @@ pub fn load_certs(filename: &Path) -> Result<Vec<Certificate>> {
     Ok(certs.into_iter().map(rustls::Certificate).collect())
 }
 
+/// Parse the certificates of a PEM in memory, e.g. one received over NATS.
+pub fn parse_certs(pem: &[u8]) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut &*pem)?;
+    Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
 // Load private key from file.
 // Source: https://github.com/rustls/hyper-rustls/blob/main/examples/server.rs
 pub fn load_private_key(filename: &Path) -> Result<PrivateKey> {
@@ -80,3 +107,13 @@ pub fn load_private_key(filename: &Path) -> Result<PrivateKey> {
 
     Ok(rustls::PrivateKey(keys[0].clone()))
 }
+
+/// Parse the single private key of a PEM in memory.
+pub fn parse_private_key(pem: &[u8]) -> Result<PrivateKey> {
+    let keys = rustls_pemfile::pkcs8_private_keys(&mut &*pem)?;
+    if keys.len() != 1 {
+        return Err(anyhow!("expected a single private key"));
+    }
+
+    Ok(rustls::PrivateKey(keys[0].clone()))
+}
@@ -11,4 +11,5 @@ pub mod messages;
 pub mod nats;
 pub mod nats_connection;
 mod retry;
+pub mod scheduler;
 pub mod types;
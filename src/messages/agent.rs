@@ -6,8 +6,8 @@ use bollard::{auth::DockerCredentials, container::LogOutput};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
-use serde_with::DurationSeconds;
-use std::{collections::HashMap, net::IpAddr, str::FromStr, time::Duration};
+use serde_with::{DurationMicroSeconds, DurationMilliSeconds, DurationSeconds};
+use std::{collections::HashMap, fmt::Display, net::IpAddr, str::FromStr, time::Duration};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum DroneLogMessageKind {
@@ -52,11 +52,64 @@ impl DroneLogMessage {
     }
 }
 
+/// Whether a drone is accepting new backends.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DroneState {
+    /// The drone accepts spawn requests.
+    #[default]
+    Ready,
+
+    /// The drone refuses spawn requests, but its existing backends keep running.
+    Draining,
+
+    /// The drone is draining and none of its backends are running, so it can be
+    /// shut down without interrupting anyone.
+    Drained,
+}
+
+/// A heartbeat which drones publish every few seconds. A drone which has not
+/// published one recently should be considered unavailable.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DroneStatusMessage {
     pub drone_id: DroneId,
     pub cluster: String,
+
+    /// The number of additional backends the drone will accept, or 100 if it
+    /// has no limit. Zero unless the drone is ready.
     pub capacity: u32,
+
+    #[serde(default)]
+    pub state: DroneState,
+
+    /// The number of backends on the drone which have not terminated.
+    #[serde(default)]
+    pub running_backends: u32,
+
+    /// The most backends the drone runs at once, if it is limited.
+    #[serde(default)]
+    pub max_backends: Option<u32>,
+
+    /// Percentage of the host's CPU time which was idle since the previous
+    /// heartbeat, if it could be read.
+    #[serde(default)]
+    pub cpu_idle_percent: Option<f64>,
+
+    /// Memory available to new processes on the host, if it could be read.
+    #[serde(default)]
+    pub memory_available_bytes: Option<u64>,
+
+    /// Total memory of the host, if it could be read.
+    #[serde(default)]
+    pub memory_total_bytes: Option<u64>,
+
+    /// Version of the agent.
+    #[serde(default)]
+    pub version: String,
+
+    /// Labels the drone was started with, e.g. its region or hardware class, which
+    /// spawn requests' `drone_selector`s are matched against.
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
 }
 
 impl DroneStatusMessage {
@@ -69,14 +122,38 @@ impl DroneStatusMessage {
     }
 }
 
-/// A request from a drone to connect to the platform.
+/// Stop (or with `drain: false`, resume) accepting spawn requests on a drone.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DrainRequest {
+    pub drain: bool,
+}
+
+impl DrainRequest {
+    #[must_use] pub fn subject(drone_id: DroneId) -> Subject<DrainRequest, bool> {
+        Subject::new(format!("drone.{}.drain", drone_id.id()))
+    }
+}
+
+/// A request from a drone to connect to the platform.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct DroneConnectRequest {
     /// The cluster the drone is requesting to join.
     pub cluster: String,
 
     /// The public-facing IP address of the drone.
     pub ip: IpAddr,
+
+    /// Labels the drone was started with, e.g. its region or hardware class.
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+
+    /// CPU architecture of the drone's host, e.g. `x86_64` or `aarch64`.
+    #[serde(default)]
+    pub architecture: String,
+
+    /// The engine the drone runs backends with, e.g. `docker` or `firecracker`.
+    #[serde(default)]
+    pub engine: String,
 }
 
 /// A response from the platform to a drone's request to join.
@@ -106,24 +183,544 @@ pub struct SpawnRequest {
     /// connect to the drone.
     pub backend_id: BackendId,
 
-    /// The timeout after which the drone is shut down if no connections are made.
+    /// The timeout after which the backend is shut down if the proxy sees no
+    /// requests or open connections to it. Zero uses the drone's default.
     #[serde_as(as = "DurationSeconds")]
+    #[serde(default)]
     pub max_idle_secs: Duration,
 
+    /// How long the backend has to become ready, including pulling its image,
+    /// starting its container and passing its readiness probe. If it is not
+    /// ready in time, it is stopped and moves to `TimedOutBeforeReady`.
+    #[serde_as(as = "Option<DurationSeconds>")]
+    #[serde(default)]
+    pub startup_timeout_secs: Option<Duration>,
+
+    /// When the backend is terminated, regardless of activity. It moves to
+    /// `Expired` at this time, unless its lease is renewed first.
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+
+    /// Token which `RenewLeaseRequest`s for the backend must carry. If not
+    /// provided, the backend's lease cannot be renewed.
+    #[serde(default)]
+    pub lease_token: Option<String>,
+
+    /// Priority class of the backend. When the drone runs low on memory or
+    /// disk and evicts by priority, backends with lower values are evicted
+    /// first.
+    #[serde(default)]
+    pub priority: i32,
+
+    /// Identifies the session the backend serves. If the drone is already
+    /// running a backend with the same key, it responds with that backend
+    /// instead of starting a new one. Keys are only unique within a drone, so
+    /// requests with the same key should be sent to the same drone.
+    #[serde(default)]
+    pub key: Option<String>,
+
+    /// If provided, the proxy only forwards requests to the backend which
+    /// carry this token, either in an `Authorization: Bearer` header or in a
+    /// `spawner_token` cookie. Other requests receive a 401.
+    #[serde(default)]
+    pub bearer_token: Option<String>,
+
+    /// If provided, the proxy holds at most this many long-lived connections (e.g.
+    /// WebSockets, or connections to TCP ports) open to the backend at once, and refuses
+    /// more, e.g. so that a backend serving a single user's session refuses a second
+    /// viewer. Ordinary HTTP requests are not limited.
+    #[serde(default)]
+    pub max_connections: Option<u32>,
+
+    /// Timeouts of the proxy's requests to the backend, in place of the proxy's own, e.g.
+    /// for a backend with slow reports or long-lived quiet connections.
+    #[serde(default)]
+    pub timeouts: Option<BackendTimeouts>,
+
     /// Environment variables to pass in to the container.
     pub env: HashMap<String, String>,
 
     /// Metadata for the spawn. Typically added to log messages for debugging and observability.
     pub metadata: HashMap<String, String>,
 
+    /// Labels to apply to the backend's container, e.g. to identify its
+    /// tenant or session. Echoed in the backend's `BackendStateMessage`s.
+    /// Labels beginning with `dev.spawner.` are reserved for the drone.
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+
+    /// Labels a drone must have, with the same values, for the backend to be
+    /// scheduled on it, e.g. `gpu=true`. Drones without them are passed over.
+    #[serde(default)]
+    pub drone_selector: HashMap<String, String>,
+
+    /// The cluster to run the backend in, e.g. `sessions.eu.example.com`, for
+    /// controllers which schedule several. If not provided, the controller's default
+    /// cluster is used.
+    #[serde(default)]
+    pub cluster: Option<String>,
+
+    /// Whether the scheduler may respawn the backend, with the same ID, on another
+    /// drone if its drone is lost. `Lost` stays terminal: the scheduler reports it, then
+    /// sends the same spawn request to the new drone, which reports the states of a new
+    /// backend from `Loading`.
+    /// Anything the backend kept on the lost drone is lost with it, so only backends
+    /// which keep no state there should set this.
+    #[serde(default)]
+    pub reschedulable: bool,
+
     /// Credentials used to fetch the image.
     pub credentials: Option<DockerCredentials>,
+
+    /// Limits on the CPU and memory available to the container.
+    #[serde(default)]
+    pub resource_limits: ResourceLimits,
+
+    /// Limits on the file descriptors, processes, and core dump size of the
+    /// container's processes. Each overrides the drone's default.
+    #[serde(default)]
+    pub ulimits: Ulimits,
+
+    /// Number of GPUs to dedicate to the backend. Spawning fails if the
+    /// drone does not have enough unassigned GPUs.
+    #[serde(default)]
+    pub gpus: u32,
+
+    /// Volumes and host directories to mount into the container.
+    #[serde(default)]
+    pub volume_mounts: Vec<VolumeMount>,
+
+    /// Whether to mount the container's root filesystem as read-only. Paths
+    /// the backend needs to write to should be provided as `tmpfs_mounts` or
+    /// `volume_mounts`.
+    #[serde(default)]
+    pub read_only_root_filesystem: bool,
+
+    /// Whether to run an init process as the container's PID 1, which
+    /// forwards signals and reaps zombie processes. If not provided, the
+    /// drone's default is used.
+    #[serde(default)]
+    pub init: Option<bool>,
+
+    /// Size of the container's `/dev/shm`, in bytes. Docker defaults to 64MB.
+    /// Spawning fails if this exceeds the drone's maximum.
+    #[serde(default)]
+    pub shm_size_bytes: Option<i64>,
+
+    /// Maximum number of processes and threads in the container. If not provided,
+    /// the drone's default is used. Spawning fails if this exceeds the drone's maximum.
+    #[serde(default)]
+    pub pids_limit: Option<i64>,
+
+    /// In-memory filesystems to mount into the container as scratch space.
+    #[serde(default)]
+    pub tmpfs_mounts: Vec<TmpfsMount>,
+
+    /// Restrictions on the container's syscalls, capabilities, and privileges,
+    /// combined with those configured on the drone.
+    #[serde(default)]
+    pub security_options: SecurityOptions,
+
+    /// User to run the container's processes as, as `UID`, `UID:GID`, or a user
+    /// name known to the image. If not provided, the image's default user is used.
+    #[serde(default)]
+    pub user: Option<String>,
+
+    /// The port within the container that the backend listens on. If not
+    /// provided, the drone's default container port is used.
+    #[serde(default)]
+    pub port: Option<u16>,
+
+    /// Additional container ports to expose, keyed by name. Each is routed from
+    /// its own subdomain, formed by appending `-<name>` to the backend's subdomain.
+    #[serde(default)]
+    pub additional_ports: HashMap<String, u16>,
+
+    /// A customer-owned domain, e.g. `app.customer.com`, to route to the backend in
+    /// addition to its subdomain. Once the backend is ready, the drone checks that the
+    /// domain is a CNAME of the backend's subdomain, then routes it to the backend and
+    /// requests a certificate for it. If the check fails, the backend is still reachable
+    /// by its subdomain.
+    #[serde(default)]
+    pub custom_domain: Option<String>,
+
+    /// UDP container ports to publish, keyed by name. If the drone has a UDP
+    /// port range, each is assigned a port from it which the proxy relays
+    /// datagrams through; otherwise clients send to the host port it is
+    /// published on. Either port is advertised in the backend's
+    /// `BackendInfoMessage`.
+    #[serde(default)]
+    pub udp_ports: HashMap<String, u16>,
+
+    /// Container ports which the proxy passes raw TCP connections through to,
+    /// keyed by name, for backends which do not speak HTTP. Each is assigned a
+    /// port from the drone's TCP port range, advertised in the backend's
+    /// `BackendInfoMessage`.
+    #[serde(default)]
+    pub tcp_ports: HashMap<String, u16>,
+
+    /// The Docker network to attach the container to. If not provided, the
+    /// drone's default network is used.
+    #[serde(default)]
+    pub network: Option<BackendNetwork>,
+
+    /// Whether to pull the image before running it.
+    #[serde(default)]
+    pub pull_policy: PullPolicy,
+
+    /// Whether Docker should restart the container when it exits.
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+
+    /// Signal sent to the container's main process to stop it, e.g. `SIGINT`. If
+    /// not provided, the image's stop signal (usually `SIGTERM`) is used.
+    #[serde(default)]
+    pub stop_signal: Option<String>,
+
+    /// How long the container has to exit after being sent its stop signal
+    /// before it is killed. Defaults to 10 seconds.
+    #[serde_as(as = "Option<DurationSeconds>")]
+    #[serde(default)]
+    pub stop_grace_period_secs: Option<Duration>,
+
+    /// How to check that the backend is ready before routing traffic to it. If
+    /// not provided, any HTTP response on the backend's port counts as ready.
+    #[serde(default)]
+    pub readiness_probe: Option<ReadinessProbe>,
+}
+
+/// What a readiness probe checks.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ReadinessCheck {
+    /// A GET request for the path must return a 2xx or 3xx status.
+    Http { path: String },
+
+    /// A TCP connection to the port must be accepted.
+    Tcp,
+}
+
+/// Checks run against a starting backend's port until one passes.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReadinessProbe {
+    pub check: ReadinessCheck,
+
+    /// How long each attempt may take, in milliseconds. Defaults to one second.
+    #[serde_as(as = "Option<DurationMilliSeconds>")]
+    #[serde(default)]
+    pub timeout: Option<Duration>,
+
+    /// Time between attempts, in milliseconds. Defaults to 100 milliseconds.
+    #[serde_as(as = "Option<DurationMilliSeconds>")]
+    #[serde(default)]
+    pub interval: Option<Duration>,
+
+    /// Attempts to make before the backend fails to start. Defaults to 300.
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
+}
+
+/// Determines whether a backend's container is restarted when it exits.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RestartPolicy {
+    /// The backend terminates when its container exits.
+    #[default]
+    Never,
+
+    /// Restart the container if it exits with a non-zero status, up to an
+    /// optional maximum number of times.
+    OnFailure { max_retries: Option<u32> },
+
+    /// Restart the container whenever it exits, unless it was stopped by the drone.
+    Always,
+}
+
+/// Determines when the drone pulls a backend's image before running it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PullPolicy {
+    /// Pull the image on every spawn, so that mutable tags are kept current.
+    #[default]
+    Always,
+
+    /// Only pull the image if the drone does not already have it.
+    IfNotPresent,
+
+    /// Never pull the image; fail to spawn if the drone does not already have it.
+    Never,
+}
+
+/// The Docker network a backend's container is attached to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub enum BackendNetwork {
+    /// Docker's default bridge network, which is shared by all containers that
+    /// don't specify a network.
+    #[default]
+    Default,
+
+    /// An existing Docker network with the given name.
+    Named(String),
+
+    /// A bridge network created for this backend alone, and removed when the
+    /// backend terminates. Backends on isolated networks can't reach each other.
+    Isolated,
+}
+
+/// The source of a volume mount.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VolumeMountKind {
+    /// A named Docker volume, which is created if it does not already exist.
+    Volume,
+
+    /// A directory on the drone's host, given as an absolute path.
+    Bind,
+}
+
+/// A volume or host directory mounted into a backend's container.
+///
+/// Named volumes outlive the container, which allows session data to persist
+/// across backends that share a volume name.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct VolumeMount {
+    pub kind: VolumeMountKind,
+
+    /// The volume name (for `Volume` mounts) or host path (for `Bind` mounts).
+    pub source: String,
+
+    /// Absolute path within the container at which to mount the source.
+    pub target: String,
+
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+/// Restrictions on what the processes in a backend's container may do.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SecurityOptions {
+    /// A seccomp profile as JSON, or `unconfined`. Refused unless it matches the
+    /// drone's profile or the drone allows profile overrides.
+    #[serde(default)]
+    pub seccomp_profile: Option<String>,
+
+    /// Name of an AppArmor profile loaded on the drone's host, or `unconfined`.
+    /// Refused unless it matches the drone's profile or the drone allows profile
+    /// overrides.
+    #[serde(default)]
+    pub apparmor_profile: Option<String>,
+
+    /// Prevent processes from gaining privileges, e.g. through setuid binaries.
+    #[serde(default)]
+    pub no_new_privileges: bool,
+
+    /// Linux capabilities to drop, e.g. `NET_RAW`, or `ALL` to drop every
+    /// capability. Added to the capabilities dropped by the drone.
+    #[serde(default)]
+    pub cap_drop: Vec<String>,
+}
+
+/// An in-memory filesystem mounted into a backend's container. Its contents
+/// are discarded when the container stops.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TmpfsMount {
+    /// Absolute path within the container at which to mount the filesystem.
+    pub target: String,
+
+    /// Maximum size of the filesystem, in bytes. Docker defaults to half of
+    /// the host's memory.
+    #[serde(default)]
+    pub size_bytes: Option<i64>,
+
+    /// Permission bits of the filesystem's root, e.g. `0o1777`.
+    #[serde(default)]
+    pub mode: Option<u32>,
+}
+
+/// Constraints on the resources a backend's container may consume.
+///
+/// Each limit is optional; unset limits fall back to Docker's defaults
+/// (i.e. unconstrained).
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// Length of the CPU scheduling period. Docker defaults to 100ms.
+    #[serde_as(as = "Option<DurationMicroSeconds>")]
+    #[serde(default)]
+    pub cpu_period: Option<Duration>,
+
+    /// CPU time the container may use within each `cpu_period`. A quota
+    /// of twice the period allows the container to use two full cores.
+    #[serde_as(as = "Option<DurationMicroSeconds>")]
+    #[serde(default)]
+    pub cpu_quota: Option<Duration>,
+
+    /// Relative weight of the container when competing for CPU with other
+    /// containers. Docker's default weight is 1024.
+    #[serde(default)]
+    pub cpu_shares: Option<i64>,
+
+    /// Relative weight of the container when competing for CPU, on the cgroup
+    /// v2 scale of 1 to 10000 where the default is 100. Takes precedence over
+    /// `cpu_shares`.
+    #[serde(default)]
+    pub cpu_weight: Option<u64>,
+
+    /// CPU time that the container may accumulate while using less than its
+    /// `cpu_quota`, and spend in excess of the quota later. Requires a drone
+    /// using cgroup v2.
+    #[serde_as(as = "Option<DurationMicroSeconds>")]
+    #[serde(default)]
+    pub cpu_burst: Option<Duration>,
+
+    /// Hard limit on the container's memory, in bytes.
+    #[serde(default)]
+    pub memory_limit_bytes: Option<i64>,
+
+    /// Limit on memory plus swap, in bytes. Setting this equal to
+    /// `memory_limit_bytes` prevents the container from using swap.
+    #[serde(default)]
+    pub memory_swap_limit_bytes: Option<i64>,
+}
+
+/// A soft and hard `ulimit` value.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ulimit {
+    pub soft: i64,
+    pub hard: i64,
+}
+
+/// `ulimit`s applied to the processes in a backend's container. Unset limits
+/// are inherited from the container engine.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ulimits {
+    /// Maximum number of open file descriptors.
+    #[serde(default)]
+    pub nofile: Option<Ulimit>,
+
+    /// Maximum number of processes of the container's user.
+    #[serde(default)]
+    pub nproc: Option<Ulimit>,
+
+    /// Maximum size of core dumps, in bytes.
+    #[serde(default)]
+    pub core: Option<Ulimit>,
+}
+
+impl Ulimits {
+    /// Each limit of `backend`, or of `self` where `backend` does not set it.
+    #[must_use] pub fn overridden_by(&self, backend: &Ulimits) -> Ulimits {
+        Ulimits {
+            nofile: backend.nofile.or(self.nofile),
+            nproc: backend.nproc.or(self.nproc),
+            core: backend.core.or(self.core),
+        }
+    }
+
+    /// The limits which are set, with their `ulimit` names.
+    #[must_use] pub fn named(&self) -> Vec<(&'static str, Ulimit)> {
+        [
+            ("nofile", self.nofile),
+            ("nproc", self.nproc),
+            ("core", self.core),
+        ]
+        .into_iter()
+        .filter_map(|(name, ulimit)| Some((name, ulimit?)))
+        .collect()
+    }
+}
+
+/// Timeouts of the proxy's requests to a backend, in seconds. Unset timeouts are the
+/// proxy's own.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BackendTimeouts {
+    /// How long the proxy waits for the backend's response headers.
+    #[serde(default)]
+    pub read_secs: Option<u64>,
+
+    /// How long a response body may go without data before the proxy cuts it off.
+    #[serde(default)]
+    pub idle_secs: Option<u64>,
+
+    /// How long a WebSocket or other upgraded connection, gRPC call, server-sent event
+    /// stream, or TCP connection may go without data before the proxy closes it.
+    #[serde(default)]
+    pub streaming_idle_secs: Option<u64>,
+}
+
+/// A drone's response to a `SpawnRequest`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum SpawnResponse {
+    /// The drone will start the backend, and publish its state changes.
+    Accepted,
+
+    /// The drone is draining, so it does not accept new backends.
+    Draining,
+
+    /// The drone is already running its maximum number of backends.
+    AtCapacity { max_backends: u32 },
+
+    /// The drone is already running a backend with the spawn request's key, or the
+    /// requested backend itself, which should be connected to instead. No new backend
+    /// is started.
+    Existing { backend_id: BackendId },
 }
 
 impl SpawnRequest {
-    #[must_use] pub fn subject(drone_id: DroneId) -> Subject<SpawnRequest, bool> {
+    #[must_use] pub fn subject(drone_id: DroneId) -> Subject<SpawnRequest, SpawnResponse> {
         Subject::new(format!("drone.{}.spawn", drone_id.id()))
     }
+
+    /// The subdomain which routes to the additional port with the given name.
+    #[must_use] pub fn additional_port_subdomain(&self, port_name: &str) -> String {
+        format!("{}-{}", self.backend_id.id(), port_name)
+    }
+
+    /// The name of the route of the TCP port with the given name. Unlike a subdomain,
+    /// it can not be asked for in the host of an HTTP request.
+    #[must_use] pub fn tcp_route_name(&self, port_name: &str) -> String {
+        format!("{}:tcp:{}", self.backend_id.id(), port_name)
+    }
+
+    /// The name of the route of the UDP port with the given name.
+    #[must_use] pub fn udp_route_name(&self, port_name: &str) -> String {
+        format!("{}:udp:{}", self.backend_id.id(), port_name)
+    }
+}
+
+/// A request to push back the expiry time of a backend which was spawned with
+/// `expires_at` and a `lease_token`. Only the drone running the backend replies.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RenewLeaseRequest {
+    pub backend_id: BackendId,
+
+    /// Must match the spawn request's `lease_token`.
+    pub token: String,
+
+    /// How long from now the backend should live. The expiry time is never
+    /// brought forward by a renewal.
+    #[serde_as(as = "DurationSeconds")]
+    pub lease_secs: Duration,
+}
+
+impl RenewLeaseRequest {
+    #[must_use] pub fn subject(backend_id: &BackendId) -> Subject<RenewLeaseRequest, RenewLeaseResponse> {
+        Subject::new(format!("backend.{}.renew", backend_id.id()))
+    }
+
+    #[must_use] pub fn subscribe_subject() -> SubscribeSubject<RenewLeaseRequest, RenewLeaseResponse> {
+        SubscribeSubject::new("backend.*.renew".to_string())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum RenewLeaseResponse {
+    /// The backend now expires at the given time.
+    Renewed { expires_at: DateTime<Utc> },
+
+    /// The token did not match the backend's lease token.
+    InvalidToken,
+
+    /// The backend was not spawned with an expiry time and lease token.
+    NoLease,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
@@ -135,16 +732,21 @@ pub enum BackendState {
     ErrorLoading,
 
     /// The image has been fetched and is running, but is not yet listening
-    /// on a port.
+    /// on a port (or, if the image defines a health check, not yet healthy).
     Starting,
 
     /// A failure occured while starting the container.
     ErrorStarting,
 
-    /// The container is listening on the expected port.
+    /// The container is listening on the expected port, and healthy if the
+    /// image defines a health check.
     Ready,
 
-    /// A timeout occurred becfore the container was ready.
+    /// The container exited and is being restarted according to the backend's
+    /// restart policy. Once restarted, the backend returns to `Starting`.
+    Restarting,
+
+    /// The backend was not ready before its startup timeout.
     TimedOutBeforeReady,
 
     /// The container exited on its own initiative with a non-zero status.
@@ -155,6 +757,19 @@ pub enum BackendState {
 
     /// The container was terminated because all connections were closed.
     Swept,
+
+    /// The container's health check reported it as unhealthy after it became ready.
+    Unhealthy,
+
+    /// The container was terminated because the spawn request's expiry time passed.
+    Expired,
+
+    /// The container was terminated to relieve memory or disk pressure on the drone.
+    Evicted,
+
+    /// The drone running the backend stopped sending heartbeats, so the backend is
+    /// presumed gone. Published by the scheduler rather than the drone.
+    Lost,
 }
 
 impl FromStr for BackendState {
@@ -167,10 +782,15 @@ impl FromStr for BackendState {
             "Starting" => Ok(BackendState::Starting),
             "ErrorStarting" => Ok(BackendState::ErrorStarting),
             "Ready" => Ok(BackendState::Ready),
+            "Restarting" => Ok(BackendState::Restarting),
             "TimedOutBeforeReady" => Ok(BackendState::TimedOutBeforeReady),
             "Failed" => Ok(BackendState::Failed),
             "Exited" => Ok(BackendState::Exited),
             "Swept" => Ok(BackendState::Swept),
+            "Unhealthy" => Ok(BackendState::Unhealthy),
+            "Expired" => Ok(BackendState::Expired),
+            "Evicted" => Ok(BackendState::Evicted),
+            "Lost" => Ok(BackendState::Lost),
             _ => Err(anyhow::anyhow!(
                 "The string {:?} does not describe a valid state.",
                 s
@@ -179,19 +799,26 @@ impl FromStr for BackendState {
     }
 }
 
-impl ToString for BackendState {
-    fn to_string(&self) -> String {
-        match self {
-            BackendState::Loading => "Loading".to_string(),
-            BackendState::ErrorLoading => "ErrorLoading".to_string(),
-            BackendState::Starting => "Starting".to_string(),
-            BackendState::ErrorStarting => "ErrorStarting".to_string(),
-            BackendState::Ready => "Ready".to_string(),
-            BackendState::TimedOutBeforeReady => "TimedOutBeforeReady".to_string(),
-            BackendState::Failed => "Failed".to_string(),
-            BackendState::Exited => "Exited".to_string(),
-            BackendState::Swept => "Swept".to_string(),
-        }
+impl Display for BackendState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let result = match self {
+            BackendState::Loading => "Loading",
+            BackendState::ErrorLoading => "ErrorLoading",
+            BackendState::Starting => "Starting",
+            BackendState::ErrorStarting => "ErrorStarting",
+            BackendState::Ready => "Ready",
+            BackendState::Restarting => "Restarting",
+            BackendState::TimedOutBeforeReady => "TimedOutBeforeReady",
+            BackendState::Failed => "Failed",
+            BackendState::Exited => "Exited",
+            BackendState::Swept => "Swept",
+            BackendState::Unhealthy => "Unhealthy",
+            BackendState::Expired => "Expired",
+            BackendState::Evicted => "Evicted",
+            BackendState::Lost => "Lost",
+        };
+
+        f.write_str(result)
     }
 }
 
@@ -206,6 +833,10 @@ impl BackendState {
                 | BackendState::Failed
                 | BackendState::Exited
                 | BackendState::Swept
+                | BackendState::Unhealthy
+                | BackendState::Expired
+                | BackendState::Evicted
+                | BackendState::Lost
         )
     }
 
@@ -213,6 +844,92 @@ impl BackendState {
     #[must_use] pub fn running(self) -> bool {
         matches!(self, BackendState::Starting | BackendState::Ready)
     }
+
+    /// true if a backend in this state may move directly to `next`. Terminal
+    /// states have no successors.
+    #[must_use] pub fn can_transition_to(self, next: BackendState) -> bool {
+        use BackendState::*;
+
+        match self {
+            Loading => matches!(
+                next,
+                Starting | ErrorLoading | TimedOutBeforeReady | Expired | Lost
+            ),
+            Starting => matches!(
+                next,
+                Ready | Restarting | ErrorStarting | TimedOutBeforeReady | Expired | Evicted | Lost
+            ),
+            Ready => matches!(
+                next,
+                Restarting | Failed | Exited | Swept | Unhealthy | Expired | Evicted | Lost
+            ),
+            Restarting => matches!(
+                next,
+                Starting | Failed | Exited | TimedOutBeforeReady | Expired | Evicted | Lost
+            ),
+            ErrorLoading | ErrorStarting | TimedOutBeforeReady | Failed | Exited | Swept
+            | Unhealthy | Expired | Evicted | Lost => false,
+        }
+    }
+
+    /// The state a backend moves to if the agent encounters an error while it
+    /// is in this state, or None if the error does not affect the backend.
+    #[must_use] pub fn on_error(self) -> Option<BackendState> {
+        match self {
+            BackendState::Loading => Some(BackendState::ErrorLoading),
+            BackendState::Starting => Some(BackendState::ErrorStarting),
+            _ => None,
+        }
+    }
+}
+
+/// Why a backend's container stopped.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TerminationReason {
+    /// The container exited on its own initiative with a zero status.
+    Exited,
+
+    /// The container exited on its own initiative with a non-zero status.
+    Crashed,
+
+    /// The container's main process was killed for exceeding its memory limit.
+    OutOfMemory,
+
+    /// The drone stopped the container because it was idle.
+    Idle,
+
+    /// The drone stopped the container because its health check failed.
+    Unhealthy,
+
+    /// The container could not be created, or did not become ready.
+    StartFailed,
+
+    /// The drone stopped the container because its spawn request's expiry time passed.
+    Expired,
+
+    /// The drone stopped the container to relieve memory or disk pressure on the host.
+    Evicted,
+
+    /// The drone running the backend was lost.
+    DroneLost,
+}
+
+/// How a backend terminated.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackendTermination {
+    pub reason: TerminationReason,
+
+    /// The exit code of the container's main process, if it exited.
+    pub exit_code: Option<i64>,
+
+    /// Whether any process in the container was killed for exceeding the
+    /// container's memory limit, even if the main process survived it.
+    pub oom_killed: bool,
+
+    /// The error which caused the backend to fail, if the drone encountered one.
+    /// When an operation was retried, it lists the error of every attempt.
+    #[serde(default)]
+    pub error: Option<String>,
 }
 
 /// An message representing a change in the state of a backend.
@@ -223,6 +940,18 @@ pub struct BackendStateMessage {
 
     /// The time the state change was observed.
     pub time: DateTime<Utc>,
+
+    /// The state the backend moved from, or None for the initial state.
+    #[serde(default)]
+    pub previous_state: Option<BackendState>,
+
+    /// Details of how the backend terminated, if the new state is terminal.
+    #[serde(default)]
+    pub termination: Option<BackendTermination>,
+
+    /// The labels of the backend's spawn request.
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
 }
 
 impl BackendStateMessage {
@@ -231,6 +960,9 @@ impl BackendStateMessage {
         BackendStateMessage {
             state,
             time: Utc::now(),
+            previous_state: None,
+            termination: None,
+            labels: HashMap::new(),
         }
     }
 
@@ -242,3 +974,223 @@ impl BackendStateMessage {
         SubscribeSubject::new("backend.*.status".to_string())
     }
 }
+
+/// Details about a ready backend which clients may need in order to connect
+/// to it beyond its hostname.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct BackendInfoMessage {
+    /// Ports on the drone which clients reach the backend's UDP ports on,
+    /// keyed by the port names in the spawn request.
+    pub udp_ports: HashMap<String, u16>,
+
+    /// Ports on the drone which the proxy passes TCP connections to the
+    /// backend's TCP ports through, keyed by the port names in the spawn
+    /// request.
+    #[serde(default)]
+    pub tcp_ports: HashMap<String, u16>,
+
+    /// The CPU and memory limits in effect for the backend's container, if
+    /// the drone can read them.
+    #[serde(default)]
+    pub resource_limits: Option<EffectiveResourceLimits>,
+}
+
+/// Resource limits in effect for a backend's container, as read from its
+/// cgroup.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EffectiveResourceLimits {
+    pub cpu_weight: Option<u64>,
+
+    /// CPU time the container may use within each `cpu_period`, or None if
+    /// unlimited.
+    #[serde_as(as = "Option<DurationMicroSeconds>")]
+    pub cpu_quota: Option<Duration>,
+
+    #[serde_as(as = "DurationMicroSeconds")]
+    pub cpu_period: Duration,
+
+    #[serde_as(as = "Option<DurationMicroSeconds>")]
+    pub cpu_burst: Option<Duration>,
+
+    /// Hard limit on the container's memory, or None if unlimited.
+    pub memory_limit_bytes: Option<i64>,
+}
+
+impl BackendInfoMessage {
+    #[must_use] pub fn subject(backend_id: &BackendId) -> Subject<BackendInfoMessage, NoReply> {
+        Subject::new(format!("backend.{}.info", backend_id.id()))
+    }
+
+    #[must_use] pub fn subscribe_subject() -> SubscribeSubject<BackendInfoMessage, NoReply> {
+        SubscribeSubject::new("backend.*.info".to_string())
+    }
+}
+
+/// Resource usage of a backend's container since the previous message,
+/// published periodically while the backend is running.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BackendStatsMessage {
+    /// CPU time used as a percentage of one core, e.g. 200 when two cores
+    /// were fully used.
+    pub cpu_percent: f64,
+
+    /// Memory in use, excluding reclaimable page cache.
+    pub memory_bytes: u64,
+
+    /// Bytes received over the network.
+    pub network_rx_bytes: u64,
+
+    /// Bytes sent over the network.
+    pub network_tx_bytes: u64,
+
+    /// The time the usage was measured.
+    pub time: DateTime<Utc>,
+}
+
+impl BackendStatsMessage {
+    #[must_use] pub fn subject(backend_id: &BackendId) -> Subject<BackendStatsMessage, NoReply> {
+        Subject::new(format!("backend.{}.stats", backend_id.id()))
+    }
+
+    #[must_use] pub fn subscribe_subject() -> SubscribeSubject<BackendStatsMessage, NoReply> {
+        SubscribeSubject::new("backend.*.stats".to_string())
+    }
+}
+
+/// A request to run a command inside a backend's container, e.g. to debug it.
+/// Drones only run commands if their operator has enabled exec.
+///
+/// The command's output is published as `ExecOutputMessage`s, so the requester
+/// should subscribe to `ExecOutputMessage::subject` before sending the request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExecRequest {
+    pub backend_id: BackendId,
+
+    /// Identifies this execution among others on the same backend. Chosen by
+    /// the requester, and made of ASCII letters, digits, `-` and `_`, since it is
+    /// part of the output's subject.
+    pub exec_id: String,
+
+    /// The program to run and its arguments.
+    pub command: Vec<String>,
+}
+
+/// A drone's response to an `ExecRequest`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ExecResponse {
+    /// The command is running, and its output will be published.
+    Started,
+
+    /// The drone's operator has not enabled exec.
+    Disabled,
+
+    /// The backend's container is not running on the drone.
+    NotRunning,
+
+    /// The exec ID is empty or has characters other than those allowed.
+    InvalidExecId,
+}
+
+impl ExecRequest {
+    #[must_use] pub fn subject(drone_id: DroneId) -> Subject<ExecRequest, ExecResponse> {
+        Subject::new(format!("drone.{}.exec", drone_id.id()))
+    }
+
+    /// Whether the exec ID is a single token of the output's subject, so that the output
+    /// cannot be published to another subject.
+    pub fn has_valid_exec_id(&self) -> bool {
+        !self.exec_id.is_empty()
+            && self
+                .exec_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }
+}
+
+/// Output of a command started by an `ExecRequest`.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ExecOutputMessage {
+    Output(DroneLogMessage),
+
+    /// The command has finished. This is the last message of an execution. The
+    /// exit code is absent if the command could not be run.
+    Exited {
+        exit_code: Option<i64>,
+    },
+}
+
+impl ExecOutputMessage {
+    #[must_use] pub fn subject(backend_id: &BackendId, exec_id: &str) -> Subject<ExecOutputMessage, NoReply> {
+        Subject::new(format!("backend.{}.exec.{}", backend_id.id(), exec_id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_can_transition_to() {
+        use BackendState::*;
+
+        let allowed = [
+            (Loading, Starting),
+            (Loading, ErrorLoading),
+            (Loading, TimedOutBeforeReady),
+            (Loading, Lost),
+            (Starting, Ready),
+            (Starting, Restarting),
+            (Starting, ErrorStarting),
+            (Starting, Evicted),
+            (Ready, Restarting),
+            (Ready, Failed),
+            (Ready, Exited),
+            (Ready, Swept),
+            (Ready, Unhealthy),
+            (Ready, Expired),
+            (Restarting, Starting),
+            (Restarting, TimedOutBeforeReady),
+            (Restarting, Lost),
+        ];
+        for (from, to) in allowed {
+            assert!(from.can_transition_to(to), "{} -> {}", from, to);
+        }
+
+        let refused = [
+            (Loading, Ready),
+            (Loading, Evicted),
+            (Loading, Loading),
+            (Starting, Loading),
+            (Starting, Swept),
+            (Ready, Loading),
+            (Ready, Starting),
+            (Ready, ErrorStarting),
+            (Restarting, Ready),
+            (ErrorLoading, Starting),
+            (Failed, Restarting),
+            (Exited, Ready),
+            (Swept, Ready),
+            (Expired, Loading),
+            (Lost, Ready),
+            (Lost, Lost),
+        ];
+        for (from, to) in refused {
+            assert!(!from.can_transition_to(to), "{} -> {}", from, to);
+        }
+    }
+
+    #[test]
+    fn test_has_valid_exec_id() {
+        let request = |exec_id: &str| ExecRequest {
+            backend_id: BackendId::new("backend".to_string()),
+            exec_id: exec_id.to_string(),
+            command: vec!["ls".to_string()],
+        };
+
+        assert!(request("a1-B2_c3").has_valid_exec_id());
+        for exec_id in ["", "a.b", "*", ">", "a b", "a\tb", "é"] {
+            assert!(!request(exec_id).has_valid_exec_id(), "{:?}", exec_id);
+        }
+    }
+}
@@ -1,6 +1,12 @@
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::{fmt::Display, str::FromStr};
 
-use crate::nats::Subject;
+use crate::{
+    nats::{NoReply, Subject, SubscribeSubject},
+    types::BackendId,
+};
 
 /// A request from the drone to the DNS server telling it to set
 /// a TXT record on the given domain with the given value.
@@ -15,3 +21,195 @@ impl SetAcmeDnsRecord {
         Subject::new("acme.set_dns_record".to_string())
     }
 }
+
+/// A request from the proxy for the key authorization answering the HTTP-01 challenge
+/// with the given token. Only the certificate refresher with that challenge pending
+/// responds.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AcmeHttpChallenge {
+    pub token: String,
+}
+
+impl AcmeHttpChallenge {
+    #[must_use] pub fn subject(cluster: &str) -> Subject<AcmeHttpChallenge, String> {
+        Subject::new(format!("cluster.{}.acme.http_challenge", cluster))
+    }
+}
+
+/// The type of a certificate's private key.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyType {
+    /// 4096-bit RSA, which every client supports.
+    #[default]
+    Rsa,
+
+    /// ECDSA on P-256, whose handshakes are smaller and faster, but which some old clients
+    /// don't support.
+    Ecdsa,
+}
+
+impl KeyType {
+    pub const ALL: [KeyType; 2] = [KeyType::Rsa, KeyType::Ecdsa];
+
+    #[must_use] pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyType::Rsa => "rsa",
+            KeyType::Ecdsa => "ecdsa",
+        }
+    }
+}
+
+impl Display for KeyType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for KeyType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        KeyType::ALL
+            .into_iter()
+            .find(|key_type| key_type.as_str() == s)
+            .ok_or_else(|| anyhow!("Expected key type to be rsa or ecdsa, got {:?}.", s))
+    }
+}
+
+/// A certificate issued for a cluster, with its private key, published by the cluster's
+/// certificate refresher for every proxy in the cluster to serve.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClusterCertificate {
+    pub cluster: String,
+
+    /// The certificate chain, leaf first.
+    pub certificate_pem: String,
+    pub private_key_pem: String,
+
+    /// Certificates for the cluster with other types of key, for clients which can't use
+    /// this one's.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alternates: Vec<AlternateCertificate>,
+}
+
+/// A certificate for the same names as another, with a different type of key.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AlternateCertificate {
+    pub key_type: KeyType,
+    pub certificate_pem: String,
+    pub private_key_pem: String,
+}
+
+impl ClusterCertificate {
+    #[must_use] pub fn subject(cluster: &str) -> Subject<ClusterCertificate, NoReply> {
+        Subject::new(format!("cluster.{}.certificate", cluster))
+    }
+
+    #[must_use] pub fn subscribe_subject() -> SubscribeSubject<ClusterCertificate, NoReply> {
+        SubscribeSubject::new("cluster.*.certificate".to_string())
+    }
+
+    /// The key of the cluster's certificate in a NATS key-value bucket.
+    #[must_use] pub fn store_subject(bucket: &str, cluster: &str) -> Subject<ClusterCertificate, NoReply> {
+        Subject::new(format!("$KV.{}.{}", bucket, cluster))
+    }
+
+    #[must_use] pub fn store_subscribe_subject(bucket: &str) -> SubscribeSubject<ClusterCertificate, NoReply> {
+        SubscribeSubject::new(format!("$KV.{}.>", bucket))
+    }
+}
+
+/// The private key of the ACME account certificates are requested with, shared by every
+/// cluster's certificate refresher.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AcmeAccountKey {
+    pub private_key_pem: String,
+}
+
+impl AcmeAccountKey {
+    /// The key of the account key in a NATS key-value bucket, which can't clash with a
+    /// cluster domain's because it has no dot.
+    #[must_use] pub fn store_subject(bucket: &str) -> Subject<AcmeAccountKey, NoReply> {
+        Subject::new(format!("$KV.{}.acme-account", bucket))
+    }
+}
+
+/// The outcome of an attempt by a cluster's certificate refresher to renew its
+/// certificate.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CertificateRenewal {
+    pub cluster: String,
+    pub time: DateTime<Utc>,
+    pub success: bool,
+    pub error: Option<String>,
+
+    /// When the cluster's certificate expires after the attempt, if it has one.
+    pub valid_until: Option<DateTime<Utc>>,
+}
+
+impl CertificateRenewal {
+    #[must_use] pub fn subject(cluster: &str) -> Subject<CertificateRenewal, NoReply> {
+        Subject::new(format!("cluster.{}.certificate.renewal", cluster))
+    }
+}
+
+/// Published by a cluster's certificate refresher while its certificate is missing or
+/// expires within the danger window, i.e. renewal has been failing.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CertificateExpiryAlert {
+    pub cluster: String,
+    pub valid_until: Option<DateTime<Utc>>,
+
+    /// The error of the last failed renewal, if any.
+    pub last_error: Option<String>,
+}
+
+impl CertificateExpiryAlert {
+    #[must_use] pub fn subject(cluster: &str) -> Subject<CertificateExpiryAlert, NoReply> {
+        Subject::new(format!("cluster.{}.certificate.alert", cluster))
+    }
+}
+
+/// Published by an agent once it has routed a backend's custom domain, which it has
+/// checked is a CNAME of the backend's subdomain, for the cluster's certificate refresher
+/// to issue a certificate for.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CustomDomainCertRequest {
+    pub hostname: String,
+    pub backend_id: BackendId,
+}
+
+impl CustomDomainCertRequest {
+    #[must_use] pub fn subject(cluster: &str) -> Subject<CustomDomainCertRequest, NoReply> {
+        Subject::new(format!("cluster.{}.custom_domain.request", cluster))
+    }
+}
+
+/// A certificate issued for a backend's custom domain, with its private key, published by
+/// the cluster's certificate refresher for every proxy in the cluster to serve.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CustomDomainCertificate {
+    pub hostname: String,
+
+    /// The certificate chain, leaf first.
+    pub certificate_pem: String,
+    pub private_key_pem: String,
+}
+
+impl CustomDomainCertificate {
+    #[must_use] pub fn subject(cluster: &str) -> Subject<CustomDomainCertificate, NoReply> {
+        Subject::new(format!("cluster.{}.custom_domain.certificate", cluster))
+    }
+}
+
+/// Published by a proxy when it starts, asking the cluster's certificate refresher to
+/// publish the certificate of every custom domain it holds.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CustomDomainCertSync;
+
+impl CustomDomainCertSync {
+    #[must_use] pub fn subject(cluster: &str) -> Subject<CustomDomainCertSync, NoReply> {
+        Subject::new(format!("cluster.{}.custom_domain.sync", cluster))
+    }
+}
@@ -1,3 +1,5 @@
 pub mod agent;
 pub mod cert;
 pub mod logging;
+pub mod proxy;
+pub mod scheduler;
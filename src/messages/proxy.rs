@@ -0,0 +1,124 @@
+use crate::{
+    messages::agent::BackendTimeouts,
+    nats::{NoReply, Subject},
+    types::{BackendId, DroneId},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationMilliSeconds};
+use std::{collections::HashMap, net::IpAddr, time::Duration};
+
+/// A request handled by the proxy, for usage accounting.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccessLogMessage {
+    /// When the request was received.
+    pub time: DateTime<Utc>,
+
+    /// The backend the request was routed to, or None if the host did not
+    /// match a backend.
+    pub backend_id: Option<BackendId>,
+    pub host: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+
+    /// Bytes of response body sent to the client. For upgraded connections,
+    /// bytes sent after the upgrade.
+    pub bytes: u64,
+
+    /// Time from receiving the request until the response, or upgraded
+    /// connection, ended.
+    #[serde_as(as = "DurationMilliSeconds")]
+    pub duration_ms: Duration,
+    pub client_ip: Option<IpAddr>,
+
+    /// The W3C trace the request belongs to, which the proxy continued or began.
+    #[serde(default)]
+    pub trace_id: Option<String>,
+}
+
+impl AccessLogMessage {
+    #[must_use] pub fn subject(subject_name: &str) -> Subject<AccessLogMessage, NoReply> {
+        Subject::new(subject_name.to_string())
+    }
+}
+
+/// A route from a subdomain of a cluster to a backend on a drone.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RouteInfo {
+    pub subdomain: String,
+    pub backend_id: BackendId,
+
+    /// IP and port of the backend, reachable from the proxy.
+    pub address: String,
+
+    /// Token which requests must carry to be forwarded, if any.
+    pub bearer_token: Option<String>,
+
+    /// Long-lived connections the proxy holds open to the backend at once, if limited.
+    #[serde(default)]
+    pub max_connections: Option<u32>,
+
+    /// Timeouts of requests to the backend, in place of the proxy's own.
+    #[serde(default)]
+    pub timeouts: Option<BackendTimeouts>,
+
+    /// Addresses of further containers serving the backend, if it is a pool of replicas,
+    /// which requests are balanced across along with `address`.
+    #[serde(default)]
+    pub replicas: Vec<String>,
+}
+
+/// A change to the routes of a drone.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum RouteUpdate {
+    /// Add a route, replacing any route with the same subdomain.
+    Add(RouteInfo),
+
+    /// Remove the routes to a backend, which is stopping.
+    RemoveBackend(BackendId),
+
+    /// Every route to the drone's backends, replacing those previously received
+    /// from it. Sent periodically, so that proxies recover from missed updates.
+    Sync(Vec<RouteInfo>),
+}
+
+/// Published by agents as their routes change, so that proxies can apply
+/// routes without sharing the agent's database.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RouteUpdateMessage {
+    pub drone_id: DroneId,
+    pub update: RouteUpdate,
+}
+
+impl RouteUpdateMessage {
+    #[must_use] pub fn subject(cluster: &str) -> Subject<RouteUpdateMessage, NoReply> {
+        Subject::new(format!("cluster.{}.routes", cluster))
+    }
+}
+
+/// Published by a proxy when it starts, asking every drone in the cluster to
+/// send a `RouteUpdate::Sync` rather than waiting for the next one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RouteSyncRequest;
+
+impl RouteSyncRequest {
+    #[must_use] pub fn subject(cluster: &str) -> Subject<RouteSyncRequest, NoReply> {
+        Subject::new(format!("cluster.{}.routes.sync", cluster))
+    }
+}
+
+/// Published periodically by proxies, with the time of the most recent
+/// request, datagram, or open connection to each backend which has been
+/// active since the proxy's last message.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackendActivityMessage {
+    pub last_active: HashMap<BackendId, DateTime<Utc>>,
+}
+
+impl BackendActivityMessage {
+    #[must_use] pub fn subject(cluster: &str) -> Subject<BackendActivityMessage, NoReply> {
+        Subject::new(format!("cluster.{}.activity", cluster))
+    }
+}
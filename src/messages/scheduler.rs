@@ -0,0 +1,97 @@
+use crate::{
+    messages::agent::{SpawnRequest, SpawnResponse},
+    nats::{NoReply, Subject},
+    types::DroneId,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+
+/// Published periodically by the scheduler for each cluster, describing demand the
+/// cluster's drones could not meet, so that an autoscaler can launch more drones.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClusterDemandMessage {
+    pub cluster: String,
+    pub time: DateTime<Utc>,
+
+    /// Spawn requests rejected since the previous message because no drone had room
+    /// for them, or none was available.
+    pub rejected_spawns: u64,
+
+    /// Drones which are ready, uncordoned, and have sent a heartbeat recently.
+    pub ready_drones: u32,
+
+    /// Backends the ready drones will accept, as they last reported. Drones without a
+    /// limit report 100.
+    pub free_slots: u32,
+
+    /// Mean of the ready drones' headroom: the smallest fraction free of each drone's
+    /// backend slots, idle CPU, and available memory. Zero if none is ready.
+    pub mean_headroom: f64,
+}
+
+impl ClusterDemandMessage {
+    #[must_use] pub fn subject(cluster: &str) -> Subject<ClusterDemandMessage, NoReply> {
+        Subject::new(format!("cluster.{}.demand", cluster))
+    }
+}
+
+/// Why a backend could not be placed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ScheduleError {
+    /// No drone has ever sent a heartbeat for the cluster.
+    NoSuchCluster { cluster: String },
+
+    /// No drone of the cluster which matches the spawn request's selector is ready and
+    /// has sent a heartbeat recently.
+    NoDrones { cluster: String },
+
+    /// The cluster's ready drones have no room for the backend.
+    ClusterFull { cluster: String },
+}
+
+impl Display for ScheduleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScheduleError::NoSuchCluster { cluster } => write!(f, "No such cluster: {}.", cluster),
+            ScheduleError::NoDrones { cluster } => {
+                write!(f, "No drones are available in cluster {}.", cluster)
+            }
+            ScheduleError::ClusterFull { cluster } => {
+                write!(f, "Cluster {} has no capacity for the backend.", cluster)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ScheduleError {}
+
+/// A request for the scheduler to place a backend on a drone of its cluster, and spawn
+/// it there.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScheduleRequest {
+    pub spawn_request: SpawnRequest,
+}
+
+/// The scheduler's response to a `ScheduleRequest`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ScheduleResponse {
+    /// The spawn request was sent to the drone, which responded.
+    Scheduled {
+        drone_id: DroneId,
+        response: SpawnResponse,
+    },
+
+    /// No drone could take the backend.
+    Rejected(ScheduleError),
+
+    /// The drone the backend was placed on did not respond. It may still start the
+    /// backend, so the backend's state should be watched for.
+    DroneUnreachable { drone_id: DroneId },
+}
+
+impl ScheduleRequest {
+    #[must_use] pub fn subject() -> Subject<ScheduleRequest, ScheduleResponse> {
+        Subject::new("scheduler.schedule".to_string())
+    }
+}
@@ -48,8 +48,8 @@ where
     #[must_use] pub fn new(subject: String) -> Subject<M, R> {
         Subject {
             subject,
-            _ph_m: PhantomData::default(),
-            _ph_r: PhantomData::default(),
+            _ph_m: PhantomData,
+            _ph_r: PhantomData,
         }
     }
 }
@@ -72,8 +72,8 @@ where
     #[must_use] pub fn new(subject: String) -> SubscribeSubject<M, R> {
         SubscribeSubject {
             subject,
-            _ph_m: PhantomData::default(),
-            _ph_r: PhantomData::default(),
+            _ph_m: PhantomData,
+            _ph_r: PhantomData,
         }
     }
 }
@@ -148,10 +148,16 @@ where
             value: serde_json::from_slice(&message.payload)?,
             message,
             nc,
-            _ph: PhantomData::default(),
+            _ph: PhantomData,
         })
     }
 
+    /// The subject the message was published on, e.g. to tell which backend a message
+    /// received on a wildcard subject is about.
+    pub fn subject(&self) -> &str {
+        &self.message.subject
+    }
+
     pub async fn respond(&self, response: &R) -> Result<()> {
         self.nc
             .publish(
@@ -189,8 +195,8 @@ where
         TypedSubscription {
             subscription,
             nc,
-            _ph_r: PhantomData::default(),
-            _ph_t: PhantomData::default(),
+            _ph_r: PhantomData,
+            _ph_t: PhantomData,
         }
     }
 
@@ -206,9 +212,11 @@ where
     }
 }
 
+#[allow(clippy::wrong_self_convention)]
 trait NatsResultExt<T> {
     fn as_anyhow(self) -> Result<T>;
 
+    #[allow(unused)]
     fn with_message(self, message: &'static str) -> Result<T>;
 }
 
@@ -253,6 +261,30 @@ impl TypedNats {
         Ok(())
     }
 
+    /// Like `add_jetstream_stream`, but only the latest message of each subject is kept.
+    pub async fn add_latest_jetstream_stream<P, T, R>(
+        &self,
+        stream_name: &str,
+        subject: P,
+    ) -> Result<()>
+    where
+        P: Subscribable<T, R>,
+        T: Serialize + DeserializeOwned,
+        R: Serialize + DeserializeOwned,
+    {
+        self.jetstream
+            .get_or_create_stream(Config {
+                name: stream_name.to_string(),
+                subjects: vec![subject.subject().to_string()],
+                max_messages_per_subject: 1,
+                ..Config::default()
+            })
+            .await
+            .as_anyhow()?;
+
+        Ok(())
+    }
+
     pub async fn connect(nats_url: &str, options: ConnectOptions) -> Result<Self> {
         let nc = async_nats::connect_with_options(nats_url, options).await?;
 
@@ -337,6 +369,12 @@ impl TypedNats {
         stream
     }
 
+    /// Wait for the server to acknowledge everything sent so far, e.g. to check that the
+    /// connection is up.
+    pub async fn flush(&self) -> Result<()> {
+        self.nc.flush().await.as_anyhow()
+    }
+
     pub async fn publish<T>(&self, subject: &Subject<T, NoReply>, value: &T) -> Result<()>
     where
         T: Serialize + DeserializeOwned,
@@ -350,6 +388,22 @@ impl TypedNats {
         Ok(())
     }
 
+    /// Publish to a subject captured by a JetStream stream, returning once the stream has
+    /// stored the message.
+    pub async fn publish_jetstream<T>(&self, subject: &Subject<T, NoReply>, value: &T) -> Result<()>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        self.jetstream
+            .publish(
+                subject.subject.clone(),
+                Bytes::from(serde_json::to_vec(value)?),
+            )
+            .await
+            .as_anyhow()?;
+        Ok(())
+    }
+
     pub async fn request<T, R>(&self, subject: &Subject<T, R>, value: &T) -> Result<R>
     where
         T: Serialize + DeserializeOwned,
@@ -1,5 +1,68 @@
+// Only the drone retries with backoff.
+#![cfg_attr(not(feature = "full"), allow(dead_code))]
+
 use futures::Future;
-use std::{fmt::Debug, time::Duration};
+use std::{
+    fmt::{Debug, Write},
+    time::Duration,
+};
+
+/// How to retry an operation which may fail transiently: up to `max_attempts` times,
+/// waiting `initial_delay` after the first failure and doubling the wait after each
+/// further failure, up to `max_delay`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// The wait after the given (1-based) failed attempt.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+}
+
+/// Run a closure until the future it returns resolves to an Ok value, or the policy's
+/// attempts are exhausted. If every attempt fails, the error lists the error of each
+/// attempt.
+pub async fn do_with_backoff<T, Fut: Future<Output = anyhow::Result<T>>, F: FnMut() -> Fut>(
+    mut func: F,
+    policy: &RetryPolicy,
+) -> anyhow::Result<T> {
+    let mut errors = Vec::new();
+    loop {
+        match func().await {
+            Ok(value) => return Ok(value),
+            Err(error) => {
+                let attempt = errors.len() as u32 + 1;
+                tracing::warn!(
+                    ?error,
+                    attempt,
+                    max_attempts = policy.max_attempts,
+                    "Attempt failed."
+                );
+                errors.push(error);
+
+                if attempt >= policy.max_attempts {
+                    let mut message = format!("All {} attempts failed:", errors.len());
+                    for (i, error) in errors.iter().enumerate() {
+                        write!(message, "\n{}: {:#}", i + 1, error)
+                            .expect("Writing to a String should never fail.");
+                    }
+                    return Err(anyhow::anyhow!(message));
+                }
+
+                tokio::time::sleep(policy.delay(attempt)).await;
+            }
+        }
+    }
+}
 
 /// Run a closure until the future it returns resolves to an Ok value.
 ///
@@ -37,3 +100,22 @@ pub async fn do_with_retry<T, E: Debug, Fut: Future<Output = Result<T, E>>, F: F
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_retry_policy_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+        };
+        assert_eq!(Duration::from_secs(1), policy.delay(1));
+        assert_eq!(Duration::from_secs(2), policy.delay(2));
+        assert_eq!(Duration::from_secs(4), policy.delay(3));
+        assert_eq!(Duration::from_secs(5), policy.delay(4));
+        assert_eq!(Duration::from_secs(5), policy.delay(100));
+    }
+}
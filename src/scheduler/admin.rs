@@ -0,0 +1,202 @@
+//! An HTTP API describing the scheduler's drones and backends, so that cluster state can
+//! be inspected without subscribing to NATS, and through which operators take drones out
+//! of service. It is unauthenticated, so should only be served on a private address.
+//!
+//! - `GET /drones` returns each drone's `DroneSummary`.
+//! - `GET /backends` returns each running backend's `BackendSummary`.
+//! - `GET /backends/<backend ID>/events` returns the backend's `BackendEvent`s.
+//! - `GET /metrics` returns clusters' demand in the Prometheus text format.
+//! - `POST /drones/<drone ID>/cordon` and `/uncordon` stop and resume placing backends
+//!   on the drone.
+//! - `POST /drones/<drone ID>/drain` cordons the drone and asks it to drain, and
+//!   `/resume` undoes both.
+//!
+//! The `POST` routes respond with 204 No Content, or 404 Not Found if the scheduler has
+//! not heard from the drone.
+
+use super::Scheduler;
+use crate::types::{BackendId, DroneId};
+use anyhow::Result;
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::Serialize;
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+/// Content type of the Prometheus text exposition format.
+const TEXT_FORMAT: &str = "text/plain; version=0.0.4";
+
+fn json(value: &impl Serialize) -> (StatusCode, String) {
+    match serde_json::to_string(value) {
+        Ok(body) => (StatusCode::OK, body),
+        Err(error) => (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()),
+    }
+}
+
+fn handle(scheduler: &Mutex<Scheduler>, request: Request<Body>) -> Response<Body> {
+    let path = request.uri().path();
+    let backend_events = path
+        .strip_prefix("/backends/")
+        .and_then(|rest| rest.strip_suffix("/events"))
+        .filter(|backend_id| !backend_id.is_empty() && !backend_id.contains('/'));
+
+    let drone_action = path
+        .strip_prefix("/drones/")
+        .and_then(|rest| rest.split_once('/'))
+        .and_then(|(drone_id, action)| Some((DroneId::new(drone_id.parse().ok()?), action)));
+
+    let (status, body) = if request.method() == Method::POST {
+        match drone_action {
+            Some((drone_id, action)) => {
+                let mut scheduler = scheduler.lock().expect("Scheduler lock was poisoned.");
+                let known = match action {
+                    "cordon" => scheduler.cordon(drone_id, true),
+                    "uncordon" => scheduler.cordon(drone_id, false),
+                    "drain" => scheduler.drain(drone_id, true),
+                    "resume" => scheduler.drain(drone_id, false),
+                    _ => false,
+                };
+                if known {
+                    tracing::info!(drone_id = drone_id.id(), %action, "Applied admin action to drone.");
+                    (StatusCode::NO_CONTENT, String::new())
+                } else {
+                    (StatusCode::NOT_FOUND, String::new())
+                }
+            }
+            None => (StatusCode::METHOD_NOT_ALLOWED, String::new()),
+        }
+    } else if request.method() != Method::GET {
+        (StatusCode::METHOD_NOT_ALLOWED, String::new())
+    } else {
+        let scheduler = scheduler.lock().expect("Scheduler lock was poisoned.");
+        match (path, backend_events) {
+            ("/drones", _) => json(&scheduler.drones()),
+            ("/backends", _) => json(&scheduler.backends()),
+            ("/metrics", _) => match scheduler.render_metrics() {
+                Ok(metrics) => (StatusCode::OK, metrics),
+                Err(error) => (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()),
+            },
+            (_, Some(backend_id)) => {
+                match scheduler.backend_history(&BackendId::new(backend_id.to_string())) {
+                    Some(history) => json(&history),
+                    None => (StatusCode::NOT_FOUND, String::new()),
+                }
+            }
+            _ => (StatusCode::NOT_FOUND, String::new()),
+        }
+    };
+
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    if status == StatusCode::OK {
+        let content_type = if path == "/metrics" {
+            TEXT_FORMAT
+        } else {
+            "application/json"
+        };
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            content_type
+                .parse()
+                .expect("Content type should always parse."),
+        );
+    }
+    response
+}
+
+/// Serve the scheduler's drones and backends on the given address until the server fails.
+pub async fn serve_admin(address: SocketAddr, scheduler: Arc<Mutex<Scheduler>>) -> Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let scheduler = scheduler.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = handle(&scheduler, request);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    tracing::info!(%address, "Serving scheduler admin API.");
+    Server::try_bind(&address)?.serve(make_service).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scheduler::{test::status, DEFAULT_HEARTBEAT_TIMEOUT};
+
+    fn get(scheduler: &Mutex<Scheduler>, path: &str) -> StatusCode {
+        let request = Request::get(path).body(Body::empty()).unwrap();
+        handle(scheduler, request).status()
+    }
+
+    #[test]
+    fn test_routes() {
+        let scheduler = Mutex::new(Scheduler::new(DEFAULT_HEARTBEAT_TIMEOUT));
+        assert_eq!(StatusCode::OK, get(&scheduler, "/drones"));
+        assert_eq!(StatusCode::OK, get(&scheduler, "/backends"));
+        assert_eq!(StatusCode::OK, get(&scheduler, "/metrics"));
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            get(&scheduler, "/backends/unknown/events")
+        );
+        assert_eq!(StatusCode::NOT_FOUND, get(&scheduler, "/backends//events"));
+        assert_eq!(StatusCode::NOT_FOUND, get(&scheduler, "/"));
+
+        let request = Request::post("/drones").body(Body::empty()).unwrap();
+        assert_eq!(
+            StatusCode::METHOD_NOT_ALLOWED,
+            handle(&scheduler, request).status()
+        );
+    }
+
+    fn post(scheduler: &Mutex<Scheduler>, path: &str) -> StatusCode {
+        let request = Request::post(path).body(Body::empty()).unwrap();
+        handle(scheduler, request).status()
+    }
+
+    fn cordoned(scheduler: &Mutex<Scheduler>) -> bool {
+        scheduler.lock().unwrap().drones()[0].cordoned
+    }
+
+    #[test]
+    fn test_drone_actions() {
+        let scheduler = Mutex::new(Scheduler::new(DEFAULT_HEARTBEAT_TIMEOUT));
+        assert_eq!(StatusCode::NOT_FOUND, post(&scheduler, "/drones/1/cordon"));
+
+        scheduler.lock().unwrap().heartbeat(status(1, 5, 8 << 30));
+
+        assert_eq!(StatusCode::NO_CONTENT, post(&scheduler, "/drones/1/cordon"));
+        assert!(cordoned(&scheduler));
+        assert_eq!(
+            StatusCode::NO_CONTENT,
+            post(&scheduler, "/drones/1/uncordon")
+        );
+        assert!(!cordoned(&scheduler));
+
+        assert_eq!(StatusCode::NO_CONTENT, post(&scheduler, "/drones/1/drain"));
+        assert!(cordoned(&scheduler));
+        assert_eq!(StatusCode::NO_CONTENT, post(&scheduler, "/drones/1/resume"));
+        assert!(!cordoned(&scheduler));
+        assert_eq!(
+            vec![(DroneId::new(1), true), (DroneId::new(1), false)],
+            scheduler.lock().unwrap().take_drain_requests()
+        );
+
+        assert_eq!(StatusCode::NOT_FOUND, post(&scheduler, "/drones/1/evict"));
+        assert_eq!(StatusCode::NOT_FOUND, post(&scheduler, "/drones/2/drain"));
+        assert_eq!(
+            StatusCode::METHOD_NOT_ALLOWED,
+            post(&scheduler, "/drones/one/drain")
+        );
+        assert_eq!(StatusCode::NOT_FOUND, get(&scheduler, "/drones/1/drain"));
+    }
+}
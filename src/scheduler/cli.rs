@@ -0,0 +1,154 @@
+//! Command-line options of `spawner-scheduler`.
+
+use super::{
+    policy::SchedulingStrategy,
+    service::{serve_scheduler, SchedulerOptions},
+};
+use crate::{logging::TracingHandle, nats_connection::NatsConnection};
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use signal_hook::{consts::SIGINT, iterator::Signals};
+use std::{net::SocketAddr, thread, time::Duration};
+
+#[derive(Parser)]
+pub struct Opts {
+    /// Hostname for connecting to NATS.
+    #[clap(long, action)]
+    pub nats_url: String,
+
+    /// The domain of a cluster drones may join. May be repeated; spawn requests which
+    /// name no cluster are placed in the first.
+    #[clap(long, required = true, action)]
+    pub cluster: Vec<String>,
+
+    /// How a cluster's backends are placed, as `<cluster>=<strategy>` where the strategy
+    /// is `spread` (the default) or `bin-pack`. May be repeated.
+    #[clap(long, value_parser = parse_scheduling_strategy)]
+    pub scheduling_strategy: Vec<(String, SchedulingStrategy)>,
+
+    /// Seconds after its last heartbeat that a drone's backends are considered lost.
+    #[clap(long, default_value = "15", action)]
+    pub heartbeat_timeout: u64,
+
+    /// Private address to serve the admin API on, e.g. `127.0.0.1:9091`. Through it
+    /// drones are inspected, cordoned, and drained.
+    #[clap(long, action)]
+    pub admin_address: Option<SocketAddr>,
+}
+
+fn parse_scheduling_strategy(s: &str) -> Result<(String, SchedulingStrategy)> {
+    match s.split_once('=') {
+        Some((cluster, strategy)) if !cluster.is_empty() => {
+            Ok((cluster.to_string(), strategy.parse()?))
+        }
+        _ => Err(anyhow!(
+            "Expected scheduling strategy to be <cluster>=<strategy>, got {:?}.",
+            s
+        )),
+    }
+}
+
+impl TryFrom<Opts> for SchedulerOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(opts: Opts) -> Result<Self> {
+        Ok(SchedulerOptions {
+            nats: NatsConnection::new(opts.nats_url)?,
+            clusters: opts.cluster,
+            strategies: opts.scheduling_strategy,
+            heartbeat_timeout: Duration::from_secs(opts.heartbeat_timeout),
+            admin_address: opts.admin_address,
+        })
+    }
+}
+
+async fn main() -> Result<()> {
+    let mut tracing_handle = TracingHandle::init()?;
+
+    let options = SchedulerOptions::try_from(Opts::parse())?;
+    let nats = options.nats.connection().await?;
+    tracing_handle.attach_nats(nats, "logs.scheduler".to_string())?;
+
+    serve_scheduler(options).await
+}
+
+pub fn run() -> Result<()> {
+    let mut signals = Signals::new([SIGINT])?;
+
+    thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            std::process::exit(0)
+        }
+    });
+
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(main())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<SchedulerOptions> {
+        let mut full_args = vec!["scheduler"];
+        full_args.extend(args.iter());
+        Opts::try_parse_from(full_args)?.try_into()
+    }
+
+    #[test]
+    fn test_scheduler_options() {
+        let options = parse_args(&[
+            "--nats-url",
+            "nats://foo@bar",
+            "--cluster",
+            "spawner.test",
+            "--cluster",
+            "eu.spawner.test",
+            "--scheduling-strategy",
+            "eu.spawner.test=bin-pack",
+            "--heartbeat-timeout",
+            "30",
+            "--admin-address",
+            "127.0.0.1:9091",
+        ])
+        .unwrap();
+
+        assert_eq!(
+            SchedulerOptions {
+                nats: NatsConnection::new("nats://foo@bar".to_string()).unwrap(),
+                clusters: vec!["spawner.test".to_string(), "eu.spawner.test".to_string()],
+                strategies: vec![("eu.spawner.test".to_string(), SchedulingStrategy::BinPack)],
+                heartbeat_timeout: Duration::from_secs(30),
+                admin_address: Some("127.0.0.1:9091".parse().unwrap()),
+            },
+            options
+        );
+    }
+
+    #[test]
+    fn test_scheduler_options_invalid() {
+        assert!(parse_args(&["--nats-url", "nats://foo@bar"]).is_err());
+        assert!(parse_args(&[
+            "--nats-url",
+            "nats://foo@bar",
+            "--cluster",
+            "spawner.test",
+            "--heartbeat-timeout",
+            "soon",
+        ])
+        .is_err());
+        assert!(parse_args(&[
+            "--nats-url",
+            "nats://foo@bar",
+            "--cluster",
+            "spawner.test",
+            "--scheduling-strategy",
+            "spawner.test=random",
+        ])
+        .is_err());
+    }
+}
@@ -0,0 +1,1267 @@
+//! Placement of backends on drones. `service::serve_scheduler`, which the
+//! `spawner-scheduler` binary runs, wires the scheduler to NATS.
+//!
+//! The scheduler keeps the latest heartbeat of each drone, and places each backend on
+//! one of its cluster's drones with room for it, chosen by the cluster's
+//! `PlacementPolicy`. Drones which are not ready, have not sent a heartbeat recently,
+//! or lack room for the backend are passed over; if none is left, the spawn is
+//! rejected with a `ScheduleError` rather than sent to a drone which would refuse it.
+//! A spawn request with the key of a backend the scheduler placed goes to that
+//! backend's drone, which connects it to the backend.
+//!
+//! Drones can be taken out of service for upgrades without touching their hosts, through
+//! the admin API. A cordoned drone is passed over, but keeps running its backends;
+//! draining also asks the drone to drain, so that it reports `Drained` once its backends
+//! have finished.
+//!
+//! The scheduler also remembers where each backend it placed runs, until the backend
+//! terminates. When a drone stops sending heartbeats, `recover_lost_backends` reports
+//! its backends `Lost`, and respawns those whose spawn requests are `reschedulable`.
+//!
+//! So that more drones can be launched before spawns are turned away, demand the
+//! clusters could not meet is published on NATS by `publish_demand`, and served to
+//! Prometheus by `render_metrics`. The drones, backends, and backends' state changes
+//! the scheduler knows of are served as JSON by `admin::serve_admin`, along with the
+//! metrics.
+
+use self::policy::{headroom, PlacementPolicy, SchedulingStrategy, Spread};
+use crate::{
+    messages::agent::{
+        BackendState, BackendStateMessage, BackendTermination, DrainRequest, DroneConnectRequest,
+        DroneState, DroneStatusMessage, SpawnRequest, SpawnResponse, TerminationReason,
+    },
+    messages::scheduler::ClusterDemandMessage,
+    nats::TypedNats,
+    types::{BackendId, DroneId},
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    fmt::Write,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+pub mod admin;
+pub mod cli;
+pub mod policy;
+pub mod service;
+
+pub use crate::messages::scheduler::ScheduleError;
+
+/// How long after its last heartbeat a drone is no longer scheduled on. Drones send one
+/// every few seconds.
+pub const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Drones whose CPU is less idle than this are considered full.
+const MIN_CPU_IDLE_PERCENT: f64 = 5.0;
+
+/// How many heartbeats a drone sends without reporting a backend, after the backend's
+/// spawn request could not be confirmed as delivered, before the backend is forgotten.
+const UNCONFIRMED_HEARTBEATS: u32 = 2;
+
+/// How many terminated backends' state changes are kept.
+const TERMINATED_HISTORY: usize = 1000;
+
+/// A drone as the scheduler last saw it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DroneSummary {
+    pub drone_id: DroneId,
+    pub cluster: String,
+    pub state: DroneState,
+
+    /// Whether the drone has been cordoned, so that no backends are placed on it.
+    pub cordoned: bool,
+
+    /// Whether the drone has sent a heartbeat recently.
+    pub live: bool,
+
+    pub capacity: u32,
+    pub running_backends: u32,
+    pub max_backends: Option<u32>,
+    pub version: String,
+    pub labels: HashMap<String, String>,
+
+    /// What the drone registered with, if it registered since the scheduler started.
+    pub registration: Option<DroneConnectRequest>,
+}
+
+/// A backend the scheduler placed, which has not yet terminated.
+#[derive(Debug, Clone)]
+pub struct Placement {
+    pub drone_id: DroneId,
+    pub cluster: String,
+    pub spawn_request: SpawnRequest,
+
+    /// The backend's latest state, once its drone has reported one.
+    pub state: Option<BackendState>,
+    pub spawned_at: DateTime<Utc>,
+}
+
+/// A backend which has not yet terminated, for listing.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackendSummary {
+    pub backend_id: BackendId,
+    pub drone_id: DroneId,
+    pub cluster: String,
+    pub state: Option<BackendState>,
+    pub spawned_at: DateTime<Utc>,
+    pub age_secs: i64,
+}
+
+/// A change of a backend's state.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackendEvent {
+    pub state: BackendState,
+    pub time: DateTime<Utc>,
+    pub termination: Option<BackendTermination>,
+}
+
+/// A metric's name, help text, and value for a cluster.
+type Gauge = (&'static str, &'static str, fn(&ClusterDemandMessage) -> f64);
+
+struct DroneRecord {
+    status: DroneStatusMessage,
+    last_seen: Instant,
+}
+
+impl DroneRecord {
+    /// Whether backends may be placed on the drone, if it has room.
+    fn available(&self, cordoned: &HashSet<DroneId>, heartbeat_timeout: Duration) -> bool {
+        self.status.state == DroneState::Ready
+            && !cordoned.contains(&self.status.drone_id)
+            && self.last_seen.elapsed() <= heartbeat_timeout
+    }
+
+    /// Whether the drone has each of the selector's labels, with the same value.
+    fn matches(&self, selector: &HashMap<String, String>) -> bool {
+        selector
+            .iter()
+            .all(|(key, value)| self.status.labels.get(key) == Some(value))
+    }
+
+    /// Whether the drone has room for a backend with the given memory limit.
+    fn fits(&self, memory_bytes: u64) -> bool {
+        let status = &self.status;
+        status.capacity > 0
+            && status
+                .cpu_idle_percent
+                .is_none_or(|idle| idle >= MIN_CPU_IDLE_PERCENT)
+            && status
+                .memory_available_bytes
+                .is_none_or(|available| available >= memory_bytes)
+    }
+
+    /// Account for a backend placed on the drone, until its next heartbeat reports it.
+    fn reserve(&mut self, memory_bytes: u64) {
+        let status = &mut self.status;
+        status.capacity = status.capacity.saturating_sub(1);
+        status.running_backends += 1;
+        if let Some(available) = &mut status.memory_available_bytes {
+            *available = available.saturating_sub(memory_bytes);
+        }
+    }
+}
+
+pub struct Scheduler {
+    drones: HashMap<DroneId, DroneRecord>,
+    cordoned: HashSet<DroneId>,
+    registrations: HashMap<DroneId, DroneConnectRequest>,
+    policies: HashMap<String, Arc<dyn PlacementPolicy>>,
+    backends: HashMap<BackendId, Placement>,
+
+    /// State changes of placed backends, and of the most recently terminated.
+    history: HashMap<BackendId, Vec<BackendEvent>>,
+    terminated: VecDeque<BackendId>,
+
+    /// Spawns rejected for each cluster in total, and since demand was last published.
+    rejected_spawns: HashMap<String, (u64, u64)>,
+    heartbeat_timeout: Duration,
+
+    /// Backends whose spawn requests may not have reached their drones, and how many
+    /// heartbeats their drones have sent since without reporting them.
+    unconfirmed: HashMap<BackendId, u32>,
+
+    /// The highest drone ID given out by `next_drone_id`.
+    last_drone_id: u32,
+
+    /// Drain requests to send to drones, and whether each is to drain or resume.
+    drain_requests: Vec<(DroneId, bool)>,
+}
+
+impl Scheduler {
+    pub fn new(heartbeat_timeout: Duration) -> Self {
+        Scheduler {
+            drones: HashMap::new(),
+            cordoned: HashSet::new(),
+            registrations: HashMap::new(),
+            policies: HashMap::new(),
+            backends: HashMap::new(),
+            history: HashMap::new(),
+            terminated: VecDeque::new(),
+            rejected_spawns: HashMap::new(),
+            heartbeat_timeout,
+            unconfirmed: HashMap::new(),
+            last_drone_id: 0,
+            drain_requests: Vec::new(),
+        }
+    }
+
+    /// An ID for a drone joining, higher than that of any drone the scheduler has given
+    /// an ID or heard from. After a restart, the scheduler only learns of the drones
+    /// already running from their heartbeats, so registrations should wait for those.
+    pub fn next_drone_id(&mut self) -> DroneId {
+        let highest = self
+            .drones
+            .keys()
+            .chain(self.registrations.keys())
+            .map(DroneId::id)
+            .max()
+            .unwrap_or_default();
+        self.last_drone_id = self.last_drone_id.max(highest) + 1;
+        DroneId::new(self.last_drone_id)
+    }
+
+    /// Record what a drone registered with, once it has been given its ID.
+    pub fn register(&mut self, drone_id: DroneId, request: DroneConnectRequest) {
+        self.registrations.insert(drone_id, request);
+    }
+
+    /// Record a drone's heartbeat, replacing its previous one. Backends placed on the
+    /// drone whose spawn requests were not confirmed are forgotten once the drone has
+    /// sent `UNCONFIRMED_HEARTBEATS` heartbeats without reporting them.
+    pub fn heartbeat(&mut self, status: DroneStatusMessage) {
+        let mut not_spawned = Vec::new();
+        for (backend_id, heartbeats) in &mut self.unconfirmed {
+            if self
+                .backends
+                .get(backend_id)
+                .is_some_and(|placement| placement.drone_id == status.drone_id)
+            {
+                *heartbeats += 1;
+                if *heartbeats >= UNCONFIRMED_HEARTBEATS {
+                    not_spawned.push(backend_id.clone());
+                }
+            }
+        }
+        for backend_id in &not_spawned {
+            tracing::warn!(%backend_id, drone_id = status.drone_id.id(), "Drone did not report unconfirmed backend.");
+            self.unschedule(backend_id);
+        }
+
+        self.drones.insert(
+            status.drone_id,
+            DroneRecord {
+                status,
+                last_seen: Instant::now(),
+            },
+        );
+    }
+
+    /// Record a backend's change of state, forgetting it once it has terminated.
+    pub fn backend_state(&mut self, backend_id: &BackendId, message: &BackendStateMessage) {
+        self.unconfirmed.remove(backend_id);
+        let history = match self.history.get_mut(backend_id) {
+            Some(history) => history,
+            None => return,
+        };
+        history.push(BackendEvent {
+            state: message.state,
+            time: message.time,
+            termination: message.termination.clone(),
+        });
+
+        if message.state.terminal() {
+            self.backends.remove(backend_id);
+            self.terminate(backend_id);
+        } else if let Some(placement) = self.backends.get_mut(backend_id) {
+            placement.state = Some(message.state);
+        }
+    }
+
+    /// Keep the terminated backend's history among the most recently terminated.
+    fn terminate(&mut self, backend_id: &BackendId) {
+        self.terminated.push_back(backend_id.clone());
+        while self.terminated.len() > TERMINATED_HISTORY {
+            if let Some(forgotten) = self.terminated.pop_front() {
+                self.history.remove(&forgotten);
+            }
+        }
+    }
+
+    /// Forget a backend placed by `schedule` which was not spawned, because its drone
+    /// refused it or could not be reached. A backend with no history is forgotten
+    /// entirely.
+    pub fn unschedule(&mut self, backend_id: &BackendId) {
+        self.unconfirmed.remove(backend_id);
+        if self.backends.remove(backend_id).is_none() {
+            return;
+        }
+        if self.history.get(backend_id).is_some_and(Vec::is_empty) {
+            self.history.remove(backend_id);
+        } else {
+            self.terminate(backend_id);
+        }
+    }
+
+    /// Settle a backend placed by `schedule` by its drone's response to the spawn
+    /// request, or None if the drone could not be reached. A backend the drone refused
+    /// is unscheduled. An unreachable drone may still have received the request, so the
+    /// backend stays placed until the drone either reports it or sends heartbeats
+    /// without doing so.
+    pub fn spawn_response(&mut self, backend_id: &BackendId, response: Option<&SpawnResponse>) {
+        match response {
+            Some(SpawnResponse::Accepted) => (),
+            Some(SpawnResponse::AtCapacity { .. } | SpawnResponse::Draining) => {
+                self.unschedule(backend_id)
+            }
+            // The drone runs another backend in place of this one.
+            Some(SpawnResponse::Existing {
+                backend_id: existing,
+            }) if existing != backend_id => self.unschedule(backend_id),
+            Some(SpawnResponse::Existing { .. }) => (),
+            None => {
+                if self
+                    .backends
+                    .get(backend_id)
+                    .is_some_and(|placement| placement.state.is_none())
+                {
+                    self.unconfirmed.insert(backend_id.clone(), 0);
+                }
+            }
+        }
+    }
+
+    /// The backends placed which have not yet terminated, oldest first.
+    pub fn backends(&self) -> Vec<BackendSummary> {
+        let now = Utc::now();
+        let mut backends: Vec<BackendSummary> = self
+            .backends
+            .iter()
+            .map(|(backend_id, placement)| BackendSummary {
+                backend_id: backend_id.clone(),
+                drone_id: placement.drone_id,
+                cluster: placement.cluster.clone(),
+                state: placement.state,
+                spawned_at: placement.spawned_at,
+                age_secs: (now - placement.spawned_at).num_seconds(),
+            })
+            .collect();
+        backends.sort_by_key(|backend| backend.spawned_at);
+        backends
+    }
+
+    /// The backend's state changes, if it was placed recently enough to be remembered.
+    pub fn backend_history(&self, backend_id: &BackendId) -> Option<&[BackendEvent]> {
+        self.history.get(backend_id).map(Vec::as_slice)
+    }
+
+    /// Forget the backends of drones which have stopped sending heartbeats, and return
+    /// them.
+    pub fn take_lost_backends(&mut self) -> Vec<Placement> {
+        let lost: Vec<BackendId> = self
+            .backends
+            .iter()
+            .filter(|(_, placement)| {
+                self.drones
+                    .get(&placement.drone_id)
+                    .is_none_or(|drone| drone.last_seen.elapsed() > self.heartbeat_timeout)
+            })
+            .map(|(backend_id, _)| backend_id.clone())
+            .collect();
+
+        lost.iter()
+            .filter_map(|backend_id| {
+                self.unconfirmed.remove(backend_id);
+                self.backends.remove(backend_id)
+            })
+            .collect()
+    }
+
+    /// Place the cluster's backends by the policy, rather than spreading them.
+    pub fn set_policy(&mut self, cluster: &str, policy: Arc<dyn PlacementPolicy>) {
+        self.policies.insert(cluster.to_string(), policy);
+    }
+
+    /// Place the cluster's backends by the built-in strategy.
+    pub fn set_strategy(&mut self, cluster: &str, strategy: SchedulingStrategy) {
+        self.set_policy(cluster, strategy.policy());
+    }
+
+    /// Stop (or with `cordon: false`, resume) placing backends on the drone. Returns
+    /// false if the scheduler has not heard from the drone.
+    pub fn cordon(&mut self, drone_id: DroneId, cordon: bool) -> bool {
+        if !self.drones.contains_key(&drone_id) {
+            return false;
+        }
+        if cordon {
+            self.cordoned.insert(drone_id);
+        } else {
+            self.cordoned.remove(&drone_id);
+        }
+        true
+    }
+
+    /// Cordon the drone and ask it to drain (or with `drain: false`, uncordon it and ask
+    /// it to resume accepting backends). The request is sent by `send_drain_requests`, and
+    /// the drone's progress shows in its heartbeats' state. Returns false if the scheduler
+    /// has not heard from the drone.
+    pub fn drain(&mut self, drone_id: DroneId, drain: bool) -> bool {
+        if !self.cordon(drone_id, drain) {
+            return false;
+        }
+        self.drain_requests.push((drone_id, drain));
+        true
+    }
+
+    /// The drain requests made since they were last taken, in order.
+    pub fn take_drain_requests(&mut self) -> Vec<(DroneId, bool)> {
+        std::mem::take(&mut self.drain_requests)
+    }
+
+    /// The drones the scheduler has heard from, in order of ID.
+    pub fn drones(&self) -> Vec<DroneSummary> {
+        let mut drones: Vec<DroneSummary> = self
+            .drones
+            .values()
+            .map(|drone| DroneSummary {
+                drone_id: drone.status.drone_id,
+                cluster: drone.status.cluster.clone(),
+                state: drone.status.state,
+                cordoned: self.cordoned.contains(&drone.status.drone_id),
+                live: drone.last_seen.elapsed() <= self.heartbeat_timeout,
+                capacity: drone.status.capacity,
+                running_backends: drone.status.running_backends,
+                max_backends: drone.status.max_backends,
+                version: drone.status.version.clone(),
+                labels: drone.status.labels.clone(),
+                registration: self.registrations.get(&drone.status.drone_id).cloned(),
+            })
+            .collect();
+        drones.sort_by_key(|drone| drone.drone_id.id());
+        drones
+    }
+
+    /// Pick the drone to run the backend on, from the cluster the spawn request names
+    /// or else the default cluster, and count the backend against its capacity. If a
+    /// backend with the spawn request's key was placed in the cluster and has not
+    /// terminated, its drone is picked instead, and nothing is counted.
+    pub fn schedule(
+        &mut self,
+        default_cluster: &str,
+        spawn_request: &SpawnRequest,
+    ) -> Result<DroneId, ScheduleError> {
+        let cluster = spawn_request.cluster.as_deref().unwrap_or(default_cluster);
+        if !self
+            .drones
+            .values()
+            .any(|drone| drone.status.cluster == cluster)
+        {
+            return Err(ScheduleError::NoSuchCluster {
+                cluster: cluster.to_string(),
+            });
+        }
+        if let Some(key) = &spawn_request.key {
+            if let Some(placement) = self.backends.values().find(|placement| {
+                placement.cluster == cluster && placement.spawn_request.key.as_ref() == Some(key)
+            }) {
+                return Ok(placement.drone_id);
+            }
+        }
+
+        let result = self.place(cluster, spawn_request);
+        if result.is_err() {
+            let (total, unpublished) = self.rejected_spawns.entry(cluster.to_string()).or_default();
+            *total += 1;
+            *unpublished += 1;
+        }
+        result
+    }
+
+    fn place(
+        &mut self,
+        cluster: &str,
+        spawn_request: &SpawnRequest,
+    ) -> Result<DroneId, ScheduleError> {
+        let memory_bytes = spawn_request
+            .resource_limits
+            .memory_limit_bytes
+            .unwrap_or_default()
+            .max(0) as u64;
+        let policy = self
+            .policies
+            .get(cluster)
+            .cloned()
+            .unwrap_or_else(|| Arc::new(Spread));
+        let heartbeat_timeout = self.heartbeat_timeout;
+        let cordoned = &self.cordoned;
+        let mut candidates: Vec<&mut DroneRecord> = self
+            .drones
+            .values_mut()
+            .filter(|drone| {
+                drone.status.cluster == cluster
+                    && drone.available(cordoned, heartbeat_timeout)
+                    && drone.matches(&spawn_request.drone_selector)
+            })
+            .collect();
+        if candidates.is_empty() {
+            return Err(ScheduleError::NoDrones {
+                cluster: cluster.to_string(),
+            });
+        }
+
+        candidates.retain(|drone| {
+            drone.fits(memory_bytes) && policy.filter(&drone.status, spawn_request)
+        });
+        // Ties go to the lowest drone ID, so that placement is predictable.
+        let drone = candidates
+            .into_iter()
+            .max_by(|a, b| {
+                policy
+                    .score(&a.status, spawn_request)
+                    .total_cmp(&policy.score(&b.status, spawn_request))
+                    .then_with(|| b.status.drone_id.id().cmp(&a.status.drone_id.id()))
+            })
+            .ok_or_else(|| ScheduleError::ClusterFull {
+                cluster: cluster.to_string(),
+            })?;
+        drone.reserve(memory_bytes);
+        let drone_id = drone.status.drone_id;
+        self.backends.insert(
+            spawn_request.backend_id.clone(),
+            Placement {
+                drone_id,
+                cluster: cluster.to_string(),
+                spawn_request: spawn_request.clone(),
+                state: None,
+                spawned_at: Utc::now(),
+            },
+        );
+        // A rescheduled backend keeps its history from before its drone was lost.
+        self.history
+            .entry(spawn_request.backend_id.clone())
+            .or_default();
+        self.terminated
+            .retain(|backend_id| backend_id != &spawn_request.backend_id);
+
+        Ok(drone_id)
+    }
+
+    /// The clusters the scheduler has heard from drones of.
+    fn clusters(&self) -> BTreeSet<String> {
+        self.drones
+            .values()
+            .map(|drone| drone.status.cluster.clone())
+            .collect()
+    }
+
+    /// The cluster's current demand, counting rejections since demand was last taken.
+    fn demand(&self, cluster: &str) -> ClusterDemandMessage {
+        let ready: Vec<&DroneStatusMessage> = self
+            .drones
+            .values()
+            .filter(|drone| {
+                drone.status.cluster == cluster
+                    && drone.available(&self.cordoned, self.heartbeat_timeout)
+            })
+            .map(|drone| &drone.status)
+            .collect();
+        let mean_headroom = if ready.is_empty() {
+            0.0
+        } else {
+            ready.iter().map(|drone| headroom(drone)).sum::<f64>() / ready.len() as f64
+        };
+
+        ClusterDemandMessage {
+            cluster: cluster.to_string(),
+            time: Utc::now(),
+            rejected_spawns: self
+                .rejected_spawns
+                .get(cluster)
+                .map_or(0, |(_, unpublished)| *unpublished),
+            ready_drones: ready.len() as u32,
+            free_slots: ready.iter().map(|drone| drone.capacity).sum(),
+            mean_headroom,
+        }
+    }
+
+    /// Each cluster's demand, resetting the count of rejections since demand was last
+    /// taken.
+    pub fn take_demand(&mut self) -> Vec<ClusterDemandMessage> {
+        let demand = self
+            .clusters()
+            .iter()
+            .map(|cluster| self.demand(cluster))
+            .collect();
+        for (_, unpublished) in self.rejected_spawns.values_mut() {
+            *unpublished = 0;
+        }
+        demand
+    }
+
+    /// The clusters' demand as Prometheus metrics, in the text exposition format.
+    pub fn render_metrics(&self) -> Result<String, std::fmt::Error> {
+        let demand: Vec<ClusterDemandMessage> = self
+            .clusters()
+            .iter()
+            .map(|cluster| self.demand(cluster))
+            .collect();
+        let mut out = String::new();
+
+        writeln!(out, "# HELP spawner_scheduler_rejected_spawns_total Spawn requests rejected because no drone had room for them.")?;
+        writeln!(
+            out,
+            "# TYPE spawner_scheduler_rejected_spawns_total counter"
+        )?;
+        for demand in &demand {
+            let (total, _) = self
+                .rejected_spawns
+                .get(&demand.cluster)
+                .copied()
+                .unwrap_or_default();
+            writeln!(
+                out,
+                "spawner_scheduler_rejected_spawns_total{{cluster=\"{}\"}} {}",
+                demand.cluster, total
+            )?;
+        }
+
+        let gauges: [Gauge; 3] = [
+            (
+                "spawner_scheduler_ready_drones",
+                "Drones which are ready to run backends.",
+                |demand| f64::from(demand.ready_drones),
+            ),
+            (
+                "spawner_scheduler_free_slots",
+                "Backends the ready drones will accept.",
+                |demand| f64::from(demand.free_slots),
+            ),
+            (
+                "spawner_scheduler_mean_headroom",
+                "Mean fraction free of the ready drones' most used resource.",
+                |demand| demand.mean_headroom,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            writeln!(out, "# HELP {} {}", name, help)?;
+            writeln!(out, "# TYPE {} gauge", name)?;
+            for demand in &demand {
+                writeln!(
+                    out,
+                    "{}{{cluster=\"{}\"}} {}",
+                    name,
+                    demand.cluster,
+                    value(demand)
+                )?;
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/// Publish each cluster's demand. Called periodically by `serve_scheduler`.
+pub async fn publish_demand(
+    nats: &TypedNats,
+    scheduler: &std::sync::Mutex<Scheduler>,
+) -> Result<()> {
+    let demand = scheduler
+        .lock()
+        .expect("Scheduler lock was poisoned.")
+        .take_demand();
+    for demand in demand {
+        if demand.rejected_spawns > 0 {
+            tracing::info!(cluster = %demand.cluster, rejected_spawns = demand.rejected_spawns, "Spawns were rejected for capacity.");
+        }
+        nats.publish(&ClusterDemandMessage::subject(&demand.cluster), &demand)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Send drones the drain requests made of the scheduler. Errors are logged, and the
+/// request can be made again. Called periodically by `serve_scheduler`.
+pub async fn send_drain_requests(nats: &TypedNats, scheduler: &std::sync::Mutex<Scheduler>) {
+    let requests = scheduler
+        .lock()
+        .expect("Scheduler lock was poisoned.")
+        .take_drain_requests();
+
+    for (drone_id, drain) in requests {
+        match nats
+            .request(&DrainRequest::subject(drone_id), &DrainRequest { drain })
+            .await
+        {
+            Ok(_) => tracing::info!(
+                drone_id = drone_id.id(),
+                drain,
+                "Sent drain request to drone."
+            ),
+            Err(error) => {
+                tracing::warn!(
+                    drone_id = drone_id.id(),
+                    ?error,
+                    "Error sending drain request to drone."
+                )
+            }
+        }
+    }
+}
+
+/// Report the backends of lost drones `Lost`, and respawn those which are reschedulable
+/// on other drones of their clusters. Errors are logged, so that one backend does not
+/// hold up the others. Called periodically by `serve_scheduler`.
+pub async fn recover_lost_backends(nats: &TypedNats, scheduler: &std::sync::Mutex<Scheduler>) {
+    let lost = scheduler
+        .lock()
+        .expect("Scheduler lock was poisoned.")
+        .take_lost_backends();
+
+    for placement in lost {
+        let backend_id = &placement.spawn_request.backend_id;
+        tracing::warn!(%backend_id, drone_id = placement.drone_id.id(), "Backend's drone was lost.");
+        let message = BackendStateMessage {
+            previous_state: placement.state,
+            termination: Some(BackendTermination {
+                reason: TerminationReason::DroneLost,
+                exit_code: None,
+                oom_killed: false,
+                error: None,
+            }),
+            labels: placement.spawn_request.labels.clone(),
+            ..BackendStateMessage::new(BackendState::Lost)
+        };
+        scheduler
+            .lock()
+            .expect("Scheduler lock was poisoned.")
+            .backend_state(backend_id, &message);
+        if let Err(error) = nats
+            .publish(&BackendStateMessage::subject(backend_id), &message)
+            .await
+        {
+            tracing::warn!(%backend_id, ?error, "Error publishing lost backend's state.");
+        }
+
+        if !placement.spawn_request.reschedulable {
+            continue;
+        }
+        let scheduled = scheduler
+            .lock()
+            .expect("Scheduler lock was poisoned.")
+            .schedule(&placement.cluster, &placement.spawn_request);
+        let drone_id = match scheduled {
+            Ok(drone_id) => drone_id,
+            Err(error) => {
+                tracing::warn!(%backend_id, %error, "Could not reschedule backend.");
+                continue;
+            }
+        };
+        let response = match nats
+            .request(&SpawnRequest::subject(drone_id), &placement.spawn_request)
+            .await
+        {
+            Ok(SpawnResponse::Accepted) => {
+                tracing::info!(%backend_id, drone_id = drone_id.id(), "Rescheduled backend.");
+                continue;
+            }
+            Ok(response) => {
+                tracing::warn!(%backend_id, ?response, "Drone refused rescheduled backend.");
+                Some(response)
+            }
+            Err(error) => {
+                tracing::warn!(%backend_id, ?error, "Error sending rescheduled backend to drone.");
+                None
+            }
+        };
+        scheduler
+            .lock()
+            .expect("Scheduler lock was poisoned.")
+            .spawn_response(backend_id, response.as_ref());
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    pub fn status(drone_id: u32, capacity: u32, memory_available_bytes: u64) -> DroneStatusMessage {
+        DroneStatusMessage {
+            drone_id: DroneId::new(drone_id),
+            cluster: "spawner.test".to_string(),
+            capacity,
+            state: DroneState::Ready,
+            running_backends: 10 - capacity,
+            max_backends: Some(10),
+            cpu_idle_percent: Some(50.0),
+            memory_available_bytes: Some(memory_available_bytes),
+            memory_total_bytes: Some(16 << 30),
+            version: String::new(),
+            labels: HashMap::new(),
+        }
+    }
+
+    fn spawn_request(memory_limit_bytes: Option<i64>) -> SpawnRequest {
+        let mut spawn_request: SpawnRequest = serde_json::from_value(serde_json::json!({
+            "image": "image",
+            "backend_id": "backend",
+            "max_idle_secs": 60,
+            "env": {},
+            "metadata": {},
+        }))
+        .unwrap();
+        spawn_request.resource_limits.memory_limit_bytes = memory_limit_bytes;
+        spawn_request
+    }
+
+    #[test]
+    fn test_schedule() {
+        let mut scheduler = Scheduler::new(DEFAULT_HEARTBEAT_TIMEOUT);
+        assert_eq!(
+            Err(ScheduleError::NoSuchCluster {
+                cluster: "spawner.test".to_string()
+            }),
+            scheduler.schedule("spawner.test", &spawn_request(None))
+        );
+
+        scheduler.heartbeat(status(1, 2, 8 << 30));
+        scheduler.heartbeat(status(2, 5, 4 << 30));
+        // Drone 2 has more free slots, and enough memory to spare.
+        assert_eq!(
+            Ok(DroneId::new(2)),
+            scheduler.schedule("spawner.test", &spawn_request(Some(1 << 30)))
+        );
+        // Only drone 1 has the memory.
+        assert_eq!(
+            Ok(DroneId::new(1)),
+            scheduler.schedule("spawner.test", &spawn_request(Some(6 << 30)))
+        );
+        // Drone 1 has no memory left to spare, nor drone 2 enough.
+        assert_eq!(
+            Err(ScheduleError::ClusterFull {
+                cluster: "spawner.test".to_string()
+            }),
+            scheduler.schedule("spawner.test", &spawn_request(Some(4 << 30)))
+        );
+        assert!(scheduler
+            .schedule("other.test", &spawn_request(None))
+            .is_err());
+    }
+
+    #[test]
+    fn test_key() {
+        let mut scheduler = Scheduler::new(DEFAULT_HEARTBEAT_TIMEOUT);
+        scheduler.heartbeat(status(1, 2, 8 << 30));
+        scheduler.heartbeat(status(2, 5, 8 << 30));
+        let mut keyed = spawn_request(None);
+        keyed.key = Some("session".to_string());
+        assert_eq!(
+            Ok(DroneId::new(2)),
+            scheduler.schedule("spawner.test", &keyed)
+        );
+
+        // Drone 1 now has more room, but the key's backend is on drone 2.
+        scheduler.heartbeat(status(1, 9, 8 << 30));
+        keyed.backend_id = BackendId::new("other-backend".to_string());
+        assert_eq!(
+            Ok(DroneId::new(2)),
+            scheduler.schedule("spawner.test", &keyed)
+        );
+        assert_eq!(1, scheduler.backends().len());
+
+        // Once the key's backend terminates, the key is placed afresh.
+        scheduler.backend_state(
+            &BackendId::new("backend".to_string()),
+            &BackendStateMessage::new(BackendState::Exited),
+        );
+        assert_eq!(
+            Ok(DroneId::new(1)),
+            scheduler.schedule("spawner.test", &keyed)
+        );
+    }
+
+    #[test]
+    fn test_bin_pack() {
+        let mut scheduler = Scheduler::new(DEFAULT_HEARTBEAT_TIMEOUT);
+        scheduler.set_strategy("spawner.test", SchedulingStrategy::BinPack);
+        scheduler.heartbeat(status(1, 2, 8 << 30));
+        scheduler.heartbeat(status(2, 5, 8 << 30));
+
+        // The fuller drone is filled before the other is used.
+        for _ in 0..2 {
+            assert_eq!(
+                Ok(DroneId::new(1)),
+                scheduler.schedule("spawner.test", &spawn_request(None))
+            );
+        }
+        assert_eq!(
+            Ok(DroneId::new(2)),
+            scheduler.schedule("spawner.test", &spawn_request(None))
+        );
+    }
+
+    /// Prefers drones in the region the spawn request's metadata names, and places
+    /// backends only on drones with a known region.
+    struct NearestRegion;
+
+    impl PlacementPolicy for NearestRegion {
+        fn filter(&self, drone: &DroneStatusMessage, _spawn_request: &SpawnRequest) -> bool {
+            drone.labels.contains_key("region")
+        }
+
+        fn score(&self, drone: &DroneStatusMessage, spawn_request: &SpawnRequest) -> f64 {
+            let near = drone.labels.get("region") == spawn_request.metadata.get("region");
+            if near {
+                1.0
+            } else {
+                0.0
+            }
+        }
+    }
+
+    #[test]
+    fn test_placement_policy() {
+        let mut scheduler = Scheduler::new(DEFAULT_HEARTBEAT_TIMEOUT);
+        scheduler.set_policy("spawner.test", Arc::new(NearestRegion));
+        let with_region = |drone_id: u32, region: &str| DroneStatusMessage {
+            labels: vec![("region".to_string(), region.to_string())]
+                .into_iter()
+                .collect(),
+            ..status(drone_id, 5, 8 << 30)
+        };
+        scheduler.heartbeat(status(1, 5, 8 << 30));
+        scheduler.heartbeat(with_region(2, "eu"));
+        scheduler.heartbeat(with_region(3, "us"));
+
+        let mut spawn_request = spawn_request(None);
+        spawn_request
+            .metadata
+            .insert("region".to_string(), "us".to_string());
+        assert_eq!(
+            Ok(DroneId::new(3)),
+            scheduler.schedule("spawner.test", &spawn_request)
+        );
+        spawn_request
+            .metadata
+            .insert("region".to_string(), "ap".to_string());
+        assert_eq!(
+            Ok(DroneId::new(2)),
+            scheduler.schedule("spawner.test", &spawn_request)
+        );
+    }
+
+    #[test]
+    fn test_next_drone_id() {
+        let mut scheduler = Scheduler::new(DEFAULT_HEARTBEAT_TIMEOUT);
+        assert_eq!(DroneId::new(1), scheduler.next_drone_id());
+        assert_eq!(DroneId::new(2), scheduler.next_drone_id());
+
+        // Drones which registered before the scheduler started keep their IDs.
+        scheduler.heartbeat(status(7, 5, 8 << 30));
+        assert_eq!(DroneId::new(8), scheduler.next_drone_id());
+        scheduler.register(
+            DroneId::new(12),
+            serde_json::from_value(serde_json::json!({
+                "cluster": "spawner.test",
+                "ip": "10.0.0.12",
+            }))
+            .unwrap(),
+        );
+        assert_eq!(DroneId::new(13), scheduler.next_drone_id());
+    }
+
+    #[test]
+    fn test_take_lost_backends() {
+        let mut scheduler = Scheduler::new(Duration::from_millis(50));
+        scheduler.heartbeat(status(1, 5, 8 << 30));
+        assert_eq!(
+            Ok(DroneId::new(1)),
+            scheduler.schedule("spawner.test", &spawn_request(None))
+        );
+        assert!(scheduler.take_lost_backends().is_empty());
+
+        std::thread::sleep(Duration::from_millis(100));
+        let lost = scheduler.take_lost_backends();
+        assert_eq!(1, lost.len());
+        assert_eq!("backend", lost[0].spawn_request.backend_id.id());
+        // Each lost backend is only reported once.
+        assert!(scheduler.take_lost_backends().is_empty());
+
+        // Terminated backends are forgotten, and not reported lost.
+        scheduler.heartbeat(status(1, 5, 8 << 30));
+        scheduler
+            .schedule("spawner.test", &spawn_request(None))
+            .unwrap();
+        scheduler.backend_state(
+            &BackendId::new("backend".to_string()),
+            &BackendStateMessage::new(BackendState::Exited),
+        );
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(scheduler.take_lost_backends().is_empty());
+    }
+
+    #[test]
+    fn test_unschedule() {
+        let mut scheduler = Scheduler::new(DEFAULT_HEARTBEAT_TIMEOUT);
+        let backend_id = BackendId::new("backend".to_string());
+        scheduler.heartbeat(status(1, 5, 8 << 30));
+
+        // A backend its drone refused is forgotten entirely.
+        scheduler
+            .schedule("spawner.test", &spawn_request(None))
+            .unwrap();
+        scheduler.unschedule(&backend_id);
+        assert!(scheduler.backends().is_empty());
+        assert!(scheduler.backend_history(&backend_id).is_none());
+
+        // A refused reschedule keeps the backend's history from its lost drone.
+        scheduler
+            .schedule("spawner.test", &spawn_request(None))
+            .unwrap();
+        scheduler.backend_state(&backend_id, &BackendStateMessage::new(BackendState::Lost));
+        scheduler
+            .schedule("spawner.test", &spawn_request(None))
+            .unwrap();
+        scheduler.unschedule(&backend_id);
+        assert!(scheduler.backends().is_empty());
+        assert_eq!(1, scheduler.backend_history(&backend_id).unwrap().len());
+    }
+
+    #[test]
+    fn test_spawn_response() {
+        let mut scheduler = Scheduler::new(DEFAULT_HEARTBEAT_TIMEOUT);
+        let backend_id = BackendId::new("backend".to_string());
+        scheduler.heartbeat(status(1, 5, 8 << 30));
+
+        for response in [
+            SpawnResponse::AtCapacity { max_backends: 10 },
+            SpawnResponse::Draining,
+            SpawnResponse::Existing {
+                backend_id: BackendId::new("other".to_string()),
+            },
+        ] {
+            scheduler
+                .schedule("spawner.test", &spawn_request(None))
+                .unwrap();
+            scheduler.spawn_response(&backend_id, Some(&response));
+            assert!(scheduler.backends().is_empty(), "{:?}", response);
+        }
+        for response in [
+            SpawnResponse::Accepted,
+            SpawnResponse::Existing {
+                backend_id: backend_id.clone(),
+            },
+        ] {
+            scheduler
+                .schedule("spawner.test", &spawn_request(None))
+                .unwrap();
+            scheduler.spawn_response(&backend_id, Some(&response));
+            assert_eq!(1, scheduler.backends().len(), "{:?}", response);
+            scheduler.unschedule(&backend_id);
+        }
+    }
+
+    #[test]
+    fn test_spawn_response_unreachable() {
+        let mut scheduler = Scheduler::new(DEFAULT_HEARTBEAT_TIMEOUT);
+        let backend_id = BackendId::new("backend".to_string());
+        scheduler.heartbeat(status(1, 5, 8 << 30));
+        scheduler.heartbeat(status(2, 2, 8 << 30));
+
+        // The backend stays placed until its drone has sent heartbeats without
+        // reporting it.
+        scheduler
+            .schedule("spawner.test", &spawn_request(None))
+            .unwrap();
+        scheduler.spawn_response(&backend_id, None);
+        scheduler.heartbeat(status(2, 2, 8 << 30));
+        scheduler.heartbeat(status(2, 2, 8 << 30));
+        scheduler.heartbeat(status(1, 5, 8 << 30));
+        assert_eq!(1, scheduler.backends().len());
+        scheduler.heartbeat(status(1, 5, 8 << 30));
+        assert!(scheduler.backends().is_empty());
+        assert!(scheduler.backend_history(&backend_id).is_none());
+
+        // A backend its drone reports was spawned after all.
+        scheduler
+            .schedule("spawner.test", &spawn_request(None))
+            .unwrap();
+        scheduler.spawn_response(&backend_id, None);
+        scheduler.heartbeat(status(1, 5, 8 << 30));
+        scheduler.backend_state(
+            &backend_id,
+            &BackendStateMessage::new(BackendState::Loading),
+        );
+        scheduler.heartbeat(status(1, 5, 8 << 30));
+        scheduler.heartbeat(status(1, 5, 8 << 30));
+        assert_eq!(1, scheduler.backends().len());
+    }
+
+    #[test]
+    fn test_spawn_request_cluster() {
+        let mut scheduler = Scheduler::new(DEFAULT_HEARTBEAT_TIMEOUT);
+        scheduler.heartbeat(status(1, 5, 8 << 30));
+        scheduler.heartbeat(DroneStatusMessage {
+            cluster: "eu.spawner.test".to_string(),
+            ..status(2, 5, 8 << 30)
+        });
+
+        let mut spawn_request = spawn_request(None);
+        assert_eq!(
+            Ok(DroneId::new(1)),
+            scheduler.schedule("spawner.test", &spawn_request)
+        );
+        spawn_request.cluster = Some("eu.spawner.test".to_string());
+        assert_eq!(
+            Ok(DroneId::new(2)),
+            scheduler.schedule("spawner.test", &spawn_request)
+        );
+        spawn_request.cluster = Some("us.spawner.test".to_string());
+        assert_eq!(
+            Err(ScheduleError::NoSuchCluster {
+                cluster: "us.spawner.test".to_string()
+            }),
+            scheduler.schedule("spawner.test", &spawn_request)
+        );
+    }
+
+    #[test]
+    fn test_demand() {
+        let mut scheduler = Scheduler::new(DEFAULT_HEARTBEAT_TIMEOUT);
+        scheduler.heartbeat(status(1, 1, 8 << 30));
+        scheduler.heartbeat(status(2, 1, 8 << 30));
+        for _ in 0..3 {
+            let _ = scheduler.schedule("spawner.test", &spawn_request(None));
+        }
+
+        let demand = scheduler.take_demand();
+        assert_eq!(1, demand.len());
+        assert_eq!(1, demand[0].rejected_spawns);
+        assert_eq!(2, demand[0].ready_drones);
+        assert_eq!(0, demand[0].free_slots);
+        assert_eq!(0, scheduler.take_demand()[0].rejected_spawns);
+
+        let metrics = scheduler.render_metrics().unwrap();
+        assert!(metrics
+            .contains("spawner_scheduler_rejected_spawns_total{cluster=\"spawner.test\"} 1\n"));
+        assert!(metrics.contains("spawner_scheduler_free_slots{cluster=\"spawner.test\"} 0\n"));
+    }
+
+    #[test]
+    fn test_backend_history() {
+        let mut scheduler = Scheduler::new(DEFAULT_HEARTBEAT_TIMEOUT);
+        scheduler.heartbeat(status(1, 5, 8 << 30));
+        scheduler
+            .schedule("spawner.test", &spawn_request(None))
+            .unwrap();
+        let backend_id = BackendId::new("backend".to_string());
+        scheduler.backend_state(
+            &backend_id,
+            &BackendStateMessage::new(BackendState::Loading),
+        );
+
+        let backends = scheduler.backends();
+        assert_eq!(1, backends.len());
+        assert_eq!(Some(BackendState::Loading), backends[0].state);
+        assert_eq!(DroneId::new(1), backends[0].drone_id);
+
+        // Terminated backends are no longer listed, but their history is kept.
+        scheduler.backend_state(&backend_id, &BackendStateMessage::new(BackendState::Swept));
+        assert!(scheduler.backends().is_empty());
+        let states: Vec<BackendState> = scheduler
+            .backend_history(&backend_id)
+            .unwrap()
+            .iter()
+            .map(|event| event.state)
+            .collect();
+        assert_eq!(vec![BackendState::Loading, BackendState::Swept], states);
+
+        // Backends the scheduler did not place are ignored.
+        let other = BackendId::new("other".to_string());
+        scheduler.backend_state(&other, &BackendStateMessage::new(BackendState::Loading));
+        assert!(scheduler.backend_history(&other).is_none());
+    }
+
+    #[test]
+    fn test_registration() {
+        let mut scheduler = Scheduler::new(DEFAULT_HEARTBEAT_TIMEOUT);
+        let registration = DroneConnectRequest {
+            cluster: "spawner.test".to_string(),
+            ip: "10.0.0.1".parse().unwrap(),
+            labels: HashMap::new(),
+            architecture: "aarch64".to_string(),
+            engine: "firecracker".to_string(),
+        };
+        scheduler.register(DroneId::new(1), registration.clone());
+        scheduler.heartbeat(status(1, 5, 8 << 30));
+        scheduler.heartbeat(status(2, 5, 8 << 30));
+
+        let registrations: Vec<Option<DroneConnectRequest>> = scheduler
+            .drones()
+            .into_iter()
+            .map(|drone| drone.registration)
+            .collect();
+        assert_eq!(vec![Some(registration), None], registrations);
+        // The drone's backend limit comes from its heartbeats.
+        assert_eq!(Some(10), scheduler.drones()[0].max_backends);
+    }
+
+    #[test]
+    fn test_cordon() {
+        let mut scheduler = Scheduler::new(DEFAULT_HEARTBEAT_TIMEOUT);
+        assert!(!scheduler.cordon(DroneId::new(1), true));
+
+        scheduler.heartbeat(status(1, 5, 8 << 30));
+        scheduler.heartbeat(status(2, 2, 8 << 30));
+        assert!(scheduler.cordon(DroneId::new(1), true));
+        // The cordon outlasts the drone's next heartbeat.
+        scheduler.heartbeat(status(1, 5, 8 << 30));
+        assert_eq!(
+            Ok(DroneId::new(2)),
+            scheduler.schedule("spawner.test", &spawn_request(None))
+        );
+        let cordoned: Vec<bool> = scheduler
+            .drones()
+            .iter()
+            .map(|drone| drone.cordoned)
+            .collect();
+        assert_eq!(vec![true, false], cordoned);
+
+        scheduler.cordon(DroneId::new(1), false);
+        assert_eq!(
+            Ok(DroneId::new(1)),
+            scheduler.schedule("spawner.test", &spawn_request(None))
+        );
+    }
+
+    #[test]
+    fn test_drone_selector() {
+        let mut scheduler = Scheduler::new(DEFAULT_HEARTBEAT_TIMEOUT);
+        scheduler.heartbeat(status(1, 5, 8 << 30));
+        scheduler.heartbeat(DroneStatusMessage {
+            labels: vec![("gpu".to_string(), "true".to_string())]
+                .into_iter()
+                .collect(),
+            ..status(2, 1, 8 << 30)
+        });
+
+        let mut spawn_request = spawn_request(None);
+        spawn_request
+            .drone_selector
+            .insert("gpu".to_string(), "true".to_string());
+        assert_eq!(
+            Ok(DroneId::new(2)),
+            scheduler.schedule("spawner.test", &spawn_request)
+        );
+        // The only matching drone is now full, though drone 1 has room.
+        assert_eq!(
+            Err(ScheduleError::ClusterFull {
+                cluster: "spawner.test".to_string()
+            }),
+            scheduler.schedule("spawner.test", &spawn_request)
+        );
+    }
+}
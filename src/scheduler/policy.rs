@@ -0,0 +1,92 @@
+//! Placement policies, which choose among the drones with room for a backend.
+
+use crate::messages::agent::{DroneStatusMessage, SpawnRequest};
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::{str::FromStr, sync::Arc};
+
+/// How the drone to run a backend on is chosen. The scheduler narrows the cluster's
+/// drones down to those which are ready, match the spawn request's selector, and have
+/// room for the backend; the policy may narrow them further, and the drone it scores
+/// highest gets the backend.
+pub trait PlacementPolicy: Send + Sync {
+    /// Whether the backend may be placed on the drone. If no drone passes, the spawn is
+    /// rejected as the cluster being full.
+    fn filter(&self, _drone: &DroneStatusMessage, _spawn_request: &SpawnRequest) -> bool {
+        true
+    }
+
+    /// How well the drone suits the backend. Ties go to the lowest drone ID.
+    fn score(&self, drone: &DroneStatusMessage, spawn_request: &SpawnRequest) -> f64;
+}
+
+/// The smallest fraction free of the drone's resources which it reports: its backend
+/// slots, idle CPU, and available memory.
+pub fn headroom(drone: &DroneStatusMessage) -> f64 {
+    let slots = match drone.max_backends {
+        Some(max_backends) if max_backends > 0 => {
+            f64::from(drone.capacity) / f64::from(max_backends)
+        }
+        _ => 1.0,
+    };
+    let cpu = drone.cpu_idle_percent.map_or(1.0, |idle| idle / 100.0);
+    let memory = match (drone.memory_available_bytes, drone.memory_total_bytes) {
+        (Some(available), Some(total)) if total > 0 => available as f64 / total as f64,
+        _ => 1.0,
+    };
+
+    slots.min(cpu).min(memory)
+}
+
+/// Place each backend on the drone with the most headroom, so that losing a drone loses
+/// as few backends as possible.
+pub struct Spread;
+
+impl PlacementPolicy for Spread {
+    fn score(&self, drone: &DroneStatusMessage, _spawn_request: &SpawnRequest) -> f64 {
+        headroom(drone)
+    }
+}
+
+/// Place each backend on the drone with the least headroom, so that the cluster runs on
+/// as few drones as possible and idle ones can be shut down.
+pub struct BinPack;
+
+impl PlacementPolicy for BinPack {
+    fn score(&self, drone: &DroneStatusMessage, _spawn_request: &SpawnRequest) -> f64 {
+        -headroom(drone)
+    }
+}
+
+/// The built-in placement policies, by name.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum SchedulingStrategy {
+    #[default]
+    Spread,
+    BinPack,
+}
+
+impl SchedulingStrategy {
+    pub fn policy(self) -> Arc<dyn PlacementPolicy> {
+        match self {
+            SchedulingStrategy::Spread => Arc::new(Spread),
+            SchedulingStrategy::BinPack => Arc::new(BinPack),
+        }
+    }
+}
+
+impl FromStr for SchedulingStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "spread" => Ok(SchedulingStrategy::Spread),
+            "bin-pack" => Ok(SchedulingStrategy::BinPack),
+            _ => Err(anyhow!(
+                "Expected scheduling strategy to be spread or bin-pack, got {:?}.",
+                s
+            )),
+        }
+    }
+}
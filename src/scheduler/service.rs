@@ -0,0 +1,276 @@
+//! Runs the scheduler against NATS. Drones register with it and send it their
+//! heartbeats and backends' state changes; clients send it `ScheduleRequest`s, which it
+//! forwards to the drones it places them on.
+//!
+//! The scheduler gives drones their IDs, so it takes the place of any other handler of
+//! drone registrations rather than running beside one.
+
+use super::{
+    admin::serve_admin, policy::SchedulingStrategy, publish_demand, recover_lost_backends,
+    send_drain_requests, Scheduler,
+};
+use crate::{
+    messages::{
+        agent::{
+            BackendStateMessage, DroneConnectRequest, DroneConnectResponse, DroneStatusMessage,
+            SpawnRequest,
+        },
+        scheduler::{ScheduleRequest, ScheduleResponse},
+    },
+    nats::{MessageWithResponseHandle, TypedNats},
+    nats_connection::NatsConnection,
+    types::BackendId,
+};
+use anyhow::{anyhow, Result};
+use futures::{future::select_all, Future};
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// How often the backends of lost drones are recovered, drain requests sent, and clusters'
+/// demand published.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(PartialEq, Debug)]
+pub struct SchedulerOptions {
+    pub nats: NatsConnection,
+
+    /// Clusters drones may join. Spawn requests which name no cluster are placed in the
+    /// first.
+    pub clusters: Vec<String>,
+
+    /// How each cluster's backends are placed, if not spread.
+    pub strategies: Vec<(String, SchedulingStrategy)>,
+    pub heartbeat_timeout: Duration,
+
+    /// Where to serve the admin API, if anywhere.
+    pub admin_address: Option<SocketAddr>,
+}
+
+async fn listen_heartbeats(nats: TypedNats, scheduler: Arc<Mutex<Scheduler>>) -> Result<()> {
+    let mut sub = nats
+        .subscribe(DroneStatusMessage::subscribe_subject())
+        .await?;
+
+    loop {
+        match sub.next().await {
+            Ok(Some(message)) => scheduler
+                .lock()
+                .expect("Scheduler lock was poisoned.")
+                .heartbeat(message.value),
+            Ok(None) => return Err(anyhow!("Drone status subscription closed.")),
+            Err(error) => {
+                tracing::warn!(?error, "Non-fatal error when listening for drone statuses.")
+            }
+        }
+    }
+}
+
+async fn listen_backend_states(nats: TypedNats, scheduler: Arc<Mutex<Scheduler>>) -> Result<()> {
+    let mut sub = nats
+        .subscribe(BackendStateMessage::subscribe_subject())
+        .await?;
+
+    loop {
+        match sub.next().await {
+            Ok(Some(message)) => {
+                // Subjects are of the form backend.<backend id>.status.
+                if let Some(backend_id) = message
+                    .subject()
+                    .strip_prefix("backend.")
+                    .and_then(|subject| subject.strip_suffix(".status"))
+                {
+                    scheduler
+                        .lock()
+                        .expect("Scheduler lock was poisoned.")
+                        .backend_state(&BackendId::new(backend_id.to_string()), &message.value);
+                }
+            }
+            Ok(None) => return Err(anyhow!("Backend state subscription closed.")),
+            Err(error) => {
+                tracing::warn!(?error, "Non-fatal error when listening for backend states.")
+            }
+        }
+    }
+}
+
+/// Give drones joining one of the clusters an ID, and refuse those joining any other.
+///
+/// Registrations are only handled once every drone already running has had time to send
+/// a heartbeat, so that new drones are not given the IDs of drones registered before the
+/// scheduler started. Drones retry their registration in the meantime.
+async fn handle_registrations(
+    nats: TypedNats,
+    scheduler: Arc<Mutex<Scheduler>>,
+    clusters: Vec<String>,
+    heartbeat_timeout: Duration,
+) -> Result<()> {
+    tokio::time::sleep(heartbeat_timeout).await;
+    let mut sub = nats.subscribe(DroneConnectRequest::subject()).await?;
+
+    loop {
+        let message = match sub.next().await {
+            Ok(Some(message)) => message,
+            Ok(None) => return Err(anyhow!("Drone registration subscription closed.")),
+            Err(error) => {
+                tracing::warn!(
+                    ?error,
+                    "Non-fatal error when listening for drone registrations."
+                );
+                continue;
+            }
+        };
+
+        let request = &message.value;
+        let response = if clusters.contains(&request.cluster) {
+            let mut scheduler = scheduler.lock().expect("Scheduler lock was poisoned.");
+            let drone_id = scheduler.next_drone_id();
+            scheduler.register(drone_id, request.clone());
+            tracing::info!(drone_id = drone_id.id(), cluster = %request.cluster, ip = %request.ip, "Registered drone.");
+            DroneConnectResponse::Success { drone_id }
+        } else {
+            tracing::warn!(cluster = %request.cluster, ip = %request.ip, "Drone asked to join an unknown cluster.");
+            DroneConnectResponse::NoSuchCluster
+        };
+        if let Err(error) = message.respond(&response).await {
+            tracing::warn!(?error, "Error responding to drone registration.");
+        }
+    }
+}
+
+/// Place the backend, send its spawn request to the drone it is placed on, and respond
+/// with the outcome. If the drone refuses the backend, the placement is undone.
+async fn schedule(
+    nats: TypedNats,
+    scheduler: Arc<Mutex<Scheduler>>,
+    default_cluster: String,
+    message: MessageWithResponseHandle<ScheduleRequest, ScheduleResponse>,
+) {
+    let spawn_request = &message.value.spawn_request;
+    let backend_id = &spawn_request.backend_id;
+    let scheduled = scheduler
+        .lock()
+        .expect("Scheduler lock was poisoned.")
+        .schedule(&default_cluster, spawn_request);
+
+    let response = match scheduled {
+        Ok(drone_id) => match nats
+            .request(&SpawnRequest::subject(drone_id), spawn_request)
+            .await
+        {
+            Ok(response) => {
+                tracing::info!(%backend_id, drone_id = drone_id.id(), ?response, "Sent spawn request to drone.");
+                ScheduleResponse::Scheduled { drone_id, response }
+            }
+            Err(error) => {
+                tracing::warn!(%backend_id, drone_id = drone_id.id(), ?error, "Error sending spawn request to drone.");
+                ScheduleResponse::DroneUnreachable { drone_id }
+            }
+        },
+        Err(error) => {
+            tracing::info!(%backend_id, %error, "Could not schedule backend.");
+            ScheduleResponse::Rejected(error)
+        }
+    };
+    let spawn_response = match &response {
+        ScheduleResponse::Scheduled { response, .. } => Some(Some(response)),
+        ScheduleResponse::DroneUnreachable { .. } => Some(None),
+        ScheduleResponse::Rejected(_) => None,
+    };
+    if let Some(spawn_response) = spawn_response {
+        scheduler
+            .lock()
+            .expect("Scheduler lock was poisoned.")
+            .spawn_response(backend_id, spawn_response);
+    }
+
+    if let Err(error) = message.respond(&response).await {
+        tracing::warn!(%backend_id, ?error, "Error responding to schedule request.");
+    }
+}
+
+async fn handle_schedule_requests(
+    nats: TypedNats,
+    scheduler: Arc<Mutex<Scheduler>>,
+    default_cluster: String,
+) -> Result<()> {
+    let mut sub = nats.subscribe(ScheduleRequest::subject()).await?;
+
+    loop {
+        match sub.next().await {
+            // Each request waits on a drone, so requests are handled concurrently.
+            Ok(Some(message)) => {
+                tokio::spawn(schedule(
+                    nats.clone(),
+                    scheduler.clone(),
+                    default_cluster.clone(),
+                    message,
+                ));
+            }
+            Ok(None) => return Err(anyhow!("Schedule request subscription closed.")),
+            Err(error) => {
+                tracing::warn!(
+                    ?error,
+                    "Non-fatal error when listening for schedule requests."
+                )
+            }
+        }
+    }
+}
+
+/// Periodically recover the backends of lost drones, send drones the drain requests made
+/// through the admin API, and publish clusters' demand.
+async fn maintain(nats: TypedNats, scheduler: Arc<Mutex<Scheduler>>) -> Result<()> {
+    let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        recover_lost_backends(&nats, &scheduler).await;
+        send_drain_requests(&nats, &scheduler).await;
+        if let Err(error) = publish_demand(&nats, &scheduler).await {
+            tracing::warn!(?error, "Error publishing cluster demand.");
+        }
+    }
+}
+
+/// Run the scheduler until one of its subscriptions or the admin server fails.
+pub async fn serve_scheduler(options: SchedulerOptions) -> Result<()> {
+    let default_cluster = options
+        .clusters
+        .first()
+        .cloned()
+        .ok_or_else(|| anyhow!("Expected at least one cluster."))?;
+    let nats = options.nats.connection().await?;
+
+    let mut scheduler = Scheduler::new(options.heartbeat_timeout);
+    for (cluster, strategy) in &options.strategies {
+        scheduler.set_strategy(cluster, *strategy);
+    }
+    let scheduler = Arc::new(Mutex::new(scheduler));
+
+    let mut futs: Vec<Pin<Box<dyn Future<Output = Result<()>>>>> = vec![
+        Box::pin(listen_heartbeats(nats.clone(), scheduler.clone())),
+        Box::pin(listen_backend_states(nats.clone(), scheduler.clone())),
+        Box::pin(handle_registrations(
+            nats.clone(),
+            scheduler.clone(),
+            options.clusters,
+            options.heartbeat_timeout,
+        )),
+        Box::pin(handle_schedule_requests(
+            nats.clone(),
+            scheduler.clone(),
+            default_cluster,
+        )),
+        Box::pin(maintain(nats, scheduler.clone())),
+    ];
+    if let Some(admin_address) = options.admin_address {
+        futs.push(Box::pin(serve_admin(admin_address, scheduler)));
+    }
+
+    let (result, _, _) = select_all(futs).await;
+    result
+}
@@ -47,4 +47,22 @@ impl BackendId {
             .strip_prefix(RESOURCE_PREFIX)
             .map(|d| BackendId(d.to_string()))
     }
+
+    /// The resource name within a namespace, which lets the agents of several clusters
+    /// share a container engine. Without a namespace, this is `to_resource_name`.
+    #[must_use] pub fn to_namespaced_resource_name(&self, namespace: Option<&str>) -> String {
+        match namespace {
+            Some(namespace) => format!("{}-{}", namespace, self.to_resource_name()),
+            None => self.to_resource_name(),
+        }
+    }
+
+    #[must_use] pub fn from_namespaced_resource_name(resource_name: &str, namespace: Option<&str>) -> Option<Self> {
+        match namespace {
+            Some(namespace) => {
+                Self::from_resource_name(resource_name.strip_prefix(namespace)?.strip_prefix('-')?)
+            }
+            None => Self::from_resource_name(resource_name),
+        }
+    }
 }
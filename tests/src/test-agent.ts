@@ -46,6 +46,8 @@ test("Drone sends ready messages", async (t) => {
   let status1 = (await droneStatusSubscription.next())[0]
   t.is(status1.drone_id, 345)
   t.is(status1.cluster, "mydomain.test")
+  t.is(status1.state, "Ready")
+  t.is(status1.running_backends, 0)
 
   let status2 = (await droneStatusSubscription.next())[0]
   t.is(status2.drone_id, 345)
@@ -88,7 +90,7 @@ test("NATS logs", async (t) => {
       foo: "bar",
     },
   }
-  expectResponse(t, nats, "drone.1.spawn", request, true)
+  expectResponse(t, nats, "drone.1.spawn", request, "Accepted")
 
   let [result] = await logSubscription.next()
   t.deepEqual(result.fields.metadata, { foo: "bar" })
@@ -123,7 +125,7 @@ test("Spawn with agent", async (t) => {
     },
     metadata: {},
   }
-  expectResponse(t, nats, "drone.1.spawn", request, true)
+  expectResponse(t, nats, "drone.1.spawn", request, "Accepted")
 
   // Status update stages
   const backendStatusSubscription =
@@ -183,7 +185,7 @@ test("Lifecycle is managed when agent is restarted.", async (t) => {
     },
     metadata: {},
   }
-  expectResponse(t, nats, "drone.1.spawn", request, true)
+  expectResponse(t, nats, "drone.1.spawn", request, "Accepted")
 
   // Status update stages
   const backendStatusSubscription =
@@ -246,7 +248,7 @@ test("Spawn fails during start", async (t) => {
     },
     metadata: {},
   }
-  expectResponse(t, nats, "drone.1.spawn", request, true)
+  expectResponse(t, nats, "drone.1.spawn", request, "Accepted")
 
   // Status update stages
   const backendStatusSubscription =
@@ -296,7 +298,7 @@ test("Backend fails after ready", async (t) => {
     },
     metadata: {},
   }
-  expectResponse(t, nats, "drone.1.spawn", request, true)
+  expectResponse(t, nats, "drone.1.spawn", request, "Accepted")
 
   // Status update stages
   const backendStatusSubscription =
@@ -76,7 +76,7 @@ test("Test end-to-end", async (t) => {
         JSON_CODEC.encode(request)
     )
     const spawnResult = JSON_CODEC.decode(rawSpawnResult.data)
-    t.is(spawnResult, true)
+    t.is(spawnResult, "Accepted")
 
     t.is("Loading", (await backendStatusSubscription.next())[0].state)
     t.is("Starting", (await backendStatusSubscription.next())[0].state)
@@ -18,20 +18,33 @@ export type BackendStatus =
     | "Starting"
     | "ErrorStarting"
     | "Ready"
+    | "Restarting"
     | "TimedOutBeforeReady"
     | "Failed"
     | "Exited"
     | "Swept"
+    | "Unhealthy"
+    | "Expired"
+    | "Evicted"
+    | "Lost"
 
 export interface BackendStateMessage {
     state: BackendStatus
     time: string
+    previous_state: BackendStatus | null
 }
 
 export interface DroneStatusMessage {
     drone_id: number,
     capacity: number,
     cluster: string,
+    state: "Ready" | "Draining" | "Drained",
+    running_backends: number,
+    max_backends: number | null,
+    cpu_idle_percent: number | null,
+    memory_available_bytes: number | null,
+    memory_total_bytes: number | null,
+    version: string,
 }
 
 export interface DnsMessage {